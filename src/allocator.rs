@@ -0,0 +1,102 @@
+//! Counts what the process allocates, for `INFO memory` to set against its
+//! resident size.
+//!
+//! [`Counting`] wraps a global allocator, the system's or any other, and
+//! keeps count of the bytes it has handed out and not had back. A binary
+//! installs it with `#[global_allocator]`, as `dist-kv` does in builds with
+//! the `alloc-stats` feature; without it [`stats`] is `None` and `INFO`
+//! leaves the allocator's lines out. The counts are atomics shared by every
+//! thread, which costs each allocation a few uncontended atomic adds.
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+/// A global allocator that counts what `A` allocates.
+pub struct Counting<A> {
+    inner: A,
+}
+
+impl<A> Counting<A> {
+    pub const fn new(inner: A) -> Counting<A> {
+        Counting { inner }
+    }
+}
+
+fn allocated(size: usize) {
+    INSTALLED.store(true, Ordering::Relaxed);
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    let now = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(now, Ordering::Relaxed);
+}
+
+fn freed(size: usize) {
+    ALLOCATED.fetch_sub(size, Ordering::Relaxed);
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Counting<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+        freed(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = self.inner.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            freed(layout.size());
+            allocated(new_size);
+        }
+        new
+    }
+}
+
+/// What [`Counting`] has counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocStats {
+    /// Bytes allocated and not yet freed.
+    pub allocated: usize,
+    /// The most `allocated` has been.
+    pub peak: usize,
+    /// Allocations made, reallocations included.
+    pub allocations: u64,
+}
+
+impl AllocStats {
+    /// How much more memory the process holds than it has allocated, as
+    /// the resident size over the bytes allocated: what the allocator
+    /// loses to fragmentation and keeps cached, plus everything that isn't
+    /// heap. Near 1 is good; well above it means memory isn't going back
+    /// to the system after a burst.
+    pub fn fragmentation(&self, rss: u64) -> f64 {
+        rss as f64 / self.allocated.max(1) as f64
+    }
+}
+
+/// The counts so far, if the global allocator is a [`Counting`] one.
+pub fn stats() -> Option<AllocStats> {
+    INSTALLED.load(Ordering::Relaxed).then(|| AllocStats {
+        allocated: ALLOCATED.load(Ordering::Relaxed),
+        peak: PEAK.load(Ordering::Relaxed),
+        allocations: ALLOCATIONS.load(Ordering::Relaxed),
+    })
+}
@@ -0,0 +1,104 @@
+//! Keyspace analysis for `ANALYZE`: the largest values, the most common key
+//! prefixes and how many keys expire.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::fmt::Write;
+
+use crate::store::{Key, Val};
+
+/// How many of the largest values and most common prefixes are reported when
+/// the client doesn't ask for a number.
+pub const DEFAULT_TOP: usize = 10;
+
+/// Keys up to and including the first of these bytes count towards a
+/// prefix. Keys without one are counted under the empty prefix.
+const PREFIX_DELIMITERS: &[u8] = b":/.";
+
+/// The buckets keys are sorted into by how long they have left to live, each
+/// with the limit in milliseconds below which a TTL falls into it.
+const TTL_BUCKETS: [(&str, u64); 4] = [
+    ("ttl_under_1m", 60 * 1000),
+    ("ttl_under_1h", 60 * 60 * 1000),
+    ("ttl_under_1d", 24 * 60 * 60 * 1000),
+    ("ttl_over_1d", u64::MAX),
+];
+
+/// Totals built up one key at a time, so a scan can be split into batches.
+pub struct Analysis {
+    top: usize,
+    keys: usize,
+    bytes: usize,
+    /// The `top` largest values seen so far, smallest first.
+    largest: BinaryHeap<Reverse<(usize, Key)>>,
+    prefixes: HashMap<Key, usize>,
+    /// The time TTLs are measured from, in milliseconds since the Unix epoch.
+    now: u64,
+    no_ttl: usize,
+    ttls: [usize; TTL_BUCKETS.len()],
+}
+
+impl Analysis {
+    pub fn new(top: usize, now: u64) -> Self {
+        Analysis {
+            top,
+            keys: 0,
+            bytes: 0,
+            largest: BinaryHeap::new(),
+            prefixes: HashMap::new(),
+            now,
+            no_ttl: 0,
+            ttls: [0; TTL_BUCKETS.len()],
+        }
+    }
+
+    /// Counts a key, along with when it expires if it has a TTL.
+    pub fn add(&mut self, key: &Key, val: &Val, expiry: Option<u64>) {
+        self.keys += 1;
+        self.bytes += key.len() + val.len();
+        self.largest.push(Reverse((val.len(), key.clone())));
+        if self.largest.len() > self.top {
+            self.largest.pop();
+        }
+        let prefix = match key.iter().position(|b| PREFIX_DELIMITERS.contains(b)) {
+            Some(end) => key.slice(0..end + 1),
+            None => Key::new(),
+        };
+        *self.prefixes.entry(prefix).or_default() += 1;
+        match expiry {
+            Some(at) => {
+                let left = at.saturating_sub(self.now);
+                let bucket = TTL_BUCKETS.iter().position(|(_, limit)| left < *limit);
+                self.ttls[bucket.unwrap_or(TTL_BUCKETS.len() - 1)] += 1;
+            }
+            None => self.no_ttl += 1,
+        }
+    }
+
+    pub fn render(self) -> String {
+        let mut report = String::new();
+        report.push_str("# Keyspace\n");
+        let _ = writeln!(report, "keys:{}", self.keys);
+        let _ = writeln!(report, "bytes:{}", self.bytes);
+
+        report.push_str("# Largest values\n");
+        for Reverse((len, key)) in self.largest.into_sorted_vec() {
+            let _ = writeln!(report, "{:?} {}", String::from_utf8_lossy(&key), len);
+        }
+
+        report.push_str("# Prefixes\n");
+        let mut prefixes: Vec<_> = self.prefixes.into_iter().collect();
+        prefixes.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then(a.cmp(b)));
+        prefixes.truncate(self.top);
+        for (prefix, count) in prefixes {
+            let _ = writeln!(report, "{:?} {}", String::from_utf8_lossy(&prefix), count);
+        }
+
+        report.push_str("# TTL\n");
+        let _ = writeln!(report, "no_ttl:{}", self.no_ttl);
+        for ((name, _), count) in TTL_BUCKETS.iter().zip(self.ttls) {
+            let _ = writeln!(report, "{}:{}", name, count);
+        }
+        report
+    }
+}
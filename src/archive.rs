@@ -0,0 +1,310 @@
+//! Archiving the leader's log and snapshots to object storage, and
+//! rebuilding a node's log from the archive.
+//!
+//! The archiver ships the log in segments: every interval, the records
+//! appended since the last segment are uploaded as `wal/<n>`, where `n`
+//! counts the archive's objects. It starts with a snapshot of the map,
+//! `snapshot/<n>`, and takes another every [`SNAPSHOT_EVERY`] segments so a
+//! restore never has far to replay. After each upload the `manifest`
+//! object, the names of every segment and snapshot in order one per line,
+//! is rewritten, so an object only counts once it is in the manifest.
+//!
+//! A restore starts from the last snapshot in the manifest and replays the
+//! segments after it. Namespaces kept out of the node's log are only
+//! archived through snapshots.
+//!
+//! Backends implement [`ObjectStore`]. A location is either a directory or
+//! an `http://host[:port]/bucket` URL, objects being written with `PUT` and
+//! read with `GET` under it as S3-compatible stores do. Requests aren't
+//! signed, so the bucket has to accept them as they are, such as through a
+//! gateway that adds credentials.
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+
+use crate::error::{bail, Context, DistKvError, Result};
+use crate::manifest::replace_file;
+use crate::server::SyncLeader;
+use crate::snapshot;
+use crate::store::records;
+use crate::wal::{read_log, Entry, LogReader};
+use crate::webhook::parse_url;
+
+/// How often the archiver uploads the log written since its last segment.
+pub const ARCHIVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How many segments are uploaded between snapshots.
+pub const SNAPSHOT_EVERY: usize = 64;
+
+const MANIFEST: &str = "manifest";
+
+/// How long an HTTP store gets to accept a connection and answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Where archived objects are kept, by name. A `put` must replace an
+/// object whole, so a reader sees either the old or the new one.
+pub trait ObjectStore: Send {
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<()>;
+    /// Reads an object, or returns `None` if there is no such object.
+    fn get(&mut self, name: &str) -> Result<Option<Bytes>>;
+}
+
+/// An object store in a local directory, each object a file.
+pub struct DirStore {
+    dir: PathBuf,
+}
+
+impl DirStore {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        DirStore { dir: dir.into() }
+    }
+}
+
+impl ObjectStore for DirStore {
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        let path = self.dir.join(name);
+        let parent = path
+            .parent()
+            .ok_or_else(|| DistKvError::Config(format!("object {} has no directory", name)))?;
+        fs::create_dir_all(parent)?;
+        replace_file(&path, data)
+    }
+
+    fn get(&mut self, name: &str) -> Result<Option<Bytes>> {
+        let path = self.dir.join(name);
+        let path = path
+            .to_str()
+            .ok_or_else(|| DistKvError::Config("archive path is not UTF-8".to_string()))?;
+        Ok(read_log(path)?.map(Bytes::from))
+    }
+}
+
+/// An object store behind an HTTP endpoint, each object at its name under
+/// the base URL.
+pub struct HttpStore {
+    base: String,
+}
+
+impl HttpStore {
+    pub fn new(base: &str) -> Result<Self> {
+        parse_url(base)?;
+        Ok(HttpStore {
+            base: base.trim_end_matches('/').to_string(),
+        })
+    }
+
+    /// Sends a request for the object `name` and returns the response's
+    /// status code and body.
+    fn request(&self, method: &str, name: &str, body: &[u8]) -> Result<(u16, Vec<u8>)> {
+        let url = format!("{}/{}", self.base, name);
+        let (addr, host, path) = parse_url(&url)?;
+        let mut stream =
+            TcpStream::connect(&addr).with_context(|| format!("connecting to {}", addr))?;
+        stream.set_read_timeout(Some(REQUEST_TIMEOUT))?;
+        stream.set_write_timeout(Some(REQUEST_TIMEOUT))?;
+        let head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            method,
+            path,
+            host,
+            body.len()
+        );
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response)?;
+        let Some(end) = response.windows(4).position(|w| w == b"\r\n\r\n") else {
+            bail!(
+                Protocol,
+                "{} {} got a response without headers",
+                method,
+                url
+            );
+        };
+        let head = String::from_utf8_lossy(&response[..end]);
+        let status = head
+            .split_whitespace()
+            .nth(1)
+            .and_then(|code| code.parse().ok())
+            .ok_or_else(|| DistKvError::Protocol(format!("{} {} got no status", method, url)))?;
+        Ok((status, response[end + 4..].to_vec()))
+    }
+}
+
+impl ObjectStore for HttpStore {
+    fn put(&mut self, name: &str, data: &[u8]) -> Result<()> {
+        match self.request("PUT", name, data)? {
+            (200..=299, _) => Ok(()),
+            (status, _) => bail!(Protocol, "PUT {} answered {}", name, status),
+        }
+    }
+
+    fn get(&mut self, name: &str) -> Result<Option<Bytes>> {
+        match self.request("GET", name, &[])? {
+            (200..=299, body) => Ok(Some(Bytes::from(body))),
+            (404, _) => Ok(None),
+            (status, _) => bail!(Protocol, "GET {} answered {}", name, status),
+        }
+    }
+}
+
+/// Opens the object store at `location`: an `http://` URL or a directory.
+pub fn open_store(location: &str) -> Result<Box<dyn ObjectStore>> {
+    match location.contains("://") {
+        true => Ok(Box::new(HttpStore::new(location)?)),
+        false => Ok(Box::new(DirStore::new(location))),
+    }
+}
+
+/// The names of the archive's objects, oldest first.
+fn read_manifest(store: &mut dyn ObjectStore) -> Result<Vec<String>> {
+    let Some(manifest) = store.get(MANIFEST)? else {
+        return Ok(Vec::new());
+    };
+    let manifest = std::str::from_utf8(&manifest)
+        .map_err(|_| DistKvError::Corrupt("manifest is not UTF-8".to_string()))?;
+    Ok(manifest.lines().map(str::to_string).collect())
+}
+
+/// Uploads a node's log and snapshots to an object store.
+pub struct Archiver {
+    store: Box<dyn ObjectStore>,
+    log_path: String,
+    manifest: Vec<String>,
+    /// How much of the log has been archived, once a snapshot has been.
+    archived: Option<u64>,
+    since_snapshot: usize,
+}
+
+impl Archiver {
+    /// Starts archiving the log at `log_path` to `store`, after whatever the
+    /// store already holds.
+    pub fn open(mut store: Box<dyn ObjectStore>, log_path: &str) -> Result<Self> {
+        let manifest = read_manifest(&mut *store)?;
+        Ok(Archiver {
+            store,
+            log_path: log_path.to_string(),
+            manifest,
+            archived: None,
+            since_snapshot: 0,
+        })
+    }
+
+    /// Whether the next [`Archiver::archive`] needs a snapshot.
+    pub fn snapshot_due(&self) -> bool {
+        self.archived.is_none() || self.since_snapshot >= SNAPSHOT_EVERY
+    }
+
+    /// Uploads `snapshot`, taken when the log was `len` bytes long, if
+    /// there is one, and then the log written since the last upload.
+    pub fn archive(&mut self, snapshot: Option<(Bytes, u64)>) -> Result<()> {
+        if let Some((snapshot, len)) = snapshot {
+            self.upload(format!("snapshot/{:020}", self.manifest.len()), &snapshot)?;
+            self.archived = Some(len);
+            self.since_snapshot = 0;
+        }
+        let Some(archived) = self.archived else {
+            return Err(DistKvError::storage(
+                "the archive needs a snapshot to start from",
+            ));
+        };
+        let log = read_log(&self.log_path)?.unwrap_or_default();
+        let Some(new) = log.get(archived as usize..) else {
+            return Err(DistKvError::storage(format!(
+                "{} is shorter than what was archived",
+                self.log_path
+            )));
+        };
+        // Only whole records, leaving one still being appended for later.
+        let complete = LogReader::new(new)
+            .find_map(|entry| match entry {
+                Entry::Torn { offset, .. } => Some(offset),
+                _ => None,
+            })
+            .unwrap_or(new.len());
+        if complete == 0 {
+            return Ok(());
+        }
+        self.upload(format!("wal/{:020}", self.manifest.len()), &new[..complete])?;
+        self.archived = Some(archived + complete as u64);
+        self.since_snapshot += 1;
+        Ok(())
+    }
+
+    fn upload(&mut self, name: String, data: &[u8]) -> Result<()> {
+        self.store.put(&name, data)?;
+        self.manifest.push(name);
+        let mut manifest = self.manifest.join("\n");
+        manifest.push('\n');
+        self.store.put(MANIFEST, manifest.as_bytes())
+    }
+}
+
+/// A snapshot of the leader's map and the length its log was when it was
+/// taken.
+pub async fn take_snapshot(leader: &SyncLeader) -> Result<(Bytes, u64)> {
+    let leader = leader.lock().await;
+    let len = leader.core.wal.metadata()?.len();
+    Ok((snapshot::encode(&leader.core.hashmap, leader.core.lsn), len))
+}
+
+/// Archives the leader's log every `interval`, taking snapshots as needed.
+/// Failures are reported and retried on the next round.
+pub async fn run(mut archiver: Archiver, leader: SyncLeader, interval: Duration) {
+    loop {
+        let snapshot = match archiver.snapshot_due() {
+            true => match take_snapshot(&leader).await {
+                Ok(snapshot) => Some(snapshot),
+                Err(e) => {
+                    eprintln!("Failed to snapshot for the archive: {:?}", e);
+                    None
+                }
+            },
+            false => None,
+        };
+        let archiving = tokio::task::spawn_blocking(move || {
+            if let Err(e) = archiver.archive(snapshot) {
+                eprintln!("Failed to archive the log: {:?}", e);
+            }
+            archiver
+        });
+        archiver = match archiving.await {
+            Ok(archiver) => archiver,
+            Err(e) => return eprintln!("The archiver stopped: {:?}", e),
+        };
+        tokio::time::sleep(interval).await;
+    }
+}
+
+/// Rebuilds a log from the archive in `store`: the records of its last
+/// snapshot followed by every segment uploaded after it.
+pub fn restore(store: &mut dyn ObjectStore) -> Result<BytesMut> {
+    let manifest = read_manifest(store)?;
+    let Some(start) = manifest
+        .iter()
+        .rposition(|name| name.starts_with("snapshot/"))
+    else {
+        return Err(DistKvError::storage(
+            "the archive has no snapshot to restore from",
+        ));
+    };
+    let mut log = BytesMut::new();
+    for name in &manifest[start..] {
+        let object = store
+            .get(name)?
+            .ok_or_else(|| DistKvError::storage(format!("the archive is missing {}", name)))?;
+        match name.starts_with("snapshot/") {
+            true => {
+                let snapshot = snapshot::decode(&object).with_context(|| name.clone())?;
+                log.extend_from_slice(&records(&snapshot.hashmap));
+            }
+            false => log.extend_from_slice(&object),
+        }
+    }
+    Ok(log)
+}
@@ -0,0 +1,133 @@
+//! The audit log: a line for each write or administrative command a client
+//! sends the leader, naming the user that sent it, the address it came
+//! from and when, from `audit_log <path>`.
+//!
+//! Lines are `key=value` fields, as `CLIENT LIST` writes them:
+//!
+//! ```text
+//! time=1700000000000 user=billing addr=127.0.0.1:50312 ns=0 cmd=SET keys="billing:a" result=allowed
+//! ```
+//!
+//! `time` is in milliseconds since the Unix epoch and `user` is `-` for a
+//! connection that hasn't authenticated. Keys are quoted and values are
+//! left out. `result` is `denied` for a command the user wasn't permitted to
+//! run and for an `AUTH` with the wrong password, whose `user` is the one it
+//! tried. Commands are logged as they are let through, before they run, so
+//! a write that then fails is still logged as `allowed`.
+//!
+//! The log is only appended to. Once it would grow past `audit_log_size`
+//! it is renamed to `<path>.<time>`, with the time of the line that didn't
+//! fit, and a new one is started; rotated files are left for the operator
+//! to ship and remove. It is kept apart from the leader's log and
+//! checkpoints, which never remove it.
+
+use std::fmt::Write as _;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+use crate::error::{Context, Result};
+use crate::protocol::{ClientCommand, Command, ConfigCommand};
+
+#[derive(Debug)]
+pub struct AuditLog {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    /// How large the log grows before it is rotated. 0 never rotates it.
+    max_size: u64,
+}
+
+impl AuditLog {
+    /// Opens the log at `path` to append to, creating it if need be.
+    pub fn open(path: impl Into<PathBuf>, max_size: u64) -> Result<AuditLog> {
+        let path = path.into();
+        let file = open_append(&path)?;
+        let len = file.metadata()?.len();
+        Ok(AuditLog {
+            path,
+            file,
+            len,
+            max_size,
+        })
+    }
+
+    /// Logs `command`, sent by `user` from `addr` in `namespace` at `at`
+    /// milliseconds since the Unix epoch, and whether it was let through.
+    pub fn record(
+        &mut self,
+        at: u64,
+        user: Option<&str>,
+        addr: SocketAddr,
+        namespace: &[u8],
+        command: &Command<'_>,
+        allowed: bool,
+    ) -> Result<()> {
+        let namespace = match command {
+            Command::In(name, _) => name,
+            _ => namespace,
+        };
+        let mut line = format!(
+            "time={} user={} addr={} ns={} cmd={} keys=",
+            at,
+            user.unwrap_or("-"),
+            addr,
+            String::from_utf8_lossy(namespace),
+            command.name()
+        );
+        for (i, key) in command.keys().into_iter().enumerate() {
+            if i > 0 {
+                line.push(',');
+            }
+            let _ = write!(line, "{:?}", String::from_utf8_lossy(key));
+        }
+        let result = if allowed { "allowed" } else { "denied" };
+        let _ = writeln!(line, " result={}", result);
+
+        if self.max_size > 0 && self.len > 0 && self.len + line.len() as u64 > self.max_size {
+            self.rotate(at)?;
+        }
+        self.file.write_all(line.as_bytes())?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self, at: u64) -> Result<()> {
+        let mut rotated = self.path.clone().into_os_string();
+        rotated.push(format!(".{}", at));
+        fs::rename(&self.path, &rotated)
+            .with_context(|| format!("rotating {}", self.path.display()))?;
+        self.file = open_append(&self.path)?;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &PathBuf) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("opening audit log {}", path.display()))
+}
+
+/// Whether `command` is logged: writes, and commands that authenticate,
+/// change settings, kill connections, stop writes, change the cluster, dump
+/// or meddle with the leader's state or replicate the map.
+pub fn is_audited(command: &Command<'_>) -> bool {
+    command.is_write()
+        || matches!(
+            command,
+            Command::Auth(..)
+                | Command::Config(ConfigCommand::Set(..))
+                | Command::Client(ClientCommand::Kill(_))
+                | Command::ReadOnly(_)
+                | Command::DumpState
+                | Command::Debug(_)
+                | Command::Cluster(_)
+                | Command::TransferLeadership(_)
+                | Command::Sync(..)
+                | Command::Replicate(_)
+        )
+}
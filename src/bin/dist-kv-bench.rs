@@ -0,0 +1,621 @@
+//! Load generator for a dist-kv leader.
+//!
+//! ```text
+//! dist-kv-bench [--addr host:port] [--keys N] [--value-size BYTES]
+//!               [--read-ratio 0..1] [--workload a|b|c|d|e|f]
+//!               [--distribution uniform|zipfian|latest] [--zipf-theta T]
+//!               [--scan-length N] [--pipeline N] [--connections N]
+//!               [--requests N] [--csv PATH]
+//! ```
+//!
+//! Without `--workload` requests are reads and updates of uniformly picked
+//! keys, `--read-ratio` of them reads. The workloads are YCSB's core ones:
+//!
+//! - `a`: 50% reads, 50% updates, zipfian.
+//! - `b`: 95% reads, 5% updates, zipfian.
+//! - `c`: reads only, zipfian.
+//! - `d`: 95% reads, 5% inserts, reading the latest inserted most.
+//! - `e`: 95% scans of up to `--scan-length` keys, 5% inserts, zipfian.
+//! - `f`: 50% reads, 50% read-modify-writes, zipfian.
+//!
+//! `--distribution` overrides the workload's. Zipfian keys are scrambled,
+//! so that the popular ones are spread over the key space rather than all
+//! at its start. Inserts add keys after the first `--keys`. A scan is a
+//! `GET` of each of a run of consecutive keys, as the map has no order to
+//! scan in, and a read-modify-write a `GET` and a `SET` of the same key;
+//! each counts as one operation. `--csv` also writes the results per
+//! operation to a file, for comparing runs.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use bytes::BytesMut;
+use dist_kv::client::{DistKvClient, Reply};
+use dist_kv::protocol::Command;
+
+#[derive(Debug, Clone)]
+struct Options {
+    addr: String,
+    keys: u64,
+    value_size: usize,
+    read_ratio: f64,
+    workload: Option<char>,
+    distribution: Option<Distribution>,
+    zipf_theta: f64,
+    scan_length: u64,
+    pipeline: usize,
+    connections: usize,
+    requests: usize,
+    csv: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            addr: "localhost:47000".to_string(),
+            keys: 10_000,
+            value_size: 100,
+            read_ratio: 0.9,
+            workload: None,
+            distribution: None,
+            zipf_theta: 0.99,
+            scan_length: 100,
+            pipeline: 1,
+            connections: 8,
+            requests: 100_000,
+            csv: None,
+        }
+    }
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Options> {
+        let mut options = Options::default();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .with_context(|| format!("{} expects a value", flag))?;
+            match flag.as_str() {
+                "--addr" => options.addr = value,
+                "--keys" => options.keys = value.parse()?,
+                "--value-size" => options.value_size = value.parse()?,
+                "--read-ratio" => options.read_ratio = value.parse()?,
+                "--workload" => match value.as_str() {
+                    "a" | "b" | "c" | "d" | "e" | "f" => {
+                        options.workload = value.chars().next();
+                    }
+                    _ => bail!("--workload must be one of a, b, c, d, e and f"),
+                },
+                "--distribution" => {
+                    options.distribution = Some(match value.as_str() {
+                        "uniform" => Distribution::Uniform,
+                        "zipfian" => Distribution::Zipfian,
+                        "latest" => Distribution::Latest,
+                        _ => bail!("--distribution must be uniform, zipfian or latest"),
+                    })
+                }
+                "--zipf-theta" => options.zipf_theta = value.parse()?,
+                "--scan-length" => options.scan_length = value.parse()?,
+                "--pipeline" => options.pipeline = value.parse()?,
+                "--connections" => options.connections = value.parse()?,
+                "--requests" => options.requests = value.parse()?,
+                "--csv" => options.csv = Some(value),
+                _ => bail!("unknown flag {}", flag),
+            }
+        }
+        if options.keys == 0 || options.pipeline == 0 || options.connections == 0 {
+            bail!("--keys, --pipeline and --connections must be positive");
+        }
+        if options.scan_length == 0 {
+            bail!("--scan-length must be positive");
+        }
+        if !(0.0..=1.0).contains(&options.read_ratio) {
+            bail!("--read-ratio must be between 0 and 1");
+        }
+        if !(0.0..1.0).contains(&options.zipf_theta) {
+            bail!("--zipf-theta must be at least 0 and under 1");
+        }
+        Ok(options)
+    }
+
+    fn workload(&self) -> Workload {
+        let mut workload = match self.workload {
+            Some(name) => Workload::ycsb(name),
+            None => Workload {
+                mix: vec![
+                    (Op::Read, self.read_ratio),
+                    (Op::Update, 1.0 - self.read_ratio),
+                ],
+                distribution: Distribution::Uniform,
+            },
+        };
+        if let Some(distribution) = self.distribution {
+            workload.distribution = distribution;
+        }
+        workload
+    }
+}
+
+/// xorshift64*, plenty for picking keys and operations.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Op {
+    Read,
+    Update,
+    Insert,
+    Scan,
+    ReadModifyWrite,
+}
+
+impl Op {
+    fn name(&self) -> &'static str {
+        match self {
+            Op::Read => "read",
+            Op::Update => "update",
+            Op::Insert => "insert",
+            Op::Scan => "scan",
+            Op::ReadModifyWrite => "read_modify_write",
+        }
+    }
+}
+
+/// How keys are picked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Distribution {
+    Uniform,
+    /// A few keys are most of the requests.
+    Zipfian,
+    /// Zipfian over how recently keys were inserted, newest first.
+    Latest,
+}
+
+#[derive(Debug, Clone)]
+struct Workload {
+    /// Each operation and the fraction of requests it is.
+    mix: Vec<(Op, f64)>,
+    distribution: Distribution,
+}
+
+impl Workload {
+    fn ycsb(name: char) -> Workload {
+        let (mix, distribution) = match name {
+            'a' => (
+                vec![(Op::Read, 0.5), (Op::Update, 0.5)],
+                Distribution::Zipfian,
+            ),
+            'b' => (
+                vec![(Op::Read, 0.95), (Op::Update, 0.05)],
+                Distribution::Zipfian,
+            ),
+            'c' => (vec![(Op::Read, 1.0)], Distribution::Zipfian),
+            'd' => (
+                vec![(Op::Read, 0.95), (Op::Insert, 0.05)],
+                Distribution::Latest,
+            ),
+            'e' => (
+                vec![(Op::Scan, 0.95), (Op::Insert, 0.05)],
+                Distribution::Zipfian,
+            ),
+            _ => (
+                vec![(Op::Read, 0.5), (Op::ReadModifyWrite, 0.5)],
+                Distribution::Zipfian,
+            ),
+        };
+        Workload { mix, distribution }
+    }
+
+    fn pick(&self, rng: &mut Rng) -> Op {
+        let mut left = rng.next_f64();
+        for &(op, share) in &self.mix {
+            if left < share {
+                return op;
+            }
+            left -= share;
+        }
+        self.mix.last().map_or(Op::Read, |&(op, _)| op)
+    }
+}
+
+/// Zipfian ranks in `0..items`, 0 the most popular, by the method of Gray
+/// et al., "Quickly Generating Billion-Record Synthetic Databases", as YCSB
+/// generates them.
+#[derive(Debug, Clone)]
+struct Zipfian {
+    items: u64,
+    theta: f64,
+    alpha: f64,
+    zetan: f64,
+    eta: f64,
+}
+
+impl Zipfian {
+    fn new(items: u64, theta: f64) -> Zipfian {
+        let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+        let (zeta2, zetan) = (zeta(2), zeta(items));
+        Zipfian {
+            items,
+            theta,
+            alpha: 1.0 / (1.0 - theta),
+            zetan,
+            eta: (1.0 - (2.0 / items as f64).powf(1.0 - theta)) / (1.0 - zeta2 / zetan),
+        }
+    }
+
+    fn next(&self, rng: &mut Rng) -> u64 {
+        let u = rng.next_f64();
+        let uz = u * self.zetan;
+        if uz < 1.0 {
+            return 0;
+        }
+        if uz < 1.0 + 0.5f64.powf(self.theta) {
+            return 1.min(self.items - 1);
+        }
+        let rank = self.items as f64 * (self.eta * u - self.eta + 1.0).powf(self.alpha);
+        (rank as u64).min(self.items - 1)
+    }
+}
+
+/// FNV-1a of a rank, to scatter the popular ones over the keys.
+fn scramble(rank: u64) -> u64 {
+    rank.to_le_bytes()
+        .iter()
+        .fold(0xcbf2_9ce4_8422_2325, |hash, &b| {
+            (hash ^ b as u64).wrapping_mul(0x100_0000_01b3)
+        })
+}
+
+/// Picks the keys of a connection's requests.
+struct Keys {
+    distribution: Distribution,
+    zipfian: Option<Zipfian>,
+    /// How many keys there are, counting those inserted so far by every
+    /// connection.
+    inserted: Arc<AtomicU64>,
+}
+
+impl Keys {
+    fn existing(&self, rng: &mut Rng) -> u64 {
+        let count = self.inserted.load(Ordering::Relaxed);
+        match (self.distribution, &self.zipfian) {
+            (Distribution::Zipfian, Some(zipfian)) => scramble(zipfian.next(rng)) % count,
+            (Distribution::Latest, Some(zipfian)) => count - 1 - zipfian.next(rng).min(count - 1),
+            _ => rng.next() % count,
+        }
+    }
+
+    fn new_key(&self) -> u64 {
+        self.inserted.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+#[derive(Default)]
+struct OpResults {
+    /// Per-operation latencies in microseconds. Every operation in a
+    /// pipelined batch is charged the latency of the whole batch.
+    latencies: Vec<u64>,
+    errors: usize,
+}
+
+type Results = BTreeMap<Op, OpResults>;
+
+fn key(i: u64) -> String {
+    format!("key:{}", i)
+}
+
+/// Encodes the requests of `op`, returning how many there are.
+fn encode(
+    op: Op,
+    keys: &Keys,
+    options: &Options,
+    value: &[u8],
+    rng: &mut Rng,
+    batch: &mut BytesMut,
+) -> usize {
+    match op {
+        Op::Read => {
+            let key = key(keys.existing(rng));
+            Command::Get(key.as_bytes().into()).encode(batch);
+            1
+        }
+        Op::Update | Op::Insert => {
+            let key = match op {
+                Op::Insert => key(keys.new_key()),
+                _ => key(keys.existing(rng)),
+            };
+            Command::Set(key.as_bytes().into(), value.into()).encode(batch);
+            1
+        }
+        Op::Scan => {
+            let start = keys.existing(rng);
+            let len = 1 + rng.next() % options.scan_length;
+            for i in start..start + len {
+                Command::Get(key(i).as_bytes().into()).encode(batch);
+            }
+            len as usize
+        }
+        Op::ReadModifyWrite => {
+            let key = key(keys.existing(rng));
+            Command::Get(key.as_bytes().into()).encode(batch);
+            Command::Set(key.as_bytes().into(), value.into()).encode(batch);
+            2
+        }
+    }
+}
+
+async fn run_connection(
+    options: Options,
+    workload: Workload,
+    keys: Keys,
+    id: usize,
+    requests: usize,
+) -> Result<Results> {
+    let mut client = DistKvClient::connect(options.addr.as_str()).await?;
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15 ^ (id as u64 + 1));
+    let value = vec![b'x'; options.value_size];
+    let mut results = Results::new();
+    let mut batch = BytesMut::new();
+    let mut ops = Vec::with_capacity(options.pipeline);
+    let mut sent = 0;
+    while sent < requests {
+        let depth = options.pipeline.min(requests - sent);
+        batch.clear();
+        ops.clear();
+        for _ in 0..depth {
+            let op = workload.pick(&mut rng);
+            let replies = encode(op, &keys, &options, &value, &mut rng, &mut batch);
+            ops.push((op, replies));
+        }
+        let start = Instant::now();
+        client.send_raw(&batch).await?;
+        let mut errors = Vec::with_capacity(depth);
+        for &(_, replies) in &ops {
+            let mut failed = false;
+            for _ in 0..replies {
+                failed |= matches!(client.read_reply().await?, Reply::Error(_));
+            }
+            errors.push(failed);
+        }
+        let micros = start.elapsed().as_micros() as u64;
+        for (&(op, _), failed) in ops.iter().zip(errors) {
+            let op = results.entry(op).or_default();
+            op.latencies.push(micros);
+            op.errors += usize::from(failed);
+        }
+        sent += depth;
+    }
+    Ok(results)
+}
+
+fn percentile(sorted: &[u64], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * (sorted.len() - 1) as f64).round() as usize;
+    Duration::from_micros(sorted[rank])
+}
+
+const PERCENTILES: [f64; 5] = [50.0, 90.0, 99.0, 99.9, 100.0];
+
+/// One line per operation and one for them all: the operation, how many
+/// were made, how many failed, how many a second, and their latency
+/// percentiles in microseconds.
+fn csv(results: &Results, total: &OpResults, elapsed: Duration) -> String {
+    let mut csv =
+        String::from("operation,count,errors,ops_per_sec,p50_us,p90_us,p99_us,p999_us,max_us\n");
+    let rows = results
+        .iter()
+        .map(|(op, results)| (op.name(), results))
+        .chain([("total", total)]);
+    for (name, results) in rows {
+        let count = results.latencies.len();
+        write!(
+            csv,
+            "{},{},{},{:.0}",
+            name,
+            count,
+            results.errors,
+            count as f64 / elapsed.as_secs_f64()
+        )
+        .unwrap();
+        for p in PERCENTILES {
+            write!(csv, ",{}", percentile(&results.latencies, p).as_micros()).unwrap();
+        }
+        csv.push('\n');
+    }
+    csv
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let options = Options::parse(std::env::args().skip(1))?;
+    println!("{:?}", options);
+    let workload = options.workload();
+    let zipfian = match workload.distribution {
+        Distribution::Uniform => None,
+        _ => Some(Zipfian::new(options.keys, options.zipf_theta)),
+    };
+    let inserted = Arc::new(AtomicU64::new(options.keys));
+
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for id in 0..options.connections {
+        let requests = options.requests / options.connections
+            + usize::from(id < options.requests % options.connections);
+        let keys = Keys {
+            distribution: workload.distribution,
+            zipfian: zipfian.clone(),
+            inserted: inserted.clone(),
+        };
+        tasks.push(tokio::spawn(run_connection(
+            options.clone(),
+            workload.clone(),
+            keys,
+            id,
+            requests,
+        )));
+    }
+    let mut results = Results::new();
+    for task in tasks {
+        for (op, mut connection) in task.await?? {
+            let op = results.entry(op).or_default();
+            op.latencies.append(&mut connection.latencies);
+            op.errors += connection.errors;
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let mut total = OpResults::default();
+    for op in results.values_mut() {
+        op.latencies.sort_unstable();
+        total.latencies.extend_from_slice(&op.latencies);
+        total.errors += op.errors;
+    }
+    total.latencies.sort_unstable();
+    let count = total.latencies.len();
+    println!(
+        "{} requests in {:.2?}, {:.0} req/s, {} errors",
+        count,
+        elapsed,
+        count as f64 / elapsed.as_secs_f64(),
+        total.errors
+    );
+    for p in PERCENTILES {
+        println!("p{:<5} {:.3?}", p, percentile(&total.latencies, p));
+    }
+    if results.len() > 1 {
+        for (op, results) in &results {
+            println!(
+                "{}: {} requests, {} errors, p50 {:.3?}, p99 {:.3?}",
+                op.name(),
+                results.latencies.len(),
+                results.errors,
+                percentile(&results.latencies, 50.0),
+                percentile(&results.latencies, 99.0)
+            );
+        }
+    }
+    if let Some(path) = &options.csv {
+        std::fs::write(path, csv(&results, &total, elapsed))
+            .with_context(|| format!("writing {}", path))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Options> {
+        Options::parse(args.iter().map(|arg| arg.to_string()))
+    }
+
+    #[test]
+    fn workloads_parse_to_their_ycsb_profiles() {
+        let workload = parse(&["--workload", "d"]).unwrap().workload();
+        assert_eq!(workload.mix, [(Op::Read, 0.95), (Op::Insert, 0.05)]);
+        assert_eq!(workload.distribution, Distribution::Latest);
+
+        let options = parse(&["--workload", "e", "--distribution", "uniform"]).unwrap();
+        let workload = options.workload();
+        assert_eq!(workload.mix, [(Op::Scan, 0.95), (Op::Insert, 0.05)]);
+        assert_eq!(workload.distribution, Distribution::Uniform);
+
+        let workload = parse(&["--read-ratio", "0.25"]).unwrap().workload();
+        assert_eq!(workload.mix, [(Op::Read, 0.25), (Op::Update, 0.75)]);
+        assert_eq!(workload.distribution, Distribution::Uniform);
+    }
+
+    #[test]
+    fn unknown_profiles_are_refused() {
+        let err = parse(&["--workload", "g"]).unwrap_err();
+        assert!(err.to_string().contains("--workload must be"), "{}", err);
+        let err = parse(&["--distribution", "hotspot"]).unwrap_err();
+        assert!(
+            err.to_string().contains("--distribution must be"),
+            "{}",
+            err
+        );
+        let err = parse(&["--profile", "a"]).unwrap_err();
+        assert!(
+            err.to_string().contains("unknown flag --profile"),
+            "{}",
+            err
+        );
+        let err = parse(&["--workload"]).unwrap_err();
+        assert!(err.to_string().contains("expects a value"), "{}", err);
+    }
+
+    #[test]
+    fn zipfian_ranks_favour_the_first() {
+        let zipfian = Zipfian::new(1000, 0.99);
+        let mut rng = Rng(42);
+        let mut counts = vec![0; 1000];
+        for _ in 0..100_000 {
+            counts[zipfian.next(&mut rng) as usize] += 1;
+        }
+        // Rank 0 alone is about 1 / zeta(1000), some 13% of picks, and the
+        // top 10 nearly 40%, where uniform picks would give them 1%.
+        assert!(counts[0] > 10_000, "{:?}", &counts[..10]);
+        assert!(counts[0] > counts[1] && counts[1] > counts[9]);
+        let top: u32 = counts[..10].iter().sum();
+        assert!(top > 30_000, "{}", top);
+        let tail: u32 = counts[500..].iter().sum();
+        assert!(tail < top / 2, "{} in the tail", tail);
+
+        // The same seed picks the same ranks.
+        let (mut a, mut b) = (Rng(7), Rng(7));
+        let ranks = |rng: &mut Rng| (0..100).map(|_| zipfian.next(rng)).collect::<Vec<_>>();
+        assert_eq!(ranks(&mut a), ranks(&mut b));
+    }
+
+    #[test]
+    fn csv_has_a_row_per_operation_and_the_total() {
+        let mut results = Results::new();
+        results.insert(
+            Op::Read,
+            OpResults {
+                latencies: vec![100, 200, 300, 400],
+                errors: 1,
+            },
+        );
+        results.insert(
+            Op::Update,
+            OpResults {
+                latencies: vec![1000],
+                errors: 0,
+            },
+        );
+        let total = OpResults {
+            latencies: vec![100, 200, 300, 400, 1000],
+            errors: 1,
+        };
+        let csv = csv(&results, &total, Duration::from_secs(1));
+        assert_eq!(
+            csv.lines().collect::<Vec<_>>(),
+            [
+                "operation,count,errors,ops_per_sec,p50_us,p90_us,p99_us,p999_us,max_us",
+                "read,4,1,4,300,400,400,400,400",
+                "update,1,0,1,1000,1000,1000,1000,1000",
+                "total,5,1,5,300,1000,1000,1000,1000",
+            ]
+        );
+    }
+}
@@ -0,0 +1,327 @@
+//! Scans a dist-kv log and reports what is in it and anything wrong with it.
+//!
+//! ```text
+//! dist-kv-check [--records | --dump] <log>
+//! ```
+//!
+//! The log is read as recovery reads it: the segments its manifest names, one
+//! after the other, or the file itself if it has no manifest. Offsets are
+//! into the segments taken together.
+//!
+//! By default only a summary and any problems are printed. `--records` adds
+//! one line per record with its offset, length, command and key; `--dump`
+//! prints every record as a readable command instead. The exit status is 1 if
+//! the log holds records that can't be parsed or whose framing is broken, or
+//! request IDs out of order: each leader logs its writes under IDs of the form
+//! `<node>-<lsn>` with the LSN going up, so one that doesn't go past the last
+//! from the same node means records were lost, repeated or reordered. A torn
+//! record at the very end is only a warning, since it is what a crash in the
+//! middle of an append leaves behind and recovery cuts it off.
+//!
+//! A snapshot is recognised by its header, which is printed and validated
+//! along with the entries in place of a log summary.
+
+use std::collections::{HashMap, HashSet};
+use std::process::ExitCode;
+
+use anyhow::{bail, Result};
+use dist_kv::node::{parse_request_id, request_marker};
+use dist_kv::protocol::{ClusterCommand, Command, ConfigCommand, ParseError};
+use dist_kv::snapshot;
+use dist_kv::store::DEFAULT_NAMESPACE;
+use dist_kv::wal::{read_segments, Entry, LogReader};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Summary,
+    Records,
+    Dump,
+}
+
+fn parse_args(args: impl Iterator<Item = String>) -> Result<(Mode, String)> {
+    let mut mode = Mode::Summary;
+    let mut path = None;
+    for arg in args {
+        match arg.as_str() {
+            "--records" => mode = Mode::Records,
+            "--dump" => mode = Mode::Dump,
+            flag if flag.starts_with("--") => bail!("unknown flag {}", flag),
+            _ if path.is_some() => bail!("expected a single log"),
+            _ => path = Some(arg),
+        }
+    }
+    match path {
+        Some(path) => Ok((mode, path)),
+        None => bail!("usage: dist-kv-check [--records | --dump] <log>"),
+    }
+}
+
+fn quote(bytes: &[u8]) -> String {
+    format!("{:?}", String::from_utf8_lossy(bytes))
+}
+
+/// The key and, for writes, the value a command carries.
+fn args<'a>(command: &'a Command) -> (Option<&'a [u8]>, Option<&'a [u8]>) {
+    match command {
+        Command::Set(key, val) | Command::SetWith(key, val, _) | Command::Ephemeral(key, val) => {
+            (Some(key), Some(val))
+        }
+        Command::Get(key)
+        | Command::Delete(key)
+        | Command::SetStream(key)
+        | Command::GetStream(key, _)
+        | Command::GetRange(key, ..)
+        | Command::SetBit(key, ..)
+        | Command::GetBit(key, _)
+        | Command::GetAt(key, _)
+        | Command::GetWith(key, _)
+        | Command::GetVersion(key, _)
+        | Command::History(key, _)
+        | Command::BitCount(key, _)
+        | Command::PfCount(key)
+        | Command::LPop(key)
+        | Command::LLen(key)
+        | Command::BLPop(key, _)
+        | Command::XRead(key, ..)
+        | Command::ExpireAt(key, _)
+        | Command::PExpireAt(key, _)
+        | Command::Persist(key)
+        | Command::Ttl(key)
+        | Command::Lock(key, _)
+        | Command::Unlock(key, _)
+        | Command::WaitChange(key, _)
+        | Command::Object(_, key) => (Some(key), None),
+        Command::PfAdd(key, element)
+        | Command::LPush(key, element)
+        | Command::RPush(key, element)
+        | Command::XAdd(key, _, element) => (Some(key), Some(element)),
+        Command::DelIfEq(key, expected) => (Some(key), Some(expected)),
+        Command::Dump(key) => (Some(key), None),
+        Command::Restore(key, dump, _) => (Some(key), Some(dump)),
+        Command::PfMerge(dest, src) => (Some(dest), Some(src)),
+        Command::SetRange(key, _, val) => (Some(key), Some(val)),
+        Command::Subscribe(channel)
+        | Command::Unsubscribe(channel)
+        | Command::PSubscribe(channel)
+        | Command::PUnsubscribe(channel) => (Some(channel), None),
+        Command::Publish(channel, message) => (Some(channel), Some(message)),
+        Command::Info(section) => (section.as_deref(), None),
+        Command::Replicate(leader) => (leader.as_deref(), None),
+        Command::Install(snapshot) => (None, Some(snapshot)),
+        Command::Cluster(ClusterCommand::AddNode(addr) | ClusterCommand::RemoveNode(addr))
+        | Command::TransferLeadership(addr) => (Some(addr), None),
+        Command::RequestId(id) => (Some(id), None),
+        Command::Config(ConfigCommand::Get(name)) => (Some(name), None),
+        Command::Config(ConfigCommand::Set(name, val)) => (Some(name), Some(val)),
+        Command::Select(name) => (Some(name), None),
+        Command::Auth(user, _) => (Some(user), None),
+        Command::Eval(script, ..) => (Some(script), None),
+        Command::FCall(function, ..) => (Some(function), None),
+        Command::In(_, command) => args(command),
+        Command::Sync(..)
+        | Command::Tail(_)
+        | Command::Hello(..)
+        | Command::WhoAmI
+        | Command::WhoIsLeader
+        | Command::Peers
+        | Command::ReadOnly(_)
+        | Command::Client(_)
+        | Command::Analyze(_)
+        | Command::Export(..)
+        | Command::RandomKey
+        | Command::Sample(_)
+        | Command::BulkLoad
+        | Command::BulkLoadEnd
+        | Command::Quota
+        | Command::DumpState
+        | Command::Debug(_)
+        | Command::Batch(_) => (None, None),
+    }
+}
+
+#[derive(Default)]
+struct Summary {
+    records: usize,
+    sets: usize,
+    deletes: usize,
+    other: usize,
+    corrupt: usize,
+    /// Request IDs whose LSN didn't go past the last from the same node.
+    out_of_order: usize,
+    /// The keys set and not since deleted, with the namespace they are in.
+    live: HashSet<(Vec<u8>, Vec<u8>)>,
+    /// The last LSN logged by each node.
+    lsns: HashMap<Vec<u8>, u64>,
+}
+
+impl Summary {
+    /// Checks that a request ID goes past the last one from the same node,
+    /// returning the LSN it follows if it doesn't.
+    fn order(&mut self, id: &[u8]) -> Option<u64> {
+        let (node, lsn) = parse_request_id(id)?;
+        match self.lsns.insert(node.to_vec(), lsn) {
+            Some(last) if last >= lsn => {
+                self.out_of_order += 1;
+                Some(last)
+            }
+            _ => None,
+        }
+    }
+
+    fn count(&mut self, namespace: &[u8], command: &Command) {
+        match command {
+            Command::Set(key, _) => {
+                self.sets += 1;
+                self.live.insert((namespace.to_vec(), key.to_vec()));
+            }
+            Command::Delete(key) => {
+                self.deletes += 1;
+                self.live.remove(&(namespace.to_vec(), key.to_vec()));
+            }
+            Command::Batch(commands) => {
+                for command in commands {
+                    self.count(namespace, command);
+                }
+            }
+            Command::In(name, command) => self.count(name, command),
+            _ => self.other += 1,
+        }
+    }
+}
+
+/// The command as `--dump` prints it, with a batch's writes on the lines
+/// after it.
+fn dump(command: &Command) -> String {
+    if let Command::In(name, command) = command {
+        return format!("IN {} {}", quote(name), dump(command));
+    }
+    let (key, val) = args(command);
+    let args = [key, val].into_iter().flatten().map(quote);
+    let mut line = std::iter::once(command.name().to_string())
+        .chain(args)
+        .collect::<Vec<_>>()
+        .join(" ");
+    if let Command::Batch(commands) = command {
+        for command in commands {
+            line.push_str(&format!("\n  {}", dump(command)));
+        }
+    }
+    line
+}
+
+fn check_snapshot(path: &str, bytes: &[u8]) -> ExitCode {
+    let header = match snapshot::read_header(bytes) {
+        Ok(header) => header,
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            return ExitCode::FAILURE;
+        }
+    };
+    println!(
+        "{}: snapshot version {}, lsn {}, {} byte body, checksum {:08x}",
+        path, header.version, header.lsn, header.len, header.checksum
+    );
+    match snapshot::decode(bytes) {
+        Ok(snapshot) => {
+            println!("{} keys", snapshot.hashmap.len());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("{}: {}", path, e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn main() -> Result<ExitCode> {
+    let (mode, path) = parse_args(std::env::args().skip(1))?;
+    let Some(log) = read_segments(&path)? else {
+        bail!("{} does not exist", path);
+    };
+    if log.starts_with(snapshot::MAGIC) {
+        return Ok(check_snapshot(&path, &log));
+    }
+
+    let mut summary = Summary::default();
+    let mut failed = false;
+    for entry in LogReader::new(&log) {
+        match entry {
+            Entry::Record {
+                command: Err(ParseError::Empty),
+                ..
+            } => {}
+            Entry::Record {
+                offset,
+                len,
+                command: Ok(command),
+            } => {
+                summary.records += 1;
+                summary.count(DEFAULT_NAMESPACE, &command);
+                if let Some(id) = request_marker(&command) {
+                    if let Some(last) = summary.order(id) {
+                        failed = true;
+                        eprintln!(
+                            "offset {}: request {} is out of order, after LSN {}",
+                            offset,
+                            String::from_utf8_lossy(id),
+                            last
+                        );
+                    }
+                }
+                let (key, val) = args(&command);
+                match mode {
+                    Mode::Summary => {}
+                    Mode::Records => {
+                        let mut line = format!("{:>10} {:>8} {:<9}", offset, len, command.name());
+                        if let Some(key) = key {
+                            line.push_str(&format!(" {}", quote(key)));
+                        }
+                        if let Some(val) = val {
+                            line.push_str(&format!(" ({} bytes)", val.len()));
+                        }
+                        println!("{}", line);
+                    }
+                    Mode::Dump => println!("{}", dump(&command)),
+                }
+            }
+            Entry::Record {
+                offset,
+                command: Err(err),
+                ..
+            } => {
+                summary.corrupt += 1;
+                failed = true;
+                eprintln!("offset {}: corrupt record: {}", offset, err);
+            }
+            Entry::Torn { offset, len } => {
+                eprintln!(
+                    "offset {}: warning: log ends in a torn record of {} bytes",
+                    offset, len
+                );
+            }
+            Entry::Unframeable { offset, err } => {
+                summary.corrupt += 1;
+                failed = true;
+                eprintln!("offset {}: corrupt framing: {}", offset, err);
+            }
+        }
+    }
+
+    println!(
+        "{}: {} bytes, {} records ({} SET, {} DEL, {} other), {} corrupt, {} out of order, {} keys live",
+        path,
+        log.len(),
+        summary.records,
+        summary.sets,
+        summary.deletes,
+        summary.other,
+        summary.corrupt,
+        summary.out_of_order,
+        summary.live.len()
+    );
+    Ok(if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    })
+}
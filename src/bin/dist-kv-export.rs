@@ -0,0 +1,67 @@
+//! Writes a dist-kv leader's keys to stdout as CSV or NDJSON.
+//!
+//! ```text
+//! dist-kv-export [--addr host:port] [--format csv|ndjson]
+//!                [--namespace NAME] [--prefix PREFIX]
+//! ```
+//!
+//! The keys are written as the leader streams them, so an export of any
+//! size runs in little memory; see [`dist_kv::export`] for the formats.
+
+use anyhow::{bail, Context, Result};
+use dist_kv::client::DistKvClient;
+use dist_kv::protocol::ExportFormat;
+use tokio::io::AsyncWriteExt;
+
+struct Options {
+    addr: String,
+    format: ExportFormat,
+    namespace: Option<String>,
+    prefix: Option<String>,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Options> {
+        let mut options = Options {
+            addr: "localhost:47000".to_string(),
+            format: ExportFormat::Csv,
+            namespace: None,
+            prefix: None,
+        };
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .with_context(|| format!("{} expects a value", flag))?;
+            match flag.as_str() {
+                "--addr" => options.addr = value,
+                "--format" => {
+                    options.format = match value.as_str() {
+                        "csv" => ExportFormat::Csv,
+                        "ndjson" => ExportFormat::Ndjson,
+                        _ => bail!("--format must be csv or ndjson"),
+                    }
+                }
+                "--namespace" => options.namespace = Some(value),
+                "--prefix" => options.prefix = Some(value),
+                _ => bail!("unknown flag {}", flag),
+            }
+        }
+        Ok(options)
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let options = Options::parse(std::env::args().skip(1))?;
+    let mut client = DistKvClient::connect(&options.addr)
+        .await
+        .with_context(|| format!("connecting to {}", options.addr))?;
+    if let Some(namespace) = &options.namespace {
+        client.select(namespace.as_bytes()).await?;
+    }
+    let mut stdout = tokio::io::stdout();
+    let prefix = options.prefix.as_ref().map(|prefix| prefix.as_bytes());
+    client.export(options.format, prefix, &mut stdout).await?;
+    stdout.flush().await?;
+    Ok(())
+}
@@ -0,0 +1,69 @@
+//! Loads a Redis dataset into a dist-kv leader.
+//!
+//! ```text
+//! dist-kv-import [--addr host:port] [--pipeline N] <file>
+//! ```
+//!
+//! `file` is an RDB dump or a RESP stream, such as an append-only file; see
+//! [`dist_kv::import`] for what is read from each. The whole file is read
+//! before anything is sent, so a file that can't be imported leaves the
+//! leader untouched, but an import refused partway through by the leader
+//! leaves the writes before the refused one applied.
+
+use anyhow::{bail, Context, Result};
+use dist_kv::client::DistKvClient;
+use dist_kv::import;
+
+struct Options {
+    addr: String,
+    pipeline: usize,
+    path: String,
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Options> {
+        let mut addr = "localhost:47000".to_string();
+        let mut pipeline = 256;
+        let mut path = None;
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--addr" | "--pipeline" => {
+                    let value = args
+                        .next()
+                        .with_context(|| format!("{} expects a value", arg))?;
+                    match arg.as_str() {
+                        "--addr" => addr = value,
+                        _ => pipeline = value.parse()?,
+                    }
+                }
+                _ if arg.starts_with("--") => bail!("unknown flag {}", arg),
+                _ if path.is_none() => path = Some(arg),
+                _ => bail!("usage: dist-kv-import [--addr host:port] [--pipeline N] <file>"),
+            }
+        }
+        if pipeline == 0 {
+            bail!("--pipeline must be positive");
+        }
+        let Some(path) = path else {
+            bail!("usage: dist-kv-import [--addr host:port] [--pipeline N] <file>");
+        };
+        Ok(Options {
+            addr,
+            pipeline,
+            path,
+        })
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let options = Options::parse(std::env::args().skip(1))?;
+    let data = std::fs::read(&options.path).with_context(|| format!("reading {}", options.path))?;
+    let commands = import::read(&data).with_context(|| format!("reading {}", options.path))?;
+    let mut client = DistKvClient::connect(&options.addr)
+        .await
+        .with_context(|| format!("connecting to {}", options.addr))?;
+    let sent = import::load(&mut client, &commands, options.pipeline).await?;
+    println!("{}: imported {} commands", options.path, sent);
+    Ok(())
+}
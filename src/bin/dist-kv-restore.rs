@@ -0,0 +1,43 @@
+//! Rebuilds a node's log from an archive made by the leader's archiver.
+//!
+//! ```text
+//! dist-kv-restore <location> <log>
+//! ```
+//!
+//! `location` is the archive's directory or `http://` bucket URL, as given
+//! to the `archive` setting. The log is written from the archive's last
+//! snapshot and the segments after it, and a node started on it comes up
+//! with the map as of the last segment. An existing log is never
+//! overwritten. The log is written to a temporary file and linked into place
+//! once synced, so a restore cut short leaves no log rather than part of one.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+
+use anyhow::{bail, Context, Result};
+use dist_kv::archive::{open_store, restore};
+use dist_kv::manifest::sync_dir;
+
+fn main() -> Result<()> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let [location, path] = &args[..] else {
+        bail!("usage: dist-kv-restore <location> <log>");
+    };
+    let log = restore(&mut *open_store(location)?)?;
+    let temp = format!("{}.tmp", path);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp)
+        .with_context(|| format!("creating {}", temp))?;
+    file.write_all(&log)?;
+    file.sync_all()?;
+    // Unlike a rename, linking fails if the log exists.
+    let linked = fs::hard_link(&temp, path).with_context(|| format!("creating {}", path));
+    fs::remove_file(&temp)?;
+    linked?;
+    sync_dir(path)?;
+    println!("{}: restored {} bytes of log", path, log.len());
+    Ok(())
+}
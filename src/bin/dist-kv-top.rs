@@ -0,0 +1,218 @@
+//! A live view of a dist-kv node in the terminal, in the manner of `top`.
+//!
+//! ```text
+//! dist-kv-top [--addr host:port] [--interval SECS] [--keys N]
+//! ```
+//!
+//! Every interval it reads the node's `INFO` and redraws commands per
+//! second, how far each follower lags, what memory goes to and the keys
+//! written most often. Commands per second are the change in the calls
+//! `INFO commandstats` counts; a follower's lag is the bytes of records
+//! queued for it. The hottest keys are counted from a `TAIL` of the
+//! node's writes since the view started, so reads don't count towards
+//! them, and a node that doesn't serve `TAIL` shows none. Ctrl-C quits.
+
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use dist_kv::client::{DistKvClient, Reply};
+use dist_kv::protocol::Command;
+use dist_kv::store::DEFAULT_NAMESPACE;
+
+#[derive(Debug, Clone)]
+struct Options {
+    addr: String,
+    interval: Duration,
+    keys: usize,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            addr: "localhost:47000".to_string(),
+            interval: Duration::from_secs(1),
+            keys: 10,
+        }
+    }
+}
+
+impl Options {
+    fn parse(mut args: impl Iterator<Item = String>) -> Result<Options> {
+        let mut options = Options::default();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .with_context(|| format!("{} expects a value", flag))?;
+            match flag.as_str() {
+                "--addr" => options.addr = value,
+                "--interval" => options.interval = Duration::from_secs_f64(value.parse()?),
+                "--keys" => options.keys = value.parse()?,
+                _ => bail!("unknown flag {}", flag),
+            }
+        }
+        if options.interval.is_zero() {
+            bail!("--interval must be positive");
+        }
+        Ok(options)
+    }
+}
+
+/// How often each key has been written, by `namespace:key`, or just `key`
+/// in the default namespace.
+type WriteCounts = Arc<Mutex<HashMap<String, u64>>>;
+
+/// Follows the node's writes from `from` on, counting the keys they touch.
+/// Gives up quietly if the node won't serve a tail.
+async fn count_writes(addr: String, from: u64, counts: WriteCounts) -> Result<()> {
+    let mut client = DistKvClient::connect(&addr).await?;
+    client.tail(from).await?;
+    loop {
+        let push = client.next_push().await?;
+        // change <lsn> <time> <op> <namespace> <key> [value]
+        if let (Some(namespace), Some(key)) = (push.get(4), push.get(5)) {
+            let name = match &namespace[..] {
+                DEFAULT_NAMESPACE => String::from_utf8_lossy(key).into_owned(),
+                _ => format!(
+                    "{}:{}",
+                    String::from_utf8_lossy(namespace),
+                    String::from_utf8_lossy(key)
+                ),
+            };
+            *counts.lock().unwrap().entry(name).or_default() += 1;
+        }
+    }
+}
+
+/// `INFO`'s `name:value` lines, by name.
+fn parse_info(info: &str) -> BTreeMap<&str, &str> {
+    info.lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split_once(':'))
+        .collect()
+}
+
+/// The calls counted across every command in `INFO commandstats`.
+fn total_calls(fields: &BTreeMap<&str, &str>) -> u64 {
+    fields
+        .iter()
+        .filter(|(name, _)| name.starts_with("cmdstat_"))
+        .filter_map(|(_, stats)| {
+            stats
+                .split(',')
+                .find_map(|field| field.strip_prefix("calls="))
+                .and_then(|calls| calls.parse::<u64>().ok())
+        })
+        .sum()
+}
+
+/// One `follower_<i>:addr=..,state=..` line's fields, by name.
+fn follower_fields(line: &str) -> BTreeMap<&str, &str> {
+    line.split(',')
+        .filter_map(|field| field.split_once('='))
+        .collect()
+}
+
+fn render(
+    options: &Options,
+    fields: &BTreeMap<&str, &str>,
+    ops_per_sec: f64,
+    hottest: &[(String, u64)],
+) -> String {
+    let field = |name: &str| fields.get(name).copied().unwrap_or("-");
+    let mut screen = String::from("\x1b[2J\x1b[H");
+    screen.push_str(&format!(
+        "dist-kv-top {}  every {:.1}s, Ctrl-C to quit\n\n",
+        options.addr,
+        options.interval.as_secs_f64()
+    ));
+    screen.push_str(&format!(
+        "ops/sec {:>10.1}   lsn {:>10}   keys {:>10}   expires {:>8}\n",
+        ops_per_sec,
+        field("lsn"),
+        field("keys"),
+        field("expires")
+    ));
+    screen.push_str(&format!(
+        "rss {:>14}   keyspace {:>14}   queued for followers {:>12}\n\n",
+        field("rss_bytes"),
+        field("keyspace_bytes"),
+        field("replication_queued_bytes")
+    ));
+
+    screen.push_str(&format!(
+        "{:<24} {:<12} {:>14} {:>16}\n",
+        "FOLLOWER", "STATE", "LAG (BYTES)", "SNAPSHOT BACKLOG"
+    ));
+    let followers = fields
+        .iter()
+        .filter(|(name, _)| name.starts_with("follower_"))
+        .map(|(_, line)| follower_fields(line));
+    for follower in followers {
+        let field = |name: &str| follower.get(name).copied().unwrap_or("-");
+        screen.push_str(&format!(
+            "{:<24} {:<12} {:>14} {:>16}\n",
+            field("addr"),
+            field("state"),
+            field("queued"),
+            field("transfer_backlog")
+        ));
+    }
+
+    screen.push_str(&format!("\n{:<10} HOTTEST KEYS (WRITES)\n", "COUNT"));
+    for (key, count) in hottest {
+        screen.push_str(&format!("{:<10} {}\n", count, key));
+    }
+    screen
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let options = Options::parse(std::env::args().skip(1))?;
+    let mut client = DistKvClient::connect(&options.addr)
+        .await
+        .with_context(|| format!("connecting to {}", options.addr))?;
+
+    let counts = WriteCounts::default();
+    let mut tail = None;
+    let mut last: Option<(Instant, u64)> = None;
+    let mut interval = tokio::time::interval(options.interval);
+    loop {
+        interval.tick().await;
+        let info = match client.call(&Command::Info(None)).await? {
+            Reply::Bulk(Some(info)) => String::from_utf8_lossy(&info).into_owned(),
+            Reply::Error(err) => bail!("INFO failed: {}", err),
+            reply => bail!("unexpected reply to INFO: {:?}", reply),
+        };
+        let fields = parse_info(&info);
+
+        if tail.is_none() {
+            let from = fields.get("lsn").and_then(|lsn| lsn.parse::<u64>().ok());
+            let (addr, counts) = (options.addr.clone(), counts.clone());
+            tail = Some(tokio::spawn(count_writes(
+                addr,
+                from.unwrap_or_default() + 1,
+                counts,
+            )));
+        }
+
+        let calls = total_calls(&fields);
+        let ops_per_sec = match last {
+            Some((at, before)) => calls.saturating_sub(before) as f64 / at.elapsed().as_secs_f64(),
+            None => 0.0,
+        };
+        last = Some((Instant::now(), calls));
+
+        let mut hottest: Vec<(String, u64)> = counts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(key, count)| (key.clone(), *count))
+            .collect();
+        hottest.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        hottest.truncate(options.keys);
+
+        print!("{}", render(&options, &fields, ops_per_sec, &hottest));
+    }
+}
@@ -0,0 +1,112 @@
+//! Change data capture: the leader's committed writes, streamed to `TAIL`
+//! connections in log order.
+//!
+//! `TAIL <from_lsn>` is answered with `+OK` and then a push for every change
+//! made at or after that LSN: `>7` followed by the bulk strings `change`,
+//! the LSN, the Unix time in milliseconds it was committed at, the
+//! operation, the namespace, the key and the value, which is empty for a
+//! `del` and holds the time for a `pexpireat`. Every change a write made
+//! shares its LSN, so a consumer that has seen all of LSN `n` resumes
+//! exactly once with `TAIL n+1`.
+//!
+//! The leader keeps the last [`BACKLOG`] writes to serve tails from. Asking
+//! for anything older, including writes made before the leader last
+//! started, is an error; the consumer has to start over from a snapshot.
+
+use std::collections::VecDeque;
+
+use bytes::{Bytes, BytesMut};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::protocol::{parse_all, Command, ErrorCode, ErrorReply, Limits};
+use crate::pubsub::push;
+use crate::store::DEFAULT_NAMESPACE;
+
+/// How many writes the leader keeps for tails to catch up from.
+pub const BACKLOG: usize = 10_000;
+
+/// A committed write: its LSN and the pushes that describe it.
+struct Change {
+    lsn: u64,
+    pushes: Bytes,
+}
+
+#[derive(Default)]
+pub struct ChangeLog {
+    backlog: VecDeque<Change>,
+    tails: Vec<UnboundedSender<Bytes>>,
+}
+
+impl ChangeLog {
+    /// Records the write `record` committed as `lsn` at Unix time `time`
+    /// in milliseconds, and sends it to every tail.
+    pub fn push(&mut self, lsn: u64, time: u64, record: &[u8]) {
+        let mut pushes = BytesMut::new();
+        for command in parse_all(record, &Limits::NONE).unwrap_or_default() {
+            encode(&mut pushes, lsn, time, DEFAULT_NAMESPACE, &command);
+        }
+        let pushes = pushes.freeze();
+        self.tails.retain(|tail| tail.send(pushes.clone()).is_ok());
+        if self.backlog.len() == BACKLOG {
+            self.backlog.pop_front();
+        }
+        self.backlog.push_back(Change { lsn, pushes });
+    }
+
+    /// Sends `tail` every change from `from` on, and then every new one.
+    /// `next` is the LSN the next write will get.
+    pub fn tail(
+        &mut self,
+        from: u64,
+        next: u64,
+        tail: UnboundedSender<Bytes>,
+    ) -> Result<(), ErrorReply> {
+        let oldest = self.backlog.front().map_or(next, |change| change.lsn);
+        if from < oldest {
+            let message = format!(
+                "lsn {} is no longer available, the oldest is {}",
+                from, oldest
+            );
+            return Err(ErrorReply::new(ErrorCode::WrongArgs, message));
+        }
+        for change in self.backlog.iter().filter(|change| change.lsn >= from) {
+            let _ = tail.send(change.pushes.clone());
+        }
+        self.tails.push(tail);
+        Ok(())
+    }
+
+    /// How many writes are kept for tails, the bytes their pushes take up,
+    /// and how many tails there are.
+    pub fn stats(&self) -> (usize, usize, usize) {
+        let bytes = self.backlog.iter().map(|change| change.pushes.len()).sum();
+        (self.backlog.len(), bytes, self.tails.len())
+    }
+}
+
+fn encode(buf: &mut BytesMut, lsn: u64, time: u64, namespace: &[u8], command: &Command<'_>) {
+    let (key, val): (&[u8], Bytes) = match command {
+        Command::Batch(commands) => {
+            for command in commands {
+                encode(buf, lsn, time, namespace, command);
+            }
+            return;
+        }
+        Command::In(name, command) => return encode(buf, lsn, time, name, command),
+        Command::Set(key, val) => (key, Bytes::copy_from_slice(val)),
+        Command::Delete(key) | Command::Persist(key) => (key, Bytes::new()),
+        Command::PExpireAt(key, at) => (key, Bytes::from(at.to_string())),
+        _ => return,
+    };
+    let op = command.name().to_ascii_lowercase();
+    let (lsn, time) = (lsn.to_string(), time.to_string());
+    buf.extend_from_slice(&push(&[
+        b"change",
+        lsn.as_bytes(),
+        time.as_bytes(),
+        op.as_bytes(),
+        namespace,
+        key,
+        &val,
+    ]));
+}
@@ -0,0 +1,270 @@
+//! Checkpoints of the leader's map, so that a restart replays only the log
+//! written since the last one rather than the whole of it.
+//!
+//! A checkpoint is kept next to the log it was taken against, at
+//! `<log>.checkpoint.<generation>`, and only counts once the log's
+//! [`manifest`](crate::manifest) names it. It starts with the magic bytes `DKVCKPT\0`, the `u64`
+//! length the log was when it was taken and the CRC-32 of up to
+//! [`TAIL_LEN`] bytes of the log before that point, followed by a
+//! [`snapshot`] of the map. Opening the log loads the checkpoint and replays
+//! what follows that length. One that doesn't match the log, because the log
+//! is now shorter or different where the checkpoint ends, is ignored and the
+//! whole log is replayed as before.
+//!
+//! The leader takes a checkpoint every `checkpoint_interval` seconds. The
+//! map is encoded under the leader's lock and written out on a blocking
+//! thread to a new file, which the manifest is switched to once it is
+//! synced. The checkpoint it replaces is removed after that, so a crash
+//! leaves either the old or the new one in use.
+//!
+//! This is also how the log is kept from growing without bound. Once the
+//! segment being appended to reaches `log_segment_size`, taking a
+//! checkpoint first starts a new segment, so that the checkpoint covers the
+//! old ones whole and they are removed along with the checkpoint it
+//! replaces. Disk use is then bounded by the size of the map plus a segment
+//! or so rather than by every write ever made. Followers never read the
+//! leader's log, catching up from their replication queue or a new snapshot
+//! instead, so they hold no segment back. The archiver does read it, so no
+//! new segments are started while `archive` is set.
+
+use std::fs::{self, File};
+use std::os::unix::fs::FileExt;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::checksum::crc32;
+use crate::error::{bail, Context, DistKvError, Result};
+use crate::manifest::{file_name, replace_file, resolve, Manifest};
+use crate::server::SyncLeader;
+use crate::snapshot;
+use crate::store::{replay_onto, Db, LoadProgress, ReplayReport, Strictness};
+use crate::wal::{create_log_file, preallocate, LogFile};
+
+pub const MAGIC: &[u8; 8] = b"DKVCKPT\0";
+
+pub const HEADER_LEN: usize = MAGIC.len() + 8 + 4;
+
+/// How much of the log before a checkpoint's length its checksum covers.
+pub const TAIL_LEN: usize = 4096;
+
+/// A checkpoint as read back: the map and how much of the log it covers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub len: u64,
+    pub lsn: u64,
+    pub hashmap: Db,
+}
+
+/// The checksum of the last [`TAIL_LEN`] bytes of `log`.
+fn tail_checksum(log: &[u8]) -> u32 {
+    crc32(&log[log.len().saturating_sub(TAIL_LEN)..])
+}
+
+/// Encodes a checkpoint from a [`snapshot`] of the map, taken when the log
+/// was `len` bytes long and ended in `tail`.
+pub fn encode(snapshot: &[u8], len: u64, tail: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + snapshot.len());
+    buf.put_slice(MAGIC);
+    buf.put_u64(len);
+    buf.put_u32(tail_checksum(tail));
+    buf.put_slice(snapshot);
+    buf.freeze()
+}
+
+/// Reads a checkpoint, checking it against `log`: `None` if it was taken
+/// against some other log.
+pub fn decode(checkpoint: &[u8], log: &[u8]) -> Result<Option<Checkpoint>> {
+    if !checkpoint.starts_with(MAGIC) || checkpoint.len() < HEADER_LEN {
+        bail!(Corrupt, "checkpoint header is malformed");
+    }
+    let mut header = &checkpoint[MAGIC.len()..HEADER_LEN];
+    let len = header.get_u64();
+    let checksum = header.get_u32();
+    let Some(covered) = log.get(..len as usize) else {
+        return Ok(None);
+    };
+    if tail_checksum(covered) != checksum {
+        return Ok(None);
+    }
+    let snapshot = snapshot::decode(&checkpoint[HEADER_LEN..])?;
+    Ok(Some(Checkpoint {
+        len,
+        lsn: snapshot.header.lsn,
+        hashmap: snapshot.hashmap,
+    }))
+}
+
+/// Writes the checkpoint of the log at `log` from a snapshot taken when its
+/// segments held `len` bytes, and switches the manifest over to it from the
+/// last one. Segments the checkpoint covers whole are dropped from the
+/// manifest along with the last checkpoint, and their files removed, all
+/// but the segment writes are appended to.
+pub fn write(log: &str, snapshot: &[u8], len: u64) -> Result<()> {
+    let mut manifest = Manifest::load(log)?;
+    let mut segments = Vec::new();
+    for name in &manifest.segments {
+        let path = resolve(log, name);
+        let size = fs::metadata(&path)
+            .with_context(|| format!("reading log segment {}", name))?
+            .len();
+        segments.push((path, size));
+    }
+    let mut covered = 0;
+    let mut start = 0;
+    while covered + 1 < segments.len() && start + segments[covered].1 <= len {
+        start += segments[covered].1;
+        covered += 1;
+    }
+    let len = len - start;
+    let tail = read_range(
+        &segments[covered..],
+        len.saturating_sub(TAIL_LEN as u64),
+        len,
+    )?;
+    manifest.generation += 1;
+    let name = format!("{}.checkpoint.{}", file_name(log)?, manifest.generation);
+    replace_file(resolve(log, &name), &encode(snapshot, len, &tail))?;
+    let replaced = manifest.checkpoint.replace(name);
+    let dropped: Vec<_> = manifest.segments.drain(..covered).collect();
+    manifest.save(log)?;
+    remove(log, replaced.into_iter().chain(dropped));
+    Ok(())
+}
+
+/// Starts the log at `log` over from a snapshot, as a follower does when it
+/// syncs with the leader: the manifest is switched from everything the log
+/// held to a checkpoint of the snapshot and an empty segment after it, which
+/// is returned for writes to be appended to. A crash partway through leaves
+/// the old log in use, rather than the new one half written.
+pub fn install(log: &str, snapshot: &[u8]) -> Result<File> {
+    let mut manifest = Manifest::load(log)?;
+    let replaced: Vec<_> = manifest
+        .checkpoint
+        .take()
+        .into_iter()
+        .chain(manifest.segments.drain(..))
+        .collect();
+    manifest.generation += 1;
+    let segment = format!("{}.segment.{}", file_name(log)?, manifest.generation);
+    // Made durable by the directory sync after the checkpoint is written.
+    let file = create_log_file(resolve(log, &segment))?;
+    let checkpoint = format!("{}.checkpoint.{}", file_name(log)?, manifest.generation);
+    replace_file(resolve(log, &checkpoint), &encode(snapshot, 0, b""))?;
+    manifest.checkpoint = Some(checkpoint);
+    manifest.segments.push(segment);
+    manifest.save(log)?;
+    remove(log, replaced);
+    Ok(file)
+}
+
+/// Removes the files of the log at `log` that the manifest no longer names.
+fn remove(log: &str, names: impl IntoIterator<Item = String>) {
+    for name in names {
+        if let Err(e) = fs::remove_file(resolve(log, &name)) {
+            eprintln!("Failed to remove {}: {}", name, e);
+        }
+    }
+}
+
+/// Reads bytes `from..to` of a log made of `segments`, each a path and its
+/// size.
+fn read_range(segments: &[(PathBuf, u64)], from: u64, to: u64) -> Result<Vec<u8>> {
+    let mut range = Vec::with_capacity((to - from) as usize);
+    let mut offset = 0;
+    for (path, size) in segments {
+        let (start, end) = (from.max(offset), to.min(offset + size));
+        if start < end {
+            let mut buf = vec![0; (end - start) as usize];
+            File::open(path)
+                .and_then(|file| file.read_exact_at(&mut buf, start - offset))
+                .with_context(|| format!("reading {}", path.display()))?;
+            range.extend_from_slice(&buf);
+        }
+        offset += size;
+    }
+    if range.len() as u64 != to - from {
+        bail!(Corrupt, "the log is shorter than the checkpoint covers");
+    }
+    Ok(range)
+}
+
+/// Starts a new segment of `size` bytes for writes to the log at `log` to be
+/// appended to, naming it last in the manifest.
+fn roll(log: &str, mut manifest: Manifest, size: u64) -> Result<File> {
+    manifest.generation += 1;
+    let name = format!("{}.segment.{}", file_name(log)?, manifest.generation);
+    let file = create_log_file(resolve(log, &name))?;
+    preallocate(&file, size)?;
+    manifest.segments.push(name);
+    manifest.save(log)?;
+    Ok(file)
+}
+
+/// Rebuilds the map of the log at `path` from the checkpoint its manifest
+/// names, if that matches `log`, and the records after it, or from the
+/// whole log otherwise. The report's offsets are into the whole log.
+pub fn replay(
+    path: &str,
+    log: &[u8],
+    strictness: Strictness,
+    progress: impl FnMut(&LoadProgress),
+) -> Result<(Db, ReplayReport)> {
+    let checkpoint = match Manifest::load(path)?.checkpoint {
+        Some(name) => fs::read(resolve(path, &name))
+            .map_err(DistKvError::from)
+            .and_then(|checkpoint| decode(&checkpoint, log))
+            .with_context(|| format!("reading checkpoint {}", name))?,
+        None => None,
+    };
+    let Some(checkpoint) = checkpoint else {
+        return replay_onto(Db::new(), log, 0, strictness, progress);
+    };
+    let start = checkpoint.len as usize;
+    let (hashmap, mut report) = replay_onto(checkpoint.hashmap, log, start, strictness, progress)?;
+    report.lsn = report.lsn.max(checkpoint.lsn);
+    Ok((hashmap, report))
+}
+
+/// Takes a checkpoint of the leader's map, if it knows where its log is,
+/// first starting a new segment if the one being appended to has reached
+/// `log_segment_size`.
+pub async fn take(leader: &SyncLeader) -> Result<()> {
+    let (log, snapshot, len) = {
+        let mut leader = leader.lock().await;
+        let Some(log) = leader.log_path.clone() else {
+            return Ok(());
+        };
+        let manifest = Manifest::load(&log)?;
+        let earlier = &manifest.segments[..manifest.segments.len() - 1];
+        let mut len = 0;
+        for name in earlier {
+            len += fs::metadata(resolve(&log, name))?.len();
+        }
+        let appended = leader.core.wal.metadata()?.len();
+        len += appended;
+        let snapshot = snapshot::encode(&leader.core.hashmap, leader.core.lsn);
+        let segment_size = leader.config.log_segment_size as u64;
+        // The archiver reads the log from its first segment on.
+        if segment_size > 0 && appended >= segment_size && leader.config.archive.is_none() {
+            let file = roll(&log, manifest, segment_size).context("starting a new log segment")?;
+            leader.core.wal = LogFile::open(file, leader.config.wal_backend)?;
+        }
+        (log, snapshot, len)
+    };
+    tokio::task::spawn_blocking(move || write(&log, &snapshot, len))
+        .await
+        .map_err(DistKvError::storage)?
+}
+
+/// Checkpoints the leader's map every `interval`. Failures are reported and
+/// retried on the next round.
+pub async fn run(leader: SyncLeader, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        if let Err(e) = take(&leader).await {
+            eprintln!("Failed to checkpoint the map: {:?}", e);
+        }
+    }
+}
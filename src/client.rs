@@ -0,0 +1,1341 @@
+use std::collections::{BTreeSet, HashMap};
+use std::future::Future;
+use std::io;
+use std::net::SocketAddr;
+use std::ops::Range;
+use std::pin::pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+
+use bytes::{Buf, Bytes, BytesMut};
+use tokio::io::{AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{TcpStream, ToSocketAddrs};
+
+use crate::discovery::Peers;
+use crate::error::{DistKvError, Result};
+use crate::protocol::{
+    ClientCommand, ClusterCommand, Command, ConfigCommand, Consistency, DebugCommand, ExportFormat,
+    ObjectField, SetOptions, PROTOCOL_VERSION,
+};
+use crate::store::DEFAULT_NAMESPACE;
+use crate::stream::StreamId;
+use crate::transport::{Connection, Tcp, Transport};
+
+/// A reply as sent by the server: `+<status>`, `-ERR ...`, `:<n>`, a
+/// `$<len>` bulk value, with `$-1` standing for a missing key, or a `><n>`
+/// push of `n` bulk values sent to subscribers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Bytes>),
+    Push(Vec<Bytes>),
+}
+
+/// Finds the `$<len>` bulk value starting at `pos`, returning where its
+/// payload lies and where the next reply starts, or `None` until all of it
+/// has been buffered.
+fn bulk_at(buf: &[u8], pos: usize) -> Result<Option<(Range<usize>, usize)>> {
+    let Some(end) = buf[pos..].iter().position(|b| *b == b'\n').map(|i| pos + i) else {
+        return Ok(None);
+    };
+    let line = buf[pos..end].strip_suffix(b"\r").unwrap_or(&buf[pos..end]);
+    let Some(len) = line.strip_prefix(b"$") else {
+        return Err(malformed("expected a bulk value", line));
+    };
+    let len: usize = String::from_utf8_lossy(len)
+        .parse()
+        .map_err(|_| malformed("invalid bulk length", line))?;
+    let start = end + 1;
+    let terminator = match buf.get(start + len..) {
+        None | Some([]) | Some([b'\r']) => return Ok(None),
+        Some([b'\n', ..]) => 1,
+        Some([b'\r', b'\n', ..]) => 2,
+        Some(_) => {
+            let message = "bulk reply not followed by a line break";
+            return Err(DistKvError::Protocol(message.to_string()));
+        }
+    };
+    Ok(Some((start..start + len, start + len + terminator)))
+}
+
+/// Splits the next complete reply off the front of `buf`, returning `None`
+/// until all of it has been buffered.
+pub fn split_reply(buf: &mut BytesMut) -> Result<Option<Reply>> {
+    let Some(end) = buf.iter().position(|b| *b == b'\n') else {
+        return Ok(None);
+    };
+    let line = buf[..end].strip_suffix(b"\r").unwrap_or(&buf[..end]);
+    let (kind, rest) = match line.split_first() {
+        Some((kind, rest)) => (*kind, String::from_utf8_lossy(rest).into_owned()),
+        None => return Err(DistKvError::Protocol("empty reply line".to_string())),
+    };
+    let reply = match kind {
+        b'+' => Reply::Status(rest),
+        b'-' => Reply::Error(rest),
+        b':' => Reply::Integer(
+            rest.parse()
+                .map_err(|_| malformed("invalid integer", line))?,
+        ),
+        b'$' if rest == "-1" => Reply::Bulk(None),
+        b'$' => {
+            let Some((val, next)) = bulk_at(buf, 0)? else {
+                return Ok(None);
+            };
+            let frame = buf.split_to(next).freeze();
+            return Ok(Some(Reply::Bulk(Some(frame.slice(val)))));
+        }
+        b'>' => {
+            let n: usize = rest
+                .parse()
+                .map_err(|_| malformed("invalid push length", line))?;
+            // Every item takes at least a byte, so a length larger than
+            // what has arrived doesn't get to size the allocation.
+            let mut items = Vec::with_capacity(n.min(buf.len()));
+            let mut next = end + 1;
+            for _ in 0..n {
+                let Some((item, after)) = bulk_at(buf, next)? else {
+                    return Ok(None);
+                };
+                items.push(item);
+                next = after;
+            }
+            let frame = buf.split_to(next).freeze();
+            let items = items.into_iter().map(|item| frame.slice(item)).collect();
+            return Ok(Some(Reply::Push(items)));
+        }
+        _ => return Err(malformed("unexpected reply", line)),
+    };
+    buf.advance(end + 1);
+    Ok(Some(reply))
+}
+
+/// Who a node is, in reply to `WHOAMI`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoAmI {
+    pub role: String,
+    pub node_id: String,
+    /// The leader's address, if the node knows it.
+    pub leader: Option<String>,
+}
+
+impl WhoAmI {
+    fn parse(reply: &[u8]) -> Result<WhoAmI> {
+        let reply = String::from_utf8_lossy(reply);
+        let field = |name: &str| {
+            reply
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .ok_or_else(|| DistKvError::Protocol(format!("WHOAMI reply is missing {}", name)))
+        };
+        let leader = field("leader")?;
+        Ok(WhoAmI {
+            role: field("role")?.to_string(),
+            node_id: field("node_id")?.to_string(),
+            leader: (!leader.is_empty()).then(|| leader.to_string()),
+        })
+    }
+}
+
+/// What a server agreed to in reply to `HELLO`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hello {
+    pub version: u32,
+    pub role: String,
+    pub node_id: String,
+    pub features: Vec<String>,
+}
+
+impl Hello {
+    pub fn parse(reply: &[u8]) -> Result<Hello> {
+        let reply = String::from_utf8_lossy(reply);
+        let field = |name: &str| {
+            reply
+                .lines()
+                .find_map(|line| line.strip_prefix(name)?.strip_prefix(':'))
+                .ok_or_else(|| DistKvError::Protocol(format!("HELLO reply is missing {}", name)))
+        };
+        Ok(Hello {
+            version: field("version")?
+                .parse()
+                .map_err(|_| DistKvError::Protocol("HELLO reply has an invalid version".into()))?,
+            role: field("role")?.to_string(),
+            node_id: field("node_id")?.to_string(),
+            features: field("features")?
+                .split(',')
+                .filter(|feature| !feature.is_empty())
+                .map(str::to_string)
+                .collect(),
+        })
+    }
+}
+
+/// Values [`DistKvClient::get`] read, by namespace and key, kept until the
+/// leader says they changed.
+struct Cache {
+    capacity: usize,
+    values: HashMap<(Bytes, Bytes), Option<Bytes>>,
+}
+
+impl Cache {
+    fn insert(&mut self, key: (Bytes, Bytes), val: Option<Bytes>) {
+        if self.values.len() >= self.capacity && !self.values.contains_key(&key) {
+            let evicted = self.values.keys().next().cloned();
+            evicted.map(|evicted| self.values.remove(&evicted));
+        }
+        self.values.insert(key, val);
+    }
+}
+
+/// Whether a push is one of the leader's `invalidate <namespace> <key>`.
+fn is_invalidation(items: &[Bytes]) -> bool {
+    items.len() == 3 && items[0] == "invalidate"
+}
+
+/// How [`DistKvClient`] retries commands after its connection fails, see
+/// [`DistKvClient::enable_retries`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// How many times a command is retried before its error is returned.
+    pub attempts: u32,
+    /// How long to wait before the first retry. Each one after waits twice
+    /// as long as the one before, up to `max_backoff`.
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            attempts: 5,
+            backoff: Duration::from_millis(50),
+            max_backoff: Duration::from_secs(2),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// How long to wait after the connection failed `failures` times in a
+    /// row.
+    fn delay(&self, failures: u32) -> Duration {
+        let delay = self.backoff.saturating_mul(1 << failures.min(16));
+        delay.min(self.max_backoff)
+    }
+}
+
+/// What a connection was told that a new one has to be told again for the
+/// session to carry on where it was. The namespace and whether caching is
+/// on are kept by the client itself.
+#[derive(Default)]
+struct Session {
+    /// The features `HELLO` agreed to.
+    features: Option<Bytes>,
+    /// The tenant and password `AUTH` succeeded with.
+    auth: Option<(Bytes, Bytes)>,
+    /// The name `CLIENT SETNAME` gave the connection.
+    name: Option<Bytes>,
+    channels: BTreeSet<Bytes>,
+    patterns: BTreeSet<Bytes>,
+}
+
+/// Whether sending `command` twice leaves the cluster as sending it once
+/// does, so that it can be sent again when the connection failed before its
+/// reply arrived.
+fn is_idempotent(command: &Command<'_>) -> bool {
+    match command {
+        Command::In(_, command) => is_idempotent(command),
+        Command::SetWith(_, _, options) => options.condition.is_none(),
+        Command::Get(_)
+        | Command::GetWith(..)
+        | Command::GetAt(..)
+        | Command::GetVersion(..)
+        | Command::GetRange(..)
+        | Command::GetBit(..)
+        | Command::BitCount(..)
+        | Command::PfCount(_)
+        | Command::LLen(_)
+        | Command::XRead(..)
+        | Command::History(..)
+        | Command::Ttl(_)
+        | Command::Object(..)
+        | Command::Dump(_)
+        | Command::RandomKey
+        | Command::Sample(_)
+        | Command::WaitChange(..)
+        | Command::Info(_)
+        | Command::Hello(..)
+        | Command::WhoAmI
+        | Command::WhoIsLeader
+        | Command::Peers
+        | Command::Quota
+        | Command::Select(_)
+        | Command::Auth(..)
+        | Command::Subscribe(_)
+        | Command::PSubscribe(_)
+        | Command::Set(..)
+        | Command::Delete(_)
+        | Command::DelIfEq(..)
+        | Command::Unlock(..)
+        | Command::SetRange(..)
+        | Command::SetBit(..)
+        | Command::PfAdd(..)
+        | Command::PfMerge(..)
+        | Command::ExpireAt(..)
+        | Command::PExpireAt(..)
+        | Command::Persist(_)
+        | Command::Restore(_, _, true)
+        | Command::Config(ConfigCommand::Get(_)) => true,
+        _ => false,
+    }
+}
+
+/// A connection to a dist-kv server.
+pub struct DistKvClient {
+    stream: Connection,
+    buf: BytesMut,
+    request_id: Option<String>,
+    /// The namespace `SELECT` last switched to.
+    namespace: Bytes,
+    cache: Option<Cache>,
+    /// Where to open the connection again after it fails.
+    transport: Arc<dyn Transport>,
+    addr: String,
+    retry: Option<RetryPolicy>,
+    /// Set once the connection has failed, so that it is opened again
+    /// before it is next used.
+    broken: bool,
+    session: Session,
+}
+
+impl DistKvClient {
+    pub async fn connect(addr: impl ToSocketAddrs) -> Result<DistKvClient> {
+        let stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(true)?;
+        let addr = stream.peer_addr()?.to_string();
+        Ok(DistKvClient::new(stream.into(), Arc::new(Tcp), addr))
+    }
+
+    /// Connects to `addr` through `transport` rather than over TCP.
+    pub async fn connect_via(transport: Arc<dyn Transport>, addr: &str) -> Result<DistKvClient> {
+        let stream = transport.connect(addr).await?;
+        Ok(DistKvClient::new(stream, transport, addr.to_string()))
+    }
+
+    fn new(stream: Connection, transport: Arc<dyn Transport>, addr: String) -> DistKvClient {
+        DistKvClient {
+            stream,
+            buf: BytesMut::with_capacity(4096),
+            request_id: None,
+            namespace: Bytes::from_static(DEFAULT_NAMESPACE),
+            cache: None,
+            transport,
+            addr,
+            retry: None,
+            broken: false,
+            session: Session::default(),
+        }
+    }
+
+    /// Has the client open its connection again when it fails, and resume
+    /// the session it had: the features `HELLO` agreed to, the tenant it
+    /// authenticated as, its name, namespace, subscriptions and, if caching
+    /// is on, tracking, with the cache emptied as invalidations may have been
+    /// missed. Commands that are safe to send twice, and writes sent with
+    /// [`DistKvClient::call_once`] by a client with a `CLIENT SETNAME` name,
+    /// are retried as `policy` says; others return the error, and the
+    /// connection is opened again when the client is next used. `TAIL`
+    /// isn't resumed.
+    pub fn enable_retries(&mut self, policy: RetryPolicy) {
+        self.retry = Some(policy);
+    }
+
+    /// Opens the connection again and resumes the session on it.
+    async fn reconnect(&mut self) -> Result<()> {
+        self.stream = self.transport.connect(&self.addr).await?;
+        self.buf.clear();
+        let session = &self.session;
+        let mut resume = Vec::new();
+        if let Some(features) = &session.features {
+            resume.push(Command::Hello(PROTOCOL_VERSION, Some(features[..].into())));
+        }
+        if let Some((user, password)) = &session.auth {
+            resume.push(Command::Auth(user[..].into(), password[..].into()));
+        }
+        if let Some(name) = &session.name {
+            resume.push(Command::Client(ClientCommand::SetName(name[..].into())));
+        }
+        if self.namespace != DEFAULT_NAMESPACE {
+            resume.push(Command::Select(self.namespace[..].into()));
+        }
+        for channel in &session.channels {
+            resume.push(Command::Subscribe(channel[..].into()));
+        }
+        for pattern in &session.patterns {
+            resume.push(Command::PSubscribe(pattern[..].into()));
+        }
+        if self.cache.is_some() {
+            resume.push(Command::Client(ClientCommand::Tracking(true)));
+        }
+        let resume: Vec<Command<'static>> = resume.into_iter().map(Command::into_owned).collect();
+        let mut requests = BytesMut::new();
+        resume
+            .iter()
+            .for_each(|command| command.encode(&mut requests));
+        self.send_raw(&requests).await?;
+        for _ in &resume {
+            if let Reply::Error(err) = self.read_reply().await? {
+                return Err(DistKvError::from_line(&err));
+            }
+        }
+        if let Some(cache) = &mut self.cache {
+            cache.values.clear();
+        }
+        self.broken = false;
+        Ok(())
+    }
+
+    /// Marks the connection as failed, for the error it failed with.
+    fn fail(&mut self, err: io::Error) -> DistKvError {
+        self.broken = true;
+        err.into()
+    }
+
+    /// Has the leader track the keys this connection reads, and keeps up to
+    /// `capacity` values [`DistKvClient::get`] reads until the leader says
+    /// they changed. A read from the cache can miss a write whose
+    /// invalidation hasn't arrived yet, but never one this client made.
+    pub async fn enable_caching(&mut self, capacity: usize) -> Result<()> {
+        match self
+            .call(&Command::Client(ClientCommand::Tracking(true)))
+            .await?
+        {
+            Reply::Status(_) => {}
+            reply => return unexpected(reply),
+        }
+        self.cache = Some(Cache {
+            capacity: capacity.max(1),
+            values: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    /// Whether `key`, in the selected namespace, is cached.
+    pub fn is_cached(&self, key: &[u8]) -> bool {
+        let key = (self.namespace.clone(), Bytes::copy_from_slice(key));
+        self.cache
+            .as_ref()
+            .is_some_and(|cache| cache.values.contains_key(&key))
+    }
+
+    fn invalidate(&mut self, items: &[Bytes]) {
+        if let Some(cache) = &mut self.cache {
+            cache.values.remove(&(items[1].clone(), items[2].clone()));
+        }
+    }
+
+    /// Applies the invalidations that have already arrived, without waiting
+    /// for any more.
+    fn drain_invalidations(&mut self) -> Result<()> {
+        let mut cx = Context::from_waker(Waker::noop());
+        loop {
+            let read = pin!(self.stream.read_buf(&mut self.buf));
+            match read.poll(&mut cx) {
+                Poll::Ready(Ok(0)) => {
+                    let closed =
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by server");
+                    return Err(self.fail(closed));
+                }
+                Poll::Ready(Ok(_)) => {}
+                Poll::Ready(Err(e)) => return Err(self.fail(e)),
+                Poll::Pending => break,
+            }
+        }
+        loop {
+            // Only pushes at the front, so no reply is taken out of turn.
+            let mut rest = self.buf.clone();
+            match split_reply(&mut rest)? {
+                Some(Reply::Push(items)) if is_invalidation(&items) => {
+                    self.buf = rest;
+                    self.invalidate(&items);
+                }
+                _ => return Ok(()),
+            }
+        }
+    }
+
+    /// Writes already encoded requests, letting callers pipeline several
+    /// commands before reading any of the replies.
+    pub async fn send_raw(&mut self, requests: &[u8]) -> Result<()> {
+        if let Err(e) = self.stream.write_all(requests).await {
+            return Err(self.fail(e));
+        }
+        Ok(())
+    }
+
+    pub async fn read_reply(&mut self) -> Result<Reply> {
+        loop {
+            match split_reply(&mut self.buf)? {
+                Some(Reply::Push(items)) if items.len() == 2 && items[0] == "reqid" => {
+                    self.request_id = Some(String::from_utf8_lossy(&items[1]).into_owned());
+                    continue;
+                }
+                Some(Reply::Push(items)) if self.cache.is_some() && is_invalidation(&items) => {
+                    self.invalidate(&items);
+                    continue;
+                }
+                Some(reply) => return Ok(reply),
+                None => {}
+            }
+            match self.stream.read_buf(&mut self.buf).await {
+                Ok(0) => {
+                    let closed =
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "connection closed by server");
+                    return Err(self.fail(closed));
+                }
+                Ok(_) => {}
+                Err(e) => return Err(self.fail(e)),
+            }
+        }
+    }
+
+    /// The ID of the last write the leader logged for this client, once
+    /// `HELLO` has agreed to `reqid`.
+    pub fn request_id(&self) -> Option<&str> {
+        self.request_id.as_deref()
+    }
+
+    /// The address this end of the connection has, as the server sees it.
+    pub fn local_addr(&self) -> Result<SocketAddr> {
+        Ok(self.stream.local_addr()?)
+    }
+
+    pub async fn call(&mut self, command: &Command<'_>) -> Result<Reply> {
+        self.call_with(command, None).await
+    }
+
+    /// Sends a write under `request`, an ID this client uses for no other
+    /// write, so that if it is sent again, as when retrying after a timeout,
+    /// the leader answers it as it did the first time instead of applying it
+    /// twice. A retry is recognized on the same connection, or on any with
+    /// the same `CLIENT SETNAME` name, for as long as the leader remembers
+    /// the ID, see `request_dedup_window` and `request_dedup_timeout_ms`.
+    pub async fn call_once(&mut self, command: &Command<'_>, request: &str) -> Result<Reply> {
+        self.call_with(command, Some(request)).await
+    }
+
+    /// Sends `command`, after a `REQID` if `request` names it, retrying as
+    /// [`DistKvClient::enable_retries`] says if the connection fails.
+    async fn call_with(&mut self, command: &Command<'_>, request: Option<&str>) -> Result<Reply> {
+        let mut failures = 0;
+        loop {
+            let sent = match self.broken && self.retry.is_some() {
+                true => self.reconnect().await,
+                false => Ok(()),
+            };
+            let err = match sent {
+                Ok(()) => match self.send_and_read(command, request).await {
+                    Err(DistKvError::Io(err)) => err,
+                    result => return result,
+                },
+                Err(DistKvError::Io(err)) => err,
+                Err(err) => return Err(err),
+            };
+            // The leader only knows a retried ID on another connection by
+            // the client's name.
+            let named = request.is_some() && self.session.name.is_some();
+            let retryable = named || is_idempotent(command);
+            match self
+                .retry
+                .filter(|retry| retryable && failures < retry.attempts)
+            {
+                Some(retry) => tokio::time::sleep(retry.delay(failures)).await,
+                None => return Err(err.into()),
+            }
+            failures += 1;
+        }
+    }
+
+    async fn send_and_read(
+        &mut self,
+        command: &Command<'_>,
+        request: Option<&str>,
+    ) -> Result<Reply> {
+        let Some(request) = request else {
+            self.send(command).await?;
+            return self.read_reply().await;
+        };
+        let mut marker = BytesMut::new();
+        Command::RequestId(request.as_bytes().into()).encode(&mut marker);
+        self.send_raw(&marker).await?;
+        self.send(command).await?;
+        let marked = self.read_reply().await?;
+        let reply = self.read_reply().await?;
+        match marked {
+            Reply::Status(_) => Ok(reply),
+            marked => unexpected(marked),
+        }
+    }
+
+    async fn send(&mut self, command: &Command<'_>) -> Result<()> {
+        // The leader's invalidation of this client's own write may only
+        // arrive after the reply.
+        if let Some(cache) = self.cache.as_mut().filter(|_| command.is_write()) {
+            for key in command.keys() {
+                let key = (self.namespace.clone(), Bytes::copy_from_slice(key));
+                cache.values.remove(&key);
+            }
+        }
+        let mut request = BytesMut::new();
+        command.encode(&mut request);
+        self.send_raw(&request).await
+    }
+
+    /// Introduces the client, asking for `features`. Servers that predate
+    /// `HELLO` answer with an unknown command error, after which the
+    /// connection can still be used as if no features were agreed.
+    pub async fn hello(&mut self, features: &[&str]) -> Result<Hello> {
+        let features = features.join(",");
+        let features = (!features.is_empty()).then(|| Bytes::from(features));
+        let hello = Command::Hello(PROTOCOL_VERSION, features.as_deref().map(Into::into));
+        match self.call(&hello).await? {
+            Reply::Bulk(Some(reply)) => {
+                let hello = Hello::parse(&reply)?;
+                self.session.features = features;
+                Ok(hello)
+            }
+            reply => unexpected(reply),
+        }
+    }
+
+    pub async fn whoami(&mut self) -> Result<WhoAmI> {
+        match self.call(&Command::WhoAmI).await? {
+            Reply::Bulk(Some(reply)) => WhoAmI::parse(&reply),
+            reply => unexpected(reply),
+        }
+    }
+
+    pub async fn config_get(&mut self, name: &str) -> Result<String> {
+        match self
+            .call(&Command::Config(ConfigCommand::Get(name.as_bytes().into())))
+            .await?
+        {
+            Reply::Bulk(Some(value)) => Ok(String::from_utf8_lossy(&value).into_owned()),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Changes a setting on the running leader, and in its config file.
+    pub async fn config_set(&mut self, name: &str, value: &str) -> Result<()> {
+        let set = ConfigCommand::Set(name.as_bytes().into(), value.as_bytes().into());
+        match self.call(&Command::Config(set)).await? {
+            Reply::Status(_) => Ok(()),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Turns the leader's read-only mode on or off.
+    pub async fn read_only(&mut self, on: bool) -> Result<()> {
+        match self.call(&Command::ReadOnly(on)).await? {
+            Reply::Status(_) => Ok(()),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// The members of the cluster the node knows of.
+    pub async fn peers(&mut self) -> Result<Peers> {
+        match self.call(&Command::Peers).await? {
+            Reply::Bulk(Some(reply)) => Peers::parse(&reply),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// The address writes should go to, if the node knows it.
+    pub async fn leader(&mut self) -> Result<Option<String>> {
+        match self.call(&Command::WhoIsLeader).await? {
+            Reply::Bulk(leader) => {
+                Ok(leader.map(|leader| String::from_utf8_lossy(&leader).into_owned()))
+            }
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Subscribes to `channel`, returning how many subscriptions the
+    /// connection now has. Messages arrive as [`Reply::Push`]es.
+    pub async fn subscribe(&mut self, channel: &[u8]) -> Result<usize> {
+        match self.call(&Command::Subscribe(channel.into())).await? {
+            Reply::Integer(n) => {
+                self.session
+                    .channels
+                    .insert(Bytes::copy_from_slice(channel));
+                Ok(n as usize)
+            }
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Subscribes to every channel matching the glob `pattern`.
+    pub async fn psubscribe(&mut self, pattern: &[u8]) -> Result<usize> {
+        match self.call(&Command::PSubscribe(pattern.into())).await? {
+            Reply::Integer(n) => {
+                self.session
+                    .patterns
+                    .insert(Bytes::copy_from_slice(pattern));
+                Ok(n as usize)
+            }
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Returns how many subscribers the message was sent to.
+    pub async fn publish(&mut self, channel: &[u8], message: &[u8]) -> Result<usize> {
+        match self
+            .call(&Command::Publish(channel.into(), message.into()))
+            .await?
+        {
+            Reply::Integer(n) => Ok(n as usize),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Streams every committed change from `from` on, each as a push to read
+    /// with [`Client::next_push`].
+    pub async fn tail(&mut self, from: u64) -> Result<()> {
+        match self.call(&Command::Tail(from)).await? {
+            Reply::Status(_) => Ok(()),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Waits for the next message on a subscribed connection. With retries
+    /// on, a failed connection is opened again and subscribed again, though
+    /// messages published in between are missed.
+    pub async fn next_push(&mut self) -> Result<Vec<Bytes>> {
+        let mut failures = 0;
+        loop {
+            let read = match self.broken && self.retry.is_some() {
+                true => self.reconnect().await,
+                false => Ok(()),
+            };
+            let err = match read {
+                Ok(()) => match self.read_reply().await {
+                    Ok(Reply::Push(items)) => return Ok(items),
+                    Ok(reply) => return unexpected(reply),
+                    Err(DistKvError::Io(err)) => err,
+                    Err(err) => return Err(err),
+                },
+                Err(DistKvError::Io(err)) => err,
+                Err(err) => return Err(err),
+            };
+            match self.retry.filter(|retry| failures < retry.attempts) {
+                Some(retry) => tokio::time::sleep(retry.delay(failures)).await,
+                None => return Err(err.into()),
+            }
+            failures += 1;
+        }
+    }
+
+    /// The value of `key`, from the cache if caching is on and it has it.
+    pub async fn get(&mut self, key: &[u8]) -> Result<Option<Bytes>> {
+        if self.cache.is_some() && !self.broken {
+            // With retries on, the call below opens the connection again and
+            // empties the cache.
+            match self.drain_invalidations() {
+                Err(err) if self.retry.is_none() => return Err(err),
+                _ => {}
+            }
+        }
+        let cached = (self.namespace.clone(), Bytes::copy_from_slice(key));
+        let cache = self.cache.as_ref().filter(|_| !self.broken);
+        if let Some(val) = cache.and_then(|c| c.values.get(&cached)) {
+            return Ok(val.clone());
+        }
+        let val = match self.call(&Command::Get(key.into())).await? {
+            Reply::Bulk(val) => val,
+            reply => return unexpected(reply),
+        };
+        if let Some(cache) = &mut self.cache {
+            cache.insert(cached, val.clone());
+        }
+        Ok(val)
+    }
+
+    /// The value of `key`, from a node as up to date as `consistency` asks.
+    pub async fn get_with(
+        &mut self,
+        key: &[u8],
+        consistency: Consistency,
+    ) -> Result<Option<Bytes>> {
+        match self
+            .call(&Command::GetWith(key.into(), consistency))
+            .await?
+        {
+            Reply::Bulk(val) => Ok(val),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// The value `key` had as of the write logged as `lsn`, as `GET key AT
+    /// lsn` answers it.
+    pub async fn get_at(&mut self, key: &[u8], lsn: u64) -> Result<Option<Bytes>> {
+        match self.call(&Command::GetAt(key.into(), lsn)).await? {
+            Reply::Bulk(val) => Ok(val),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// `HISTORY key [limit]`: a line of `<lsn> <unix time ms> <value>` for
+    /// each of the last writes to `key`, newest first, with `nil` for a
+    /// write that deleted it.
+    pub async fn history(&mut self, key: &[u8], limit: Option<usize>) -> Result<String> {
+        match self.call(&Command::History(key.into(), limit)).await? {
+            Reply::Bulk(Some(reply)) => Ok(String::from_utf8_lossy(&reply).into_owned()),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// `QUOTA`: `name:value` lines of the rates the connection's tenant is
+    /// held to and how much of them it has used this second.
+    pub async fn quota(&mut self) -> Result<String> {
+        match self.call(&Command::Quota).await? {
+            Reply::Bulk(Some(reply)) => Ok(String::from_utf8_lossy(&reply).into_owned()),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// `DEBUG DUMPSTATE`: has the leader write a report of its state to a
+    /// file, returning the file's path.
+    pub async fn dump_state(&mut self) -> Result<String> {
+        match self.call(&Command::DumpState).await? {
+            Reply::Bulk(Some(reply)) => Ok(String::from_utf8_lossy(&reply).into_owned()),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// `DEBUG SLEEP`: holds the leader's lock for `ms` milliseconds.
+    pub async fn debug_sleep(&mut self, ms: u64) -> Result<()> {
+        match self.call(&Command::Debug(DebugCommand::Sleep(ms))).await? {
+            Reply::Status(_) => Ok(()),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// `DEBUG OBJECT`: how the leader stores `key`'s value, or `None` if
+    /// there is no such key.
+    pub async fn debug_object(&mut self, key: &[u8]) -> Result<Option<String>> {
+        match self
+            .call(&Command::Debug(DebugCommand::Object(key.into())))
+            .await?
+        {
+            Reply::Bulk(Some(reply)) => Ok(Some(String::from_utf8_lossy(&reply).into_owned())),
+            Reply::Bulk(None) => Ok(None),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// `DEBUG SET-LSN`: makes the leader's next write take `lsn + 1`.
+    pub async fn debug_set_lsn(&mut self, lsn: u64) -> Result<()> {
+        match self
+            .call(&Command::Debug(DebugCommand::SetLsn(lsn)))
+            .await?
+        {
+            Reply::Status(_) => Ok(()),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// `CLUSTER ADD-NODE`: has the leader start replicating to the follower
+    /// at `addr`.
+    pub async fn cluster_add_node(&mut self, addr: &str) -> Result<()> {
+        let command = Command::Cluster(ClusterCommand::AddNode(addr.as_bytes().into()));
+        match self.call(&command).await? {
+            Reply::Status(_) => Ok(()),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// `CLUSTER REMOVE-NODE`: has the leader stop replicating to the
+    /// follower at `addr`.
+    pub async fn cluster_remove_node(&mut self, addr: &str) -> Result<()> {
+        let command = Command::Cluster(ClusterCommand::RemoveNode(addr.as_bytes().into()));
+        match self.call(&command).await? {
+            Reply::Status(_) => Ok(()),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// `TRANSFER LEADERSHIP`: has the leader stop taking writes and wait
+    /// until the follower at `addr` has every write.
+    pub async fn transfer_leadership(&mut self, addr: &str) -> Result<()> {
+        match self
+            .call(&Command::TransferLeadership(addr.as_bytes().into()))
+            .await?
+        {
+            Reply::Status(_) => Ok(()),
+            reply => unexpected(reply),
+        }
+    }
+
+    pub async fn set(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        match self.call(&Command::Set(key.into(), val.into())).await? {
+            Reply::Status(_) => Ok(()),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// `SET` with options, returning whether the key was set, which it isn't
+    /// when an `NX` or `XX` condition doesn't hold.
+    pub async fn set_with(&mut self, key: &[u8], val: &[u8], options: SetOptions) -> Result<bool> {
+        match self
+            .call(&Command::SetWith(key.into(), val.into(), options))
+            .await?
+        {
+            Reply::Status(_) => Ok(true),
+            Reply::Bulk(None) => Ok(false),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Switches the connection to another namespace.
+    pub async fn select(&mut self, namespace: &[u8]) -> Result<()> {
+        match self.call(&Command::Select(namespace.into())).await? {
+            Reply::Status(_) => {
+                self.namespace = Bytes::copy_from_slice(namespace);
+                Ok(())
+            }
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Authenticates the connection as a tenant.
+    pub async fn auth(&mut self, user: &[u8], password: &[u8]) -> Result<()> {
+        match self
+            .call(&Command::Auth(user.into(), password.into()))
+            .await?
+        {
+            Reply::Status(_) => {
+                let (user, password) = (
+                    Bytes::copy_from_slice(user),
+                    Bytes::copy_from_slice(password),
+                );
+                self.session.auth = Some((user, password));
+                Ok(())
+            }
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Names the connection, as `CLIENT LIST` shows it. The leader also
+    /// recognizes retries of [`DistKvClient::call_once`] writes by name.
+    pub async fn set_name(&mut self, name: &[u8]) -> Result<()> {
+        let command = Command::Client(ClientCommand::SetName(name.into()));
+        match self.call(&command).await? {
+            Reply::Status(_) => {
+                self.session.name = Some(Bytes::copy_from_slice(name));
+                Ok(())
+            }
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Runs a script on the leader, returning whatever it evaluated to.
+    pub async fn eval(&mut self, script: &str, keys: &[&[u8]], args: &[&[u8]]) -> Result<Reply> {
+        let keys = keys.iter().map(|key| (*key).into()).collect();
+        let args = args.iter().map(|arg| (*arg).into()).collect();
+        let command = Command::Eval(script.as_bytes().into(), keys, args);
+        match self.call(&command).await? {
+            Reply::Error(err) => Err(DistKvError::from_line(&err)),
+            reply => Ok(reply),
+        }
+    }
+
+    /// Calls a function the leader loaded from its config.
+    pub async fn fcall(&mut self, function: &str, keys: &[&[u8]], args: &[&[u8]]) -> Result<Reply> {
+        let keys = keys.iter().map(|key| (*key).into()).collect();
+        let args = args.iter().map(|arg| (*arg).into()).collect();
+        let command = Command::FCall(function.as_bytes().into(), keys, args);
+        match self.call(&command).await? {
+            Reply::Error(err) => Err(DistKvError::from_line(&err)),
+            reply => Ok(reply),
+        }
+    }
+
+    /// The bytes of the value from `start` to `end` inclusive, empty if the
+    /// key is missing.
+    pub async fn getrange(&mut self, key: &[u8], start: i64, end: i64) -> Result<Bytes> {
+        match self
+            .call(&Command::GetRange(key.into(), start, end))
+            .await?
+        {
+            Reply::Bulk(val) => Ok(val.unwrap_or_default()),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Overwrites the value from `offset` on, returning its new length.
+    pub async fn setrange(&mut self, key: &[u8], offset: usize, val: &[u8]) -> Result<usize> {
+        match self
+            .call(&Command::SetRange(key.into(), offset, val.into()))
+            .await?
+        {
+            Reply::Integer(n) => Ok(n as usize),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Sets or clears a bit, returning whether it was set before.
+    pub async fn setbit(&mut self, key: &[u8], offset: usize, bit: bool) -> Result<bool> {
+        match self.call(&Command::SetBit(key.into(), offset, bit)).await? {
+            Reply::Integer(n) => Ok(n == 1),
+            reply => unexpected(reply),
+        }
+    }
+
+    pub async fn getbit(&mut self, key: &[u8], offset: usize) -> Result<bool> {
+        match self.call(&Command::GetBit(key.into(), offset)).await? {
+            Reply::Integer(n) => Ok(n == 1),
+            reply => unexpected(reply),
+        }
+    }
+
+    pub async fn bitcount(&mut self, key: &[u8]) -> Result<u64> {
+        match self.call(&Command::BitCount(key.into(), None)).await? {
+            Reply::Integer(n) => Ok(n as u64),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Deletes the key if its value is `expected`, returning whether it did.
+    pub async fn delifeq(&mut self, key: &[u8], expected: &[u8]) -> Result<bool> {
+        match self
+            .call(&Command::DelIfEq(key.into(), expected.into()))
+            .await?
+        {
+            Reply::Integer(n) => Ok(n == 1),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Waits for the next write to `key`, returning the LSN it was logged at
+    /// and the value it left, `None` if it deleted the key. Returns `None`
+    /// if there was no write within `timeout`.
+    pub async fn wait_change(
+        &mut self,
+        key: &[u8],
+        timeout: Option<Duration>,
+    ) -> Result<Option<(u64, Option<Bytes>)>> {
+        let timeout = timeout.map(|timeout| timeout.as_millis().max(1) as u64);
+        match self.call(&Command::WaitChange(key.into(), timeout)).await? {
+            Reply::Bulk(None) => Ok(None),
+            Reply::Push(items) if !items.is_empty() && items.len() <= 2 => {
+                let lsn = String::from_utf8_lossy(&items[0]).parse().map_err(|_| {
+                    DistKvError::Protocol(format!("invalid WAITCHANGE LSN {:?}", items[0]))
+                })?;
+                Ok(Some((lsn, items.get(1).cloned())))
+            }
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Adds `element` to the head of the list at `key`, returning how long
+    /// the list now is.
+    pub async fn lpush(&mut self, key: &[u8], element: &[u8]) -> Result<usize> {
+        match self
+            .call(&Command::LPush(key.into(), element.into()))
+            .await?
+        {
+            Reply::Integer(len) => Ok(len as usize),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Adds `element` to the tail of the list at `key`, returning how long
+    /// the list now is.
+    pub async fn rpush(&mut self, key: &[u8], element: &[u8]) -> Result<usize> {
+        match self
+            .call(&Command::RPush(key.into(), element.into()))
+            .await?
+        {
+            Reply::Integer(len) => Ok(len as usize),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Removes and returns the head of the list at `key`, `None` if it is
+    /// empty.
+    pub async fn lpop(&mut self, key: &[u8]) -> Result<Option<Bytes>> {
+        match self.call(&Command::LPop(key.into())).await? {
+            Reply::Bulk(element) => Ok(element),
+            reply => unexpected(reply),
+        }
+    }
+
+    pub async fn llen(&mut self, key: &[u8]) -> Result<usize> {
+        match self.call(&Command::LLen(key.into())).await? {
+            Reply::Integer(len) => Ok(len as usize),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Pops the head of the list at `key`, waiting up to `timeout` for an
+    /// element to be pushed if it is empty, or for as long as it takes if
+    /// `timeout` is `None`. Returns `None` if the wait timed out.
+    pub async fn blpop(&mut self, key: &[u8], timeout: Option<Duration>) -> Result<Option<Bytes>> {
+        let timeout = timeout.map(|timeout| timeout.as_millis().max(1) as u64);
+        match self.call(&Command::BLPop(key.into(), timeout)).await? {
+            Reply::Bulk(element) => Ok(element),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Appends `data` to the stream at `key` under `id`, or under an ID the
+    /// leader picks if `id` is `None`, returning the ID.
+    pub async fn xadd(
+        &mut self,
+        key: &[u8],
+        id: Option<StreamId>,
+        data: &[u8],
+    ) -> Result<StreamId> {
+        match self
+            .call(&Command::XAdd(key.into(), id, data.into()))
+            .await?
+        {
+            Reply::Bulk(Some(id)) => StreamId::parse(&id)
+                .ok_or_else(|| DistKvError::Protocol(format!("invalid stream ID {:?}", id))),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Up to `count` entries of the stream at `key` added after `after`,
+    /// oldest first. Pass the ID of the last entry read to read on from it.
+    pub async fn xread(
+        &mut self,
+        key: &[u8],
+        after: StreamId,
+        count: Option<usize>,
+    ) -> Result<Vec<(StreamId, Bytes)>> {
+        let items = match self.call(&Command::XRead(key.into(), after, count)).await? {
+            Reply::Push(items) if items.len() % 2 == 0 => items,
+            reply => return unexpected(reply),
+        };
+        items
+            .chunks(2)
+            .map(|entry| match StreamId::parse(&entry[0]) {
+                Some(id) => Ok((id, entry[1].clone())),
+                None => Err(DistKvError::Protocol(format!(
+                    "invalid stream ID {:?}",
+                    entry[0]
+                ))),
+            })
+            .collect()
+    }
+
+    /// Sets `key` for as long as this connection stays open and keeps
+    /// sending commands within the leader's `session_timeout_ms`.
+    pub async fn ephemeral(&mut self, key: &[u8], val: &[u8]) -> Result<()> {
+        match self
+            .call(&Command::Ephemeral(key.into(), val.into()))
+            .await?
+        {
+            Reply::Status(_) => Ok(()),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Takes the lock at `key` for `ttl`, returning its fencing token, or
+    /// `None` if someone else holds it.
+    pub async fn lock(&mut self, key: &[u8], ttl: Duration) -> Result<Option<u64>> {
+        let ttl = ttl.as_millis().max(1) as u64;
+        match self.call(&Command::Lock(key.into(), ttl)).await? {
+            Reply::Integer(token) => Ok(Some(token as u64)),
+            Reply::Bulk(None) => Ok(None),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Releases the lock at `key` if it's still held with `token`, returning
+    /// whether it was.
+    pub async fn unlock(&mut self, key: &[u8], token: u64) -> Result<bool> {
+        match self.call(&Command::Unlock(key.into(), token)).await? {
+            Reply::Integer(n) => Ok(n == 1),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Makes the key expire at a Unix time in seconds, returning whether it
+    /// exists.
+    pub async fn expireat(&mut self, key: &[u8], at: u64) -> Result<bool> {
+        match self.call(&Command::ExpireAt(key.into(), at)).await? {
+            Reply::Integer(n) => Ok(n == 1),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Removes the key's TTL, returning whether it had one.
+    pub async fn persist(&mut self, key: &[u8]) -> Result<bool> {
+        match self.call(&Command::Persist(key.into())).await? {
+            Reply::Integer(n) => Ok(n == 1),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Seconds until the key expires, `-1` if it has no TTL and `-2` if it
+    /// doesn't exist.
+    pub async fn ttl(&mut self, key: &[u8]) -> Result<i64> {
+        match self.call(&Command::Ttl(key.into())).await? {
+            Reply::Integer(n) => Ok(n),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// How the key's value is stored, or `None` if it doesn't exist.
+    pub async fn object_encoding(&mut self, key: &[u8]) -> Result<Option<String>> {
+        match self.object(ObjectField::Encoding, key).await? {
+            Reply::Bulk(encoding) => {
+                Ok(encoding.map(|encoding| String::from_utf8_lossy(&encoding).into_owned()))
+            }
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Seconds since the key was last read or written, or `None` if it
+    /// doesn't exist.
+    pub async fn object_idle_time(&mut self, key: &[u8]) -> Result<Option<u64>> {
+        match self.object(ObjectField::IdleTime, key).await? {
+            Reply::Integer(secs) => Ok(Some(secs as u64)),
+            Reply::Bulk(None) => Ok(None),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// The key's access frequency counter, see [`crate::store::Access`], or
+    /// `None` if it doesn't exist.
+    pub async fn object_freq(&mut self, key: &[u8]) -> Result<Option<u8>> {
+        match self.object(ObjectField::Freq, key).await? {
+            Reply::Integer(freq) => Ok(Some(freq as u8)),
+            Reply::Bulk(None) => Ok(None),
+            reply => unexpected(reply),
+        }
+    }
+
+    async fn object(&mut self, field: ObjectField, key: &[u8]) -> Result<Reply> {
+        self.call(&Command::Object(field, key.into())).await
+    }
+
+    /// Applies `SET`s and `DEL`s as one atomic write.
+    pub async fn batch(&mut self, commands: Vec<Command<'_>>) -> Result<()> {
+        match self.call(&Command::Batch(commands)).await? {
+            Reply::Status(_) => Ok(()),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Writes the namespace's keys starting with `prefix` to `out` in
+    /// `format` as they arrive, see [`crate::export`]. Returns how many
+    /// bytes were written.
+    pub async fn export(
+        &mut self,
+        format: ExportFormat,
+        prefix: Option<&[u8]>,
+        out: &mut (impl AsyncWrite + Unpin),
+    ) -> Result<u64> {
+        let command = Command::Export(format, prefix.map(Into::into));
+        let mut written = 0;
+        let mut reply = self.call(&command).await?;
+        loop {
+            match reply {
+                Reply::Bulk(Some(chunk)) if chunk.is_empty() => return Ok(written),
+                Reply::Bulk(Some(chunk)) => {
+                    out.write_all(&chunk).await?;
+                    written += chunk.len() as u64;
+                }
+                reply => return unexpected(reply),
+            }
+            reply = self.read_reply().await?;
+        }
+    }
+
+    /// Loads `writes`, `SET`s and `DEL`s, with `BULKLOAD`, sending them all
+    /// before waiting on a reply. Returns how many were loaded.
+    pub async fn bulk_load(&mut self, writes: &[Command<'_>]) -> Result<u64> {
+        match self.call(&Command::BulkLoad).await? {
+            Reply::Status(_) => {}
+            reply => return unexpected(reply),
+        }
+        let mut requests = BytesMut::new();
+        for write in writes {
+            write.encode(&mut requests);
+            if requests.len() >= 64 * 1024 {
+                self.send_raw(&requests).await?;
+                requests.clear();
+            }
+        }
+        Command::BulkLoadEnd.encode(&mut requests);
+        self.send_raw(&requests).await?;
+        match self.read_reply().await? {
+            Reply::Integer(n) => Ok(n as u64),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// A key picked at random, or `None` if there are none.
+    pub async fn random_key(&mut self) -> Result<Option<Bytes>> {
+        match self.call(&Command::RandomKey).await? {
+            Reply::Bulk(key) => Ok(key),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Up to `n` different keys picked at random.
+    pub async fn sample(&mut self, n: usize) -> Result<Vec<Bytes>> {
+        match self.call(&Command::Sample(n)).await? {
+            Reply::Push(keys) => Ok(keys),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// The key serialized with its TTL, for [`DistKvClient::restore`].
+    pub async fn dump(&mut self, key: &[u8]) -> Result<Option<Bytes>> {
+        match self.call(&Command::Dump(key.into())).await? {
+            Reply::Bulk(dump) => Ok(dump),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Sets `key` from a [`DistKvClient::dump`], overwriting it only if
+    /// `replace` is set.
+    pub async fn restore(&mut self, key: &[u8], dump: &[u8], replace: bool) -> Result<()> {
+        match self
+            .call(&Command::Restore(key.into(), dump.into(), replace))
+            .await?
+        {
+            Reply::Status(_) => Ok(()),
+            reply => unexpected(reply),
+        }
+    }
+
+    /// Returns whether the key existed.
+    pub async fn del(&mut self, key: &[u8]) -> Result<bool> {
+        match self.call(&Command::Delete(key.into())).await? {
+            Reply::Integer(n) => Ok(n > 0),
+            Reply::Bulk(None) => Ok(false),
+            reply => unexpected(reply),
+        }
+    }
+}
+
+fn unexpected<T>(reply: Reply) -> Result<T> {
+    match reply {
+        Reply::Error(err) => Err(DistKvError::from_line(&err)),
+        reply => Err(DistKvError::Protocol(format!(
+            "unexpected reply {:?}",
+            reply
+        ))),
+    }
+}
+
+/// A reply line that doesn't parse.
+fn malformed(what: &str, line: &[u8]) -> DistKvError {
+    DistKvError::Protocol(format!("{}, got {:?}", what, String::from_utf8_lossy(line)))
+}
@@ -0,0 +1,37 @@
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// A monotonic time source, so code that measures time can run against the
+/// simulator's virtual clock as well as the real one.
+pub trait Clock {
+    /// Time elapsed since some fixed, clock-specific starting point.
+    fn now(&self) -> Duration;
+
+    /// Wall-clock time since the Unix epoch, which key expiry is measured
+    /// in.
+    fn unix_time(&self) -> Duration;
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SystemClock {
+    start: Instant,
+}
+
+impl Default for SystemClock {
+    fn default() -> Self {
+        SystemClock {
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Clock for SystemClock {
+    fn now(&self) -> Duration {
+        self.start.elapsed()
+    }
+
+    fn unix_time(&self) -> Duration {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+    }
+}
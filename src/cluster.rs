@@ -0,0 +1,420 @@
+//! An in-process leader and followers for end-to-end tests.
+//!
+//! Every node listens on an ephemeral localhost port and keeps its log in a
+//! temporary directory that is removed when the cluster is dropped. Killing
+//! a node drops its listener, connections and open log without any shutdown,
+//! and restarting it replays the log on the same port.
+//!
+//! A cluster started with [`TestCluster::start_in_memory`] connects its
+//! nodes and clients through a [`MemoryNetwork`] instead of TCP, each node
+//! on a host of its own, so that it can be
+//! [partitioned](TestCluster::partition_follower).
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::task::JoinHandle;
+
+use crate::client::DistKvClient;
+use crate::clock::SystemClock;
+use crate::config::Config;
+use crate::error::{bail, DistKvError, Result};
+use crate::follower::{self, SyncFollower};
+use crate::node::{FollowerCore, LeaderCore};
+use crate::protocol::Limits;
+use crate::server::{self, Leader, Replica, SyncLeader};
+use crate::store::{Db, Strictness};
+use crate::transport::{MemoryNetwork, Tcp, Transport};
+use crate::wal::{self, Durability, LogFile, NamespaceLogs};
+use crate::webhook::Webhooks;
+
+/// How long [`TestCluster::wait_for_replication`] waits for followers.
+const REPLICATION_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A node's address and log, plus its state and serving task while it runs.
+struct Node<T> {
+    addr: SocketAddr,
+    log: PathBuf,
+    running: Option<(T, JoinHandle<()>)>,
+    /// Whether a restarted leader is pointed at the node, rather than
+    /// leaving it to the membership in the leader's log.
+    founding: bool,
+}
+
+impl<T> Node<T> {
+    async fn kill(&mut self) {
+        if let Some((_, task)) = self.running.take() {
+            task.abort();
+            let _ = task.await;
+        }
+    }
+}
+
+pub struct TestCluster {
+    dir: PathBuf,
+    limits: Limits,
+    /// The namespace, tenant, function and trigger settings the nodes
+    /// start with.
+    config: Config,
+    leader: Node<SyncLeader>,
+    followers: Vec<Node<SyncFollower>>,
+    /// The network the nodes are connected through, if not TCP.
+    network: Option<MemoryNetwork>,
+}
+
+/// Replays the log at `path` and the logs of `namespaces` kept next to it,
+/// returning the highest LSN they hold as well.
+fn open_log(
+    path: &Path,
+    namespaces: &BTreeMap<String, Durability>,
+) -> Result<(Db, File, NamespaceLogs<File>, u64)> {
+    let path = path
+        .to_str()
+        .ok_or_else(|| DistKvError::Config("log path is not UTF-8".to_string()))?;
+    let (mut hashmap, file, report) = wal::open_log_with(path, Strictness::Strict)?;
+    let (namespace_logs, lsn) =
+        wal::open_namespace_logs(path, namespaces, Strictness::Strict, &mut hashmap)?;
+    Ok((hashmap, file, namespace_logs, report.lsn.max(lsn)))
+}
+
+/// The host in-memory clusters connect clients from.
+const CLIENT_HOST: Ipv4Addr = Ipv4Addr::new(10, 0, 1, 1);
+
+/// The address of node `n`, 0 for the leader. Over TCP every node is on
+/// localhost, while on an in-memory network each has a host of its own, for
+/// partitions to tell them apart.
+fn host(network: &Option<MemoryNetwork>, n: usize) -> IpAddr {
+    match network {
+        Some(_) => IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1 + n as u8)),
+        None => IpAddr::V4(Ipv4Addr::LOCALHOST),
+    }
+}
+
+fn temp_dir() -> Result<PathBuf> {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    let dir = std::env::temp_dir().join(format!("dist-kv-cluster-{}-{}", std::process::id(), n));
+    std::fs::create_dir_all(&dir)?;
+    Ok(dir)
+}
+
+impl TestCluster {
+    /// Starts a leader replicating to `followers` followers, all with fresh
+    /// logs. Must be called from within a tokio runtime.
+    pub async fn start(followers: usize) -> Result<TestCluster> {
+        Self::start_with_config(followers, Config::default()).await
+    }
+
+    /// Like [`TestCluster::start`], with the `namespace`, `tenant`,
+    /// `function` and `trigger` settings of `config`. Its limits are not applied.
+    pub async fn start_with_config(followers: usize, config: Config) -> Result<TestCluster> {
+        Self::start_on(followers, config, None).await
+    }
+
+    /// Like [`TestCluster::start`], on an in-memory network rather than
+    /// TCP. Clients must connect with [`TestCluster::connect`].
+    pub async fn start_in_memory(followers: usize) -> Result<TestCluster> {
+        Self::start_on(followers, Config::default(), Some(MemoryNetwork::new())).await
+    }
+
+    async fn start_on(
+        followers: usize,
+        config: Config,
+        network: Option<MemoryNetwork>,
+    ) -> Result<TestCluster> {
+        let dir = temp_dir()?;
+        let mut cluster = TestCluster {
+            leader: Node {
+                addr: SocketAddr::new(host(&network, 0), 0),
+                log: dir.join("leader.log"),
+                running: None,
+                founding: true,
+            },
+            followers: Vec::new(),
+            limits: Limits::NONE,
+            config,
+            dir,
+            network,
+        };
+        for i in 0..followers {
+            cluster.followers.push(Node {
+                addr: SocketAddr::new(host(&cluster.network, i + 1), 0),
+                log: cluster.dir.join(format!("follower-{}.log", i)),
+                running: None,
+                founding: true,
+            });
+            cluster.restart_follower(i).await?;
+        }
+        cluster.restart_leader().await?;
+        Ok(cluster)
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn leader_addr(&self) -> SocketAddr {
+        self.leader.addr
+    }
+
+    pub fn follower_addr(&self, i: usize) -> SocketAddr {
+        self.followers[i].addr
+    }
+
+    /// Connects a new client to the leader.
+    pub async fn client(&self) -> Result<DistKvClient> {
+        self.connect(self.leader.addr).await
+    }
+
+    /// Connects a new client to the node at `addr`.
+    pub async fn connect(&self, addr: SocketAddr) -> Result<DistKvClient> {
+        let transport = self.transport(IpAddr::V4(CLIENT_HOST));
+        DistKvClient::connect_via(transport, &addr.to_string()).await
+    }
+
+    /// How the node at `ip` connects to the others.
+    fn transport(&self, ip: IpAddr) -> Arc<dyn Transport> {
+        match &self.network {
+            Some(network) => Arc::new(network.host(ip)),
+            None => Arc::new(Tcp),
+        }
+    }
+
+    /// Cuts the leader and follower `i` off from each other, until
+    /// [`TestCluster::heal`]. Only an in-memory cluster can be partitioned.
+    pub fn partition_follower(&self, i: usize) -> Result<()> {
+        let Some(network) = &self.network else {
+            bail!(Config, "only an in-memory cluster can be partitioned");
+        };
+        network.partition(self.leader.addr.ip(), self.followers[i].addr.ip());
+        Ok(())
+    }
+
+    /// Undoes every partition.
+    pub fn heal(&self) {
+        if let Some(network) = &self.network {
+            network.heal();
+        }
+    }
+
+    /// The leader's state, if it is running.
+    pub fn leader(&self) -> Option<&SyncLeader> {
+        self.leader.running.as_ref().map(|(leader, _)| leader)
+    }
+
+    /// A copy of the leader's map, if it is running.
+    pub async fn leader_hashmap(&self) -> Option<Db> {
+        let (leader, _) = self.leader.running.as_ref()?;
+        Some(leader.lock().await.core.hashmap.clone())
+    }
+
+    /// A copy of follower `i`'s map, if it is running.
+    pub fn follower_hashmap(&self, i: usize) -> Option<Db> {
+        let (follower, _) = self.followers[i].running.as_ref()?;
+        Some(follower.lock().unwrap().hashmap.clone())
+    }
+
+    /// Waits until every running follower's map matches the leader's.
+    pub async fn wait_for_replication(&self) -> Result<()> {
+        let Some(expected) = self.leader_hashmap().await else {
+            bail!(Config, "the leader is not running");
+        };
+        let deadline = tokio::time::Instant::now() + REPLICATION_TIMEOUT;
+        for i in 0..self.followers.len() {
+            while self.follower_hashmap(i).is_some_and(|map| map != expected) {
+                if tokio::time::Instant::now() > deadline {
+                    bail!(Config, "follower {} did not catch up with the leader", i);
+                }
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stops the leader abruptly, as if the process had been killed.
+    pub async fn kill_leader(&mut self) {
+        self.leader.kill().await;
+    }
+
+    /// Starts the leader (again), replaying its log and reconnecting to every
+    /// follower it was started with that is running. Nodes added or removed
+    /// since with `CLUSTER` are as its log has them.
+    pub async fn restart_leader(&mut self) -> Result<()> {
+        self.kill_leader().await;
+        let (hashmap, file, namespace_logs, lsn) =
+            open_log(&self.leader.log, &self.config.namespaces)?;
+        let transport = self.transport(self.leader.addr.ip());
+        let listener = transport.bind(&self.leader.addr.to_string()).await?;
+        self.leader.addr = listener.local_addr()?;
+        let mut replicas = Vec::new();
+        for follower in self.followers.iter().filter(|f| f.founding) {
+            let addr = follower.addr.to_string();
+            let stream = match follower.running {
+                Some(_) => Some(transport.connect(&addr).await?),
+                None => None,
+            };
+            replicas.push(Replica::new(addr, stream));
+        }
+        let file = LogFile::open(file, self.config.wal_backend)?;
+        let mut core = LeaderCore::new(hashmap, file, SystemClock::default());
+        core.namespace_logs = namespace_logs.map(LogFile::File);
+        core.lsn = lsn;
+        let mut leader = Leader::new(core, replicas);
+        leader.tenants = self.config.tenants.clone();
+        leader.functions = self.config.functions.clone();
+        leader.webhooks = Webhooks::start(self.config.triggers.clone());
+        leader.addr = Some(self.leader.addr.to_string());
+        leader.config = self.config.clone();
+        leader.data_dir = self.dir.clone();
+        leader.log_path = self.leader.log.to_str().map(str::to_string);
+        leader.transport = transport;
+        leader.restore_membership().await;
+        let leader = Arc::new(tokio::sync::Mutex::new(leader));
+        let task = tokio::spawn(server::serve(listener, leader.clone(), self.limits));
+        self.leader.running = Some((leader, task));
+        Ok(())
+    }
+
+    /// Stops follower `i` abruptly, as if the process had been killed.
+    pub async fn kill_follower(&mut self, i: usize) {
+        self.followers[i].kill().await;
+    }
+
+    /// Starts follower `i` (again), replaying its log, and points a running
+    /// leader at the new process. Writes made while it was down are not
+    /// caught up on.
+    pub async fn restart_follower(&mut self, i: usize) -> Result<()> {
+        self.revive_follower(i).await?;
+        let node = &self.followers[i];
+        if let Some((leader, _)) = &self.leader.running {
+            let addr = node.addr.to_string();
+            let stream = leader.lock().await.transport.connect(&addr).await?;
+            let replica = Replica::new(addr, Some(stream));
+            let followers = &mut leader.lock().await.followers;
+            match followers.get_mut(i) {
+                Some(follower) => *follower = replica,
+                None => followers.push(replica),
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts follower `i` again, replaying its log, leaving the leader to
+    /// find out on its own that it is back.
+    pub async fn revive_follower(&mut self, i: usize) -> Result<()> {
+        let node = &mut self.followers[i];
+        node.kill().await;
+        let (hashmap, file, namespace_logs, lsn) = open_log(&node.log, &self.config.namespaces)?;
+        let mut follower = FollowerCore::new(hashmap, file, SystemClock::default());
+        follower.namespace_logs = namespace_logs;
+        follower.lsn = lsn;
+        follower.log_path = node.log.to_str().map(str::to_string);
+        let follower = Arc::new(Mutex::new(follower));
+        let addr = node.addr;
+        let listener = self.transport(addr.ip()).bind(&addr.to_string()).await?;
+        let node = &mut self.followers[i];
+        node.addr = listener.local_addr()?;
+        let serving = follower.clone();
+        let task = tokio::spawn(async move {
+            if let Err(e) = follower::serve(listener, serving).await {
+                eprintln!("Error = {:?}", e);
+            }
+        });
+        node.running = Some((follower, task));
+        Ok(())
+    }
+
+    /// Starts a new follower with an empty log that bootstraps itself from a
+    /// snapshot of the running leader, returning its index.
+    pub async fn add_follower(&mut self) -> Result<usize> {
+        if self.leader.running.is_none() {
+            bail!(Config, "the leader is not running");
+        }
+        let i = self.followers.len();
+        let log = self.dir.join(format!("follower-{}.log", i));
+        let (hashmap, file, namespace_logs, _) = open_log(&log, &self.config.namespaces)?;
+        let mut follower = FollowerCore::new(hashmap, file, SystemClock::default());
+        follower.namespace_logs = namespace_logs;
+        follower.log_path = log.to_str().map(str::to_string);
+        let ip = host(&self.network, i + 1);
+        let transport = self.transport(ip);
+        let listener = transport.bind(&SocketAddr::new(ip, 0).to_string()).await?;
+        let addr = listener.local_addr()?;
+        follower.addr = Some(addr.to_string());
+        let follower = Arc::new(Mutex::new(follower));
+        let leader_addr = self.leader.addr.to_string();
+        let (syncing, serving) = (follower.clone(), follower.clone());
+        let task = tokio::spawn(async move {
+            let syncing = async {
+                if let Err(e) = follower::bootstrap(&*transport, &leader_addr, &syncing).await {
+                    eprintln!("Error = {:?}", e);
+                }
+            };
+            let serving = async {
+                if let Err(e) = follower::serve(listener, serving).await {
+                    eprintln!("Error = {:?}", e);
+                }
+            };
+            tokio::join!(syncing, serving);
+        });
+        self.followers.push(Node {
+            addr,
+            log,
+            running: Some((follower, task)),
+            founding: true,
+        });
+        Ok(i)
+    }
+
+    /// Starts a new follower with an empty log that the leader doesn't
+    /// replicate to until it is added with `CLUSTER ADD-NODE`, returning its
+    /// index.
+    pub async fn start_node(&mut self) -> Result<usize> {
+        let i = self.followers.len();
+        let log = self.dir.join(format!("follower-{}.log", i));
+        let (hashmap, file, namespace_logs, _) = open_log(&log, &self.config.namespaces)?;
+        let mut follower = FollowerCore::new(hashmap, file, SystemClock::default());
+        follower.namespace_logs = namespace_logs;
+        follower.log_path = log.to_str().map(str::to_string);
+        let ip = host(&self.network, i + 1);
+        let listener = self
+            .transport(ip)
+            .bind(&SocketAddr::new(ip, 0).to_string())
+            .await?;
+        let addr = listener.local_addr()?;
+        follower.addr = Some(addr.to_string());
+        let follower = Arc::new(Mutex::new(follower));
+        let serving = follower.clone();
+        let task = tokio::spawn(async move {
+            if let Err(e) = follower::serve(listener, serving).await {
+                eprintln!("Error = {:?}", e);
+            }
+        });
+        self.followers.push(Node {
+            addr,
+            log,
+            running: Some((follower, task)),
+            founding: false,
+        });
+        Ok(i)
+    }
+}
+
+impl Drop for TestCluster {
+    fn drop(&mut self) {
+        if let Some((_, task)) = self.leader.running.take() {
+            task.abort();
+        }
+        for node in &mut self.followers {
+            if let Some((_, task)) = node.running.take() {
+                task.abort();
+            }
+        }
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
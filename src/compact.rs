@@ -0,0 +1,265 @@
+//! The byte strings the map holds its keys and values as.
+//!
+//! Most keys, and many values, are only a few bytes long: IDs, counters,
+//! flags. Held as [`Bytes`] each would be a 32-byte handle plus an
+//! allocation of its own, and another for the reference count the first
+//! time it is cloned. A [`CompactBytes`] is 24 bytes, and keeps up to
+//! [`INLINE`] bytes in those 24 with no allocation at all. Longer strings
+//! go behind an `Arc`, so that cloning one, as the map does for each index
+//! a key is in, only counts a reference.
+//!
+//! With `intern_values` on, the leader's map also shares one `Arc` between
+//! every value with the same bytes, see [`Interner`], for data where many
+//! keys hold the same payload, like feature flags or statuses.
+
+use std::borrow::Borrow;
+use std::cmp::Ordering;
+use std::collections::hash_map::{Entry, HashMap};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Deref;
+use std::sync::Arc;
+
+use bytes::Bytes;
+
+/// The longest string kept inline.
+pub const INLINE: usize = 22;
+
+#[derive(Clone)]
+pub struct CompactBytes(Repr);
+
+#[derive(Clone)]
+enum Repr {
+    Inline { len: u8, buf: [u8; INLINE] },
+    Heap(Arc<[u8]>),
+}
+
+impl CompactBytes {
+    pub const fn new() -> CompactBytes {
+        CompactBytes(Repr::Inline {
+            len: 0,
+            buf: [0; INLINE],
+        })
+    }
+
+    pub fn copy_from_slice(bytes: &[u8]) -> CompactBytes {
+        if bytes.len() <= INLINE {
+            let mut buf = [0; INLINE];
+            buf[..bytes.len()].copy_from_slice(bytes);
+            CompactBytes(Repr::Inline {
+                len: bytes.len() as u8,
+                buf,
+            })
+        } else {
+            CompactBytes(Repr::Heap(bytes.into()))
+        }
+    }
+
+    /// Whether the string is kept inline rather than behind an `Arc`.
+    pub fn is_inline(&self) -> bool {
+        matches!(self.0, Repr::Inline { .. })
+    }
+
+    /// Whether `self` and `other` are the same allocation, rather than
+    /// just the same bytes.
+    pub fn shares(&self, other: &CompactBytes) -> bool {
+        match (&self.0, &other.0) {
+            (Repr::Heap(a), Repr::Heap(b)) => Arc::ptr_eq(a, b),
+            _ => false,
+        }
+    }
+
+    /// The bytes from `range`, in a string of their own.
+    pub fn slice(&self, range: std::ops::Range<usize>) -> CompactBytes {
+        CompactBytes::copy_from_slice(&self[range])
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        Bytes::copy_from_slice(self)
+    }
+}
+
+impl Default for CompactBytes {
+    fn default() -> CompactBytes {
+        CompactBytes::new()
+    }
+}
+
+impl Deref for CompactBytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match &self.0 {
+            Repr::Inline { len, buf } => &buf[..*len as usize],
+            Repr::Heap(bytes) => bytes,
+        }
+    }
+}
+
+impl AsRef<[u8]> for CompactBytes {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl Borrow<[u8]> for CompactBytes {
+    fn borrow(&self) -> &[u8] {
+        self
+    }
+}
+
+/// Hashes as the slice does, so that a map keyed by these can be looked up
+/// by `&[u8]`.
+impl Hash for CompactBytes {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        (**self).hash(state)
+    }
+}
+
+impl PartialEq for CompactBytes {
+    fn eq(&self, other: &CompactBytes) -> bool {
+        **self == **other
+    }
+}
+
+impl Eq for CompactBytes {}
+
+impl PartialEq<[u8]> for CompactBytes {
+    fn eq(&self, other: &[u8]) -> bool {
+        **self == *other
+    }
+}
+
+impl PartialEq<&[u8]> for CompactBytes {
+    fn eq(&self, other: &&[u8]) -> bool {
+        **self == **other
+    }
+}
+
+impl PartialEq<str> for CompactBytes {
+    fn eq(&self, other: &str) -> bool {
+        **self == *other.as_bytes()
+    }
+}
+
+impl PartialEq<&str> for CompactBytes {
+    fn eq(&self, other: &&str) -> bool {
+        **self == *other.as_bytes()
+    }
+}
+
+impl PartialOrd for CompactBytes {
+    fn partial_cmp(&self, other: &CompactBytes) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for CompactBytes {
+    fn cmp(&self, other: &CompactBytes) -> Ordering {
+        (**self).cmp(&**other)
+    }
+}
+
+/// Written as `Bytes` writes itself, `b"..."` with anything that isn't
+/// printable ASCII escaped.
+impl fmt::Debug for CompactBytes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "b\"")?;
+        for &b in self.iter() {
+            match b {
+                b'\n' => write!(f, "\\n")?,
+                b'\r' => write!(f, "\\r")?,
+                b'\t' => write!(f, "\\t")?,
+                b'\\' | b'"' => write!(f, "\\{}", b as char)?,
+                0x20..=0x7e => write!(f, "{}", b as char)?,
+                _ => write!(f, "\\x{:02x}", b)?,
+            }
+        }
+        write!(f, "\"")
+    }
+}
+
+impl From<&[u8]> for CompactBytes {
+    fn from(bytes: &[u8]) -> CompactBytes {
+        CompactBytes::copy_from_slice(bytes)
+    }
+}
+
+impl From<&str> for CompactBytes {
+    fn from(text: &str) -> CompactBytes {
+        CompactBytes::copy_from_slice(text.as_bytes())
+    }
+}
+
+impl From<Vec<u8>> for CompactBytes {
+    fn from(bytes: Vec<u8>) -> CompactBytes {
+        if bytes.len() <= INLINE {
+            CompactBytes::copy_from_slice(&bytes)
+        } else {
+            CompactBytes(Repr::Heap(bytes.into()))
+        }
+    }
+}
+
+impl From<Bytes> for CompactBytes {
+    fn from(bytes: Bytes) -> CompactBytes {
+        CompactBytes::copy_from_slice(&bytes)
+    }
+}
+
+impl From<CompactBytes> for Bytes {
+    fn from(bytes: CompactBytes) -> Bytes {
+        bytes.to_bytes()
+    }
+}
+
+/// The distinct long strings interned, each with how many of the strings
+/// handed out for it are in use. Strings short enough to be inline are left
+/// alone, as there is no allocation to share.
+///
+/// A string is forgotten once every use of it is
+/// [released](Interner::release), whatever copies of it are still held
+/// elsewhere, such as in the history.
+#[derive(Debug, Clone, Default)]
+pub struct Interner {
+    strings: HashMap<CompactBytes, usize>,
+}
+
+impl Interner {
+    /// `bytes`, or the interned string with the same bytes, counted as a
+    /// use of it until it is released.
+    pub fn intern(&mut self, bytes: CompactBytes) -> CompactBytes {
+        if bytes.is_inline() {
+            return bytes;
+        }
+        match self.strings.entry(bytes) {
+            Entry::Occupied(mut shared) => {
+                *shared.get_mut() += 1;
+                shared.key().clone()
+            }
+            Entry::Vacant(new) => {
+                let bytes = new.key().clone();
+                new.insert(1);
+                bytes
+            }
+        }
+    }
+
+    /// Ends a use of `bytes`, which [`Interner::intern`] returned, forgetting
+    /// it if it was the last.
+    pub fn release(&mut self, bytes: &CompactBytes) {
+        let Some(uses) = self.strings.get_mut(&bytes[..]) else {
+            return;
+        };
+        *uses -= 1;
+        if *uses == 0 {
+            self.strings.remove(&bytes[..]);
+        }
+    }
+
+    /// How many distinct strings are interned, and their bytes.
+    pub fn stats(&self) -> (usize, usize) {
+        let bytes = self.strings.keys().map(|shared| shared.len()).sum();
+        (self.strings.len(), bytes)
+    }
+}
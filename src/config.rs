@@ -0,0 +1,841 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use crate::protocol::{self, Command, Limits};
+use bytes::Bytes;
+
+use crate::archive;
+use crate::error::{bail, Context, DistKvError, Result};
+use crate::filter::{KeyFilter, Rewrite};
+use crate::replication::{Batching, Overflow, QueueLimit};
+use crate::script;
+use crate::store::{Strictness, DEFAULT_NAMESPACE};
+use crate::tenant::Tenant;
+use crate::wal::{Durability, WalBackend};
+use crate::webhook::{self, Trigger};
+
+/// The settings `CONFIG SET` and a reload on `SIGHUP` can change while the
+/// server runs.
+pub const LIVE_SETTINGS: &[&str] = &[
+    "bulkload_batch",
+    "intern_values",
+    "max_key_size",
+    "max_value_size",
+    "min_free_space",
+    "request_dedup_timeout_ms",
+    "request_dedup_window",
+    "session_timeout_ms",
+    "slow_query_ms",
+];
+
+/// Server settings, read from a file of `name value` lines. Blank lines and
+/// lines starting with `#` are ignored; sizes may carry a `kb`, `mb` or `gb`
+/// suffix.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+    /// `strict` refuses to start from a log with corrupt records, `skip`
+    /// skips them and reports their offsets.
+    pub log_recovery: Strictness,
+    /// The leader to bootstrap from with a snapshot, making this process a
+    /// follower of it rather than a leader with a follower of its own.
+    pub replicaof: Option<String>,
+    /// Nodes to learn the cluster from, from `seeds <host:port>[,...]`,
+    /// when there is no `replicaof`: the process becomes a follower of the
+    /// leader they name.
+    pub seeds: Vec<String>,
+    /// Namespaces kept out of the node's log, from `namespace <name> memory`
+    /// for ones that live only in memory and `namespace <name> separate` for
+    /// ones logged to a file of their own.
+    pub namespaces: BTreeMap<String, Durability>,
+    /// Tenants by name, from `tenant <name> <password> <prefix>` lines that
+    /// may go on with `max_keys <n>`, `max_bytes <size>`, and the per-second
+    /// rates `max_ops <n>` and `max_write_bytes <size>`.
+    pub tenants: BTreeMap<String, Tenant>,
+    /// The scripts `FCALL` can call, from `function <name> <path>` lines
+    /// naming a file that holds the function's script.
+    pub functions: BTreeMap<String, Bytes>,
+    /// Webhooks, from `trigger <pattern> <url>` lines: writes to keys
+    /// matching the glob pattern are posted to the URL.
+    pub triggers: Vec<Trigger>,
+    /// Where the leader archives its log and snapshots, from `archive
+    /// <location>`: a directory or an `http://` URL of a bucket.
+    pub archive: Option<String>,
+    /// Where spans are exported to, from `otlp_endpoint <url>`: the
+    /// `http://` URL of an OpenTelemetry collector's OTLP traces endpoint.
+    pub otlp_endpoint: Option<String>,
+    /// How the leader batches the records it replicates: it writes them to
+    /// a follower once `replication_batch_size` of them are waiting, and
+    /// otherwise every `replication_flush_ms` milliseconds. A flush interval
+    /// of 0 writes every record as it is made, for the lowest latency.
+    pub replication_batch_size: usize,
+    pub replication_flush_ms: u64,
+    /// Whether the leader compresses what it replicates, from
+    /// `replication_compression lz4|none`, for followers that agree to it.
+    pub replication_compression: bool,
+    /// How many bytes of records the leader queues for a follower, from
+    /// `replication_queue_size`, and what it does when a follower falls
+    /// further behind than that, from `replication_overflow`: `block`
+    /// holds up writes until the follower catches up, `spill` writes the
+    /// records to a file in `replication_spill_dir` to send later, and
+    /// `drop` drops the follower.
+    pub replication_queue_size: usize,
+    pub replication_overflow: Overflow,
+    pub replication_spill_dir: String,
+    /// Followers kept as remote copies over a wide-area link, from
+    /// `wan_replicas <host:port>[,...]`. They are batched by
+    /// `wan_batch_size` and `wan_flush_ms` instead, always offered lz4,
+    /// spill rather than hold up writes when `replication_overflow` is
+    /// `block`, and are left out of `QUORUM` reads. The leader warns when
+    /// more than `wan_lag_alarm` bytes are queued for one, 0 never.
+    pub wan_replicas: Vec<String>,
+    pub wan_batch_size: usize,
+    pub wan_flush_ms: u64,
+    pub wan_lag_alarm: usize,
+    /// The key prefixes each follower is sent, by address, from
+    /// `replication_filter <host:port> <prefix>[,...]` lines; see
+    /// [`crate::filter`]. Followers without one are sent every key.
+    pub replication_filters: BTreeMap<String, KeyFilter>,
+    /// What each follower's records are put through, by address, from
+    /// `replication_transform <host:port> <transform>` lines in the order
+    /// given; see [`crate::filter::Rewrite`].
+    pub replication_transforms: BTreeMap<String, Vec<Rewrite>>,
+    /// The free space the leader keeps on its disk: with less, it refuses
+    /// writes until there is that much again. 0 turns the check off.
+    pub min_free_space: usize,
+    /// Whether the leader starts out refusing writes, from `read_only
+    /// on|off`, as if sent `READONLY ON`.
+    pub read_only: bool,
+    /// How often the leader checkpoints its map, from `checkpoint_interval`
+    /// in seconds, so that a restart only replays the log written since. 0
+    /// turns checkpoints off.
+    pub checkpoint_interval: Duration,
+    /// How large the leader lets a segment of its log grow before the next
+    /// checkpoint starts a new one and removes those it covers, and how much
+    /// disk space it reserves for each up front. 0 keeps the whole log in one
+    /// segment, allocated as it grows.
+    pub log_segment_size: usize,
+    /// How the leader writes and syncs its log, from `wal_backend
+    /// file|io_uring`. Takes effect on restart.
+    pub wal_backend: WalBackend,
+    /// How many of its last writes the leader keeps the changes of, from
+    /// `history_size`, for `GET key AT <lsn>` to look back over. 0 keeps
+    /// none. Takes effect on restart.
+    pub history_size: usize,
+    /// Where the leader logs who sent each write and administrative command,
+    /// from `audit_log <path>`, and how large the log grows before it is
+    /// rotated, from `audit_log_size`; see [`crate::audit`]. A size of 0
+    /// never rotates it. Both take effect on restart.
+    pub audit_log: Option<String>,
+    pub audit_log_size: usize,
+    /// The commands the leader's client port accepts, from `commands
+    /// <name>[,...]|all`.
+    pub commands: Accepted,
+    /// More ports the leader takes clients on, each with the commands it
+    /// accepts, from `listen <host:port> <name>[,...]|all` lines.
+    pub listeners: Vec<(String, Accepted)>,
+    /// How long a command takes before it is kept as a slow query, from
+    /// `slow_query_ms`.
+    pub slow_query_ms: u64,
+    /// The most writes of a `BULKLOAD` applied as one batch, from
+    /// `bulkload_batch`.
+    pub bulkload_batch: usize,
+    /// Whether the leader keeps one copy of values that many keys hold the
+    /// same bytes of, from `intern_values on|off`; see [`crate::compact`].
+    /// Off by default.
+    pub intern_values: bool,
+    /// Where the leader serves its web dashboard, from `dashboard
+    /// <host:port>`, in builds with the `dashboard` feature; see
+    /// [`crate::dashboard`]. Takes effect on restart.
+    pub dashboard: Option<String>,
+    /// Whether the leader runs the `DEBUG` subcommands tests use to stall
+    /// it, look inside values and force LSNs, from `debug_commands on|off`.
+    /// Off by default, and only turned on by a restart.
+    pub debug_commands: bool,
+    /// How long a lease the leader asks followers for when a `QUORUM` read
+    /// asks them, from `read_lease_ms`; 0 asks for none. While it lasts
+    /// `QUORUM` reads are answered by the leader alone.
+    pub read_lease_ms: u64,
+    /// How far apart the leader's and followers' clocks may run over a
+    /// lease, from `max_clock_drift_ms`. The leader's lease is cut short by
+    /// this much.
+    pub max_clock_drift_ms: u64,
+    /// How long a connection that set `EPHEMERAL` keys may send nothing
+    /// before the leader closes it and deletes them, from
+    /// `session_timeout_ms`; 0 waits for the connection to close.
+    pub session_timeout_ms: u64,
+    /// How many of each client's writes sent after a `REQID` the leader
+    /// remembers the replies to, so that retries of them aren't applied
+    /// again, from `request_dedup_window`; 0 remembers none.
+    pub request_dedup_window: usize,
+    /// How long a client can go without a write sent after a `REQID` before
+    /// the leader forgets its IDs, from `request_dedup_timeout_ms`; 0 keeps
+    /// them for as long as the leader runs.
+    pub request_dedup_timeout_ms: u64,
+}
+
+/// The commands a listener accepts: every one, or only those named.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Accepted {
+    #[default]
+    All,
+    Only(BTreeSet<String>),
+}
+
+impl Accepted {
+    /// Parses `all` or a comma-separated list of command names.
+    pub fn parse(value: &str) -> Result<Accepted> {
+        if value == "all" {
+            return Ok(Accepted::All);
+        }
+        let mut names = BTreeSet::new();
+        for name in value
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+        {
+            let name = name.to_ascii_uppercase();
+            if !protocol::is_command(&name) {
+                bail!(Config, "unknown command {}", name);
+            }
+            names.insert(name);
+        }
+        if names.is_empty() {
+            bail!(Config, "expected `all` or a list of commands");
+        }
+        Ok(Accepted::Only(names))
+    }
+
+    /// Whether `command` may be run. A command run `IN` a namespace needs
+    /// both `IN` and itself to be accepted.
+    pub fn accepts(&self, command: &Command<'_>) -> bool {
+        let Accepted::Only(names) = self else {
+            return true;
+        };
+        names.contains(command.name())
+            && match command {
+                Command::In(_, command) => self.accepts(command),
+                _ => true,
+            }
+    }
+
+    fn render(&self) -> String {
+        match self {
+            Accepted::All => "all".to_string(),
+            Accepted::Only(names) => names.iter().cloned().collect::<Vec<_>>().join(","),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_key_size: 64 * 1024,
+            max_value_size: 512 * 1024 * 1024,
+            log_recovery: Strictness::Strict,
+            replicaof: None,
+            seeds: Vec::new(),
+            namespaces: BTreeMap::new(),
+            tenants: BTreeMap::new(),
+            functions: BTreeMap::new(),
+            triggers: Vec::new(),
+            archive: None,
+            otlp_endpoint: None,
+            replication_batch_size: 64 * 1024,
+            replication_flush_ms: 2,
+            replication_compression: false,
+            replication_queue_size: 64 * 1024 * 1024,
+            replication_overflow: Overflow::Block,
+            replication_spill_dir: ".".to_string(),
+            wan_replicas: Vec::new(),
+            wan_batch_size: 1024 * 1024,
+            wan_flush_ms: 100,
+            wan_lag_alarm: 16 * 1024 * 1024,
+            replication_filters: BTreeMap::new(),
+            replication_transforms: BTreeMap::new(),
+            min_free_space: 256 * 1024 * 1024,
+            read_only: false,
+            checkpoint_interval: Duration::from_secs(60),
+            log_segment_size: 64 * 1024 * 1024,
+            wal_backend: WalBackend::File,
+            history_size: 10_000,
+            audit_log: None,
+            audit_log_size: 64 * 1024 * 1024,
+            commands: Accepted::All,
+            listeners: Vec::new(),
+            slow_query_ms: 10,
+            bulkload_batch: 1000,
+            intern_values: false,
+            dashboard: None,
+            debug_commands: false,
+            read_lease_ms: 0,
+            max_clock_drift_ms: 50,
+            session_timeout_ms: 30_000,
+            request_dedup_window: 100,
+            request_dedup_timeout_ms: 600_000,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &str) -> Result<Config> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("reading config {}", path))?;
+        let mut config = Config::default();
+        for (number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = line.split_once(char::is_whitespace).ok_or_else(|| {
+                DistKvError::Config(format!("{}:{}: expected `name value`", path, number + 1))
+            })?;
+            config
+                .set(name, value.trim())
+                .with_context(|| format!("{}:{}", path, number + 1))?;
+        }
+        Ok(config)
+    }
+
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        match name {
+            "max_key_size" => self.max_key_size = parse_size(value)?,
+            "max_value_size" => self.max_value_size = parse_size(value)?,
+            "log_recovery" => {
+                self.log_recovery = match value {
+                    "strict" => Strictness::Strict,
+                    "skip" => Strictness::Skip,
+                    _ => bail!(Config, "log_recovery must be strict or skip"),
+                }
+            }
+            "replicaof" => self.replicaof = Some(value.to_string()),
+            "seeds" => {
+                self.seeds = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|seed| !seed.is_empty())
+                    .map(str::to_string)
+                    .collect();
+                if self.seeds.is_empty() {
+                    bail!(Config, "expected `seeds <host:port>[,<host:port>...]`");
+                }
+            }
+            "namespace" => {
+                let Some((namespace, durability)) = value.split_once(char::is_whitespace) else {
+                    bail!(Config, "expected `namespace <name> memory|separate`");
+                };
+                let valid = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+                if namespace.is_empty()
+                    || namespace.as_bytes() == DEFAULT_NAMESPACE
+                    || !namespace.chars().all(valid)
+                {
+                    bail!(Config, "invalid namespace {}", namespace);
+                }
+                let durability = match durability.trim() {
+                    "memory" => Durability::Memory,
+                    "separate" => Durability::Separate,
+                    _ => bail!(Config, "namespace durability must be memory or separate"),
+                };
+                self.namespaces.insert(namespace.to_string(), durability);
+            }
+            "tenant" => {
+                let fields: Vec<_> = value.split_whitespace().collect();
+                let [name, password, prefix, quotas @ ..] = &fields[..] else {
+                    bail!(Config, "expected `tenant <name> <password> <prefix>`");
+                };
+                let mut tenant = Tenant {
+                    password: password.to_string(),
+                    prefix: Bytes::copy_from_slice(prefix.as_bytes()),
+                    max_keys: None,
+                    max_bytes: None,
+                    max_ops: None,
+                    max_write_bytes: None,
+                };
+                for quota in quotas.chunks(2) {
+                    match quota {
+                        ["max_keys", n] => {
+                            tenant.max_keys = Some(
+                                n.parse()
+                                    .with_context(|| format!("invalid max_keys {}", n))?,
+                            )
+                        }
+                        ["max_bytes", size] => tenant.max_bytes = Some(parse_size(size)?),
+                        ["max_ops", n] => {
+                            tenant.max_ops = Some(
+                                n.parse()
+                                    .with_context(|| format!("invalid max_ops {}", n))?,
+                            )
+                        }
+                        ["max_write_bytes", size] => {
+                            tenant.max_write_bytes = Some(parse_size(size)?)
+                        }
+                        _ => bail!(
+                            Config,
+                            "expected `max_keys <n>`, `max_bytes <size>`, `max_ops <n>` \
+                             or `max_write_bytes <size>`"
+                        ),
+                    }
+                }
+                self.tenants.insert(name.to_string(), tenant);
+            }
+            "function" => {
+                let Some((function, path)) = value.split_once(char::is_whitespace) else {
+                    bail!(Config, "expected `function <name> <path>`");
+                };
+                let path = path.trim();
+                let script =
+                    fs::read(path).with_context(|| format!("reading function {}", path))?;
+                script::check(&script)
+                    .map_err(|err| DistKvError::Config(format!("{}: {}", path, err.message)))?;
+                self.functions
+                    .insert(function.to_string(), Bytes::from(script));
+            }
+            "trigger" => {
+                let Some((pattern, url)) = value.split_once(char::is_whitespace) else {
+                    bail!(Config, "expected `trigger <pattern> <url>`");
+                };
+                let url = url.trim();
+                webhook::check_url(url)?;
+                self.triggers.push(Trigger {
+                    pattern: Bytes::copy_from_slice(pattern.as_bytes()),
+                    url: url.to_string(),
+                });
+            }
+            "archive" => {
+                archive::open_store(value)?;
+                self.archive = Some(value.to_string());
+            }
+            "otlp_endpoint" => {
+                webhook::check_url(value)?;
+                self.otlp_endpoint = Some(value.to_string());
+            }
+            "replication_batch_size" => self.replication_batch_size = parse_size(value)?,
+            "replication_flush_ms" => {
+                self.replication_flush_ms = value
+                    .parse()
+                    .with_context(|| format!("invalid replication_flush_ms {}", value))?
+            }
+            "replication_compression" => {
+                self.replication_compression = match value {
+                    "lz4" => true,
+                    "none" => false,
+                    _ => bail!(Config, "replication_compression must be lz4 or none"),
+                }
+            }
+            "replication_queue_size" => match parse_size(value)? {
+                0 => bail!(Config, "replication_queue_size must be more than 0"),
+                size => self.replication_queue_size = size,
+            },
+            "wan_replicas" => {
+                self.wan_replicas = value
+                    .split(',')
+                    .map(str::trim)
+                    .filter(|addr| !addr.is_empty())
+                    .map(str::to_string)
+                    .collect()
+            }
+            "wan_batch_size" => self.wan_batch_size = parse_size(value)?,
+            "wan_flush_ms" => {
+                self.wan_flush_ms = value
+                    .parse()
+                    .with_context(|| format!("invalid wan_flush_ms {}", value))?
+            }
+            "wan_lag_alarm" => self.wan_lag_alarm = parse_size(value)?,
+            "replication_filter" => {
+                let Some((addr, prefixes)) = value.split_once(char::is_whitespace) else {
+                    bail!(
+                        Config,
+                        "expected `replication_filter <host:port> <prefix>[,...]`"
+                    );
+                };
+                let filter = KeyFilter::parse(prefixes)?;
+                self.replication_filters.insert(addr.to_string(), filter);
+            }
+            "replication_transform" => {
+                let Some((addr, transform)) = value.split_once(char::is_whitespace) else {
+                    bail!(
+                        Config,
+                        "expected `replication_transform <host:port> <transform>`"
+                    );
+                };
+                let transform = Rewrite::parse(transform)?;
+                self.replication_transforms
+                    .entry(addr.to_string())
+                    .or_default()
+                    .push(transform);
+            }
+            "replication_overflow" => {
+                self.replication_overflow = match value {
+                    "block" => Overflow::Block,
+                    "spill" => Overflow::Spill,
+                    "drop" => Overflow::Drop,
+                    _ => bail!(Config, "replication_overflow must be block, spill or drop"),
+                }
+            }
+            "replication_spill_dir" => self.replication_spill_dir = value.to_string(),
+            "min_free_space" => self.min_free_space = parse_size(value)?,
+            "read_only" => {
+                self.read_only = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => bail!(Config, "read_only must be on or off"),
+                }
+            }
+            "checkpoint_interval" => {
+                let secs = value
+                    .parse()
+                    .with_context(|| format!("invalid checkpoint_interval {}", value))?;
+                self.checkpoint_interval = Duration::from_secs(secs)
+            }
+            "log_segment_size" => self.log_segment_size = parse_size(value)?,
+            "wal_backend" => {
+                self.wal_backend = match value {
+                    "file" => WalBackend::File,
+                    "io_uring" => WalBackend::IoUring,
+                    _ => bail!(Config, "wal_backend must be file or io_uring"),
+                }
+            }
+            "history_size" => {
+                self.history_size = value
+                    .parse()
+                    .with_context(|| format!("invalid history_size {}", value))?
+            }
+            "audit_log" => self.audit_log = Some(value.to_string()),
+            "audit_log_size" => self.audit_log_size = parse_size(value)?,
+            "commands" => self.commands = Accepted::parse(value)?,
+            "slow_query_ms" => {
+                self.slow_query_ms = value
+                    .parse()
+                    .with_context(|| format!("invalid slow_query_ms {}", value))?
+            }
+            "bulkload_batch" => {
+                self.bulkload_batch = match value.parse() {
+                    Ok(0) | Err(_) => bail!(Config, "invalid bulkload_batch {}", value),
+                    Ok(n) => n,
+                }
+            }
+            "intern_values" => {
+                self.intern_values = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => bail!(Config, "intern_values must be on or off"),
+                }
+            }
+            "dashboard" => {
+                if !cfg!(feature = "dashboard") {
+                    bail!(Config, "built without the dashboard feature");
+                }
+                self.dashboard = Some(value.to_string());
+            }
+            "read_lease_ms" => {
+                self.read_lease_ms = value
+                    .parse()
+                    .with_context(|| format!("invalid read_lease_ms {}", value))?
+            }
+            "max_clock_drift_ms" => {
+                self.max_clock_drift_ms = value
+                    .parse()
+                    .with_context(|| format!("invalid max_clock_drift_ms {}", value))?
+            }
+            "session_timeout_ms" => {
+                self.session_timeout_ms = value
+                    .parse()
+                    .with_context(|| format!("invalid session_timeout_ms {}", value))?
+            }
+            "request_dedup_window" => {
+                self.request_dedup_window = value
+                    .parse()
+                    .with_context(|| format!("invalid request_dedup_window {}", value))?
+            }
+            "request_dedup_timeout_ms" => {
+                self.request_dedup_timeout_ms = value
+                    .parse()
+                    .with_context(|| format!("invalid request_dedup_timeout_ms {}", value))?
+            }
+            "debug_commands" => {
+                self.debug_commands = match value {
+                    "on" => true,
+                    "off" => false,
+                    _ => bail!(Config, "debug_commands must be on or off"),
+                }
+            }
+            "listen" => {
+                let Some((addr, commands)) = value.split_once(char::is_whitespace) else {
+                    bail!(Config, "expected `listen <host:port> <command>[,...]|all`");
+                };
+                let commands = Accepted::parse(commands.trim())?;
+                self.listeners.push((addr.to_string(), commands));
+            }
+            _ => bail!(Config, "unknown setting {}", name),
+        }
+        Ok(())
+    }
+
+    /// The value of a single-valued setting, as it would be written in the
+    /// config file.
+    pub fn get(&self, name: &str) -> Result<String> {
+        Ok(match name {
+            "max_key_size" => self.max_key_size.to_string(),
+            "max_value_size" => self.max_value_size.to_string(),
+            "log_recovery" => match self.log_recovery {
+                Strictness::Strict => "strict".to_string(),
+                Strictness::Skip => "skip".to_string(),
+            },
+            "replicaof" => self.replicaof.clone().unwrap_or_default(),
+            "seeds" => self.seeds.join(","),
+            "archive" => self.archive.clone().unwrap_or_default(),
+            "otlp_endpoint" => self.otlp_endpoint.clone().unwrap_or_default(),
+            "replication_batch_size" => self.replication_batch_size.to_string(),
+            "replication_flush_ms" => self.replication_flush_ms.to_string(),
+            "replication_compression" => match self.replication_compression {
+                true => "lz4".to_string(),
+                false => "none".to_string(),
+            },
+            "replication_queue_size" => self.replication_queue_size.to_string(),
+            "replication_overflow" => match self.replication_overflow {
+                Overflow::Block => "block".to_string(),
+                Overflow::Spill => "spill".to_string(),
+                Overflow::Drop => "drop".to_string(),
+            },
+            "replication_spill_dir" => self.replication_spill_dir.clone(),
+            "wan_replicas" => self.wan_replicas.join(","),
+            "wan_batch_size" => self.wan_batch_size.to_string(),
+            "wan_flush_ms" => self.wan_flush_ms.to_string(),
+            "wan_lag_alarm" => self.wan_lag_alarm.to_string(),
+            "min_free_space" => self.min_free_space.to_string(),
+            "read_only" => match self.read_only {
+                true => "on".to_string(),
+                false => "off".to_string(),
+            },
+            "checkpoint_interval" => self.checkpoint_interval.as_secs().to_string(),
+            "log_segment_size" => self.log_segment_size.to_string(),
+            "wal_backend" => match self.wal_backend {
+                WalBackend::File => "file".to_string(),
+                WalBackend::IoUring => "io_uring".to_string(),
+            },
+            "history_size" => self.history_size.to_string(),
+            "audit_log" => self.audit_log.clone().unwrap_or_default(),
+            "audit_log_size" => self.audit_log_size.to_string(),
+            "commands" => self.commands.render(),
+            "slow_query_ms" => self.slow_query_ms.to_string(),
+            "bulkload_batch" => self.bulkload_batch.to_string(),
+            "intern_values" => match self.intern_values {
+                true => "on".to_string(),
+                false => "off".to_string(),
+            },
+            "dashboard" => self.dashboard.clone().unwrap_or_default(),
+            "read_lease_ms" => self.read_lease_ms.to_string(),
+            "max_clock_drift_ms" => self.max_clock_drift_ms.to_string(),
+            "session_timeout_ms" => self.session_timeout_ms.to_string(),
+            "request_dedup_window" => self.request_dedup_window.to_string(),
+            "request_dedup_timeout_ms" => self.request_dedup_timeout_ms.to_string(),
+            "debug_commands" => match self.debug_commands {
+                true => "on".to_string(),
+                false => "off".to_string(),
+            },
+            _ => bail!(Config, "unknown setting {}", name),
+        })
+    }
+
+    /// The settings whose values differ between `self` and `other`.
+    pub fn changed_settings(&self, other: &Config) -> Vec<&'static str> {
+        [
+            ("max_key_size", self.max_key_size != other.max_key_size),
+            (
+                "max_value_size",
+                self.max_value_size != other.max_value_size,
+            ),
+            ("log_recovery", self.log_recovery != other.log_recovery),
+            ("replicaof", self.replicaof != other.replicaof),
+            ("seeds", self.seeds != other.seeds),
+            ("namespace", self.namespaces != other.namespaces),
+            ("tenant", self.tenants != other.tenants),
+            ("function", self.functions != other.functions),
+            ("trigger", self.triggers != other.triggers),
+            ("archive", self.archive != other.archive),
+            ("otlp_endpoint", self.otlp_endpoint != other.otlp_endpoint),
+            (
+                "replication_batch_size",
+                self.replication_batch_size != other.replication_batch_size,
+            ),
+            (
+                "replication_flush_ms",
+                self.replication_flush_ms != other.replication_flush_ms,
+            ),
+            (
+                "replication_compression",
+                self.replication_compression != other.replication_compression,
+            ),
+            (
+                "replication_queue_size",
+                self.replication_queue_size != other.replication_queue_size,
+            ),
+            (
+                "replication_overflow",
+                self.replication_overflow != other.replication_overflow,
+            ),
+            (
+                "replication_spill_dir",
+                self.replication_spill_dir != other.replication_spill_dir,
+            ),
+            ("wan_replicas", self.wan_replicas != other.wan_replicas),
+            (
+                "wan_batch_size",
+                self.wan_batch_size != other.wan_batch_size,
+            ),
+            ("wan_flush_ms", self.wan_flush_ms != other.wan_flush_ms),
+            ("wan_lag_alarm", self.wan_lag_alarm != other.wan_lag_alarm),
+            (
+                "replication_filter",
+                self.replication_filters != other.replication_filters,
+            ),
+            (
+                "replication_transform",
+                self.replication_transforms != other.replication_transforms,
+            ),
+            (
+                "min_free_space",
+                self.min_free_space != other.min_free_space,
+            ),
+            ("read_only", self.read_only != other.read_only),
+            (
+                "checkpoint_interval",
+                self.checkpoint_interval != other.checkpoint_interval,
+            ),
+            (
+                "log_segment_size",
+                self.log_segment_size != other.log_segment_size,
+            ),
+            ("wal_backend", self.wal_backend != other.wal_backend),
+            ("history_size", self.history_size != other.history_size),
+            ("audit_log", self.audit_log != other.audit_log),
+            (
+                "audit_log_size",
+                self.audit_log_size != other.audit_log_size,
+            ),
+            ("commands", self.commands != other.commands),
+            ("listen", self.listeners != other.listeners),
+            ("slow_query_ms", self.slow_query_ms != other.slow_query_ms),
+            (
+                "bulkload_batch",
+                self.bulkload_batch != other.bulkload_batch,
+            ),
+            ("intern_values", self.intern_values != other.intern_values),
+            ("dashboard", self.dashboard != other.dashboard),
+            (
+                "debug_commands",
+                self.debug_commands != other.debug_commands,
+            ),
+            ("read_lease_ms", self.read_lease_ms != other.read_lease_ms),
+            (
+                "max_clock_drift_ms",
+                self.max_clock_drift_ms != other.max_clock_drift_ms,
+            ),
+            (
+                "session_timeout_ms",
+                self.session_timeout_ms != other.session_timeout_ms,
+            ),
+            (
+                "request_dedup_window",
+                self.request_dedup_window != other.request_dedup_window,
+            ),
+            (
+                "request_dedup_timeout_ms",
+                self.request_dedup_timeout_ms != other.request_dedup_timeout_ms,
+            ),
+        ]
+        .into_iter()
+        .filter_map(|(name, changed)| changed.then_some(name))
+        .collect()
+    }
+
+    pub fn batching(&self) -> Batching {
+        Batching {
+            max_bytes: self.replication_batch_size,
+            max_delay: Duration::from_millis(self.replication_flush_ms),
+        }
+    }
+
+    /// Whether the follower at `addr` is one of the `wan_replicas`.
+    pub fn is_wan_replica(&self, addr: &str) -> bool {
+        self.wan_replicas.iter().any(|wan| wan == addr)
+    }
+
+    pub fn wan_batching(&self) -> Batching {
+        Batching {
+            max_bytes: self.wan_batch_size,
+            max_delay: Duration::from_millis(self.wan_flush_ms),
+        }
+    }
+
+    /// The queue limit for a WAN replica, which never holds up writes.
+    pub fn wan_queue_limit(&self) -> QueueLimit {
+        let mut limit = self.queue_limit();
+        if limit.overflow == Overflow::Block {
+            limit.overflow = Overflow::Spill;
+        }
+        limit
+    }
+
+    pub fn queue_limit(&self) -> QueueLimit {
+        QueueLimit {
+            max_bytes: self.replication_queue_size,
+            overflow: self.replication_overflow,
+            spill_dir: PathBuf::from(&self.replication_spill_dir),
+        }
+    }
+
+    pub fn limits(&self) -> Limits {
+        Limits {
+            max_key_size: self.max_key_size,
+            max_value_size: self.max_value_size,
+        }
+    }
+}
+
+/// Writes `name value` into the config file at `path`, in place of the
+/// setting's last line or at the end if it has none. The file is replaced
+/// whole, so a crash leaves either the old or the new one.
+pub fn save_setting(path: &str, name: &str, value: &str) -> Result<()> {
+    let contents = fs::read_to_string(path).with_context(|| format!("reading config {}", path))?;
+    let mut lines: Vec<String> = contents.lines().map(str::to_string).collect();
+    let line = format!("{} {}", name, value);
+    let existing = lines.iter().rposition(|line| {
+        let line = line.trim();
+        !line.starts_with('#') && line.split_whitespace().next() == Some(name)
+    });
+    match existing {
+        Some(i) => lines[i] = line,
+        None => lines.push(line),
+    }
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+    let temp = format!("{}.tmp", path);
+    fs::write(&temp, contents).with_context(|| format!("writing {}", temp))?;
+    fs::File::open(&temp)?.sync_all()?;
+    fs::rename(&temp, path).with_context(|| format!("replacing config {}", path))?;
+    Ok(())
+}
+
+fn parse_size(value: &str) -> Result<usize> {
+    let lower = value.to_ascii_lowercase();
+    let (digits, unit) = match lower.find(|c: char| !c.is_ascii_digit()) {
+        Some(split) => lower.split_at(split),
+        None => (lower.as_str(), ""),
+    };
+    let unit = match unit {
+        "" | "b" => 1,
+        "kb" | "k" => 1024,
+        "mb" | "m" => 1024 * 1024,
+        "gb" | "g" => 1024 * 1024 * 1024,
+        _ => bail!(Config, "invalid size {}", value),
+    };
+    let size: usize = digits
+        .parse()
+        .with_context(|| format!("invalid size {}", value))?;
+    size.checked_mul(unit)
+        .ok_or_else(|| DistKvError::Config(format!("size {} is too large", value)))
+}
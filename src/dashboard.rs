@@ -0,0 +1,250 @@
+//! A web dashboard of the leader, for deployments without a monitoring
+//! stack: throughput, latency by command, the size of the keyspace, how far
+//! each follower lags and the recent slow queries. Served from `dashboard
+//! <host:port>` in builds with the `dashboard` feature.
+//!
+//! `GET /` is an HTML page that reloads itself every [`REFRESH_SECS`]
+//! seconds and `GET /stats` the same figures as JSON. Throughput is the
+//! commands run per second over the last second, sampled by the dashboard.
+//! A follower's lag is the bytes of records queued for it. Requests are
+//! answered one per connection, which is then closed.
+
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinSet;
+
+use crate::error::{bail, Result};
+use crate::metrics::{CommandStats, Histogram, SlowQuery};
+use crate::server::{FollowerStatus, Leader, SyncLeader};
+use crate::webhook::json_string;
+
+/// How often the page reloads itself, in seconds.
+pub const REFRESH_SECS: u64 = 2;
+
+/// The most bytes of request head read before giving up on a request.
+const MAX_REQUEST: usize = 8 * 1024;
+
+/// The figures the dashboard shows, taken in one turn of the leader's lock.
+struct Stats {
+    ops_per_sec: f64,
+    lsn: u64,
+    keys: usize,
+    keyspace_bytes: usize,
+    commands: Vec<(&'static str, CommandStats, Option<Histogram>)>,
+    followers: Vec<FollowerStatus>,
+    slow_queries: Vec<SlowQuery>,
+}
+
+impl Stats {
+    fn collect(leader: &Leader, ops_per_sec: f64) -> Stats {
+        let metrics = &leader.core.metrics;
+        let commands = metrics
+            .all_command_stats()
+            .into_iter()
+            .map(|(name, stats)| (name, stats, metrics.histogram(name)))
+            .collect();
+        let namespaces = leader.core.hashmap.namespaces().map(|(_, db)| db.len());
+        Stats {
+            ops_per_sec,
+            lsn: leader.core.lsn,
+            keys: leader.core.hashmap.len() + namespaces.sum::<usize>(),
+            keyspace_bytes: leader.keyspace_bytes(),
+            commands,
+            followers: leader.follower_statuses(),
+            slow_queries: metrics.slow_queries(),
+        }
+    }
+}
+
+/// Serves the dashboard until the task is dropped.
+pub async fn serve(listener: TcpListener, leader: SyncLeader) {
+    let mut sample = tokio::time::interval(Duration::from_secs(1));
+    let (mut sampled_at, mut sampled_calls) = (Instant::now(), 0);
+    let mut ops_per_sec = 0.0;
+    let mut connections = JoinSet::new();
+    loop {
+        tokio::select! {
+            _ = sample.tick() => {
+                let calls = leader.lock().await.core.metrics.calls();
+                let elapsed = sampled_at.elapsed().as_secs_f64();
+                if elapsed > 0.0 {
+                    ops_per_sec = calls.saturating_sub(sampled_calls) as f64 / elapsed;
+                }
+                (sampled_at, sampled_calls) = (Instant::now(), calls);
+            }
+            accepted = listener.accept() => match accepted {
+                Ok((socket, _)) => {
+                    let leader = leader.clone();
+                    connections.spawn(async move {
+                        if let Err(e) = respond(socket, &leader, ops_per_sec).await {
+                            eprintln!("Error = {:?}", e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Error = {:?}", e),
+            },
+            Some(_) = connections.join_next() => {}
+        }
+    }
+}
+
+async fn respond(mut socket: TcpStream, leader: &SyncLeader, ops_per_sec: f64) -> Result<()> {
+    let mut request = Vec::new();
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST {
+            bail!(Protocol, "request head over {} bytes", MAX_REQUEST);
+        }
+        let mut buf = [0; 1024];
+        match socket.read(&mut buf).await? {
+            0 => bail!(Protocol, "connection closed mid-request"),
+            n => request.extend_from_slice(&buf[..n]),
+        }
+    }
+    let head = String::from_utf8_lossy(&request);
+    let mut line = head.lines().next().unwrap_or_default().split(' ');
+    let (method, target) = (line.next().unwrap_or_default(), line.next().unwrap_or("/"));
+    let path = target.split('?').next().unwrap_or_default();
+    let (status, content_type, body) = match (method, path) {
+        ("GET", "/" | "/stats") => {
+            let stats = Stats::collect(&*leader.lock().await, ops_per_sec);
+            match path {
+                "/" => ("200 OK", "text/html; charset=utf-8", render_html(&stats)),
+                _ => ("200 OK", "application/json", render_json(&stats)),
+            }
+        }
+        ("GET", _) => ("404 Not Found", "text/plain", "not found\n".to_string()),
+        _ => (
+            "405 Method Not Allowed",
+            "text/plain",
+            "only GET is supported\n".to_string(),
+        ),
+    };
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    socket.write_all(response.as_bytes()).await?;
+    socket.shutdown().await?;
+    Ok(())
+}
+
+fn render_json(stats: &Stats) -> String {
+    let optional =
+        |value: Option<&str>| value.map_or("null".to_string(), |v| json_string(v.as_bytes()));
+    let commands: Vec<String> = stats
+        .commands
+        .iter()
+        .map(|(name, command, histogram)| {
+            let percentile = |p| histogram.as_ref().map_or(0, |h| h.percentile(p));
+            format!(
+                "{{\"name\":{},\"calls\":{},\"errors\":{},\"p50_usec\":{},\"p99_usec\":{},\"max_usec\":{}}}",
+                json_string(name.as_bytes()),
+                command.calls,
+                command.errors,
+                percentile(50.0),
+                percentile(99.0),
+                command.max_usec,
+            )
+        })
+        .collect();
+    let followers: Vec<String> = stats
+        .followers
+        .iter()
+        .map(|follower| {
+            format!(
+                "{{\"addr\":{},\"state\":{},\"voter\":{},\"wan\":{},\"lag_alarm\":{},\"queued\":{},\"transfer_backlog\":{}}}",
+                optional(follower.addr.as_deref()),
+                json_string(follower.state.as_bytes()),
+                follower.voter,
+                follower.wan,
+                follower.lag_alarm,
+                follower.queued,
+                follower.transfer_backlog,
+            )
+        })
+        .collect();
+    let slow_queries: Vec<String> = stats
+        .slow_queries
+        .iter()
+        .map(|query| {
+            format!(
+                "{{\"time\":{},\"usec\":{},\"command\":{},\"key\":{}}}",
+                query.at,
+                query.micros,
+                json_string(query.name.as_bytes()),
+                optional(query.key.as_deref()),
+            )
+        })
+        .collect();
+    format!(
+        "{{\"ops_per_sec\":{:.1},\"lsn\":{},\"keys\":{},\"keyspace_bytes\":{},\"commands\":[{}],\"followers\":[{}],\"slow_queries\":[{}]}}",
+        stats.ops_per_sec,
+        stats.lsn,
+        stats.keys,
+        stats.keyspace_bytes,
+        commands.join(","),
+        followers.join(","),
+        slow_queries.join(","),
+    )
+}
+
+fn render_html(stats: &Stats) -> String {
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <meta http-equiv=\"refresh\" content=\"{}\"><title>dist-kv</title></head><body>\n\
+         <h1>dist-kv leader</h1>\n<p>{:.1} ops/sec &middot; LSN {} &middot; {} keys, {} bytes</p>\n",
+        REFRESH_SECS, stats.ops_per_sec, stats.lsn, stats.keys, stats.keyspace_bytes
+    );
+    html.push_str("<h2>Latency</h2>\n<table><tr><th>command</th><th>calls</th><th>errors</th><th>p50 &micro;s</th><th>p99 &micro;s</th><th>max &micro;s</th></tr>\n");
+    for (name, command, histogram) in &stats.commands {
+        let percentile = |p| histogram.as_ref().map_or(0, |h| h.percentile(p));
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            escape(name),
+            command.calls,
+            command.errors,
+            percentile(50.0),
+            percentile(99.0),
+            command.max_usec
+        ));
+    }
+    html.push_str("</table>\n<h2>Replication</h2>\n<table><tr><th>follower</th><th>state</th><th>role</th><th>lag (bytes queued)</th><th>snapshot backlog</th></tr>\n");
+    for follower in &stats.followers {
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            escape(follower.addr.as_deref().unwrap_or("synced")),
+            follower.state,
+            match (follower.voter, follower.wan) {
+                (true, _) => "voter",
+                (false, true) => "wan replica",
+                (false, false) => "learner",
+            },
+            follower.queued,
+            follower.transfer_backlog
+        ));
+    }
+    html.push_str("</table>\n<h2>Slow queries</h2>\n<table><tr><th>time (unix ms)</th><th>&micro;s</th><th>command</th><th>key</th></tr>\n");
+    for query in &stats.slow_queries {
+        html.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td></tr>\n",
+            query.at,
+            query.micros,
+            escape(query.name),
+            escape(query.key.as_deref().unwrap_or(""))
+        ));
+    }
+    html.push_str("</table>\n</body></html>\n");
+    html
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
@@ -0,0 +1,87 @@
+//! Finding the cluster from a few seed nodes rather than a configured
+//! address for every peer.
+//!
+//! Any node answers `PEERS` with the members it knows of as `role:addr`
+//! lines: the leader names itself and every follower it replicates to by
+//! address, while a follower only knows the leader. Discovery asks the seeds
+//! in turn, each a `host:port` whose name may resolve to several nodes, until
+//! one names the leader, and then asks the leader for the full membership.
+
+use tokio::net::lookup_host;
+
+use crate::client::DistKvClient;
+use crate::error::{self, bail, Context, DistKvError, Result};
+
+/// The cluster's members as a node knows them.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Peers {
+    pub leader: Option<String>,
+    pub followers: Vec<String>,
+}
+
+impl Peers {
+    /// The `PEERS` reply.
+    pub fn render(&self) -> String {
+        let mut reply = String::new();
+        if let Some(leader) = &self.leader {
+            reply.push_str(&format!("leader:{}\n", leader));
+        }
+        for follower in &self.followers {
+            reply.push_str(&format!("follower:{}\n", follower));
+        }
+        reply
+    }
+
+    pub fn parse(reply: &[u8]) -> error::Result<Peers> {
+        let mut peers = Peers::default();
+        for line in String::from_utf8_lossy(reply).lines() {
+            match line.split_once(':') {
+                Some(("leader", addr)) => peers.leader = Some(addr.to_string()),
+                Some(("follower", addr)) => peers.followers.push(addr.to_string()),
+                _ => {
+                    let message = format!("unexpected PEERS line {:?}", line);
+                    return Err(DistKvError::Protocol(message));
+                }
+            }
+        }
+        Ok(peers)
+    }
+}
+
+/// Asks the node at `addr` who it knows of.
+async fn ask(addr: &str) -> Result<Peers> {
+    DistKvClient::connect(addr).await?.peers().await
+}
+
+/// Learns the cluster's membership from the first of `seeds` that knows
+/// the leader.
+pub async fn discover(seeds: &[String]) -> Result<Peers> {
+    for seed in seeds {
+        let addrs = match lookup_host(seed.as_str()).await {
+            Ok(addrs) => addrs,
+            Err(e) => {
+                eprintln!("Can't resolve seed {}: {:?}", seed, e);
+                continue;
+            }
+        };
+        for addr in addrs {
+            match ask(&addr.to_string()).await {
+                Ok(Peers {
+                    leader: Some(leader),
+                    ..
+                }) => {
+                    return ask(&leader)
+                        .await
+                        .with_context(|| format!("asking the leader at {}", leader));
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Seed {} ({}) didn't answer: {:?}", seed, addr, e),
+            }
+        }
+    }
+    bail!(
+        Protocol,
+        "none of the seeds {} knows the leader",
+        seeds.join(",")
+    )
+}
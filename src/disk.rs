@@ -0,0 +1,19 @@
+//! Watching the free space on the disk a node keeps its logs on, so that
+//! the leader can stop taking writes before the disk fills rather than fail
+//! partway through appending one.
+
+use std::path::Path;
+use std::time::Duration;
+
+use nix::sys::statvfs::statvfs;
+
+use crate::error::{Context, Result};
+
+/// How often the leader checks the free space on its disk.
+pub const DISK_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The bytes free to an unprivileged process on the disk `dir` is on.
+pub fn free_space(dir: &Path) -> Result<u64> {
+    let stat = statvfs(dir).with_context(|| format!("checking free space in {}", dir.display()))?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
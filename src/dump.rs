@@ -0,0 +1,76 @@
+//! The serialized form of a single key, as `DUMP` returns it and `RESTORE`
+//! takes it, for moving keys between clusters or keeping them elsewhere.
+//!
+//! A dump is the magic bytes `DKVD`, a `u8` format version, a `u8` value
+//! type, the big-endian `u64` Unix time in milliseconds the key expires at,
+//! or 0 if it has no TTL, a `u32` value length and the value, followed by
+//! the CRC-32 of everything before it. The key itself isn't included, so a
+//! dump can be restored under any name. Every value is type 0, bytes; the
+//! type is there so that a version of dist-kv with other types can refuse
+//! dumps it can't restore rather than misread them.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::checksum::crc32;
+use crate::error::{bail, Result};
+
+pub const MAGIC: &[u8; 4] = b"DKVD";
+
+/// The format version written by [`encode`]. Anything newer is refused.
+pub const VERSION: u8 = 1;
+
+/// Bytes a dump adds to its value.
+pub const OVERHEAD: usize = MAGIC.len() + 1 + 1 + 8 + 4 + 4;
+
+const TYPE_BYTES: u8 = 0;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Dump {
+    pub val: Bytes,
+    pub expires_at: Option<u64>,
+}
+
+pub fn encode(val: &[u8], expires_at: Option<u64>) -> Bytes {
+    let mut buf = BytesMut::with_capacity(OVERHEAD + val.len());
+    buf.put_slice(MAGIC);
+    buf.put_u8(VERSION);
+    buf.put_u8(TYPE_BYTES);
+    buf.put_u64(expires_at.unwrap_or(0));
+    buf.put_u32(val.len() as u32);
+    buf.put_slice(val);
+    buf.put_u32(crc32(&buf));
+    buf.freeze()
+}
+
+pub fn decode(dump: &[u8]) -> Result<Dump> {
+    if dump.len() < OVERHEAD || !dump.starts_with(MAGIC) {
+        bail!(Corrupt, "not a dump");
+    }
+    let (body, checksum) = dump.split_at(dump.len() - 4);
+    if crc32(body) != u32::from_be_bytes(checksum.try_into().unwrap()) {
+        bail!(Corrupt, "dump checksum mismatch");
+    }
+    let mut body = &body[MAGIC.len()..];
+    let version = body.get_u8();
+    if version == 0 || version > VERSION {
+        bail!(Corrupt, "unsupported dump version {}", version);
+    }
+    let kind = body.get_u8();
+    if kind != TYPE_BYTES {
+        bail!(Corrupt, "unsupported dump value type {}", kind);
+    }
+    let expires_at = body.get_u64();
+    let len = body.get_u32() as usize;
+    if body.len() != len {
+        bail!(
+            Corrupt,
+            "dump value is {} bytes, expected {}",
+            body.len(),
+            len
+        );
+    }
+    Ok(Dump {
+        val: Bytes::copy_from_slice(body),
+        expires_at: (expires_at != 0).then_some(expires_at),
+    })
+}
@@ -0,0 +1,106 @@
+//! Checking that the log of a sequence of commands replays to the map they
+//! were applied to.
+//!
+//! [`check`] runs commands through a leader as clients would send them,
+//! logging their records as the leader does, and after each one rebuilds
+//! the map twice: by replaying the log, as a restart does, and by feeding
+//! the replicated records to a follower. Both must match the leader's map.
+//! Property tests run it over random command sequences, so that a change to
+//! how a command is logged can't silently break recovery or replication.
+
+use std::fmt;
+
+use bytes::BytesMut;
+
+use crate::node::{FollowerCore, LeaderCore};
+use crate::protocol::Command;
+use crate::sim::{NullStorage, SimClock, SimStorage};
+use crate::store::{replay, Db};
+
+/// Where a rebuilt map first stopped matching the leader's.
+#[derive(Debug, Clone)]
+pub struct Mismatch {
+    /// How many of the commands had been applied.
+    pub applied: usize,
+    /// What rebuilt the map: `"replay"` or `"follower"`.
+    pub rebuilt_by: &'static str,
+    pub expected: Db,
+    pub rebuilt: Db,
+    /// Why replay failed, if it did.
+    pub error: Option<String>,
+    /// The log so far.
+    pub log: Vec<u8>,
+}
+
+impl fmt::Display for Mismatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(error) = &self.error {
+            return write!(
+                f,
+                "after {} commands replay failed: {}",
+                self.applied, error
+            );
+        }
+        write!(
+            f,
+            "after {} commands the {} map has {} keys where the leader's has {}",
+            self.applied,
+            self.rebuilt_by,
+            self.rebuilt.len(),
+            self.expected.len()
+        )?;
+        let differs = self
+            .expected
+            .iter()
+            .find(|(key, val)| self.rebuilt.get(&key[..]) != Some(val))
+            .map(|(key, _)| key)
+            .or_else(|| {
+                let mut extra = self.rebuilt.keys();
+                extra.find(|key| !self.expected.contains_key(&key[..]))
+            });
+        if let Some(key) = differs {
+            write!(f, ", starting with {:?}", key)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Mismatch {}
+
+/// Applies `commands`, which must be ones a client could send, in order,
+/// checking after each that both replaying the log and a follower applying
+/// the replicated records rebuild the leader's map. The leader's clock
+/// stays at the Unix epoch, so no TTL runs out partway through.
+pub fn check<'a>(commands: impl IntoIterator<Item = &'a Command<'a>>) -> Result<(), Box<Mismatch>> {
+    let disk = SimStorage::new(0.0, 0);
+    let clock = SimClock::default();
+    let mut leader = LeaderCore::new(Db::new(), disk.clone(), clock.clone());
+    let mut follower = FollowerCore::new(Db::new(), NullStorage, clock);
+    for (i, command) in commands.into_iter().enumerate() {
+        let (_, record) = leader
+            .execute(command)
+            .expect("simulated storage doesn't fail");
+        let mut stream = BytesMut::from(&record.unwrap_or_default()[..]);
+        let _ = follower.receive(&mut stream);
+        let log = disk.disk().durable().to_vec();
+        let mismatch = |rebuilt_by, rebuilt: Db, error| {
+            Box::new(Mismatch {
+                applied: i + 1,
+                rebuilt_by,
+                expected: leader.hashmap.clone(),
+                rebuilt,
+                error,
+                log: log.clone(),
+            })
+        };
+        match replay(&log) {
+            Ok(replayed) if replayed == leader.hashmap => {}
+            Ok(replayed) => return Err(mismatch("replay", replayed, None)),
+            Err(e) => return Err(mismatch("replay", Db::new(), Some(format!("{:#}", e)))),
+        }
+        if follower.hashmap != leader.hashmap {
+            return Err(mismatch("follower", follower.hashmap.clone(), None));
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,220 @@
+//! The errors the library returns, sorted by kind so that callers can match
+//! on why a request failed rather than on the text of the reply.
+//!
+//! Errors the server sends back are sorted by their [`ErrorCode`], and every
+//! kind that came from the server maps back to the code it arrived with, see
+//! [`DistKvError::reply`]. The node's own failures are sorted the same way:
+//! a log that can't be written is a storage error with the code `IOERR`, and
+//! a replication stream that broke a replication error with `LINKDOWN`,
+//! whether an embedder meets them from [`crate::node::LeaderCore`] or a
+//! client from the server.
+//!
+//! [`Context`] adds what was being done to an error, as `anyhow`'s trait of
+//! the same name does, without changing its kind.
+
+use std::fmt;
+use std::io;
+use std::num::ParseIntError;
+
+use crate::protocol::{ErrorCode, ErrorReply, ParseError};
+
+pub type Result<T, E = DistKvError> = std::result::Result<T, E>;
+
+#[derive(Debug)]
+pub enum DistKvError {
+    /// The request didn't parse: `SYNTAX`, `WRONGARGS`, `UNKNOWN` or
+    /// `TOOLARGE`, whether the server said so or [`ParseError`] did.
+    Parse(ErrorReply),
+    /// The leader can't take writes to its log, `READONLY`, is still
+    /// loading it, `LOADING`, or failed to read or write it, `IOERR`.
+    Storage(ErrorReply),
+    /// The cluster couldn't agree on the request, `NOQUORUM` or `LEASED`,
+    /// or a replication stream broke, `LINKDOWN`.
+    Replication(ErrorReply),
+    /// The connection isn't authenticated, `NOAUTH`, or may not run the
+    /// command, `NOPERM`.
+    Auth(ErrorReply),
+    /// The node is a follower; writes go to `leader`.
+    NotLeader { leader: String },
+    /// Any other error the server replied with.
+    Server(ErrorReply),
+    /// The connection failed or was closed, or another system call did.
+    Io(io::Error),
+    /// The server sent a reply that isn't one the request expects, or a
+    /// peer something else that isn't what the protocol allows.
+    Protocol(String),
+    /// Data read back doesn't decode: a log record, snapshot, checkpoint,
+    /// manifest, dump or import that is malformed or fails its checksum.
+    Corrupt(String),
+    /// A setting, argument or path the node was given that it can't use.
+    Config(String),
+}
+
+/// Returns early with a [`DistKvError`] of the kind named, one of those
+/// holding just a message, formatted as `format!` does.
+macro_rules! bail {
+    ($kind:ident, $($arg:tt)+) => {
+        return Err($crate::error::DistKvError::$kind(format!($($arg)+)))
+    };
+}
+
+pub(crate) use bail;
+
+impl DistKvError {
+    /// Sorts an error line from the server, `ERR <code> <message>`, by its
+    /// code. Codes this client doesn't know are kept as they were sent.
+    pub fn from_line(line: &str) -> DistKvError {
+        match ErrorReply::parse(line) {
+            Some(reply) => reply.into(),
+            None => DistKvError::Protocol(line.to_string()),
+        }
+    }
+
+    /// The code the error has on the wire, if it has one.
+    pub fn code(&self) -> Option<ErrorCode> {
+        self.reply().map(|reply| reply.code)
+    }
+
+    /// The error as the server sends it. Errors of the connection itself
+    /// never came from a server and have no reply.
+    pub fn reply(&self) -> Option<ErrorReply> {
+        match self {
+            DistKvError::Parse(reply)
+            | DistKvError::Storage(reply)
+            | DistKvError::Replication(reply)
+            | DistKvError::Auth(reply)
+            | DistKvError::Server(reply) => Some(reply.clone()),
+            DistKvError::NotLeader { leader } => {
+                Some(ErrorReply::new(ErrorCode::Moved, leader.clone()))
+            }
+            DistKvError::Io(_)
+            | DistKvError::Protocol(_)
+            | DistKvError::Corrupt(_)
+            | DistKvError::Config(_) => None,
+        }
+    }
+
+    /// The node's log or another file it keeps couldn't be read or written.
+    pub fn storage(err: impl fmt::Display) -> DistKvError {
+        ErrorReply::new(ErrorCode::IoErr, err.to_string()).into()
+    }
+
+    /// The replication link to or from another node broke.
+    pub fn link_down(err: impl fmt::Display) -> DistKvError {
+        ErrorReply::new(ErrorCode::LinkDown, err.to_string()).into()
+    }
+
+    /// The same error, its message led by `context`. A `MOVED` error's
+    /// message is the leader's address, so it is left as it is.
+    pub fn context(self, context: impl fmt::Display) -> DistKvError {
+        let lead = |message: String| format!("{}: {}", context, message);
+        match self {
+            DistKvError::Parse(reply) => DistKvError::Parse(reply.led_by(lead)),
+            DistKvError::Storage(reply) => DistKvError::Storage(reply.led_by(lead)),
+            DistKvError::Replication(reply) => DistKvError::Replication(reply.led_by(lead)),
+            DistKvError::Auth(reply) => DistKvError::Auth(reply.led_by(lead)),
+            DistKvError::Server(reply) => DistKvError::Server(reply.led_by(lead)),
+            DistKvError::NotLeader { leader } => DistKvError::NotLeader { leader },
+            DistKvError::Io(err) => {
+                DistKvError::Io(io::Error::new(err.kind(), lead(err.to_string())))
+            }
+            DistKvError::Protocol(message) => DistKvError::Protocol(lead(message)),
+            DistKvError::Corrupt(message) => DistKvError::Corrupt(lead(message)),
+            DistKvError::Config(message) => DistKvError::Config(lead(message)),
+        }
+    }
+}
+
+impl ErrorReply {
+    fn led_by(self, lead: impl FnOnce(String) -> String) -> ErrorReply {
+        ErrorReply::new(self.code, lead(self.message))
+    }
+}
+
+/// Adds what was being done to the error of a result.
+pub trait Context<T> {
+    fn context(self, context: impl fmt::Display) -> Result<T>;
+
+    fn with_context<C: fmt::Display>(self, context: impl FnOnce() -> C) -> Result<T>;
+}
+
+impl<T, E: Into<DistKvError>> Context<T> for std::result::Result<T, E> {
+    fn context(self, context: impl fmt::Display) -> Result<T> {
+        self.map_err(|err| err.into().context(context))
+    }
+
+    fn with_context<C: fmt::Display>(self, context: impl FnOnce() -> C) -> Result<T> {
+        self.map_err(|err| err.into().context(context()))
+    }
+}
+
+impl fmt::Display for DistKvError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DistKvError::Io(err) => write!(f, "{}", err),
+            DistKvError::Protocol(message)
+            | DistKvError::Corrupt(message)
+            | DistKvError::Config(message) => write!(f, "{}", message),
+            err => {
+                let reply = err.reply().unwrap();
+                write!(f, "ERR {} {}", reply.code.as_str(), reply.message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for DistKvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            DistKvError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<ErrorReply> for DistKvError {
+    fn from(reply: ErrorReply) -> Self {
+        match reply.code {
+            ErrorCode::Syntax
+            | ErrorCode::WrongArgs
+            | ErrorCode::UnknownCommand
+            | ErrorCode::TooLarge => DistKvError::Parse(reply),
+            ErrorCode::ReadOnly | ErrorCode::Loading | ErrorCode::IoErr => {
+                DistKvError::Storage(reply)
+            }
+            ErrorCode::NoQuorum | ErrorCode::Leased | ErrorCode::LinkDown => {
+                DistKvError::Replication(reply)
+            }
+            ErrorCode::NoAuth | ErrorCode::NoPerm => DistKvError::Auth(reply),
+            ErrorCode::Moved => DistKvError::NotLeader {
+                leader: reply.message,
+            },
+            _ => DistKvError::Server(reply),
+        }
+    }
+}
+
+impl From<ParseError> for DistKvError {
+    fn from(err: ParseError) -> Self {
+        DistKvError::Parse(err.into())
+    }
+}
+
+impl From<io::Error> for DistKvError {
+    fn from(err: io::Error) -> Self {
+        DistKvError::Io(err)
+    }
+}
+
+impl From<nix::Error> for DistKvError {
+    fn from(err: nix::Error) -> Self {
+        DistKvError::Io(err.into())
+    }
+}
+
+/// A number in a setting or argument that doesn't parse.
+impl From<ParseIntError> for DistKvError {
+    fn from(err: ParseIntError) -> Self {
+        DistKvError::Config(err.to_string())
+    }
+}
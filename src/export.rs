@@ -0,0 +1,115 @@
+//! The rows `EXPORT` writes keys as, for feeding a namespace to analytics
+//! tools.
+//!
+//! CSV starts with a `key,value,expires_at` header and quotes fields as RFC
+//! 4180 does. Keys and values are written as they are, so one that isn't
+//! text comes out as its bytes. NDJSON is an object per line with `key`,
+//! `value` and `expires_at` fields. JSON strings have to be text, so a key
+//! or value that isn't UTF-8 is written base64-encoded under `key_base64`
+//! or `value_base64` instead. In both, `expires_at` is in milliseconds since
+//! the Unix epoch, and empty or `null` for keys that don't expire.
+
+use std::fmt::Write as _;
+
+use crate::protocol::ExportFormat;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// What an export in `format` starts with.
+pub fn header(format: ExportFormat) -> &'static [u8] {
+    match format {
+        ExportFormat::Csv => b"key,value,expires_at\r\n",
+        ExportFormat::Ndjson => b"",
+    }
+}
+
+/// Appends the row for a key to `out`.
+pub fn row(
+    format: ExportFormat,
+    key: &[u8],
+    val: &[u8],
+    expires_at: Option<u64>,
+    out: &mut Vec<u8>,
+) {
+    match format {
+        ExportFormat::Csv => {
+            csv_field(key, out);
+            out.push(b',');
+            csv_field(val, out);
+            out.push(b',');
+            if let Some(at) = expires_at {
+                out.extend_from_slice(at.to_string().as_bytes());
+            }
+            out.extend_from_slice(b"\r\n");
+        }
+        ExportFormat::Ndjson => {
+            let mut line = String::from("{");
+            json_field(&mut line, "key", key);
+            line.push(',');
+            json_field(&mut line, "value", val);
+            match expires_at {
+                Some(at) => write!(line, ",\"expires_at\":{}}}", at).unwrap(),
+                None => line.push_str(",\"expires_at\":null}"),
+            }
+            line.push('\n');
+            out.extend_from_slice(line.as_bytes());
+        }
+    }
+}
+
+fn csv_field(field: &[u8], out: &mut Vec<u8>) {
+    if !field
+        .iter()
+        .any(|b| matches!(b, b',' | b'"' | b'\r' | b'\n'))
+    {
+        out.extend_from_slice(field);
+        return;
+    }
+    out.push(b'"');
+    for &b in field {
+        if b == b'"' {
+            out.push(b'"');
+        }
+        out.push(b);
+    }
+    out.push(b'"');
+}
+
+fn json_field(line: &mut String, name: &str, field: &[u8]) {
+    match std::str::from_utf8(field) {
+        Ok(text) => {
+            write!(line, "\"{}\":\"", name).unwrap();
+            for c in text.chars() {
+                match c {
+                    '"' => line.push_str("\\\""),
+                    '\\' => line.push_str("\\\\"),
+                    '\n' => line.push_str("\\n"),
+                    '\r' => line.push_str("\\r"),
+                    '\t' => line.push_str("\\t"),
+                    c if (c as u32) < 0x20 => write!(line, "\\u{:04x}", c as u32).unwrap(),
+                    c => line.push(c),
+                }
+            }
+            line.push('"');
+        }
+        Err(_) => write!(line, "\"{}_base64\":\"{}\"", name, base64(field)).unwrap(),
+    }
+}
+
+fn base64(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(BASE64[(n >> (18 - 6 * i) & 63) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
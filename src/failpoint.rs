@@ -0,0 +1,123 @@
+//! Named failpoints for exercising recovery paths in tests.
+//!
+//! Call sites ask [`eval`] whether a failpoint is armed and act on the
+//! returned [`Action`]. Without the `failpoints` feature `eval` always
+//! returns `None` and the registry doesn't exist, so the checks compile away.
+//!
+//! Failpoints in use:
+//! - `wal::append`: `Error` or `ShortWrite(n)` on log appends, or `Crash`
+//!   halfway through writing the record.
+//! - `wal::sync`: `Error` on log syncs, or `Crash` before syncing.
+//! - `leader::after_sync`: `Crash` once a write is durable but before it has
+//!   been replicated or acknowledged.
+//! - `replication::send`: `Drop`, `Delay`, `Duplicate` or `Reorder` records
+//!   sent to a follower, or `Crash` halfway through sending one.
+
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Fail the operation with an I/O error.
+    Error,
+    /// Write only the first `n` bytes, then fail.
+    ShortWrite(usize),
+    /// Silently skip the operation.
+    Drop,
+    /// Wait before performing the operation.
+    Delay(Duration),
+    /// Perform the operation twice.
+    Duplicate,
+    /// Hold this message back and send it after the next one.
+    Reorder,
+    /// Kill the process on the spot, see [`crash`].
+    Crash,
+}
+
+/// Ends the process immediately, like `kill -9`: no destructors run and
+/// nothing buffered in user space is flushed. Data already handed to the
+/// kernel survives, as it would a real process crash.
+pub fn crash() -> ! {
+    std::process::abort()
+}
+
+#[cfg(feature = "failpoints")]
+mod registry {
+    use std::collections::HashMap;
+    use std::sync::{Mutex, OnceLock};
+
+    use super::Action;
+
+    struct FailPoint {
+        action: Action,
+        /// How many more times the failpoint fires, or `None` for always.
+        remaining: Option<usize>,
+    }
+
+    fn failpoints() -> &'static Mutex<HashMap<String, FailPoint>> {
+        static FAILPOINTS: OnceLock<Mutex<HashMap<String, FailPoint>>> = OnceLock::new();
+        FAILPOINTS.get_or_init(Default::default)
+    }
+
+    pub fn set(name: &str, action: Action, remaining: Option<usize>) {
+        let failpoint = FailPoint { action, remaining };
+        failpoints()
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), failpoint);
+    }
+
+    pub fn remove(name: &str) {
+        failpoints().lock().unwrap().remove(name);
+    }
+
+    pub fn clear() {
+        failpoints().lock().unwrap().clear();
+    }
+
+    pub fn eval(name: &str) -> Option<Action> {
+        let mut failpoints = failpoints().lock().unwrap();
+        let failpoint = failpoints.get_mut(name)?;
+        let action = failpoint.action;
+        match failpoint.remaining.as_mut() {
+            Some(0) => return None,
+            Some(remaining) => *remaining -= 1,
+            None => {}
+        }
+        Some(action)
+    }
+}
+
+/// Arms `name` until it is removed.
+#[cfg(feature = "failpoints")]
+pub fn set(name: &str, action: Action) {
+    registry::set(name, action, None);
+}
+
+/// Arms `name` for the next `times` evaluations only.
+#[cfg(feature = "failpoints")]
+pub fn set_times(name: &str, action: Action, times: usize) {
+    registry::set(name, action, Some(times));
+}
+
+#[cfg(feature = "failpoints")]
+pub fn remove(name: &str) {
+    registry::remove(name);
+}
+
+/// Disarms every failpoint.
+#[cfg(feature = "failpoints")]
+pub fn clear() {
+    registry::clear();
+}
+
+/// Returns the action to take if `name` is armed.
+#[cfg(feature = "failpoints")]
+pub fn eval(name: &str) -> Option<Action> {
+    registry::eval(name)
+}
+
+#[cfg(not(feature = "failpoints"))]
+#[inline(always)]
+pub fn eval(_name: &str) -> Option<Action> {
+    None
+}
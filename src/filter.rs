@@ -0,0 +1,179 @@
+//! Filters and transforms on what the leader replicates to a follower, for
+//! followers such as edge caches that only need part of the data, or
+//! copies in less trusted places that shouldn't see all of it.
+//!
+//! Each follower's records pass through its pipeline of [`Transform`]s
+//! before they are queued, and so do the snapshots it is sent, so what a
+//! transform drops or redacts never leaves the leader. The pipeline is the
+//! follower's `replication_filter`, then its `replication_transform`s in
+//! the order given, then any transforms the code that made its
+//! [`crate::server::Replica`] added.
+
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+
+use crate::error::{bail, Result};
+use crate::protocol::{parse_all, Command, Limits};
+use crate::store::{self, Db, DEFAULT_NAMESPACE};
+
+/// A stage of a follower's replication pipeline.
+pub trait Transform: Send + Sync {
+    /// What to send in place of `command`, a record made in `namespace`, or
+    /// `None` to drop it. `BATCH` and `IN` records are taken apart first,
+    /// so `command` is never one; records without keys, like `REQID`
+    /// markers, are passed too.
+    fn transform(&self, namespace: &[u8], command: Command<'static>) -> Option<Command<'static>>;
+}
+
+/// The key prefixes a follower is sent, from `replication_filter
+/// <host:port> <prefix>[,...]`. Records without keys always go through, and
+/// a write to several keys goes through whole if any of them match.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyFilter {
+    prefixes: Vec<Bytes>,
+}
+
+impl KeyFilter {
+    pub fn parse(prefixes: &str) -> Result<KeyFilter> {
+        let prefixes: Vec<Bytes> = prefixes
+            .split(',')
+            .map(str::trim)
+            .filter(|prefix| !prefix.is_empty())
+            .map(|prefix| Bytes::copy_from_slice(prefix.as_bytes()))
+            .collect();
+        if prefixes.is_empty() {
+            bail!(Config, "a replication filter needs at least one prefix");
+        }
+        Ok(KeyFilter { prefixes })
+    }
+
+    pub fn matches(&self, key: &[u8]) -> bool {
+        self.prefixes.iter().any(|prefix| key.starts_with(prefix))
+    }
+}
+
+impl Transform for KeyFilter {
+    fn transform(&self, _: &[u8], command: Command<'static>) -> Option<Command<'static>> {
+        let keys = command.keys();
+        (keys.is_empty() || keys.into_iter().any(|key| self.matches(key))).then_some(command)
+    }
+}
+
+/// A change made to the records of keys with a prefix, from
+/// `replication_transform <host:port> redact|drop <prefix>` and
+/// `replication_transform <host:port> rename <prefix> <new prefix>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Rewrite {
+    /// Sends the keys with empty values.
+    Redact(Bytes),
+    /// Swaps the prefix of the keys for another.
+    Rename(Bytes, Bytes),
+    /// Doesn't send the keys at all.
+    Drop(Bytes),
+}
+
+impl Rewrite {
+    pub fn parse(spec: &str) -> Result<Rewrite> {
+        let fields: Vec<_> = spec.split_whitespace().collect();
+        let bytes = |field: &str| Bytes::copy_from_slice(field.as_bytes());
+        Ok(match fields[..] {
+            ["redact", prefix] => Rewrite::Redact(bytes(prefix)),
+            ["rename", from, to] => Rewrite::Rename(bytes(from), bytes(to)),
+            ["drop", prefix] => Rewrite::Drop(bytes(prefix)),
+            _ => bail!(
+                Config,
+                "expected `redact <prefix>`, `rename <prefix> <new prefix>` or `drop <prefix>`"
+            ),
+        })
+    }
+}
+
+impl Transform for Rewrite {
+    fn transform(&self, _: &[u8], command: Command<'static>) -> Option<Command<'static>> {
+        let prefix = match self {
+            Rewrite::Redact(prefix) | Rewrite::Rename(prefix, _) | Rewrite::Drop(prefix) => prefix,
+        };
+        if !command.keys().iter().any(|key| key.starts_with(prefix)) {
+            return Some(command);
+        }
+        match self {
+            Rewrite::Drop(_) => None,
+            Rewrite::Redact(_) => Some(match command {
+                Command::Set(key, _) => Command::Set(key, Vec::new().into()),
+                command => command,
+            }),
+            Rewrite::Rename(from, to) => {
+                let rename = |key: std::borrow::Cow<'static, [u8]>| {
+                    let mut renamed = to.to_vec();
+                    renamed.extend_from_slice(&key[from.len()..]);
+                    renamed.into()
+                };
+                Some(match command {
+                    Command::Set(key, val) => Command::Set(rename(key), val),
+                    Command::Delete(key) => Command::Delete(rename(key)),
+                    Command::PExpireAt(key, at) => Command::PExpireAt(rename(key), at),
+                    Command::Persist(key) => Command::Persist(rename(key)),
+                    command => command,
+                })
+            }
+        }
+    }
+}
+
+/// A follower's transforms, applied in order.
+pub struct Pipeline<'a> {
+    stages: Vec<&'a dyn Transform>,
+}
+
+impl<'a> Pipeline<'a> {
+    /// The pipeline of `stages`, or `None` if there are none, as records
+    /// are then sent as they are.
+    pub fn new(
+        filter: Option<&'a KeyFilter>,
+        rewrites: &'a [Rewrite],
+        transforms: &'a [Arc<dyn Transform>],
+    ) -> Option<Pipeline<'a>> {
+        let mut stages: Vec<&'a dyn Transform> = Vec::new();
+        stages.extend(filter.map(|filter| filter as &dyn Transform));
+        stages.extend(rewrites.iter().map(|rewrite| rewrite as &dyn Transform));
+        stages.extend(transforms.iter().map(|transform| &**transform));
+        (!stages.is_empty()).then_some(Pipeline { stages })
+    }
+
+    fn command(&self, namespace: &[u8], command: Command<'static>) -> Option<Command<'static>> {
+        match command {
+            Command::Batch(commands) => {
+                let commands: Vec<_> = commands
+                    .into_iter()
+                    .filter_map(|command| self.command(namespace, command))
+                    .collect();
+                (!commands.is_empty()).then_some(Command::Batch(commands))
+            }
+            Command::In(name, command) => self
+                .command(&name, *command)
+                .map(|command| Command::In(name, Box::new(command))),
+            command => self.stages.iter().try_fold(command, |command, stage| {
+                stage.transform(namespace, command)
+            }),
+        }
+    }
+
+    /// What is sent of `record`.
+    pub fn record(&self, record: &[u8]) -> BytesMut {
+        let mut transformed = BytesMut::new();
+        let commands = parse_all(record, &Limits::NONE).expect("records always parse");
+        for command in commands {
+            if let Some(command) = self.command(DEFAULT_NAMESPACE, command) {
+                command.encode(&mut transformed);
+            }
+        }
+        transformed
+    }
+
+    /// What is sent of `hashmap`, as a map of its own.
+    pub fn map(&self, hashmap: &Db) -> Db {
+        let records = self.record(&store::records(hashmap));
+        store::replay(&records).expect("records always replay")
+    }
+}
@@ -1,79 +1,204 @@
 use std::fs::File;
-use std::fs::OpenOptions;
-use std::io::Write;
-use tokio::io::AsyncBufReadExt;
-
-use tokio::io::BufReader;
-use tokio::net::TcpStream;
-
-use anyhow::Result;
-use std::collections::HashMap;
 use std::sync::Arc;
 use std::sync::Mutex;
 
-type Key = String;
-type Val = String;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::task::JoinSet;
+
+use crate::checkpoint;
+use crate::clock::SystemClock;
+use crate::error::{DistKvError, Result};
+use crate::lz4::FrameDecoder;
+use crate::node::FollowerCore;
+use crate::protocol::{split_frame, split_line, Command, Limits};
+use crate::replication::offer_lz4;
+use crate::snapshot::{self, Download};
+use crate::transport::{Connection, Listener, Transport};
+
+/// How many times [`bootstrap`] connects to the leader before giving up.
+const SYNC_ATTEMPTS: usize = 5;
 
-type Db = HashMap<String, String>;
-type SyncDb = Arc<Mutex<Db>>;
-type SyncFile = Arc<Mutex<File>>;
+pub type SyncFollower = Arc<Mutex<FollowerCore<File, SystemClock>>>;
 
-#[derive(Debug)]
-enum Command {
-    Set(Key, Val),
-    Delete(Key),
-    Unknown,
+/// Accepts replication connections until the task is dropped, which also
+/// drops every connection it accepted.
+pub async fn serve(mut listener: Listener, follower: SyncFollower) -> Result<()> {
+    let mut connections = JoinSet::new();
+    loop {
+        let (mut socket, _addr) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            Some(_) = connections.join_next() => continue,
+        };
+        let follower = follower.clone();
+        connections.spawn(async move {
+            if let Err(e) = handle_client(&mut socket, &follower).await {
+                eprintln!("Error = {:?}", e);
+            }
+        });
+    }
 }
 
-impl From<String> for Command {
-    fn from(s: String) -> Self {
-        let mut split_s = s.split_whitespace().skip(1);
-        let key = split_s.next().expect("Expected a key").to_string();
-        if s.starts_with("SET") {
-            let val = split_s.next().expect("Expected a value").to_string();
-            Command::Set(key, val)
-        } else if s.starts_with("DEL") {
-            Command::Delete(key)
-        } else {
-            Command::Unknown
+/// Serves a connection to the follower's port, which is the leader's
+/// replication stream once it says so with `REPLICATE`. Until then writes
+/// are turned away, see [`FollowerCore::answer`].
+pub async fn handle_client(socket: &mut Connection, follower: &SyncFollower) -> Result<()> {
+    let mut buf = BytesMut::with_capacity(4096);
+    let mut lz4 = false;
+    loop {
+        let (replies, replicating) = follower.lock().unwrap().answer(&mut buf, &mut lz4)?;
+        if !replies.is_empty() {
+            socket.write_all(&replies).await?;
+        }
+        if replicating {
+            return apply_stream(socket, buf, follower, lz4).await;
+        }
+        if socket.read_buf(&mut buf).await? == 0 {
+            return Ok(());
         }
     }
 }
 
-pub async fn handle_client(
-    socket: &mut TcpStream,
-    file: &mut SyncFile,
-    hashmap: &mut SyncDb,
+/// Brings a new follower up from the leader at `addr`: downloads a snapshot,
+/// installs it in place of the follower's log and then applies the writes
+/// the leader replicates over the same connection. A transfer that is cut
+/// off is resumed from where it stopped. When the leader closes the stream,
+/// as it does to a follower that fell too far behind, the follower syncs
+/// again, until the leader can't be reached through `transport`.
+pub async fn bootstrap(
+    transport: &dyn Transport,
+    addr: &str,
+    follower: &SyncFollower,
 ) -> Result<()> {
-    let (mut read_stream, _write_stream) = tokio::io::split(socket);
+    follower.lock().unwrap().leader = Some(addr.to_string());
     loop {
-        let mut read_stream = BufReader::new(&mut read_stream);
-        loop {
-            let mut data = String::new();
-            let _read = read_stream.read_line(&mut data).await?;
-            let data = data.trim_end().to_string();
-            dbg!(&data);
-            match Command::from(data) {
-                Command::Delete(key) => {
-                    let mut hashmap = hashmap.lock().unwrap();
-                    let mut file = file.lock().unwrap();
-                    hashmap.remove(&key);
-                    let str_command = format!("DEL {}\n", key);
-                    dbg!(&str_command);
-                    file.write_all(str_command.as_bytes())?;
-                    file.sync_all()?;
-                }
-                Command::Set(key, val) => {
-                    let mut hashmap = hashmap.lock().unwrap();
-                    let mut file = file.lock().unwrap();
-                    hashmap.insert(key.clone(), val.clone());
-                    let str_command = format!("SET {} {}\n", key, val);
-                    dbg!(&str_command);
-                    file.write_all(str_command.as_bytes())?;
-                    file.sync_all()?;
+        let (mut socket, buf, lz4) = sync(transport, addr, follower).await?;
+        apply_stream(&mut socket, buf, follower, lz4).await?;
+        eprintln!("{} closed the replication stream, syncing again", addr);
+    }
+}
+
+/// Downloads and installs a snapshot, retrying up to [`SYNC_ATTEMPTS`]
+/// times.
+async fn sync(
+    transport: &dyn Transport,
+    addr: &str,
+    follower: &SyncFollower,
+) -> Result<(Connection, BytesMut, bool)> {
+    let mut download = None;
+    let mut attempt = 1;
+    loop {
+        match fetch(transport, addr, &mut download, follower).await {
+            Ok(synced) => return Ok(synced),
+            Err(e) if attempt < SYNC_ATTEMPTS => {
+                eprintln!("Sync with {} failed, retrying: {:?}", addr, e);
+                attempt += 1;
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Downloads and installs a snapshot, resuming `download` if there is one.
+/// Returns the connection along with whatever of the replication stream was
+/// read past the snapshot, and whether the leader compresses that stream.
+async fn fetch(
+    transport: &dyn Transport,
+    addr: &str,
+    download: &mut Option<Download>,
+    follower: &SyncFollower,
+) -> Result<(Connection, BytesMut, bool)> {
+    let mut socket = transport.connect(addr).await?;
+    let mut buf = BytesMut::with_capacity(snapshot::CHUNK_SIZE);
+    let lz4 = offer_lz4(&mut socket, &mut buf).await?;
+    let resume = download.as_ref().map(|d| (d.id, d.data.len()));
+    let mut request = BytesMut::new();
+    let advertised = follower.lock().unwrap().addr.clone();
+    Command::Sync(
+        resume,
+        advertised.as_deref().map(|addr| addr.as_bytes().into()),
+    )
+    .encode(&mut request);
+    socket.write_all(&request).await?;
+    let header = loop {
+        if let Some(line) = split_line(&mut buf) {
+            break line;
+        }
+        if socket.read_buf(&mut buf).await? == 0 {
+            return Err(DistKvError::link_down(
+                "leader closed the connection before replying to SYNC",
+            ));
+        }
+    };
+    let (id, len, offset) = snapshot::parse_header(&header)?;
+    if download.as_ref().map(|d| (d.id, d.data.len())) != Some((id, offset)) {
+        *download = Some(Download::new(id, len));
+    }
+    let download = download.as_mut().unwrap();
+
+    while !download.is_complete() {
+        match split_frame(&mut buf, &Limits::NONE)? {
+            Some(frame) => download.push(&frame)?,
+            None => {
+                if socket.read_buf(&mut buf).await? == 0 {
+                    return Err(DistKvError::link_down(
+                        "leader closed the connection partway through the snapshot",
+                    ));
                 }
-                Command::Unknown => {}
             }
         }
     }
+    install(&mut follower.lock().unwrap(), &download.data)?;
+    Ok((socket, buf, lz4))
+}
+
+/// Replaces the follower's map and logs with a snapshot from the leader. A
+/// follower that knows where its log is starts it over from a checkpoint of
+/// the snapshot, see [`checkpoint::install`], and replaces its namespaces'
+/// logs file by file, so that a crash partway through can't leave a log
+/// that replays to part of the snapshot.
+fn install(follower: &mut FollowerCore<File, SystemClock>, data: &[u8]) -> Result<()> {
+    let Some(log) = follower.log_path.clone() else {
+        return follower.install_snapshot(data);
+    };
+    let snapshot = snapshot::decode(data)?;
+    follower.namespace_logs.replace(&log, &snapshot.hashmap)?;
+    follower.wal = checkpoint::install(&log, data)?;
+    follower.hashmap = snapshot.hashmap;
+    follower.lsn = snapshot.header.lsn;
+    Ok(())
+}
+
+/// Applies the replication stream, starting with what is already in `buf`.
+/// An `lz4` stream is decompressed as it arrives.
+async fn apply_stream(
+    socket: &mut Connection,
+    buf: BytesMut,
+    follower: &SyncFollower,
+    lz4: bool,
+) -> Result<()> {
+    let mut decoder = lz4.then(FrameDecoder::new);
+    let (mut compressed, mut buf) = match lz4 {
+        true => (buf, BytesMut::new()),
+        false => (BytesMut::new(), buf),
+    };
+    loop {
+        if let Some(decoder) = &mut decoder {
+            decoder.decode(&mut compressed, &mut buf)?;
+        }
+        let replies = follower.lock().unwrap().receive(&mut buf)?;
+        if !replies.is_empty() {
+            socket.write_all(&replies).await?;
+        }
+        let read = match decoder {
+            Some(_) => socket.read_buf(&mut compressed).await?,
+            None => socket.read_buf(&mut buf).await?,
+        };
+        if read == 0 {
+            return Ok(());
+        }
+    }
 }
@@ -0,0 +1,275 @@
+//! Recent history of the leader's map, so that `GET key AT <lsn>` can answer
+//! what a key held as of an earlier write, and `HISTORY key [limit]` list the
+//! values the last writes to it left, with their LSNs and when they were made.
+//!
+//! Before each write the leader notes the values of the keys it is about to
+//! change, and of those that expire along with it, tagged with the LSN the
+//! write is logged as. A key's value as of LSN `n` is then its value now with
+//! every change logged after `n` undone, newest first. Only the last
+//! `history_size` writes are kept, so LSNs from before them can't be looked
+//! up. The history is kept in memory only and starts over when the leader
+//! restarts.
+//!
+//! The same history makes the map multi-versioned for reads that span more
+//! than one turn of the leader's lock. A scan [pins](History::pin) the LSN it
+//! started at and reads every key [as of](History::value_at) it, so it sees
+//! the map as it was when it began while writes go ahead in between. The
+//! changes of writes after a pinned LSN are kept until it is unpinned,
+//! however many there are. TTLs are not versioned.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write as _;
+
+use bytes::Bytes;
+
+use crate::protocol::{Command, ErrorCode, ErrorReply};
+use crate::store::{Db, Key, Response, Val, DEFAULT_NAMESPACE};
+
+/// The namespace and key a write changed, and the value it had before.
+type Change = (Bytes, Key, Option<Val>);
+
+/// How many versions `HISTORY` lists when not given a limit.
+pub const DEFAULT_HISTORY_LIMIT: usize = 10;
+
+/// A write kept in the history.
+#[derive(Debug)]
+struct Write {
+    lsn: u64,
+    /// When it was made, in milliseconds since the Unix epoch.
+    at: u64,
+    changes: Vec<Change>,
+}
+
+#[derive(Debug, Default)]
+pub struct History {
+    /// How many writes to keep the changes of. 0 keeps none.
+    pub limit: usize,
+    /// The writes kept, oldest first.
+    writes: VecDeque<Write>,
+    /// Noted for the write in progress, before it has an LSN.
+    pending: Vec<Change>,
+    /// The earliest LSN values can be looked up as of, once there are any
+    /// writes kept: the one before the oldest kept.
+    floor: u64,
+    /// The LSNs scans are reading as of, and how many are at each.
+    pins: BTreeMap<u64, usize>,
+}
+
+impl History {
+    /// Notes the values the keys `command` writes have in `hashmap`, before
+    /// it runs.
+    pub fn note_command(&mut self, hashmap: &Db, command: &Command<'_>) {
+        if !self.recording() || !command.is_write() {
+            return;
+        }
+        let mut touched = Vec::new();
+        touched_keys(DEFAULT_NAMESPACE, command, &mut touched);
+        for (namespace, key) in touched {
+            let previous = hashmap
+                .namespace(namespace)
+                .and_then(|db| db.get(key))
+                .cloned();
+            self.note(namespace, key, previous);
+        }
+    }
+
+    /// Notes that `key` of `namespace` held `previous` before the write in
+    /// progress.
+    pub fn note(&mut self, namespace: &[u8], key: &[u8], previous: Option<Val>) {
+        if !self.recording() {
+            return;
+        }
+        self.pending.push((
+            Bytes::copy_from_slice(namespace),
+            Key::copy_from_slice(key),
+            previous,
+        ));
+    }
+
+    /// Keeps what was noted as the changes of the write logged as `lsn` at
+    /// `at`, or drops it if the write changed nothing and so wasn't logged.
+    pub fn commit(&mut self, lsn: Option<u64>, at: u64) {
+        let changes = std::mem::take(&mut self.pending);
+        let Some(lsn) = lsn.filter(|_| self.recording()) else {
+            return;
+        };
+        if self.writes.is_empty() {
+            self.floor = lsn - 1;
+        }
+        self.writes.push_back(Write { lsn, at, changes });
+        self.trim();
+    }
+
+    /// Drops the oldest writes past `limit` that no pinned LSN needs.
+    fn trim(&mut self) {
+        let pinned = self.pins.keys().next().copied().unwrap_or(u64::MAX);
+        while self.writes.len() > self.limit {
+            match self.writes.front() {
+                Some(oldest) if oldest.lsn <= pinned => self.floor = oldest.lsn,
+                _ => break,
+            }
+            self.writes.pop_front();
+        }
+    }
+
+    /// How many writes' changes are kept, and how many scans have LSNs
+    /// pinned.
+    pub fn stats(&self) -> (usize, usize) {
+        (self.writes.len(), self.pins.values().sum())
+    }
+
+    /// Whether writes' changes are being kept, for lookups or scans.
+    fn recording(&self) -> bool {
+        self.limit > 0 || !self.pins.is_empty()
+    }
+
+    /// Keeps the changes of every write after `lsn`, the last written, until
+    /// it is unpinned, so that the map can be read as of `lsn` meanwhile.
+    pub fn pin(&mut self, lsn: u64) {
+        *self.pins.entry(lsn).or_default() += 1;
+    }
+
+    pub fn unpin(&mut self, lsn: u64) {
+        if let Some(count) = self.pins.get_mut(&lsn) {
+            *count -= 1;
+            if *count == 0 {
+                self.pins.remove(&lsn);
+            }
+        }
+        self.trim();
+    }
+
+    /// Answers `command` if it reads the history, as `GET key AT lsn` and
+    /// `HISTORY key [limit]` do, in whichever namespace it is run, given the
+    /// map as of `current`, the LSN of the last write.
+    pub fn answer(&self, hashmap: &Db, command: &Command<'_>, current: u64) -> Option<Response> {
+        let (namespace, command) = match command {
+            Command::In(name, command) => (&name[..], &**command),
+            command => (DEFAULT_NAMESPACE, command),
+        };
+        match command {
+            Command::GetAt(key, lsn) => Some(self.get_at(hashmap, namespace, key, *lsn, current)),
+            Command::History(key, limit) => {
+                let limit = limit.unwrap_or(DEFAULT_HISTORY_LIMIT);
+                let mut out = String::new();
+                for (lsn, at, value) in self.versions(hashmap, namespace, key, limit) {
+                    let value = match value {
+                        Some(value) => format!("{:?}", String::from_utf8_lossy(&value)),
+                        None => "nil".to_string(),
+                    };
+                    let _ = writeln!(out, "{} {} {}", lsn, at, value);
+                }
+                Some(Response::Info(out))
+            }
+            _ => None,
+        }
+    }
+
+    fn get_at(
+        &self,
+        hashmap: &Db,
+        namespace: &[u8],
+        key: &[u8],
+        lsn: u64,
+        current: u64,
+    ) -> Response {
+        if lsn > current {
+            let message = format!(
+                "LSN {} hasn't been written yet, the last is {}",
+                lsn, current
+            );
+            return Response::Error(ErrorReply::new(ErrorCode::WrongArgs, message));
+        }
+        // Without any writes kept, only the map as it is now is known.
+        let floor = match self.writes.is_empty() {
+            true => current,
+            false => self.floor,
+        };
+        if lsn < floor {
+            let message = format!("history before LSN {} is not kept", floor);
+            return Response::Error(ErrorReply::new(ErrorCode::NotSupported, message));
+        }
+        let key = Key::copy_from_slice(key);
+        match self.value_at(hashmap, namespace, &key, lsn) {
+            Some(value) => Response::Get(key, value),
+            None => Response::KeyNotFound(key),
+        }
+    }
+
+    /// The value `key` of `namespace` had as of `lsn`, given the map as it
+    /// is now. `lsn` must be one the history goes back to, as a pinned one
+    /// does.
+    pub fn value_at(&self, hashmap: &Db, namespace: &[u8], key: &[u8], lsn: u64) -> Option<Val> {
+        let mut value = hashmap
+            .namespace(namespace)
+            .and_then(|db| db.get(key))
+            .cloned();
+        let later = self.writes.iter().rev().take_while(|write| write.lsn > lsn);
+        for write in later {
+            value = write.undo(namespace, key, value);
+        }
+        value
+    }
+
+    /// The values the last `limit` writes kept that changed `key` of
+    /// `namespace` left it with, newest first, with the LSN and time of
+    /// each write. `None` is a write that deleted it.
+    pub fn versions(
+        &self,
+        hashmap: &Db,
+        namespace: &[u8],
+        key: &[u8],
+        limit: usize,
+    ) -> Vec<(u64, u64, Option<Val>)> {
+        let mut value = hashmap
+            .namespace(namespace)
+            .and_then(|db| db.get(key))
+            .cloned();
+        let mut versions = Vec::new();
+        for write in self.writes.iter().rev() {
+            if versions.len() == limit {
+                break;
+            }
+            if write.changed(namespace, key) {
+                let before = write.undo(namespace, key, value.clone());
+                versions.push((write.lsn, write.at, value));
+                value = before;
+            }
+        }
+        versions
+    }
+}
+
+impl Write {
+    fn changed(&self, namespace: &[u8], key: &[u8]) -> bool {
+        self.changes
+            .iter()
+            .any(|(name, changed, _)| name == namespace && changed == key)
+    }
+
+    /// The value of `key` before this write, given it was `value` after.
+    fn undo(&self, namespace: &[u8], key: &[u8], mut value: Option<Val>) -> Option<Val> {
+        // Undone newest first, so the value before the earliest change wins.
+        for (name, changed, previous) in self.changes.iter().rev() {
+            if name == namespace && changed == key {
+                value = previous.clone();
+            }
+        }
+        value
+    }
+}
+
+/// The keys of each namespace that `command`, run in `namespace`, writes.
+pub(crate) fn touched_keys<'c>(
+    namespace: &'c [u8],
+    command: &'c Command<'_>,
+    touched: &mut Vec<(&'c [u8], &'c [u8])>,
+) {
+    match command {
+        Command::In(name, command) => touched_keys(name, command, touched),
+        Command::Batch(commands) => commands
+            .iter()
+            .for_each(|command| touched_keys(namespace, command, touched)),
+        command => touched.extend(command.keys().into_iter().map(|key| (namespace, key))),
+    }
+}
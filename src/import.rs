@@ -0,0 +1,345 @@
+//! Reads Redis data as the commands that load it into dist-kv, for
+//! `dist-kv-import`.
+//!
+//! An RDB dump is read for its string keys, with their TTLs, and each of its
+//! databases becomes the namespace of the same number; keys of any other
+//! type stop the import, as dist-kv has nowhere to put them. A RESP stream,
+//! such as an append-only file or `redis-cli --pipe` input, is read as the
+//! commands in it, which have to be ones dist-kv knows. `MULTI` and `EXEC`
+//! are left out, so the writes of a transaction are imported one by one.
+//! An append-only file that starts with an RDB preamble is read as both.
+//!
+//! The RDB checksum isn't checked.
+
+use std::borrow::Cow;
+
+use bytes::BytesMut;
+
+use crate::client::{DistKvClient, Reply};
+use crate::error::{bail, DistKvError, Result};
+use crate::protocol::Command;
+
+pub const RDB_MAGIC: &[u8] = b"REDIS";
+
+const OPCODE_FUNCTION: u8 = 0xf5;
+const OPCODE_MODULE_AUX: u8 = 0xf7;
+const OPCODE_IDLE: u8 = 0xf8;
+const OPCODE_FREQ: u8 = 0xf9;
+const OPCODE_AUX: u8 = 0xfa;
+const OPCODE_RESIZEDB: u8 = 0xfb;
+const OPCODE_EXPIRETIME_MS: u8 = 0xfc;
+const OPCODE_EXPIRETIME: u8 = 0xfd;
+const OPCODE_SELECTDB: u8 = 0xfe;
+const OPCODE_EOF: u8 = 0xff;
+
+const TYPE_STRING: u8 = 0;
+
+const ENCODING_INT8: u64 = 0;
+const ENCODING_INT16: u64 = 1;
+const ENCODING_INT32: u64 = 2;
+const ENCODING_LZF: u64 = 3;
+
+/// The commands that load `data`, an RDB dump or a RESP stream.
+pub fn read(data: &[u8]) -> Result<Vec<Command<'static>>> {
+    if data.starts_with(RDB_MAGIC) {
+        let mut reader = Reader { data, pos: 0 };
+        let mut commands = reader.rdb()?;
+        commands.extend(read_resp(&data[reader.pos..])?);
+        Ok(commands)
+    } else {
+        read_resp(data)
+    }
+}
+
+/// The commands that load the string keys of an RDB dump.
+pub fn read_rdb(data: &[u8]) -> Result<Vec<Command<'static>>> {
+    Reader { data, pos: 0 }.rdb()
+}
+
+/// The commands of a RESP stream, each an array of bulk strings.
+pub fn read_resp(data: &[u8]) -> Result<Vec<Command<'static>>> {
+    let mut reader = Reader { data, pos: 0 };
+    let mut commands = Vec::new();
+    while reader.pos < data.len() {
+        let at = reader.pos;
+        let mut args = reader.resp_array()?;
+        let Some(name) = args.first_mut() else {
+            bail!(Corrupt, "empty command at byte {}", at);
+        };
+        name.make_ascii_uppercase();
+        if matches!(&name[..], b"MULTI" | b"EXEC") {
+            continue;
+        }
+        let command = Command::from_args(args.iter().map(Vec::as_slice)).map_err(|e| {
+            let name = String::from_utf8_lossy(&args[0]);
+            DistKvError::Corrupt(format!("{} at byte {}: {}", name, at, e))
+        })?;
+        commands.push(command.into_owned());
+    }
+    Ok(commands)
+}
+
+/// Sends `commands` over `client`, `pipeline` at a time, stopping at the
+/// first one refused. Returns how many were sent.
+pub async fn load(
+    client: &mut DistKvClient,
+    commands: &[Command<'_>],
+    pipeline: usize,
+) -> Result<usize> {
+    for (i, chunk) in commands.chunks(pipeline.max(1)).enumerate() {
+        let mut requests = BytesMut::new();
+        for command in chunk {
+            command.encode(&mut requests);
+        }
+        client.send_raw(&requests).await?;
+        let mut refused = None;
+        for (j, command) in chunk.iter().enumerate() {
+            if let Reply::Error(err) = client.read_reply().await? {
+                let n = i * pipeline.max(1) + j;
+                refused.get_or_insert_with(|| {
+                    DistKvError::from_line(&err).context(format!("{} #{}", command.name(), n + 1))
+                });
+            }
+        }
+        if let Some(err) = refused {
+            return Err(err);
+        }
+    }
+    Ok(commands.len())
+}
+
+/// An RDB length, or the special encoding of a string in its place.
+enum Length {
+    Len(u64),
+    Encoded(u64),
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .data
+            .get(self.pos..self.pos.saturating_add(len))
+            .ok_or_else(|| {
+                DistKvError::Corrupt(format!("input ends at byte {}", self.data.len()))
+            })?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+        Ok(self.take(N)?.try_into().unwrap())
+    }
+
+    fn rdb(&mut self) -> Result<Vec<Command<'static>>> {
+        let header = self.take(RDB_MAGIC.len() + 4)?;
+        if !header.starts_with(RDB_MAGIC) {
+            bail!(Corrupt, "not an RDB dump");
+        }
+        let version = std::str::from_utf8(&header[RDB_MAGIC.len()..])
+            .ok()
+            .and_then(|version| version.parse::<u32>().ok())
+            .ok_or_else(|| DistKvError::Corrupt("invalid RDB version".to_string()))?;
+        let mut commands = Vec::new();
+        let mut expires_at = None;
+        loop {
+            match self.byte()? {
+                OPCODE_EOF => {
+                    if version >= 5 {
+                        self.take(8)?;
+                    }
+                    return Ok(commands);
+                }
+                OPCODE_SELECTDB => {
+                    let db = self.length()?;
+                    commands.push(Command::Select(db.to_string().into_bytes().into()));
+                }
+                OPCODE_RESIZEDB => {
+                    self.length()?;
+                    self.length()?;
+                }
+                OPCODE_AUX => {
+                    self.string()?;
+                    self.string()?;
+                }
+                OPCODE_FUNCTION => {
+                    self.string()?;
+                }
+                OPCODE_EXPIRETIME_MS => {
+                    expires_at = Some(u64::from_le_bytes(self.array()?));
+                }
+                OPCODE_EXPIRETIME => {
+                    expires_at = Some(u32::from_le_bytes(self.array()?) as u64 * 1000);
+                }
+                OPCODE_IDLE => {
+                    self.length()?;
+                }
+                OPCODE_FREQ => {
+                    self.byte()?;
+                }
+                OPCODE_MODULE_AUX => bail!(Corrupt, "RDB dumps with module data can't be imported"),
+                TYPE_STRING => {
+                    let key: Cow<'static, [u8]> = self.string()?.into();
+                    let val = self.string()?;
+                    commands.push(Command::Set(key.clone(), val.into()));
+                    if let Some(at) = expires_at.take() {
+                        commands.push(Command::PExpireAt(key, at));
+                    }
+                }
+                kind => {
+                    let key = self.string()?;
+                    bail!(
+                        Corrupt,
+                        "{} is of RDB type {}, and only string keys can be imported",
+                        String::from_utf8_lossy(&key),
+                        kind
+                    );
+                }
+            }
+        }
+    }
+
+    fn length_or_encoding(&mut self) -> Result<Length> {
+        let first = self.byte()?;
+        Ok(match first >> 6 {
+            0 => Length::Len((first & 0x3f) as u64),
+            1 => Length::Len(((first & 0x3f) as u64) << 8 | self.byte()? as u64),
+            2 if first == 0x80 => Length::Len(u32::from_be_bytes(self.array()?) as u64),
+            2 if first == 0x81 => Length::Len(u64::from_be_bytes(self.array()?)),
+            2 => bail!(
+                Corrupt,
+                "invalid RDB length {:#x} at byte {}",
+                first,
+                self.pos - 1
+            ),
+            _ => Length::Encoded((first & 0x3f) as u64),
+        })
+    }
+
+    fn length(&mut self) -> Result<u64> {
+        match self.length_or_encoding()? {
+            Length::Len(len) => Ok(len),
+            Length::Encoded(_) => bail!(Corrupt, "expected an RDB length at byte {}", self.pos - 1),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        Ok(match self.length_or_encoding()? {
+            Length::Len(len) => self.take(len as usize)?.to_vec(),
+            Length::Encoded(ENCODING_INT8) => (self.byte()? as i8).to_string().into_bytes(),
+            Length::Encoded(ENCODING_INT16) => {
+                i16::from_le_bytes(self.array()?).to_string().into_bytes()
+            }
+            Length::Encoded(ENCODING_INT32) => {
+                i32::from_le_bytes(self.array()?).to_string().into_bytes()
+            }
+            Length::Encoded(ENCODING_LZF) => {
+                let compressed = self.length()? as usize;
+                let len = self.length()? as usize;
+                lzf_decompress(self.take(compressed)?, len)?
+            }
+            Length::Encoded(encoding) => bail!(Corrupt, "unknown RDB string encoding {}", encoding),
+        })
+    }
+
+    fn resp_line(&mut self) -> Result<&'a [u8]> {
+        let rest = &self.data[self.pos..];
+        let end = rest.windows(2).position(|w| w == b"\r\n").ok_or_else(|| {
+            DistKvError::Corrupt(format!("unterminated RESP line at byte {}", self.pos))
+        })?;
+        self.pos += end + 2;
+        Ok(&rest[..end])
+    }
+
+    fn resp_count(&mut self, kind: u8) -> Result<usize> {
+        let at = self.pos;
+        let line = self.resp_line()?;
+        match line.split_first() {
+            Some((&first, count)) if first == kind => std::str::from_utf8(count)
+                .ok()
+                .and_then(|count| count.parse().ok())
+                .ok_or_else(|| DistKvError::Corrupt(format!("invalid RESP length at byte {}", at))),
+            _ => bail!(Corrupt, "expected `{}` at byte {}", kind as char, at),
+        }
+    }
+
+    fn resp_array(&mut self) -> Result<Vec<Vec<u8>>> {
+        let count = self.resp_count(b'*')?;
+        let mut args = Vec::with_capacity(count.min(1024));
+        for _ in 0..count {
+            let len = self.resp_count(b'$')?;
+            args.push(self.take(len)?.to_vec());
+            if self.take(2)? != b"\r\n" {
+                bail!(
+                    Corrupt,
+                    "RESP bulk string doesn't end at byte {}",
+                    self.pos - 2
+                );
+            }
+        }
+        Ok(args)
+    }
+}
+
+/// Decompresses an LZF-compressed RDB string of `len` bytes.
+fn lzf_decompress(src: &[u8], len: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(len);
+    let mut i = 0;
+    while i < src.len() {
+        let ctrl = src[i] as usize;
+        i += 1;
+        if ctrl < 32 {
+            let Some(literals) = src.get(i..i + ctrl + 1) else {
+                bail!(Corrupt, "LZF string ends partway through its literals");
+            };
+            out.extend_from_slice(literals);
+            i += ctrl + 1;
+        } else {
+            let mut run = ctrl >> 5;
+            if run == 7 {
+                run += *src.get(i).ok_or_else(|| {
+                    DistKvError::Corrupt("LZF string ends partway through a match".to_string())
+                })? as usize;
+                i += 1;
+            }
+            let low = *src.get(i).ok_or_else(|| {
+                DistKvError::Corrupt("LZF string ends partway through a match".to_string())
+            })? as usize;
+            i += 1;
+            let back = ((ctrl & 0x1f) << 8) + low + 1;
+            if back > out.len() {
+                bail!(Corrupt, "LZF match offset {} is out of range", back);
+            }
+            // The match may overlap the bytes it makes, so it is copied a
+            // byte at a time.
+            let from = out.len() - back;
+            for k in 0..run + 2 {
+                out.push(out[from + k]);
+            }
+        }
+        if out.len() > len {
+            bail!(
+                Corrupt,
+                "LZF string decompresses to more than {} bytes",
+                len
+            );
+        }
+    }
+    if out.len() != len {
+        bail!(
+            Corrupt,
+            "LZF string decompresses to {} bytes, not {}",
+            out.len(),
+            len
+        );
+    }
+    Ok(out)
+}
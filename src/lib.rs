@@ -0,0 +1,49 @@
+pub mod allocator;
+pub mod analyze;
+pub mod archive;
+pub mod audit;
+pub mod cdc;
+pub mod checkpoint;
+pub mod checksum;
+pub mod client;
+pub mod clock;
+pub mod cluster;
+pub mod compact;
+pub mod config;
+#[cfg(feature = "dashboard")]
+pub mod dashboard;
+pub mod discovery;
+pub mod disk;
+pub mod dump;
+pub mod equivalence;
+pub mod error;
+pub mod export;
+pub mod failpoint;
+pub mod filter;
+pub mod follower;
+pub mod history;
+pub mod hyperloglog;
+pub mod import;
+pub mod linearizability;
+pub mod list;
+pub mod lz4;
+pub mod manifest;
+pub mod metrics;
+pub mod node;
+pub mod protocol;
+pub mod pubsub;
+pub mod replication;
+pub mod script;
+pub mod server;
+pub mod sim;
+pub mod snapshot;
+pub mod store;
+pub mod stream;
+pub mod tenant;
+pub mod trace;
+pub mod tracking;
+pub mod transport;
+#[cfg(feature = "io-uring")]
+pub mod uring;
+pub mod wal;
+pub mod webhook;
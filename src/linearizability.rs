@@ -0,0 +1,242 @@
+//! Recording client operations and checking them for linearizability.
+//!
+//! A [`Recorder`] is shared by concurrent clients, which note when they
+//! invoke an operation and when its response arrived. The resulting
+//! [`History`] can be written out in Jepsen's EDN history format for external
+//! checkers such as Knossos, or checked in-crate with [`History::check`]
+//! against a register per key.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::io;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::clock::Clock;
+use crate::store::{Key, Val};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Input {
+    Read,
+    Write(Val),
+    Delete,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Output {
+    Read(Option<Val>),
+    Write,
+    /// Whether the key existed.
+    Delete(bool),
+}
+
+#[derive(Debug, Clone)]
+pub struct Operation {
+    pub client: usize,
+    pub key: Key,
+    pub input: Input,
+    pub call: Duration,
+    /// The response and when it arrived, or `None` if the client never got
+    /// one, in which case the operation may or may not have taken effect.
+    pub output: Option<(Output, Duration)>,
+}
+
+/// Collects operations from concurrent clients, timestamped by one clock.
+pub struct Recorder<C> {
+    clock: C,
+    operations: Mutex<Vec<Operation>>,
+}
+
+impl<C: Clock> Recorder<C> {
+    pub fn new(clock: C) -> Self {
+        Recorder {
+            clock,
+            operations: Mutex::default(),
+        }
+    }
+
+    /// Records the invocation of an operation, returning the id to complete
+    /// it with once the response arrives.
+    pub fn invoke(&self, client: usize, key: &[u8], input: Input) -> usize {
+        let mut operations = self.operations.lock().unwrap();
+        operations.push(Operation {
+            client,
+            key: Key::copy_from_slice(key),
+            input,
+            call: self.clock.now(),
+            output: None,
+        });
+        operations.len() - 1
+    }
+
+    pub fn complete(&self, id: usize, output: Output) {
+        let now = self.clock.now();
+        self.operations.lock().unwrap()[id].output = Some((output, now));
+    }
+
+    pub fn history(&self) -> History {
+        History(self.operations.lock().unwrap().clone())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct History(pub Vec<Operation>);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub key: Key,
+    /// The most operations on `key` that could be put in a legal order.
+    pub linearized: usize,
+    pub operations: usize,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "history of key {:?} is not linearizable, only {} of {} operations could be ordered",
+            String::from_utf8_lossy(&self.key),
+            self.linearized,
+            self.operations
+        )
+    }
+}
+
+impl std::error::Error for Violation {}
+
+fn edn_string(bytes: &[u8]) -> String {
+    format!("{:?}", String::from_utf8_lossy(bytes))
+}
+
+impl History {
+    /// Writes the history as Jepsen EDN, one `:invoke` and one `:ok` (or
+    /// `:info`, for operations that never completed) map per line, in time
+    /// order.
+    pub fn write_edn(&self, out: &mut impl io::Write) -> io::Result<()> {
+        let mut events = Vec::new();
+        for (id, operation) in self.0.iter().enumerate() {
+            events.push((operation.call, id, None));
+            let end = operation.output.as_ref();
+            events.push((end.map_or(Duration::MAX, |(_, at)| *at), id, Some(end)));
+        }
+        events.sort_by_key(|(at, id, _)| (*at, *id));
+        for (at, id, end) in events {
+            let operation = &self.0[id];
+            let key = edn_string(&operation.key);
+            let (kind, at) = match end {
+                None => (":invoke", operation.call),
+                Some(Some(_)) => (":ok", at),
+                Some(None) => (":info", operation.call),
+            };
+            let (f, value) = match (&operation.input, end.flatten()) {
+                (Input::Read, Some((Output::Read(Some(val)), _))) => ("read", edn_string(val)),
+                (Input::Read, _) => ("read", "nil".to_string()),
+                (Input::Write(val), _) => ("write", edn_string(val)),
+                (Input::Delete, Some((Output::Delete(existed), _))) => {
+                    ("delete", existed.to_string())
+                }
+                (Input::Delete, _) => ("delete", "nil".to_string()),
+            };
+            writeln!(
+                out,
+                "{{:process {}, :type {}, :f :{}, :value [{} {}], :time {}}}",
+                operation.client,
+                kind,
+                f,
+                key,
+                value,
+                at.as_nanos()
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Checks that every key behaves like a single register starting out
+    /// empty. Keys are independent, so each is checked on its own, using a
+    /// Wing & Gong style search over the operations that could take effect
+    /// next.
+    pub fn check(&self) -> Result<(), Violation> {
+        let mut keys: HashMap<&Key, Vec<&Operation>> = HashMap::new();
+        for operation in &self.0 {
+            keys.entry(&operation.key).or_default().push(operation);
+        }
+        for (key, operations) in keys {
+            let mut search = Search {
+                operations: &operations,
+                done: vec![false; operations.len()],
+                seen: HashSet::new(),
+                deepest: 0,
+            };
+            if !search.run(None, 0) {
+                return Err(Violation {
+                    key: key.clone(),
+                    linearized: search.deepest,
+                    operations: operations.len(),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Applies `operation` to a register holding `state`, returning the new
+/// state if the operation's response is possible from it.
+fn step(state: &Option<Val>, operation: &Operation) -> Option<Option<Val>> {
+    let output = operation.output.as_ref().map(|(output, _)| output);
+    match (&operation.input, output) {
+        (Input::Read, Some(Output::Read(val))) => (val == state).then(|| state.clone()),
+        (Input::Read, None) => Some(state.clone()),
+        (Input::Write(val), None | Some(Output::Write)) => Some(Some(val.clone())),
+        (Input::Delete, None) => Some(None),
+        (Input::Delete, Some(Output::Delete(existed))) => {
+            (*existed == state.is_some()).then_some(None)
+        }
+        _ => None,
+    }
+}
+
+struct Search<'a> {
+    operations: &'a [&'a Operation],
+    done: Vec<bool>,
+    /// Combinations of linearized operations and register state already
+    /// known to lead nowhere.
+    seen: HashSet<(Vec<bool>, Option<Val>)>,
+    deepest: usize,
+}
+
+impl Search<'_> {
+    fn run(&mut self, state: Option<Val>, depth: usize) -> bool {
+        self.deepest = self.deepest.max(depth);
+        // Once everything that returned has been placed, the operations that
+        // never completed are free to never have taken effect.
+        let Some(first_return) = self
+            .operations
+            .iter()
+            .zip(&self.done)
+            .filter(|(_, done)| !**done)
+            .filter_map(|(operation, _)| operation.output.as_ref().map(|(_, at)| *at))
+            .min()
+        else {
+            return true;
+        };
+        if !self.seen.insert((self.done.clone(), state.clone())) {
+            return false;
+        }
+        // Only an operation invoked before the earliest outstanding response
+        // can be the next to take effect.
+        for i in 0..self.operations.len() {
+            let operation = self.operations[i];
+            if self.done[i] || operation.call > first_return {
+                continue;
+            }
+            if let Some(next) = step(&state, operation) {
+                self.done[i] = true;
+                if self.run(next, depth + 1) {
+                    return true;
+                }
+                self.done[i] = false;
+            }
+        }
+        false
+    }
+}
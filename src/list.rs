@@ -0,0 +1,65 @@
+//! Lists for `LPUSH`, `RPUSH`, `LPOP` and `BLPOP`, stored as ordinary values:
+//! the magic bytes `LIST` followed by each element as a 4-byte big-endian
+//! length and its bytes, head first.
+
+use std::collections::VecDeque;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+const MAGIC: &[u8; 4] = b"LIST";
+
+#[derive(Default)]
+pub struct List {
+    elements: VecDeque<Bytes>,
+}
+
+impl List {
+    /// Reads a list back from a value, or `None` if the value isn't one.
+    pub fn from_bytes(val: &[u8]) -> Option<List> {
+        let mut rest = val.strip_prefix(MAGIC)?;
+        let mut elements = VecDeque::new();
+        while !rest.is_empty() {
+            if rest.len() < 4 {
+                return None;
+            }
+            let len = rest.get_u32() as usize;
+            if rest.len() < len {
+                return None;
+            }
+            elements.push_back(Bytes::copy_from_slice(&rest[..len]));
+            rest.advance(len);
+        }
+        Some(List { elements })
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let len = self.elements.iter().map(|e| 4 + e.len()).sum::<usize>();
+        let mut buf = BytesMut::with_capacity(MAGIC.len() + len);
+        buf.put_slice(MAGIC);
+        for element in &self.elements {
+            buf.put_u32(element.len() as u32);
+            buf.put_slice(element);
+        }
+        buf.freeze()
+    }
+
+    pub fn push_front(&mut self, element: &[u8]) {
+        self.elements.push_front(Bytes::copy_from_slice(element));
+    }
+
+    pub fn push_back(&mut self, element: &[u8]) {
+        self.elements.push_back(Bytes::copy_from_slice(element));
+    }
+
+    pub fn pop_front(&mut self) -> Option<Bytes> {
+        self.elements.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.elements.is_empty()
+    }
+}
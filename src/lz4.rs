@@ -0,0 +1,397 @@
+//! The LZ4 frame format, as the replication stream is compressed with.
+//!
+//! [`FrameEncoder`] writes a frame a block at a time, each block compressed
+//! on its own so that it can be written out as soon as it is made, and
+//! [`FrameDecoder`] reads one back from however much of it has arrived. The
+//! decoder takes frames from other encoders too, as long as their blocks are
+//! independent and they name no dictionary; content checksums are skipped
+//! rather than checked.
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::error::{bail, Result};
+
+const MAGIC: u32 = 0x184d_2204;
+
+/// Version 01, independent blocks, no checksums.
+const FLG: u8 = 0b0110_0000;
+
+/// Blocks of up to 4MB.
+const BD: u8 = 7 << 4;
+
+const MAX_BLOCK_SIZE: usize = 4 * 1024 * 1024;
+
+/// Set in a block's size when its data is stored uncompressed.
+const UNCOMPRESSED: u32 = 1 << 31;
+
+const MIN_MATCH: usize = 4;
+
+/// A block ends with at least this many literals...
+const LAST_LITERALS: usize = 5;
+
+/// ...and its last match starts at least this far from the end.
+const MF_LIMIT: usize = 12;
+
+const HASH_LOG: u32 = 12;
+
+/// Writes an LZ4 frame, one block per call to [`FrameEncoder::encode`].
+#[derive(Debug, Default)]
+pub struct FrameEncoder {
+    started: bool,
+}
+
+impl FrameEncoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Appends `data` to `out` as the frame's next blocks, after the
+    /// frame's header if this is the first of them.
+    pub fn encode(&mut self, data: &[u8], out: &mut BytesMut) {
+        if !self.started {
+            out.put_u32_le(MAGIC);
+            out.put_u8(FLG);
+            out.put_u8(BD);
+            out.put_u8(header_checksum(&[FLG, BD]));
+            self.started = true;
+        }
+        for chunk in data.chunks(MAX_BLOCK_SIZE) {
+            let mut block = BytesMut::new();
+            compress_block(chunk, &mut block);
+            match block.len() < chunk.len() {
+                true => {
+                    out.put_u32_le(block.len() as u32);
+                    out.extend_from_slice(&block);
+                }
+                false => {
+                    out.put_u32_le(chunk.len() as u32 | UNCOMPRESSED);
+                    out.extend_from_slice(chunk);
+                }
+            }
+        }
+    }
+
+    /// Ends the frame. Nothing is written for a frame that never started.
+    pub fn finish(&mut self, out: &mut BytesMut) {
+        if self.started {
+            out.put_u32_le(0);
+            self.started = false;
+        }
+    }
+}
+
+/// Where a [`FrameDecoder`] is in its frame.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Header,
+    Blocks {
+        block_checksums: bool,
+        content_checksum: bool,
+        max_block_size: usize,
+    },
+    ContentChecksum,
+}
+
+/// Reads LZ4 frames, one after another, from a stream that arrives in
+/// pieces.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    state: State,
+}
+
+impl Default for FrameDecoder {
+    fn default() -> Self {
+        FrameDecoder {
+            state: State::Header,
+        }
+    }
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decompresses what `input` holds of the stream into `out`, leaving
+    /// any partial header or block in `input` for when the rest arrives.
+    pub fn decode(&mut self, input: &mut BytesMut, out: &mut BytesMut) -> Result<()> {
+        loop {
+            match self.state {
+                State::Header => {
+                    if input.len() < 7 {
+                        return Ok(());
+                    }
+                    if u32::from_le_bytes(input[..4].try_into().unwrap()) != MAGIC {
+                        bail!(Corrupt, "not an LZ4 frame");
+                    }
+                    let (flg, bd) = (input[4], input[5]);
+                    if flg >> 6 != 1 {
+                        bail!(Corrupt, "unsupported LZ4 frame version {}", flg >> 6);
+                    }
+                    if flg & 0b0010_0000 == 0 {
+                        bail!(
+                            Corrupt,
+                            "LZ4 frames with dependent blocks are not supported"
+                        );
+                    }
+                    if flg & 0b0000_0001 != 0 {
+                        bail!(Corrupt, "LZ4 frames with dictionaries are not supported");
+                    }
+                    let content_size = flg & 0b0000_1000 != 0;
+                    let len = if content_size { 15 } else { 7 };
+                    if input.len() < len {
+                        return Ok(());
+                    }
+                    if header_checksum(&input[4..len - 1]) != input[len - 1] {
+                        bail!(Corrupt, "LZ4 frame header checksum mismatch");
+                    }
+                    let max_block_size = match (bd >> 4) & 0b111 {
+                        4 => 64 * 1024,
+                        5 => 256 * 1024,
+                        6 => 1024 * 1024,
+                        7 => MAX_BLOCK_SIZE,
+                        size => bail!(Corrupt, "invalid LZ4 block size {}", size),
+                    };
+                    input.advance(len);
+                    self.state = State::Blocks {
+                        block_checksums: flg & 0b0001_0000 != 0,
+                        content_checksum: flg & 0b0000_0100 != 0,
+                        max_block_size,
+                    };
+                }
+                State::Blocks {
+                    block_checksums,
+                    content_checksum,
+                    max_block_size,
+                } => {
+                    if input.len() < 4 {
+                        return Ok(());
+                    }
+                    let size = u32::from_le_bytes(input[..4].try_into().unwrap());
+                    if size == 0 {
+                        input.advance(4);
+                        self.state = match content_checksum {
+                            true => State::ContentChecksum,
+                            false => State::Header,
+                        };
+                        continue;
+                    }
+                    let len = (size & !UNCOMPRESSED) as usize;
+                    if len > max_block_size {
+                        bail!(Corrupt, "LZ4 block of {} bytes is too large", len);
+                    }
+                    let checksum = if block_checksums { 4 } else { 0 };
+                    if input.len() < 4 + len + checksum {
+                        return Ok(());
+                    }
+                    input.advance(4);
+                    let block = input.split_to(len);
+                    if block_checksums {
+                        let expected = input.get_u32_le();
+                        if xxh32(&block, 0) != expected {
+                            bail!(Corrupt, "LZ4 block checksum mismatch");
+                        }
+                    }
+                    match size & UNCOMPRESSED != 0 {
+                        true => out.extend_from_slice(&block),
+                        false => decompress_block(&block, out, max_block_size)?,
+                    }
+                }
+                State::ContentChecksum => {
+                    if input.len() < 4 {
+                        return Ok(());
+                    }
+                    input.advance(4);
+                    self.state = State::Header;
+                }
+            }
+        }
+    }
+}
+
+/// Compresses `src` into `out` as a single LZ4 block.
+pub fn compress_block(src: &[u8], out: &mut BytesMut) {
+    let mut table = vec![0usize; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut pos = 0;
+    while pos + MF_LIMIT < src.len() {
+        let sequence = u32::from_le_bytes(src[pos..pos + 4].try_into().unwrap());
+        let hash = (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize;
+        let candidate = table[hash];
+        table[hash] = pos;
+        if candidate >= pos
+            || pos - candidate > u16::MAX as usize
+            || src[candidate..candidate + MIN_MATCH] != src[pos..pos + MIN_MATCH]
+        {
+            pos += 1;
+            continue;
+        }
+        let max = src.len() - LAST_LITERALS - pos;
+        let mut len = MIN_MATCH;
+        while len < max && src[candidate + len] == src[pos + len] {
+            len += 1;
+        }
+        write_sequence(out, &src[anchor..pos], Some((pos - candidate, len)));
+        pos += len;
+        anchor = pos;
+    }
+    write_sequence(out, &src[anchor..], None);
+}
+
+fn write_sequence(out: &mut BytesMut, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_len = matched.map_or(0, |(_, len)| len - MIN_MATCH);
+    let token = (literals.len().min(15) << 4) as u8 | match_len.min(15) as u8;
+    out.put_u8(token);
+    if literals.len() >= 15 {
+        write_length(out, literals.len() - 15);
+    }
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.put_u16_le(offset as u16);
+        if match_len >= 15 {
+            write_length(out, match_len - 15);
+        }
+    }
+}
+
+fn write_length(out: &mut BytesMut, mut len: usize) {
+    while len >= 255 {
+        out.put_u8(255);
+        len -= 255;
+    }
+    out.put_u8(len as u8);
+}
+
+/// Decompresses the LZ4 block `src` onto the end of `out`, refusing to
+/// make more than `max_len` bytes of it.
+pub fn decompress_block(src: &[u8], out: &mut BytesMut, max_len: usize) -> Result<()> {
+    let start = out.len();
+    let mut i = 0;
+    loop {
+        let Some(&token) = src.get(i) else {
+            bail!(Corrupt, "LZ4 block ends without its last literals");
+        };
+        i += 1;
+        let mut literals = (token >> 4) as usize;
+        if literals == 15 {
+            literals += read_length(src, &mut i)?;
+        }
+        let Some(copied) = src.get(i..i + literals) else {
+            bail!(Corrupt, "LZ4 block ends partway through its literals");
+        };
+        if out.len() - start + literals > max_len {
+            bail!(
+                Corrupt,
+                "LZ4 block decompresses to more than {} bytes",
+                max_len
+            );
+        }
+        out.extend_from_slice(copied);
+        i += literals;
+        if i == src.len() {
+            return Ok(());
+        }
+        let Some(offset) = src.get(i..i + 2) else {
+            bail!(Corrupt, "LZ4 block ends partway through a match");
+        };
+        let offset = u16::from_le_bytes([offset[0], offset[1]]) as usize;
+        i += 2;
+        if offset == 0 || offset > out.len() - start {
+            bail!(Corrupt, "LZ4 match offset {} is out of range", offset);
+        }
+        let mut len = (token & 15) as usize;
+        if len == 15 {
+            len += read_length(src, &mut i)?;
+        }
+        len += MIN_MATCH;
+        if out.len() - start + len > max_len {
+            bail!(
+                Corrupt,
+                "LZ4 block decompresses to more than {} bytes",
+                max_len
+            );
+        }
+        // The match may overlap the bytes it makes, so it is copied a byte
+        // at a time.
+        let from = out.len() - offset;
+        out.reserve(len);
+        for j in 0..len {
+            let b = out[from + j];
+            out.put_u8(b);
+        }
+    }
+}
+
+fn read_length(src: &[u8], i: &mut usize) -> Result<usize> {
+    let mut len = 0;
+    loop {
+        let Some(&b) = src.get(*i) else {
+            bail!(Corrupt, "LZ4 block ends partway through a length");
+        };
+        *i += 1;
+        len += b as usize;
+        if b != 255 {
+            return Ok(len);
+        }
+    }
+}
+
+fn header_checksum(descriptor: &[u8]) -> u8 {
+    (xxh32(descriptor, 0) >> 8) as u8
+}
+
+const PRIME1: u32 = 2_654_435_761;
+const PRIME2: u32 = 2_246_822_519;
+const PRIME3: u32 = 3_266_489_917;
+const PRIME4: u32 = 668_265_263;
+const PRIME5: u32 = 374_761_393;
+
+/// xxHash32, the checksum LZ4 frames use.
+pub fn xxh32(data: &[u8], seed: u32) -> u32 {
+    let read = |i: usize| u32::from_le_bytes(data[i..i + 4].try_into().unwrap());
+    let round = |acc: u32, input: u32| {
+        acc.wrapping_add(input.wrapping_mul(PRIME2))
+            .rotate_left(13)
+            .wrapping_mul(PRIME1)
+    };
+    let mut i = 0;
+    let mut hash = if data.len() >= 16 {
+        let mut v = [
+            seed.wrapping_add(PRIME1).wrapping_add(PRIME2),
+            seed.wrapping_add(PRIME2),
+            seed,
+            seed.wrapping_sub(PRIME1),
+        ];
+        while i + 16 <= data.len() {
+            for (lane, acc) in v.iter_mut().enumerate() {
+                *acc = round(*acc, read(i + lane * 4));
+            }
+            i += 16;
+        }
+        v[0].rotate_left(1)
+            .wrapping_add(v[1].rotate_left(7))
+            .wrapping_add(v[2].rotate_left(12))
+            .wrapping_add(v[3].rotate_left(18))
+    } else {
+        seed.wrapping_add(PRIME5)
+    };
+    hash = hash.wrapping_add(data.len() as u32);
+    while i + 4 <= data.len() {
+        hash = hash
+            .wrapping_add(read(i).wrapping_mul(PRIME3))
+            .rotate_left(17)
+            .wrapping_mul(PRIME4);
+        i += 4;
+    }
+    for &b in &data[i..] {
+        hash = hash
+            .wrapping_add((b as u32).wrapping_mul(PRIME5))
+            .rotate_left(11)
+            .wrapping_mul(PRIME1);
+    }
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(PRIME2);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(PRIME3);
+    hash ^ (hash >> 16)
+}
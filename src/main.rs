@@ -1,205 +1,224 @@
-use std::collections::HashMap;
-use std::fs::{File, OpenOptions};
-use std::io::{BufRead, Write};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use tokio::io::AsyncWriteExt;
-
-use anyhow::Result;
+use anyhow::{Context, Result};
 use tokio::net::{TcpListener, TcpStream};
 
-type Key = String;
-type Val = String;
+use dist_kv::archive::{self, open_store, Archiver, ARCHIVE_INTERVAL};
+use dist_kv::checkpoint;
+use dist_kv::clock::{Clock, SystemClock};
+use dist_kv::config::Config;
+use dist_kv::discovery::discover;
+use dist_kv::follower;
+use dist_kv::node::{FollowerCore, LeaderCore};
+use dist_kv::protocol::{Command, ParseError};
+use dist_kv::server::{self, Leader, Replica, SyncLeader};
+use dist_kv::store::{Db, LoadProgress, Response, Strictness};
+use dist_kv::trace;
+use dist_kv::transport::Tcp;
+use dist_kv::wal::{open_last_segment, open_namespace_logs, read_segments, LogFile};
+use dist_kv::webhook::Webhooks;
 
-type Db = HashMap<String, String>;
+use rustyline::error::ReadlineError;
+use rustyline::DefaultEditor;
 
-#[derive(Debug)]
-enum Command {
-    Get(Key),
-    Set(Key, Val),
-    Delete(Key),
-    Unknown,
-}
+use nix::unistd::{fork, ForkResult};
 
-impl From<String> for Command {
-    fn from(s: String) -> Self {
-        let mut split_s = s.split_whitespace().skip(1);
-        let key = split_s.next().expect("Expected a key").to_string();
-        if s.starts_with("SET") {
-            let val = split_s.next().expect("Expected a value").to_string();
-            Command::Set(key, val)
-        } else if s.starts_with("GET") {
-            Command::Get(key)
-        } else if s.starts_with("DEL") {
-            Command::Delete(key)
-        } else {
-            Command::Unknown
-        }
+/// Counts allocations for `INFO memory`, see [`dist_kv::allocator`].
+#[cfg(feature = "alloc-stats")]
+#[global_allocator]
+static ALLOCATOR: dist_kv::allocator::Counting<std::alloc::System> =
+    dist_kv::allocator::Counting::new(std::alloc::System);
+
+/// Exports the process's spans if the config names a collector.
+fn start_tracing(config: &Config, instance: &str) -> Result<()> {
+    match &config.otlp_endpoint {
+        Some(url) => Ok(trace::start_exporter(url, instance)?),
+        None => Ok(()),
     }
 }
 
-#[derive(Debug)]
-enum Response {
-    Get(Key, Val),
-    Set(Key, Val),
-    Replace(Key, Val, Val),
-    Delete(Key, Val),
-    KeyNotFound(Key),
-    Unknown,
+async fn setup_follower(config: Config) -> Result<()> {
+    start_tracing(&config, "follower")?;
+    let listener = TcpListener::bind("localhost:48000").await?;
+    let log = read_segments("follower.db")?;
+    let (mut hashmap, lsn) = load("follower.db", log, config.log_recovery, None).await?;
+    let (namespace_logs, namespace_lsn) = open_namespace_logs(
+        "follower.log",
+        &config.namespaces,
+        config.log_recovery,
+        &mut hashmap,
+    )?;
+    let log_file = open_last_segment("follower.log")?;
+    let mut follower = FollowerCore::new(hashmap, log_file, SystemClock::default());
+    follower.namespace_logs = namespace_logs;
+    follower.lsn = lsn.max(namespace_lsn);
+    follower.log_path = Some("follower.log".to_string());
+    let follower = Arc::new(Mutex::new(follower));
+    Ok(follower::serve(listener.into(), follower).await?)
 }
 
-use std::fmt;
-
-impl fmt::Display for Response {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Response::Get(key, val) => write!(f, "Key {}={}", key, val),
-            Response::Set(key, val) => write!(f, "Set {}={}", key, val),
-            Response::Replace(key, old_val, new_val) => {
-                write!(f, "Key {}={}, used to be {}", key, new_val, old_val)
-            }
-            Response::Delete(key, val) => write!(f, "Deleted key {} that was set to {}", key, val),
-            Response::KeyNotFound(key) => write!(f, "Key {} was not found.", key),
-            Response::Unknown => write!(f, "Unknown command"),
+/// Runs a follower of the leader, as given by `replicaof` or found through
+/// the seeds, that starts from the leader's snapshot rather than its own
+/// log.
+async fn setup_replica(config: &Config) -> Result<()> {
+    start_tracing(config, "follower")?;
+    let addr = match &config.replicaof {
+        Some(addr) => addr.clone(),
+        None => {
+            let peers = discover(&config.seeds).await?;
+            peers.leader.context("the seeds named no leader")?
         }
-    }
+    };
+    let (namespace_logs, _) = open_namespace_logs(
+        "follower.log",
+        &config.namespaces,
+        config.log_recovery,
+        &mut Db::default(),
+    )?;
+    let log_file = open_last_segment("follower.log")?;
+    let mut follower = FollowerCore::new(Db::default(), log_file, SystemClock::default());
+    follower.namespace_logs = namespace_logs;
+    follower.log_path = Some("follower.log".to_string());
+    let follower = Arc::new(Mutex::new(follower));
+    Ok(follower::bootstrap(&Tcp, &addr, &follower).await?)
 }
 
-pub fn create_log_file(path: &str) -> Result<File> {
-    Ok(OpenOptions::new().append(true).create(true).open(path)?)
+/// Replays `log`, the contents of the log at `name`, on a blocking thread
+/// from the log's checkpoint if it has one, logging progress as it goes and,
+/// for the leader, publishing it to INFO. Returns the map and the LSN to
+/// carry on from.
+async fn load(
+    name: &'static str,
+    log: Option<Vec<u8>>,
+    strictness: Strictness,
+    leader: Option<SyncLeader>,
+) -> Result<(Db, u64)> {
+    let Some(log) = log else {
+        return Ok((Db::default(), 0));
+    };
+    tokio::task::spawn_blocking(move || {
+        let start = Instant::now();
+        let (hashmap, report) = checkpoint::replay(name, &log, strictness, |progress| {
+            let elapsed = start.elapsed();
+            let remaining = progress.total_bytes - progress.bytes_read;
+            let eta = match progress.bytes_read {
+                0 => Duration::ZERO,
+                read => elapsed.mul_f64(remaining as f64 / read as f64),
+            };
+            eprintln!(
+                "Loading {}: {} records, {}/{} bytes, ETA {:.1?}",
+                name, progress.records, progress.bytes_read, progress.total_bytes, eta
+            );
+            if let Some(leader) = &leader {
+                leader.blocking_lock().core.loading = Some(*progress);
+            }
+        })?;
+        Ok((hashmap, report.lsn))
+    })
+    .await?
 }
 
-fn run_command(hashmap: &mut Db, command: &Command) -> Response {
-    match command {
-        Command::Get(key) => {
-            if hashmap.contains_key(key) {
-                Response::Get(key.to_string(), hashmap.get(key).unwrap().to_string())
-            } else {
-                Response::KeyNotFound(key.to_string())
-            }
-        }
-        Command::Set(key, val) => {
-            if hashmap.contains_key(key) {
-                let old_val = hashmap.get(key).unwrap().to_string();
-                let (key, val) = (key.to_string(), val.to_string());
-                hashmap.insert(key.clone(), val.clone());
-                Response::Replace(key, old_val, val)
-            } else {
-                let (key, val) = (key.to_string(), val.to_string());
-                hashmap.insert(key.to_string(), val.to_string());
-                Response::Set(key, val)
-            }
-        }
-        Command::Delete(key) => {
-            if hashmap.contains_key(key) {
-                let old_val = hashmap.get(key).unwrap().to_string();
-                hashmap.remove(key);
-                Response::Delete(key.to_string(), old_val)
-            } else {
-                Response::KeyNotFound(key.to_string())
+/// The follower is started alongside the leader, so give it a moment to
+/// start listening before giving up.
+async fn connect_follower(addr: &str) -> Result<TcpStream> {
+    let mut attempts = 0;
+    loop {
+        match TcpStream::connect(addr).await {
+            Ok(stream) => return Ok(stream),
+            Err(_) if attempts < 40 => {
+                attempts += 1;
+                tokio::time::sleep(Duration::from_millis(50)).await;
             }
+            Err(e) => return Err(e.into()),
         }
-        Command::Unknown => Response::Unknown,
     }
 }
 
-async fn persist_command(
-    file: &mut File,
-    hashmap: &mut Db,
-    stream: &mut TcpStream,
-    command: &Command,
-) -> Result<()> {
-    let response = run_command(hashmap, command);
-    match &response {
-        Response::Set(key, val) => {
-            let str_command = format!("SET {} {}\n", key, val);
-            file.write_all(str_command.as_bytes())?;
-            stream.write_all(str_command.as_bytes()).await?;
-        }
-        Response::Delete(key, _val) => {
-            let str_command = format!("DEL {}\n", key);
-            file.write_all(str_command.as_bytes())?;
-            stream.write_all(str_command.as_bytes()).await?;
-        }
-        _ => {}
+async fn setup_leader(config: Config, config_path: Option<String>) -> Result<()> {
+    start_tracing(&config, "leader")?;
+    let limits = config.limits();
+    let mut rl = DefaultEditor::new()?;
+    let stream = connect_follower("localhost:48000").await?;
+
+    let log = read_segments("leader.db")?;
+    let file = LogFile::open(open_last_segment("leader.log")?, config.wal_backend)?;
+    let mut core = LeaderCore::new(Db::default(), file, SystemClock::default());
+    core.loading = Some(LoadProgress {
+        total_bytes: log.as_ref().map_or(0, Vec::len),
+        ..LoadProgress::default()
+    });
+
+    // Clients can connect while the log is replayed, and are told to retry.
+    let mut leader = Leader::new(
+        core,
+        vec![Replica::new("localhost:48000", Some(stream.into()))],
+    );
+    leader.tenants = config.tenants.clone();
+    leader.functions = config.functions.clone();
+    leader.webhooks = Webhooks::start(config.triggers.clone());
+    leader.addr = Some("localhost:47000".to_string());
+    leader.config = config.clone();
+    leader.config_path = config_path.clone();
+    leader.log_path = Some("leader.log".to_string());
+    let leader = Arc::new(tokio::sync::Mutex::new(leader));
+    if let Some(path) = config_path {
+        server::reload_on_hangup(leader.clone(), path)?;
     }
-    file.sync_all()?;
-    println!("{}", response);
-    Ok(())
-}
+    server::dump_state_on_signal(leader.clone())?;
+    let listener = TcpListener::bind("localhost:47000").await?;
+    tokio::spawn(server::serve(listener.into(), leader.clone(), limits));
+
+    let (mut hashmap, lsn) =
+        load("leader.db", log, config.log_recovery, Some(leader.clone())).await?;
+    let (namespace_logs, namespace_lsn) = open_namespace_logs(
+        "leader.log",
+        &config.namespaces,
+        config.log_recovery,
+        &mut hashmap,
+    )?;
 
-use rustyline::error::ReadlineError;
-use rustyline::DefaultEditor;
+    dbg!(&hashmap);
 
-fn replay(file: File) -> Result<HashMap<String, String>> {
-    let mut hashmap = HashMap::default();
-    for line in std::io::BufReader::new(file).lines() {
-        let line = line?;
-        match Command::from(line) {
-            Command::Set(key, val) => {
-                hashmap.insert(key, val);
-            }
-            Command::Delete(key) => {
-                hashmap.remove(&key);
-            }
-            _ => {}
-        }
+    {
+        let mut leader = leader.lock().await;
+        leader.core.hashmap = hashmap;
+        let now = leader.core.clock.unix_time().as_millis() as u64;
+        leader.core.hashmap.touch_all(now);
+        leader.core.hashmap.set_interning(config.intern_values);
+        leader
+            .core
+            .hashmap
+            .set_max_value_size(Some(limits.max_value_size));
+        leader.core.namespace_logs = namespace_logs.map(LogFile::File);
+        leader.core.lsn = lsn.max(namespace_lsn);
+        leader.core.loading = None;
+        leader.restore_membership().await;
     }
-    Ok(hashmap)
-}
-
-use nix::unistd::{fork, ForkResult};
-mod follower;
-use follower::*;
-
-async fn setup_follower() -> Result<()> {
-    let listener = TcpListener::bind("localhost:48000").await?;
-    let mut hashmap = HashMap::default();
-    if let Ok(file) = OpenOptions::new().read(true).open("follower.db") {
-        hashmap = replay(file)?;
-    };
-    let log_file = create_log_file("follower.log")?;
-    let file = Arc::new(Mutex::new(log_file));
-    let hashmap = Arc::new(Mutex::new(hashmap));
-
-    loop {
-        let (mut socket, _addr) = listener.accept().await?;
-        let mut hashmap = hashmap.clone();
-        let mut file = file.clone();
-        tokio::spawn(async move {
-            if let Err(e) = handle_client(&mut socket, &mut file, &mut hashmap).await {
-                eprintln!("Error = {:?}", e);
-            }
-        });
+    if let Some(location) = &config.archive {
+        let archiver = Archiver::open(open_store(location)?, "leader.log")?;
+        tokio::spawn(archive::run(archiver, leader.clone(), ARCHIVE_INTERVAL));
     }
-}
-
-async fn setup_leader() -> Result<()> {
-    let mut rl = DefaultEditor::new()?;
-    let mut stream = TcpStream::connect("localhost:48000").await?;
-
-    let mut hashmap = HashMap::default();
-    if let Ok(file) = OpenOptions::new().read(true).open("leader.db") {
-        hashmap = replay(file)?;
-    };
-
-    let mut file = create_log_file("leader.log")?;
-
-    dbg!(&hashmap);
 
     loop {
         let readline = rl.readline(">> ");
         match readline {
-            Ok(line) => {
-                let command = Command::from(line);
-                persist_command(&mut file, &mut hashmap, &mut stream, &command).await?;
-            }
+            Ok(line) => match Command::parse(line.as_bytes())
+                .and_then(|command| limits.check(&command).map(|_| command))
+            {
+                Ok(command) => {
+                    let response = leader.lock().await.persist(&command).await?;
+                    println!("{}", response);
+                }
+                Err(ParseError::Empty) => {}
+                Err(err) => println!("{}", Response::Error(err.into())),
+            },
             Err(ReadlineError::Interrupted) | Err(ReadlineError::Eof) => {
-                stream.shutdown().await?;
+                leader.lock().await.shutdown().await?;
                 break;
             }
             Err(err) => {
-                stream.shutdown().await?;
+                leader.lock().await.shutdown().await?;
                 println!("Error: {:?}", err);
                 break;
             }
@@ -208,14 +227,29 @@ async fn setup_leader() -> Result<()> {
     Ok(())
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
+/// Each process builds its own runtime after the fork, since a runtime's
+/// worker threads don't survive into the child.
+fn runtime() -> Result<tokio::runtime::Runtime> {
+    Ok(tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?)
+}
+
+fn main() -> Result<()> {
+    let config_path = std::env::args().nth(1);
+    let config = match &config_path {
+        Some(path) => Config::load(path)?,
+        None => Config::default(),
+    };
+    if config.replicaof.is_some() || !config.seeds.is_empty() {
+        return runtime()?.block_on(setup_replica(&config));
+    }
     match unsafe { fork() } {
         Ok(ForkResult::Parent { .. }) => {}
         Ok(ForkResult::Child) => {
-            setup_follower().await?;
+            runtime()?.block_on(setup_follower(config.clone()))?;
         }
         Err(_) => println!("Fork failed"),
     }
-    setup_leader().await
+    runtime()?.block_on(setup_leader(config, config_path))
 }
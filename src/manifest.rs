@@ -0,0 +1,143 @@
+//! The manifest naming the files that make up a node's database: the
+//! checkpoint it starts from, if any, and the log segments replayed after
+//! it, in order.
+//!
+//! It is kept next to the node's log at `<log>.manifest`, one `name value`
+//! line per field:
+//!
+//! ```text
+//! generation 3
+//! checkpoint leader.log.checkpoint.3
+//! log leader.log
+//! ```
+//!
+//! Files are named relative to the log's directory. A node without a
+//! manifest has no checkpoint and a single segment, the log itself. The
+//! manifest is only ever replaced whole, by writing a temporary file,
+//! syncing it, renaming it over the old one and syncing the directory, so
+//! recovery sees either the old set of files or the new one and never a mix
+//! of the two. Files a crash left behind that the manifest doesn't name are
+//! ignored.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::error::{bail, Context, DistKvError, Result};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Manifest {
+    /// Bumped by every change, and used to name the files it brings in.
+    pub generation: u64,
+    pub checkpoint: Option<String>,
+    /// The log segments, oldest first. Writes are appended to the last.
+    pub segments: Vec<String>,
+}
+
+/// Where the manifest of the node whose log is at `log` is kept.
+pub fn manifest_path(log: &str) -> String {
+    format!("{}.manifest", log)
+}
+
+/// The path of a file the manifest of the log at `log` names.
+pub fn resolve(log: &str, name: &str) -> PathBuf {
+    Path::new(log).with_file_name(name)
+}
+
+/// The name of the log at `log` within its directory.
+pub fn file_name(log: &str) -> Result<String> {
+    let name = Path::new(log)
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| DistKvError::Config(format!("{} is not a file", log)))?;
+    Ok(name.to_string())
+}
+
+impl Manifest {
+    /// The manifest of the log at `log`, or that of a lone log if it has
+    /// none yet.
+    pub fn load(log: &str) -> Result<Manifest> {
+        let path = manifest_path(log);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(Manifest {
+                    generation: 0,
+                    checkpoint: None,
+                    segments: vec![file_name(log)?],
+                })
+            }
+            Err(e) => return Err(e).with_context(|| format!("reading {}", path)),
+        };
+        Self::parse(&contents).with_context(|| format!("reading {}", path))
+    }
+
+    pub fn parse(contents: &str) -> Result<Manifest> {
+        let mut manifest = Manifest {
+            generation: 0,
+            checkpoint: None,
+            segments: Vec::new(),
+        };
+        for line in contents.lines() {
+            let Some((name, value)) = line.split_once(' ') else {
+                bail!(Corrupt, "malformed manifest line {:?}", line);
+            };
+            match name {
+                "generation" => {
+                    manifest.generation = value
+                        .parse()
+                        .with_context(|| format!("invalid generation {}", value))?
+                }
+                "checkpoint" => manifest.checkpoint = Some(value.to_string()),
+                "log" => manifest.segments.push(value.to_string()),
+                _ => bail!(Corrupt, "unknown manifest field {}", name),
+            }
+        }
+        if manifest.segments.is_empty() {
+            bail!(Corrupt, "the manifest names no log");
+        }
+        Ok(manifest)
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = format!("generation {}\n", self.generation);
+        if let Some(checkpoint) = &self.checkpoint {
+            out.push_str(&format!("checkpoint {}\n", checkpoint));
+        }
+        for segment in &self.segments {
+            out.push_str(&format!("log {}\n", segment));
+        }
+        out
+    }
+
+    /// Replaces the manifest of the log at `log` with this one.
+    pub fn save(&self, log: &str) -> Result<()> {
+        replace_file(manifest_path(log), self.render().as_bytes())
+    }
+}
+
+/// Writes `data` to `path` in place of whatever is there, by way of a
+/// temporary file next to it that is synced and renamed over it, syncing the
+/// directory after. A crash leaves either the old contents or all of the new,
+/// never a truncated file that reads as if it were whole.
+pub fn replace_file(path: impl AsRef<Path>, data: &[u8]) -> Result<()> {
+    let path = path.as_ref();
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let mut file = File::create(&temp).with_context(|| format!("creating {:?}", temp))?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    fs::rename(&temp, path).with_context(|| format!("replacing {}", path.display()))?;
+    sync_dir(path)
+}
+
+/// Syncs the directory the file at `path` is in, making the files created in
+/// or renamed into it durable.
+pub fn sync_dir(path: impl AsRef<Path>) -> Result<()> {
+    let dir = match path.as_ref().parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)?.sync_all()?;
+    Ok(())
+}
@@ -0,0 +1,212 @@
+use std::collections::{BTreeMap, VecDeque};
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Linear sub-buckets per power of two. Values below this are recorded
+/// exactly, larger ones to within 1/16th (about 6%).
+const SUB_BUCKETS: u64 = 16;
+const SUB_BUCKET_BITS: u64 = SUB_BUCKETS.trailing_zeros() as u64;
+const BUCKETS: usize = ((64 - SUB_BUCKET_BITS + 1) * SUB_BUCKETS) as usize;
+
+/// An HDR-style log-linear histogram of microsecond latencies with a fixed
+/// memory footprint and bounded relative error.
+#[derive(Clone)]
+pub struct Histogram {
+    counts: Box<[u64]>,
+    count: u64,
+    sum: u64,
+    max: u64,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            counts: vec![0; BUCKETS].into_boxed_slice(),
+            count: 0,
+            sum: 0,
+            max: 0,
+        }
+    }
+}
+
+fn bucket_index(value: u64) -> usize {
+    if value < SUB_BUCKETS {
+        return value as usize;
+    }
+    let exp = 63 - value.leading_zeros() as u64;
+    let sub = (value >> (exp - SUB_BUCKET_BITS)) - SUB_BUCKETS;
+    ((exp - SUB_BUCKET_BITS + 1) * SUB_BUCKETS + sub) as usize
+}
+
+/// The largest value that lands in bucket `index`.
+fn bucket_value(index: usize) -> u64 {
+    let index = index as u64;
+    if index < SUB_BUCKETS {
+        return index;
+    }
+    let exp = index / SUB_BUCKETS + SUB_BUCKET_BITS - 1;
+    let sub = index % SUB_BUCKETS;
+    let shift = exp - SUB_BUCKET_BITS;
+    ((SUB_BUCKETS + sub) << shift) + ((1 << shift) - 1)
+}
+
+impl Histogram {
+    pub fn record(&mut self, micros: u64) {
+        self.counts[bucket_index(micros)] += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(micros);
+        self.max = self.max.max(micros);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum as f64 / self.count as f64
+    }
+
+    /// The value at percentile `p` (0-100), accurate to the bucket width.
+    pub fn percentile(&self, p: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((p / 100.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (index, count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                return bucket_value(index).min(self.max);
+            }
+        }
+        self.max
+    }
+}
+
+/// How often a command ran, how often it failed and how long it took.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CommandStats {
+    pub calls: u64,
+    pub errors: u64,
+    pub total_usec: u64,
+    pub max_usec: u64,
+}
+
+/// How many of the slowest recent commands are kept.
+pub const SLOW_QUERIES: usize = 128;
+
+/// A command that took longer than `slow_query_ms`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SlowQuery {
+    /// When it finished, in milliseconds since the Unix epoch.
+    pub at: u64,
+    pub micros: u64,
+    pub name: &'static str,
+    /// The first key it named, if any.
+    pub key: Option<String>,
+}
+
+/// Latency histograms keyed by command name, plus the `fsync` and
+/// `replication` stages of a write, each command's counters and the last
+/// [`SLOW_QUERIES`] slow commands.
+#[derive(Default)]
+pub struct Metrics {
+    latencies: Mutex<BTreeMap<&'static str, Histogram>>,
+    commands: Mutex<BTreeMap<&'static str, CommandStats>>,
+    slow: Mutex<VecDeque<SlowQuery>>,
+}
+
+impl Metrics {
+    pub fn record(&self, name: &'static str, elapsed: Duration) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let mut latencies = self.latencies.lock().unwrap();
+        latencies.entry(name).or_default().record(micros);
+    }
+
+    /// Counts a call of the command `name` that took `elapsed`, and whether
+    /// it was answered with an error.
+    pub fn record_call(&self, name: &'static str, elapsed: Duration, failed: bool) {
+        let micros = elapsed.as_micros().min(u64::MAX as u128) as u64;
+        let mut commands = self.commands.lock().unwrap();
+        let stats = commands.entry(name).or_default();
+        stats.calls += 1;
+        stats.errors += u64::from(failed);
+        stats.total_usec = stats.total_usec.saturating_add(micros);
+        stats.max_usec = stats.max_usec.max(micros);
+    }
+
+    pub fn record_slow(&self, query: SlowQuery) {
+        let mut slow = self.slow.lock().unwrap();
+        if slow.len() == SLOW_QUERIES {
+            slow.pop_front();
+        }
+        slow.push_back(query);
+    }
+
+    /// The slow commands kept, newest first.
+    pub fn slow_queries(&self) -> Vec<SlowQuery> {
+        self.slow.lock().unwrap().iter().rev().cloned().collect()
+    }
+
+    /// How many commands have been run, of every kind.
+    pub fn calls(&self) -> u64 {
+        let commands = self.commands.lock().unwrap();
+        commands.values().map(|stats| stats.calls).sum()
+    }
+
+    /// Every command's counters, by name.
+    pub fn all_command_stats(&self) -> BTreeMap<&'static str, CommandStats> {
+        self.commands.lock().unwrap().clone()
+    }
+
+    pub fn command_stats(&self, name: &str) -> Option<CommandStats> {
+        self.commands.lock().unwrap().get(name).copied()
+    }
+
+    pub fn histogram(&self, name: &str) -> Option<Histogram> {
+        self.latencies.lock().unwrap().get(name).cloned()
+    }
+
+    /// Appends the `# Latency` INFO section, one line per histogram.
+    pub fn render(&self, out: &mut String) {
+        out.push_str("# Latency\n");
+        for (name, histogram) in self.latencies.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "latency_{}:calls={},mean_usec={:.1},p50_usec={},p99_usec={},p999_usec={},max_usec={}",
+                name.to_ascii_lowercase(),
+                histogram.count(),
+                histogram.mean(),
+                histogram.percentile(50.0),
+                histogram.percentile(99.0),
+                histogram.percentile(99.9),
+                histogram.max(),
+            );
+        }
+    }
+
+    /// Appends the `# Commandstats` INFO section, one line per command.
+    pub fn render_commands(&self, out: &mut String) {
+        out.push_str("# Commandstats\n");
+        for (name, stats) in self.commands.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "cmdstat_{}:calls={},usec={},usec_per_call={:.2},max_usec={},errors={}",
+                name.to_ascii_lowercase(),
+                stats.calls,
+                stats.total_usec,
+                stats.total_usec as f64 / stats.calls as f64,
+                stats.max_usec,
+                stats.errors,
+            );
+        }
+    }
+}
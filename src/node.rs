@@ -0,0 +1,708 @@
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+
+use crate::clock::Clock;
+use crate::discovery::Peers;
+use crate::error::{DistKvError, Result};
+use crate::failpoint::{self, Action};
+use crate::history::{touched_keys, History};
+use crate::metrics::Metrics;
+use crate::protocol::{
+    parse_all, split_frame, ClusterCommand, Command, Condition, Consistency, ErrorCode, ErrorReply,
+    Expiry, Limits, ObjectField, ParseError, SetOptions, FEATURES, PROTOCOL_VERSION,
+};
+use crate::pubsub::push;
+use crate::snapshot;
+use crate::store::{
+    apply, encoding, in_namespace, is_record, records, run_command, ttl, Access, Db, Key,
+    LoadProgress, Response, Val, DEFAULT_NAMESPACE,
+};
+use crate::stream::Stream;
+use crate::trace::Span;
+use crate::wal::{NamespaceLogs, Storage};
+
+/// The ID of the request that made `record`, if it names one.
+pub fn request_id(record: &[u8]) -> Option<String> {
+    let commands = parse_all(record, &Limits::NONE).ok()?;
+    let id = request_marker(commands.first()?)?;
+    Some(String::from_utf8_lossy(id).into_owned())
+}
+
+/// The node and LSN a request ID names, as [`LeaderCore::execute`] makes
+/// them: `<node>-<lsn>`.
+pub fn parse_request_id(id: &[u8]) -> Option<(&[u8], u64)> {
+    let dash = id.iter().rposition(|b| *b == b'-')?;
+    let lsn = std::str::from_utf8(&id[dash + 1..]).ok()?.parse().ok()?;
+    Some((&id[..dash], lsn))
+}
+
+/// The ID a `REQID` record names, in whatever namespace it was logged.
+pub fn request_marker<'c>(command: &'c Command<'_>) -> Option<&'c [u8]> {
+    match command {
+        Command::In(_, command) => request_marker(command),
+        Command::RequestId(id) => Some(id),
+        _ => None,
+    }
+}
+
+/// The namespace and key a `GETVERSION` asks after, in whatever namespace
+/// it was sent, and the lease it asks for.
+fn version_request<'c>(command: &'c Command<'_>) -> Option<(&'c [u8], &'c [u8], Option<u64>)> {
+    match command {
+        Command::GetVersion(key, lease) => Some((DEFAULT_NAMESPACE, key, *lease)),
+        Command::In(name, command) => match &**command {
+            Command::GetVersion(key, lease) => Some((name, key, *lease)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// A random id that tells nodes apart, fresh for every process.
+pub fn new_node_id() -> String {
+    format!("{:016x}", RandomState::new().build_hasher().finish())
+}
+
+/// Answers `HELLO`, settling on the lower of the two protocol versions and on
+/// the requested features among those `supported`.
+pub fn hello(
+    role: &str,
+    node_id: &str,
+    version: u32,
+    features: Option<&[u8]>,
+    supported: &[&'static str],
+) -> Response {
+    if version == 0 {
+        let err = ErrorReply::new(ErrorCode::NoProto, "unsupported protocol version 0");
+        return Response::Error(err);
+    }
+    let requested = features.unwrap_or_default().split(|b| *b == b',');
+    let agreed: Vec<&str> = requested
+        .filter_map(|wanted| {
+            supported
+                .iter()
+                .find(|feature| feature.as_bytes().eq_ignore_ascii_case(wanted))
+        })
+        .copied()
+        .collect();
+    Response::Info(format!(
+        "version:{}\nrole:{}\nnode_id:{}\nfeatures:{}\n",
+        version.min(PROTOCOL_VERSION),
+        role,
+        node_id,
+        agreed.join(",")
+    ))
+}
+
+/// Whether the features a `HELLO` asked for include `feature`.
+pub fn wants_feature(features: Option<&[u8]>, feature: &str) -> bool {
+    features.is_some_and(|features| {
+        features
+            .split(|b| *b == b',')
+            .any(|wanted| wanted.eq_ignore_ascii_case(feature.as_bytes()))
+    })
+}
+
+/// Answers `WHOAMI` with the node's role, id and the leader's address, as
+/// `name:value` lines. The address is empty while a follower doesn't know
+/// it.
+pub fn whoami(role: &str, node_id: &str, leader: Option<&str>) -> Response {
+    Response::Info(format!(
+        "role:{}\nnode_id:{}\nleader:{}\n",
+        role,
+        node_id,
+        leader.unwrap_or_default()
+    ))
+}
+
+/// Answers `WHOISLEADER` with the leader's address, or nil if it isn't
+/// known.
+pub fn who_is_leader(leader: Option<&str>) -> Response {
+    match leader {
+        Some(leader) => Response::Info(leader.to_string()),
+        None => Response::KeyNotFound(Key::new()),
+    }
+}
+
+/// Applies `command` as [`apply`] does, also answering `TTL` and `OBJECT`,
+/// turning relative TTLs into times and noting the keys it uses, which need
+/// the time it is `now`.
+fn apply_at(
+    hashmap: &mut Db,
+    command: &Command<'_>,
+    now: u64,
+    lsn: u64,
+) -> (Response, Option<BytesMut>) {
+    let applied = match command {
+        Command::Ttl(key) => (ttl(hashmap, key, now), None),
+        // Looking at a key isn't using it, so this doesn't touch it.
+        Command::Object(field, key) => return (object(hashmap, *field, key, now), None),
+        Command::SetWith(key, val, options) => {
+            let mut options = *options;
+            options.expiry = options.expiry.map(|expiry| Expiry::At(expiry.at(now)));
+            apply(
+                hashmap,
+                &Command::SetWith(key[..].into(), val[..].into(), options),
+            )
+        }
+        // The lock is logged as a SET of its token, which is the LSN the
+        // write will be logged at.
+        Command::Lock(key, ttl) => {
+            let options = SetOptions {
+                condition: Some(Condition::Absent),
+                expiry: Some(Expiry::At(now.saturating_add(*ttl))),
+                ..SetOptions::default()
+            };
+            let token = lsn.to_string();
+            let set = Command::SetWith(key[..].into(), token.as_bytes().into(), options);
+            match apply(hashmap, &set) {
+                (Response::Set(..), record) => (Response::Integer(lsn as i64), record),
+                (response, record) => (response, record),
+            }
+        }
+        // IDs come from the leader's clock, which `run_command` doesn't have.
+        Command::XAdd(key, None, data) => {
+            let stream = hashmap
+                .get(&key[..])
+                .and_then(|val| Stream::from_bytes(val));
+            let id = stream.unwrap_or_default().next_id(now);
+            apply(
+                hashmap,
+                &Command::XAdd(key[..].into(), Some(id), data[..].into()),
+            )
+        }
+        Command::In(name, command) => {
+            let (response, record) = apply_at(hashmap.namespace_mut(name), command, now, lsn);
+            hashmap.drop_if_empty(name);
+            return (response, record.map(|record| in_namespace(name, &record)));
+        }
+        command => apply(hashmap, command),
+    };
+    for key in command.keys() {
+        hashmap.touch(key, now);
+    }
+    applied
+}
+
+/// Answers `OBJECT` for `key` at `now`, or nil if it doesn't exist.
+fn object(hashmap: &Db, field: ObjectField, key: &[u8], now: u64) -> Response {
+    let Some(val) = hashmap.get(key) else {
+        return Response::KeyNotFound(Key::copy_from_slice(key));
+    };
+    let access = hashmap.access(key).unwrap_or(Access::new(now));
+    match field {
+        ObjectField::Encoding => Response::Info(encoding(val).to_string()),
+        ObjectField::IdleTime => Response::Integer(access.idle(now) as i64),
+        ObjectField::Freq => Response::Integer(access.freq(now).into()),
+    }
+}
+
+/// A key's value and when it expires, if it has a TTL.
+type Entry = (Val, Option<u64>);
+
+/// What a write is about to change in the map, so that it can be put back
+/// if the write can't be logged: each key it touches, in the namespace it
+/// is in, with what it held, `None` if it didn't exist, and the node a
+/// `CLUSTER` command adds or removes, with what the map knew of it.
+#[derive(Debug, Default)]
+struct Undo {
+    keys: Vec<(Bytes, Key, Option<Entry>)>,
+    member: Option<(String, Option<bool>)>,
+}
+
+impl Undo {
+    /// Notes what the keys `command` writes hold in `hashmap`, before it
+    /// runs.
+    fn note_command(&mut self, hashmap: &Db, command: &Command<'_>) {
+        if let Command::Cluster(ClusterCommand::AddNode(addr) | ClusterCommand::RemoveNode(addr)) =
+            command
+        {
+            let addr = String::from_utf8_lossy(addr).into_owned();
+            self.member = Some((addr.clone(), hashmap.member(&addr)));
+        }
+        let mut touched = Vec::new();
+        touched_keys(DEFAULT_NAMESPACE, command, &mut touched);
+        for (namespace, key) in touched {
+            let db = hashmap.namespace(namespace);
+            let previous = db.and_then(|db| Some((db.get(key)?.clone(), db.expiry(key))));
+            self.note(namespace, key, previous);
+        }
+    }
+
+    fn note(&mut self, namespace: &[u8], key: &[u8], previous: Option<Entry>) {
+        self.keys.push((
+            Bytes::copy_from_slice(namespace),
+            Key::copy_from_slice(key),
+            previous,
+        ));
+    }
+
+    /// Puts back what was noted, newest first, so that the value a key had
+    /// before the earliest change wins.
+    fn undo(self, hashmap: &mut Db) {
+        if let Some((addr, member)) = self.member {
+            hashmap.set_member(&addr, member);
+        }
+        for (namespace, key, previous) in self.keys.into_iter().rev() {
+            let db = hashmap.namespace_mut(&namespace);
+            match previous {
+                Some((val, expiry)) => {
+                    db.insert(key.clone(), val);
+                    if let Some(at) = expiry {
+                        db.expire_at(&key, at);
+                    }
+                }
+                None => {
+                    db.remove(&key);
+                }
+            }
+            hashmap.drop_if_empty(&namespace);
+        }
+    }
+}
+
+/// The leader's state machine: the map, its log and its metrics. It does no
+/// networking itself; `execute` hands back the record to replicate so the
+/// caller can ship it over whatever transport it uses.
+pub struct LeaderCore<S, C> {
+    pub hashmap: Db,
+    pub wal: S,
+    /// Where namespaces that aren't logged to `wal` are logged, if anywhere.
+    pub namespace_logs: NamespaceLogs<S>,
+    pub clock: C,
+    pub metrics: Metrics,
+    /// Set while the map is still being replayed from the log, during which
+    /// commands are refused with a `LOADING` error.
+    pub loading: Option<LoadProgress>,
+    /// Why writes are refused with a `READONLY` error, while they are.
+    pub read_only: Option<String>,
+    /// Log sequence number: how many records this leader has written to its
+    /// log.
+    pub lsn: u64,
+    pub node_id: String,
+    /// The ID of the request whose records [`LeaderCore::execute`] last
+    /// logged, if its command wrote any.
+    pub request_id: Option<String>,
+    /// What the last writes changed, for `GET key AT lsn`.
+    pub history: History,
+}
+
+impl<S: Storage, C: Clock> LeaderCore<S, C> {
+    pub fn new(mut hashmap: Db, wal: S, clock: C) -> Self {
+        hashmap.touch_all(clock.unix_time().as_millis() as u64);
+        LeaderCore {
+            hashmap,
+            wal,
+            namespace_logs: NamespaceLogs::default(),
+            clock,
+            metrics: Metrics::default(),
+            loading: None,
+            read_only: None,
+            lsn: 0,
+            node_id: new_node_id(),
+            request_id: None,
+            history: History::default(),
+        }
+    }
+
+    /// Applies `command` and, if it changed the map, appends it to the log and
+    /// syncs it before returning. The returned record is what followers need
+    /// to apply the same change, led by a `REQID` naming the request: the
+    /// node id and the LSN it was logged as. A write that can't be logged
+    /// and synced is undone, leaving the map as it was, and fails.
+    pub fn execute(&mut self, command: &Command<'_>) -> Result<(Response, Option<BytesMut>)> {
+        if self.loading.is_some() {
+            let err = ErrorReply::new(ErrorCode::Loading, "the dataset is still being loaded");
+            return Ok((Response::Error(err), None));
+        }
+        if let Some(reason) = self.read_only.as_ref().filter(|_| command.is_write()) {
+            let err = ErrorReply::new(
+                ErrorCode::ReadOnly,
+                format!("writes are refused: {}", reason),
+            );
+            return Ok((Response::Error(err), None));
+        }
+        if let Some(response) = self.history.answer(&self.hashmap, command, self.lsn) {
+            return Ok((response, None));
+        }
+        self.history.note_command(&self.hashmap, command);
+        let now = self.clock.unix_time().as_millis() as u64;
+        let mut undo = Undo::default();
+        let mut records = self.remove_expired(now, &mut undo);
+        undo.note_command(&self.hashmap, command);
+        let (response, record) = apply_at(&mut self.hashmap, command, now, self.lsn + 1);
+        records.extend_from_slice(&record.unwrap_or_default());
+        // An expiry time that has already passed deletes the key at once.
+        records.extend_from_slice(&self.remove_expired(now, &mut undo));
+        self.request_id = None;
+        let mut logged = None;
+        if !records.is_empty() {
+            let id = format!("{}-{}", self.node_id, self.lsn + 1);
+            // Logged where the write is, so a namespace's own log has it.
+            let mut marker = Command::RequestId(id.as_bytes().into());
+            if let Command::In(name, _) = command {
+                marker = Command::In(name[..].into(), Box::new(marker));
+            }
+            let mut traced = BytesMut::new();
+            marker.encode(&mut traced);
+            traced.extend_from_slice(&records);
+            let mut span = Span::start("wal.append");
+            span.attr("request.id", &id);
+            span.attr("bytes", traced.len());
+            let appended = self
+                .namespace_logs
+                .append(&mut self.wal, &traced)
+                .map_err(|err| {
+                    DistKvError::storage(err).context(format!("logging request {}", id))
+                });
+            drop(span);
+            if let Err(err) = appended {
+                self.roll_back(undo, now);
+                return Err(err);
+            }
+            logged = Some((id, traced));
+        }
+        let start = self.clock.now();
+        let span = Span::start("wal.sync");
+        let synced = self.namespace_logs.sync(&mut self.wal);
+        drop(span);
+        if let Err(err) = synced {
+            self.roll_back(undo, now);
+            return Err(DistKvError::storage(err).context("syncing the log"));
+        }
+        self.metrics.record("fsync", self.clock.now() - start);
+        let record = logged.map(|(id, record)| {
+            self.lsn += 1;
+            self.request_id = Some(id);
+            record
+        });
+        self.history.commit(record.as_ref().map(|_| self.lsn), now);
+        if let Some(Action::Crash) = failpoint::eval("leader::after_sync") {
+            failpoint::crash();
+        }
+        Ok((response, record))
+    }
+
+    /// Puts the map back as it was before a write that couldn't be logged,
+    /// so that the leader doesn't serve, or go on to replicate on top of, a
+    /// change that neither its log nor its followers have.
+    fn roll_back(&mut self, undo: Undo, now: u64) {
+        undo.undo(&mut self.hashmap);
+        self.history.commit(None, now);
+    }
+
+    /// Deletes the keys whose TTL is up without waiting for a command, as
+    /// [`LeaderCore::execute`] would, returning the records to replicate.
+    pub fn expire(&mut self) -> Result<Option<BytesMut>> {
+        if self.loading.is_some() {
+            return Ok(None);
+        }
+        let now = self.clock.unix_time().as_millis() as u64;
+        let mut undo = Undo::default();
+        let records = self.remove_expired(now, &mut undo);
+        if records.is_empty() {
+            return Ok(None);
+        }
+        let logged = self
+            .namespace_logs
+            .append(&mut self.wal, &records)
+            .and_then(|()| self.namespace_logs.sync(&mut self.wal));
+        if let Err(err) = logged {
+            self.roll_back(undo, now);
+            return Err(err.into());
+        }
+        self.lsn += 1;
+        self.history.commit(Some(self.lsn), now);
+        Ok(Some(records))
+    }
+
+    /// Removes the keys whose TTL is up at `now` and returns a `DEL` record
+    /// for each, so that replay and followers drop them too. Each is noted
+    /// in `undo` as expiring at `now`, which is no earlier than it did, so
+    /// that one put back goes again with the next write.
+    fn remove_expired(&mut self, now: u64, undo: &mut Undo) -> BytesMut {
+        let mut records = BytesMut::new();
+        for (key, val) in self.hashmap.remove_expired(now) {
+            undo.note(DEFAULT_NAMESPACE, &key, Some((val.clone(), Some(now))));
+            self.history.note(DEFAULT_NAMESPACE, &key, Some(val));
+            Command::Delete(key[..].into()).encode(&mut records);
+        }
+        let names: Vec<_> = self
+            .hashmap
+            .namespaces()
+            .map(|(name, _)| name.clone())
+            .collect();
+        for name in names {
+            for (key, val) in self.hashmap.namespace_mut(&name).remove_expired(now) {
+                undo.note(&name, &key, Some((val.clone(), Some(now))));
+                self.history.note(&name, &key, Some(val));
+                let delete = Command::Delete(key[..].into());
+                Command::In(name[..].into(), Box::new(delete)).encode(&mut records);
+            }
+            self.hashmap.drop_if_empty(&name);
+        }
+        records
+    }
+
+    pub fn info(&self, section: Option<&[u8]>) -> String {
+        let mut info = String::new();
+        let wants = |name: &str| {
+            section.is_none_or(|section| section.eq_ignore_ascii_case(name.as_bytes()))
+        };
+        if wants("keyspace") {
+            info.push_str("# Keyspace\n");
+            info.push_str(&format!("keys:{}\n", self.hashmap.len()));
+            info.push_str(&format!("expires:{}\n", self.hashmap.expiring()));
+            for (name, namespace) in self.hashmap.namespaces() {
+                info.push_str(&format!(
+                    "ns_{}:keys={},expires={}\n",
+                    String::from_utf8_lossy(name),
+                    namespace.len(),
+                    namespace.expiring()
+                ));
+            }
+        }
+        if wants("persistence") {
+            let loading = self.loading.unwrap_or_default();
+            info.push_str("# Persistence\n");
+            info.push_str(&format!("loading:{}\n", u8::from(self.loading.is_some())));
+            info.push_str(&format!("loading_loaded_records:{}\n", loading.records));
+            info.push_str(&format!("loading_loaded_bytes:{}\n", loading.bytes_read));
+            info.push_str(&format!("loading_total_bytes:{}\n", loading.total_bytes));
+            info.push_str(&format!(
+                "read_only:{}\n",
+                u8::from(self.read_only.is_some())
+            ));
+        }
+        if wants("latency") {
+            self.metrics.render(&mut info);
+        }
+        if wants("commandstats") {
+            self.metrics.render_commands(&mut info);
+        }
+        info
+    }
+}
+
+/// The follower's state machine, fed the raw replication stream.
+pub struct FollowerCore<S, C> {
+    pub hashmap: Db,
+    pub wal: S,
+    pub clock: C,
+    pub namespace_logs: NamespaceLogs<S>,
+    pub node_id: String,
+    /// The address clients reach the leader at, as it last gave it.
+    pub leader: Option<String>,
+    /// The request the records being applied were made by, as the last
+    /// `REQID` named it.
+    pub request_id: Option<String>,
+    /// The LSN of the last write applied, from the request ID the leader
+    /// logs with each write or the snapshot it sent. 0 until the first
+    /// since the follower started.
+    pub lsn: u64,
+    /// The leader the follower last promised, by answering a `GETVERSION`
+    /// with `LEASE`, to take no other leader's stream from, and until when,
+    /// by `clock`.
+    /// The leader counts its lease from before it asked, less the clock
+    /// drift it allows for, so it runs out before the follower's does.
+    pub lease: Option<(Option<String>, Duration)>,
+    /// Where the follower answers reads, if it does, which it names when it
+    /// syncs so that the leader can count it in quorum reads.
+    pub addr: Option<String>,
+    /// The path of the log `wal` appends to, which a snapshot from the
+    /// leader replaces whole, see [`crate::follower`]. Without one the log
+    /// is reset and the snapshot appended to it.
+    pub log_path: Option<String>,
+}
+
+impl<S: Storage, C: Clock> FollowerCore<S, C> {
+    pub fn new(hashmap: Db, wal: S, clock: C) -> Self {
+        FollowerCore {
+            hashmap,
+            wal,
+            clock,
+            namespace_logs: NamespaceLogs::default(),
+            node_id: new_node_id(),
+            leader: None,
+            request_id: None,
+            lsn: 0,
+            lease: None,
+            addr: None,
+            log_path: None,
+        }
+    }
+
+    /// Answers the requests a client sent in `buf`, up to a `REPLICATE` that
+    /// turns the connection into the leader's replication stream. Reads are
+    /// answered from the follower's map, and writes with a `MOVED` error
+    /// naming the leader, so that only the leader changes the map, as are
+    /// reads that want the leader's answer. Returns
+    /// the replies and whether the connection is now replicating, with the
+    /// rest of the stream left in `buf`. `lz4` is set once `HELLO` agrees to
+    /// it, for the stream to be decompressed.
+    pub fn answer(&mut self, buf: &mut BytesMut, lz4: &mut bool) -> Result<(BytesMut, bool)> {
+        let mut replies = BytesMut::new();
+        while let Some(frame) = split_frame(buf, &Limits::NONE)? {
+            let response = match frame.command(&Limits::NONE) {
+                Ok(Command::Replicate(leader)) => {
+                    let leader = leader.map(|addr| String::from_utf8_lossy(&addr).into_owned());
+                    let now = self.clock.now();
+                    match &self.lease {
+                        Some((holder, until)) if *holder != leader && *until > now => {
+                            let left = until.saturating_sub(now);
+                            Response::Error(ErrorReply::new(
+                                ErrorCode::Leased,
+                                format!(
+                                    "leased to {} for another {}ms",
+                                    holder.as_deref().unwrap_or("unknown"),
+                                    left.as_millis()
+                                ),
+                            ))
+                        }
+                        _ => {
+                            self.leader = leader;
+                            return Ok((replies, true));
+                        }
+                    }
+                }
+                Ok(Command::Hello(version, features)) => {
+                    *lz4 = wants_feature(features.as_deref(), "lz4");
+                    hello(
+                        "follower",
+                        &self.node_id,
+                        version,
+                        features.as_deref(),
+                        FEATURES,
+                    )
+                }
+                Ok(Command::WhoAmI) => whoami("follower", &self.node_id, self.leader.as_deref()),
+                Ok(Command::WhoIsLeader) => who_is_leader(self.leader.as_deref()),
+                Ok(Command::Peers) => {
+                    let peers = Peers {
+                        leader: self.leader.clone(),
+                        followers: Vec::new(),
+                    };
+                    Response::Info(peers.render())
+                }
+                Ok(Command::GetWith(_, Consistency::Leader | Consistency::Quorum)) => {
+                    let leader = self.leader.as_deref().unwrap_or("unknown");
+                    Response::Error(ErrorReply::new(ErrorCode::Moved, leader))
+                }
+                Ok(command) if command.is_write() || matches!(command, Command::Cluster(_)) => {
+                    let leader = self.leader.as_deref().unwrap_or("unknown");
+                    Response::Error(ErrorReply::new(ErrorCode::Moved, leader))
+                }
+                Ok(command) => match version_request(&command) {
+                    Some((namespace, key, lease)) => {
+                        if let Some(ms) = lease {
+                            let until = self.clock.now() + Duration::from_millis(ms);
+                            self.lease = Some((self.leader.clone(), until));
+                        }
+                        let lsn = self.lsn.to_string();
+                        let val = self.hashmap.namespace(namespace).and_then(|db| db.get(key));
+                        match val {
+                            Some(val) => replies.extend_from_slice(&push(&[lsn.as_bytes(), val])),
+                            None => replies.extend_from_slice(&push(&[lsn.as_bytes()])),
+                        }
+                        continue;
+                    }
+                    None => run_command(&mut self.hashmap, &command),
+                },
+                Err(ParseError::Empty) => continue,
+                Err(err) => Response::Error(err.into()),
+            };
+            response.encode(&mut replies);
+        }
+        Ok((replies, false))
+    }
+
+    /// Applies and logs every complete record in `buf`, leaving any partial
+    /// record buffered. Returns the replies owed to the sender, which are
+    /// only ever errors for requests that couldn't be parsed and answers to
+    /// `HELLO`.
+    pub fn receive(&mut self, buf: &mut BytesMut) -> Result<BytesMut> {
+        let mut replies = BytesMut::new();
+        while let Some(frame) = split_frame(buf, &Limits::NONE)? {
+            let command = match frame.command(&Limits::NONE) {
+                Ok(command) => command,
+                Err(ParseError::Empty) => continue,
+                Err(err) => {
+                    replies.extend_from_slice(format!("{}\n", ErrorReply::from(err)).as_bytes());
+                    continue;
+                }
+            };
+            if let Command::Hello(version, features) = &command {
+                hello(
+                    "follower",
+                    &self.node_id,
+                    *version,
+                    features.as_deref(),
+                    FEATURES,
+                )
+                .encode(&mut replies);
+                continue;
+            }
+            if let Command::Replicate(Some(leader)) = &command {
+                self.leader = Some(String::from_utf8_lossy(leader).into_owned());
+            }
+            if let Command::Install(snapshot) = &command {
+                self.install_snapshot(snapshot)?;
+                continue;
+            }
+            self.apply(&command)?;
+        }
+        Ok(replies)
+    }
+
+    pub fn apply(&mut self, command: &Command<'_>) -> Result<()> {
+        // Request IDs are logged as they came, to trace the writes after them.
+        let marker = request_marker(command);
+        if let Some(id) = marker {
+            self.request_id = Some(String::from_utf8_lossy(id).into_owned());
+            if let Some((_, lsn)) = parse_request_id(id) {
+                self.lsn = lsn;
+            }
+        } else if !is_record(command) {
+            return Ok(());
+        }
+        let mut span = Span::start("replication.apply");
+        span.attr("command", command.name());
+        if let Some(id) = &self.request_id {
+            span.attr("request.id", id);
+        }
+        if marker.is_none() {
+            run_command(&mut self.hashmap, command);
+        }
+        let mut record = BytesMut::new();
+        command.encode(&mut record);
+        self.namespace_logs
+            .append(&mut self.wal, &record)
+            .map_err(DistKvError::storage)?;
+        self.namespace_logs
+            .sync(&mut self.wal)
+            .map_err(DistKvError::storage)?;
+        Ok(())
+    }
+
+    /// Replaces the map with a snapshot from the leader, and the log with
+    /// the records that rebuild it.
+    pub fn install_snapshot(&mut self, snapshot: &[u8]) -> Result<()> {
+        let snapshot = snapshot::decode(snapshot)?;
+        self.namespace_logs
+            .reset(&mut self.wal)
+            .map_err(DistKvError::storage)?;
+        let records = records(&snapshot.hashmap);
+        self.namespace_logs
+            .append(&mut self.wal, &records)
+            .map_err(DistKvError::storage)?;
+        self.namespace_logs
+            .sync(&mut self.wal)
+            .map_err(DistKvError::storage)?;
+        self.hashmap = snapshot.hashmap;
+        self.lsn = snapshot.header.lsn;
+        Ok(())
+    }
+}
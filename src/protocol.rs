@@ -0,0 +1,1976 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::analyze::DEFAULT_TOP;
+use crate::stream::StreamId;
+
+/// Values at least this long (or containing non-ASCII bytes) are written with
+/// bulk framing instead of being quoted and escaped.
+const BULK_THRESHOLD: usize = 1024;
+
+/// Chunk size used by `GETSTREAM` when the client doesn't ask for one.
+pub const DEFAULT_STREAM_CHUNK: usize = 64 * 1024;
+
+/// The protocol version spoken by this server, reported by `HELLO`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Optional features a client can ask for in `HELLO`. `bulk` is `$<len>`
+/// framing of requests, and `reqid` has the leader send a `>2` push of
+/// `reqid` and the request ID ahead of the reply to each write it logs.
+/// `lz4` is for replication connections: agreed to, it has the leader
+/// compress the stream it sends after `REPLICATE` or the snapshot `SYNC`
+/// asked for. A leader only agrees to it with `replication_compression lz4`
+/// set.
+pub const FEATURES: &[&str] = &["bulk", "reqid", "lz4"];
+
+/// Every command name the parser knows, used to tell an unknown command apart
+/// from a known one called with the wrong arguments.
+const COMMANDS: &[&[u8]] = &[
+    b"GET",
+    b"GETVERSION",
+    b"SET",
+    b"DEL",
+    b"SETSTREAM",
+    b"GETSTREAM",
+    b"INFO",
+    b"SYNC",
+    b"REPLICATE",
+    b"REQID",
+    b"HELLO",
+    b"WHOAMI",
+    b"WHOISLEADER",
+    b"PEERS",
+    b"READONLY",
+    b"CLIENT",
+    b"CONFIG",
+    b"ANALYZE",
+    b"EXPORT",
+    b"BULKLOAD",
+    b"SUBSCRIBE",
+    b"UNSUBSCRIBE",
+    b"PSUBSCRIBE",
+    b"PUNSUBSCRIBE",
+    b"PUBLISH",
+    b"TAIL",
+    b"GETRANGE",
+    b"SETRANGE",
+    b"SETBIT",
+    b"GETBIT",
+    b"BITCOUNT",
+    b"PFADD",
+    b"PFCOUNT",
+    b"PFMERGE",
+    b"LPUSH",
+    b"RPUSH",
+    b"LPOP",
+    b"LLEN",
+    b"BLPOP",
+    b"XADD",
+    b"XREAD",
+    b"EXPIREAT",
+    b"PEXPIREAT",
+    b"PERSIST",
+    b"TTL",
+    b"OBJECT",
+    b"RANDOMKEY",
+    b"SAMPLE",
+    b"DELIFEQ",
+    b"LOCK",
+    b"UNLOCK",
+    b"EPHEMERAL",
+    b"WAITCHANGE",
+    b"DUMP",
+    b"RESTORE",
+    b"BATCH",
+    b"SELECT",
+    b"AUTH",
+    b"EVAL",
+    b"FCALL",
+    b"IN",
+    b"HISTORY",
+    b"QUOTA",
+    b"DEBUG",
+    b"CLUSTER",
+    b"INSTALL",
+    b"TRANSFER",
+];
+
+/// Whether `name`, in capitals, is a command the parser knows.
+pub fn is_command(name: &str) -> bool {
+    COMMANDS.contains(&name.as_bytes())
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Command<'a> {
+    Get(Cow<'a, [u8]>),
+    Set(Cow<'a, [u8]>, Cow<'a, [u8]>),
+    /// `SET` with any of the options that can follow the value.
+    SetWith(Cow<'a, [u8]>, Cow<'a, [u8]>, SetOptions),
+    Delete(Cow<'a, [u8]>),
+    /// Deletes a key only if its value is the one given, so that a client
+    /// can't release a lock that has since been taken by another.
+    DelIfEq(Cow<'a, [u8]>, Cow<'a, [u8]>),
+    /// `LOCK <key> <ttl>`: takes the lock at `key` for `ttl` milliseconds
+    /// if no one holds it, answering with a fencing token. The token is the
+    /// LSN the lock was logged at, so each one is larger than any the leader
+    /// gave out before it, and whatever the lock guards can refuse a holder
+    /// whose lease ran out by refusing tokens older than the last it saw.
+    /// A restarted leader carries on from the LSN its log ends at, so they
+    /// keep growing across restarts.
+    Lock(Cow<'a, [u8]>, u64),
+    /// `UNLOCK <key> <token>`: releases the lock if the token is the one it
+    /// is still held with.
+    Unlock(Cow<'a, [u8]>, u64),
+    /// `EPHEMERAL <key> <value>`: sets the key for as long as the
+    /// connection's session lasts. The leader deletes it when the connection
+    /// closes, or when it sends nothing for `session_timeout_ms`, unless the
+    /// key has been written since. It is logged as a `SET`.
+    Ephemeral(Cow<'a, [u8]>, Cow<'a, [u8]>),
+    /// `WAITCHANGE <key> [<timeout>]`: blocks the connection until the next
+    /// write to `key`, then answers as `GETVERSION` does with the write's
+    /// LSN and the value it left, or nil once `timeout` milliseconds pass.
+    /// A timeout of 0, or none, waits for as long as it takes.
+    WaitChange(Cow<'a, [u8]>, Option<u64>),
+    /// The key's value and TTL serialized, see [`crate::dump`].
+    Dump(Cow<'a, [u8]>),
+    /// `RESTORE <key> [REPLACE] <dump>`: sets the key from what `DUMP`
+    /// returned, refusing to overwrite an existing key without `REPLACE`.
+    Restore(Cow<'a, [u8]>, Cow<'a, [u8]>, bool),
+    /// `SET`s and `DEL`s applied together, and logged and replicated as a
+    /// single record so that a crash never leaves part of them applied.
+    Batch(Vec<Command<'a>>),
+    /// Switches the connection to another namespace, which has keys of its
+    /// own. Namespaces are named, with `0` the one connections start in.
+    Select(Cow<'a, [u8]>),
+    /// Authenticates the connection as a tenant with its name and password,
+    /// confining it to the tenant's keys.
+    Auth(Cow<'a, [u8]>, Cow<'a, [u8]>),
+    /// Runs a script, with the keys it may use and its arguments, see
+    /// [`crate::script`]. Its writes are logged as a `BATCH`.
+    Eval(Cow<'a, [u8]>, Vec<Cow<'a, [u8]>>, Vec<Cow<'a, [u8]>>),
+    /// Calls a function loaded from the config by name, with keys and
+    /// arguments as for `EVAL`.
+    FCall(Cow<'a, [u8]>, Vec<Cow<'a, [u8]>>, Vec<Cow<'a, [u8]>>),
+    /// A command run in the given namespace. This is how commands sent after
+    /// `SELECT` reach the store, and how their writes are logged.
+    In(Cow<'a, [u8]>, Box<Command<'a>>),
+    /// Starts a chunked upload of `key`, see [`split_frame`] for the chunks.
+    SetStream(Cow<'a, [u8]>),
+    /// Sends the value of `key` back in chunks of at most the given size.
+    GetStream(Cow<'a, [u8]>, usize),
+    /// Server statistics, optionally limited to one section.
+    Info(Option<Cow<'a, [u8]>>),
+    /// `SYNC [<id> <offset>] [<host:port>]`: sent by a new follower to be
+    /// sent a snapshot and then the leader's writes, optionally resuming an
+    /// interrupted snapshot transfer from the given snapshot id and offset,
+    /// and naming where it answers reads, if it does.
+    Sync(Option<(u64, usize)>, Option<Cow<'a, [u8]>>),
+    /// Sent by the leader first on a connection it opens to a follower, to
+    /// mark it as the replication stream, with the address clients reach the
+    /// leader at if it has one.
+    Replicate(Option<Cow<'a, [u8]>>),
+    /// Sent by the leader on a replication stream ahead of any write, with
+    /// a snapshot for the follower to replace its map and log with.
+    Install(Cow<'a, [u8]>),
+    /// Logged and replicated by the leader ahead of the records of a client
+    /// write, naming the request they were made by. Sent by a client ahead
+    /// of a write, it names the write instead, so that the leader answers a
+    /// retry of it under the same ID without applying it again, see
+    /// `request_dedup_window`.
+    RequestId(Cow<'a, [u8]>),
+    /// Opens a connection by naming the protocol version the client speaks
+    /// and, comma separated, the features it would like to use.
+    Hello(u32, Option<Cow<'a, [u8]>>),
+    /// The node's role, id and the address of the leader.
+    WhoAmI,
+    /// Just the address of the leader.
+    WhoIsLeader,
+    /// The members of the cluster the node knows of, see
+    /// [`crate::discovery`].
+    Peers,
+    /// Has the leader refuse writes, while still serving reads, until it is
+    /// turned off again.
+    ReadOnly(bool),
+    Client(ClientCommand<'a>),
+    Config(ConfigCommand<'a>),
+    Cluster(ClusterCommand<'a>),
+    /// `TRANSFER LEADERSHIP <host:port>`: has the leader stop taking writes
+    /// and wait for the follower there to have every write, so that it can
+    /// take over as the leader.
+    TransferLeadership(Cow<'a, [u8]>),
+    /// Reports the largest values and most common key prefixes, with the
+    /// given number of each.
+    Analyze(usize),
+    /// `EXPORT CSV|NDJSON [PREFIX <prefix>]`: streams the namespace's keys,
+    /// or those starting with the prefix, in chunks as for `GETSTREAM`.
+    Export(ExportFormat, Option<Cow<'a, [u8]>>),
+    /// Starts a bulk load on the connection: the `SET`s and `DEL`s sent
+    /// after it go unanswered and are applied as `BATCH`es, see
+    /// [`crate::server`].
+    BulkLoad,
+    /// `BULKLOAD END`: applies what is left of the bulk load and answers
+    /// with how many writes it loaded.
+    BulkLoadEnd,
+    /// Reports the rates the connection's tenant is held to and how much of
+    /// them it has used this second.
+    Quota,
+    /// `DEBUG DUMPSTATE`: writes a report of the leader's state to a file in
+    /// its data directory, for looking into an incident afterwards.
+    DumpState,
+    /// The `DEBUG` subcommands for reproducing failures in tests, refused
+    /// unless `debug_commands` is on.
+    Debug(DebugCommand<'a>),
+    Subscribe(Cow<'a, [u8]>),
+    Unsubscribe(Cow<'a, [u8]>),
+    /// Subscribes to every channel matching a glob pattern.
+    PSubscribe(Cow<'a, [u8]>),
+    PUnsubscribe(Cow<'a, [u8]>),
+    Publish(Cow<'a, [u8]>, Cow<'a, [u8]>),
+    /// Streams every committed change from the given LSN on. LSNs carry on
+    /// across a leader restart, so a consumer resumes from the one after
+    /// the last change it saw, though the changes before the restart are
+    /// no longer kept.
+    Tail(u64),
+    /// The bytes of a value from `start` to `end` inclusive, counting back
+    /// from the end for negative offsets.
+    GetRange(Cow<'a, [u8]>, i64, i64),
+    /// Overwrites a value from the given offset on, padding it with zero
+    /// bytes if it is shorter.
+    SetRange(Cow<'a, [u8]>, usize, Cow<'a, [u8]>),
+    /// Sets or clears a bit of a value, growing it with zero bytes as
+    /// needed. Bit 0 is the most significant bit of the first byte.
+    SetBit(Cow<'a, [u8]>, usize, bool),
+    GetBit(Cow<'a, [u8]>, usize),
+    /// `GET key AT lsn`: the value a key had once the write logged as the
+    /// given LSN was made, from the leader's recent history.
+    GetAt(Cow<'a, [u8]>, u64),
+    /// `GET key LOCAL|LEADER|QUORUM`: a read that says how stale an answer
+    /// it will take, see [`Consistency`].
+    GetWith(Cow<'a, [u8]>, Consistency),
+    /// `GETVERSION key [LEASE ms]`: a follower's value of a key with the
+    /// LSN of the last write it applied, as a push of the LSN and then the
+    /// value, if there is one. The leader asks it of followers for a quorum
+    /// read, and with `LEASE` has the follower promise to stay with it for
+    /// that long, see [`crate::node::FollowerCore::lease`].
+    GetVersion(Cow<'a, [u8]>, Option<u64>),
+    /// `HISTORY key [limit]`: the values the last writes to a key left it
+    /// with, newest first, with the LSN and time of each, from the leader's
+    /// recent history.
+    History(Cow<'a, [u8]>, Option<usize>),
+    /// Counts the set bits of a value, or of the bytes from `start` to `end`
+    /// inclusive as for [`Command::GetRange`].
+    BitCount(Cow<'a, [u8]>, Option<(i64, i64)>),
+    /// Adds an element to the HyperLogLog sketch stored at a key.
+    PfAdd(Cow<'a, [u8]>, Cow<'a, [u8]>),
+    /// The approximate number of distinct elements added to a sketch.
+    PfCount(Cow<'a, [u8]>),
+    /// Merges the second sketch into the first.
+    PfMerge(Cow<'a, [u8]>, Cow<'a, [u8]>),
+    /// Adds an element to the head of the list stored at a key, see
+    /// [`crate::list`].
+    LPush(Cow<'a, [u8]>, Cow<'a, [u8]>),
+    /// Adds an element to the tail of a list.
+    RPush(Cow<'a, [u8]>, Cow<'a, [u8]>),
+    /// Removes and returns the head of a list, deleting the key once the
+    /// list is empty.
+    LPop(Cow<'a, [u8]>),
+    /// The number of elements in a list.
+    LLen(Cow<'a, [u8]>),
+    /// `BLPOP <key> [<timeout>]`: `LPOP`, but if the list is empty, blocks
+    /// the connection until an element is pushed, or answers nil once
+    /// `timeout` milliseconds pass. Connections blocked on the same list are
+    /// handed elements in the order they started waiting. A timeout of 0, or
+    /// none, waits for as long as it takes.
+    BLPop(Cow<'a, [u8]>, Option<u64>),
+    /// `XADD <key> <id>|* <data>`: appends an entry to the stream stored at
+    /// a key, see [`crate::stream`], answering with its ID. With `*` the
+    /// leader picks the ID from its clock.
+    XAdd(Cow<'a, [u8]>, Option<StreamId>, Cow<'a, [u8]>),
+    /// `XREAD <key> <id> [<count>]`: the entries of a stream added after
+    /// the given ID, at most `count` of them, as a push of each entry's ID
+    /// followed by its data. `0` reads from the start.
+    XRead(Cow<'a, [u8]>, StreamId, Option<usize>),
+    /// Makes a key expire at a Unix time in seconds.
+    ExpireAt(Cow<'a, [u8]>, u64),
+    /// Makes a key expire at a Unix time in milliseconds. This is the form
+    /// every TTL takes in the log.
+    PExpireAt(Cow<'a, [u8]>, u64),
+    /// Removes a key's TTL.
+    Persist(Cow<'a, [u8]>),
+    /// The seconds left until a key expires, `-1` if it has no TTL and `-2`
+    /// if it doesn't exist.
+    Ttl(Cow<'a, [u8]>),
+    /// `OBJECT ENCODING|IDLETIME|FREQ <key>`: how a key's value is stored,
+    /// or how recently or often the leader has used it.
+    Object(ObjectField, Cow<'a, [u8]>),
+    /// A key picked uniformly at random, or nil if there are none.
+    RandomKey,
+    /// `SAMPLE <n>`: up to `n` different keys picked uniformly at random.
+    Sample(usize),
+}
+
+/// The `CLIENT` subcommands, for looking at and managing connections.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClientCommand<'a> {
+    List,
+    SetName(Cow<'a, [u8]>),
+    Kill(u64),
+    /// `CLIENT TRACKING ON|OFF`: whether the leader tells the connection
+    /// when keys it read change, see [`crate::tracking`].
+    Tracking(bool),
+}
+
+/// The `CLUSTER` subcommands, which change the followers the leader
+/// replicates to. Each change is logged and replicated as a record of its
+/// own, so that followers know it and a restarted leader picks it up again.
+/// Only one change is under way at a time: a node added is a learner until
+/// it has caught up, and until then neither another node can be added nor a
+/// voter removed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ClusterCommand<'a> {
+    /// `CLUSTER ADD-NODE <host:port>`: starts replicating to the follower
+    /// there, beginning with a snapshot of the leader's map.
+    AddNode(Cow<'a, [u8]>),
+    /// `CLUSTER REMOVE-NODE <host:port>`: stops replicating to it.
+    RemoveNode(Cow<'a, [u8]>),
+}
+
+/// How up to date a read has to be. `LOCAL` is answered by whichever node
+/// gets it, which on a follower may be behind the leader. `LEADER` is only
+/// answered by the leader; a follower refers it there with `MOVED`. `QUORUM`
+/// also goes to the leader, which asks a majority of the cluster, itself
+/// included, and answers with the value of whichever applied the latest
+/// write, so a read still needs a majority to be up.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Consistency {
+    Local,
+    Leader,
+    Quorum,
+}
+
+impl Consistency {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Consistency::Local => "LOCAL",
+            Consistency::Leader => "LEADER",
+            Consistency::Quorum => "QUORUM",
+        }
+    }
+}
+
+/// The formats `EXPORT` writes keys in, see [`crate::export`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Ndjson,
+}
+
+impl ExportFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ExportFormat::Csv => "CSV",
+            ExportFormat::Ndjson => "NDJSON",
+        }
+    }
+}
+
+/// What `OBJECT` reports about a key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ObjectField {
+    /// How the value is stored, see [`crate::store::encoding`].
+    Encoding,
+    /// Seconds since the key was last read or written.
+    IdleTime,
+    /// The key's access frequency counter, see [`crate::store::Access`].
+    Freq,
+}
+
+impl ObjectField {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ObjectField::Encoding => "ENCODING",
+            ObjectField::IdleTime => "IDLETIME",
+            ObjectField::Freq => "FREQ",
+        }
+    }
+}
+
+/// The `DEBUG` subcommands for tests, which reach into the leader's
+/// internals. `DEBUG DUMPSTATE` is [`Command::DumpState`], which is always
+/// allowed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DebugCommand<'a> {
+    /// `DEBUG SLEEP <ms>`: holds the leader's lock for that long, stalling
+    /// every other command.
+    Sleep(u64),
+    /// `DEBUG OBJECT <key>`: how the key's value is stored.
+    Object(Cow<'a, [u8]>),
+    /// `DEBUG SET-LSN <lsn>`: makes the leader's next write take the LSN
+    /// after the one given.
+    SetLsn(u64),
+}
+
+/// The `CONFIG` subcommands, for reading settings and changing the ones in
+/// [`crate::config::LIVE_SETTINGS`] without a restart.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigCommand<'a> {
+    Get(Cow<'a, [u8]>),
+    Set(Cow<'a, [u8]>, Cow<'a, [u8]>),
+}
+
+/// The options `SET` takes after the value: at most one of `EX`, `PX`,
+/// `EXAT`, `PXAT` and `KEEPTTL`, and at most one of `NX` and `XX`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SetOptions {
+    pub expiry: Option<Expiry>,
+    pub condition: Option<Condition>,
+    /// Keeps the TTL the key already has rather than dropping it.
+    pub keep_ttl: bool,
+}
+
+/// When a key set with a TTL expires, in milliseconds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Expiry {
+    /// From when the command is executed, as given by `EX` and `PX`.
+    In(u64),
+    /// Since the Unix epoch, as given by `EXAT` and `PXAT`.
+    At(u64),
+}
+
+impl Expiry {
+    /// The Unix time in milliseconds this expires at, for a command executed
+    /// at `now`.
+    pub fn at(self, now: u64) -> u64 {
+        match self {
+            Expiry::In(ms) => now.saturating_add(ms),
+            Expiry::At(at) => at,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    /// `NX`: only set the key if it doesn't exist.
+    Absent,
+    /// `XX`: only set the key if it already exists.
+    Exists,
+}
+
+impl SetOptions {
+    fn parse<'a>(
+        options: impl Iterator<Item = Result<Cow<'a, [u8]>, ParseError>>,
+    ) -> Result<Self, ParseError> {
+        let mut parsed = SetOptions::default();
+        let mut options = options.peekable();
+        while let Some(option) = options.next().transpose()? {
+            let mut time = |scale: u64, expiry: fn(u64) -> Expiry| {
+                let time = options
+                    .next()
+                    .transpose()?
+                    .ok_or(ParseError::InvalidOptions)?;
+                match parse_int::<u64>(&time)?.checked_mul(scale) {
+                    Some(time) if time > 0 => Ok(Some(expiry(time))),
+                    _ => Err(ParseError::InvalidExpireTime),
+                }
+            };
+            let expiry = match &*option {
+                b"EX" => time(1000, Expiry::In)?,
+                b"PX" => time(1, Expiry::In)?,
+                b"EXAT" => time(1000, Expiry::At)?,
+                b"PXAT" => time(1, Expiry::At)?,
+                b"NX" | b"XX" if parsed.condition.is_some() => {
+                    return Err(ParseError::InvalidOptions)
+                }
+                b"NX" => {
+                    parsed.condition = Some(Condition::Absent);
+                    continue;
+                }
+                b"XX" => {
+                    parsed.condition = Some(Condition::Exists);
+                    continue;
+                }
+                b"KEEPTTL" => None,
+                _ => return Err(ParseError::InvalidOptions),
+            };
+            if parsed.expiry.is_some() || parsed.keep_ttl {
+                return Err(ParseError::InvalidOptions);
+            }
+            parsed.expiry = expiry;
+            parsed.keep_ttl = expiry.is_none();
+        }
+        Ok(parsed)
+    }
+
+    fn encode(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        match self.expiry {
+            Some(Expiry::In(ms)) => args.extend(["PX".to_string(), ms.to_string()]),
+            Some(Expiry::At(at)) => args.extend(["PXAT".to_string(), at.to_string()]),
+            None if self.keep_ttl => args.push("KEEPTTL".to_string()),
+            None => {}
+        }
+        match self.condition {
+            Some(Condition::Absent) => args.push("NX".to_string()),
+            Some(Condition::Exists) => args.push("XX".to_string()),
+            None => {}
+        }
+        args
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ParseError {
+    Empty,
+    WrongNumberOfArguments,
+    UnknownCommand(String),
+    UnbalancedQuotes,
+    InvalidEscape,
+    InvalidBulk,
+    NotAnInteger,
+    InvalidOptions,
+    InvalidExpireTime,
+    InvalidBatch,
+    InvalidIn,
+    InvalidStreamId,
+    KeyTooLarge { len: usize, max: usize },
+    ValueTooLarge { len: usize, max: usize },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Empty => write!(f, "empty command"),
+            ParseError::WrongNumberOfArguments => write!(f, "wrong number of arguments"),
+            ParseError::UnknownCommand(name) => write!(f, "unknown command '{}'", name),
+            ParseError::UnbalancedQuotes => write!(f, "unbalanced quotes in request"),
+            ParseError::InvalidEscape => write!(f, "invalid escape sequence"),
+            ParseError::InvalidBulk => write!(f, "bulk payload not followed by a line break"),
+            ParseError::NotAnInteger => write!(f, "value is not an integer or out of range"),
+            ParseError::InvalidOptions => write!(f, "unknown or conflicting options"),
+            ParseError::InvalidExpireTime => write!(f, "invalid expire time"),
+            ParseError::InvalidBatch => write!(f, "a batch may only hold SET and DEL"),
+            ParseError::InvalidIn => write!(f, "IN takes a single command on keys"),
+            ParseError::InvalidStreamId => write!(f, "invalid stream ID"),
+            ParseError::KeyTooLarge { len, max } => {
+                write!(f, "key is {} bytes, the limit is {}", len, max)
+            }
+            ParseError::ValueTooLarge { len, max } => {
+                write!(f, "value is {} bytes, the limit is {}", len, max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Machine-readable failure classes sent as the first word after `-ERR`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+    Syntax,
+    WrongArgs,
+    UnknownCommand,
+    NotSupported,
+    TooLarge,
+    Loading,
+    NoProto,
+    NoSuchClient,
+    WrongType,
+    /// The connection hasn't authenticated, or gave the wrong password.
+    NoAuth,
+    /// The connection's tenant may not run the command or touch the key.
+    NoPerm,
+    /// Writes go to the leader, whose address is the message.
+    Moved,
+    /// The write would take a tenant over one of its quotas.
+    OverQuota,
+    /// A script failed to parse or run.
+    Script,
+    /// The leader is refusing writes, as when its disk is nearly full.
+    ReadOnly,
+    /// A `QUORUM` read didn't hear back from a majority of the cluster.
+    NoQuorum,
+    /// The follower promised another leader a lease that hasn't run out.
+    Leased,
+    /// Another cluster membership change is still under way.
+    Busy,
+    /// Something the command waited for didn't happen in time.
+    Timeout,
+    /// `RESTORE` without `REPLACE` found the key already there.
+    BusyKey,
+    /// The node couldn't read or write its log or another file it keeps.
+    IoErr,
+    /// The replication link between the leader and a follower broke.
+    LinkDown,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 22] = [
+        ErrorCode::Syntax,
+        ErrorCode::WrongArgs,
+        ErrorCode::UnknownCommand,
+        ErrorCode::NotSupported,
+        ErrorCode::TooLarge,
+        ErrorCode::Loading,
+        ErrorCode::NoProto,
+        ErrorCode::NoSuchClient,
+        ErrorCode::WrongType,
+        ErrorCode::NoAuth,
+        ErrorCode::NoPerm,
+        ErrorCode::Moved,
+        ErrorCode::OverQuota,
+        ErrorCode::Script,
+        ErrorCode::ReadOnly,
+        ErrorCode::NoQuorum,
+        ErrorCode::Leased,
+        ErrorCode::Busy,
+        ErrorCode::Timeout,
+        ErrorCode::BusyKey,
+        ErrorCode::IoErr,
+        ErrorCode::LinkDown,
+    ];
+
+    /// The code named `name`, as [`ErrorCode::as_str`] spells it.
+    pub fn parse(name: &str) -> Option<ErrorCode> {
+        ErrorCode::ALL
+            .into_iter()
+            .find(|code| code.as_str() == name)
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ErrorCode::Syntax => "SYNTAX",
+            ErrorCode::WrongArgs => "WRONGARGS",
+            ErrorCode::UnknownCommand => "UNKNOWN",
+            ErrorCode::NotSupported => "NOTSUPPORTED",
+            ErrorCode::TooLarge => "TOOLARGE",
+            ErrorCode::Loading => "LOADING",
+            ErrorCode::NoProto => "NOPROTO",
+            ErrorCode::NoSuchClient => "NOSUCHCLIENT",
+            ErrorCode::WrongType => "WRONGTYPE",
+            ErrorCode::NoAuth => "NOAUTH",
+            ErrorCode::NoPerm => "NOPERM",
+            ErrorCode::Moved => "MOVED",
+            ErrorCode::OverQuota => "OVERQUOTA",
+            ErrorCode::Script => "SCRIPT",
+            ErrorCode::NoQuorum => "NOQUORUM",
+            ErrorCode::Leased => "LEASED",
+            ErrorCode::Busy => "BUSY",
+            ErrorCode::Timeout => "TIMEOUT",
+            ErrorCode::BusyKey => "BUSYKEY",
+            ErrorCode::IoErr => "IOERR",
+            ErrorCode::LinkDown => "LINKDOWN",
+            ErrorCode::ReadOnly => "READONLY",
+        }
+    }
+}
+
+/// An error reply, rendered on the wire as `-ERR <code> <message>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorReply {
+    pub code: ErrorCode,
+    pub message: String,
+}
+
+impl ErrorReply {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        ErrorReply {
+            code,
+            message: message.into(),
+        }
+    }
+
+    /// Parses an error line as a client reads it, `ERR <code> <message>`
+    /// without the leading `-`.
+    pub fn parse(line: &str) -> Option<ErrorReply> {
+        let rest = line.strip_prefix("ERR ")?;
+        let (code, message) = rest.split_once(' ').unwrap_or((rest, ""));
+        Some(ErrorReply::new(ErrorCode::parse(code)?, message))
+    }
+}
+
+impl fmt::Display for ErrorReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "-ERR {} {}", self.code.as_str(), self.message)
+    }
+}
+
+impl From<ParseError> for ErrorReply {
+    fn from(err: ParseError) -> Self {
+        let code = match err {
+            ParseError::Empty
+            | ParseError::UnbalancedQuotes
+            | ParseError::InvalidEscape
+            | ParseError::InvalidBulk
+            | ParseError::InvalidOptions
+            | ParseError::InvalidBatch
+            | ParseError::InvalidIn => ErrorCode::Syntax,
+            ParseError::NotAnInteger
+            | ParseError::InvalidExpireTime
+            | ParseError::InvalidStreamId => ErrorCode::WrongArgs,
+            ParseError::KeyTooLarge { .. } | ParseError::ValueTooLarge { .. } => {
+                ErrorCode::TooLarge
+            }
+            ParseError::WrongNumberOfArguments => ErrorCode::WrongArgs,
+            ParseError::UnknownCommand(_) => ErrorCode::UnknownCommand,
+        };
+        ErrorReply::new(code, err.to_string())
+    }
+}
+
+/// Size limits applied to requests as they are parsed, so an oversized value
+/// is refused before it has been buffered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    pub max_key_size: usize,
+    pub max_value_size: usize,
+}
+
+impl Limits {
+    /// No limits, used for replaying the log and for replication, which only
+    /// ever carry requests that were already accepted.
+    pub const NONE: Limits = Limits {
+        max_key_size: usize::MAX,
+        max_value_size: usize::MAX,
+    };
+
+    pub fn check_key(&self, key: &[u8]) -> Result<(), ParseError> {
+        if key.len() > self.max_key_size {
+            return Err(ParseError::KeyTooLarge {
+                len: key.len(),
+                max: self.max_key_size,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check_value(&self, len: usize) -> Result<(), ParseError> {
+        if len > self.max_value_size {
+            return Err(ParseError::ValueTooLarge {
+                len,
+                max: self.max_value_size,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn check(&self, command: &Command) -> Result<(), ParseError> {
+        match command {
+            Command::Set(key, val)
+            | Command::SetWith(key, val, _)
+            | Command::Ephemeral(key, val) => {
+                self.check_key(key)?;
+                self.check_value(val.len())
+            }
+            Command::DelIfEq(key, expected) => {
+                self.check_key(key)?;
+                self.check_value(expected.len())
+            }
+            Command::Restore(key, dump, _) => {
+                self.check_key(key)?;
+                self.check_value(dump.len().saturating_sub(crate::dump::OVERHEAD))
+            }
+            Command::Batch(commands) => commands.iter().try_for_each(|command| self.check(command)),
+            Command::In(_, command) => self.check(command),
+            Command::Get(key)
+            | Command::Delete(key)
+            | Command::Dump(key)
+            | Command::SetStream(key)
+            | Command::GetStream(key, _)
+            | Command::Export(_, Some(key)) => self.check_key(key),
+            Command::Info(_)
+            | Command::Sync(..)
+            | Command::Replicate(_)
+            | Command::RequestId(_)
+            | Command::Hello(..)
+            | Command::WhoAmI
+            | Command::WhoIsLeader
+            | Command::Peers
+            | Command::ReadOnly(_)
+            | Command::Client(_)
+            | Command::Config(_)
+            | Command::Analyze(_)
+            | Command::Export(_, None)
+            | Command::RandomKey
+            | Command::Sample(_)
+            | Command::BulkLoad
+            | Command::BulkLoadEnd
+            | Command::Quota
+            | Command::DumpState
+            | Command::Debug(_)
+            | Command::Cluster(_)
+            | Command::TransferLeadership(_)
+            | Command::Install(_)
+            | Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::PSubscribe(_)
+            | Command::PUnsubscribe(_)
+            | Command::Tail(_)
+            | Command::Select(_)
+            | Command::Auth(..) => Ok(()),
+            Command::Eval(script, keys, args) | Command::FCall(script, keys, args) => {
+                self.check_value(script.len())?;
+                keys.iter().try_for_each(|key| self.check_key(key))?;
+                args.iter().try_for_each(|arg| self.check_value(arg.len()))
+            }
+            Command::Publish(_, message) => self.check_value(message.len()),
+            Command::GetRange(key, ..)
+            | Command::GetBit(key, _)
+            | Command::GetAt(key, _)
+            | Command::GetWith(key, _)
+            | Command::GetVersion(key, _)
+            | Command::History(key, _)
+            | Command::BitCount(key, _) => self.check_key(key),
+            Command::SetBit(key, offset, _) => {
+                self.check_key(key)?;
+                self.check_value(offset / 8 + 1)
+            }
+            Command::LPush(key, element)
+            | Command::RPush(key, element)
+            | Command::XAdd(key, _, element) => {
+                self.check_key(key)?;
+                self.check_value(element.len())
+            }
+            Command::PfAdd(key, _)
+            | Command::PfCount(key)
+            | Command::LPop(key)
+            | Command::LLen(key)
+            | Command::BLPop(key, _)
+            | Command::XRead(key, ..)
+            | Command::ExpireAt(key, _)
+            | Command::PExpireAt(key, _)
+            | Command::Persist(key)
+            | Command::Ttl(key)
+            | Command::Lock(key, _)
+            | Command::Unlock(key, _)
+            | Command::WaitChange(key, _)
+            | Command::Object(_, key) => self.check_key(key),
+            Command::PfMerge(dest, src) => {
+                self.check_key(dest)?;
+                self.check_key(src)
+            }
+            Command::SetRange(key, offset, val) => {
+                self.check_key(key)?;
+                self.check_value(offset.saturating_add(val.len()))
+            }
+        }
+    }
+
+    /// The longest line that can still hold a key and value within the
+    /// limits, allowing for every byte being written as a `\xHH` escape.
+    fn max_line(&self) -> usize {
+        self.max_key_size
+            .saturating_add(self.max_value_size)
+            .saturating_mul(4)
+            .saturating_add(64)
+    }
+}
+
+impl<'a> TryFrom<&'a [u8]> for Command<'a> {
+    type Error = ParseError;
+
+    fn try_from(line: &'a [u8]) -> Result<Self, Self::Error> {
+        Command::from_tokens(Tokens { line, pos: 0 })
+    }
+}
+
+impl<'a> Command<'a> {
+    /// Parses a command already split into its name and arguments, as the
+    /// arrays of a RESP stream are.
+    pub fn from_args(args: impl IntoIterator<Item = &'a [u8]>) -> Result<Self, ParseError> {
+        Command::from_tokens(args.into_iter().map(|arg| Ok(Cow::Borrowed(arg))))
+    }
+
+    fn from_tokens(
+        mut tokens: impl Iterator<Item = Result<Cow<'a, [u8]>, ParseError>>,
+    ) -> Result<Self, ParseError> {
+        let name = tokens.next().transpose()?.ok_or(ParseError::Empty)?;
+        // One more argument than any command takes, so that extra arguments
+        // are caught by requiring the slot after the last one to be empty.
+        let args = [
+            tokens.next().transpose()?,
+            tokens.next().transpose()?,
+            tokens.next().transpose()?,
+            tokens.next().transpose()?,
+        ];
+        let command = match (&*name, args) {
+            (b"SET", [Some(key), Some(val), None, ..]) => Command::Set(key, val),
+            (b"SET", [Some(key), Some(val), Some(first), second]) => {
+                let options = [Some(first), second].into_iter().flatten().map(Ok);
+                Command::SetWith(key, val, SetOptions::parse(options.chain(tokens))?)
+            }
+            (b"GET", [Some(key), None, ..]) => Command::Get(key),
+            (b"GET", [Some(key), Some(at), Some(lsn), None]) if &*at == b"AT" => {
+                Command::GetAt(key, parse_int(&lsn)?)
+            }
+            (b"GET", [Some(key), Some(level), None, ..]) => {
+                let consistency = match &*level {
+                    b"LOCAL" => Consistency::Local,
+                    b"LEADER" => Consistency::Leader,
+                    b"QUORUM" => Consistency::Quorum,
+                    _ => return Err(ParseError::InvalidOptions),
+                };
+                Command::GetWith(key, consistency)
+            }
+            (b"GETVERSION", [Some(key), None, ..]) => Command::GetVersion(key, None),
+            (b"GETVERSION", [Some(key), Some(lease), Some(ms), None]) if &*lease == b"LEASE" => {
+                Command::GetVersion(key, Some(parse_int(&ms)?))
+            }
+            (b"HISTORY", [Some(key), None, ..]) => Command::History(key, None),
+            (b"HISTORY", [Some(key), Some(limit), None, ..]) => {
+                Command::History(key, Some(parse_int(&limit)?))
+            }
+            (b"DEL", [Some(key), None, ..]) => Command::Delete(key),
+            (b"DELIFEQ", [Some(key), Some(expected), None, ..]) => Command::DelIfEq(key, expected),
+            (b"EPHEMERAL", [Some(key), Some(val), None, ..]) => Command::Ephemeral(key, val),
+            (b"WAITCHANGE", [Some(key), None, ..]) => Command::WaitChange(key, None),
+            (b"WAITCHANGE", [Some(key), Some(timeout), None, ..]) => {
+                Command::WaitChange(key, Some(parse_int(&timeout)?))
+            }
+            (b"LOCK", [Some(key), Some(ttl), None, ..]) => match parse_int(&ttl)? {
+                0 => return Err(ParseError::InvalidExpireTime),
+                ttl => Command::Lock(key, ttl),
+            },
+            (b"UNLOCK", [Some(key), Some(token), None, ..]) => {
+                Command::Unlock(key, parse_int(&token)?)
+            }
+            (b"DUMP", [Some(key), None, ..]) => Command::Dump(key),
+            (b"RESTORE", [Some(key), Some(dump), None, ..]) => Command::Restore(key, dump, false),
+            (b"RESTORE", [Some(key), Some(replace), Some(dump), None])
+                if &*replace == b"REPLACE" =>
+            {
+                Command::Restore(key, dump, true)
+            }
+            (b"BATCH", [Some(records), None, ..]) => Command::Batch(parse_batch(&records)?),
+            (b"SELECT", [Some(name), None, ..]) => Command::Select(name),
+            (b"AUTH", [Some(user), Some(password), None, ..]) => Command::Auth(user, password),
+            (b"EVAL" | b"FCALL", [Some(target), Some(numkeys), first, second]) => {
+                let rest = [first, second].into_iter().flatten().map(Ok);
+                let mut args = rest.chain(tokens).collect::<Result<Vec<_>, _>>()?;
+                let numkeys: usize = parse_int(&numkeys)?;
+                if numkeys > args.len() {
+                    return Err(ParseError::WrongNumberOfArguments);
+                }
+                let keys = args.drain(..numkeys).collect();
+                match &*name {
+                    b"EVAL" => Command::Eval(target, keys, args),
+                    _ => Command::FCall(target, keys, args),
+                }
+            }
+            (b"IN", [Some(name), Some(record), None, ..]) => {
+                let mut commands = parse_all(&record, &Limits::NONE)?;
+                match (commands.pop(), commands.is_empty()) {
+                    (Some(command), true) if command.is_keyed() => {
+                        Command::In(name, Box::new(command))
+                    }
+                    _ => return Err(ParseError::InvalidIn),
+                }
+            }
+            (b"SETSTREAM", [Some(key), None, ..]) => Command::SetStream(key),
+            (b"GETSTREAM", [Some(key), chunk, None, ..]) => {
+                let chunk = match chunk {
+                    Some(chunk) => parse_int(&chunk)?,
+                    None => DEFAULT_STREAM_CHUNK,
+                };
+                if chunk == 0 {
+                    return Err(ParseError::NotAnInteger);
+                }
+                Command::GetStream(key, chunk)
+            }
+            (b"INFO", [section, None, ..]) => Command::Info(section),
+            (b"SYNC", [None, ..]) => Command::Sync(None, None),
+            (b"SYNC", [Some(addr), None, ..]) => Command::Sync(None, Some(addr)),
+            (b"SYNC", [Some(id), Some(offset), addr, None]) => {
+                Command::Sync(Some((parse_int(&id)?, parse_int(&offset)?)), addr)
+            }
+            (b"REPLICATE", [leader, None, ..]) => Command::Replicate(leader),
+            (b"INSTALL", [Some(snapshot), None, ..]) => Command::Install(snapshot),
+            (b"REQID", [Some(id), None, ..]) => Command::RequestId(id),
+            (b"HELLO", [Some(version), features, None, ..]) => {
+                Command::Hello(parse_int(&version)?, features)
+            }
+            (b"WHOAMI", [None, ..]) => Command::WhoAmI,
+            (b"QUOTA", [None, ..]) => Command::Quota,
+            (b"DEBUG", [Some(sub), arg, None, ..]) => match (&*sub, arg) {
+                (b"DUMPSTATE", None) => Command::DumpState,
+                (b"SLEEP", Some(ms)) => Command::Debug(DebugCommand::Sleep(parse_int(&ms)?)),
+                (b"OBJECT", Some(key)) => Command::Debug(DebugCommand::Object(key)),
+                (b"SET-LSN", Some(lsn)) => Command::Debug(DebugCommand::SetLsn(parse_int(&lsn)?)),
+                _ => return Err(ParseError::WrongNumberOfArguments),
+            },
+            (b"WHOISLEADER", [None, ..]) => Command::WhoIsLeader,
+            (b"PEERS", [None, ..]) => Command::Peers,
+            (b"READONLY", [Some(mode), None, ..]) => match &*mode {
+                b"ON" => Command::ReadOnly(true),
+                b"OFF" => Command::ReadOnly(false),
+                _ => return Err(ParseError::WrongNumberOfArguments),
+            },
+            (b"CLIENT", [Some(sub), arg, None, ..]) => match (&*sub, arg) {
+                (b"LIST", None) => Command::Client(ClientCommand::List),
+                (b"SETNAME", Some(name)) => Command::Client(ClientCommand::SetName(name)),
+                (b"KILL", Some(id)) => Command::Client(ClientCommand::Kill(parse_int(&id)?)),
+                (b"TRACKING", Some(mode)) => match &*mode {
+                    b"ON" => Command::Client(ClientCommand::Tracking(true)),
+                    b"OFF" => Command::Client(ClientCommand::Tracking(false)),
+                    _ => return Err(ParseError::InvalidOptions),
+                },
+                _ => return Err(ParseError::WrongNumberOfArguments),
+            },
+            (b"CONFIG", [Some(sub), Some(name), val, None]) => match (&*sub, val) {
+                (b"GET", None) => Command::Config(ConfigCommand::Get(name)),
+                (b"SET", Some(val)) => Command::Config(ConfigCommand::Set(name, val)),
+                _ => return Err(ParseError::WrongNumberOfArguments),
+            },
+            (b"CLUSTER", [Some(sub), Some(addr), None, ..]) => match &*sub {
+                b"ADD-NODE" => Command::Cluster(ClusterCommand::AddNode(addr)),
+                b"REMOVE-NODE" => Command::Cluster(ClusterCommand::RemoveNode(addr)),
+                _ => return Err(ParseError::WrongNumberOfArguments),
+            },
+            (b"TRANSFER", [Some(sub), Some(addr), None, ..]) => match &*sub {
+                b"LEADERSHIP" => Command::TransferLeadership(addr),
+                _ => return Err(ParseError::WrongNumberOfArguments),
+            },
+            (b"EXPORT", [Some(format), prefix, rest, None]) => {
+                let format = match &*format {
+                    b"CSV" => ExportFormat::Csv,
+                    b"NDJSON" => ExportFormat::Ndjson,
+                    _ => return Err(ParseError::InvalidOptions),
+                };
+                match (prefix, rest) {
+                    (None, _) => Command::Export(format, None),
+                    (Some(option), Some(prefix)) if &*option == b"PREFIX" => {
+                        Command::Export(format, Some(prefix))
+                    }
+                    _ => return Err(ParseError::InvalidOptions),
+                }
+            }
+            (b"BULKLOAD", [None, ..]) => Command::BulkLoad,
+            (b"BULKLOAD", [Some(end), None, ..]) if &*end == b"END" => Command::BulkLoadEnd,
+            (b"ANALYZE", [top, None, ..]) => match top {
+                Some(top) => Command::Analyze(parse_int(&top)?),
+                None => Command::Analyze(DEFAULT_TOP),
+            },
+            (b"SUBSCRIBE", [Some(channel), None, ..]) => Command::Subscribe(channel),
+            (b"UNSUBSCRIBE", [Some(channel), None, ..]) => Command::Unsubscribe(channel),
+            (b"PSUBSCRIBE", [Some(pattern), None, ..]) => Command::PSubscribe(pattern),
+            (b"PUNSUBSCRIBE", [Some(pattern), None, ..]) => Command::PUnsubscribe(pattern),
+            (b"PUBLISH", [Some(channel), Some(message), None, ..]) => {
+                Command::Publish(channel, message)
+            }
+            (b"TAIL", [Some(from), None, ..]) => Command::Tail(parse_int(&from)?),
+            (b"GETRANGE", [Some(key), Some(start), Some(end), None]) => {
+                Command::GetRange(key, parse_int(&start)?, parse_int(&end)?)
+            }
+            (b"SETRANGE", [Some(key), Some(offset), Some(val), None]) => {
+                Command::SetRange(key, parse_int(&offset)?, val)
+            }
+            (b"SETBIT", [Some(key), Some(offset), Some(bit), None]) => {
+                let bit = match &*bit {
+                    b"0" => false,
+                    b"1" => true,
+                    _ => return Err(ParseError::NotAnInteger),
+                };
+                Command::SetBit(key, parse_int(&offset)?, bit)
+            }
+            (b"GETBIT", [Some(key), Some(offset), None, ..]) => {
+                Command::GetBit(key, parse_int(&offset)?)
+            }
+            (b"BITCOUNT", [Some(key), None, ..]) => Command::BitCount(key, None),
+            (b"BITCOUNT", [Some(key), Some(start), Some(end), None]) => {
+                Command::BitCount(key, Some((parse_int(&start)?, parse_int(&end)?)))
+            }
+            (b"PFADD", [Some(key), Some(element), None, ..]) => Command::PfAdd(key, element),
+            (b"PFCOUNT", [Some(key), None, ..]) => Command::PfCount(key),
+            (b"PFMERGE", [Some(dest), Some(src), None, ..]) => Command::PfMerge(dest, src),
+            (b"LPUSH", [Some(key), Some(element), None, ..]) => Command::LPush(key, element),
+            (b"RPUSH", [Some(key), Some(element), None, ..]) => Command::RPush(key, element),
+            (b"LPOP", [Some(key), None, ..]) => Command::LPop(key),
+            (b"LLEN", [Some(key), None, ..]) => Command::LLen(key),
+            (b"BLPOP", [Some(key), None, ..]) => Command::BLPop(key, None),
+            (b"BLPOP", [Some(key), Some(timeout), None, ..]) => {
+                Command::BLPop(key, Some(parse_int(&timeout)?))
+            }
+            (b"XADD", [Some(key), Some(id), Some(data), None, ..]) => match &id[..] {
+                b"*" => Command::XAdd(key, None, data),
+                id => Command::XAdd(key, Some(parse_stream_id(id)?), data),
+            },
+            (b"XREAD", [Some(key), Some(after), None, ..]) => {
+                Command::XRead(key, parse_stream_id(&after)?, None)
+            }
+            (b"XREAD", [Some(key), Some(after), Some(count), None]) => {
+                Command::XRead(key, parse_stream_id(&after)?, Some(parse_int(&count)?))
+            }
+            (b"EXPIREAT", [Some(key), Some(at), None, ..]) => {
+                Command::ExpireAt(key, parse_int(&at)?)
+            }
+            (b"PEXPIREAT", [Some(key), Some(at), None, ..]) => {
+                Command::PExpireAt(key, parse_int(&at)?)
+            }
+            (b"PERSIST", [Some(key), None, ..]) => Command::Persist(key),
+            (b"TTL", [Some(key), None, ..]) => Command::Ttl(key),
+            (b"OBJECT", [Some(field), Some(key), None, ..]) => {
+                let field = match &*field {
+                    b"ENCODING" => ObjectField::Encoding,
+                    b"IDLETIME" => ObjectField::IdleTime,
+                    b"FREQ" => ObjectField::Freq,
+                    _ => return Err(ParseError::InvalidOptions),
+                };
+                Command::Object(field, key)
+            }
+            (b"RANDOMKEY", [None, ..]) => Command::RandomKey,
+            (b"SAMPLE", [Some(n), None, ..]) => Command::Sample(parse_int(&n)?),
+            (name, _) if COMMANDS.contains(&name) => {
+                return Err(ParseError::WrongNumberOfArguments)
+            }
+            _ => {
+                let name = String::from_utf8_lossy(&name).into_owned();
+                return Err(ParseError::UnknownCommand(name));
+            }
+        };
+        Ok(command)
+    }
+
+    /// Parses a single line, borrowing the key and value from it instead of
+    /// allocating, so the command only lives until it has been applied.
+    pub fn parse(line: &'a [u8]) -> Result<Self, ParseError> {
+        Command::try_from(line)
+    }
+
+    /// Parses a line whose final argument was sent separately as a bulk
+    /// payload, see [`split_frame`].
+    pub fn parse_bulk(line: &'a [u8], payload: &'a [u8]) -> Result<Self, ParseError> {
+        let payload = std::iter::once(Ok(Cow::Borrowed(payload)));
+        Command::from_tokens(Tokens { line, pos: 0 }.chain(payload))
+    }
+
+    /// Copies any borrowed arguments so the command outlives its buffer.
+    pub fn into_owned(self) -> Command<'static> {
+        let own = |arg: Cow<'_, [u8]>| Cow::Owned(arg.into_owned());
+        match self {
+            Command::Get(key) => Command::Get(own(key)),
+            Command::Set(key, val) => Command::Set(own(key), own(val)),
+            Command::SetWith(key, val, options) => Command::SetWith(own(key), own(val), options),
+            Command::Delete(key) => Command::Delete(own(key)),
+            Command::DelIfEq(key, expected) => Command::DelIfEq(own(key), own(expected)),
+            Command::Lock(key, ttl) => Command::Lock(own(key), ttl),
+            Command::Ephemeral(key, val) => Command::Ephemeral(own(key), own(val)),
+            Command::WaitChange(key, timeout) => Command::WaitChange(own(key), timeout),
+            Command::Unlock(key, token) => Command::Unlock(own(key), token),
+            Command::Dump(key) => Command::Dump(own(key)),
+            Command::Restore(key, dump, replace) => Command::Restore(own(key), own(dump), replace),
+            Command::Batch(commands) => {
+                Command::Batch(commands.into_iter().map(Command::into_owned).collect())
+            }
+            Command::Select(name) => Command::Select(own(name)),
+            Command::Auth(user, password) => Command::Auth(own(user), own(password)),
+            Command::Eval(script, keys, args) => Command::Eval(
+                own(script),
+                keys.into_iter().map(own).collect(),
+                args.into_iter().map(own).collect(),
+            ),
+            Command::FCall(function, keys, args) => Command::FCall(
+                own(function),
+                keys.into_iter().map(own).collect(),
+                args.into_iter().map(own).collect(),
+            ),
+            Command::In(name, command) => Command::In(own(name), Box::new(command.into_owned())),
+            Command::SetStream(key) => Command::SetStream(own(key)),
+            Command::GetStream(key, chunk) => Command::GetStream(own(key), chunk),
+            Command::Info(section) => Command::Info(section.map(own)),
+            Command::Sync(resume, addr) => Command::Sync(resume, addr.map(own)),
+            Command::Replicate(leader) => Command::Replicate(leader.map(own)),
+            Command::Install(snapshot) => Command::Install(own(snapshot)),
+            Command::RequestId(id) => Command::RequestId(own(id)),
+            Command::Hello(version, features) => Command::Hello(version, features.map(own)),
+            Command::WhoAmI => Command::WhoAmI,
+            Command::Quota => Command::Quota,
+            Command::DumpState => Command::DumpState,
+            Command::Debug(DebugCommand::Sleep(ms)) => Command::Debug(DebugCommand::Sleep(ms)),
+            Command::Debug(DebugCommand::Object(key)) => {
+                Command::Debug(DebugCommand::Object(own(key)))
+            }
+            Command::Debug(DebugCommand::SetLsn(lsn)) => Command::Debug(DebugCommand::SetLsn(lsn)),
+            Command::WhoIsLeader => Command::WhoIsLeader,
+            Command::Peers => Command::Peers,
+            Command::ReadOnly(on) => Command::ReadOnly(on),
+            Command::Client(ClientCommand::List) => Command::Client(ClientCommand::List),
+            Command::Client(ClientCommand::SetName(name)) => {
+                Command::Client(ClientCommand::SetName(own(name)))
+            }
+            Command::Client(ClientCommand::Kill(id)) => Command::Client(ClientCommand::Kill(id)),
+            Command::Client(ClientCommand::Tracking(on)) => {
+                Command::Client(ClientCommand::Tracking(on))
+            }
+            Command::Config(ConfigCommand::Get(name)) => {
+                Command::Config(ConfigCommand::Get(own(name)))
+            }
+            Command::Config(ConfigCommand::Set(name, val)) => {
+                Command::Config(ConfigCommand::Set(own(name), own(val)))
+            }
+            Command::Cluster(ClusterCommand::AddNode(addr)) => {
+                Command::Cluster(ClusterCommand::AddNode(own(addr)))
+            }
+            Command::Cluster(ClusterCommand::RemoveNode(addr)) => {
+                Command::Cluster(ClusterCommand::RemoveNode(own(addr)))
+            }
+            Command::TransferLeadership(addr) => Command::TransferLeadership(own(addr)),
+            Command::Analyze(top) => Command::Analyze(top),
+            Command::Export(format, prefix) => Command::Export(format, prefix.map(own)),
+            Command::BulkLoad => Command::BulkLoad,
+            Command::BulkLoadEnd => Command::BulkLoadEnd,
+            Command::Subscribe(channel) => Command::Subscribe(own(channel)),
+            Command::Unsubscribe(channel) => Command::Unsubscribe(own(channel)),
+            Command::PSubscribe(pattern) => Command::PSubscribe(own(pattern)),
+            Command::PUnsubscribe(pattern) => Command::PUnsubscribe(own(pattern)),
+            Command::Publish(channel, message) => Command::Publish(own(channel), own(message)),
+            Command::Tail(from) => Command::Tail(from),
+            Command::GetRange(key, start, end) => Command::GetRange(own(key), start, end),
+            Command::SetRange(key, offset, val) => Command::SetRange(own(key), offset, own(val)),
+            Command::SetBit(key, offset, bit) => Command::SetBit(own(key), offset, bit),
+            Command::GetBit(key, offset) => Command::GetBit(own(key), offset),
+            Command::GetAt(key, lsn) => Command::GetAt(own(key), lsn),
+            Command::GetWith(key, consistency) => Command::GetWith(own(key), consistency),
+            Command::GetVersion(key, lease) => Command::GetVersion(own(key), lease),
+            Command::History(key, limit) => Command::History(own(key), limit),
+            Command::BitCount(key, range) => Command::BitCount(own(key), range),
+            Command::PfAdd(key, element) => Command::PfAdd(own(key), own(element)),
+            Command::PfCount(key) => Command::PfCount(own(key)),
+            Command::PfMerge(dest, src) => Command::PfMerge(own(dest), own(src)),
+            Command::LPush(key, element) => Command::LPush(own(key), own(element)),
+            Command::RPush(key, element) => Command::RPush(own(key), own(element)),
+            Command::LPop(key) => Command::LPop(own(key)),
+            Command::LLen(key) => Command::LLen(own(key)),
+            Command::BLPop(key, timeout) => Command::BLPop(own(key), timeout),
+            Command::XAdd(key, id, data) => Command::XAdd(own(key), id, own(data)),
+            Command::XRead(key, after, count) => Command::XRead(own(key), after, count),
+            Command::ExpireAt(key, at) => Command::ExpireAt(own(key), at),
+            Command::PExpireAt(key, at) => Command::PExpireAt(own(key), at),
+            Command::Persist(key) => Command::Persist(own(key)),
+            Command::Ttl(key) => Command::Ttl(own(key)),
+            Command::Object(field, key) => Command::Object(field, own(key)),
+            Command::RandomKey => Command::RandomKey,
+            Command::Sample(n) => Command::Sample(n),
+        }
+    }
+
+    /// Whether the command works on keys, and so runs in the connection's
+    /// namespace. A `REQID` counts, as it is logged in the namespace of the
+    /// write it marks.
+    pub fn is_keyed(&self) -> bool {
+        !matches!(
+            self,
+            Command::Info(_)
+                | Command::Sync(..)
+                | Command::Replicate(_)
+                | Command::Hello(..)
+                | Command::WhoAmI
+                | Command::WhoIsLeader
+                | Command::Peers
+                | Command::ReadOnly(_)
+                | Command::Client(_)
+                | Command::Config(_)
+                | Command::Analyze(_)
+                | Command::Export(..)
+                | Command::BulkLoad
+                | Command::BulkLoadEnd
+                | Command::Quota
+                | Command::DumpState
+                | Command::Debug(_)
+                | Command::Cluster(_)
+                | Command::TransferLeadership(_)
+                | Command::Install(_)
+                | Command::Subscribe(_)
+                | Command::Unsubscribe(_)
+                | Command::PSubscribe(_)
+                | Command::PUnsubscribe(_)
+                | Command::Publish(..)
+                | Command::Tail(_)
+                | Command::Select(_)
+                | Command::Auth(..)
+                | Command::In(..)
+        )
+    }
+
+    /// Whether the command may change the map, and so has to be sent to the
+    /// leader.
+    pub fn is_write(&self) -> bool {
+        match self {
+            Command::In(_, command) => command.is_write(),
+            command => matches!(
+                command,
+                Command::Set(..)
+                    | Command::SetWith(..)
+                    | Command::Delete(_)
+                    | Command::DelIfEq(..)
+                    | Command::Lock(..)
+                    | Command::Unlock(..)
+                    | Command::Ephemeral(..)
+                    | Command::Restore(..)
+                    | Command::Batch(_)
+                    | Command::Eval(..)
+                    | Command::FCall(..)
+                    | Command::SetStream(_)
+                    | Command::SetRange(..)
+                    | Command::SetBit(..)
+                    | Command::PfAdd(..)
+                    | Command::PfMerge(..)
+                    | Command::LPush(..)
+                    | Command::RPush(..)
+                    | Command::LPop(_)
+                    | Command::BLPop(..)
+                    | Command::XAdd(..)
+                    | Command::ExpireAt(..)
+                    | Command::PExpireAt(..)
+                    | Command::Persist(_)
+                    | Command::BulkLoad
+                    | Command::BulkLoadEnd
+            ),
+        }
+    }
+
+    /// The keys the command reads or writes.
+    pub fn keys(&self) -> Vec<&[u8]> {
+        match self {
+            Command::Get(key)
+            | Command::Set(key, _)
+            | Command::SetWith(key, ..)
+            | Command::Delete(key)
+            | Command::DelIfEq(key, _)
+            | Command::Lock(key, _)
+            | Command::Unlock(key, _)
+            | Command::Ephemeral(key, _)
+            | Command::WaitChange(key, _)
+            | Command::Dump(key)
+            | Command::Restore(key, ..)
+            | Command::SetStream(key)
+            | Command::GetStream(key, _)
+            | Command::GetRange(key, ..)
+            | Command::SetRange(key, ..)
+            | Command::SetBit(key, ..)
+            | Command::GetBit(key, _)
+            | Command::GetAt(key, _)
+            | Command::GetWith(key, _)
+            | Command::GetVersion(key, _)
+            | Command::History(key, _)
+            | Command::BitCount(key, _)
+            | Command::PfAdd(key, _)
+            | Command::PfCount(key)
+            | Command::LPush(key, _)
+            | Command::RPush(key, _)
+            | Command::LPop(key)
+            | Command::LLen(key)
+            | Command::BLPop(key, _)
+            | Command::XAdd(key, ..)
+            | Command::XRead(key, ..)
+            | Command::ExpireAt(key, _)
+            | Command::PExpireAt(key, _)
+            | Command::Persist(key)
+            | Command::Ttl(key)
+            | Command::Object(_, key) => vec![key],
+            Command::PfMerge(dest, src) => vec![dest, src],
+            Command::Eval(_, keys, _) | Command::FCall(_, keys, _) => {
+                keys.iter().map(|key| &key[..]).collect()
+            }
+            Command::Batch(commands) => commands.iter().flat_map(Command::keys).collect(),
+            Command::In(_, command) => command.keys(),
+            Command::Select(_)
+            | Command::Auth(..)
+            | Command::Info(_)
+            | Command::Sync(..)
+            | Command::Replicate(_)
+            | Command::RequestId(_)
+            | Command::Hello(..)
+            | Command::WhoAmI
+            | Command::WhoIsLeader
+            | Command::Peers
+            | Command::ReadOnly(_)
+            | Command::Client(_)
+            | Command::Config(_)
+            | Command::Analyze(_)
+            | Command::Export(..)
+            | Command::RandomKey
+            | Command::Sample(_)
+            | Command::BulkLoad
+            | Command::BulkLoadEnd
+            | Command::Quota
+            | Command::DumpState
+            | Command::Debug(_)
+            | Command::Cluster(_)
+            | Command::TransferLeadership(_)
+            | Command::Install(_)
+            | Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::PSubscribe(_)
+            | Command::PUnsubscribe(_)
+            | Command::Publish(..)
+            | Command::Tail(_) => Vec::new(),
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Command::Get(_) | Command::GetAt(..) | Command::GetWith(..) => "GET",
+            Command::GetVersion(..) => "GETVERSION",
+            Command::History(..) => "HISTORY",
+            Command::Set(..) | Command::SetWith(..) => "SET",
+            Command::Delete(_) => "DEL",
+            Command::DelIfEq(..) => "DELIFEQ",
+            Command::Lock(..) => "LOCK",
+            Command::Ephemeral(..) => "EPHEMERAL",
+            Command::WaitChange(..) => "WAITCHANGE",
+            Command::Unlock(..) => "UNLOCK",
+            Command::Dump(_) => "DUMP",
+            Command::Restore(..) => "RESTORE",
+            Command::Batch(_) => "BATCH",
+            Command::Select(_) => "SELECT",
+            Command::Auth(..) => "AUTH",
+            Command::Eval(..) => "EVAL",
+            Command::FCall(..) => "FCALL",
+            Command::In(..) => "IN",
+            Command::SetStream(_) => "SETSTREAM",
+            Command::GetStream(..) => "GETSTREAM",
+            Command::Info(_) => "INFO",
+            Command::Sync(..) => "SYNC",
+            Command::Replicate(_) => "REPLICATE",
+            Command::Install(_) => "INSTALL",
+            Command::RequestId(_) => "REQID",
+            Command::Hello(..) => "HELLO",
+            Command::WhoAmI => "WHOAMI",
+            Command::Quota => "QUOTA",
+            Command::DumpState | Command::Debug(_) => "DEBUG",
+            Command::WhoIsLeader => "WHOISLEADER",
+            Command::Peers => "PEERS",
+            Command::ReadOnly(_) => "READONLY",
+            Command::Client(_) => "CLIENT",
+            Command::Config(_) => "CONFIG",
+            Command::Cluster(_) => "CLUSTER",
+            Command::TransferLeadership(_) => "TRANSFER",
+            Command::Analyze(_) => "ANALYZE",
+            Command::Export(..) => "EXPORT",
+            Command::BulkLoad | Command::BulkLoadEnd => "BULKLOAD",
+            Command::Subscribe(_) => "SUBSCRIBE",
+            Command::Unsubscribe(_) => "UNSUBSCRIBE",
+            Command::PSubscribe(_) => "PSUBSCRIBE",
+            Command::PUnsubscribe(_) => "PUNSUBSCRIBE",
+            Command::Publish(..) => "PUBLISH",
+            Command::Tail(_) => "TAIL",
+            Command::GetRange(..) => "GETRANGE",
+            Command::SetRange(..) => "SETRANGE",
+            Command::SetBit(..) => "SETBIT",
+            Command::GetBit(..) => "GETBIT",
+            Command::BitCount(..) => "BITCOUNT",
+            Command::PfAdd(..) => "PFADD",
+            Command::PfCount(_) => "PFCOUNT",
+            Command::PfMerge(..) => "PFMERGE",
+            Command::LPush(..) => "LPUSH",
+            Command::RPush(..) => "RPUSH",
+            Command::LPop(_) => "LPOP",
+            Command::LLen(_) => "LLEN",
+            Command::BLPop(..) => "BLPOP",
+            Command::XAdd(..) => "XADD",
+            Command::XRead(..) => "XREAD",
+            Command::ExpireAt(..) => "EXPIREAT",
+            Command::PExpireAt(..) => "PEXPIREAT",
+            Command::Persist(_) => "PERSIST",
+            Command::Ttl(_) => "TTL",
+            Command::Object(..) => "OBJECT",
+            Command::RandomKey => "RANDOMKEY",
+            Command::Sample(_) => "SAMPLE",
+        }
+    }
+
+    /// Appends the request form of the command to `buf`. This is also the
+    /// form mutating commands take in the log and the replication stream.
+    pub fn encode(&self, buf: &mut BytesMut) {
+        match self {
+            Command::Get(key) => encode_args(buf, b"GET", &[key]),
+            Command::Set(key, val) => encode_args(buf, b"SET", &[key, val]),
+            Command::SetWith(key, val, options) => {
+                let options = options.encode();
+                let mut args: Vec<&[u8]> = vec![key, val];
+                args.extend(options.iter().map(|option| option.as_bytes()));
+                encode_args(buf, b"SET", &args)
+            }
+            Command::Delete(key) => encode_args(buf, b"DEL", &[key]),
+            Command::DelIfEq(key, expected) => encode_args(buf, b"DELIFEQ", &[key, expected]),
+            Command::Ephemeral(key, val) => encode_args(buf, b"EPHEMERAL", &[key, val]),
+            Command::WaitChange(key, None) => encode_args(buf, b"WAITCHANGE", &[key]),
+            Command::WaitChange(key, Some(timeout)) => {
+                encode_args(buf, b"WAITCHANGE", &[key, timeout.to_string().as_bytes()])
+            }
+            Command::Lock(key, ttl) => {
+                encode_args(buf, b"LOCK", &[key, ttl.to_string().as_bytes()])
+            }
+            Command::Unlock(key, token) => {
+                encode_args(buf, b"UNLOCK", &[key, token.to_string().as_bytes()])
+            }
+            Command::Dump(key) => encode_args(buf, b"DUMP", &[key]),
+            Command::Restore(key, dump, false) => encode_args(buf, b"RESTORE", &[key, dump]),
+            Command::Restore(key, dump, true) => {
+                encode_args(buf, b"RESTORE", &[key, b"REPLACE", dump])
+            }
+            Command::Batch(commands) => {
+                let mut records = BytesMut::new();
+                for command in commands {
+                    command.encode(&mut records);
+                }
+                encode_args(buf, b"BATCH", &[&records])
+            }
+            Command::Select(name) => encode_args(buf, b"SELECT", &[name]),
+            Command::Auth(user, password) => encode_args(buf, b"AUTH", &[user, password]),
+            Command::Eval(target, keys, args) | Command::FCall(target, keys, args) => {
+                let numkeys = keys.len().to_string();
+                let mut all: Vec<&[u8]> = vec![target, numkeys.as_bytes()];
+                all.extend(keys.iter().chain(args).map(|arg| &arg[..]));
+                encode_args(buf, self.name().as_bytes(), &all);
+            }
+            Command::In(name, command) => {
+                let mut record = BytesMut::new();
+                command.encode(&mut record);
+                encode_args(buf, b"IN", &[name, &record])
+            }
+            Command::SetStream(key) => encode_args(buf, b"SETSTREAM", &[key]),
+            Command::GetStream(key, chunk) => {
+                let chunk = chunk.to_string();
+                encode_args(buf, b"GETSTREAM", &[key, chunk.as_bytes()])
+            }
+            Command::Info(Some(section)) => encode_args(buf, b"INFO", &[section]),
+            Command::Info(None) => encode_args(buf, b"INFO", &[]),
+            Command::Sync(resume, addr) => {
+                let resume = resume.map(|(id, offset)| (id.to_string(), offset.to_string()));
+                let mut args: Vec<&[u8]> = Vec::new();
+                if let Some((id, offset)) = &resume {
+                    args.extend([id.as_bytes(), offset.as_bytes()]);
+                }
+                args.extend(addr.as_deref());
+                encode_args(buf, b"SYNC", &args)
+            }
+            Command::Replicate(Some(leader)) => encode_args(buf, b"REPLICATE", &[leader]),
+            Command::Replicate(None) => encode_args(buf, b"REPLICATE", &[]),
+            Command::Install(snapshot) => encode_args(buf, b"INSTALL", &[snapshot]),
+            Command::RequestId(id) => encode_args(buf, b"REQID", &[id]),
+            Command::WhoAmI => encode_args(buf, b"WHOAMI", &[]),
+            Command::Quota => encode_args(buf, b"QUOTA", &[]),
+            Command::DumpState => encode_args(buf, b"DEBUG", &[b"DUMPSTATE"]),
+            Command::Debug(DebugCommand::Sleep(ms)) => {
+                let ms = ms.to_string();
+                encode_args(buf, b"DEBUG", &[b"SLEEP", ms.as_bytes()])
+            }
+            Command::Debug(DebugCommand::Object(key)) => {
+                encode_args(buf, b"DEBUG", &[b"OBJECT", key])
+            }
+            Command::Debug(DebugCommand::SetLsn(lsn)) => {
+                let lsn = lsn.to_string();
+                encode_args(buf, b"DEBUG", &[b"SET-LSN", lsn.as_bytes()])
+            }
+            Command::WhoIsLeader => encode_args(buf, b"WHOISLEADER", &[]),
+            Command::Peers => encode_args(buf, b"PEERS", &[]),
+            Command::ReadOnly(true) => encode_args(buf, b"READONLY", &[b"ON"]),
+            Command::ReadOnly(false) => encode_args(buf, b"READONLY", &[b"OFF"]),
+            Command::Hello(version, features) => {
+                let version = version.to_string();
+                match features {
+                    Some(features) => encode_args(buf, b"HELLO", &[version.as_bytes(), features]),
+                    None => encode_args(buf, b"HELLO", &[version.as_bytes()]),
+                }
+            }
+            Command::Client(ClientCommand::List) => encode_args(buf, b"CLIENT", &[b"LIST"]),
+            Command::Client(ClientCommand::SetName(name)) => {
+                encode_args(buf, b"CLIENT", &[b"SETNAME", name])
+            }
+            Command::Client(ClientCommand::Kill(id)) => {
+                let id = id.to_string();
+                encode_args(buf, b"CLIENT", &[b"KILL", id.as_bytes()])
+            }
+            Command::Client(ClientCommand::Tracking(on)) => {
+                let mode: &[u8] = if *on { b"ON" } else { b"OFF" };
+                encode_args(buf, b"CLIENT", &[b"TRACKING", mode])
+            }
+            Command::Config(ConfigCommand::Get(name)) => {
+                encode_args(buf, b"CONFIG", &[b"GET", name])
+            }
+            Command::Config(ConfigCommand::Set(name, val)) => {
+                encode_args(buf, b"CONFIG", &[b"SET", name, val])
+            }
+            Command::Cluster(ClusterCommand::AddNode(addr)) => {
+                encode_args(buf, b"CLUSTER", &[b"ADD-NODE", addr])
+            }
+            Command::Cluster(ClusterCommand::RemoveNode(addr)) => {
+                encode_args(buf, b"CLUSTER", &[b"REMOVE-NODE", addr])
+            }
+            Command::TransferLeadership(addr) => {
+                encode_args(buf, b"TRANSFER", &[b"LEADERSHIP", addr])
+            }
+            Command::Analyze(top) => {
+                let top = top.to_string();
+                encode_args(buf, b"ANALYZE", &[top.as_bytes()])
+            }
+            Command::BulkLoad => encode_args(buf, b"BULKLOAD", &[]),
+            Command::BulkLoadEnd => encode_args(buf, b"BULKLOAD", &[b"END"]),
+            Command::Export(format, None) => {
+                encode_args(buf, b"EXPORT", &[format.as_str().as_bytes()])
+            }
+            Command::Export(format, Some(prefix)) => {
+                let format = format.as_str().as_bytes();
+                encode_args(buf, b"EXPORT", &[format, b"PREFIX", prefix])
+            }
+            Command::Subscribe(channel) => encode_args(buf, b"SUBSCRIBE", &[channel]),
+            Command::Unsubscribe(channel) => encode_args(buf, b"UNSUBSCRIBE", &[channel]),
+            Command::PSubscribe(pattern) => encode_args(buf, b"PSUBSCRIBE", &[pattern]),
+            Command::PUnsubscribe(pattern) => encode_args(buf, b"PUNSUBSCRIBE", &[pattern]),
+            Command::Publish(channel, message) => encode_args(buf, b"PUBLISH", &[channel, message]),
+            Command::Tail(from) => {
+                let from = from.to_string();
+                encode_args(buf, b"TAIL", &[from.as_bytes()])
+            }
+            Command::GetRange(key, start, end) => {
+                let (start, end) = (start.to_string(), end.to_string());
+                encode_args(buf, b"GETRANGE", &[key, start.as_bytes(), end.as_bytes()])
+            }
+            Command::SetRange(key, offset, val) => {
+                let offset = offset.to_string();
+                encode_args(buf, b"SETRANGE", &[key, offset.as_bytes(), val])
+            }
+            Command::SetBit(key, offset, bit) => {
+                let offset = offset.to_string();
+                let bit: &[u8] = if *bit { b"1" } else { b"0" };
+                encode_args(buf, b"SETBIT", &[key, offset.as_bytes(), bit])
+            }
+            Command::GetBit(key, offset) => {
+                let offset = offset.to_string();
+                encode_args(buf, b"GETBIT", &[key, offset.as_bytes()])
+            }
+            Command::GetAt(key, lsn) => {
+                let lsn = lsn.to_string();
+                encode_args(buf, b"GET", &[key, b"AT", lsn.as_bytes()])
+            }
+            Command::GetWith(key, consistency) => {
+                encode_args(buf, b"GET", &[key, consistency.as_str().as_bytes()])
+            }
+            Command::GetVersion(key, None) => encode_args(buf, b"GETVERSION", &[key]),
+            Command::GetVersion(key, Some(ms)) => {
+                let ms = ms.to_string();
+                encode_args(buf, b"GETVERSION", &[key, b"LEASE", ms.as_bytes()])
+            }
+            Command::History(key, None) => encode_args(buf, b"HISTORY", &[key]),
+            Command::History(key, Some(limit)) => {
+                let limit = limit.to_string();
+                encode_args(buf, b"HISTORY", &[key, limit.as_bytes()])
+            }
+            Command::BitCount(key, None) => encode_args(buf, b"BITCOUNT", &[key]),
+            Command::BitCount(key, Some((start, end))) => {
+                let (start, end) = (start.to_string(), end.to_string());
+                encode_args(buf, b"BITCOUNT", &[key, start.as_bytes(), end.as_bytes()])
+            }
+            Command::PfAdd(key, element) => encode_args(buf, b"PFADD", &[key, element]),
+            Command::PfCount(key) => encode_args(buf, b"PFCOUNT", &[key]),
+            Command::PfMerge(dest, src) => encode_args(buf, b"PFMERGE", &[dest, src]),
+            Command::LPush(key, element) => encode_args(buf, b"LPUSH", &[key, element]),
+            Command::RPush(key, element) => encode_args(buf, b"RPUSH", &[key, element]),
+            Command::LPop(key) => encode_args(buf, b"LPOP", &[key]),
+            Command::LLen(key) => encode_args(buf, b"LLEN", &[key]),
+            Command::BLPop(key, None) => encode_args(buf, b"BLPOP", &[key]),
+            Command::BLPop(key, Some(timeout)) => {
+                encode_args(buf, b"BLPOP", &[key, timeout.to_string().as_bytes()])
+            }
+            Command::XAdd(key, None, data) => encode_args(buf, b"XADD", &[key, b"*", data]),
+            Command::XAdd(key, Some(id), data) => {
+                encode_args(buf, b"XADD", &[key, id.to_string().as_bytes(), data])
+            }
+            Command::XRead(key, after, None) => {
+                encode_args(buf, b"XREAD", &[key, after.to_string().as_bytes()])
+            }
+            Command::XRead(key, after, Some(count)) => {
+                let (after, count) = (after.to_string(), count.to_string());
+                encode_args(buf, b"XREAD", &[key, after.as_bytes(), count.as_bytes()])
+            }
+            Command::ExpireAt(key, at) => {
+                let at = at.to_string();
+                encode_args(buf, b"EXPIREAT", &[key, at.as_bytes()])
+            }
+            Command::PExpireAt(key, at) => {
+                let at = at.to_string();
+                encode_args(buf, b"PEXPIREAT", &[key, at.as_bytes()])
+            }
+            Command::Persist(key) => encode_args(buf, b"PERSIST", &[key]),
+            Command::Ttl(key) => encode_args(buf, b"TTL", &[key]),
+            Command::Object(field, key) => {
+                encode_args(buf, b"OBJECT", &[field.as_str().as_bytes(), key])
+            }
+            Command::RandomKey => encode_args(buf, b"RANDOMKEY", &[]),
+            Command::Sample(n) => encode_args(buf, b"SAMPLE", &[n.to_string().as_bytes()]),
+        }
+    }
+}
+
+/// Parses the records a `BATCH` carries, refusing anything but `SET` and
+/// `DEL`.
+fn parse_batch(records: &[u8]) -> Result<Vec<Command<'static>>, ParseError> {
+    let commands = parse_all(records, &Limits::NONE)?;
+    let writes = commands
+        .iter()
+        .all(|command| matches!(command, Command::Set(..) | Command::Delete(_)));
+    if !writes {
+        return Err(ParseError::InvalidBatch);
+    }
+    Ok(commands)
+}
+
+fn parse_stream_id(arg: &[u8]) -> Result<StreamId, ParseError> {
+    StreamId::parse(arg).ok_or(ParseError::InvalidStreamId)
+}
+
+fn parse_int<T: std::str::FromStr>(arg: &[u8]) -> Result<T, ParseError> {
+    std::str::from_utf8(arg)
+        .ok()
+        .and_then(|arg| arg.parse().ok())
+        .ok_or(ParseError::NotAnInteger)
+}
+
+/// Splits a request line into whitespace separated tokens. A token wrapped in
+/// double quotes may contain whitespace and the escapes `\"`, `\\`, `\n`, `\r`,
+/// `\t` and `\xHH`; only tokens that actually contain escapes are copied.
+struct Tokens<'a> {
+    line: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Tokens<'a> {
+    fn quoted(&mut self) -> Result<Cow<'a, [u8]>, ParseError> {
+        let start = self.pos;
+        let mut owned: Option<Vec<u8>> = None;
+        loop {
+            let b = *self
+                .line
+                .get(self.pos)
+                .ok_or(ParseError::UnbalancedQuotes)?;
+            self.pos += 1;
+            match b {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = *self.line.get(self.pos).ok_or(ParseError::InvalidEscape)?;
+                    self.pos += 1;
+                    let unescaped = match escaped {
+                        b'"' | b'\\' => escaped,
+                        b'n' => b'\n',
+                        b'r' => b'\r',
+                        b't' => b'\t',
+                        b'x' => {
+                            let hex = self
+                                .line
+                                .get(self.pos..self.pos + 2)
+                                .and_then(|hex| std::str::from_utf8(hex).ok())
+                                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                                .ok_or(ParseError::InvalidEscape)?;
+                            self.pos += 2;
+                            hex
+                        }
+                        _ => return Err(ParseError::InvalidEscape),
+                    };
+                    let escape_len = if escaped == b'x' { 4 } else { 2 };
+                    owned
+                        .get_or_insert_with(|| self.line[start..self.pos - escape_len].to_vec())
+                        .push(unescaped);
+                }
+                _ => {
+                    if let Some(owned) = owned.as_mut() {
+                        owned.push(b);
+                    }
+                }
+            }
+        }
+        // A closing quote has to end the token.
+        if self
+            .line
+            .get(self.pos)
+            .is_some_and(|b| !b.is_ascii_whitespace())
+        {
+            return Err(ParseError::UnbalancedQuotes);
+        }
+        Ok(match owned {
+            Some(owned) => Cow::Owned(owned),
+            None => Cow::Borrowed(&self.line[start..self.pos - 1]),
+        })
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Result<Cow<'a, [u8]>, ParseError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.line.get(self.pos)?.is_ascii_whitespace() {
+            self.pos += 1;
+        }
+        if self.line[self.pos] == b'"' {
+            self.pos += 1;
+            return Some(self.quoted());
+        }
+        let start = self.pos;
+        while self
+            .line
+            .get(self.pos)
+            .is_some_and(|b| !b.is_ascii_whitespace())
+        {
+            self.pos += 1;
+        }
+        Some(Ok(Cow::Borrowed(&self.line[start..self.pos])))
+    }
+}
+
+/// Writes a request line. The final argument is sent with bulk framing when
+/// it is large or binary, everything else as a (possibly quoted) token.
+fn encode_args(buf: &mut BytesMut, name: &[u8], args: &[&[u8]]) {
+    buf.put_slice(name);
+    for (i, arg) in args.iter().enumerate() {
+        buf.put_u8(b' ');
+        if i + 1 == args.len() && (arg.len() >= BULK_THRESHOLD || !arg.is_ascii()) {
+            buf.put_slice(format!("${}\n", arg.len()).as_bytes());
+            buf.put_slice(arg);
+        } else {
+            encode_token(buf, arg);
+        }
+    }
+    buf.put_u8(b'\n');
+}
+
+/// Writes `token` so that `Tokens` reads it back unchanged, quoting it only
+/// when it is empty, could be mistaken for a `$<len>` marker, or contains
+/// whitespace, quotes or unprintable bytes.
+fn encode_token(buf: &mut BytesMut, token: &[u8]) {
+    let needs_quotes = token.is_empty()
+        || token.starts_with(b"$")
+        || token
+            .iter()
+            .any(|b| !b.is_ascii_graphic() || *b == b'"' || *b == b'\\');
+    if !needs_quotes {
+        buf.put_slice(token);
+        return;
+    }
+    buf.put_u8(b'"');
+    for &b in token {
+        match b {
+            b'"' | b'\\' => buf.put_slice(&[b'\\', b]),
+            b'\n' => buf.put_slice(b"\\n"),
+            b'\r' => buf.put_slice(b"\\r"),
+            b'\t' => buf.put_slice(b"\\t"),
+            b' ' => buf.put_u8(b),
+            _ if b.is_ascii_graphic() => buf.put_u8(b),
+            _ => buf.put_slice(format!("\\x{:02x}", b).as_bytes()),
+        }
+    }
+    buf.put_u8(b'"');
+}
+
+/// Splits the next complete line off the front of `buf`, without its line
+/// terminator. Returns `None` until a full line has been buffered.
+pub fn split_line(buf: &mut BytesMut) -> Option<BytesMut> {
+    let end = buf.iter().position(|b| *b == b'\n')?;
+    let mut line = buf.split_to(end + 1);
+    line.truncate(end);
+    if line.last() == Some(&b'\r') {
+        line.truncate(end - 1);
+    }
+    Some(line)
+}
+
+/// A complete request: the command line and, when the line ended in a
+/// `$<len>` marker, the raw bulk payload that followed it.
+#[derive(Debug)]
+pub struct Frame {
+    pub line: BytesMut,
+    pub payload: Option<BytesMut>,
+}
+
+impl Frame {
+    /// Returns the payload if this frame is a bare `$<len>` chunk.
+    pub fn chunk(&self) -> Option<&BytesMut> {
+        self.payload.as_ref().filter(|_| self.line.is_empty())
+    }
+
+    pub fn command(&self, limits: &Limits) -> Result<Command<'_>, ParseError> {
+        let command = match &self.payload {
+            Some(payload) => Command::parse_bulk(&self.line, payload)?,
+            None => Command::parse(&self.line)?,
+        };
+        limits.check(&command)?;
+        Ok(command)
+    }
+}
+
+/// Returns where the `$<len>` marker starts and the announced length if
+/// `line` ends with one. A quoted `"$5"` is an ordinary value.
+fn bulk_marker(line: &[u8]) -> Option<(usize, usize)> {
+    let start = line
+        .iter()
+        .rposition(|b| b.is_ascii_whitespace())
+        .map_or(0, |end| end + 1);
+    let digits = line[start..].strip_prefix(b"$")?;
+    if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let len = std::str::from_utf8(digits).ok()?.parse().ok()?;
+    Some((start, len))
+}
+
+/// Splits the next complete frame off the front of `buf`. A line ending in
+/// `$<len>` is followed by exactly `<len>` payload bytes and a line break, so
+/// large or binary values never need escaping. A line holding nothing but the
+/// marker is a bare chunk, as sent after `SETSTREAM`. Returns `Ok(None)` until
+/// the whole frame has been buffered.
+///
+/// Lines and payloads that could never fit within `limits` are rejected as
+/// soon as that is known. The rest of the stream can't be framed after that,
+/// so callers should reply with the error and drop the connection.
+pub fn split_frame(buf: &mut BytesMut, limits: &Limits) -> Result<Option<Frame>, ParseError> {
+    let Some(end) = buf.iter().position(|b| *b == b'\n') else {
+        if buf.len() > limits.max_line() {
+            return Err(ParseError::ValueTooLarge {
+                len: buf.len(),
+                max: limits.max_line(),
+            });
+        }
+        return Ok(None);
+    };
+    let line = buf[..end].strip_suffix(b"\r").unwrap_or(&buf[..end]);
+    let Some((marker, len)) = bulk_marker(line) else {
+        return Ok(split_line(buf).map(|line| Frame {
+            line,
+            payload: None,
+        }));
+    };
+    limits.check_value(len)?;
+    let payload_start = end + 1;
+    let Some(payload_end) = payload_start.checked_add(len) else {
+        return Err(ParseError::InvalidBulk);
+    };
+    let terminator_len = match buf.get(payload_end..) {
+        None | Some([]) | Some([b'\r']) => return Ok(None),
+        Some([b'\n', ..]) => 1,
+        Some([b'\r', b'\n', ..]) => 2,
+        Some(_) => return Err(ParseError::InvalidBulk),
+    };
+    let mut line = buf.split_to(payload_start);
+    line.truncate(marker);
+    let payload = buf.split_to(len);
+    buf.advance(terminator_len);
+    Ok(Some(Frame {
+        line,
+        payload: Some(payload),
+    }))
+}
+
+/// Parses every complete request in `buf`, the inverse of encoding a
+/// sequence of commands back to back. A trailing partial request is ignored.
+pub fn parse_all(buf: &[u8], limits: &Limits) -> Result<Vec<Command<'static>>, ParseError> {
+    let mut buf = BytesMut::from(buf);
+    let mut commands = Vec::new();
+    while let Some(frame) = split_frame(&mut buf, limits)? {
+        match frame.command(limits) {
+            Ok(command) => commands.push(command.into_owned()),
+            Err(ParseError::Empty) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(commands)
+}
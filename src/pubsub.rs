@@ -0,0 +1,278 @@
+//! Publish/subscribe between client connections, and keyspace notifications
+//! published by the leader's writes.
+//!
+//! A subscribed connection is sent each message as a push: `>3` followed by
+//! the bulk strings `message`, the channel and the payload, or `>4` with
+//! `pmessage`, the pattern, the channel and the payload when it matched a
+//! pattern subscription. Every write to `key` is published on
+//! `__keyspace__:<key>` with the command's name as the payload, or on
+//! `__keyspace@<namespace>__:<key>` for a namespace other than the default.
+
+use std::collections::HashMap;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use tokio::sync::mpsc::UnboundedSender;
+
+use crate::protocol::Command;
+use crate::store::DEFAULT_NAMESPACE;
+
+/// The channel prefix keyspace notifications are published under.
+pub const KEYSPACE_PREFIX: &[u8] = b"__keyspace__:";
+
+/// Where a connection's pushes are sent, tagged with its client id.
+type Subscriber = (u64, UnboundedSender<Bytes>);
+
+#[derive(Default)]
+pub struct PubSub {
+    channels: HashMap<Bytes, Vec<Subscriber>>,
+    patterns: HashMap<Bytes, Vec<Subscriber>>,
+}
+
+fn add(map: &mut HashMap<Bytes, Vec<Subscriber>>, name: &[u8], subscriber: Subscriber) {
+    let subscribers = map.entry(Bytes::copy_from_slice(name)).or_default();
+    if !subscribers.iter().any(|(id, _)| *id == subscriber.0) {
+        subscribers.push(subscriber);
+    }
+}
+
+fn remove(map: &mut HashMap<Bytes, Vec<Subscriber>>, name: &[u8], id: u64) {
+    if let Some(subscribers) = map.get_mut(name) {
+        subscribers.retain(|(subscriber, _)| *subscriber != id);
+        if subscribers.is_empty() {
+            map.remove(name);
+        }
+    }
+}
+
+fn count(map: &HashMap<Bytes, Vec<Subscriber>>, id: u64) -> usize {
+    map.values()
+        .filter(|subscribers| subscribers.iter().any(|(subscriber, _)| *subscriber == id))
+        .count()
+}
+
+/// Encodes `items` as a push of bulk strings.
+pub fn push(items: &[&[u8]]) -> Bytes {
+    let mut buf = BytesMut::new();
+    buf.put_slice(format!(">{}\n", items.len()).as_bytes());
+    for item in items {
+        buf.put_slice(format!("${}\n", item.len()).as_bytes());
+        buf.put_slice(item);
+        buf.put_u8(b'\n');
+    }
+    buf.freeze()
+}
+
+impl PubSub {
+    /// Subscribes client `id` to `channel`, returning how many channels and
+    /// patterns it is now subscribed to.
+    pub fn subscribe(&mut self, channel: &[u8], id: u64, tx: UnboundedSender<Bytes>) -> usize {
+        add(&mut self.channels, channel, (id, tx));
+        self.subscriptions(id)
+    }
+
+    pub fn unsubscribe(&mut self, channel: &[u8], id: u64) -> usize {
+        remove(&mut self.channels, channel, id);
+        self.subscriptions(id)
+    }
+
+    /// Subscribes client `id` to every channel matching the glob `pattern`.
+    pub fn psubscribe(&mut self, pattern: &[u8], id: u64, tx: UnboundedSender<Bytes>) -> usize {
+        add(&mut self.patterns, pattern, (id, tx));
+        self.subscriptions(id)
+    }
+
+    pub fn punsubscribe(&mut self, pattern: &[u8], id: u64) -> usize {
+        remove(&mut self.patterns, pattern, id);
+        self.subscriptions(id)
+    }
+
+    /// Drops every subscription of a connection that has gone away.
+    pub fn unsubscribe_all(&mut self, id: u64) {
+        for map in [&mut self.channels, &mut self.patterns] {
+            map.retain(|_, subscribers| {
+                subscribers.retain(|(subscriber, _)| *subscriber != id);
+                !subscribers.is_empty()
+            });
+        }
+    }
+
+    /// How many channels and how many patterns client `id` is subscribed to.
+    pub fn counts(&self, id: u64) -> (usize, usize) {
+        (count(&self.channels, id), count(&self.patterns, id))
+    }
+
+    fn subscriptions(&self, id: u64) -> usize {
+        let (channels, patterns) = self.counts(id);
+        channels + patterns
+    }
+
+    /// Sends `message` to the subscribers of `channel` and of every pattern
+    /// matching it, returning how many pushes were sent.
+    pub fn publish(&self, channel: &[u8], message: &[u8]) -> usize {
+        let mut sent = 0;
+        if let Some(subscribers) = self.channels.get(channel) {
+            let push = push(&[b"message", channel, message]);
+            for (_, tx) in subscribers {
+                sent += usize::from(tx.send(push.clone()).is_ok());
+            }
+        }
+        for (pattern, subscribers) in &self.patterns {
+            if glob_match(pattern, channel) {
+                let push = push(&[b"pmessage", pattern, channel, message]);
+                for (_, tx) in subscribers {
+                    sent += usize::from(tx.send(push.clone()).is_ok());
+                }
+            }
+        }
+        sent
+    }
+
+    /// Publishes a keyspace notification for a write that changed the map,
+    /// one for each write of a batch.
+    pub fn notify_keyspace(&self, command: &Command<'_>) {
+        if self.channels.is_empty() && self.patterns.is_empty() {
+            return;
+        }
+        for KeyEvent {
+            namespace,
+            key,
+            event,
+        } in key_events(command)
+        {
+            let mut channel = BytesMut::new();
+            match namespace {
+                DEFAULT_NAMESPACE => channel.extend_from_slice(KEYSPACE_PREFIX),
+                namespace => {
+                    channel.extend_from_slice(b"__keyspace@");
+                    channel.extend_from_slice(namespace);
+                    channel.extend_from_slice(b"__:");
+                }
+            }
+            channel.extend_from_slice(key);
+            self.publish(&channel, event.as_bytes());
+        }
+    }
+}
+
+/// A change a write made to one key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEvent<'a> {
+    pub namespace: &'a [u8],
+    pub key: &'a [u8],
+    /// The name of the command that made it, in lower case.
+    pub event: String,
+}
+
+/// The keys a write that changed the map changed, one for each write of a
+/// batch.
+pub fn key_events<'a>(command: &'a Command<'_>) -> Vec<KeyEvent<'a>> {
+    let mut events = Vec::new();
+    add_key_events(&mut events, DEFAULT_NAMESPACE, command);
+    events
+}
+
+fn add_key_events<'a>(
+    events: &mut Vec<KeyEvent<'a>>,
+    namespace: &'a [u8],
+    command: &'a Command<'_>,
+) {
+    let key = match command {
+        Command::Batch(commands) => {
+            for command in commands {
+                add_key_events(events, namespace, command);
+            }
+            return;
+        }
+        Command::In(name, command) => return add_key_events(events, name, command),
+        Command::Set(key, _)
+        | Command::SetWith(key, ..)
+        | Command::Delete(key)
+        | Command::DelIfEq(key, _)
+        | Command::Lock(key, _)
+        | Command::Unlock(key, _)
+        | Command::Ephemeral(key, _)
+        | Command::SetRange(key, ..)
+        | Command::SetBit(key, ..)
+        | Command::PfAdd(key, _)
+        | Command::PfMerge(key, _)
+        | Command::LPush(key, _)
+        | Command::RPush(key, _)
+        | Command::LPop(key)
+        | Command::XAdd(key, ..)
+        | Command::ExpireAt(key, _)
+        | Command::PExpireAt(key, _)
+        | Command::Persist(key) => key,
+        _ => return,
+    };
+    events.push(KeyEvent {
+        namespace,
+        key,
+        event: command.name().to_ascii_lowercase(),
+    });
+}
+
+/// Matches `subject` against a glob: `*` matches any run of bytes, `?` any
+/// one byte, `[abc]`, `[^abc]` and `[a-z]` one byte from a set, and `\`
+/// escapes the byte after it.
+pub fn glob_match(pattern: &[u8], subject: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // Where to resume after the last `*` if the rest fails to match.
+    let mut backtrack = None;
+    while s < subject.len() {
+        let step = match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, s));
+                p += 1;
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => match_class(&pattern[p..], subject[s]),
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == subject[s]).then_some(2),
+            Some(&b) => (b == subject[s]).then_some(1),
+            None => None,
+        };
+        match (step, backtrack) {
+            (Some(len), _) => {
+                p += len;
+                s += 1;
+            }
+            (None, Some((star, from))) => {
+                p = star + 1;
+                s = from + 1;
+                backtrack = Some((star, from + 1));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|b| *b == b'*')
+}
+
+/// Matches `b` against the `[...]` class at the start of `pattern`,
+/// returning the class's length if it matches. An unterminated class
+/// matches a literal `[`.
+fn match_class(pattern: &[u8], b: u8) -> Option<usize> {
+    let Some(end) = pattern
+        .iter()
+        .skip(2)
+        .position(|c| *c == b']')
+        .map(|i| i + 2)
+    else {
+        return (b == b'[').then_some(1);
+    };
+    let (negated, class) = match pattern[1] {
+        b'^' => (true, &pattern[2..end]),
+        _ => (false, &pattern[1..end]),
+    };
+    let mut matched = false;
+    let mut i = 0;
+    while i < class.len() {
+        if i + 2 < class.len() && class[i + 1] == b'-' {
+            matched |= (class[i]..=class[i + 2]).contains(&b);
+            i += 3;
+        } else {
+            matched |= class[i] == b;
+            i += 1;
+        }
+    }
+    (matched != negated).then_some(end + 1)
+}
@@ -0,0 +1,414 @@
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::sync::{mpsc, oneshot, Semaphore, TryAcquireError};
+use tokio::task::JoinHandle;
+
+use crate::client::{split_reply, Hello, Reply};
+use crate::error::{bail, Context, DistKvError, Result};
+use crate::failpoint::{self, Action};
+use crate::lz4::FrameEncoder;
+use crate::node;
+use crate::protocol::{Command, PROTOCOL_VERSION};
+
+/// When a replication stream writes out the records it is sent: once
+/// `max_bytes` of them are buffered, and otherwise when the leader flushes
+/// its streams every `max_delay`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Batching {
+    pub max_bytes: usize,
+    pub max_delay: Duration,
+}
+
+impl Batching {
+    /// Every record is written as soon as it is sent.
+    pub const OFF: Batching = Batching {
+        max_bytes: 0,
+        max_delay: Duration::ZERO,
+    };
+
+    pub fn is_off(&self) -> bool {
+        self.max_delay.is_zero()
+    }
+}
+
+/// The leader's end of a replication connection.
+pub struct ReplicaStream<W> {
+    stream: W,
+    /// A record held back by the `replication::send` reorder failpoint.
+    held: Option<BytesMut>,
+    batching: Batching,
+    /// Records sent but not yet written.
+    pending: BytesMut,
+    /// Set once the follower has agreed to take the stream compressed.
+    compression: Option<FrameEncoder>,
+}
+
+impl<W: AsyncWrite + Unpin> ReplicaStream<W> {
+    /// A stream that writes every record as it is sent, until told to
+    /// batch them with [`ReplicaStream::set_batching`].
+    pub fn new(stream: W) -> Self {
+        ReplicaStream {
+            stream,
+            held: None,
+            batching: Batching::OFF,
+            pending: BytesMut::new(),
+            compression: None,
+        }
+    }
+
+    pub fn set_batching(&mut self, batching: Batching) {
+        self.batching = batching;
+    }
+
+    /// Writes what follows as an LZ4 frame, a block per write.
+    pub fn compress(&mut self) {
+        self.compression = Some(FrameEncoder::new());
+    }
+
+    pub async fn send(&mut self, record: &[u8]) -> io::Result<()> {
+        match failpoint::eval("replication::send") {
+            Some(Action::Drop) => return Ok(()),
+            Some(Action::Crash) => {
+                self.pending.extend_from_slice(&record[..record.len() / 2]);
+                self.flush().await?;
+                self.stream.flush().await?;
+                failpoint::crash();
+            }
+            Some(Action::Delay(delay)) => tokio::time::sleep(delay).await,
+            Some(Action::Duplicate) => self.pending.extend_from_slice(record),
+            Some(Action::Reorder) if self.held.is_none() => {
+                self.held = Some(BytesMut::from(record));
+                return Ok(());
+            }
+            _ => {}
+        }
+        self.pending.extend_from_slice(record);
+        if let Some(held) = self.held.take() {
+            self.pending.extend_from_slice(&held);
+        }
+        if self.batching.is_off() || self.pending.len() >= self.batching.max_bytes {
+            self.flush().await?;
+        }
+        Ok(())
+    }
+
+    /// Writes out the records sent since the last write.
+    pub async fn flush(&mut self) -> io::Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        match &mut self.compression {
+            Some(encoder) => {
+                let mut block = BytesMut::new();
+                encoder.encode(&self.pending, &mut block);
+                self.stream.write_all(&block).await?;
+            }
+            None => self.stream.write_all(&self.pending).await?,
+        }
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Sends what the follower needs before the records, which no
+    /// failpoint interferes with.
+    pub async fn introduce(&mut self, handshake: &[u8]) -> io::Result<()> {
+        self.flush().await?;
+        self.stream.write_all(handshake).await
+    }
+
+    pub async fn shutdown(&mut self) -> io::Result<()> {
+        if let Some(held) = self.held.take() {
+            self.pending.extend_from_slice(&held);
+        }
+        self.flush().await?;
+        if let Some(encoder) = &mut self.compression {
+            let mut end = BytesMut::new();
+            encoder.finish(&mut end);
+            self.stream.write_all(&end).await?;
+        }
+        self.stream.shutdown().await
+    }
+}
+
+impl<W: AsyncRead + AsyncWrite + Unpin> ReplicaStream<W> {
+    /// Asks the follower whether it takes a compressed stream, see
+    /// [`offer_lz4`].
+    pub async fn offer_lz4(&mut self) -> Result<bool> {
+        offer_lz4(&mut self.stream, &mut BytesMut::new()).await
+    }
+}
+
+/// Offers the other end of a replication connection `lz4` with `HELLO` and
+/// returns whether it agreed, reading its reply through `buf`. Either end
+/// may offer it: the leader before `REPLICATE`, a follower before `SYNC`.
+/// What the leader sends after that is then compressed.
+pub async fn offer_lz4<S>(stream: &mut S, buf: &mut BytesMut) -> Result<bool>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut request = BytesMut::new();
+    Command::Hello(PROTOCOL_VERSION, Some(b"lz4"[..].into())).encode(&mut request);
+    stream.write_all(&request).await?;
+    loop {
+        match split_reply(buf)? {
+            Some(Reply::Bulk(Some(reply))) => {
+                let hello = Hello::parse(&reply)?;
+                return Ok(hello.features.iter().any(|feature| feature == "lz4"));
+            }
+            // Predates HELLO, so it predates compression too.
+            Some(Reply::Error(_)) => return Ok(false),
+            Some(reply) => bail!(Protocol, "unexpected reply to HELLO: {:?}", reply),
+            None => {}
+        }
+        if stream.read_buf(buf).await? == 0 {
+            return Err(DistKvError::link_down(
+                "connection closed before replying to HELLO",
+            ));
+        }
+    }
+}
+
+/// What a follower's queue does with a record there's no room for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Overflow {
+    /// Waits for the follower to make room, holding up the leader's writes
+    /// until it does.
+    Block,
+    /// Writes the record to a file, to be sent once the follower has taken
+    /// the records ahead of it.
+    Spill,
+    /// Drops the follower, which has to sync again to catch up.
+    Drop,
+}
+
+/// How a follower's queue is bounded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueLimit {
+    pub max_bytes: usize,
+    pub overflow: Overflow,
+    /// Where [`Overflow::Spill`] writes its files.
+    pub spill_dir: PathBuf,
+}
+
+enum Message {
+    /// A record in memory, holding this many of the queue's permits.
+    Record(Bytes, u32),
+    /// A record of this many bytes, next in the spill file.
+    Spilled(Arc<Mutex<Spill>>, usize),
+    Flush,
+    Shutdown(oneshot::Sender<io::Result<()>>),
+}
+
+/// Records written past a full queue, read back in the order they were
+/// written. The file is emptied whenever it has all been read, and removed
+/// with the queue.
+struct Spill {
+    file: File,
+    path: PathBuf,
+    written: u64,
+    read: u64,
+}
+
+impl Spill {
+    fn create(dir: &Path) -> Result<Spill> {
+        static SPILLS: AtomicU64 = AtomicU64::new(0);
+        let n = SPILLS.fetch_add(1, Ordering::Relaxed);
+        let path = dir.join(format!("replication-{}-{}.spill", process::id(), n));
+        let file = File::options()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)
+            .with_context(|| format!("creating {}", path.display()))?;
+        Ok(Spill {
+            file,
+            path,
+            written: 0,
+            read: 0,
+        })
+    }
+
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        self.file.write_all_at(record, self.written)?;
+        self.written += record.len() as u64;
+        Ok(())
+    }
+
+    fn take(&mut self, len: usize) -> io::Result<Bytes> {
+        let mut record = vec![0; len];
+        self.file.read_exact_at(&mut record, self.read)?;
+        self.read += len as u64;
+        if self.read == self.written {
+            self.file.set_len(0)?;
+            (self.read, self.written) = (0, 0);
+        }
+        Ok(Bytes::from(record))
+    }
+}
+
+impl Drop for Spill {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// A follower's queue of records, written to its [`ReplicaStream`] by a
+/// task of its own so that the leader doesn't wait on the follower until
+/// the queue is full. Dropping the queue stops the task and closes the
+/// stream, whatever is still queued.
+pub struct ReplicaQueue {
+    messages: mpsc::UnboundedSender<Message>,
+    /// A permit for each byte the queue has room for, closed once the
+    /// writer stops.
+    room: Arc<Semaphore>,
+    limit: QueueLimit,
+    spill: Option<Arc<Mutex<Spill>>>,
+    writer: JoinHandle<()>,
+}
+
+impl ReplicaQueue {
+    /// Starts writing to `stream`, whose failures are reported under `name`.
+    pub fn start<W>(stream: ReplicaStream<W>, limit: QueueLimit, name: String) -> Self
+    where
+        W: AsyncWrite + Unpin + Send + 'static,
+    {
+        let (messages, received) = mpsc::unbounded_channel();
+        let room = Arc::new(Semaphore::new(limit.max_bytes.min(Semaphore::MAX_PERMITS)));
+        let writer = tokio::spawn(write_queue(stream, received, room.clone(), name));
+        ReplicaQueue {
+            messages,
+            room,
+            limit,
+            spill: None,
+            writer,
+        }
+    }
+
+    /// Queues a record, doing as the queue's [`Overflow`] says if there's
+    /// no room for it. Fails if the writer has stopped or the follower is
+    /// to be dropped.
+    pub async fn push(&mut self, record: &[u8]) -> Result<()> {
+        // A record larger than the whole queue waits for it to empty.
+        let wanted = record
+            .len()
+            .min(self.limit.max_bytes)
+            .min(u32::MAX as usize) as u32;
+        let permit = match self.room.clone().try_acquire_many_owned(wanted) {
+            Ok(permit) => permit,
+            Err(TryAcquireError::Closed) => {
+                return Err(DistKvError::link_down("the replication stream has stopped"))
+            }
+            Err(TryAcquireError::NoPermits) => match self.limit.overflow {
+                Overflow::Block => match self.room.clone().acquire_many_owned(wanted).await {
+                    Ok(permit) => permit,
+                    Err(_) => {
+                        return Err(DistKvError::link_down("the replication stream has stopped"))
+                    }
+                },
+                Overflow::Spill => return self.spill(record),
+                Overflow::Drop => {
+                    return Err(DistKvError::link_down(format!(
+                        "the follower fell {} bytes behind",
+                        self.limit.max_bytes
+                    )))
+                }
+            },
+        };
+        permit.forget();
+        self.send(Message::Record(Bytes::copy_from_slice(record), wanted))
+    }
+
+    fn spill(&mut self, record: &[u8]) -> Result<()> {
+        let spill = match &self.spill {
+            Some(spill) => spill.clone(),
+            None => {
+                let spill = Arc::new(Mutex::new(Spill::create(&self.limit.spill_dir)?));
+                self.spill.insert(spill).clone()
+            }
+        };
+        spill.lock().unwrap().append(record)?;
+        self.send(Message::Spilled(spill, record.len()))
+    }
+
+    fn send(&self, message: Message) -> Result<()> {
+        self.messages
+            .send(message)
+            .map_err(|_| DistKvError::link_down("the replication stream has stopped"))
+    }
+
+    /// Bytes queued in memory and not yet written.
+    pub fn queued(&self) -> usize {
+        self.limit.max_bytes.min(Semaphore::MAX_PERMITS) - self.room.available_permits()
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.writer.is_finished()
+    }
+
+    /// Has the writer write out what its stream is holding back.
+    pub fn flush(&self) {
+        let _ = self.messages.send(Message::Flush);
+    }
+
+    /// Writes out everything queued and closes the stream.
+    pub async fn shutdown(self) -> Result<()> {
+        let (done, result) = oneshot::channel();
+        self.send(Message::Shutdown(done))?;
+        let result = result.await;
+        Ok(result.map_err(|_| DistKvError::link_down("the replication stream has stopped"))??)
+    }
+}
+
+impl Drop for ReplicaQueue {
+    fn drop(&mut self) {
+        self.writer.abort();
+    }
+}
+
+async fn write_queue<W: AsyncWrite + Unpin>(
+    mut stream: ReplicaStream<W>,
+    mut messages: mpsc::UnboundedReceiver<Message>,
+    room: Arc<Semaphore>,
+    name: String,
+) {
+    while let Some(message) = messages.recv().await {
+        let written = match message {
+            Message::Record(record, permits) => {
+                let sent = stream.send(&record).await;
+                room.add_permits(permits as usize);
+                sent.map_err(|e| (e, Some(record)))
+            }
+            Message::Spilled(spill, len) => {
+                let record = spill.lock().unwrap().take(len);
+                match record {
+                    Ok(record) => stream.send(&record).await.map_err(|e| (e, Some(record))),
+                    Err(e) => Err((e, None)),
+                }
+            }
+            Message::Flush => stream.flush().await.map_err(|e| (e, None)),
+            Message::Shutdown(done) => {
+                let _ = done.send(stream.shutdown().await);
+                break;
+            }
+        };
+        if let Err((e, record)) = written {
+            let request = record
+                .as_deref()
+                .and_then(node::request_id)
+                .map_or(String::new(), |id| format!(" of request {}", id));
+            eprintln!("Replication{} to {} failed: {:?}", request, name, e);
+            break;
+        }
+    }
+    // Anyone waiting for room finds the stream has stopped.
+    room.close();
+}
@@ -0,0 +1,399 @@
+//! The scripting language `EVAL` runs, so that read-modify-write logic can
+//! run on the leader in one step rather than over several round trips.
+//!
+//! A script is a series of s-expressions evaluated in order, whose value is
+//! the last one's. Values are nil, 64-bit integers and byte strings, and
+//! nil, `0` and the empty string are false. Strings are written in double
+//! quotes with the escapes `\"`, `\\` and `\n`; integers are parsed out of
+//! strings wherever one is needed. The forms are:
+//!
+//! - `(get k)`, `(set k v)` and `(del k)` on the keys the script was given;
+//! - `(key n)` and `(arg n)` for the `n`th key and argument, from 1;
+//! - `(let name v)` to bind a variable, which is then just `name`;
+//! - `(if c then [else])`, `(do ...)` and `(while c ...)`;
+//! - `(= a b)`, `(< a b)`, `(> a b)`, `(not a)`, `(and ...)` and `(or ...)`;
+//! - `(+ ...)`, `(- a b)`, `(* ...)`, `(concat ...)` and `(len s)`;
+//! - `(error msg)` to fail the script.
+//!
+//! Scripts are sandboxed: they can only reach the keys passed to `EVAL`,
+//! run for at most [`MAX_STEPS`] steps and build strings of at most
+//! [`MAX_STRING_LEN`] bytes. Their writes are buffered and applied together
+//! as a `BATCH` once the script finishes, so a script that fails changes
+//! nothing.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use bytes::Bytes;
+
+use crate::protocol::{Command, ErrorCode, ErrorReply};
+use crate::store::{Db, Key, Response, Val};
+
+/// How many expressions a script may evaluate before it is stopped.
+pub const MAX_STEPS: usize = 100_000;
+
+/// The longest string `concat` may build.
+pub const MAX_STRING_LEN: usize = 16 * 1024 * 1024;
+
+/// How deeply expressions may nest.
+const MAX_DEPTH: usize = 64;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Int(i64),
+    Str(Bytes),
+    Symbol(String),
+    List(Vec<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Nil,
+    Int(i64),
+    Str(Bytes),
+}
+
+impl Value {
+    fn is_true(&self) -> bool {
+        match self {
+            Value::Nil => false,
+            Value::Int(n) => *n != 0,
+            Value::Str(s) => !s.is_empty(),
+        }
+    }
+
+    fn bytes(&self) -> Bytes {
+        match self {
+            Value::Nil => Bytes::new(),
+            Value::Int(n) => Bytes::from(n.to_string()),
+            Value::Str(s) => s.clone(),
+        }
+    }
+
+    fn int(&self) -> Result<i64, String> {
+        match self {
+            Value::Int(n) => Ok(*n),
+            Value::Str(s) => std::str::from_utf8(s)
+                .ok()
+                .and_then(|s| s.parse().ok())
+                .ok_or_else(|| format!("{:?} is not an integer", String::from_utf8_lossy(s))),
+            Value::Nil => Err("nil is not an integer".to_string()),
+        }
+    }
+
+    /// The reply to `EVAL`: `$-1` for nil, `:<n>` or a bulk string.
+    pub fn into_response(self) -> Response {
+        match self {
+            Value::Nil => Response::KeyNotFound(Key::new()),
+            Value::Int(n) => Response::Integer(n),
+            Value::Str(s) => Response::Get(Key::new(), s.into()),
+        }
+    }
+}
+
+impl From<bool> for Value {
+    fn from(b: bool) -> Self {
+        Value::Int(b.into())
+    }
+}
+
+fn parse(script: &[u8]) -> Result<Vec<Expr>, String> {
+    let mut pos = 0;
+    let mut exprs = Vec::new();
+    while let Some(expr) = parse_expr(script, &mut pos, 0)? {
+        exprs.push(expr);
+    }
+    Ok(exprs)
+}
+
+/// Parses the expression at `pos`, or returns `None` at the end of the
+/// script.
+fn parse_expr(script: &[u8], pos: &mut usize, depth: usize) -> Result<Option<Expr>, String> {
+    while script.get(*pos).is_some_and(u8::is_ascii_whitespace) {
+        *pos += 1;
+    }
+    let Some(&b) = script.get(*pos) else {
+        return Ok(None);
+    };
+    match b {
+        b'(' => {
+            if depth == MAX_DEPTH {
+                return Err("expressions are nested too deeply".to_string());
+            }
+            *pos += 1;
+            let mut list = Vec::new();
+            loop {
+                while script.get(*pos).is_some_and(u8::is_ascii_whitespace) {
+                    *pos += 1;
+                }
+                match script.get(*pos) {
+                    Some(b')') => {
+                        *pos += 1;
+                        return Ok(Some(Expr::List(list)));
+                    }
+                    Some(_) => list.extend(parse_expr(script, pos, depth + 1)?),
+                    None => return Err("unbalanced parentheses".to_string()),
+                }
+            }
+        }
+        b')' => Err("unbalanced parentheses".to_string()),
+        b'"' => {
+            *pos += 1;
+            let mut s = Vec::new();
+            loop {
+                match script.get(*pos) {
+                    Some(b'"') => break,
+                    Some(b'\\') => {
+                        s.push(match script.get(*pos + 1) {
+                            Some(b'n') => b'\n',
+                            Some(&c @ (b'"' | b'\\')) => c,
+                            _ => return Err("invalid escape in string".to_string()),
+                        });
+                        *pos += 2;
+                    }
+                    Some(&c) => {
+                        s.push(c);
+                        *pos += 1;
+                    }
+                    None => return Err("unterminated string".to_string()),
+                }
+            }
+            *pos += 1;
+            Ok(Some(Expr::Str(Bytes::from(s))))
+        }
+        _ => {
+            let start = *pos;
+            while script
+                .get(*pos)
+                .is_some_and(|b| !b.is_ascii_whitespace() && !b"()\"".contains(b))
+            {
+                *pos += 1;
+            }
+            let atom = String::from_utf8_lossy(&script[start..*pos]).into_owned();
+            Ok(Some(match atom.parse() {
+                Ok(n) => Expr::Int(n),
+                Err(_) => Expr::Symbol(atom),
+            }))
+        }
+    }
+}
+
+struct Interpreter<'a> {
+    hashmap: &'a Db,
+    keys: &'a [Cow<'a, [u8]>],
+    args: &'a [Cow<'a, [u8]>],
+    /// The values the script has written so far, `None` for deleted keys.
+    written: HashMap<Bytes, Option<Bytes>>,
+    writes: Vec<Command<'static>>,
+    vars: HashMap<String, Value>,
+    steps: usize,
+}
+
+impl Interpreter<'_> {
+    fn eval(&mut self, expr: &Expr) -> Result<Value, String> {
+        self.steps += 1;
+        if self.steps > MAX_STEPS {
+            return Err(format!("script ran for more than {} steps", MAX_STEPS));
+        }
+        let list = match expr {
+            Expr::Int(n) => return Ok(Value::Int(*n)),
+            Expr::Str(s) => return Ok(Value::Str(s.clone())),
+            Expr::Symbol(name) if name == "nil" => return Ok(Value::Nil),
+            Expr::Symbol(name) => {
+                return self
+                    .vars
+                    .get(name)
+                    .cloned()
+                    .ok_or_else(|| format!("unknown variable {}", name))
+            }
+            Expr::List(list) => list,
+        };
+        let Some((Expr::Symbol(form), operands)) = list.split_first() else {
+            return Err("expected a form name at the start of a list".to_string());
+        };
+        match (form.as_str(), operands) {
+            ("get", [key]) => {
+                let key = self.key(key)?;
+                let val = match self.written.get(&key) {
+                    Some(written) => written.clone(),
+                    None => self.hashmap.get(&key[..]).map(Val::to_bytes),
+                };
+                Ok(val.map_or(Value::Nil, Value::Str))
+            }
+            ("set", [key, val]) => {
+                let key = self.key(key)?;
+                let val = self.eval(val)?;
+                self.writes.push(Command::Set(
+                    key.to_vec().into(),
+                    val.bytes().to_vec().into(),
+                ));
+                self.written.insert(key, Some(val.bytes()));
+                Ok(val)
+            }
+            ("del", [key]) => {
+                let key = self.key(key)?;
+                let existed = match self.written.get(&key) {
+                    Some(written) => written.is_some(),
+                    None => self.hashmap.contains_key(&key[..]),
+                };
+                if existed {
+                    self.writes.push(Command::Delete(key.to_vec().into()));
+                    self.written.insert(key, None);
+                }
+                Ok(existed.into())
+            }
+            ("key", [n]) => self.nth(self.keys, n, "key"),
+            ("arg", [n]) => self.nth(self.args, n, "argument"),
+            ("let", [Expr::Symbol(name), val]) => {
+                let val = self.eval(val)?;
+                self.vars.insert(name.clone(), val.clone());
+                Ok(val)
+            }
+            ("if", [cond, then, rest @ ..]) if rest.len() <= 1 => {
+                if self.eval(cond)?.is_true() {
+                    self.eval(then)
+                } else {
+                    rest.first()
+                        .map_or(Ok(Value::Nil), |other| self.eval(other))
+                }
+            }
+            ("do", body) => self.eval_all(body),
+            ("while", [cond, body @ ..]) => {
+                let mut last = Value::Nil;
+                while self.eval(cond)?.is_true() {
+                    last = self.eval_all(body)?;
+                }
+                Ok(last)
+            }
+            ("=", [a, b]) => Ok((self.eval(a)?.bytes() == self.eval(b)?.bytes()).into()),
+            ("<", [a, b]) => Ok((self.eval(a)?.int()? < self.eval(b)?.int()?).into()),
+            (">", [a, b]) => Ok((self.eval(a)?.int()? > self.eval(b)?.int()?).into()),
+            ("not", [a]) => Ok((!self.eval(a)?.is_true()).into()),
+            ("and", operands) => {
+                let mut last = Value::Int(1);
+                for operand in operands {
+                    last = self.eval(operand)?;
+                    if !last.is_true() {
+                        break;
+                    }
+                }
+                Ok(last)
+            }
+            ("or", operands) => {
+                let mut last = Value::Nil;
+                for operand in operands {
+                    last = self.eval(operand)?;
+                    if last.is_true() {
+                        break;
+                    }
+                }
+                Ok(last)
+            }
+            ("+", operands) => self.fold(operands, 0, i64::checked_add),
+            ("*", operands) => self.fold(operands, 1, i64::checked_mul),
+            ("-", [a, b]) => {
+                let (a, b) = (self.eval(a)?.int()?, self.eval(b)?.int()?);
+                a.checked_sub(b)
+                    .map(Value::Int)
+                    .ok_or_else(|| "integer overflow".to_string())
+            }
+            ("concat", operands) => {
+                let mut s = Vec::new();
+                for operand in operands {
+                    s.extend_from_slice(&self.eval(operand)?.bytes());
+                    if s.len() > MAX_STRING_LEN {
+                        return Err(format!("strings are limited to {} bytes", MAX_STRING_LEN));
+                    }
+                }
+                Ok(Value::Str(Bytes::from(s)))
+            }
+            ("len", [s]) => Ok(Value::Int(self.eval(s)?.bytes().len() as i64)),
+            ("error", [message]) => {
+                Err(String::from_utf8_lossy(&self.eval(message)?.bytes()).into_owned())
+            }
+            (
+                "get" | "set" | "del" | "key" | "arg" | "let" | "if" | "while" | "=" | "<" | ">"
+                | "not" | "-" | "len" | "error",
+                _,
+            ) => Err(format!("wrong number of arguments for {}", form)),
+            _ => Err(format!("unknown form {}", form)),
+        }
+    }
+
+    fn eval_all(&mut self, exprs: &[Expr]) -> Result<Value, String> {
+        let mut last = Value::Nil;
+        for expr in exprs {
+            last = self.eval(expr)?;
+        }
+        Ok(last)
+    }
+
+    fn fold(
+        &mut self,
+        operands: &[Expr],
+        start: i64,
+        op: fn(i64, i64) -> Option<i64>,
+    ) -> Result<Value, String> {
+        let mut acc = start;
+        for operand in operands {
+            let n = self.eval(operand)?.int()?;
+            acc = op(acc, n).ok_or_else(|| "integer overflow".to_string())?;
+        }
+        Ok(Value::Int(acc))
+    }
+
+    /// Evaluates a key, which must be one of those the script was given.
+    fn key(&mut self, key: &Expr) -> Result<Bytes, String> {
+        let key = self.eval(key)?.bytes();
+        if !self.keys.iter().any(|given| given[..] == key[..]) {
+            return Err(format!(
+                "key {} was not passed to EVAL",
+                String::from_utf8_lossy(&key)
+            ));
+        }
+        Ok(key)
+    }
+
+    fn nth(&mut self, items: &[Cow<'_, [u8]>], n: &Expr, what: &str) -> Result<Value, String> {
+        let n = self.eval(n)?.int()?;
+        let item = usize::try_from(n)
+            .ok()
+            .and_then(|n| n.checked_sub(1))
+            .and_then(|i| items.get(i));
+        match item {
+            Some(item) => Ok(Value::Str(Bytes::copy_from_slice(item))),
+            None => Err(format!("there is no {} {}", what, n)),
+        }
+    }
+}
+
+/// Checks that `script` parses, without running it.
+pub fn check(script: &[u8]) -> Result<(), ErrorReply> {
+    match parse(script) {
+        Ok(_) => Ok(()),
+        Err(message) => Err(ErrorReply::new(ErrorCode::Script, message)),
+    }
+}
+
+/// Runs `script` against `hashmap` without changing it, returning its value
+/// and the `SET`s and `DEL`s it made, in order.
+pub fn eval(
+    hashmap: &Db,
+    script: &[u8],
+    keys: &[Cow<'_, [u8]>],
+    args: &[Cow<'_, [u8]>],
+) -> Result<(Value, Vec<Command<'static>>), ErrorReply> {
+    let error = |message: String| ErrorReply::new(ErrorCode::Script, message);
+    let exprs = parse(script).map_err(error)?;
+    let mut interpreter = Interpreter {
+        hashmap,
+        keys,
+        args,
+        written: HashMap::new(),
+        writes: Vec::new(),
+        vars: HashMap::new(),
+        steps: 0,
+    };
+    let value = interpreter.eval_all(&exprs).map_err(error)?;
+    Ok((value, interpreter.writes))
+}
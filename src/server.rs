@@ -0,0 +1,2699 @@
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::mem;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bytes::{Bytes, BytesMut};
+use nix::unistd::{sysconf, SysconfVar};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::task::JoinSet;
+
+use crate::allocator;
+use crate::analyze::Analysis;
+use crate::audit::{self, AuditLog};
+use crate::cdc::ChangeLog;
+use crate::checkpoint;
+use crate::client::{DistKvClient, Reply};
+use crate::clock::{Clock, SystemClock};
+use crate::config::{self, Accepted, Config, LIVE_SETTINGS};
+use crate::discovery::Peers;
+use crate::disk::{self, DISK_CHECK_INTERVAL};
+use crate::error::{bail, Context, DistKvError, Result};
+use crate::export;
+use crate::filter::{Pipeline, Transform};
+use crate::metrics::SlowQuery;
+use crate::node::{self, LeaderCore};
+use crate::protocol::{
+    parse_all, split_frame, ClientCommand, ClusterCommand, Command, ConfigCommand, Consistency,
+    DebugCommand, ErrorCode, ErrorReply, ExportFormat, Limits, ParseError, FEATURES,
+};
+use crate::pubsub::{push, PubSub};
+use crate::replication::{ReplicaQueue, ReplicaStream};
+use crate::snapshot;
+use crate::store::{encoding, Key, Response, Val, DEFAULT_NAMESPACE};
+use crate::tenant::{Rate, Tenant};
+use crate::trace::Span;
+use crate::tracking::{self, Poppers, Tracking, Waiters};
+use crate::transport::{Connection, Listener, Tcp, Transport};
+use crate::wal::{self, LogFile};
+use crate::webhook::Webhooks;
+
+/// How many keys `ANALYZE` looks at each time it takes the lock.
+const ANALYZE_BATCH: usize = 1024;
+
+/// Writes buffered for a follower that is being sent a snapshot, beyond
+/// which the transfer is abandoned and the follower has to start over.
+const MAX_TRANSFER_BACKLOG: usize = 64 * 1024 * 1024;
+
+/// How often keys whose TTL is up are deleted when no command comes along to
+/// do it first.
+const EXPIRE_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long a `QUORUM` read waits for followers to answer.
+const QUORUM_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long `TRANSFER LEADERSHIP` waits for the follower to catch up.
+const TRANSFER_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A snapshot being sent to a follower that asked for one with `SYNC`.
+struct Transfer {
+    id: u64,
+    snapshot: Bytes,
+    /// Writes made since the snapshot was taken, to send once it's done.
+    backlog: Vec<BytesMut>,
+    backlog_len: usize,
+}
+
+/// A follower the leader replicates to, through a queue bounded by the
+/// `replication_queue_size` and `replication_overflow` settings. A follower
+/// that can't be reached, or is dropped for falling behind, is skipped and
+/// reconnected to on a later write. Having missed any number of writes, it
+/// is then sent a snapshot of the map to start from again, see
+/// [`Leader::replicate`]. Followers
+/// that connected to the leader with `SYNC` have no address to reconnect to
+/// and are dropped instead, leaving them to sync again.
+///
+/// Followers that connect with `SYNC` join as learners, which are sent the
+/// leader's writes but left out of the majority `QUORUM` reads need, so a
+/// follower still catching up can't hold reads up. A learner is promoted to
+/// a voter once its snapshot has been sent and nothing is queued for it,
+/// see [`Leader::promote_learners`].
+pub struct Replica {
+    addr: Option<String>,
+    /// A connection the leader opened and has yet to mark with `REPLICATE`
+    /// as the follower's replication stream.
+    connection: Option<Connection>,
+    queue: Option<ReplicaQueue>,
+    transfer: Option<Transfer>,
+    learner: bool,
+    /// Where a follower that connected with `SYNC` said it answers reads.
+    reads_at: Option<String>,
+    /// A snapshot to send the follower ahead of any write once its stream
+    /// is open.
+    install: Option<Bytes>,
+    /// Whether a write may not have reached the follower, which then needs
+    /// a snapshot before it can be streamed to again.
+    behind: bool,
+    /// Whether the leader last warned that the follower lags, see
+    /// [`Replica::check_lag`].
+    lag_alarm: bool,
+    /// Transforms added with [`Replica::with_transform`].
+    transforms: Vec<Arc<dyn Transform>>,
+}
+
+impl Replica {
+    pub fn new(addr: impl Into<String>, stream: Option<Connection>) -> Self {
+        Replica {
+            addr: Some(addr.into()),
+            connection: stream,
+            queue: None,
+            transfer: None,
+            learner: false,
+            reads_at: None,
+            install: None,
+            behind: false,
+            lag_alarm: false,
+            transforms: Vec::new(),
+        }
+    }
+
+    /// Runs the records sent to the follower through `transform`, after its
+    /// configured filter and transforms, see [`crate::filter`].
+    pub fn with_transform(mut self, transform: Arc<dyn Transform>) -> Self {
+        self.transforms.push(transform);
+        self
+    }
+
+    fn name(&self) -> String {
+        self.addr
+            .clone()
+            .unwrap_or_else(|| "a synced follower".to_string())
+    }
+
+    async fn send(
+        &mut self,
+        record: &[u8],
+        leader: Option<&str>,
+        config: &Config,
+        transport: &dyn Transport,
+    ) {
+        let transformed;
+        let record = match self.pipeline(config) {
+            Some(pipeline) => {
+                transformed = pipeline.record(record);
+                &transformed[..]
+            }
+            None => record,
+        };
+        if record.is_empty() {
+            return;
+        }
+        if let Some(transfer) = &mut self.transfer {
+            transfer.backlog.push(BytesMut::from(record));
+            transfer.backlog_len += record.len();
+            if transfer.backlog_len > MAX_TRANSFER_BACKLOG {
+                eprintln!("Abandoning snapshot transfer {}", transfer.id);
+                self.transfer = None;
+            }
+            return;
+        }
+        // The writer has already reported why it stopped.
+        if self.queue.as_ref().is_some_and(ReplicaQueue::is_stopped) {
+            self.queue = None;
+            self.behind = self.addr.is_some();
+        }
+        // It is caught up with a snapshot, see `Leader::replicate`.
+        if self.behind {
+            return;
+        }
+        if self.queue.is_none() && !self.connect(leader, config, transport).await {
+            self.behind = self.addr.is_some();
+            return;
+        }
+        if let Err(e) = self.queue.as_mut().unwrap().push(record).await {
+            let request =
+                node::request_id(record).map_or(String::new(), |id| format!(" of request {}", id));
+            eprintln!("Replication{} to {} failed: {:?}", request, self.name(), e);
+            self.queue = None;
+            self.behind = true;
+        }
+    }
+
+    /// Whether the follower has an address to reconnect to and may have
+    /// missed writes, having lost its stream.
+    fn is_behind(&self) -> bool {
+        self.addr.is_some()
+            && self.transfer.is_none()
+            && (self.behind || self.queue.as_ref().is_some_and(ReplicaQueue::is_stopped))
+    }
+
+    /// Connects to the follower if there isn't a connection waiting to be
+    /// made its stream already, returning whether there is one now.
+    async fn open(&mut self, transport: &dyn Transport) -> bool {
+        if self.connection.is_none() {
+            let Some(addr) = &self.addr else {
+                return false;
+            };
+            self.connection = transport.connect(addr).await.ok();
+        }
+        self.connection.is_some()
+    }
+
+    /// Makes the connection [`Replica::open`] made the follower's
+    /// replication stream, first sending it the snapshot it is to start
+    /// from if there is one. Returns whether the stream is open.
+    async fn connect(
+        &mut self,
+        leader: Option<&str>,
+        config: &Config,
+        transport: &dyn Transport,
+    ) -> bool {
+        if !self.open(transport).await {
+            return false;
+        }
+        let connection = self.connection.take().unwrap();
+        let wan = self.is_wan(config);
+        let mut stream = match introduce(connection, leader, config, wan).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                eprintln!("Replication to {} failed: {:?}", self.name(), e);
+                return false;
+            }
+        };
+        if let Some(snapshot) = &self.install {
+            let mut record = BytesMut::new();
+            Command::Install(snapshot[..].into()).encode(&mut record);
+            let sent = match stream.send(&record).await {
+                Ok(()) => stream.flush().await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = sent {
+                eprintln!("Sending a snapshot to {} failed: {:?}", self.name(), e);
+                return false;
+            }
+            self.install = None;
+            self.behind = false;
+        }
+        let limit = match wan {
+            true => config.wan_queue_limit(),
+            false => config.queue_limit(),
+        };
+        self.queue = Some(ReplicaQueue::start(stream, limit, self.name()));
+        true
+    }
+
+    /// Whether the follower has been sent every write so far.
+    fn is_caught_up(&self) -> bool {
+        self.transfer.is_none()
+            && self
+                .queue
+                .as_ref()
+                .is_some_and(|queue| !queue.is_stopped() && queue.queued() == 0)
+    }
+
+    /// Where the follower can be asked for a quorum read, if anywhere.
+    fn read_addr(&self) -> Option<String> {
+        self.addr.clone().or_else(|| self.reads_at.clone())
+    }
+
+    fn is_gone(&self) -> bool {
+        self.addr.is_none() && self.queue.is_none() && self.transfer.is_none()
+    }
+
+    /// What the follower's records go through before they are sent, if
+    /// anything.
+    fn pipeline<'a>(&'a self, config: &'a Config) -> Option<Pipeline<'a>> {
+        let addr = self.read_addr();
+        let filter = addr
+            .as_ref()
+            .and_then(|addr| config.replication_filters.get(addr));
+        let rewrites = addr
+            .as_ref()
+            .and_then(|addr| config.replication_transforms.get(addr))
+            .map_or(&[][..], Vec::as_slice);
+        Pipeline::new(filter, rewrites, &self.transforms)
+    }
+
+    /// Whether the follower is one of the `wan_replicas`.
+    fn is_wan(&self, config: &Config) -> bool {
+        self.read_addr()
+            .is_some_and(|addr| config.is_wan_replica(&addr))
+    }
+
+    /// Whether the follower counts towards the majority `QUORUM` reads
+    /// need, which learners and WAN replicas don't.
+    fn is_voter(&self, config: &Config) -> bool {
+        !self.learner && !self.is_wan(config)
+    }
+
+    /// Warns when the bytes queued for a WAN replica cross `wan_lag_alarm`,
+    /// either way.
+    fn check_lag(&mut self, config: &Config) {
+        if config.wan_lag_alarm == 0 || !self.is_wan(config) {
+            return;
+        }
+        let queued = self.queue.as_ref().map_or(0, ReplicaQueue::queued);
+        let lagging = queued > config.wan_lag_alarm;
+        if lagging != self.lag_alarm {
+            match lagging {
+                true => eprintln!("WAN replica {} is {} bytes behind", self.name(), queued),
+                false => eprintln!("WAN replica {} has caught up", self.name()),
+            }
+            self.lag_alarm = lagging;
+        }
+    }
+
+    fn status(&self, config: &Config) -> FollowerStatus {
+        let state = match (&self.transfer, &self.queue) {
+            (Some(_), _) => "syncing",
+            (None, Some(queue)) if !queue.is_stopped() => "streaming",
+            _ => "disconnected",
+        };
+        FollowerStatus {
+            addr: self.read_addr(),
+            voter: self.is_voter(config),
+            wan: self.is_wan(config),
+            lag_alarm: self.lag_alarm,
+            state,
+            queued: self.queue.as_ref().map_or(0, ReplicaQueue::queued),
+            transfer_backlog: self.transfer.as_ref().map_or(0, |t| t.backlog_len),
+        }
+    }
+}
+
+/// What replicating to a follower is up to, for state dumps and the
+/// dashboard. How far a follower lags is the bytes queued for it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FollowerStatus {
+    /// `None` for a follower that connected with `SYNC` without saying
+    /// where it answers reads.
+    pub addr: Option<String>,
+    /// Whether it counts towards `QUORUM` reads, rather than a learner or
+    /// a WAN replica.
+    pub voter: bool,
+    pub wan: bool,
+    /// Whether more than `wan_lag_alarm` bytes are queued for a WAN replica.
+    pub lag_alarm: bool,
+    /// `streaming`, `syncing` while it is sent a snapshot, or
+    /// `disconnected`.
+    pub state: &'static str,
+    pub queued: usize,
+    /// Bytes of writes held back until its snapshot has been sent.
+    pub transfer_backlog: usize,
+}
+
+/// The refusal of a membership change while `learner` is still catching
+/// up, so that the cluster changes one node at a time.
+fn membership_busy(learner: &Replica) -> Response {
+    let message = format!(
+        "{} is still catching up; change the cluster one node at a time",
+        learner.name()
+    );
+    Response::Error(ErrorReply::new(ErrorCode::Busy, message))
+}
+
+/// Marks `connection` as the follower's replication stream, after offering
+/// the follower compression if the leader is set to compress.
+async fn introduce(
+    connection: Connection,
+    leader: Option<&str>,
+    config: &Config,
+    wan: bool,
+) -> Result<ReplicaStream<Connection>> {
+    let mut stream = ReplicaStream::new(connection);
+    stream.set_batching(match wan {
+        true => config.wan_batching(),
+        false => config.batching(),
+    });
+    let lz4 = (wan || config.replication_compression) && stream.offer_lz4().await?;
+    let mut handshake = BytesMut::new();
+    Command::Replicate(leader.map(|addr| addr.as_bytes().into())).encode(&mut handshake);
+    stream.introduce(&handshake).await?;
+    if lz4 {
+        stream.compress();
+    }
+    Ok(stream)
+}
+
+/// A client connection, as shown by `CLIENT LIST`. Times are as reported
+/// by the leader's clock.
+struct ClientInfo {
+    addr: SocketAddr,
+    name: String,
+    connected: Duration,
+    last_active: Duration,
+    last_command: &'static str,
+    /// Closes the connection when notified.
+    kill: Arc<Notify>,
+}
+
+/// The `EPHEMERAL` keys connections set, by connection id, each with its
+/// namespace and the value it was set to.
+#[derive(Default)]
+pub struct Sessions {
+    keys: BTreeMap<u64, Vec<(Bytes, Bytes, Bytes)>>,
+}
+
+impl Sessions {
+    fn own(&mut self, id: u64, namespace: &[u8], key: &[u8], val: &[u8]) {
+        let keys = self.keys.entry(id).or_default();
+        keys.retain(|(n, k, _)| (&n[..], &k[..]) != (namespace, key));
+        keys.push((
+            Bytes::copy_from_slice(namespace),
+            Bytes::copy_from_slice(key),
+            Bytes::copy_from_slice(val),
+        ));
+    }
+
+    fn end(&mut self, id: u64) -> Vec<(Bytes, Bytes, Bytes)> {
+        self.keys.remove(&id).unwrap_or_default()
+    }
+}
+
+/// Whose `REQID`s a connection's are: those of its client name under the
+/// tenant it authenticated as, so that a client retrying on a new connection
+/// under the same name is recognized, or the connection's own if it has no
+/// name.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum Requester {
+    Named(Option<String>, String),
+    Connection(u64),
+}
+
+/// The replies to the writes clients sent after a `REQID`, so that a write
+/// retried under the same ID is answered again instead of applied again.
+/// Only each client's `request_dedup_window` most recent IDs are kept, and
+/// only until it has gone `request_dedup_timeout_ms` without adding one.
+#[derive(Default)]
+pub struct Requests {
+    /// Each client's IDs and replies, oldest first, and when, by the
+    /// leader's clock, it last added one.
+    replies: HashMap<Requester, (Duration, VecDeque<(Bytes, Response)>)>,
+}
+
+impl Requests {
+    fn reply(&self, requester: &Requester, request: &[u8]) -> Option<Response> {
+        let (_, replies) = self.replies.get(requester)?;
+        let (_, response) = replies.iter().find(|(id, _)| id[..] == *request)?;
+        Some(response.clone())
+    }
+
+    fn remember(
+        &mut self,
+        requester: Requester,
+        request: Bytes,
+        response: Response,
+        window: usize,
+        now: Duration,
+    ) {
+        if window == 0 {
+            return;
+        }
+        let (used, replies) = self.replies.entry(requester).or_default();
+        *used = now;
+        replies.push_back((request, response));
+        while replies.len() > window {
+            replies.pop_front();
+        }
+    }
+
+    /// Forgets the requests of connection `id` that had no name, as when it
+    /// closes, since no one else can retry them.
+    fn forget(&mut self, id: u64) {
+        self.replies.remove(&Requester::Connection(id));
+    }
+
+    /// Forgets the requests of the clients that have added none for
+    /// `timeout`, named ones included, which would otherwise be kept for
+    /// good. A zero `timeout` keeps them all.
+    fn expire(&mut self, now: Duration, timeout: Duration) {
+        if !timeout.is_zero() {
+            self.replies
+                .retain(|_, (used, _)| now.saturating_sub(*used) < timeout);
+        }
+    }
+}
+
+/// The connections the leader is serving, by id.
+#[derive(Default)]
+pub struct Clients {
+    next_id: u64,
+    clients: BTreeMap<u64, ClientInfo>,
+}
+
+impl Clients {
+    /// Adds a connection, returning its id and what to wait on for it to be
+    /// killed.
+    fn register(&mut self, addr: SocketAddr, now: Duration) -> (u64, Arc<Notify>) {
+        self.next_id += 1;
+        let kill = Arc::new(Notify::new());
+        self.clients.insert(
+            self.next_id,
+            ClientInfo {
+                addr,
+                name: String::new(),
+                connected: now,
+                last_active: now,
+                last_command: "NULL",
+                kill: kill.clone(),
+            },
+        );
+        (self.next_id, kill)
+    }
+
+    fn unregister(&mut self, id: u64) {
+        self.clients.remove(&id);
+    }
+
+    /// When the connection last sent a command, if it's still open.
+    fn last_active(&self, id: u64) -> Option<Duration> {
+        self.clients.get(&id).map(|client| client.last_active)
+    }
+
+    fn touch(&mut self, id: u64, command: &'static str, now: Duration) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.last_command = command;
+            client.last_active = now;
+        }
+    }
+
+    fn set_name(&mut self, id: u64, name: &[u8]) -> Response {
+        if !name.iter().all(u8::is_ascii_graphic) {
+            let err = ErrorReply::new(
+                ErrorCode::Syntax,
+                "client names can't contain spaces or special characters",
+            );
+            return Response::Error(err);
+        }
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.name = String::from_utf8_lossy(name).into_owned();
+        }
+        Response::Ok
+    }
+
+    fn requester(&self, id: u64, user: Option<&str>) -> Requester {
+        match self
+            .clients
+            .get(&id)
+            .filter(|client| !client.name.is_empty())
+        {
+            Some(client) => Requester::Named(user.map(str::to_string), client.name.clone()),
+            None => Requester::Connection(id),
+        }
+    }
+
+    /// One line per connection: its id, address, name, age and idle time in
+    /// seconds, how many channels and patterns it is subscribed to, and the
+    /// last command it sent.
+    fn list(&self, now: Duration, pubsub: &PubSub) -> String {
+        let mut list = String::new();
+        for (id, client) in &self.clients {
+            let (channels, patterns) = pubsub.counts(*id);
+            list.push_str(&format!(
+                "id={} addr={} name={} age={} idle={} sub={} psub={} cmd={}\n",
+                id,
+                client.addr,
+                client.name,
+                (now - client.connected).as_secs(),
+                (now - client.last_active).as_secs(),
+                channels,
+                patterns,
+                client.last_command.to_ascii_lowercase(),
+            ));
+        }
+        list
+    }
+
+    fn kill(&mut self, id: u64) -> Response {
+        match self.clients.remove(&id) {
+            Some(client) => {
+                client.kill.notify_one();
+                Response::Ok
+            }
+            None => {
+                let err = ErrorReply::new(ErrorCode::NoSuchClient, format!("no client {}", id));
+                Response::Error(err)
+            }
+        }
+    }
+}
+
+/// State shared between the leader's REPL and its client connections.
+pub struct Leader {
+    pub core: LeaderCore<LogFile, SystemClock>,
+    pub followers: Vec<Replica>,
+    pub clients: Clients,
+    pub sessions: Sessions,
+    pub requests: Requests,
+    pub pubsub: PubSub,
+    pub tracking: Tracking,
+    pub waiters: Waiters,
+    pub poppers: Poppers,
+    /// The tenants connections authenticate as, by name. While there are
+    /// none connections don't have to authenticate.
+    pub tenants: BTreeMap<String, Tenant>,
+    /// How much of their rates the tenants have used this second, by name.
+    pub rates: BTreeMap<String, Rate>,
+    /// Until when, by `core.clock`, a majority of the cluster has promised
+    /// to take no other leader, so `QUORUM` reads can be answered locally.
+    pub lease: Option<Duration>,
+    /// The scripts `FCALL` runs, by function name.
+    pub functions: BTreeMap<String, Bytes>,
+    pub webhooks: Webhooks,
+    /// The address clients reach the leader at, which followers name when
+    /// they turn a write away.
+    pub addr: Option<String>,
+    /// The recent writes, for `TAIL` connections.
+    pub changes: ChangeLog,
+    /// Where the writes and administrative commands clients send are
+    /// logged, with who sent them, if `audit_log` is set.
+    pub audit: Option<AuditLog>,
+    /// The settings the leader runs with, and the file `CONFIG SET` saves
+    /// changes to, if it was started from one.
+    pub config: Config,
+    pub config_path: Option<String>,
+    /// Where the leader's logs are, whose disk it keeps `min_free_space`
+    /// free on.
+    pub data_dir: PathBuf,
+    /// The path of the log `core.wal` appends to, which the leader takes
+    /// checkpoints of its map against if it is set, see
+    /// [`crate::checkpoint`].
+    pub log_path: Option<String>,
+    /// Whether `READONLY ON` has the leader refusing writes, and why the
+    /// disk check has it refusing them, kept apart so that either clearing
+    /// doesn't lift the other.
+    read_only: bool,
+    low_disk: Option<String>,
+    /// The follower `TRANSFER LEADERSHIP` is handing over to, which also
+    /// has writes refused until `READONLY OFF`.
+    handover: Option<String>,
+    /// The limits connections parse requests with, which `CONFIG SET` can
+    /// change under them.
+    limits: watch::Sender<Limits>,
+    /// How the leader takes connections on `listen` ports and opens them to
+    /// followers, [`Tcp`] unless a test runs it on an in-memory network.
+    pub transport: Arc<dyn Transport>,
+}
+
+/// What re-reading the config file changed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Reload {
+    /// The settings that took effect.
+    pub applied: Vec<&'static str>,
+    /// The settings that differ from the running ones but need a restart.
+    pub needs_restart: Vec<&'static str>,
+}
+
+impl Leader {
+    pub fn new(core: LeaderCore<LogFile, SystemClock>, followers: Vec<Replica>) -> Self {
+        Leader {
+            core,
+            followers,
+            clients: Clients::default(),
+            sessions: Sessions::default(),
+            requests: Requests::default(),
+            tracking: Tracking::default(),
+            waiters: Waiters::default(),
+            poppers: Poppers::default(),
+            pubsub: PubSub::default(),
+            tenants: BTreeMap::new(),
+            rates: BTreeMap::new(),
+            lease: None,
+            functions: BTreeMap::new(),
+            webhooks: Webhooks::default(),
+            addr: None,
+            changes: ChangeLog::default(),
+            audit: None,
+            config: Config::default(),
+            config_path: None,
+            data_dir: PathBuf::from("."),
+            log_path: None,
+            read_only: false,
+            low_disk: None,
+            handover: None,
+            limits: watch::channel(Limits::NONE).0,
+            transport: Arc::new(Tcp),
+        }
+    }
+
+    pub async fn persist(&mut self, command: &Command<'_>) -> Result<Response> {
+        let start = self.core.clock.now();
+        let resolved;
+        let command = match self.resolve(command) {
+            Ok(Some(call)) => {
+                resolved = call;
+                &resolved
+            }
+            Ok(None) => command,
+            Err(err) => return Ok(Response::Error(err)),
+        };
+        let response = match command {
+            Command::Info(section) => Response::Info(self.info(section.as_deref())),
+            Command::Hello(version, features) => node::hello(
+                "leader",
+                &self.core.node_id,
+                *version,
+                features.as_deref(),
+                &self.features(),
+            ),
+            Command::WhoAmI => node::whoami("leader", &self.core.node_id, self.addr.as_deref()),
+            Command::WhoIsLeader => node::who_is_leader(self.addr.as_deref()),
+            Command::Peers => {
+                let peers = Peers {
+                    leader: self.addr.clone(),
+                    followers: self
+                        .followers
+                        .iter()
+                        .filter_map(|f| f.addr.clone())
+                        .collect(),
+                };
+                Response::Info(peers.render())
+            }
+            Command::Client(ClientCommand::List) => {
+                Response::Info(self.clients.list(self.core.clock.now(), &self.pubsub))
+            }
+            Command::Client(ClientCommand::Kill(id)) => self.clients.kill(*id),
+            Command::Cluster(ClusterCommand::AddNode(addr)) => {
+                self.add_node(String::from_utf8_lossy(addr).into_owned())
+                    .await?
+            }
+            Command::Cluster(ClusterCommand::RemoveNode(addr)) => {
+                self.remove_node(&String::from_utf8_lossy(addr)).await?
+            }
+            Command::Config(command) => self.configure(command),
+            Command::ReadOnly(on) => {
+                if !on {
+                    self.handover = None;
+                }
+                self.set_read_only(*on);
+                Response::Ok
+            }
+            Command::Analyze(top) => {
+                let now = self.core.clock.unix_time().as_millis() as u64;
+                let mut analysis = Analysis::new(*top, now);
+                for (key, val) in &self.core.hashmap {
+                    analysis.add(key, val, self.core.hashmap.expiry(key));
+                }
+                Response::Info(analysis.render())
+            }
+            Command::Publish(channel, message) => {
+                Response::Integer(self.pubsub.publish(channel, message) as i64)
+            }
+            Command::Client(ClientCommand::SetName(_) | ClientCommand::Tracking(_))
+            | Command::Select(_)
+            | Command::Auth(..)
+            | Command::Tail(_)
+            | Command::Subscribe(_)
+            | Command::Unsubscribe(_)
+            | Command::PSubscribe(_)
+            | Command::PUnsubscribe(_)
+            | Command::Debug(_)
+            | Command::Export(..)
+            | Command::WaitChange(..)
+            | Command::BLPop(..)
+            | Command::BulkLoad
+            | Command::BulkLoadEnd
+            | Command::TransferLeadership(_) => Response::Error(ErrorReply::new(
+                ErrorCode::NotSupported,
+                format!("{} needs a client connection", command.name()),
+            )),
+            command => {
+                let span = Span::start("execute");
+                let (response, record) = span.enter_sync(|| self.core.execute(command))?;
+                drop(span);
+                if let Some(record) = record {
+                    if is_script(command) {
+                        // What a script wrote is only known from its record.
+                        let writes = parse_all(&record, &Limits::NONE).unwrap_or_default();
+                        writes.iter().for_each(|write| self.notify(write));
+                    } else {
+                        self.notify(command);
+                    }
+                    self.record_change(&record);
+                    self.replicate(&record).await;
+                    if !self.poppers.is_empty() {
+                        self.serve_poppers(&record).await?;
+                    }
+                }
+                response
+            }
+        };
+        let elapsed = self.core.clock.now() - start;
+        self.core.metrics.record(command.name(), elapsed);
+        if elapsed >= Duration::from_millis(self.config.slow_query_ms) {
+            self.core.metrics.record_slow(SlowQuery {
+                at: self.core.clock.unix_time().as_millis() as u64,
+                micros: elapsed.as_micros() as u64,
+                name: command.name(),
+                key: (command.keys().first()).map(|key| String::from_utf8_lossy(key).into_owned()),
+            });
+        }
+        let failed = matches!(response, Response::Error(_));
+        self.core
+            .metrics
+            .record_call(command.name(), elapsed, failed);
+        Ok(response)
+    }
+
+    /// Answers `CONFIG GET`, and applies `CONFIG SET` to a live setting,
+    /// saving it to the config file first so that it survives a restart.
+    fn configure(&mut self, command: &ConfigCommand<'_>) -> Response {
+        let (name, value) = match command {
+            ConfigCommand::Get(name) => {
+                return match self.config.get(&String::from_utf8_lossy(name)) {
+                    Ok(value) => Response::Info(value),
+                    Err(e) => Response::Error(ErrorReply::new(ErrorCode::WrongArgs, e.to_string())),
+                };
+            }
+            ConfigCommand::Set(name, value) => (
+                String::from_utf8_lossy(name).into_owned(),
+                String::from_utf8_lossy(value).into_owned(),
+            ),
+        };
+        if !LIVE_SETTINGS.contains(&name.as_str()) {
+            let message = format!("{} can only be changed with a restart", name);
+            return Response::Error(ErrorReply::new(ErrorCode::NotSupported, message));
+        }
+        let mut changed = self.config.clone();
+        if let Err(e) = changed.set(&name, &value) {
+            return Response::Error(ErrorReply::new(ErrorCode::WrongArgs, format!("{:#}", e)));
+        }
+        if let Some(path) = &self.config_path {
+            if let Err(e) = config::save_setting(path, &name, &value) {
+                let message = format!("not saved: {:#}", e);
+                return Response::Error(ErrorReply::new(ErrorCode::NotSupported, message));
+            }
+        }
+        self.config = changed;
+        self.set_limits(self.config.limits());
+        self.core.hashmap.set_interning(self.config.intern_values);
+        Response::Ok
+    }
+
+    /// Has connections parse requests with `limits`, and the map refuse to
+    /// grow a list past their largest value.
+    fn set_limits(&mut self, limits: Limits) {
+        self.limits.send_replace(limits);
+        self.core
+            .hashmap
+            .set_max_value_size(Some(limits.max_value_size));
+    }
+
+    /// Takes on the settings in `config` that can change live, as when the
+    /// config file is re-read, and reports the ones that differ but only
+    /// take effect with a restart.
+    pub fn reload(&mut self, config: &Config) -> Reload {
+        let mut reload = Reload::default();
+        for name in self.config.changed_settings(config) {
+            match LIVE_SETTINGS.contains(&name) {
+                true => {
+                    if let Ok(value) = config.get(name) {
+                        let _ = self.config.set(name, &value);
+                    }
+                    reload.applied.push(name);
+                }
+                false => reload.needs_restart.push(name),
+            }
+        }
+        self.set_limits(self.config.limits());
+        self.core.hashmap.set_interning(self.config.intern_values);
+        reload
+    }
+
+    /// Refuses writes while the disk the leader's logs are on has less than
+    /// `min_free_space` free, and takes them again once it has that much,
+    /// which [`serve`] checks every [`DISK_CHECK_INTERVAL`].
+    pub fn check_disk(&mut self) -> Result<()> {
+        let min = self.config.min_free_space as u64;
+        let free = match min {
+            0 => None,
+            _ => Some(disk::free_space(&self.data_dir)?),
+        };
+        match (free.filter(|free| *free < min), &self.low_disk) {
+            (Some(free), None) => {
+                let reason = format!(
+                    "{} has {} bytes free, under min_free_space {}",
+                    self.data_dir.display(),
+                    free,
+                    min
+                );
+                eprintln!("Refusing writes: {}", reason);
+                self.low_disk = Some(reason);
+            }
+            (None, Some(_)) => {
+                eprintln!("Taking writes again");
+                self.low_disk = None;
+            }
+            _ => return Ok(()),
+        }
+        self.refuse_writes();
+        Ok(())
+    }
+
+    pub fn follower_statuses(&self) -> Vec<FollowerStatus> {
+        self.followers
+            .iter()
+            .map(|follower| follower.status(&self.config))
+            .collect()
+    }
+
+    /// The bytes of every key and value, across namespaces.
+    pub fn keyspace_bytes(&self) -> usize {
+        let namespaces = self.core.hashmap.namespaces().map(|(_, db)| db);
+        std::iter::once(&self.core.hashmap)
+            .chain(namespaces)
+            .flat_map(|db| db.iter())
+            .map(|(key, val)| key.len() + val.len())
+            .sum()
+    }
+
+    /// The report a state dump writes, in `INFO`'s format: the leader's
+    /// connections, then everything `INFO` reports, its followers and what
+    /// its memory goes to among it. Locking isn't timed as it is held, so the time the
+    /// dump waited for the lock stands in for how contended it is.
+    fn dump_state(&self, at: u128, lock_wait: Duration) -> String {
+        let mut report = String::new();
+        report.push_str("# State\n");
+        report.push_str(&format!("time:{}\n", at));
+        report.push_str(&format!("node_id:{}\n", self.core.node_id));
+        report.push_str(&format!("lock_wait_us:{}\n", lock_wait.as_micros()));
+
+        report.push_str("# Clients\n");
+        report.push_str(&format!("connected:{}\n", self.clients.clients.len()));
+        report.push_str(&self.clients.list(self.core.clock.now(), &self.pubsub));
+
+        report.push_str(&self.info(None));
+        report
+    }
+
+    /// `INFO`: the node's sections, then `replication` and `memory`, which
+    /// only the leader can fill in.
+    fn info(&self, section: Option<&[u8]>) -> String {
+        let mut info = self.core.info(section);
+        let wants = |name: &str| {
+            section.is_none_or(|section| section.eq_ignore_ascii_case(name.as_bytes()))
+        };
+        if wants("replication") {
+            let (_, _, tails) = self.changes.stats();
+            info.push_str("# Replication\n");
+            info.push_str(&format!("lsn:{}\n", self.core.lsn));
+            info.push_str(&format!("followers:{}\n", self.followers.len()));
+            for (i, follower) in self.follower_statuses().into_iter().enumerate() {
+                info.push_str(&format!(
+                    "follower_{}:addr={},state={},voter={},queued={},transfer_backlog={},wan={},lag_alarm={}\n",
+                    i,
+                    follower.addr.as_deref().unwrap_or("-"),
+                    follower.state,
+                    u8::from(follower.voter),
+                    follower.queued,
+                    follower.transfer_backlog,
+                    u8::from(follower.wan),
+                    u8::from(follower.lag_alarm),
+                ));
+            }
+            info.push_str(&format!("tails:{}\n", tails));
+        }
+        if wants("clients") {
+            info.push_str("# Clients\n");
+            info.push_str(&format!(
+                "connected_clients:{}\n",
+                self.clients.clients.len()
+            ));
+            info.push_str(&format!("tracked_keys:{}\n", self.tracking.len()));
+        }
+        if wants("memory") {
+            let (changes, change_bytes, _) = self.changes.stats();
+            let queued: usize = self
+                .followers
+                .iter()
+                .filter_map(|follower| follower.queue.as_ref())
+                .map(ReplicaQueue::queued)
+                .sum();
+            let (history, pins) = self.core.history.stats();
+            info.push_str("# Memory\n");
+            let rss = rss_bytes();
+            if let Some(rss) = rss {
+                info.push_str(&format!("rss_bytes:{}\n", rss));
+            }
+            if let Some(stats) = allocator::stats() {
+                info.push_str(&format!("allocated_bytes:{}\n", stats.allocated));
+                info.push_str(&format!("allocated_peak_bytes:{}\n", stats.peak));
+                info.push_str(&format!("allocations:{}\n", stats.allocations));
+                if let Some(rss) = rss {
+                    let ratio = stats.fragmentation(rss);
+                    info.push_str(&format!("fragmentation_ratio:{:.2}\n", ratio));
+                }
+            }
+            info.push_str(&format!("keyspace_bytes:{}\n", self.keyspace_bytes()));
+            let (interned, interned_bytes) = self.core.hashmap.interned();
+            info.push_str(&format!("interned_values:{}\n", interned));
+            info.push_str(&format!("interned_bytes:{}\n", interned_bytes));
+            info.push_str(&format!("replication_queued_bytes:{}\n", queued));
+            info.push_str(&format!("history_writes:{}\n", history));
+            info.push_str(&format!("history_pins:{}\n", pins));
+            info.push_str(&format!("changes_backlog:{}\n", changes));
+            info.push_str(&format!("changes_backlog_bytes:{}\n", change_bytes));
+        }
+        info
+    }
+
+    /// Counts a request from the tenant `user` against its rates, or refuses
+    /// it if it would take the tenant over one.
+    fn charge(
+        &mut self,
+        user: &str,
+        tenant: &Tenant,
+        command: &Command<'_>,
+    ) -> Result<(), ErrorReply> {
+        let now = self.core.clock.now();
+        let rate = self.rates.entry(user.to_string()).or_default();
+        tenant.charge(rate, command, now)
+    }
+
+    /// Logs `command` to the audit log, if there is one. A failure to write
+    /// it is reported but doesn't hold the command up.
+    fn audit(
+        &mut self,
+        user: Option<&str>,
+        addr: SocketAddr,
+        namespace: &[u8],
+        command: &Command<'_>,
+        allowed: bool,
+    ) {
+        let at = self.core.clock.unix_time().as_millis() as u64;
+        if let Some(log) = &mut self.audit {
+            if let Err(e) = log.record(at, user, addr, namespace, command, allowed) {
+                eprintln!("Error = {:?}", e);
+            }
+        }
+    }
+
+    /// Refuses writes until called again with `false`, for `READONLY` and
+    /// the `read_only` setting. Reads are still served.
+    pub fn set_read_only(&mut self, on: bool) {
+        if self.read_only != on {
+            eprintln!("Read-only mode {}", if on { "on" } else { "off" });
+        }
+        self.read_only = on;
+        self.refuse_writes();
+    }
+
+    fn refuse_writes(&mut self) {
+        self.core.read_only = match (&self.handover, self.read_only) {
+            (Some(addr), _) => Some(format!("leadership is being handed to {}", addr)),
+            (None, true) => Some("the leader is in read-only mode".to_string()),
+            (None, false) => self.low_disk.clone(),
+        };
+    }
+
+    /// Tells subscribers and webhooks about a write that changed the map.
+    fn notify(&self, command: &Command<'_>) {
+        self.pubsub.notify_keyspace(command);
+        self.webhooks.notify(command);
+    }
+
+    /// Turns an `FCALL` into an `EVAL` of the function's script.
+    fn resolve(&self, command: &Command<'_>) -> Result<Option<Command<'static>>, ErrorReply> {
+        match command {
+            Command::FCall(name, keys, args) => {
+                let name = String::from_utf8_lossy(name);
+                let Some(script) = self.functions.get(&*name) else {
+                    let message = format!("no function named {}", name);
+                    return Err(ErrorReply::new(ErrorCode::Script, message));
+                };
+                let script = Cow::Owned(script.to_vec());
+                Ok(Some(
+                    Command::Eval(script, keys.clone(), args.clone()).into_owned(),
+                ))
+            }
+            Command::In(namespace, command) => Ok(self
+                .resolve(command)?
+                .map(|command| Command::In(Cow::Owned(namespace.to_vec()), Box::new(command)))),
+            _ => Ok(None),
+        }
+    }
+
+    /// Keeps the record just written, under the LSN it was written as, for
+    /// `TAIL` connections.
+    fn record_change(&mut self, record: &[u8]) {
+        let now = self.core.clock.unix_time().as_millis() as u64;
+        self.changes.push(self.core.lsn, now, record);
+        if !self.tracking.is_empty() || !self.waiters.is_empty() {
+            let changed = tracking::changed_keys(record);
+            self.tracking.invalidate(&changed);
+            self.waiters
+                .wake(&changed, &self.core.hashmap, self.core.lsn);
+        }
+    }
+
+    /// Pops an element for each connection blocked in `BLPOP` on the lists
+    /// `record` pushed to, longest waiting first, for as long as there are
+    /// elements. Each pop is logged and replicated as a write of its own.
+    async fn serve_poppers(&mut self, record: &[u8]) -> Result<()> {
+        for key in tracking::changed_keys(record) {
+            while let Some(tx) = self.poppers.next(&key) {
+                let pop = scoped(&key.0, Command::LPop(key.1[..].into()));
+                let Response::Get(_, element) = self.write(&pop).await? else {
+                    self.poppers.put_back(&key, tx);
+                    break;
+                };
+                if let Err(element) = tx.send(element) {
+                    // It gave up waiting since it was taken, so the element
+                    // goes back to the head of the list for the next one.
+                    let push = Command::LPush(key.1[..].into(), element[..].into());
+                    self.write(&scoped(&key.0, push)).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs a write the leader makes itself, logging and replicating it as
+    /// `persist` does.
+    async fn write(&mut self, command: &Command<'_>) -> Result<Response> {
+        let (response, record) = self.core.execute(command)?;
+        if let Some(record) = record {
+            self.notify(command);
+            self.record_change(&record);
+            self.replicate(&record).await;
+        }
+        Ok(response)
+    }
+
+    /// Sends `record` to every follower. A follower that lost its stream is
+    /// reconnected to instead, and sent a snapshot of the map, which already
+    /// has the write in it, so that it catches up on whatever it missed.
+    async fn replicate(&mut self, record: &[u8]) {
+        self.promote_learners();
+        let start = self.core.clock.now();
+        let mut snapshot = None;
+        for follower in &mut self.followers {
+            let mut span = Span::start("replication.send");
+            span.attr("follower", follower.addr.as_deref().unwrap_or("synced"));
+            if follower.is_behind() {
+                if follower.open(&*self.transport).await {
+                    let (hashmap, lsn) = (&self.core.hashmap, self.core.lsn);
+                    let install = match follower.pipeline(&self.config) {
+                        Some(pipeline) => snapshot::encode(&pipeline.map(hashmap), lsn),
+                        None => snapshot
+                            .get_or_insert_with(|| snapshot::encode(hashmap, lsn))
+                            .clone(),
+                    };
+                    follower.install = Some(install);
+                    let (leader, transport) = (self.addr.as_deref(), &*self.transport);
+                    follower.connect(leader, &self.config, transport).await;
+                }
+                continue;
+            }
+            follower
+                .send(record, self.addr.as_deref(), &self.config, &*self.transport)
+                .await;
+            follower.check_lag(&self.config);
+        }
+        self.followers.retain(|follower| !follower.is_gone());
+        let elapsed = self.core.clock.now() - start;
+        self.core.metrics.record("replication", elapsed);
+    }
+
+    /// Makes voters of the learners that have caught up with the leader.
+    pub fn promote_learners(&mut self) {
+        for follower in &mut self.followers {
+            if follower.learner && follower.is_caught_up() {
+                eprintln!("{} has caught up, promoting it to a voter", follower.name());
+                follower.learner = false;
+            }
+        }
+    }
+
+    /// Starts replicating to the follower at `addr` as a learner, sending it
+    /// a snapshot of the map before any write. The change is logged and
+    /// replicated, the new node included, so that it outlasts a restart.
+    async fn add_node(&mut self, addr: String) -> Result<Response> {
+        self.promote_learners();
+        if let Some(learner) = self.followers.iter().find(|f| f.learner) {
+            return Ok(membership_busy(learner));
+        }
+        if self
+            .followers
+            .iter()
+            .any(|f| f.read_addr().as_deref() == Some(addr.as_str()))
+        {
+            let message = format!("{} is already a member", addr);
+            return Ok(Response::Error(ErrorReply::new(
+                ErrorCode::WrongArgs,
+                message,
+            )));
+        }
+        let mut replica = Replica::new(addr.clone(), None);
+        replica.learner = true;
+        replica.install = Some(self.snapshot(replica.pipeline(&self.config)));
+        let (leader, transport) = (self.addr.as_deref(), &*self.transport);
+        if !replica.connect(leader, &self.config, transport).await {
+            let message = format!("could not start replicating to {}", addr);
+            return Ok(Response::Error(ErrorReply::new(
+                ErrorCode::WrongArgs,
+                message,
+            )));
+        }
+        eprintln!("Added {} to the cluster as a learner", addr);
+        self.followers.push(replica);
+        // A lease was granted by the old majority.
+        self.lease = None;
+        let command = Command::Cluster(ClusterCommand::AddNode(addr.as_bytes().into()));
+        let written = self.write(&command).await;
+        if written.is_err() {
+            self.followers.pop();
+        }
+        written
+    }
+
+    /// Stops replicating to the follower at `addr`. A learner can be removed
+    /// while it catches up, calling off its addition. The change is logged
+    /// and replicated as [`Leader::add_node`]'s is.
+    async fn remove_node(&mut self, addr: &str) -> Result<Response> {
+        self.promote_learners();
+        let Some(i) = self
+            .followers
+            .iter()
+            .position(|f| f.read_addr().as_deref() == Some(addr))
+        else {
+            let message = format!("{} is not a member", addr);
+            return Ok(Response::Error(ErrorReply::new(
+                ErrorCode::WrongArgs,
+                message,
+            )));
+        };
+        let learner = self
+            .followers
+            .iter()
+            .enumerate()
+            .find(|(j, f)| f.learner && *j != i);
+        if let Some((_, learner)) = learner {
+            return Ok(membership_busy(learner));
+        }
+        let removed = self.followers.remove(i);
+        eprintln!("Removed {} from the cluster", addr);
+        self.lease = None;
+        let command = Command::Cluster(ClusterCommand::RemoveNode(addr.as_bytes().into()));
+        let written = self.write(&command).await;
+        if written.is_err() {
+            self.followers.insert(i, removed);
+        }
+        written
+    }
+
+    /// Brings the followers the leader was started with in line with the
+    /// membership changes replayed from its log: a node `CLUSTER ADD-NODE`
+    /// added rejoins, starting from a snapshot of the map, and one
+    /// `REMOVE-NODE` removed is dropped. A node that can't be reached yet is
+    /// caught up with a snapshot once it can, as any follower that fell
+    /// behind is.
+    pub async fn restore_membership(&mut self) {
+        let membership: Vec<_> = self
+            .core
+            .hashmap
+            .membership()
+            .map(|(addr, member)| (addr.to_string(), member))
+            .collect();
+        for (addr, member) in membership {
+            let known = self
+                .followers
+                .iter()
+                .position(|f| f.read_addr().as_deref() == Some(addr.as_str()));
+            match (known, member) {
+                (Some(i), false) => {
+                    self.followers.remove(i);
+                }
+                (None, true) => {
+                    let mut replica = Replica::new(addr.clone(), None);
+                    replica.install = Some(self.snapshot(replica.pipeline(&self.config)));
+                    let (leader, transport) = (self.addr.as_deref(), &*self.transport);
+                    if !replica.connect(leader, &self.config, transport).await {
+                        replica.behind = true;
+                    }
+                    eprintln!("Rejoined {} to the cluster", addr);
+                    self.followers.push(replica);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Deletes the keys whose TTL is up, for when no command has come along
+    /// to do it.
+    pub async fn expire(&mut self) -> Result<()> {
+        if let Some(record) = self.core.expire()? {
+            self.record_change(&record);
+            self.replicate(&record).await;
+        }
+        Ok(())
+    }
+
+    /// Forgets the request IDs of clients that have sent no write under one
+    /// for `request_dedup_timeout_ms`.
+    pub fn expire_requests(&mut self) {
+        let timeout = Duration::from_millis(self.config.request_dedup_timeout_ms);
+        let now = self.core.clock.now();
+        self.requests.expire(now, timeout);
+    }
+
+    /// Closes the connections holding `EPHEMERAL` keys that have sent
+    /// nothing for `session_timeout_ms`, which deletes their keys.
+    pub fn expire_sessions(&mut self) {
+        let timeout = Duration::from_millis(self.config.session_timeout_ms);
+        if timeout.is_zero() {
+            return;
+        }
+        let now = self.core.clock.now();
+        let ids: Vec<u64> = self.sessions.keys.keys().copied().collect();
+        for id in ids {
+            match self.clients.last_active(id) {
+                Some(active) if now - active >= timeout => {
+                    self.clients.kill(id);
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Deletes the `EPHEMERAL` keys connection `id` set, each only if it
+    /// still has the value the connection gave it.
+    pub async fn end_session(&mut self, id: u64) -> Result<()> {
+        for (namespace, key, val) in self.sessions.end(id) {
+            let release = Command::DelIfEq(key[..].into(), val[..].into());
+            self.persist(&scoped(&namespace, release)).await?;
+        }
+        Ok(())
+    }
+
+    /// A snapshot of the map, as `pipeline` transforms it if there is one.
+    fn snapshot(&self, pipeline: Option<Pipeline>) -> Bytes {
+        match pipeline {
+            Some(pipeline) => snapshot::encode(&pipeline.map(&self.core.hashmap), self.core.lsn),
+            None => snapshot::encode(&self.core.hashmap, self.core.lsn),
+        }
+    }
+
+    /// Starts sending a snapshot to a follower, or resumes the transfer it
+    /// asks for if that is still around. Returns the transfer's id, the
+    /// snapshot and the offset to send it from.
+    fn start_sync(
+        &mut self,
+        resume: Option<(u64, usize)>,
+        reads_at: Option<String>,
+    ) -> (u64, Bytes, usize) {
+        if let Some((id, offset)) = resume {
+            let transfer = self
+                .followers
+                .iter()
+                .filter_map(|follower| follower.transfer.as_ref())
+                .find(|transfer| transfer.id == id && offset <= transfer.snapshot.len());
+            if let Some(transfer) = transfer {
+                return (id, transfer.snapshot.clone(), offset);
+            }
+        }
+        let id = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_nanos() as u64);
+        let mut replica = Replica {
+            addr: None,
+            connection: None,
+            queue: None,
+            transfer: None,
+            learner: true,
+            reads_at,
+            install: None,
+            behind: false,
+            lag_alarm: false,
+            transforms: Vec::new(),
+        };
+        let snapshot = self.snapshot(replica.pipeline(&self.config));
+        replica.transfer = Some(Transfer {
+            id,
+            snapshot: snapshot.clone(),
+            backlog: Vec::new(),
+            backlog_len: 0,
+        });
+        self.followers.push(replica);
+        (id, snapshot, 0)
+    }
+
+    /// Sends a follower that has received the whole snapshot the writes made
+    /// in the meantime, and replicates to it over `socket` from then on.
+    async fn finish_sync(&mut self, id: u64, socket: Connection, lz4: bool) -> Result<()> {
+        let follower = self
+            .followers
+            .iter_mut()
+            .find(|follower| follower.transfer.as_ref().is_some_and(|t| t.id == id));
+        let Some(follower) = follower else {
+            let message = format!("snapshot transfer {} was abandoned", id);
+            return Err(DistKvError::link_down(message));
+        };
+        let transfer = follower.transfer.take().unwrap();
+        let wan = follower.is_wan(&self.config);
+        let mut stream = ReplicaStream::new(socket);
+        stream.set_batching(match wan {
+            true => self.config.wan_batching(),
+            false => self.config.batching(),
+        });
+        if lz4 {
+            stream.compress();
+        }
+        for record in transfer.backlog {
+            stream.send(&record).await?;
+        }
+        let limit = match wan {
+            true => self.config.wan_queue_limit(),
+            false => self.config.queue_limit(),
+        };
+        let queue = ReplicaQueue::start(stream, limit, follower.name());
+        follower.queue = Some(queue);
+        Ok(())
+    }
+
+    /// The `HELLO` features the leader agrees to: all of them, except `lz4`
+    /// unless replication is to be compressed.
+    fn features(&self) -> Vec<&'static str> {
+        FEATURES
+            .iter()
+            .copied()
+            .filter(|feature| *feature != "lz4" || self.config.replication_compression)
+            .collect()
+    }
+
+    /// Writes out the records every follower's stream is holding back, which
+    /// [`serve`] does every `replication_flush_ms`.
+    pub fn flush_replication(&self) {
+        for queue in self.followers.iter().filter_map(|f| f.queue.as_ref()) {
+            queue.flush();
+        }
+    }
+
+    /// Closes the replication streams, letting followers see the end of the
+    /// log.
+    pub async fn shutdown(&mut self) -> Result<()> {
+        for follower in &mut self.followers {
+            if let Some(queue) = follower.queue.take() {
+                queue.shutdown().await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+pub type SyncLeader = Arc<tokio::sync::Mutex<Leader>>;
+
+/// A `SETSTREAM` upload in progress. Chunks are kept as the separate buffers
+/// they arrived in and only joined once the final empty chunk is received.
+struct Upload {
+    key: Bytes,
+    chunks: Vec<BytesMut>,
+}
+
+/// A `BULKLOAD` in progress. Its writes go unanswered, so the client can
+/// stream them without waiting, and are gathered until `bulkload_batch` of
+/// them are in or `BULKLOAD END` comes, then applied as a `BATCH`: one log
+/// append, one fsync and one record for followers for the lot. The first
+/// write refused ends the load, and the writes after it are skipped rather
+/// than applied out of order; `BULKLOAD END` then answers with the error.
+struct Load {
+    pending: Vec<Command<'static>>,
+    batch: usize,
+    loaded: u64,
+    failed: Option<ErrorReply>,
+}
+
+impl Load {
+    fn fail(&mut self, err: ErrorReply) {
+        self.pending.clear();
+        self.failed.get_or_insert(err);
+    }
+
+    /// Applies the writes gathered so far.
+    async fn flush(
+        &mut self,
+        leader: &SyncLeader,
+        tenant: Option<&Tenant>,
+        namespace: &[u8],
+    ) -> Result<()> {
+        if self.pending.is_empty() || self.failed.is_some() {
+            return Ok(());
+        }
+        let writes = mem::take(&mut self.pending);
+        let n = writes.len() as u64;
+        match persist_as(leader, tenant, namespace, Command::Batch(writes)).await? {
+            (Response::Error(err), _) => self.fail(err),
+            _ => self.loaded += n,
+        }
+        Ok(())
+    }
+}
+
+/// Re-reads the config file at `path` whenever the process gets `SIGHUP`,
+/// applying what can change live and reporting what can't. Must be called
+/// from within a tokio runtime; the signal is handled from then on.
+pub fn reload_on_hangup(leader: SyncLeader, path: String) -> Result<()> {
+    let mut hangups = signal(SignalKind::hangup())?;
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            let config = match Config::load(&path) {
+                Ok(config) => config,
+                Err(e) => {
+                    eprintln!("Not reloading {}: {:#}", path, e);
+                    continue;
+                }
+            };
+            let reload = leader.lock().await.reload(&config);
+            eprintln!(
+                "Reloaded {}: applied {:?}, needs a restart: {:?}",
+                path, reload.applied, reload.needs_restart
+            );
+        }
+    });
+    Ok(())
+}
+
+/// Writes a [state dump](dump_state) whenever the process gets `SIGUSR1`.
+/// Must be called from within a tokio runtime; the signal is handled from
+/// then on.
+pub fn dump_state_on_signal(leader: SyncLeader) -> Result<()> {
+    let mut signals = signal(SignalKind::user_defined1())?;
+    tokio::spawn(async move {
+        while signals.recv().await.is_some() {
+            match dump_state(&leader).await {
+                Ok(path) => eprintln!("Dumped state to {}", path.display()),
+                Err(e) => eprintln!("Error = {:?}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Writes a report of the leader's state to `dumpstate-<unix ms>.txt` in
+/// its data directory, for `DEBUG DUMPSTATE` and `SIGUSR1`, returning its
+/// path. The report is taken in one turn of the leader's lock and written
+/// out after.
+pub async fn dump_state(leader: &SyncLeader) -> Result<PathBuf> {
+    let waiting = Instant::now();
+    let leader = leader.lock().await;
+    let lock_wait = waiting.elapsed();
+    let at = leader.core.clock.unix_time().as_millis();
+    let path = leader.data_dir.join(format!("dumpstate-{}.txt", at));
+    let report = leader.dump_state(at, lock_wait);
+    drop(leader);
+    fs::write(&path, report).with_context(|| format!("writing {}", path.display()))?;
+    Ok(path)
+}
+
+/// The process's resident set size, where `/proc` has it.
+fn rss_bytes() -> Option<u64> {
+    let statm = fs::read_to_string("/proc/self/statm").ok()?;
+    let pages: u64 = statm.split_whitespace().nth(1)?.parse().ok()?;
+    let page_size = sysconf(SysconfVar::PAGE_SIZE).ok()??;
+    Some(pages * page_size as u64)
+}
+
+/// Accepts client connections until the task is dropped, which also drops
+/// every connection it accepted and stops taking checkpoints. Connections
+/// are also taken on the ports of `listen` lines, each running only the
+/// commands its line accepts.
+pub async fn serve(mut listener: Listener, leader: SyncLeader, limits: Limits) {
+    let (watched, batching, checkpoint_every, commands, listeners, transport) = {
+        let mut leader = leader.lock().await;
+        leader.set_limits(limits);
+        let read_only = leader.config.read_only;
+        leader.set_read_only(read_only);
+        leader.core.history.limit = leader.config.history_size;
+        let interning = leader.config.intern_values;
+        leader.core.hashmap.set_interning(interning);
+        if let Some(path) = leader.config.audit_log.clone() {
+            match AuditLog::open(path, leader.config.audit_log_size as u64) {
+                Ok(log) => leader.audit = Some(log),
+                Err(e) => eprintln!("Error = {:?}", e),
+            }
+        }
+        let segment_size = leader.config.log_segment_size as u64;
+        if let Err(e) = wal::preallocate(&leader.core.wal, segment_size) {
+            eprintln!("Error = {:?}", e);
+        }
+        (
+            leader.limits.subscribe(),
+            leader.config.batching(),
+            leader.config.checkpoint_interval,
+            Arc::new(leader.config.commands.clone()),
+            leader.config.listeners.clone(),
+            leader.transport.clone(),
+        )
+    };
+    let (handoff, mut handed) = mpsc::unbounded_channel();
+    let mut listening = JoinSet::new();
+    for (addr, commands) in listeners {
+        match transport.bind(&addr).await {
+            Ok(listener) => {
+                listening.spawn(accept(listener, Arc::new(commands), handoff.clone()));
+            }
+            Err(e) => eprintln!("Error = listening on {}: {:?}", addr, e),
+        }
+    }
+    #[cfg(feature = "dashboard")]
+    if let Some(addr) = leader.lock().await.config.dashboard.clone() {
+        match tokio::net::TcpListener::bind(&addr).await {
+            Ok(listener) => {
+                listening.spawn(crate::dashboard::serve(listener, leader.clone()));
+            }
+            Err(e) => eprintln!("Error = serving the dashboard on {}: {:?}", addr, e),
+        }
+    }
+    let mut connections = JoinSet::new();
+    let mut checkpoints = JoinSet::new();
+    if !checkpoint_every.is_zero() {
+        checkpoints.spawn(checkpoint::run(leader.clone(), checkpoint_every));
+    }
+    let mut expiry = tokio::time::interval(EXPIRE_INTERVAL);
+    let mut disk_check = tokio::time::interval(DISK_CHECK_INTERVAL);
+    let flush_every = batching.max_delay.max(Duration::from_millis(1));
+    let mut flush =
+        tokio::time::interval_at(tokio::time::Instant::now() + flush_every, flush_every);
+    loop {
+        let (socket, addr, commands) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok((socket, addr)) => (socket, addr, commands.clone()),
+                Err(e) => {
+                    eprintln!("Error = {:?}", e);
+                    continue;
+                }
+            },
+            Some(conn) = handed.recv() => conn,
+            Some(_) = connections.join_next() => continue,
+            _ = expiry.tick() => {
+                let mut leader = leader.lock().await;
+                if let Err(e) = leader.expire().await {
+                    eprintln!("Error = {:?}", e);
+                }
+                leader.expire_sessions();
+                leader.expire_requests();
+                continue;
+            }
+            _ = disk_check.tick() => {
+                if let Err(e) = leader.lock().await.check_disk() {
+                    eprintln!("Error = {:?}", e);
+                }
+                continue;
+            }
+            _ = flush.tick(), if !batching.is_off() => {
+                leader.lock().await.flush_replication();
+                continue;
+            }
+        };
+        if let Err(e) = socket.set_nodelay(true) {
+            eprintln!("Error = {:?}", e);
+        }
+        let leader = leader.clone();
+        let limits = watched.clone();
+        connections.spawn(async move {
+            let (id, killed) = {
+                let mut leader = leader.lock().await;
+                let now = leader.core.clock.now();
+                leader.clients.register(addr, now)
+            };
+            let connection =
+                handle_connection(socket, id, addr, &commands, &killed, &leader, &limits);
+            if let Err(e) = connection.await {
+                eprintln!("Error = {:?}", e);
+            }
+            let mut leader = leader.lock().await;
+            leader.clients.unregister(id);
+            leader.pubsub.unsubscribe_all(id);
+            leader.tracking.disable(id);
+            leader.requests.forget(id);
+            if let Err(e) = leader.end_session(id).await {
+                eprintln!("Error = {:?}", e);
+            }
+        });
+    }
+}
+
+/// Takes connections on the port of a `listen` line, handing each to
+/// `serve` with the commands the port accepts.
+async fn accept(
+    mut listener: Listener,
+    commands: Arc<Accepted>,
+    handoff: mpsc::UnboundedSender<(Connection, SocketAddr, Arc<Accepted>)>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok((socket, addr)) => {
+                if handoff.send((socket, addr, commands.clone())).is_err() {
+                    return;
+                }
+            }
+            Err(e) => eprintln!("Error = {:?}", e),
+        }
+    }
+}
+
+async fn handle_connection(
+    mut socket: Connection,
+    id: u64,
+    addr: SocketAddr,
+    commands: &Accepted,
+    killed: &Notify,
+    leader: &SyncLeader,
+    limits: &watch::Receiver<Limits>,
+) -> Result<()> {
+    let mut buf = BytesMut::with_capacity(4096);
+    let mut upload: Option<Upload> = None;
+    let mut load: Option<Load> = None;
+    let mut namespace = Bytes::from_static(DEFAULT_NAMESPACE);
+    let mut tenant: Option<Tenant> = None;
+    // The name of the tenant, for the audit log.
+    let mut user: Option<String> = None;
+    // Whether HELLO asked for the ID of each write ahead of its reply.
+    let mut request_ids = false;
+    // Whether HELLO agreed to compress a replication stream `SYNC` asks for.
+    let mut lz4 = false;
+    // Whether CLIENT TRACKING is on, so reads are noted for invalidation.
+    let mut tracking = false;
+    // The ID a REQID gave the next command, if it is a write.
+    let mut next_request: Option<Bytes> = None;
+    // Messages for the channels this connection subscribes to.
+    let (pushes, mut pushed) = mpsc::unbounded_channel();
+    loop {
+        loop {
+            // Copied, as CONFIG SET may change them between requests.
+            let limits = &limits.borrow().clone();
+            let mut reply = BytesMut::new();
+            let frame = match split_frame(&mut buf, limits) {
+                Ok(Some(frame)) => frame,
+                Ok(None) => break,
+                Err(err) => {
+                    // The stream can't be framed past this point.
+                    Response::Error(err.into()).encode(&mut reply);
+                    socket.write_all(&reply).await?;
+                    return Ok(());
+                }
+            };
+            if let Some(Upload { key, chunks }) = upload.as_mut() {
+                match frame.chunk() {
+                    Some(chunk) if !chunk.is_empty() => {
+                        let len = chunks.iter().map(|chunk| chunk.len()).sum::<usize>();
+                        if let Err(err) = limits.check_value(len + chunk.len()) {
+                            Response::Error(err.into()).encode(&mut reply);
+                            socket.write_all(&reply).await?;
+                            return Ok(());
+                        }
+                        chunks.push(frame.payload.unwrap());
+                        continue;
+                    }
+                    Some(_) => {
+                        let val = join_chunks(mem::take(chunks));
+                        let command = Command::Set(key[..].into(), val[..].into());
+                        // The value counts against the tenant's rate once it is
+                        // all there.
+                        let charged = match (&tenant, &user) {
+                            (Some(tenant), Some(user)) => {
+                                leader.lock().await.charge(user, tenant, &command)
+                            }
+                            _ => Ok(()),
+                        };
+                        match charged {
+                            Ok(()) => {
+                                let (response, request_id) =
+                                    persist_as(leader, tenant.as_ref(), &namespace, command)
+                                        .await?;
+                                if let Some(id) = request_id.filter(|_| request_ids) {
+                                    reply.extend_from_slice(&push(&[b"reqid", id.as_bytes()]));
+                                }
+                                response.encode(&mut reply);
+                            }
+                            Err(err) => Response::Error(err).encode(&mut reply),
+                        }
+                    }
+                    None => {
+                        let err = ErrorReply::new(ErrorCode::Syntax, "expected a $<len> chunk");
+                        Response::Error(err).encode(&mut reply);
+                    }
+                }
+                upload = None;
+                socket.write_all(&reply).await?;
+                continue;
+            }
+
+            let command = match frame.command(limits) {
+                Ok(command) => command,
+                Err(ParseError::Empty) => continue,
+                Err(err) => {
+                    if let Some(load) = load.as_mut() {
+                        load.fail(err.into());
+                        continue;
+                    }
+                    Response::Error(err.into()).encode(&mut reply);
+                    socket.write_all(&reply).await?;
+                    continue;
+                }
+            };
+            // A REQID only names the command right after it.
+            let request = next_request.take();
+            if !commands.accepts(&command) {
+                let err = ErrorReply::new(
+                    ErrorCode::NoPerm,
+                    format!("this port doesn't accept {}", command.name()),
+                );
+                if let Some(load) = load.as_mut() {
+                    load.fail(err);
+                    continue;
+                }
+                Response::Error(err).encode(&mut reply);
+                socket.write_all(&reply).await?;
+                continue;
+            }
+            let open = {
+                let mut leader = leader.lock().await;
+                let now = leader.core.clock.now();
+                leader.clients.touch(id, command.name(), now);
+                leader.tenants.is_empty()
+            };
+            let permitted = match &tenant {
+                Some(tenant) => tenant.permits(&command),
+                None if open
+                    || matches!(
+                        command,
+                        Command::Hello(..)
+                            | Command::Auth(..)
+                            | Command::WhoAmI
+                            | Command::WhoIsLeader
+                            | Command::Peers
+                    ) =>
+                {
+                    Ok(())
+                }
+                None => Err(ErrorReply::new(
+                    ErrorCode::NoAuth,
+                    "authentication required",
+                )),
+            };
+            if audit::is_audited(&command) && !matches!(command, Command::Auth(..)) {
+                let allowed = permitted.is_ok();
+                let mut leader = leader.lock().await;
+                leader.audit(user.as_deref(), addr, &namespace, &command, allowed);
+            }
+            if let Err(err) = permitted {
+                if let Some(load) = load.as_mut() {
+                    load.fail(err);
+                    continue;
+                }
+                Response::Error(err).encode(&mut reply);
+                socket.write_all(&reply).await?;
+                continue;
+            }
+            if let (Some(tenant), Some(user)) = (&tenant, &user) {
+                let charged = match command {
+                    // Left uncounted, so a tenant over its rate can see why.
+                    Command::Quota => Ok(()),
+                    _ => leader.lock().await.charge(user, tenant, &command),
+                };
+                if let Err(err) = charged {
+                    if let Some(load) = load.as_mut() {
+                        load.fail(err);
+                        continue;
+                    }
+                    Response::Error(err).encode(&mut reply);
+                    socket.write_all(&reply).await?;
+                    continue;
+                }
+            }
+            if let Some(current) = load.as_mut() {
+                match command {
+                    Command::Set(..) | Command::Delete(_) => {
+                        if current.failed.is_none() {
+                            current.pending.push(command.into_owned());
+                        }
+                        if current.pending.len() >= current.batch {
+                            current.flush(leader, tenant.as_ref(), &namespace).await?;
+                        }
+                    }
+                    Command::BulkLoadEnd => {
+                        current.flush(leader, tenant.as_ref(), &namespace).await?;
+                        match current.failed.take() {
+                            Some(err) => {
+                                let message =
+                                    format!("{} ({} writes loaded)", err.message, current.loaded);
+                                Response::Error(ErrorReply::new(err.code, message))
+                            }
+                            None => Response::Integer(current.loaded as i64),
+                        }
+                        .encode(&mut reply);
+                        load = None;
+                        socket.write_all(&reply).await?;
+                    }
+                    command => current.fail(ErrorReply::new(
+                        ErrorCode::WrongArgs,
+                        format!("{} can't be part of a bulk load", command.name()),
+                    )),
+                }
+                continue;
+            }
+            match command {
+                Command::BulkLoad => {
+                    let batch = leader.lock().await.config.bulkload_batch;
+                    load = Some(Load {
+                        pending: Vec::new(),
+                        batch,
+                        loaded: 0,
+                        failed: None,
+                    });
+                    Response::Ok.encode(&mut reply);
+                }
+                Command::BulkLoadEnd => {
+                    let err = ErrorReply::new(ErrorCode::WrongArgs, "no bulk load in progress");
+                    Response::Error(err).encode(&mut reply);
+                }
+                Command::SetStream(key) => {
+                    upload = Some(Upload {
+                        key: Bytes::copy_from_slice(&key),
+                        chunks: Vec::new(),
+                    });
+                    reply.extend_from_slice(b"+OK\n");
+                }
+                Command::Sync(resume, reads_at) => {
+                    let reads_at = reads_at.map(|addr| String::from_utf8_lossy(&addr).into_owned());
+                    return sync_follower(socket, leader, resume, reads_at, lz4).await;
+                }
+                Command::Select(name) if name.is_empty() => {
+                    let err =
+                        ErrorReply::new(ErrorCode::WrongArgs, "namespace names can't be empty");
+                    Response::Error(err).encode(&mut reply);
+                }
+                Command::Select(name) => {
+                    namespace = Bytes::copy_from_slice(&name);
+                    Response::Ok.encode(&mut reply);
+                }
+                Command::Auth(ref name, ref password) => {
+                    let name = String::from_utf8_lossy(name).into_owned();
+                    let mut locked = leader.lock().await;
+                    let found = locked.tenants.get(&name).cloned();
+                    let found = found.filter(|found| found.password.as_bytes() == &password[..]);
+                    locked.audit(Some(&name), addr, &namespace, &command, found.is_some());
+                    drop(locked);
+                    match found {
+                        Some(found) => {
+                            tenant = Some(found);
+                            user = Some(name);
+                            Response::Ok.encode(&mut reply);
+                        }
+                        None => {
+                            let err =
+                                ErrorReply::new(ErrorCode::NoAuth, "invalid user or password");
+                            Response::Error(err).encode(&mut reply);
+                        }
+                    }
+                }
+                Command::Client(ClientCommand::SetName(name)) => {
+                    let response = leader.lock().await.clients.set_name(id, &name);
+                    response.encode(&mut reply);
+                }
+                Command::RequestId(request) => {
+                    next_request = Some(Bytes::copy_from_slice(&request));
+                    Response::Ok.encode(&mut reply);
+                }
+                Command::WaitChange(key, timeout) => {
+                    let changed = leader.lock().await.waiters.wait(&namespace, &key);
+                    let changed = async {
+                        match timeout.filter(|ms| *ms > 0) {
+                            Some(ms) => {
+                                let timeout = Duration::from_millis(ms);
+                                tokio::time::timeout(timeout, changed).await.ok()?.ok()
+                            }
+                            None => changed.await.ok(),
+                        }
+                    };
+                    let changed = tokio::select! {
+                        changed = changed => changed,
+                        _ = killed.notified() => return Ok(()),
+                    };
+                    match changed {
+                        Some((lsn, val)) => {
+                            let lsn = lsn.to_string();
+                            match val {
+                                Some(val) => {
+                                    reply.extend_from_slice(&push(&[lsn.as_bytes(), &val]))
+                                }
+                                None => reply.extend_from_slice(&push(&[lsn.as_bytes()])),
+                            }
+                        }
+                        None => {
+                            Response::KeyNotFound(Key::copy_from_slice(&key)).encode(&mut reply)
+                        }
+                    }
+                }
+                Command::BLPop(key, timeout) => {
+                    // Popping and queueing up happen under one lock, so that
+                    // no push can come in between.
+                    let mut locked = leader.lock().await;
+                    let pop = scoped(&namespace, Command::LPop(key.clone()));
+                    let response = locked.persist(&pop).await?;
+                    let response = match response {
+                        Response::KeyNotFound(_) => {
+                            let mut handed = locked.poppers.wait(&namespace, &key);
+                            drop(locked);
+                            let popped = async {
+                                match timeout.filter(|ms| *ms > 0) {
+                                    Some(ms) => {
+                                        let timeout = Duration::from_millis(ms);
+                                        let popped = tokio::time::timeout(timeout, &mut handed);
+                                        popped.await.ok()?.ok()
+                                    }
+                                    None => (&mut handed).await.ok(),
+                                }
+                            };
+                            let popped = tokio::select! {
+                                popped = popped => Some(popped),
+                                _ = killed.notified() => None,
+                            };
+                            // An element may have been popped for this
+                            // connection just as it stopped waiting.
+                            handed.close();
+                            let late = handed.try_recv().ok();
+                            let Some(popped) = popped else {
+                                // Killed, so it goes back to the head of the
+                                // list for the next one.
+                                if let Some(element) = late {
+                                    let push = Command::LPush(key.clone(), element[..].into());
+                                    leader
+                                        .lock()
+                                        .await
+                                        .persist(&scoped(&namespace, push))
+                                        .await?;
+                                }
+                                return Ok(());
+                            };
+                            match popped.or(late) {
+                                Some(element) => Response::Get(Key::copy_from_slice(&key), element),
+                                None => Response::KeyNotFound(Key::copy_from_slice(&key)),
+                            }
+                        }
+                        response => response,
+                    };
+                    response.encode(&mut reply);
+                }
+                Command::Client(ClientCommand::Tracking(on)) => {
+                    let mut leader = leader.lock().await;
+                    match on {
+                        true => leader.tracking.enable(id, pushes.clone()),
+                        false => leader.tracking.disable(id),
+                    }
+                    tracking = on;
+                    Response::Ok.encode(&mut reply);
+                }
+                Command::Subscribe(channel) => {
+                    let n = leader
+                        .lock()
+                        .await
+                        .pubsub
+                        .subscribe(&channel, id, pushes.clone());
+                    Response::Integer(n as i64).encode(&mut reply);
+                }
+                Command::Tail(from) => {
+                    let mut leader = leader.lock().await;
+                    let next = leader.core.lsn + 1;
+                    match leader.changes.tail(from, next, pushes.clone()) {
+                        Ok(()) => Response::Ok.encode(&mut reply),
+                        Err(err) => Response::Error(err).encode(&mut reply),
+                    }
+                }
+                Command::Unsubscribe(channel) => {
+                    let n = leader.lock().await.pubsub.unsubscribe(&channel, id);
+                    Response::Integer(n as i64).encode(&mut reply);
+                }
+                Command::PSubscribe(pattern) => {
+                    let n = leader
+                        .lock()
+                        .await
+                        .pubsub
+                        .psubscribe(&pattern, id, pushes.clone());
+                    Response::Integer(n as i64).encode(&mut reply);
+                }
+                Command::PUnsubscribe(pattern) => {
+                    let n = leader.lock().await.pubsub.punsubscribe(&pattern, id);
+                    Response::Integer(n as i64).encode(&mut reply);
+                }
+                Command::GetStream(key, chunk_size) => {
+                    let val = {
+                        let leader = leader.lock().await;
+                        match leader.core.loading {
+                            Some(_) => Err(ErrorReply::new(
+                                ErrorCode::Loading,
+                                "the dataset is still being loaded",
+                            )),
+                            None => Ok(leader
+                                .core
+                                .hashmap
+                                .namespace(&namespace)
+                                .and_then(|hashmap| hashmap.get(&key[..]))
+                                .cloned()),
+                        }
+                    };
+                    match val {
+                        Ok(Some(val)) => send_chunks(&mut socket, &val, chunk_size).await?,
+                        Ok(None) => Response::KeyNotFound(Key::new()).encode(&mut reply),
+                        Err(err) => Response::Error(err).encode(&mut reply),
+                    }
+                }
+                Command::Analyze(top) => analyze(leader, top, &namespace).await.encode(&mut reply),
+                Command::Export(format, prefix) => {
+                    let prefix = prefix.as_deref().unwrap_or_default();
+                    if let Err(err) =
+                        export(leader, &mut socket, format, prefix, &namespace).await?
+                    {
+                        Response::Error(err).encode(&mut reply);
+                    }
+                }
+                Command::GetWith(key, Consistency::Quorum) => quorum_get(leader, &namespace, &key)
+                    .await
+                    .encode(&mut reply),
+                Command::Debug(command) => {
+                    debug(leader, &namespace, command).await.encode(&mut reply)
+                }
+                Command::TransferLeadership(addr) => {
+                    let addr = String::from_utf8_lossy(&addr).into_owned();
+                    transfer_leadership(leader, addr).await.encode(&mut reply)
+                }
+                Command::DumpState => {
+                    let response = match dump_state(leader).await {
+                        Ok(path) => Response::Info(path.display().to_string()),
+                        Err(e) => {
+                            let message = format!("not dumped: {:#}", e);
+                            Response::Error(ErrorReply::new(ErrorCode::NotSupported, message))
+                        }
+                    };
+                    response.encode(&mut reply);
+                }
+                Command::Quota => {
+                    let response = match (&tenant, &user) {
+                        (Some(tenant), Some(user)) => {
+                            let mut leader = leader.lock().await;
+                            let now = leader.core.clock.now();
+                            let rate = leader.rates.entry(user.clone()).or_default();
+                            Response::Info(tenant.render_rate(rate, now))
+                        }
+                        _ => Response::Error(ErrorReply::new(
+                            ErrorCode::NotSupported,
+                            "QUOTA reports the rates of the tenant the connection authenticated as",
+                        )),
+                    };
+                    response.encode(&mut reply);
+                }
+                command => {
+                    if let Command::Hello(_, features) = &command {
+                        request_ids = node::wants_feature(features.as_deref(), "reqid");
+                        lz4 = node::wants_feature(features.as_deref(), "lz4")
+                            && leader.lock().await.config.replication_compression;
+                    }
+                    let mut span = Span::start("request");
+                    span.attr("command", command.name());
+                    span.attr("client.id", id);
+                    let ephemeral = match &command {
+                        Command::Ephemeral(key, val) => Some((key.to_vec(), val.to_vec())),
+                        _ => None,
+                    };
+                    // Noted before the read, so that no write can come
+                    // between the two without an invalidation.
+                    if tracking && !command.is_write() && command.is_keyed() {
+                        let keys = command.keys();
+                        leader.lock().await.tracking.read(id, &namespace, &keys);
+                    }
+                    let (response, request_id) = match request.filter(|_| command.is_write()) {
+                        Some(request) => {
+                            let requester =
+                                leader.lock().await.clients.requester(id, user.as_deref());
+                            let persisting = persist_once(
+                                leader,
+                                tenant.as_ref(),
+                                &namespace,
+                                command,
+                                requester,
+                                request,
+                            );
+                            span.enter(persisting).await?
+                        }
+                        None => {
+                            let persisting =
+                                persist_as(leader, tenant.as_ref(), &namespace, command);
+                            span.enter(persisting).await?
+                        }
+                    };
+                    if let Some((key, val)) = ephemeral {
+                        if matches!(response, Response::Set(..) | Response::Replace(..)) {
+                            let mut leader = leader.lock().await;
+                            leader.sessions.own(id, &namespace, &key, &val);
+                        }
+                    }
+                    if let Some(id) = &request_id {
+                        span.attr("request.id", id);
+                    }
+                    if let Some(id) = request_id.filter(|_| request_ids) {
+                        reply.extend_from_slice(&push(&[b"reqid", id.as_bytes()]));
+                    }
+                    response.encode(&mut reply);
+                }
+            }
+            socket.write_all(&reply).await?;
+        }
+        let read = tokio::select! {
+            read = socket.read_buf(&mut buf) => read?,
+            _ = killed.notified() => return Ok(()),
+            Some(push) = pushed.recv() => {
+                socket.write_all(&push).await?;
+                continue;
+            }
+        };
+        if read == 0 {
+            return Ok(());
+        }
+    }
+}
+
+fn is_script(command: &Command<'_>) -> bool {
+    match command {
+        Command::Eval(..) => true,
+        Command::In(_, command) => is_script(command),
+        _ => false,
+    }
+}
+
+/// Runs a keyed command in the connection's namespace.
+fn scoped<'a>(namespace: &'a [u8], command: Command<'a>) -> Command<'a> {
+    if namespace == DEFAULT_NAMESPACE || !command.is_keyed() {
+        return command;
+    }
+    Command::In(namespace.into(), Box::new(command))
+}
+
+/// Runs a command for a connection: in its namespace and, if it has
+/// authenticated as a tenant, only if the tenant stays within its quotas.
+/// Returns the response and, if the command logged a write, its request ID.
+async fn persist_as(
+    leader: &SyncLeader,
+    tenant: Option<&Tenant>,
+    namespace: &[u8],
+    command: Command<'_>,
+) -> Result<(Response, Option<String>)> {
+    let mut leader = leader.lock().await;
+    persist_locked(&mut leader, tenant, namespace, command).await
+}
+
+/// [`persist_as`] for a caller already holding the lock.
+async fn persist_locked(
+    leader: &mut Leader,
+    tenant: Option<&Tenant>,
+    namespace: &[u8],
+    command: Command<'_>,
+) -> Result<(Response, Option<String>)> {
+    if let Some(tenant) = tenant {
+        if let Err(err) = tenant.admits(&leader.core.hashmap, namespace, &command) {
+            return Ok((Response::Error(err), None));
+        }
+    }
+    leader.core.request_id = None;
+    let response = leader.persist(&scoped(namespace, command)).await?;
+    Ok((response, leader.core.request_id.take()))
+}
+
+/// Runs a write a client sent after `REQID <request>` as [`persist_as`]
+/// does, unless the client sent it before, in which case it is answered as
+/// it was the first time and not applied again. Failed writes aren't
+/// remembered, so they can be retried. Checking and writing under one lock
+/// means a retry racing the original is still applied only once.
+async fn persist_once(
+    leader: &SyncLeader,
+    tenant: Option<&Tenant>,
+    namespace: &[u8],
+    command: Command<'_>,
+    requester: Requester,
+    request: Bytes,
+) -> Result<(Response, Option<String>)> {
+    let mut leader = leader.lock().await;
+    if let Some(response) = leader.requests.reply(&requester, &request) {
+        return Ok((response, None));
+    }
+    let (response, request_id) = persist_locked(&mut leader, tenant, namespace, command).await?;
+    if !matches!(response, Response::Error(_)) {
+        let window = leader.config.request_dedup_window;
+        let remembered = response.clone();
+        let now = leader.core.clock.now();
+        leader
+            .requests
+            .remember(requester, request, remembered, window, now);
+    }
+    Ok((response, request_id))
+}
+
+/// Answers `ANALYZE` for a namespace from a list of its keys taken up front,
+/// looking the values up a batch at a time so writes can go ahead in between.
+/// Values are read as of when the scan started, see [`crate::history`].
+async fn analyze(leader: &SyncLeader, top: usize, namespace: &[u8]) -> Response {
+    let (keys, now, pinned): (Vec<Key>, _, _) = {
+        let mut locked = leader.lock().await;
+        if locked.core.loading.is_some() {
+            let err = ErrorReply::new(ErrorCode::Loading, "the dataset is still being loaded");
+            return Response::Error(err);
+        }
+        let now = locked.core.clock.unix_time().as_millis() as u64;
+        let keys = locked
+            .core
+            .hashmap
+            .namespace(namespace)
+            .map(|hashmap| hashmap.keys());
+        let keys = keys.into_iter().flatten().cloned().collect();
+        (keys, now, Pinned::new(&mut locked, leader.clone()))
+    };
+    let mut analysis = Analysis::new(top, now);
+    for batch in keys.chunks(ANALYZE_BATCH) {
+        let leader = leader.lock().await;
+        let core = &leader.core;
+        for key in batch {
+            if let Some(val) = core
+                .history
+                .value_at(&core.hashmap, namespace, key, pinned.lsn)
+            {
+                let expiry = core
+                    .hashmap
+                    .namespace(namespace)
+                    .and_then(|db| db.expiry(key));
+                analysis.add(key, &val, expiry);
+            }
+        }
+        drop(leader);
+        tokio::task::yield_now().await;
+    }
+    Response::Info(analysis.render())
+}
+
+/// Answers `EXPORT` for the keys of a namespace starting with `prefix`,
+/// taken up front and looked up a batch at a time as for `ANALYZE`. Each
+/// batch's rows are sent as a chunk before the next is looked up, so the
+/// export is never held whole on either end.
+async fn export(
+    leader: &SyncLeader,
+    socket: &mut Connection,
+    format: ExportFormat,
+    prefix: &[u8],
+    namespace: &[u8],
+) -> Result<Result<(), ErrorReply>> {
+    let (mut keys, pinned): (Vec<Key>, _) = {
+        let mut locked = leader.lock().await;
+        if locked.core.loading.is_some() {
+            let err = ErrorReply::new(ErrorCode::Loading, "the dataset is still being loaded");
+            return Ok(Err(err));
+        }
+        let keys = locked
+            .core
+            .hashmap
+            .namespace(namespace)
+            .map(|hashmap| hashmap.keys().filter(|key| key.starts_with(prefix)));
+        let keys = keys.into_iter().flatten().cloned().collect();
+        (keys, Pinned::new(&mut locked, leader.clone()))
+    };
+    keys.sort();
+    write_chunk(socket, export::header(format)).await?;
+    for batch in keys.chunks(ANALYZE_BATCH) {
+        let mut rows = Vec::new();
+        {
+            let leader = leader.lock().await;
+            let core = &leader.core;
+            for key in batch {
+                if let Some(val) = core
+                    .history
+                    .value_at(&core.hashmap, namespace, key, pinned.lsn)
+                {
+                    let expiry = core
+                        .hashmap
+                        .namespace(namespace)
+                        .and_then(|db| db.expiry(key));
+                    export::row(format, key, &val, expiry, &mut rows);
+                }
+            }
+        }
+        write_chunk(socket, &rows).await?;
+    }
+    socket.write_all(b"$0\n\n").await?;
+    Ok(Ok(()))
+}
+
+/// Answers a `QUORUM` read: asks every follower for its value at once and,
+/// once a majority of the cluster counting the leader has answered, replies
+/// with the value of whichever applied the latest write.
+///
+/// With `read_lease_ms` set, the followers are also asked for a lease, and
+/// while a majority's lease lasts reads are answered from the leader's own
+/// map without asking. The lease is counted from before the followers were
+/// asked and cut short by `max_clock_drift_ms`, so it runs out before the
+/// leases the followers count from when they answered, as long as clocks
+/// run no further apart than that.
+async fn quorum_get(leader: &SyncLeader, namespace: &[u8], key: &[u8]) -> Response {
+    let (mut newest, addrs, nodes, lease, transport) = {
+        let mut leader = leader.lock().await;
+        if leader.core.loading.is_some() {
+            let err = ErrorReply::new(ErrorCode::Loading, "the dataset is still being loaded");
+            return Response::Error(err);
+        }
+        let val = leader
+            .core
+            .hashmap
+            .namespace(namespace)
+            .and_then(|db| db.get(key))
+            .cloned();
+        let now = leader.core.clock.now();
+        if leader.lease.is_some_and(|until| now < until) {
+            return match val {
+                Some(val) => Response::Get(Key::copy_from_slice(key), val),
+                None => Response::KeyNotFound(Key::copy_from_slice(key)),
+            };
+        }
+        leader.promote_learners();
+        let voters = leader
+            .followers
+            .iter()
+            .filter(|follower| follower.is_voter(&leader.config));
+        let addrs: Vec<String> = voters.clone().filter_map(Replica::read_addr).collect();
+        let config = &leader.config;
+        let lease = (config.read_lease_ms > 0).then(|| {
+            let held = config
+                .read_lease_ms
+                .saturating_sub(config.max_clock_drift_ms);
+            (config.read_lease_ms, now + Duration::from_millis(held))
+        });
+        let transport = leader.transport.clone();
+        (
+            (leader.core.lsn, val),
+            addrs,
+            voters.count() + 1,
+            lease,
+            transport,
+        )
+    };
+    let majority = nodes / 2 + 1;
+    let mut asked = JoinSet::new();
+    for addr in addrs {
+        let ask = Command::GetVersion(key.into(), lease.map(|(ms, _)| ms));
+        let request = scoped(namespace, ask).into_owned();
+        asked.spawn(tokio::time::timeout(
+            QUORUM_TIMEOUT,
+            read_version(transport.clone(), addr, request),
+        ));
+    }
+    let mut answered = 1;
+    while answered < majority {
+        match asked.join_next().await {
+            Some(Ok(Ok(Ok((lsn, val))))) => {
+                answered += 1;
+                if lsn > newest.0 {
+                    newest = (lsn, val.map(Val::from));
+                }
+            }
+            Some(_) => {}
+            None => {
+                return Response::Error(ErrorReply::new(
+                    ErrorCode::NoQuorum,
+                    format!(
+                        "{} of {} nodes answered, {} needed",
+                        answered, nodes, majority
+                    ),
+                ))
+            }
+        }
+    }
+    if let Some((_, until)) = lease {
+        let mut leader = leader.lock().await;
+        leader.lease = leader.lease.max(Some(until));
+    }
+    match newest.1 {
+        Some(val) => Response::Get(Key::copy_from_slice(key), val),
+        None => Response::KeyNotFound(Key::copy_from_slice(key)),
+    }
+}
+
+/// Asks the follower at `addr` for its LSN and value with `GETVERSION`.
+async fn read_version(
+    transport: Arc<dyn Transport>,
+    addr: String,
+    request: Command<'static>,
+) -> Result<(u64, Option<Bytes>)> {
+    let mut client = DistKvClient::connect_via(transport, &addr).await?;
+    match client.call(&request).await? {
+        Reply::Push(items) if !items.is_empty() => {
+            let lsn = std::str::from_utf8(&items[0])
+                .ok()
+                .and_then(|lsn| lsn.parse().ok())
+                .ok_or_else(|| {
+                    DistKvError::Protocol(format!("invalid GETVERSION LSN {:?}", items[0]))
+                })?;
+            Ok((lsn, items.get(1).cloned()))
+        }
+        reply => bail!(Protocol, "unexpected reply to GETVERSION: {:?}", reply),
+    }
+}
+
+/// Hands leadership to the voter at `addr`, for maintenance on the leader.
+/// Writes are refused from here on, and the follower is sent a `REQID`
+/// marker naming the LSN after the leader's last write, which no write has,
+/// this leader's or, as the LSN is carried over from the log, an earlier
+/// one's. Once the follower reports that LSN it has applied every write
+/// before it, and can be restarted as the leader. Writes are let through again if it
+/// doesn't get there in time, or on `READONLY OFF`.
+async fn transfer_leadership(leader: &SyncLeader, addr: String) -> Response {
+    let (fence, transport) = {
+        let mut leader = leader.lock().await;
+        let leader = &mut *leader;
+        leader.promote_learners();
+        let i = leader
+            .followers
+            .iter()
+            .position(|f| f.read_addr().as_deref() == Some(addr.as_str()));
+        let Some(i) = i else {
+            let message = format!("{} is not a member", addr);
+            return Response::Error(ErrorReply::new(ErrorCode::WrongArgs, message));
+        };
+        if leader.followers[i].learner || leader.followers[i].is_behind() {
+            let message = format!("{} is still catching up", addr);
+            return Response::Error(ErrorReply::new(ErrorCode::Busy, message));
+        }
+        leader.handover = Some(addr.clone());
+        leader.refuse_writes();
+        let follower = &mut leader.followers[i];
+        let fence = leader.core.lsn + 1;
+        let id = format!("{}-{}", leader.core.node_id, fence);
+        let mut record = BytesMut::new();
+        Command::RequestId(id.as_bytes().into()).encode(&mut record);
+        follower
+            .send(
+                &record,
+                leader.addr.as_deref(),
+                &leader.config,
+                &*leader.transport,
+            )
+            .await;
+        if let Some(queue) = &follower.queue {
+            queue.flush();
+        }
+        (fence, leader.transport.clone())
+    };
+    let deadline = Instant::now() + TRANSFER_TIMEOUT;
+    while Instant::now() < deadline {
+        let ask = Command::GetVersion(Cow::Borrowed(b""), None);
+        if let Ok((lsn, _)) = read_version(transport.clone(), addr.clone(), ask).await {
+            if lsn >= fence {
+                eprintln!("{} has every write, handing leadership over", addr);
+                return Response::Ok;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    let mut leader = leader.lock().await;
+    if leader.handover.as_deref() == Some(addr.as_str()) {
+        leader.handover = None;
+        leader.refuse_writes();
+    }
+    let message = format!("{} did not catch up in time", addr);
+    Response::Error(ErrorReply::new(ErrorCode::Timeout, message))
+}
+
+/// Answers a `DEBUG` subcommand, if `debug_commands` is on.
+async fn debug(leader: &SyncLeader, namespace: &[u8], command: DebugCommand<'_>) -> Response {
+    let mut leader = leader.lock().await;
+    if !leader.config.debug_commands {
+        return Response::Error(ErrorReply::new(
+            ErrorCode::NotSupported,
+            "DEBUG is off, see debug_commands",
+        ));
+    }
+    match command {
+        DebugCommand::Sleep(ms) => {
+            // The lock is held throughout, so every other command waits.
+            tokio::time::sleep(Duration::from_millis(ms)).await;
+            Response::Ok
+        }
+        DebugCommand::Object(key) => {
+            let core = &leader.core;
+            let db = core.hashmap.namespace(namespace);
+            let Some(val) = db.and_then(|db| db.get(&key[..])) else {
+                return Response::KeyNotFound(Key::new());
+            };
+            let mut record = BytesMut::new();
+            scoped(namespace, Command::Set(key[..].into(), val[..].into())).encode(&mut record);
+            let expiry = db.and_then(|db| db.expiry(&key));
+            let versions = core
+                .history
+                .versions(&core.hashmap, namespace, &key, usize::MAX);
+            let mut object = String::new();
+            object.push_str(&format!("encoding:{}\n", encoding(val)));
+            object.push_str(&format!("len:{}\n", val.len()));
+            object.push_str(&format!("record_bytes:{}\n", record.len()));
+            object.push_str(&format!(
+                "expires_at:{}\n",
+                expiry.map_or("none".to_string(), |at| at.to_string())
+            ));
+            object.push_str(&format!("history_versions:{}\n", versions.len()));
+            if let Some((lsn, _, _)) = versions.first() {
+                object.push_str(&format!("last_write_lsn:{}\n", lsn));
+            }
+            Response::Info(object)
+        }
+        DebugCommand::SetLsn(lsn) => {
+            leader.core.lsn = lsn;
+            Response::Ok
+        }
+    }
+}
+
+/// The leader's LSN when a scan started, pinned in its history until the
+/// scan is done or dropped.
+struct Pinned {
+    leader: SyncLeader,
+    lsn: u64,
+}
+
+impl Pinned {
+    fn new(locked: &mut Leader, leader: SyncLeader) -> Pinned {
+        let lsn = locked.core.lsn;
+        locked.core.history.pin(lsn);
+        Pinned { leader, lsn }
+    }
+}
+
+impl Drop for Pinned {
+    fn drop(&mut self) {
+        let (leader, lsn) = (self.leader.clone(), self.lsn);
+        tokio::spawn(async move { leader.lock().await.core.history.unpin(lsn) });
+    }
+}
+
+/// Sends a follower that asked for it with `SYNC` a snapshot and hands the
+/// connection over to replication.
+async fn sync_follower(
+    mut socket: Connection,
+    leader: &SyncLeader,
+    resume: Option<(u64, usize)>,
+    reads_at: Option<String>,
+    lz4: bool,
+) -> Result<()> {
+    let mut buf = BytesMut::new();
+    let (id, snapshot, offset) = {
+        let mut leader = leader.lock().await;
+        if leader.core.loading.is_some() {
+            let err = ErrorReply::new(ErrorCode::Loading, "the dataset is still being loaded");
+            Response::Error(err).encode(&mut buf);
+            socket.write_all(&buf).await?;
+            return Ok(());
+        }
+        leader.start_sync(resume, reads_at)
+    };
+    snapshot::encode_header(&mut buf, id, snapshot.len(), offset);
+    socket.write_all(&buf).await?;
+    let mut offset = offset;
+    for chunk in snapshot[offset..].chunks(snapshot::CHUNK_SIZE) {
+        buf.clear();
+        snapshot::encode_chunk(&mut buf, offset, chunk);
+        socket.write_all(&buf).await?;
+        offset += chunk.len();
+    }
+    leader.lock().await.finish_sync(id, socket, lz4).await
+}
+
+fn join_chunks(chunks: Vec<BytesMut>) -> BytesMut {
+    let mut val = BytesMut::with_capacity(chunks.iter().map(|chunk| chunk.len()).sum());
+    for chunk in chunks {
+        val.extend_from_slice(&chunk);
+    }
+    val
+}
+
+/// Writes `val` as a series of `$<len>` chunks followed by an empty one,
+/// straight from the stored value without copying it.
+async fn send_chunks(socket: &mut Connection, val: &[u8], chunk_size: usize) -> Result<()> {
+    for chunk in val.chunks(chunk_size) {
+        write_chunk(socket, chunk).await?;
+    }
+    socket.write_all(b"$0\n\n").await?;
+    Ok(())
+}
+
+/// Sends one chunk of a chunked reply, unless it's empty, as an empty chunk
+/// ends the reply.
+async fn write_chunk(socket: &mut Connection, chunk: &[u8]) -> Result<()> {
+    if chunk.is_empty() {
+        return Ok(());
+    }
+    socket
+        .write_all(format!("${}\n", chunk.len()).as_bytes())
+        .await?;
+    socket.write_all(chunk).await?;
+    socket.write_all(b"\n").await?;
+    Ok(())
+}
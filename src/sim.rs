@@ -0,0 +1,524 @@
+//! A single-threaded, deterministic simulation of a leader and a follower.
+//!
+//! Time, the disks and the replication link are all simulated and every
+//! random choice comes from one seeded generator, so a failing run can be
+//! replayed exactly from its seed. Faults (failed fsyncs, crashes that lose
+//! unsynced data, dropped connections and delivery delays) are injected at
+//! configurable rates while the checker compares both nodes against a model
+//! of the acknowledged writes.
+
+use std::cell::{Cell, RefCell};
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::rc::Rc;
+use std::time::Duration;
+
+use bytes::BytesMut;
+
+use crate::clock::Clock;
+use crate::node::{FollowerCore, LeaderCore};
+use crate::protocol::Command;
+use crate::store::{replay, run_command, Db};
+use crate::wal::Storage;
+
+/// splitmix64: small, fast and good enough to drive fault injection.
+#[derive(Debug, Clone)]
+pub struct SimRng(u64);
+
+impl SimRng {
+    pub fn new(seed: u64) -> Self {
+        SimRng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// A value in `0..n`.
+    pub fn below(&mut self, n: u64) -> u64 {
+        self.next_u64() % n.max(1)
+    }
+
+    /// True with probability `p`.
+    pub fn chance(&mut self, p: f64) -> bool {
+        ((self.next_u64() >> 11) as f64 / (1u64 << 53) as f64) < p
+    }
+}
+
+/// Virtual time that only moves when the simulation advances it.
+#[derive(Debug, Clone, Default)]
+pub struct SimClock(Rc<Cell<Duration>>);
+
+impl SimClock {
+    pub fn advance(&self, by: Duration) {
+        self.0.set(self.0.get() + by);
+    }
+}
+
+impl Clock for SimClock {
+    fn now(&self) -> Duration {
+        self.0.get()
+    }
+
+    /// The simulation starts at the epoch.
+    fn unix_time(&self) -> Duration {
+        self.0.get()
+    }
+}
+
+/// The contents of a simulated disk: what has been synced and what is still
+/// only in the page cache.
+#[derive(Debug, Default)]
+pub struct SimDisk {
+    durable: Vec<u8>,
+    unsynced: Vec<u8>,
+    /// Probability that a sync fails, leaving its data unsynced.
+    pub sync_failure_rate: f64,
+    rng: Option<SimRng>,
+}
+
+impl SimDisk {
+    pub fn durable(&self) -> &[u8] {
+        &self.durable
+    }
+
+    /// Simulates losing power: the unsynced data either all makes it to disk
+    /// or is lost, as chosen by `rng`.
+    pub fn crash(&mut self, rng: &mut SimRng) {
+        if rng.chance(0.5) {
+            self.durable.extend_from_slice(&self.unsynced);
+        }
+        self.unsynced.clear();
+    }
+}
+
+/// A handle onto a [`SimDisk`] that implements [`Storage`]. The disk itself
+/// outlives the node using it, so it can be reopened after a crash.
+#[derive(Debug, Clone)]
+pub struct SimStorage(Rc<RefCell<SimDisk>>);
+
+impl SimStorage {
+    pub fn new(sync_failure_rate: f64, seed: u64) -> Self {
+        SimStorage(Rc::new(RefCell::new(SimDisk {
+            sync_failure_rate,
+            rng: Some(SimRng::new(seed)),
+            ..SimDisk::default()
+        })))
+    }
+
+    pub fn disk(&self) -> std::cell::RefMut<'_, SimDisk> {
+        self.0.borrow_mut()
+    }
+}
+
+impl Storage for SimStorage {
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        self.0.borrow_mut().unsynced.extend_from_slice(record);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        let mut disk = self.0.borrow_mut();
+        let rate = disk.sync_failure_rate;
+        if disk.rng.as_mut().is_some_and(|rng| rng.chance(rate)) {
+            return Err(io::Error::other("simulated fsync failure"));
+        }
+        let unsynced = std::mem::take(&mut disk.unsynced);
+        disk.durable.extend_from_slice(&unsynced);
+        Ok(())
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        let mut disk = self.0.borrow_mut();
+        disk.durable.clear();
+        disk.unsynced.clear();
+        Ok(())
+    }
+}
+
+/// The replication connection. Like TCP it delivers in order; a dropped
+/// connection loses everything in flight.
+#[derive(Debug, Default)]
+struct SimLink {
+    in_flight: VecDeque<(Duration, BytesMut)>,
+    connected: bool,
+    last_delivery: Duration,
+}
+
+#[derive(Debug, Clone)]
+pub struct SimConfig {
+    pub seed: u64,
+    pub steps: usize,
+    pub keys: u64,
+    pub max_delay: Duration,
+    pub sync_failure_rate: f64,
+    pub crash_rate: f64,
+    pub partition_rate: f64,
+}
+
+impl Default for SimConfig {
+    fn default() -> Self {
+        SimConfig {
+            seed: 0,
+            steps: 1_000,
+            keys: 16,
+            max_delay: Duration::from_millis(20),
+            sync_failure_rate: 0.01,
+            crash_rate: 0.01,
+            partition_rate: 0.01,
+        }
+    }
+}
+
+/// An invariant violation, with enough context to reproduce it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SimError {
+    pub seed: u64,
+    pub step: usize,
+    pub message: String,
+    /// The most recent events leading up to the failure.
+    pub trace: Vec<String>,
+}
+
+impl fmt::Display for SimError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "seed {} step {}: {}", self.seed, self.step, self.message)?;
+        for event in &self.trace {
+            writeln!(f, "  {}", event)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for SimError {}
+
+const TRACE_LEN: usize = 32;
+
+pub struct Simulation {
+    config: SimConfig,
+    rng: SimRng,
+    clock: SimClock,
+    leader_disk: SimStorage,
+    leader: Option<LeaderCore<SimStorage, SimClock>>,
+    follower_disk: SimStorage,
+    follower: Option<FollowerCore<SimStorage, SimClock>>,
+    follower_buf: BytesMut,
+    link: SimLink,
+    /// Every write the leader acknowledged, applied in order.
+    model: Db,
+    /// A write whose fsync failed: it may or may not survive a crash.
+    in_doubt: Option<BytesMut>,
+    /// What the follower must hold: the records it has synced.
+    follower_model: Db,
+    follower_in_doubt: Option<BytesMut>,
+    step: usize,
+    trace: VecDeque<String>,
+}
+
+impl Simulation {
+    pub fn new(config: SimConfig) -> Self {
+        let mut rng = SimRng::new(config.seed);
+        let clock = SimClock::default();
+        let leader_disk = SimStorage::new(config.sync_failure_rate, rng.next_u64());
+        let follower_disk = SimStorage::new(config.sync_failure_rate, rng.next_u64());
+        let mut leader = LeaderCore::new(Db::default(), leader_disk.clone(), clock.clone());
+        // Node ids end up in request ids, so they come from the step, not
+        // the process, to keep runs of a seed identical.
+        leader.node_id = "leader-0".to_string();
+        let follower = FollowerCore::new(Db::default(), follower_disk.clone(), clock.clone());
+        Simulation {
+            config,
+            rng,
+            clock,
+            leader_disk,
+            leader: Some(leader),
+            follower_disk,
+            follower: Some(follower),
+            follower_buf: BytesMut::new(),
+            link: SimLink {
+                connected: true,
+                ..SimLink::default()
+            },
+            model: Db::default(),
+            in_doubt: None,
+            follower_model: Db::default(),
+            follower_in_doubt: None,
+            step: 0,
+            trace: VecDeque::new(),
+        }
+    }
+
+    pub fn clock(&self) -> &SimClock {
+        &self.clock
+    }
+
+    /// The most recent events, oldest first.
+    pub fn trace(&self) -> Vec<String> {
+        self.trace.iter().cloned().collect()
+    }
+
+    /// Runs the configured number of steps, stopping at the first violation.
+    pub fn run(&mut self) -> Result<(), SimError> {
+        for _ in 0..self.config.steps {
+            self.step()?;
+        }
+        Ok(())
+    }
+
+    /// Performs one randomly chosen event and checks the invariants it
+    /// touches.
+    pub fn step(&mut self) -> Result<(), SimError> {
+        self.step += 1;
+        self.clock
+            .advance(Duration::from_micros(self.rng.below(1_000) + 1));
+        let config = self.config.clone();
+        if self.rng.chance(config.crash_rate) {
+            if self.rng.chance(0.5) {
+                self.crash_leader()
+            } else {
+                self.crash_follower()
+            }
+        } else if self.rng.chance(config.partition_rate) {
+            self.toggle_link();
+            Ok(())
+        } else {
+            match self.rng.below(3) {
+                0 => self.read(),
+                1 => self.write(),
+                _ => self.deliver(),
+            }
+        }
+    }
+
+    fn event(&mut self, event: String) {
+        if self.trace.len() == TRACE_LEN {
+            self.trace.pop_front();
+        }
+        self.trace
+            .push_back(format!("[{:?}] {}", self.clock.now(), event));
+    }
+
+    fn fail(&self, message: String) -> SimError {
+        SimError {
+            seed: self.config.seed,
+            step: self.step,
+            message,
+            trace: self.trace.iter().cloned().collect(),
+        }
+    }
+
+    fn random_key(&mut self) -> String {
+        format!("key:{}", self.rng.below(self.config.keys))
+    }
+
+    fn read(&mut self) -> Result<(), SimError> {
+        let key = self.random_key();
+        let Some(leader) = self.leader.as_mut() else {
+            return self.restart_leader();
+        };
+        let command = Command::Get(key.as_bytes().into());
+        let actual = run_command(&mut leader.hashmap, &command);
+        let expected = run_command(&mut self.model, &command);
+        self.event(format!("GET {} -> {}", key, actual));
+        if actual != expected {
+            return Err(self.fail(format!("read {} but the model has {}", actual, expected)));
+        }
+        Ok(())
+    }
+
+    fn write(&mut self) -> Result<(), SimError> {
+        let key = self.random_key();
+        let val = self.rng.below(1_000).to_string();
+        let Some(leader) = self.leader.as_mut() else {
+            return self.restart_leader();
+        };
+        let command = if self.rng.chance(0.2) {
+            Command::Delete(key.as_bytes().into())
+        } else {
+            Command::Set(key.as_bytes().into(), val.as_bytes().into())
+        };
+        match leader.execute(&command) {
+            Ok((response, record)) => {
+                run_command(&mut self.model, &command);
+                self.event(format!("write {:?} -> {}", command, response));
+                if let Some(record) = record {
+                    self.send(record);
+                }
+                Ok(())
+            }
+            Err(e) => {
+                // The node fails stop on a storage error, as the real leader
+                // does, leaving the write unacknowledged.
+                let mut record = BytesMut::new();
+                command.encode(&mut record);
+                self.in_doubt = Some(record);
+                self.event(format!("write {:?} failed: {}", command, e));
+                self.leader = None;
+                self.leader_disk.disk().crash(&mut self.rng);
+                self.reset_link();
+                Ok(())
+            }
+        }
+    }
+
+    fn send(&mut self, record: BytesMut) {
+        if !self.link.connected {
+            return;
+        }
+        let delay =
+            Duration::from_micros(self.rng.below(self.config.max_delay.as_micros() as u64 + 1));
+        let at = (self.clock.now() + delay).max(self.link.last_delivery);
+        self.link.last_delivery = at;
+        self.link.in_flight.push_back((at, record));
+    }
+
+    fn deliver(&mut self) -> Result<(), SimError> {
+        let now = self.clock.now();
+        let Some((at, _)) = self.link.in_flight.front() else {
+            return Ok(());
+        };
+        if *at > now {
+            return Ok(());
+        }
+        let (_, record) = self.link.in_flight.pop_front().unwrap();
+        let Some(follower) = self.follower.as_mut() else {
+            return self.restart_follower();
+        };
+        self.follower_buf.extend_from_slice(&record);
+        match follower.receive(&mut self.follower_buf) {
+            Ok(_) => {
+                apply_records(&mut self.follower_model, &record);
+                let diverged = follower.hashmap != self.follower_model;
+                self.event(format!(
+                    "follower applied {:?}",
+                    String::from_utf8_lossy(&record)
+                ));
+                if diverged {
+                    return Err(self.fail("follower diverged from its model".to_string()));
+                }
+                Ok(())
+            }
+            Err(e) => {
+                self.follower_in_doubt = Some(record);
+                self.event(format!("follower failed: {}", e));
+                self.follower = None;
+                self.follower_disk.disk().crash(&mut self.rng);
+                self.reset_link();
+                Ok(())
+            }
+        }
+    }
+
+    fn toggle_link(&mut self) {
+        if self.link.connected {
+            self.event("link dropped".to_string());
+            self.reset_link();
+        } else {
+            self.event("link restored".to_string());
+            self.link.connected = true;
+        }
+    }
+
+    fn reset_link(&mut self) {
+        self.link.in_flight.clear();
+        self.link.connected = false;
+        self.follower_buf.clear();
+    }
+
+    fn crash_leader(&mut self) -> Result<(), SimError> {
+        self.event("leader crashed".to_string());
+        self.leader = None;
+        self.leader_disk.disk().crash(&mut self.rng);
+        self.reset_link();
+        self.restart_leader()
+    }
+
+    fn crash_follower(&mut self) -> Result<(), SimError> {
+        self.event("follower crashed".to_string());
+        self.follower = None;
+        self.follower_disk.disk().crash(&mut self.rng);
+        self.reset_link();
+        self.restart_follower()
+    }
+
+    /// Recovers the leader from its disk and checks nothing acknowledged was
+    /// lost and nothing unacknowledged appeared, apart from the one write
+    /// that may or may not have reached the disk.
+    fn restart_leader(&mut self) -> Result<(), SimError> {
+        let log = self.leader_disk.disk().durable().to_vec();
+        let recovered = replay(&log).map_err(|e| self.fail(format!("leader replay: {}", e)))?;
+        let in_doubt = self.in_doubt.take();
+        if recovered != self.model {
+            let mut with_doubt = self.model.clone();
+            if let Some(record) = in_doubt {
+                apply_records(&mut with_doubt, &record);
+            }
+            if recovered != with_doubt {
+                return Err(self.fail("leader recovered to a state it never acknowledged".into()));
+            }
+            self.model = with_doubt;
+        }
+        self.event(format!("leader restarted with {} keys", recovered.len()));
+        let mut leader = LeaderCore::new(recovered, self.leader_disk.clone(), self.clock.clone());
+        leader.node_id = format!("leader-{}", self.step);
+        self.leader = Some(leader);
+        self.link.connected = true;
+        Ok(())
+    }
+
+    /// Recovers the follower from its disk. Its model is whatever it applied
+    /// and synced, which a crash must not lose.
+    fn restart_follower(&mut self) -> Result<(), SimError> {
+        let log = self.follower_disk.disk().durable().to_vec();
+        let recovered = replay(&log).map_err(|e| self.fail(format!("follower replay: {}", e)))?;
+        let in_doubt = self.follower_in_doubt.take();
+        if recovered != self.follower_model {
+            let mut with_doubt = self.follower_model.clone();
+            if let Some(record) = in_doubt {
+                apply_records(&mut with_doubt, &record);
+            }
+            if recovered != with_doubt {
+                return Err(self.fail("follower lost records it had synced".into()));
+            }
+            self.follower_model = with_doubt;
+        }
+        self.event(format!("follower restarted with {} keys", recovered.len()));
+        self.follower = Some(FollowerCore::new(
+            recovered,
+            self.follower_disk.clone(),
+            self.clock.clone(),
+        ));
+        self.link.connected = true;
+        Ok(())
+    }
+}
+
+/// Storage that discards everything, for applying records to a model.
+pub struct NullStorage;
+
+impl Storage for NullStorage {
+    fn append(&mut self, _record: &[u8]) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Applies `records` to `model` using the follower's own parsing, so the
+/// model can't drift from how a follower reads the stream.
+fn apply_records(model: &mut Db, records: &[u8]) {
+    let mut core = FollowerCore::new(std::mem::take(model), NullStorage, SimClock::default());
+    let _ = core.receive(&mut BytesMut::from(records));
+    *model = core.hashmap;
+}
@@ -0,0 +1,309 @@
+//! Point-in-time snapshots of the map, and the protocol for shipping one to
+//! a new follower.
+//!
+//! A snapshot file starts with a fixed header: the magic bytes `DKVSNAP\0`,
+//! a big-endian `u16` format version, the `u64` LSN of the log when it was
+//! taken, the `u64` length of the body and the body's CRC-32. Version 4's
+//! body starts with the cluster's membership changes, a `u32` count and, in
+//! address order, each node's `u32` address length, address and a byte that
+//! is 1 if it was last added and 0 if removed. A section for each namespace
+//! follows, the default one first and the rest in name order, each a `u32`
+//! name length, the name, a `u64` entry count and the namespace's entries
+//! in key order. An entry is a `u32` key length, the key, a `u32` value
+//! length, the value and the `u64` Unix time in milliseconds the key
+//! expires at, or 0 if it has no TTL. Version 3's body is the same without
+//! the membership, version 2's just the default namespace's entries, and
+//! version 1's the same without the expiry times. Snapshots from before the header was added are plain
+//! `SET` records and are still read, as version 0.
+//!
+//! A follower asks for one with `SYNC`. The leader replies
+//! `+SNAPSHOT <id> <len> <offset>` and sends the snapshot from `offset` on as
+//! `CHUNK <offset> <crc32> $<len>` frames. Once the last chunk is sent the
+//! same connection carries the replication stream, starting with the writes
+//! made during the transfer. A follower whose connection drops partway
+//! through resumes with `SYNC <id> <offset>`, which the leader honours for
+//! as long as it still holds that transfer and starts over otherwise.
+
+use std::str::FromStr;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::checksum::crc32;
+use crate::error::{bail, DistKvError, Result};
+use crate::protocol::Frame;
+use crate::store::{replay, Db, DEFAULT_NAMESPACE};
+
+/// How much of a snapshot each `CHUNK` frame carries.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+pub const MAGIC: &[u8; 8] = b"DKVSNAP\0";
+
+/// The format version written by [`encode`]. Anything newer is refused.
+pub const VERSION: u16 = 4;
+
+pub const HEADER_LEN: usize = MAGIC.len() + 2 + 8 + 8 + 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Header {
+    pub version: u16,
+    /// How many records the leader had written to its log when the snapshot
+    /// was taken.
+    pub lsn: u64,
+    pub len: u64,
+    pub checksum: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub header: Header,
+    pub hashmap: Db,
+}
+
+/// Writes the map in the current format, in key order so that equal maps
+/// give equal snapshots.
+pub fn encode(hashmap: &Db, lsn: u64) -> Bytes {
+    let mut body = BytesMut::new();
+    body.put_u32(hashmap.membership().count() as u32);
+    for (addr, member) in hashmap.membership() {
+        body.put_u32(addr.len() as u32);
+        body.put_slice(addr.as_bytes());
+        body.put_u8(member.into());
+    }
+    let namespaces = hashmap.namespaces().map(|(name, db)| (&name[..], db));
+    for (name, namespace) in std::iter::once((DEFAULT_NAMESPACE, hashmap)).chain(namespaces) {
+        body.put_u32(name.len() as u32);
+        body.put_slice(name);
+        body.put_u64(namespace.len() as u64);
+        encode_entries(&mut body, namespace);
+    }
+    let mut buf = BytesMut::with_capacity(HEADER_LEN + body.len());
+    buf.put_slice(MAGIC);
+    buf.put_u16(VERSION);
+    buf.put_u64(lsn);
+    buf.put_u64(body.len() as u64);
+    buf.put_u32(crc32(&body));
+    buf.put_slice(&body);
+    buf.freeze()
+}
+
+fn encode_entries(body: &mut BytesMut, hashmap: &Db) {
+    let mut keys: Vec<_> = hashmap.keys().collect();
+    keys.sort();
+    for key in keys {
+        let val = &hashmap[key];
+        body.put_u32(key.len() as u32);
+        body.put_slice(key);
+        body.put_u32(val.len() as u32);
+        body.put_slice(val);
+        body.put_u64(hashmap.expiry(key).unwrap_or(0));
+    }
+}
+
+/// Reads a snapshot's header without checking its body. Snapshots without
+/// one have the header of an empty version 0 snapshot.
+pub fn read_header(snapshot: &[u8]) -> Result<Header> {
+    if !snapshot.starts_with(MAGIC) {
+        return Ok(Header {
+            version: 0,
+            lsn: 0,
+            len: snapshot.len() as u64,
+            checksum: crc32(snapshot),
+        });
+    }
+    if snapshot.len() < HEADER_LEN {
+        bail!(Corrupt, "snapshot header is truncated");
+    }
+    let mut header = &snapshot[MAGIC.len()..HEADER_LEN];
+    let header = Header {
+        version: header.get_u16(),
+        lsn: header.get_u64(),
+        len: header.get_u64(),
+        checksum: header.get_u32(),
+    };
+    if header.version > VERSION {
+        bail!(Corrupt, "unsupported snapshot version {}", header.version);
+    }
+    Ok(header)
+}
+
+/// Validates a snapshot against its header and reads the map out of it.
+pub fn decode(snapshot: &[u8]) -> Result<Snapshot> {
+    let header = read_header(snapshot)?;
+    if header.version == 0 {
+        let hashmap = replay(snapshot)?;
+        return Ok(Snapshot { header, hashmap });
+    }
+    let body = &snapshot[HEADER_LEN..];
+    if body.len() as u64 != header.len {
+        bail!(
+            Corrupt,
+            "snapshot body is {} bytes, expected {}",
+            body.len(),
+            header.len
+        );
+    }
+    if crc32(body) != header.checksum {
+        bail!(Corrupt, "snapshot checksum mismatch");
+    }
+    let mut body = body;
+    let mut hashmap = Db::new();
+    if header.version < 3 {
+        let with_expiry = header.version == 2;
+        while body.has_remaining() {
+            read_entry(&mut body, &mut hashmap, with_expiry)?;
+        }
+        return Ok(Snapshot { header, hashmap });
+    }
+    if header.version >= 4 {
+        read_membership(&mut body, &mut hashmap)?;
+    }
+    while body.has_remaining() {
+        let name = read_field(&mut body)?;
+        if body.remaining() < 8 {
+            bail!(Corrupt, "snapshot namespace is truncated");
+        }
+        let namespace = hashmap.namespace_mut(&name);
+        for _ in 0..body.get_u64() {
+            read_entry(&mut body, namespace, true)?;
+        }
+        hashmap.drop_if_empty(&name);
+    }
+    Ok(Snapshot { header, hashmap })
+}
+
+fn read_membership(body: &mut &[u8], hashmap: &mut Db) -> Result<()> {
+    if body.remaining() < 4 {
+        bail!(Corrupt, "snapshot membership is truncated");
+    }
+    for _ in 0..body.get_u32() {
+        let addr = read_field(body)?;
+        if !body.has_remaining() {
+            bail!(Corrupt, "snapshot membership is truncated");
+        }
+        let member = body.get_u8() != 0;
+        hashmap.set_member(&String::from_utf8_lossy(&addr), Some(member));
+    }
+    Ok(())
+}
+
+fn read_entry(body: &mut &[u8], hashmap: &mut Db, with_expiry: bool) -> Result<()> {
+    let key = read_field(body)?;
+    let val = read_field(body)?;
+    let expiry = match with_expiry {
+        false => 0,
+        true if body.remaining() < 8 => bail!(Corrupt, "snapshot entry is truncated"),
+        true => body.get_u64(),
+    };
+    hashmap.insert(key.clone().into(), val.into());
+    if expiry != 0 {
+        hashmap.expire_at(&key, expiry);
+    }
+    Ok(())
+}
+
+fn read_field(body: &mut &[u8]) -> Result<Bytes> {
+    if body.remaining() < 4 {
+        bail!(Corrupt, "snapshot entry is truncated");
+    }
+    let len = body.get_u32() as usize;
+    if body.remaining() < len {
+        bail!(Corrupt, "snapshot entry is truncated");
+    }
+    let field = Bytes::copy_from_slice(&body[..len]);
+    body.advance(len);
+    Ok(field)
+}
+
+pub fn encode_header(buf: &mut BytesMut, id: u64, len: usize, offset: usize) {
+    buf.put_slice(format!("+SNAPSHOT {} {} {}\n", id, len, offset).as_bytes());
+}
+
+/// Parses the leader's reply to `SYNC` into the transfer id, the snapshot's
+/// length and the offset the chunks start at.
+pub fn parse_header(line: &[u8]) -> Result<(u64, usize, usize)> {
+    let line = String::from_utf8_lossy(line);
+    let Some(fields) = line.strip_prefix("+SNAPSHOT ") else {
+        let line = line.strip_prefix('-').unwrap_or(&line);
+        return Err(DistKvError::from_line(line).context("leader refused to sync"));
+    };
+    let fields: Vec<_> = fields.split(' ').collect();
+    let [id, len, offset] = fields[..] else {
+        bail!(Protocol, "malformed snapshot header {:?}", line);
+    };
+    Ok((
+        parse_field("snapshot transfer id", id)?,
+        parse_field("snapshot length", len)?,
+        parse_field("snapshot offset", offset)?,
+    ))
+}
+
+/// Parses a number from a line of the snapshot transfer, naming `field` if
+/// it isn't one: a peer sending a bad one is a protocol error, not a
+/// misconfiguration.
+fn parse_field<T: FromStr>(field: &str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| DistKvError::Protocol(format!("malformed {} {:?}", field, value)))
+}
+
+pub fn encode_chunk(buf: &mut BytesMut, offset: usize, chunk: &[u8]) {
+    let line = format!("CHUNK {} {} ${}\n", offset, crc32(chunk), chunk.len());
+    buf.put_slice(line.as_bytes());
+    buf.put_slice(chunk);
+    buf.put_u8(b'\n');
+}
+
+/// A snapshot being received. It survives reconnects so that the transfer
+/// can pick up where it stopped.
+#[derive(Debug)]
+pub struct Download {
+    pub id: u64,
+    pub len: usize,
+    pub data: BytesMut,
+}
+
+impl Download {
+    pub fn new(id: u64, len: usize) -> Self {
+        Download {
+            id,
+            len,
+            data: BytesMut::with_capacity(len),
+        }
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.data.len() == self.len
+    }
+
+    /// Appends a `CHUNK` frame, checking that it is the next one and that it
+    /// arrived intact.
+    pub fn push(&mut self, frame: &Frame) -> Result<()> {
+        let line = String::from_utf8_lossy(&frame.line);
+        let fields: Vec<_> = line.split_whitespace().collect();
+        let (["CHUNK", offset, crc], Some(chunk)) = (&fields[..], &frame.payload) else {
+            bail!(Protocol, "expected a snapshot chunk, got {:?}", line);
+        };
+        let offset: usize = parse_field("chunk offset", offset)?;
+        let crc: u32 = parse_field("chunk checksum", crc)?;
+        if offset != self.data.len() {
+            bail!(
+                Protocol,
+                "expected the chunk at {}, got {}",
+                self.data.len(),
+                offset
+            );
+        }
+        if crc32(chunk) != crc {
+            bail!(Corrupt, "checksum mismatch in the chunk at {}", offset);
+        }
+        if offset + chunk.len() > self.len {
+            bail!(
+                Corrupt,
+                "chunk at {} runs past the end of the snapshot",
+                offset
+            );
+        }
+        self.data.extend_from_slice(chunk);
+        Ok(())
+    }
+}
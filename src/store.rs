@@ -0,0 +1,1203 @@
+use std::borrow::Cow;
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
+use std::hash::{BuildHasher, Hasher};
+use std::ops::Deref;
+
+use bytes::{Bytes, BytesMut};
+
+use crate::compact::{CompactBytes, Interner};
+use crate::dump;
+use crate::error::{bail, Result};
+use crate::hyperloglog::HyperLogLog;
+use crate::list::List;
+use crate::node::{parse_request_id, request_marker};
+use crate::protocol::{
+    parse_all, ClusterCommand, Command, Condition, ErrorCode, ErrorReply, Limits, ParseError,
+};
+use crate::pubsub::push;
+use crate::script;
+use crate::stream::Stream;
+use crate::wal::{replay_threads, Entry, LogReader};
+
+pub type Key = CompactBytes;
+pub type Val = CompactBytes;
+
+/// The namespace connections start in. Its keys are those of the [`Db`]
+/// itself, while every other namespace is a `Db` of its own inside it.
+pub const DEFAULT_NAMESPACE: &[u8] = b"0";
+
+/// The map, along with when each key that has a TTL expires, in
+/// milliseconds since the Unix epoch. It derefs to the map for reading, while
+/// writes go through its own methods so that a key's TTL goes with it.
+#[derive(Debug, Clone, Default)]
+pub struct Db {
+    entries: HashMap<Key, Val>,
+    /// The same keys in a list, in no particular order, so that one can be
+    /// picked at random without a scan.
+    keys: Vec<Key>,
+    /// Where each key is in `keys`.
+    slots: HashMap<Key, usize>,
+    /// How recently and often the leader has used each key, which isn't
+    /// part of the map's contents and so isn't logged, replicated or
+    /// compared.
+    access: HashMap<Key, Access>,
+    /// Shares values with the same bytes, while `intern_values` is on.
+    interner: Option<Interner>,
+    expires: HashMap<Key, u64>,
+    /// The same TTLs ordered by time, so that due keys are found without a
+    /// scan.
+    deadlines: BTreeSet<(u64, Key)>,
+    /// The namespaces other than the default one that hold any keys.
+    namespaces: BTreeMap<Bytes, Db>,
+    /// Whether each node a `CLUSTER` record names was last added or
+    /// removed, kept with the map so that the log, snapshots and followers
+    /// carry membership too. Only the default namespace has any.
+    membership: BTreeMap<String, bool>,
+    /// The largest value a push may grow a list to, while the leader has a
+    /// `max_value_size`. Replay and replication only carry writes that were
+    /// already accepted, so their maps have none.
+    max_value_size: Option<usize>,
+}
+
+/// Maps holding the same keys, values and TTLs are equal, whatever order
+/// the keys were added in.
+impl PartialEq for Db {
+    fn eq(&self, other: &Db) -> bool {
+        self.entries == other.entries
+            && self.expires == other.expires
+            && self.namespaces == other.namespaces
+            && self.membership == other.membership
+    }
+}
+
+impl Eq for Db {}
+
+/// A number in `0..n`.
+fn random_below(n: usize) -> usize {
+    (RandomState::new().build_hasher().finish() % n as u64) as usize
+}
+
+/// A number in `0.0..1.0`.
+fn random_unit() -> f64 {
+    (RandomState::new().build_hasher().finish() >> 11) as f64 / (1u64 << 53) as f64
+}
+
+/// The counter a key's frequency starts at, so that a new key isn't the
+/// first to go before it has had a chance to be read.
+const LFU_INIT: u8 = 5;
+
+/// How much harder each step of the frequency counter is to reach than the
+/// one before.
+const LFU_LOG_FACTOR: f64 = 10.0;
+
+/// How long a key goes unused before its frequency counter drops by one.
+const LFU_DECAY_MS: u64 = 60_000;
+
+/// When a key was last read or written, in milliseconds since the Unix
+/// epoch, and how often it is, as the logarithmic counter Redis's LFU
+/// eviction keeps: 0 to 255, going up with each use by less the higher it
+/// is, and down by one every minute the key isn't used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub at: u64,
+    counter: u8,
+}
+
+impl Access {
+    /// A key created at `now`.
+    pub fn new(now: u64) -> Access {
+        Access {
+            at: now,
+            counter: LFU_INIT,
+        }
+    }
+
+    /// Seconds since the key was last used.
+    pub fn idle(&self, now: u64) -> u64 {
+        now.saturating_sub(self.at) / 1000
+    }
+
+    /// The frequency counter at `now`, after the minutes since the key was
+    /// last used are taken off it.
+    pub fn freq(&self, now: u64) -> u8 {
+        let decay = now.saturating_sub(self.at) / LFU_DECAY_MS;
+        self.counter.saturating_sub(decay.min(u8::MAX as u64) as u8)
+    }
+
+    fn hit(&mut self, now: u64) {
+        let mut counter = self.freq(now);
+        let odds = counter.saturating_sub(LFU_INIT) as f64 * LFU_LOG_FACTOR + 1.0;
+        if counter < u8::MAX && random_unit() < 1.0 / odds {
+            counter += 1;
+        }
+        self.counter = counter;
+        self.at = now;
+    }
+}
+
+/// How a value is stored, as `OBJECT ENCODING` and `DEBUG OBJECT` name it:
+/// `inline` in the map's own memory, or `bytes` in an allocation of its
+/// own, see [`crate::compact`].
+pub fn encoding(val: &Val) -> &'static str {
+    match val.is_inline() {
+        true => "inline",
+        false => "bytes",
+    }
+}
+
+impl Db {
+    pub fn new() -> Self {
+        Db::default()
+    }
+
+    /// Sets `key` as `SET` does, dropping any TTL it had.
+    pub fn insert(&mut self, key: Key, val: Val) -> Option<Val> {
+        self.persist(&key);
+        self.update(key, val)
+    }
+
+    /// Replaces the value of `key`, keeping its TTL.
+    pub fn update(&mut self, key: Key, val: Val) -> Option<Val> {
+        let val = match &mut self.interner {
+            Some(interner) => interner.intern(val),
+            None => val,
+        };
+        let old = self.entries.insert(key.clone(), val);
+        match &old {
+            Some(old) => self.release(old),
+            None => self.track(&key),
+        }
+        old
+    }
+
+    pub fn remove(&mut self, key: &[u8]) -> Option<Val> {
+        self.persist(key);
+        let old = self.entries.remove(key);
+        if let Some(old) = &old {
+            self.untrack(key);
+            self.release(old);
+        }
+        old
+    }
+
+    /// Shares one allocation between values with the same bytes, in every
+    /// namespace, from now on and among the values already there, or stops
+    /// doing so.
+    pub fn set_interning(&mut self, on: bool) {
+        match (on, &self.interner) {
+            (true, None) => {
+                let mut interner = Interner::default();
+                for val in self.entries.values_mut() {
+                    *val = interner.intern(std::mem::take(val));
+                }
+                self.interner = Some(interner);
+            }
+            (false, Some(_)) => self.interner = None,
+            _ => {}
+        }
+        for namespace in self.namespaces.values_mut() {
+            namespace.set_interning(on);
+        }
+    }
+
+    /// Refuses pushes that would grow a list past `max` bytes, in every
+    /// namespace, or with `None` lets lists grow without limit.
+    pub fn set_max_value_size(&mut self, max: Option<usize>) {
+        self.max_value_size = max;
+        for namespace in self.namespaces.values_mut() {
+            namespace.set_max_value_size(max);
+        }
+    }
+
+    /// How many distinct values are shared, and their bytes, across
+    /// namespaces.
+    pub fn interned(&self) -> (usize, usize) {
+        let (mut values, mut bytes) = self.interner.as_ref().map_or((0, 0), Interner::stats);
+        for namespace in self.namespaces.values() {
+            let (more_values, more_bytes) = namespace.interned();
+            values += more_values;
+            bytes += more_bytes;
+        }
+        (values, bytes)
+    }
+
+    /// Lets go of a value that is leaving the map.
+    fn release(&mut self, val: &Val) {
+        if let Some(interner) = &mut self.interner {
+            interner.release(val);
+        }
+    }
+
+    /// When `key` expires, if it has a TTL.
+    pub fn expiry(&self, key: &[u8]) -> Option<u64> {
+        self.expires.get(key).copied()
+    }
+
+    /// Makes an existing `key` expire at `at`, returning whether it exists.
+    pub fn expire_at(&mut self, key: &[u8], at: u64) -> bool {
+        let Some((key, _)) = self.entries.get_key_value(key) else {
+            return false;
+        };
+        let key = key.clone();
+        self.persist(&key);
+        self.deadlines.insert((at, key.clone()));
+        self.expires.insert(key, at);
+        true
+    }
+
+    /// Drops the TTL of `key`, returning whether it had one.
+    pub fn persist(&mut self, key: &[u8]) -> bool {
+        match self.expires.remove_entry(key) {
+            Some((key, at)) => self.deadlines.remove(&(at, key)),
+            None => false,
+        }
+    }
+
+    /// A key picked uniformly at random, without a scan.
+    pub fn random_key(&self) -> Option<&Key> {
+        (!self.keys.is_empty()).then(|| &self.keys[random_below(self.keys.len())])
+    }
+
+    /// `n` different keys picked uniformly at random, or every key if there
+    /// are no more than `n`, by Floyd's algorithm so that it takes as long
+    /// as `n` rather than the number of keys.
+    pub fn sample(&self, n: usize) -> Vec<Key> {
+        let len = self.keys.len();
+        let mut picked = HashSet::new();
+        for last in len.saturating_sub(n)..len {
+            let i = random_below(last + 1);
+            picked.insert(if picked.contains(&i) { last } else { i });
+        }
+        picked.into_iter().map(|i| self.keys[i].clone()).collect()
+    }
+
+    /// Notes that `key` was used at `now`, if it exists. A key's first use
+    /// is its creation, which starts its frequency counter off.
+    pub fn touch(&mut self, key: &[u8], now: u64) {
+        if let Some((key, _)) = self.entries.get_key_value(key) {
+            match self.access.get_mut(key) {
+                Some(access) => access.hit(now),
+                None => {
+                    self.access.insert(key.clone(), Access::new(now));
+                }
+            }
+        }
+    }
+
+    /// Notes every key, in every namespace, as created at `now`, for a map
+    /// just loaded.
+    pub fn touch_all(&mut self, now: u64) {
+        self.access = self
+            .keys
+            .iter()
+            .map(|key| (key.clone(), Access::new(now)))
+            .collect();
+        for namespace in self.namespaces.values_mut() {
+            namespace.touch_all(now);
+        }
+    }
+
+    /// How recently and often `key` has been used, if the leader has noted
+    /// it.
+    pub fn access(&self, key: &[u8]) -> Option<Access> {
+        self.access.get(key).copied()
+    }
+
+    /// Adds a key that is new to `entries` to the keys picked from.
+    fn track(&mut self, key: &Key) {
+        self.slots.insert(key.clone(), self.keys.len());
+        self.keys.push(key.clone());
+    }
+
+    /// Drops a key removed from `entries` from the keys picked from.
+    fn untrack(&mut self, key: &[u8]) {
+        self.access.remove(key);
+        if let Some(slot) = self.slots.remove(key) {
+            self.keys.swap_remove(slot);
+            if let Some(moved) = self.keys.get(slot) {
+                self.slots.insert(moved.clone(), slot);
+            }
+        }
+    }
+
+    /// How many keys have a TTL.
+    pub fn expiring(&self) -> usize {
+        self.expires.len()
+    }
+
+    pub fn namespace(&self, name: &[u8]) -> Option<&Db> {
+        match name {
+            DEFAULT_NAMESPACE => Some(self),
+            name => self.namespaces.get(name),
+        }
+    }
+
+    /// The namespace `name`, created if it doesn't exist yet. Call
+    /// [`Db::drop_if_empty`] once done with it.
+    pub fn namespace_mut(&mut self, name: &[u8]) -> &mut Db {
+        let (interning, max_value_size) = (self.interner.is_some(), self.max_value_size);
+        match name {
+            DEFAULT_NAMESPACE => self,
+            name => self
+                .namespaces
+                .entry(Bytes::copy_from_slice(name))
+                .or_insert_with(|| {
+                    let mut db = Db::new();
+                    db.set_interning(interning);
+                    db.set_max_value_size(max_value_size);
+                    db
+                }),
+        }
+    }
+
+    /// Forgets the namespace `name` if it has no keys left, so that maps
+    /// which hold the same keys compare equal.
+    pub fn drop_if_empty(&mut self, name: &[u8]) {
+        if self.namespaces.get(name).is_some_and(|db| db.is_empty()) {
+            self.namespaces.remove(name);
+        }
+    }
+
+    /// The namespaces other than the default one, in name order.
+    pub fn namespaces(&self) -> impl Iterator<Item = (&Bytes, &Db)> {
+        self.namespaces.iter()
+    }
+
+    /// Whether the node at `addr` was last added to the cluster, `true`, or
+    /// removed from it, `false`, or `None` if no `CLUSTER` record named it.
+    pub fn member(&self, addr: &str) -> Option<bool> {
+        self.membership.get(addr).copied()
+    }
+
+    /// Every node a `CLUSTER` record named, in address order, with whether
+    /// it was last added.
+    pub fn membership(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.membership
+            .iter()
+            .map(|(addr, member)| (addr.as_str(), *member))
+    }
+
+    /// Notes that the node at `addr` was added or removed, or with `None`
+    /// forgets that it was either.
+    pub fn set_member(&mut self, addr: &str, member: Option<bool>) {
+        match member {
+            Some(member) => self.membership.insert(addr.to_string(), member),
+            None => self.membership.remove(addr),
+        };
+    }
+
+    /// Removes every key whose TTL is up at `now`, returning them and the
+    /// values they had in the order they expired.
+    pub fn remove_expired(&mut self, now: u64) -> Vec<(Key, Val)> {
+        let mut expired = Vec::new();
+        while let Some((at, _)) = self.deadlines.first() {
+            if *at > now {
+                break;
+            }
+            let (_, key) = self.deadlines.pop_first().unwrap();
+            self.expires.remove(&key);
+            if let Some(val) = self.entries.remove(&key) {
+                self.untrack(&key);
+                self.release(&val);
+                expired.push((key, val));
+            }
+        }
+        expired
+    }
+}
+
+impl Deref for Db {
+    type Target = HashMap<Key, Val>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.entries
+    }
+}
+
+impl<'a> IntoIterator for &'a Db {
+    type Item = (&'a Key, &'a Val);
+    type IntoIter = std::collections::hash_map::Iter<'a, Key, Val>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.iter()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Get(Key, Val),
+    Set(Key, Val),
+    Replace(Key, Val, Val),
+    Delete(Key, Val),
+    KeyNotFound(Key),
+    /// A conditional `SET` whose condition didn't hold.
+    NotSet(Key),
+    Info(String),
+    Integer(i64),
+    /// Keys, as `SAMPLE` answers with, or the IDs and data of stream
+    /// entries for `XREAD`, sent as a push of bulk values.
+    Keys(Vec<Key>),
+    Ok,
+    Error(ErrorReply),
+}
+
+fn lossy(bytes: &[u8]) -> Cow<'_, str> {
+    String::from_utf8_lossy(bytes)
+}
+
+impl fmt::Display for Response {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Response::Get(key, val) => write!(f, "Key {}={}", lossy(key), lossy(val)),
+            Response::Set(key, val) => write!(f, "Set {}={}", lossy(key), lossy(val)),
+            Response::Replace(key, old_val, new_val) => write!(
+                f,
+                "Key {}={}, used to be {}",
+                lossy(key),
+                lossy(new_val),
+                lossy(old_val)
+            ),
+            Response::Delete(key, val) => write!(
+                f,
+                "Deleted key {} that was set to {}",
+                lossy(key),
+                lossy(val)
+            ),
+            Response::KeyNotFound(key) => write!(f, "Key {} was not found.", lossy(key)),
+            Response::NotSet(key) => write!(f, "Key {} was not set.", lossy(key)),
+            Response::Info(info) => write!(f, "{}", info.trim_end()),
+            Response::Integer(n) => write!(f, "{}", n),
+            Response::Keys(keys) => {
+                let keys: Vec<_> = keys.iter().map(|key| lossy(key)).collect();
+                write!(f, "{}", keys.join("\n"))
+            }
+            Response::Ok => write!(f, "OK"),
+            Response::Error(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl Response {
+    /// Appends the wire form of the response: `+OK`, `:<n>`, a `$<len>` bulk
+    /// value, `$-1` for a missing key, a `><n>` push or an `-ERR` line.
+    pub fn encode(&self, buf: &mut BytesMut) {
+        match self {
+            Response::Get(_, val) => {
+                buf.extend_from_slice(format!("${}\n", val.len()).as_bytes());
+                buf.extend_from_slice(val);
+                buf.extend_from_slice(b"\n");
+            }
+            Response::Set(..) | Response::Replace(..) | Response::Ok => {
+                buf.extend_from_slice(b"+OK\n")
+            }
+            Response::Delete(..) => buf.extend_from_slice(b":1\n"),
+            Response::KeyNotFound(_) | Response::NotSet(_) => buf.extend_from_slice(b"$-1\n"),
+            Response::Integer(n) => buf.extend_from_slice(format!(":{}\n", n).as_bytes()),
+            Response::Info(info) => {
+                buf.extend_from_slice(format!("${}\n", info.len()).as_bytes());
+                buf.extend_from_slice(info.as_bytes());
+                buf.extend_from_slice(b"\n");
+            }
+            Response::Keys(keys) => {
+                let keys: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+                buf.extend_from_slice(&push(&keys));
+            }
+            Response::Error(err) => buf.extend_from_slice(format!("{}\n", err).as_bytes()),
+        }
+    }
+}
+
+pub fn run_command(hashmap: &mut Db, command: &Command) -> Response {
+    match command {
+        Command::Get(key) | Command::GetWith(key, _) => match hashmap.get(&key[..]) {
+            Some(val) => Response::Get(Key::copy_from_slice(key), val.clone()),
+            None => Response::KeyNotFound(Key::copy_from_slice(key)),
+        },
+        Command::Set(key, val) => {
+            let (key, val) = (Key::copy_from_slice(key), Val::copy_from_slice(val));
+            match hashmap.insert(key.clone(), val.clone()) {
+                Some(old_val) => Response::Replace(key, old_val, val),
+                None => Response::Set(key, val),
+            }
+        }
+        // A relative expiry counts from the epoch here: `LeaderCore::execute`
+        // turns it into a time before the command gets this far.
+        Command::SetWith(key, val, options) => {
+            let exists = hashmap.contains_key(&key[..]);
+            match options.condition {
+                Some(Condition::Absent) if exists => {
+                    return Response::NotSet(Key::copy_from_slice(key))
+                }
+                Some(Condition::Exists) if !exists => {
+                    return Response::NotSet(Key::copy_from_slice(key))
+                }
+                _ => {}
+            }
+            let (key, val) = (Key::copy_from_slice(key), Val::copy_from_slice(val));
+            let old = if options.keep_ttl {
+                hashmap.update(key.clone(), val.clone())
+            } else {
+                hashmap.insert(key.clone(), val.clone())
+            };
+            if let Some(expiry) = options.expiry {
+                hashmap.expire_at(&key, expiry.at(0));
+            }
+            match old {
+                Some(old_val) => Response::Replace(key, old_val, val),
+                None => Response::Set(key, val),
+            }
+        }
+        Command::Delete(key) => match hashmap.remove(&key[..]) {
+            Some(old_val) => Response::Delete(Key::copy_from_slice(key), old_val),
+            None => Response::KeyNotFound(Key::copy_from_slice(key)),
+        },
+        Command::Batch(commands) => {
+            for command in commands {
+                run_command(hashmap, command);
+            }
+            Response::Ok
+        }
+        Command::In(name, command) => {
+            let response = run_command(hashmap.namespace_mut(name), command);
+            hashmap.drop_if_empty(name);
+            response
+        }
+        Command::Eval(script, keys, args) => match script::eval(hashmap, script, keys, args) {
+            Ok((value, writes)) => {
+                run_command(hashmap, &Command::Batch(writes));
+                value.into_response()
+            }
+            Err(err) => Response::Error(err),
+        },
+        // Keys are answered as a value with no key, as a script's strings are.
+        Command::RandomKey => match hashmap.random_key() {
+            Some(key) => Response::Get(Key::new(), key.clone()),
+            None => Response::KeyNotFound(Key::new()),
+        },
+        Command::Sample(n) => Response::Keys(hashmap.sample(*n)),
+        Command::Dump(key) => match hashmap.get(&key[..]) {
+            Some(val) => {
+                let dump = dump::encode(val, hashmap.expiry(key));
+                Response::Get(Key::copy_from_slice(key), dump.into())
+            }
+            None => Response::KeyNotFound(Key::copy_from_slice(key)),
+        },
+        Command::Restore(key, dump, replace) => {
+            let dump = match dump::decode(dump) {
+                Ok(dump) => dump,
+                Err(e) => {
+                    return Response::Error(ErrorReply::new(ErrorCode::WrongArgs, e.to_string()))
+                }
+            };
+            if !replace && hashmap.contains_key(&key[..]) {
+                let err = ErrorReply::new(ErrorCode::BusyKey, "the key already exists");
+                return Response::Error(err);
+            }
+            let (key, val) = (Key::copy_from_slice(key), Val::from(dump.val));
+            let old = hashmap.insert(key.clone(), val.clone());
+            match dump.expires_at {
+                Some(at) => hashmap.expire_at(&key, at),
+                None => hashmap.persist(&key),
+            };
+            match old {
+                Some(old_val) => Response::Replace(key, old_val, val),
+                None => Response::Set(key, val),
+            }
+        }
+        Command::DelIfEq(key, expected) => {
+            if hashmap
+                .get(&key[..])
+                .is_some_and(|val| val[..] == expected[..])
+            {
+                hashmap.remove(key);
+                return Response::Integer(1);
+            }
+            Response::Integer(0)
+        }
+        Command::Ephemeral(key, val) => {
+            run_command(hashmap, &Command::Set(key[..].into(), val[..].into()))
+        }
+        Command::Unlock(key, token) => {
+            let token = token.to_string();
+            run_command(
+                hashmap,
+                &Command::DelIfEq(key[..].into(), token.as_bytes().into()),
+            )
+        }
+        Command::GetRange(key, start, end) => {
+            let val = hashmap.get(&key[..]).cloned().unwrap_or_default();
+            let range = byte_range(val.len(), *start, *end);
+            Response::Get(Key::copy_from_slice(key), val.slice(range))
+        }
+        Command::SetRange(key, offset, val) => {
+            let old = hashmap.get(&key[..]);
+            if val.is_empty() {
+                // Nothing to write, so a missing key isn't created either.
+                return Response::Integer(old.map_or(0, |old| old.len()) as i64);
+            }
+            let mut new = BytesMut::from(old.map_or(&[][..], |old| &old[..]));
+            let end = offset + val.len();
+            if new.len() < end {
+                new.resize(end, 0);
+            }
+            new[*offset..end].copy_from_slice(val);
+            let len = new.len();
+            hashmap.update(Key::copy_from_slice(key), new.freeze().into());
+            Response::Integer(len as i64)
+        }
+        Command::SetBit(key, offset, bit) => {
+            let (byte, mask) = (offset / 8, 0x80 >> (offset % 8));
+            let old = hashmap.get(&key[..]);
+            let mut new = BytesMut::from(old.map_or(&[][..], |old| &old[..]));
+            if new.len() <= byte {
+                new.resize(byte + 1, 0);
+            }
+            let was_set = new[byte] & mask != 0;
+            if *bit {
+                new[byte] |= mask;
+            } else {
+                new[byte] &= !mask;
+            }
+            hashmap.update(Key::copy_from_slice(key), new.freeze().into());
+            Response::Integer(was_set.into())
+        }
+        Command::GetBit(key, offset) => {
+            let byte = hashmap.get(&key[..]).and_then(|val| val.get(offset / 8));
+            let set = byte.is_some_and(|byte| byte & (0x80 >> (offset % 8)) != 0);
+            Response::Integer(set.into())
+        }
+        Command::BitCount(key, range) => {
+            let val = hashmap.get(&key[..]).cloned().unwrap_or_default();
+            let range = match range {
+                Some((start, end)) => byte_range(val.len(), *start, *end),
+                None => 0..val.len(),
+            };
+            let count: u32 = val[range].iter().map(|byte| byte.count_ones()).sum();
+            Response::Integer(count.into())
+        }
+        Command::PfAdd(key, element) => {
+            let mut sketch = match sketch(hashmap, key) {
+                Ok(sketch) => sketch.unwrap_or_default(),
+                Err(err) => return err,
+            };
+            let changed = sketch.add(element) || !hashmap.contains_key(&key[..]);
+            if changed {
+                hashmap.update(Key::copy_from_slice(key), sketch.to_bytes().into());
+            }
+            Response::Integer(changed.into())
+        }
+        Command::PfCount(key) => match sketch(hashmap, key) {
+            Ok(sketch) => Response::Integer(sketch.map_or(0, |sketch| sketch.count()) as i64),
+            Err(err) => err,
+        },
+        Command::PfMerge(dest, src) => {
+            let (mut merged, src) = match (sketch(hashmap, dest), sketch(hashmap, src)) {
+                (Ok(dest), Ok(src)) => (dest.unwrap_or_default(), src),
+                (Err(err), _) | (_, Err(err)) => return err,
+            };
+            if let Some(src) = src {
+                merged.merge(&src);
+            }
+            hashmap.update(Key::copy_from_slice(dest), merged.to_bytes().into());
+            Response::Ok
+        }
+        Command::LPush(key, element) | Command::RPush(key, element) => {
+            let mut list = match list(hashmap, key) {
+                Ok(list) => list.unwrap_or_default(),
+                Err(err) => return err,
+            };
+            match command {
+                Command::LPush(..) => list.push_front(element),
+                _ => list.push_back(element),
+            }
+            // Each element was checked as it was parsed; the list as a whole
+            // is a value too.
+            let val = list.to_bytes();
+            if let Some(max) = hashmap.max_value_size.filter(|max| val.len() > *max) {
+                let err = ParseError::ValueTooLarge {
+                    len: val.len(),
+                    max,
+                };
+                return Response::Error(err.into());
+            }
+            hashmap.update(Key::copy_from_slice(key), val.into());
+            Response::Integer(list.len() as i64)
+        }
+        Command::LPop(key) => {
+            let mut list = match list(hashmap, key) {
+                Ok(Some(list)) => list,
+                Ok(None) => return Response::KeyNotFound(Key::copy_from_slice(key)),
+                Err(err) => return err,
+            };
+            let Some(element) = list.pop_front() else {
+                return Response::KeyNotFound(Key::copy_from_slice(key));
+            };
+            if list.is_empty() {
+                hashmap.remove(key);
+            } else {
+                hashmap.update(Key::copy_from_slice(key), list.to_bytes().into());
+            }
+            Response::Get(Key::copy_from_slice(key), element.into())
+        }
+        Command::LLen(key) => match list(hashmap, key) {
+            Ok(list) => Response::Integer(list.map_or(0, |list| list.len()) as i64),
+            Err(err) => err,
+        },
+        // Without an ID, as when replayed from a script, the entry goes right
+        // after the newest one: the leader picks IDs from its clock in
+        // `node::apply_at`.
+        Command::XAdd(key, id, data) => {
+            let mut stream = match stream(hashmap, key) {
+                Ok(stream) => stream.unwrap_or_default(),
+                Err(err) => return err,
+            };
+            let id = id.unwrap_or_else(|| stream.next_id(0));
+            if !stream.add(id, data) {
+                let err = ErrorReply::new(
+                    ErrorCode::WrongArgs,
+                    format!(
+                        "ID {} is not after the stream's last, {}",
+                        id,
+                        stream.last_id()
+                    ),
+                );
+                return Response::Error(err);
+            }
+            hashmap.update(Key::copy_from_slice(key), stream.to_bytes().into());
+            Response::Info(id.to_string())
+        }
+        Command::XRead(key, after, count) => match stream(hashmap, key) {
+            Ok(stream) => {
+                let stream = stream.unwrap_or_default();
+                let entries = stream.read(*after, count.unwrap_or(usize::MAX));
+                let entries = entries.iter().flat_map(|(id, data)| {
+                    [
+                        Key::copy_from_slice(id.to_string().as_bytes()),
+                        data.clone().into(),
+                    ]
+                });
+                Response::Keys(entries.collect())
+            }
+            Err(err) => err,
+        },
+        Command::ExpireAt(key, at) => {
+            Response::Integer(hashmap.expire_at(key, at.saturating_mul(1000)).into())
+        }
+        Command::PExpireAt(key, at) => Response::Integer(hashmap.expire_at(key, *at).into()),
+        Command::Persist(key) => Response::Integer(hashmap.persist(key).into()),
+        Command::SetStream(_) | Command::GetStream(..) => Response::Error(ErrorReply::new(
+            ErrorCode::NotSupported,
+            "streaming is only available to network clients",
+        )),
+        Command::Replicate(_) => Response::Error(ErrorReply::new(
+            ErrorCode::NotSupported,
+            "REPLICATE is only sent by the leader to its followers",
+        )),
+        Command::Install(_) => Response::Error(ErrorReply::new(
+            ErrorCode::NotSupported,
+            "INSTALL only appears in the replication stream",
+        )),
+        Command::RequestId(_) => Response::Error(ErrorReply::new(
+            ErrorCode::NotSupported,
+            "REQID names the next write sent to the leader",
+        )),
+        // The leader starts and stops replicating itself; the map only
+        // keeps the change, for the log.
+        Command::Cluster(ClusterCommand::AddNode(addr)) => {
+            hashmap.set_member(&String::from_utf8_lossy(addr), Some(true));
+            Response::Ok
+        }
+        Command::Cluster(ClusterCommand::RemoveNode(addr)) => {
+            hashmap.set_member(&String::from_utf8_lossy(addr), Some(false));
+            Response::Ok
+        }
+        Command::Info(_)
+        | Command::Sync(..)
+        | Command::Hello(..)
+        | Command::WhoAmI
+        | Command::WhoIsLeader
+        | Command::Peers
+        | Command::ReadOnly(_)
+        | Command::Config(_)
+        | Command::Client(_)
+        | Command::Analyze(_)
+        | Command::Export(..)
+        | Command::BulkLoad
+        | Command::BulkLoadEnd
+        | Command::Quota
+        | Command::DumpState
+        | Command::Debug(_)
+        | Command::TransferLeadership(_)
+        | Command::Subscribe(_)
+        | Command::Unsubscribe(_)
+        | Command::PSubscribe(_)
+        | Command::PUnsubscribe(_)
+        | Command::Publish(..)
+        | Command::Tail(_)
+        | Command::Select(_)
+        | Command::Auth(..)
+        | Command::FCall(..)
+        | Command::GetAt(..)
+        | Command::GetVersion(..)
+        | Command::History(..)
+        | Command::Ttl(_)
+        | Command::Lock(..)
+        | Command::WaitChange(..)
+        | Command::BLPop(..)
+        | Command::Object(..) => Response::Error(ErrorReply::new(
+            ErrorCode::NotSupported,
+            format!("{} is answered by the leader", command.name()),
+        )),
+    }
+}
+
+/// The sketch stored at `key`, or a `WRONGTYPE` error if the value there
+/// isn't one.
+fn sketch(hashmap: &Db, key: &[u8]) -> Result<Option<HyperLogLog>, Response> {
+    let Some(val) = hashmap.get(key) else {
+        return Ok(None);
+    };
+    match HyperLogLog::from_bytes(val) {
+        Some(sketch) => Ok(Some(sketch)),
+        None => Err(Response::Error(ErrorReply::new(
+            ErrorCode::WrongType,
+            "value is not a HyperLogLog",
+        ))),
+    }
+}
+
+/// The list stored at `key`, `None` if there isn't one, or a `WRONGTYPE`
+/// error if the value isn't a list.
+fn list(hashmap: &Db, key: &[u8]) -> Result<Option<List>, Response> {
+    let Some(val) = hashmap.get(key) else {
+        return Ok(None);
+    };
+    match List::from_bytes(val) {
+        Some(list) => Ok(Some(list)),
+        None => Err(Response::Error(ErrorReply::new(
+            ErrorCode::WrongType,
+            "value is not a list",
+        ))),
+    }
+}
+
+/// The stream stored at `key`, `None` if there isn't one, or a `WRONGTYPE`
+/// error if the value isn't a stream.
+fn stream(hashmap: &Db, key: &[u8]) -> Result<Option<Stream>, Response> {
+    let Some(val) = hashmap.get(key) else {
+        return Ok(None);
+    };
+    match Stream::from_bytes(val) {
+        Some(stream) => Ok(Some(stream)),
+        None => Err(Response::Error(ErrorReply::new(
+            ErrorCode::WrongType,
+            "value is not a stream",
+        ))),
+    }
+}
+
+/// Answers `TTL` for `key` at `now`, in milliseconds since the Unix epoch.
+pub fn ttl(hashmap: &Db, key: &[u8], now: u64) -> Response {
+    if !hashmap.contains_key(key) {
+        return Response::Integer(-2);
+    }
+    match hashmap.expiry(key) {
+        Some(at) => Response::Integer((at.saturating_sub(now) + 500) as i64 / 1000),
+        None => Response::Integer(-1),
+    }
+}
+
+/// Records the whole value of `key`, and its TTL if it has one, which the
+/// `SET` on its own would drop.
+fn set_record(hashmap: &Db, key: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::new();
+    Command::Set(key.into(), hashmap[key][..].into()).encode(&mut buf);
+    if let Some(at) = hashmap.expiry(key) {
+        Command::PExpireAt(key.into(), at).encode(&mut buf);
+    }
+    buf
+}
+
+/// Whether `command` is one of the records logs are made of, and so is
+/// applied on replay and by followers.
+pub fn is_record(command: &Command) -> bool {
+    match command {
+        Command::In(_, command) => is_record(command),
+        command => matches!(
+            command,
+            Command::Set(..)
+                | Command::Delete(_)
+                | Command::Batch(_)
+                | Command::PExpireAt(..)
+                | Command::Persist(_)
+                | Command::Cluster(_)
+        ),
+    }
+}
+
+/// Wraps each of `records`, made by a command run in namespace `name`, in an
+/// `IN` record.
+pub fn in_namespace(name: &[u8], records: &[u8]) -> BytesMut {
+    let mut buf = BytesMut::new();
+    let commands = parse_all(records, &Limits::NONE).expect("records always parse");
+    for command in commands {
+        Command::In(name.into(), Box::new(command)).encode(&mut buf);
+    }
+    buf
+}
+
+/// The records that rebuild the map, in every namespace: a `SET` for each
+/// key, a `PEXPIREAT` for each TTL and a `CLUSTER` record for each node
+/// added or removed.
+pub fn records(hashmap: &Db) -> BytesMut {
+    let mut records = BytesMut::new();
+    for (addr, member) in hashmap.membership() {
+        let addr = addr.as_bytes().into();
+        let command = match member {
+            true => ClusterCommand::AddNode(addr),
+            false => ClusterCommand::RemoveNode(addr),
+        };
+        Command::Cluster(command).encode(&mut records);
+    }
+    for (key, val) in hashmap {
+        Command::Set(key[..].into(), val[..].into()).encode(&mut records);
+        if let Some(at) = hashmap.expiry(key) {
+            Command::PExpireAt(key[..].into(), at).encode(&mut records);
+        }
+    }
+    for (name, namespace) in hashmap.namespaces() {
+        records.extend_from_slice(&in_namespace(name, &self::records(namespace)));
+    }
+    records
+}
+
+/// The byte range of a value of length `len` from `start` to `end`
+/// inclusive, with negative offsets counting back from the end and both ends
+/// clamped to the value.
+fn byte_range(len: usize, start: i64, end: i64) -> std::ops::Range<usize> {
+    let len = len as i64;
+    let clamp = |i: i64| if i < 0 { (len + i).max(0) } else { i };
+    let (start, end) = (clamp(start), clamp(end).min(len - 1));
+    if start > end {
+        return 0..0;
+    }
+    start as usize..end as usize + 1
+}
+
+/// Applies `command` to the map and returns the response along with the log
+/// record for it, if it changed the map. Replaying the records of any
+/// sequence of applied commands rebuilds the same map.
+pub fn apply(hashmap: &mut Db, command: &Command) -> (Response, Option<BytesMut>) {
+    if let Command::In(name, command) = command {
+        let (response, record) = apply(hashmap.namespace_mut(name), command);
+        hashmap.drop_if_empty(name);
+        return (response, record.map(|record| in_namespace(name, &record)));
+    }
+    // A script's writes are logged as a batch, and not at all if it fails.
+    if let Command::Eval(script, keys, args) = command {
+        return match script::eval(hashmap, script, keys, args) {
+            Ok((value, writes)) => {
+                let (_, record) = apply(hashmap, &Command::Batch(writes));
+                (value.into_response(), record)
+            }
+            Err(err) => (Response::Error(err), None),
+        };
+    }
+    let response = run_command(hashmap, command);
+    let mut record = None;
+    match (command, &response) {
+        // Logged with the TTL as a time, so that replay doesn't restart it.
+        (
+            Command::SetWith(key, ..) | Command::Restore(key, ..),
+            Response::Set(..) | Response::Replace(..),
+        ) => {
+            record = Some(set_record(hashmap, key));
+        }
+        (Command::Ephemeral(key, val), Response::Set(..) | Response::Replace(..)) => {
+            let mut buf = BytesMut::new();
+            Command::Set(key[..].into(), val[..].into()).encode(&mut buf);
+            record = Some(buf);
+        }
+        (_, Response::Set(..) | Response::Replace(..) | Response::Delete(..)) => {
+            let mut buf = BytesMut::new();
+            command.encode(&mut buf);
+            record = Some(buf);
+        }
+        // Writes to part of a value are logged as a SET of the whole value.
+        (Command::SetRange(key, _, val), Response::Integer(_)) if !val.is_empty() => {
+            record = Some(set_record(hashmap, key));
+        }
+        (Command::SetBit(key, ..), Response::Integer(_)) => record = Some(set_record(hashmap, key)),
+        (Command::PfAdd(key, _), Response::Integer(1))
+        | (Command::PfMerge(key, _), Response::Ok) => record = Some(set_record(hashmap, key)),
+        (Command::LPush(key, _) | Command::RPush(key, _), Response::Integer(_)) => {
+            record = Some(set_record(hashmap, key))
+        }
+        // Streams too are logged whole, so that the log holds only records
+        // every reader of it understands.
+        (Command::XAdd(key, ..), Response::Info(_)) => record = Some(set_record(hashmap, key)),
+        // A pop that empties the list deletes the key.
+        (Command::LPop(key), Response::Get(..)) if hashmap.contains_key(&key[..]) => {
+            record = Some(set_record(hashmap, key))
+        }
+        (Command::LPop(key), Response::Get(..)) => {
+            let mut buf = BytesMut::new();
+            Command::Delete(key[..].into()).encode(&mut buf);
+            record = Some(buf);
+        }
+        // TTLs are logged as the time they run out, in milliseconds.
+        (Command::ExpireAt(key, _) | Command::PExpireAt(key, _), Response::Integer(1)) => {
+            let mut buf = BytesMut::new();
+            let at = hashmap.expiry(key).unwrap_or_default();
+            Command::PExpireAt(key[..].into(), at).encode(&mut buf);
+            record = Some(buf);
+        }
+        (Command::Batch(commands), Response::Ok) if !commands.is_empty() => {
+            let mut buf = BytesMut::new();
+            command.encode(&mut buf);
+            record = Some(buf);
+        }
+        (Command::DelIfEq(key, _) | Command::Unlock(key, _), Response::Integer(1)) => {
+            let mut buf = BytesMut::new();
+            Command::Delete(key[..].into()).encode(&mut buf);
+            record = Some(buf);
+        }
+        (Command::Persist(_), Response::Integer(1)) | (Command::Cluster(_), Response::Ok) => {
+            let mut buf = BytesMut::new();
+            command.encode(&mut buf);
+            record = Some(buf);
+        }
+        _ => {}
+    }
+    (response, record)
+}
+
+/// How replay treats records that can't be parsed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Strictness {
+    /// Refuse to start from a log with corrupt records.
+    #[default]
+    Strict,
+    /// Skip corrupt records, reporting where they were.
+    Skip,
+}
+
+/// What replaying a log found.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReplayReport {
+    /// Records applied.
+    pub records: usize,
+    /// Offsets of the corrupt records that were skipped.
+    pub skipped: Vec<usize>,
+    /// Bytes of the log up to the torn record it ends in, if it does.
+    pub complete: usize,
+    /// The highest LSN among the request IDs replayed, or the checkpoint's
+    /// if that is higher, for the node to carry on from.
+    pub lsn: u64,
+}
+
+/// How far replay has got, as reported to [`replay_with_progress`]'s
+/// callback.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LoadProgress {
+    pub records: usize,
+    pub bytes_read: usize,
+    pub total_bytes: usize,
+}
+
+/// How many bytes of log to replay between progress reports.
+const PROGRESS_INTERVAL: usize = 4 * 1024 * 1024;
+
+/// Rebuilds the map from the contents of a log.
+pub fn replay(log: &[u8]) -> Result<Db> {
+    Ok(replay_with(log, Strictness::Strict)?.0)
+}
+
+/// Rebuilds the map from the contents of a log, handling corrupt records
+/// according to `strictness`. Records of commands this version doesn't know
+/// are skipped either way, and a torn record at the end is always ignored.
+/// Records are parsed on one thread per core and applied in log order.
+pub fn replay_with(log: &[u8], strictness: Strictness) -> Result<(Db, ReplayReport)> {
+    replay_with_progress(log, strictness, |_| {})
+}
+
+/// Like [`replay_with`], calling `progress` every few megabytes of log and
+/// once more at the end.
+pub fn replay_with_progress(
+    log: &[u8],
+    strictness: Strictness,
+    progress: impl FnMut(&LoadProgress),
+) -> Result<(Db, ReplayReport)> {
+    replay_onto(Db::new(), log, 0, strictness, progress)
+}
+
+/// Like [`replay_with_progress`], applying the records of the log from
+/// `start` on top of `hashmap` rather than the whole log to an empty map,
+/// for a log that carries on from a checkpoint. Offsets, in errors, the
+/// report and progress alike, are into the whole log.
+pub fn replay_onto(
+    mut hashmap: Db,
+    log: &[u8],
+    start: usize,
+    strictness: Strictness,
+    mut progress: impl FnMut(&LoadProgress),
+) -> Result<(Db, ReplayReport)> {
+    let mut report = ReplayReport {
+        complete: log.len(),
+        ..ReplayReport::default()
+    };
+    let mut loaded = LoadProgress {
+        bytes_read: start,
+        total_bytes: log.len(),
+        ..LoadProgress::default()
+    };
+    for entry in LogReader::parallel(&log[start..], replay_threads()) {
+        let (offset, err) = match entry {
+            Entry::Record {
+                offset,
+                len,
+                command: Ok(command),
+            } => {
+                if is_record(&command) {
+                    run_command(&mut hashmap, &command);
+                    report.records += 1;
+                }
+                if let Some((_, lsn)) = request_marker(&command).and_then(parse_request_id) {
+                    report.lsn = report.lsn.max(lsn);
+                }
+                let read = start + offset + len;
+                if read / PROGRESS_INTERVAL > loaded.bytes_read / PROGRESS_INTERVAL {
+                    loaded.records = report.records;
+                    loaded.bytes_read = read;
+                    progress(&loaded);
+                }
+                continue;
+            }
+            Entry::Record {
+                command: Err(ParseError::UnknownCommand(_) | ParseError::Empty),
+                ..
+            } => continue,
+            Entry::Torn { offset, .. } => {
+                report.complete = start + offset;
+                continue;
+            }
+            Entry::Record {
+                offset,
+                command: Err(err),
+                ..
+            } => (start + offset, err),
+            Entry::Unframeable { offset, err } => (start + offset, err),
+        };
+        if strictness == Strictness::Strict {
+            bail!(Corrupt, "corrupt log record at offset {}: {}", offset, err);
+        }
+        eprintln!("Skipping corrupt log record at offset {}: {}", offset, err);
+        report.skipped.push(offset);
+    }
+    loaded.records = report.records;
+    loaded.bytes_read = log.len();
+    progress(&loaded);
+    Ok((hashmap, report))
+}
@@ -0,0 +1,126 @@
+//! Append-only streams for `XADD` and `XREAD`, stored as ordinary values:
+//! the magic bytes `STRM` followed by each entry as its ID, two 8-byte
+//! big-endian integers, a 4-byte big-endian length and its data, oldest
+//! first.
+//!
+//! Entries are never removed, so a consumer keeps its place in a stream by
+//! remembering the ID of the last entry it read and reading after it.
+
+use std::fmt;
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+const MAGIC: &[u8; 4] = b"STRM";
+
+/// An entry's ID, `<ms>-<seq>`: the Unix time in milliseconds it was added
+/// at, and a sequence number telling apart entries added in the same
+/// millisecond. IDs only ever go up within a stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    /// Parses `<ms>-<seq>`, or `<ms>` for `<ms>-0`.
+    pub fn parse(id: &[u8]) -> Option<StreamId> {
+        let id = std::str::from_utf8(id).ok()?;
+        let (ms, seq) = id.split_once('-').unwrap_or((id, "0"));
+        Some(StreamId {
+            ms: ms.parse().ok()?,
+            seq: seq.parse().ok()?,
+        })
+    }
+}
+
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+#[derive(Default)]
+pub struct Stream {
+    entries: Vec<(StreamId, Bytes)>,
+}
+
+impl Stream {
+    /// Reads a stream back from a value, or `None` if the value isn't one.
+    pub fn from_bytes(val: &[u8]) -> Option<Stream> {
+        let mut rest = val.strip_prefix(MAGIC)?;
+        let mut entries = Vec::new();
+        while !rest.is_empty() {
+            if rest.len() < 20 {
+                return None;
+            }
+            let id = StreamId {
+                ms: rest.get_u64(),
+                seq: rest.get_u64(),
+            };
+            let len = rest.get_u32() as usize;
+            if rest.len() < len {
+                return None;
+            }
+            entries.push((id, Bytes::copy_from_slice(&rest[..len])));
+            rest.advance(len);
+        }
+        Some(Stream { entries })
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let len = self.entries.iter().map(|(_, data)| 20 + data.len());
+        let mut buf = BytesMut::with_capacity(MAGIC.len() + len.sum::<usize>());
+        buf.put_slice(MAGIC);
+        for (id, data) in &self.entries {
+            buf.put_u64(id.ms);
+            buf.put_u64(id.seq);
+            buf.put_u32(data.len() as u32);
+            buf.put_slice(data);
+        }
+        buf.freeze()
+    }
+
+    /// The ID of the newest entry, `0-0` if there are none.
+    pub fn last_id(&self) -> StreamId {
+        self.entries.last().map(|(id, _)| *id).unwrap_or_default()
+    }
+
+    /// The ID an entry added at `now`, in milliseconds since the Unix epoch,
+    /// gets: `now` itself, unless the clock hasn't moved past the newest
+    /// entry, in which case the newest entry's time with the next sequence
+    /// number.
+    pub fn next_id(&self, now: u64) -> StreamId {
+        let last = self.last_id();
+        if now > last.ms {
+            return StreamId { ms: now, seq: 0 };
+        }
+        StreamId {
+            ms: last.ms,
+            seq: last.seq + 1,
+        }
+    }
+
+    /// Appends an entry, unless `id` isn't greater than the newest entry's.
+    pub fn add(&mut self, id: StreamId, data: &[u8]) -> bool {
+        if id <= self.last_id() {
+            return false;
+        }
+        self.entries.push((id, Bytes::copy_from_slice(data)));
+        true
+    }
+
+    /// Up to `count` of the entries added after `after`, oldest first.
+    pub fn read(&self, after: StreamId, count: usize) -> &[(StreamId, Bytes)] {
+        let start = self.entries.partition_point(|(id, _)| *id <= after);
+        let end = self.entries.len().min(start.saturating_add(count));
+        &self.entries[start..end]
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
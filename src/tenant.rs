@@ -0,0 +1,240 @@
+//! Tenants: users that authenticate with `AUTH` and are then confined to
+//! the keys under a prefix, with optional quotas on how many keys they hold
+//! and how many bytes of keys and values those take up.
+//!
+//! Once any tenant is configured every connection to the leader has to
+//! authenticate before it can run anything but `HELLO` and `AUTH`. Quotas
+//! count the tenant's keys in every namespace and are checked when a write
+//! is made, against a copy of the keys it touches, so a write that would
+//! take the tenant over a quota is refused without being applied. Writes
+//! that shrink a tenant are always let through, even one already over a
+//! quota that has since been lowered.
+//!
+//! Tenants may also be held to a rate: at most `max_ops` requests a second,
+//! and at most `max_write_bytes` bytes of write requests a second, across
+//! all of their connections. Rates are counted over whole seconds of the
+//! leader's clock, and a request that would go over one is refused with
+//! `OVERQUOTA` without being run or counted. `QUOTA` reports a tenant's
+//! rates and how much of them it has used this second.
+
+use std::fmt::Write as _;
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+
+use crate::protocol::{ClientCommand, Command, ErrorCode, ErrorReply};
+use crate::store::{run_command, Db};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Tenant {
+    pub password: String,
+    /// Every key the tenant reads or writes must start with this.
+    pub prefix: Bytes,
+    pub max_keys: Option<usize>,
+    /// The most bytes the tenant's keys and values may add up to.
+    pub max_bytes: Option<usize>,
+    /// The most requests the tenant may send a second.
+    pub max_ops: Option<u64>,
+    /// The most bytes of write requests the tenant may send a second.
+    pub max_write_bytes: Option<usize>,
+}
+
+/// How much of its rates a tenant has used in the current second.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Rate {
+    /// The second of the leader's clock the counts are for.
+    second: u64,
+    pub ops: u64,
+    pub write_bytes: usize,
+}
+
+impl Rate {
+    /// Starts the counts over if `now` is past the second they are for.
+    fn roll(&mut self, now: Duration) {
+        if now.as_secs() != self.second {
+            *self = Rate {
+                second: now.as_secs(),
+                ..Rate::default()
+            };
+        }
+    }
+}
+
+/// How much of the map a tenant's keys take up. Usage is counted by walking
+/// the map, and only for writes that grow the tenant.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Usage {
+    pub keys: usize,
+    pub bytes: usize,
+}
+
+impl Usage {
+    fn add(&mut self, key: &[u8], val: &[u8]) {
+        self.keys += 1;
+        self.bytes += key.len() + val.len();
+    }
+}
+
+impl Tenant {
+    /// Checks that the tenant may run `command`: only commands on its own
+    /// keys, plus the few that manage the connection.
+    pub fn permits(&self, command: &Command<'_>) -> Result<(), ErrorReply> {
+        match command {
+            Command::Hello(..)
+            | Command::WhoAmI
+            | Command::WhoIsLeader
+            | Command::Peers
+            | Command::Auth(..)
+            | Command::Select(_)
+            | Command::Quota
+            | Command::BulkLoad
+            | Command::BulkLoadEnd
+            | Command::Client(ClientCommand::SetName(_) | ClientCommand::Tracking(_)) => Ok(()),
+            // Keyed, so that they're run in the connection's namespace, but
+            // not confined to a prefix.
+            Command::In(..) | Command::RandomKey | Command::Sample(_) => Err(self.denied(command)),
+            command if !command.is_keyed() => Err(self.denied(command)),
+            command => match command
+                .keys()
+                .into_iter()
+                .find(|key| !key.starts_with(&self.prefix))
+            {
+                Some(key) => Err(ErrorReply::new(
+                    ErrorCode::NoPerm,
+                    format!(
+                        "key {} is outside the tenant's prefix {}",
+                        String::from_utf8_lossy(key),
+                        String::from_utf8_lossy(&self.prefix)
+                    ),
+                )),
+                None => Ok(()),
+            },
+        }
+    }
+
+    fn denied(&self, command: &Command<'_>) -> ErrorReply {
+        ErrorReply::new(
+            ErrorCode::NoPerm,
+            format!("tenants can't run {}", command.name()),
+        )
+    }
+
+    /// Counts `command` against the tenant's rates as of `now`, or refuses
+    /// it if it would take the tenant over one.
+    pub fn charge(
+        &self,
+        rate: &mut Rate,
+        command: &Command<'_>,
+        now: Duration,
+    ) -> Result<(), ErrorReply> {
+        rate.roll(now);
+        if let Some(max) = self.max_ops.filter(|max| rate.ops >= *max) {
+            let message = format!("the tenant may send at most {} requests a second", max);
+            return Err(ErrorReply::new(ErrorCode::OverQuota, message));
+        }
+        let written = match command.is_write() {
+            true => {
+                let mut request = BytesMut::new();
+                command.encode(&mut request);
+                request.len()
+            }
+            false => 0,
+        };
+        if let Some(max) = self
+            .max_write_bytes
+            .filter(|max| written > 0 && rate.write_bytes + written > *max)
+        {
+            let message = format!("the tenant may write at most {} bytes a second", max);
+            return Err(ErrorReply::new(ErrorCode::OverQuota, message));
+        }
+        rate.ops += 1;
+        rate.write_bytes += written;
+        Ok(())
+    }
+
+    /// The tenant's rates and how much of them `rate` has used as of `now`,
+    /// as `QUOTA` answers: `name:value` lines, with `none` for no limit.
+    pub fn render_rate(&self, rate: &mut Rate, now: Duration) -> String {
+        rate.roll(now);
+        let limit = |max: Option<String>| max.unwrap_or_else(|| "none".to_string());
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "max_ops:{}",
+            limit(self.max_ops.map(|max| max.to_string()))
+        );
+        let _ = writeln!(out, "ops:{}", rate.ops);
+        let max_write_bytes = self.max_write_bytes.map(|max| max.to_string());
+        let _ = writeln!(out, "max_write_bytes:{}", limit(max_write_bytes));
+        let _ = writeln!(out, "write_bytes:{}", rate.write_bytes);
+        out
+    }
+
+    /// What the tenant's keys take up in `hashmap`, across its namespaces.
+    pub fn usage(&self, hashmap: &Db) -> Usage {
+        let mut usage = Usage::default();
+        let namespaces = hashmap.namespaces().map(|(_, namespace)| namespace);
+        for namespace in std::iter::once(hashmap).chain(namespaces) {
+            for (key, val) in namespace {
+                if key.starts_with(&self.prefix) {
+                    usage.add(key, val);
+                }
+            }
+        }
+        usage
+    }
+
+    /// Checks that running `command` in namespace `name` of `hashmap` leaves
+    /// the tenant within its quotas.
+    pub fn admits(
+        &self,
+        hashmap: &Db,
+        name: &[u8],
+        command: &Command<'_>,
+    ) -> Result<(), ErrorReply> {
+        if self.max_keys.is_none() && self.max_bytes.is_none() {
+            return Ok(());
+        }
+        let namespace = hashmap.namespace(name);
+        // Run the command against a copy of just the keys it touches.
+        let mut scratch = Db::new();
+        let (mut before, mut after) = (Usage::default(), Usage::default());
+        for key in command.keys() {
+            if let Some((key, val)) = namespace.and_then(|db| db.get_key_value(key)) {
+                if !scratch.contains_key(key) {
+                    scratch.insert(key.clone(), val.clone());
+                    before.add(key, val);
+                }
+            }
+        }
+        run_command(&mut scratch, command);
+        for (key, val) in &scratch {
+            after.add(key, val);
+        }
+        if after.keys <= before.keys && after.bytes <= before.bytes {
+            return Ok(());
+        }
+        let usage = self.usage(hashmap);
+        let keys = usage.keys - before.keys + after.keys;
+        let bytes = usage.bytes - before.bytes + after.bytes;
+        let growing = |max: &usize, total, before, after| total > *max && after > before;
+        if let Some(max) = self
+            .max_keys
+            .filter(|max| growing(max, keys, before.keys, after.keys))
+        {
+            let message = format!("the tenant may hold at most {} keys", max);
+            return Err(ErrorReply::new(ErrorCode::OverQuota, message));
+        }
+        if let Some(max) = self
+            .max_bytes
+            .filter(|max| growing(max, bytes, before.bytes, after.bytes))
+        {
+            let message = format!(
+                "the write would take the tenant to {} bytes, over its quota of {}",
+                bytes, max
+            );
+            return Err(ErrorReply::new(ErrorCode::OverQuota, message));
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+//! Tracing: spans for the stages of a request, exported to an OpenTelemetry
+//! collector as OTLP over HTTP in its JSON encoding.
+//!
+//! The leader opens a `request` span for each command a connection runs,
+//! and the stages under it, `execute`, `wal.append`, `wal.sync` and
+//! `replication.send`, are children of whichever span the task is running
+//! in. A follower opens a `replication.apply` span for each record it
+//! applies, tagged with the `request.id` the leader logged ahead of it, so a
+//! write can be followed from the leader's trace to its followers.
+//!
+//! Spans are only recorded once [`start_exporter`] has been called; until
+//! then they cost next to nothing. The exporter posts finished spans in
+//! batches to the collector's URL, normally `http://<host>:4318/v1/traces`,
+//! and drops a batch the collector doesn't take rather than hold up the
+//! ones after it.
+
+use std::collections::hash_map::RandomState;
+use std::fmt::{Display, Write};
+use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::error::{bail, Result};
+use crate::webhook::{self, json_string};
+
+/// How long finished spans wait to be batched before they are posted.
+pub const EXPORT_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The most spans posted in one request.
+const MAX_BATCH: usize = 512;
+
+static EXPORTER: OnceLock<UnboundedSender<Finished>> = OnceLock::new();
+
+tokio::task_local! {
+    /// The span the task is running in, parent to the spans it starts.
+    static CURRENT: SpanContext;
+}
+
+/// What ties a span to its trace and its children to it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpanContext {
+    pub trace_id: u128,
+    pub span_id: u64,
+}
+
+/// A span as sent to the collector.
+struct Finished {
+    context: SpanContext,
+    parent: Option<u64>,
+    name: &'static str,
+    start: u64,
+    end: u64,
+    attributes: Vec<(&'static str, String)>,
+}
+
+/// A span that ends, and is exported, when dropped.
+pub struct Span {
+    inner: Option<Finished>,
+}
+
+impl Span {
+    /// Starts a span in the one the task is running in, or the first span
+    /// of a new trace if it isn't running in one.
+    pub fn start(name: &'static str) -> Span {
+        if EXPORTER.get().is_none() {
+            return Span { inner: None };
+        }
+        let parent = CURRENT.try_with(|context| *context).ok();
+        let context = SpanContext {
+            trace_id: match parent {
+                Some(parent) => parent.trace_id,
+                None => (random() as u128) << 64 | random() as u128,
+            },
+            span_id: random(),
+        };
+        Span {
+            inner: Some(Finished {
+                context,
+                parent: parent.map(|parent| parent.span_id),
+                name,
+                start: unix_nanos(),
+                end: 0,
+                attributes: Vec::new(),
+            }),
+        }
+    }
+
+    pub fn attr(&mut self, key: &'static str, value: impl Display) {
+        if let Some(span) = &mut self.inner {
+            span.attributes.push((key, value.to_string()));
+        }
+    }
+
+    pub fn context(&self) -> Option<SpanContext> {
+        self.inner.as_ref().map(|span| span.context)
+    }
+
+    /// Runs `future` in this span, so that the spans it starts are children
+    /// of it.
+    pub async fn enter<F: Future>(&self, future: F) -> F::Output {
+        match self.context() {
+            Some(context) => CURRENT.scope(context, future).await,
+            None => future.await,
+        }
+    }
+
+    /// Like [`Span::enter`], for code that doesn't await.
+    pub fn enter_sync<R>(&self, f: impl FnOnce() -> R) -> R {
+        match self.context() {
+            Some(context) => CURRENT.sync_scope(context, f),
+            None => f(),
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        if let Some(mut span) = self.inner.take() {
+            span.end = unix_nanos();
+            if let Some(exporter) = EXPORTER.get() {
+                let _ = exporter.send(span);
+            }
+        }
+    }
+}
+
+/// Starts exporting spans to the collector at `url`, naming this process
+/// `instance`. Must be called from within a tokio runtime, and only once.
+pub fn start_exporter(url: &str, instance: &str) -> Result<()> {
+    webhook::check_url(url)?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    if EXPORTER.set(tx).is_err() {
+        bail!(Config, "spans are already being exported");
+    }
+    tokio::spawn(export(url.to_string(), instance.to_string(), rx));
+    Ok(())
+}
+
+async fn export(url: String, instance: String, mut spans: UnboundedReceiver<Finished>) {
+    while let Some(first) = spans.recv().await {
+        let mut batch = vec![first];
+        let deadline = tokio::time::sleep(EXPORT_INTERVAL);
+        tokio::pin!(deadline);
+        while batch.len() < MAX_BATCH {
+            tokio::select! {
+                Some(span) = spans.recv() => batch.push(span),
+                _ = &mut deadline => break,
+            }
+        }
+        if let Err(e) = webhook::post(&url, &encode(&instance, &batch)).await {
+            eprintln!(
+                "Dropping {} spans the collector didn't take: {:?}",
+                batch.len(),
+                e
+            );
+        }
+    }
+}
+
+/// An OTLP `ExportTraceServiceRequest` holding `spans`.
+fn encode(instance: &str, spans: &[Finished]) -> String {
+    let mut json = String::new();
+    let _ = write!(
+        json,
+        "{{\"resourceSpans\":[{{\"resource\":{{\"attributes\":[{},{}]}},\"scopeSpans\":[{{\"scope\":{{\"name\":\"dist-kv\"}},\"spans\":[",
+        attribute("service.name", "dist-kv"),
+        attribute("service.instance.id", instance)
+    );
+    for (i, span) in spans.iter().enumerate() {
+        if i > 0 {
+            json.push(',');
+        }
+        let parent = span
+            .parent
+            .map_or(String::new(), |id| format!("{:016x}", id));
+        let attributes: Vec<_> = span
+            .attributes
+            .iter()
+            .map(|(key, value)| attribute(key, value))
+            .collect();
+        // Spans that start a trace are the server side of a request.
+        let kind = if span.parent.is_none() { 2 } else { 1 };
+        let _ = write!(
+            json,
+            "{{\"traceId\":\"{:032x}\",\"spanId\":\"{:016x}\",\"parentSpanId\":\"{}\",\"name\":{},\"kind\":{},\"startTimeUnixNano\":\"{}\",\"endTimeUnixNano\":\"{}\",\"attributes\":[{}]}}",
+            span.context.trace_id,
+            span.context.span_id,
+            parent,
+            json_string(span.name.as_bytes()),
+            kind,
+            span.start,
+            span.end,
+            attributes.join(",")
+        );
+    }
+    json.push_str("]}]}]}");
+    json
+}
+
+fn attribute(key: &str, value: &str) -> String {
+    format!(
+        "{{\"key\":{},\"value\":{{\"stringValue\":{}}}}}",
+        json_string(key.as_bytes()),
+        json_string(value.as_bytes())
+    )
+}
+
+fn random() -> u64 {
+    RandomState::new().build_hasher().finish()
+}
+
+fn unix_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
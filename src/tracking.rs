@@ -0,0 +1,206 @@
+//! Which keys connections are interested in, so that they can be told when
+//! the keys change.
+//!
+//! A connection turns tracking on with `CLIENT TRACKING ON`. From then on
+//! the leader remembers each key it reads, and the first write to one of
+//! them, expiry included, sends the connection a push of `invalidate`, the
+//! namespace and the key. The key is then forgotten until the connection
+//! reads it again. The connection that wrote the key is told too.
+//!
+//! `WAITCHANGE` instead blocks the connection until the next write to one
+//! key, see [`Waiters`], and `BLPOP` until there is an element to pop from a
+//! list, see [`Poppers`].
+
+use std::collections::{HashMap, HashSet, VecDeque};
+
+use bytes::Bytes;
+use tokio::sync::mpsc::UnboundedSender;
+use tokio::sync::oneshot;
+
+use crate::protocol::{parse_all, Limits};
+use crate::pubsub::{key_events, push};
+use crate::store::{Db, Val};
+
+/// A key in a namespace.
+type NamespacedKey = (Bytes, Bytes);
+
+/// The keys `records` change, each with its namespace.
+pub fn changed_keys(records: &[u8]) -> Vec<NamespacedKey> {
+    let commands = parse_all(records, &Limits::NONE).unwrap_or_default();
+    let mut keys = Vec::new();
+    for command in &commands {
+        for event in key_events(command) {
+            keys.push((
+                Bytes::copy_from_slice(event.namespace),
+                Bytes::copy_from_slice(event.key),
+            ));
+        }
+    }
+    keys
+}
+
+#[derive(Default)]
+pub struct Tracking {
+    /// Where each tracking connection's pushes go, by client id.
+    connections: HashMap<u64, UnboundedSender<Bytes>>,
+    /// The connections that have read each key since it last changed, by
+    /// namespace and key.
+    readers: HashMap<NamespacedKey, HashSet<u64>>,
+}
+
+impl Tracking {
+    pub fn enable(&mut self, id: u64, tx: UnboundedSender<Bytes>) {
+        self.connections.insert(id, tx);
+    }
+
+    /// Stops tracking for connection `id`, as when it closes.
+    pub fn disable(&mut self, id: u64) {
+        if self.connections.remove(&id).is_none() {
+            return;
+        }
+        self.readers.retain(|_, readers| {
+            readers.remove(&id);
+            !readers.is_empty()
+        });
+    }
+
+    pub fn is_enabled(&self, id: u64) -> bool {
+        self.connections.contains_key(&id)
+    }
+
+    /// Notes that connection `id` read `keys` in `namespace`, if it tracks
+    /// them.
+    pub fn read(&mut self, id: u64, namespace: &[u8], keys: &[&[u8]]) {
+        if !self.is_enabled(id) {
+            return;
+        }
+        for key in keys {
+            let key = (
+                Bytes::copy_from_slice(namespace),
+                Bytes::copy_from_slice(key),
+            );
+            self.readers.entry(key).or_default().insert(id);
+        }
+    }
+
+    /// Tells the readers of each of the `changed` keys that it changed.
+    pub fn invalidate(&mut self, changed: &[NamespacedKey]) {
+        for key in changed {
+            let Some(readers) = self.readers.remove(key) else {
+                continue;
+            };
+            let push = push(&[b"invalidate", &key.0, &key.1]);
+            for id in readers {
+                if let Some(tx) = self.connections.get(&id) {
+                    let _ = tx.send(push.clone());
+                }
+            }
+        }
+    }
+
+    /// How many keys are tracked, for `INFO`.
+    pub fn len(&self) -> usize {
+        self.readers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.readers.is_empty()
+    }
+}
+
+/// What a `WAITCHANGE` is woken with: the LSN of the write that changed the
+/// key and the value it left, `None` if it deleted the key.
+pub type Change = (u64, Option<Val>);
+
+/// The connections blocked in `WAITCHANGE`, by the key they wait on.
+#[derive(Default)]
+pub struct Waiters {
+    waiting: HashMap<NamespacedKey, Vec<oneshot::Sender<Change>>>,
+}
+
+impl Waiters {
+    /// Waits for the next write to `key` in `namespace`.
+    pub fn wait(&mut self, namespace: &[u8], key: &[u8]) -> oneshot::Receiver<Change> {
+        let (tx, rx) = oneshot::channel();
+        let key = (
+            Bytes::copy_from_slice(namespace),
+            Bytes::copy_from_slice(key),
+        );
+        let waiting = self.waiting.entry(key).or_default();
+        // Connections that gave up waiting are dropped here, if not by a
+        // write.
+        waiting.retain(|tx| !tx.is_closed());
+        waiting.push(tx);
+        rx
+    }
+
+    /// Wakes whoever waits on each of the `changed` keys with the value it
+    /// has in `hashmap` after the write logged at `lsn`.
+    pub fn wake(&mut self, changed: &[NamespacedKey], hashmap: &Db, lsn: u64) {
+        for key in changed {
+            let Some(waiting) = self.waiting.remove(key) else {
+                continue;
+            };
+            let namespace = hashmap.namespace(&key.0);
+            let val = namespace.and_then(|db| db.get(&key.1[..])).cloned();
+            for tx in waiting {
+                let _ = tx.send((lsn, val.clone()));
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+}
+
+/// The connections blocked in `BLPOP`, by the list they wait on, in the
+/// order they started waiting. The leader pops for them as elements are
+/// pushed, so that each pop is logged and replicated like any other, and
+/// sends each connection the element it popped.
+#[derive(Default)]
+pub struct Poppers {
+    waiting: HashMap<NamespacedKey, VecDeque<oneshot::Sender<Val>>>,
+}
+
+impl Poppers {
+    /// Waits for an element of the list at `key` in `namespace`, behind
+    /// every connection already waiting on it.
+    pub fn wait(&mut self, namespace: &[u8], key: &[u8]) -> oneshot::Receiver<Val> {
+        let (tx, rx) = oneshot::channel();
+        let key = (
+            Bytes::copy_from_slice(namespace),
+            Bytes::copy_from_slice(key),
+        );
+        let waiting = self.waiting.entry(key).or_default();
+        waiting.retain(|tx| !tx.is_closed());
+        waiting.push_back(tx);
+        rx
+    }
+
+    /// Takes the connection that has waited longest on `key` and is still
+    /// waiting, if there is one.
+    pub fn next(&mut self, key: &NamespacedKey) -> Option<oneshot::Sender<Val>> {
+        let waiting = self.waiting.get_mut(key)?;
+        let next = loop {
+            match waiting.pop_front() {
+                Some(tx) if tx.is_closed() => continue,
+                next => break next,
+            }
+        };
+        if waiting.is_empty() {
+            self.waiting.remove(key);
+        }
+        next
+    }
+
+    /// Puts a connection taken by [`Poppers::next`] back at the front of
+    /// the queue, as when there turned out to be nothing to pop.
+    pub fn put_back(&mut self, key: &NamespacedKey, tx: oneshot::Sender<Val>) {
+        self.waiting.entry(key.clone()).or_default().push_front(tx);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.waiting.is_empty()
+    }
+}
@@ -0,0 +1,405 @@
+//! How nodes and clients reach each other.
+//!
+//! The leader, followers and client open and accept connections through a
+//! [`Transport`]: [`Tcp`] in a real deployment, or a host on a
+//! [`MemoryNetwork`], which connects tasks in the same process through
+//! in-memory pipes, for tests that run a cluster without binding real
+//! ports. The network can be split with [`MemoryNetwork::partition`], to
+//! see how a cluster behaves when its nodes can't reach each other.
+
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+
+use tokio::io::{AsyncRead, AsyncWrite, DuplexStream, ReadBuf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+
+pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+/// Opens connections to, and listens at, `host:port` addresses.
+pub trait Transport: Send + Sync {
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, io::Result<Connection>>;
+
+    fn bind<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, io::Result<Listener>>;
+}
+
+/// The operating system's TCP stack.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Tcp;
+
+impl Transport for Tcp {
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, io::Result<Connection>> {
+        Box::pin(async move {
+            let stream = TcpStream::connect(addr).await?;
+            stream.set_nodelay(true)?;
+            Ok(Connection::Tcp(stream))
+        })
+    }
+
+    fn bind<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, io::Result<Listener>> {
+        Box::pin(async move { Ok(Listener::Tcp(TcpListener::bind(addr).await?)) })
+    }
+}
+
+/// A connection made through a [`Transport`].
+#[derive(Debug)]
+pub enum Connection {
+    Tcp(TcpStream),
+    Memory(MemoryStream),
+}
+
+impl Connection {
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Connection::Tcp(stream) => stream.local_addr(),
+            Connection::Memory(stream) => Ok(stream.local),
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Connection::Tcp(stream) => stream.peer_addr(),
+            Connection::Memory(stream) => Ok(stream.peer),
+        }
+    }
+
+    /// Turns off Nagle's algorithm on a TCP connection. In-memory
+    /// connections never hold writes back.
+    pub fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        match self {
+            Connection::Tcp(stream) => stream.set_nodelay(nodelay),
+            Connection::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl From<TcpStream> for Connection {
+    fn from(stream: TcpStream) -> Connection {
+        Connection::Tcp(stream)
+    }
+}
+
+impl AsyncRead for Connection {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Connection::Memory(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Connection {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Connection::Memory(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Connection::Memory(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Connection::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Connection::Memory(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// Where a [`Transport`] takes connections.
+#[derive(Debug)]
+pub enum Listener {
+    Tcp(TcpListener),
+    Memory(MemoryListener),
+}
+
+impl Listener {
+    /// The next connection, and the address it came from.
+    pub async fn accept(&mut self) -> io::Result<(Connection, SocketAddr)> {
+        match self {
+            Listener::Tcp(listener) => {
+                let (stream, addr) = listener.accept().await?;
+                Ok((Connection::Tcp(stream), addr))
+            }
+            Listener::Memory(listener) => match listener.incoming.recv().await {
+                Some(stream) => {
+                    let addr = stream.peer;
+                    Ok((Connection::Memory(stream), addr))
+                }
+                None => Err(io::ErrorKind::NotConnected.into()),
+            },
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Listener::Tcp(listener) => listener.local_addr(),
+            Listener::Memory(listener) => Ok(listener.addr),
+        }
+    }
+}
+
+impl From<TcpListener> for Listener {
+    fn from(listener: TcpListener) -> Listener {
+        Listener::Tcp(listener)
+    }
+}
+
+/// How many bytes an in-memory connection holds in each direction before
+/// writes wait for the other end to read.
+const PIPE_SIZE: usize = 64 * 1024;
+
+/// The first port handed out to listeners bound to port 0, and to the
+/// connecting end of each connection.
+const FIRST_PORT: u16 = 40_000;
+
+/// In-process hosts that connect to each other through in-memory pipes.
+///
+/// Each node takes a [`MemoryHost`] of its own with [`MemoryNetwork::host`],
+/// whose IP address its listeners and connections have, so that a
+/// partition can tell the nodes apart. Addresses must be IP addresses, not
+/// host names, and binding port 0 picks a free one as TCP does. Dropping a
+/// listener or a connection closes it, as closing a socket would.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryNetwork {
+    inner: Arc<Mutex<Network>>,
+}
+
+#[derive(Debug, Default)]
+struct Network {
+    listeners: HashMap<SocketAddr, mpsc::UnboundedSender<MemoryStream>>,
+    next_port: u16,
+    /// Pairs of hosts that can't reach each other, the lower address first.
+    partitions: HashSet<(IpAddr, IpAddr)>,
+    links: Vec<Weak<Link>>,
+}
+
+impl Network {
+    fn port(&mut self) -> u16 {
+        self.next_port = match self.next_port {
+            u16::MAX => FIRST_PORT,
+            port => port.max(FIRST_PORT) + 1,
+        };
+        self.next_port
+    }
+}
+
+fn pair(a: IpAddr, b: IpAddr) -> (IpAddr, IpAddr) {
+    (a.min(b), a.max(b))
+}
+
+impl MemoryNetwork {
+    pub fn new() -> MemoryNetwork {
+        MemoryNetwork::default()
+    }
+
+    /// A transport for the host at `ip`.
+    pub fn host(&self, ip: IpAddr) -> MemoryHost {
+        MemoryHost {
+            network: self.clone(),
+            ip,
+        }
+    }
+
+    /// Stops hosts `a` and `b` reaching each other: the connections between
+    /// them fail as reset ones would, and new ones as unreachable, until
+    /// the network is [healed](MemoryNetwork::heal).
+    pub fn partition(&self, a: IpAddr, b: IpAddr) {
+        let mut network = self.inner.lock().unwrap();
+        let cut = pair(a, b);
+        network.partitions.insert(cut);
+        network.links.retain(|link| {
+            let Some(link) = link.upgrade() else {
+                return false;
+            };
+            if pair(link.hosts.0, link.hosts.1) == cut {
+                link.cut();
+            }
+            true
+        });
+    }
+
+    /// Lets every host reach every other again. Connections cut by a
+    /// partition stay cut.
+    pub fn heal(&self) {
+        self.inner.lock().unwrap().partitions.clear();
+    }
+}
+
+/// One host on a [`MemoryNetwork`].
+#[derive(Debug, Clone)]
+pub struct MemoryHost {
+    network: MemoryNetwork,
+    ip: IpAddr,
+}
+
+fn parse_addr(addr: &str) -> io::Result<SocketAddr> {
+    addr.parse().map_err(|_| {
+        let message = format!("{} is not an IP address and port", addr);
+        io::Error::new(io::ErrorKind::InvalidInput, message)
+    })
+}
+
+impl Transport for MemoryHost {
+    fn connect<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, io::Result<Connection>> {
+        Box::pin(async move {
+            let peer = parse_addr(addr)?;
+            let mut network = self.network.inner.lock().unwrap();
+            if network.partitions.contains(&pair(self.ip, peer.ip())) {
+                return Err(io::ErrorKind::HostUnreachable.into());
+            }
+            let Some(listener) = network.listeners.get(&peer).cloned() else {
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            };
+            let local = SocketAddr::new(self.ip, network.port());
+            let link = Arc::new(Link {
+                hosts: (self.ip, peer.ip()),
+                cut: AtomicBool::new(false),
+                wakers: Mutex::default(),
+            });
+            network.links.retain(|link| link.strong_count() > 0);
+            network.links.push(Arc::downgrade(&link));
+            let (ours, theirs) = tokio::io::duplex(PIPE_SIZE);
+            let accepted = MemoryStream {
+                pipe: theirs,
+                link: link.clone(),
+                end: 1,
+                local: peer,
+                peer: local,
+            };
+            if listener.send(accepted).is_err() {
+                network.listeners.remove(&peer);
+                return Err(io::ErrorKind::ConnectionRefused.into());
+            }
+            Ok(Connection::Memory(MemoryStream {
+                pipe: ours,
+                link,
+                end: 0,
+                local,
+                peer,
+            }))
+        })
+    }
+
+    fn bind<'a>(&'a self, addr: &'a str) -> BoxFuture<'a, io::Result<Listener>> {
+        Box::pin(async move {
+            let mut addr = parse_addr(addr)?;
+            if addr.ip().is_unspecified() {
+                addr.set_ip(self.ip);
+            }
+            let mut network = self.network.inner.lock().unwrap();
+            if addr.port() == 0 {
+                addr.set_port(network.port());
+            }
+            if network.listeners.get(&addr).is_some_and(|l| !l.is_closed()) {
+                return Err(io::ErrorKind::AddrInUse.into());
+            }
+            let (sender, incoming) = mpsc::unbounded_channel();
+            network.listeners.insert(addr, sender);
+            Ok(Listener::Memory(MemoryListener { addr, incoming }))
+        })
+    }
+}
+
+/// A listener on a [`MemoryNetwork`].
+#[derive(Debug)]
+pub struct MemoryListener {
+    addr: SocketAddr,
+    incoming: mpsc::UnboundedReceiver<MemoryStream>,
+}
+
+/// The state both ends of an in-memory connection share.
+#[derive(Debug)]
+struct Link {
+    /// The connecting host and the accepting one.
+    hosts: (IpAddr, IpAddr),
+    cut: AtomicBool,
+    /// The tasks waiting on each end to read, and to write.
+    wakers: Mutex<[Option<Waker>; 4]>,
+}
+
+impl Link {
+    fn cut(&self) {
+        self.cut.store(true, Ordering::SeqCst);
+        for waker in self.wakers.lock().unwrap().iter_mut() {
+            if let Some(waker) = waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Whether the link is cut, otherwise waking `cx` when it is.
+    fn is_cut(&self, slot: usize, cx: &Context<'_>) -> bool {
+        if self.cut.load(Ordering::SeqCst) {
+            return true;
+        }
+        self.wakers.lock().unwrap()[slot] = Some(cx.waker().clone());
+        self.cut.load(Ordering::SeqCst)
+    }
+}
+
+/// One end of a connection on a [`MemoryNetwork`].
+#[derive(Debug)]
+pub struct MemoryStream {
+    pipe: DuplexStream,
+    link: Arc<Link>,
+    /// 0 for the connecting end, 1 for the accepting one.
+    end: usize,
+    local: SocketAddr,
+    peer: SocketAddr,
+}
+
+impl AsyncRead for MemoryStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.link.is_cut(this.end * 2, cx) {
+            return Poll::Ready(Err(io::ErrorKind::ConnectionReset.into()));
+        }
+        Pin::new(&mut this.pipe).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for MemoryStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if this.link.is_cut(this.end * 2 + 1, cx) {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        Pin::new(&mut this.pipe).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().pipe).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().pipe).poll_shutdown(cx)
+    }
+}
@@ -0,0 +1,437 @@
+//! An io_uring backend for the leader's log, chosen with `wal_backend
+//! io_uring`.
+//!
+//! Appends are queued on the ring as writes, each linked to the next, and
+//! only submitted together with the `fdatasync` that follows them. A batch
+//! of records then costs one system call to write and sync, rather than one
+//! per write plus one for the sync. Linking keeps the writes in order, and
+//! the sync after them. Records are copied as they are queued, since the
+//! kernel reads them after `append` has returned, and a write that fails is
+//! reported by the `sync` that submitted it, which is also when appends
+//! become durable with plain files. A write the kernel only partly
+//! completes is finished, with those after it, by plain system calls.
+//!
+//! Only built with the `io-uring` feature. The ring is set up with the
+//! system calls directly rather than through liburing.
+
+use std::fs::File;
+use std::io::{self, Write};
+use std::ops::Deref;
+use std::os::unix::io::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::ptr;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use nix::libc;
+
+use crate::wal::Storage;
+
+/// How many operations the ring holds. Appends past this many before a sync
+/// wait for those already queued to complete first.
+const ENTRIES: u32 = 256;
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x8000000;
+const IORING_OFF_SQES: libc::off_t = 0x10000000;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OP_FSYNC: u8 = 3;
+const IORING_OP_WRITE: u8 = 23;
+const IORING_FSYNC_DATASYNC: u32 = 1;
+const IOSQE_IO_LINK: u8 = 1 << 2;
+
+/// The `user_data` of a queued sync, to tell it from the writes, which use
+/// their index.
+const SYNC: u64 = u64::MAX;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    resv2: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+/// A submission queue entry, laid out as the kernel's `io_uring_sqe`.
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+/// A completion queue entry, laid out as the kernel's `io_uring_cqe`.
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+/// A region of the ring mapped into memory, unmapped on drop.
+struct Mapping {
+    ptr: *mut u8,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize, offset: libc::off_t) -> io::Result<Mapping> {
+        // SAFETY: a fresh shared mapping of the ring, which the kernel sized.
+        let ptr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mapping {
+            ptr: ptr.cast(),
+            len,
+        })
+    }
+
+    /// A pointer to the field `offset` bytes in.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        // SAFETY: the offsets are the kernel's, within the mapping.
+        unsafe { self.ptr.add(offset as usize).cast() }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        // SAFETY: mapped by `Mapping::new` and not used past here.
+        unsafe { libc::munmap(self.ptr.cast(), self.len) };
+    }
+}
+
+/// An io_uring, submitted to and reaped from one operation at a time by its
+/// owner.
+struct Ring {
+    sq: Mapping,
+    cq: Mapping,
+    sqes: Mapping,
+    /// Closed once the mappings above have been dropped.
+    fd: OwnedFd,
+    sq_mask: u32,
+    sq_entries: u32,
+    cq_mask: u32,
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+    /// Queued but not yet submitted to the kernel.
+    unsubmitted: u32,
+}
+
+// SAFETY: the ring is only touched through `&mut self`, and the kernel's side
+// of it doesn't care which thread submits.
+unsafe impl Send for Ring {}
+
+impl Ring {
+    fn new(entries: u32) -> io::Result<Ring> {
+        let mut params = Params::default();
+        // SAFETY: `params` is a valid `io_uring_params` for the kernel to fill.
+        let fd = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &mut params) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // SAFETY: a new descriptor that nothing else owns.
+        let fd = unsafe { OwnedFd::from_raw_fd(fd as RawFd) };
+        let raw = fd.as_raw_fd();
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len =
+            params.cq_off.cqes as usize + params.cq_entries as usize * std::mem::size_of::<Cqe>();
+        let sqes_len = params.sq_entries as usize * std::mem::size_of::<Sqe>();
+        let sq = Mapping::new(raw, sq_len, IORING_OFF_SQ_RING)?;
+        let cq = Mapping::new(raw, cq_len, IORING_OFF_CQ_RING)?;
+        let sqes = Mapping::new(raw, sqes_len, IORING_OFF_SQES)?;
+        // SAFETY: the masks are plain `u32`s the kernel has set.
+        let (sq_mask, cq_mask) = unsafe {
+            (
+                *sq.at::<u32>(params.sq_off.ring_mask),
+                *cq.at::<u32>(params.cq_off.ring_mask),
+            )
+        };
+        Ok(Ring {
+            sq,
+            cq,
+            sqes,
+            fd,
+            sq_mask,
+            sq_entries: params.sq_entries,
+            cq_mask,
+            sq_off: params.sq_off,
+            cq_off: params.cq_off,
+            unsubmitted: 0,
+        })
+    }
+
+    fn sq_head(&self) -> &AtomicU32 {
+        // SAFETY: the kernel updates it, so it's only read atomically.
+        unsafe { &*self.sq.at::<AtomicU32>(self.sq_off.head) }
+    }
+
+    fn sq_tail(&self) -> &AtomicU32 {
+        // SAFETY: as for `sq_head`, though only this side writes it.
+        unsafe { &*self.sq.at::<AtomicU32>(self.sq_off.tail) }
+    }
+
+    fn cq_head(&self) -> &AtomicU32 {
+        // SAFETY: as for `sq_head`.
+        unsafe { &*self.cq.at::<AtomicU32>(self.cq_off.head) }
+    }
+
+    fn cq_tail(&self) -> &AtomicU32 {
+        // SAFETY: as for `sq_head`.
+        unsafe { &*self.cq.at::<AtomicU32>(self.cq_off.tail) }
+    }
+
+    /// Whether another entry can be queued without submitting.
+    fn has_room(&self) -> bool {
+        let tail = self.sq_tail().load(Ordering::Relaxed);
+        let head = self.sq_head().load(Ordering::Acquire);
+        tail.wrapping_sub(head) < self.sq_entries
+    }
+
+    /// Queues `sqe` for the next [`Ring::submit`]. The ring must have room.
+    fn push(&mut self, sqe: Sqe) {
+        debug_assert!(self.has_room());
+        let tail = self.sq_tail().load(Ordering::Relaxed);
+        let index = tail & self.sq_mask;
+        // SAFETY: `index` is within both arrays, and the kernel doesn't read
+        // the slot until the tail is moved past it.
+        unsafe {
+            self.sqes.at::<Sqe>(0).add(index as usize).write(sqe);
+            *self.sq.at::<u32>(self.sq_off.array).add(index as usize) = index;
+        }
+        self.sq_tail()
+            .store(tail.wrapping_add(1), Ordering::Release);
+        self.unsubmitted += 1;
+    }
+
+    /// Submits what has been queued and waits for `wait` completions.
+    fn submit(&mut self, wait: u32) -> io::Result<()> {
+        let mut wait = wait;
+        loop {
+            // SAFETY: no signal mask is passed.
+            let submitted = unsafe {
+                libc::syscall(
+                    libc::SYS_io_uring_enter,
+                    self.fd.as_raw_fd(),
+                    self.unsubmitted,
+                    wait,
+                    IORING_ENTER_GETEVENTS,
+                    ptr::null::<libc::sigset_t>(),
+                    0usize,
+                )
+            };
+            if submitted < 0 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            self.unsubmitted -= submitted as u32;
+            if self.unsubmitted == 0 {
+                return Ok(());
+            }
+            // Whatever completed already counts towards what's waited for.
+            wait = 0;
+        }
+    }
+
+    /// Takes the next completion, if there is one.
+    fn pop(&mut self) -> Option<Cqe> {
+        let head = self.cq_head().load(Ordering::Relaxed);
+        if head == self.cq_tail().load(Ordering::Acquire) {
+            return None;
+        }
+        let index = head & self.cq_mask;
+        // SAFETY: the kernel has written every entry up to the tail.
+        let cqe = unsafe { *self.cq.at::<Cqe>(self.cq_off.cqes).add(index as usize) };
+        self.cq_head()
+            .store(head.wrapping_add(1), Ordering::Release);
+        Some(cqe)
+    }
+}
+
+/// A log file appended to and synced through an io_uring.
+pub struct UringFile {
+    file: File,
+    ring: Ring,
+    /// The records queued since the last sync, in order, kept until the
+    /// kernel has written them.
+    queued: Vec<Box<[u8]>>,
+}
+
+impl UringFile {
+    /// Sets up a ring for appending to `file`, which fails on kernels
+    /// without io_uring or where it has been turned off. Writes need Linux
+    /// 5.6 or later.
+    pub fn new(file: File) -> io::Result<UringFile> {
+        Ok(UringFile {
+            file,
+            ring: Ring::new(ENTRIES)?,
+            queued: Vec::new(),
+        })
+    }
+
+    /// Submits what has been queued, ending with a sync if `sync` is set,
+    /// and waits for it all to complete. Writes the kernel didn't finish are
+    /// finished here.
+    fn flush(&mut self, sync: bool) -> io::Result<()> {
+        if self.queued.is_empty() && !sync {
+            return Ok(());
+        }
+        if sync {
+            if !self.ring.has_room() {
+                self.flush(false)?;
+            }
+            self.ring.push(Sqe {
+                opcode: IORING_OP_FSYNC,
+                fd: self.file.as_raw_fd(),
+                op_flags: IORING_FSYNC_DATASYNC,
+                user_data: SYNC,
+                ..Sqe::default()
+            });
+        }
+        let expected = self.queued.len() as u32 + sync as u32;
+        self.ring.submit(expected)?;
+        // How much of each write made it, and whether the sync did.
+        let mut written = vec![None; self.queued.len()];
+        let mut synced = !sync;
+        let mut error = None;
+        for _ in 0..expected {
+            let cqe = loop {
+                match self.ring.pop() {
+                    Some(cqe) => break cqe,
+                    None => self.ring.submit(1)?,
+                }
+            };
+            // Linked operations after one that fails are cancelled.
+            if cqe.res < 0 && cqe.res != -libc::ECANCELED {
+                error.get_or_insert(io::Error::from_raw_os_error(-cqe.res));
+            }
+            match cqe.user_data {
+                SYNC => synced = cqe.res >= 0,
+                index => written[index as usize] = Some(cqe.res.max(0) as usize),
+            }
+        }
+        let queued = std::mem::take(&mut self.queued);
+        if let Some(e) = error {
+            return Err(e);
+        }
+        let mut finished = false;
+        for (record, written) in queued.iter().zip(written) {
+            let written = written.unwrap_or(0);
+            if written < record.len() {
+                self.file.write_all(&record[written..])?;
+                finished = true;
+            }
+        }
+        if sync && (finished || !synced) {
+            self.file.sync_data()?;
+        }
+        Ok(())
+    }
+}
+
+impl Deref for UringFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        &self.file
+    }
+}
+
+impl Storage for UringFile {
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        if !self.ring.has_room() {
+            self.flush(false)?;
+        }
+        let record: Box<[u8]> = record.into();
+        self.ring.push(Sqe {
+            opcode: IORING_OP_WRITE,
+            flags: IOSQE_IO_LINK,
+            fd: self.file.as_raw_fd(),
+            // The file is opened for appending, so this is ignored.
+            off: 0,
+            addr: record.as_ptr() as u64,
+            len: record.len() as u32,
+            user_data: self.queued.len() as u64,
+            ..Sqe::default()
+        });
+        self.queued.push(record);
+        Ok(())
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        self.flush(true)
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.flush(false)?;
+        self.file.set_len(0)?;
+        self.file.sync_all()
+    }
+}
+
+impl Drop for UringFile {
+    /// Waits for queued writes, which read from buffers freed here.
+    fn drop(&mut self) {
+        if let Err(e) = self.flush(false) {
+            eprintln!("Failed to write the queued log records: {}", e);
+        }
+    }
+}
@@ -0,0 +1,529 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::mem;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::thread;
+
+use bytes::{Buf, Bytes, BytesMut};
+use nix::errno::Errno;
+use nix::fcntl::{fallocate, FallocateFlags};
+
+use crate::checkpoint;
+use crate::error::{Context, DistKvError, Result};
+use crate::failpoint::{self, Action};
+use crate::manifest::{manifest_path, replace_file, resolve, Manifest};
+use crate::protocol::{split_frame, Command, Frame, Limits, ParseError};
+use crate::store::{in_namespace, records, Db, ReplayReport, Strictness};
+
+/// Where a node's log is written. Appends only become durable once `sync`
+/// has returned successfully.
+pub trait Storage {
+    fn append(&mut self, record: &[u8]) -> io::Result<()>;
+    fn sync(&mut self) -> io::Result<()>;
+    /// Discards the whole log, for when a snapshot replaces it.
+    fn reset(&mut self) -> io::Result<()>;
+}
+
+impl Storage for File {
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        match failpoint::eval("wal::append") {
+            Some(Action::Error) => return Err(io::Error::other("failpoint wal::append")),
+            Some(Action::ShortWrite(n)) => {
+                self.write_all(&record[..n.min(record.len())])?;
+                return Err(io::Error::other("failpoint wal::append short write"));
+            }
+            Some(Action::Crash) => {
+                self.write_all(&record[..record.len() / 2])?;
+                failpoint::crash();
+            }
+            _ => {}
+        }
+        self.write_all(record)
+    }
+
+    /// Syncs as `fdatasync` does: the data, and the file's length whenever
+    /// it has grown, which every append does, but not its timestamps.
+    fn sync(&mut self) -> io::Result<()> {
+        match failpoint::eval("wal::sync") {
+            Some(Action::Error) => return Err(io::Error::other("failpoint wal::sync")),
+            Some(Action::Crash) => failpoint::crash(),
+            _ => {}
+        }
+        self.sync_data()
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        self.set_len(0)?;
+        self.sync_all()
+    }
+}
+
+/// How the leader appends to and syncs its log, from `wal_backend`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum WalBackend {
+    /// With a system call for each write and sync.
+    #[default]
+    File,
+    /// Through an io_uring, see [`crate::uring`]. Needs the `io-uring`
+    /// feature.
+    IoUring,
+}
+
+/// The leader's log, written through the [`WalBackend`] it was opened with.
+/// Namespaces' logs are always plain files.
+pub enum LogFile {
+    File(File),
+    #[cfg(feature = "io-uring")]
+    IoUring(crate::uring::UringFile),
+}
+
+impl LogFile {
+    pub fn open(file: File, backend: WalBackend) -> Result<LogFile> {
+        match backend {
+            WalBackend::File => Ok(LogFile::File(file)),
+            #[cfg(feature = "io-uring")]
+            WalBackend::IoUring => Ok(LogFile::IoUring(
+                crate::uring::UringFile::new(file).context("setting up an io_uring")?,
+            )),
+            #[cfg(not(feature = "io-uring"))]
+            WalBackend::IoUring => Err(DistKvError::Config(
+                "built without the io-uring feature".to_string(),
+            )),
+        }
+    }
+}
+
+impl std::ops::Deref for LogFile {
+    type Target = File;
+
+    fn deref(&self) -> &File {
+        match self {
+            LogFile::File(file) => file,
+            #[cfg(feature = "io-uring")]
+            LogFile::IoUring(file) => file,
+        }
+    }
+}
+
+impl Storage for LogFile {
+    fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        match self {
+            LogFile::File(file) => file.append(record),
+            #[cfg(feature = "io-uring")]
+            LogFile::IoUring(file) => file.append(record),
+        }
+    }
+
+    fn sync(&mut self) -> io::Result<()> {
+        match self {
+            LogFile::File(file) => file.sync(),
+            #[cfg(feature = "io-uring")]
+            LogFile::IoUring(file) => file.sync(),
+        }
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        match self {
+            LogFile::File(file) => file.reset(),
+            #[cfg(feature = "io-uring")]
+            LogFile::IoUring(file) => file.reset(),
+        }
+    }
+}
+
+pub fn create_log_file(path: impl AsRef<Path>) -> Result<File> {
+    Ok(OpenOptions::new().append(true).create(true).open(path)?)
+}
+
+/// Reserves the disk space for the first `size` bytes of a log file, so
+/// that appends within them don't have to allocate blocks as they go. Only
+/// the allocation is saved: the file's length stays that of what has been
+/// appended, so each append still grows it and each sync still writes it
+/// out. Filesystems that can't preallocate are left to allocate as before.
+pub fn preallocate(file: &File, size: u64) -> Result<()> {
+    if size == 0 {
+        return Ok(());
+    }
+    let flags = FallocateFlags::FALLOC_FL_KEEP_SIZE;
+    match fallocate(file.as_raw_fd(), flags, 0, size as i64) {
+        Ok(()) | Err(Errno::EOPNOTSUPP) => Ok(()),
+        Err(e) => Err(e).context("preallocating a log segment"),
+    }
+}
+
+/// Reads a whole file, or returns `None` if it doesn't exist yet.
+pub fn read_log(path: impl AsRef<Path>) -> Result<Option<Vec<u8>>> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    let mut contents = Vec::new();
+    file.read_to_end(&mut contents)?;
+    Ok(Some(contents))
+}
+
+/// Reads the log at `path` whole: the segments its manifest names, one after
+/// the other, or `None` if it doesn't exist yet. See [`crate::manifest`].
+pub fn read_segments(path: &str) -> Result<Option<Vec<u8>>> {
+    let manifest = Manifest::load(path)?;
+    let mut log: Option<Vec<u8>> = None;
+    for segment in &manifest.segments {
+        match read_log(resolve(path, segment))? {
+            Some(contents) => log.get_or_insert_with(Vec::new).extend(contents),
+            // Without a manifest, the log not existing is a fresh start.
+            None if manifest.generation == 0 => {}
+            None => {
+                return Err(DistKvError::storage(format!(
+                    "{} names log segment {}, which is missing",
+                    manifest_path(path),
+                    segment
+                )))
+            }
+        }
+    }
+    Ok(log)
+}
+
+/// Opens the last segment of the log at `path`, the one writes are appended
+/// to, creating it if it doesn't exist yet.
+pub fn open_last_segment(path: &str) -> Result<File> {
+    let manifest = Manifest::load(path)?;
+    create_log_file(resolve(path, manifest.segments.last().unwrap()))
+}
+
+/// Replays the log at `path` and opens it for appending. A torn record at
+/// the end, left by a crash in the middle of an append, is cut off first so
+/// that new records don't get glued onto it.
+pub fn open_log(path: &str) -> Result<(Db, File)> {
+    let (hashmap, file, _) = open_log_with(path, Strictness::Strict)?;
+    Ok((hashmap, file))
+}
+
+/// Like [`open_log`], handling corrupt records according to `strictness` and
+/// returning what replay found. Replay starts from the log's checkpoint, if
+/// it has one, see [`crate::checkpoint`], and the file opened is the last
+/// of its segments.
+pub fn open_log_with(path: &str, strictness: Strictness) -> Result<(Db, File, ReplayReport)> {
+    let Some(log) = read_segments(path)? else {
+        return Ok((
+            Db::default(),
+            open_last_segment(path)?,
+            ReplayReport::default(),
+        ));
+    };
+    let (hashmap, report) = checkpoint::replay(path, &log, strictness, |_| {})?;
+    let file = open_last_segment(path)?;
+    if report.complete < log.len() {
+        let start = log.len() - file.metadata()?.len() as usize;
+        file.set_len(report.complete.saturating_sub(start) as u64)?;
+        file.sync_all()?;
+    }
+    Ok((hashmap, file, report))
+}
+
+/// How a namespace keeps its writes, when not in the node's own log with
+/// every other namespace's.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Not logged at all, so the namespace starts empty after a restart. Its
+    /// writes are still replicated.
+    Memory,
+    /// Logged to a file of its own next to the node's log.
+    Separate,
+}
+
+/// The logs of namespaces that don't share the node's: `None` for one kept
+/// only in memory. Records of every other namespace go to the node's log.
+pub struct NamespaceLogs<S> {
+    logs: HashMap<Bytes, Option<S>>,
+}
+
+impl<S> Default for NamespaceLogs<S> {
+    fn default() -> Self {
+        NamespaceLogs {
+            logs: HashMap::new(),
+        }
+    }
+}
+
+impl<S: Storage> NamespaceLogs<S> {
+    /// The same logs, each converted by `f`.
+    pub fn map<T>(self, mut f: impl FnMut(S) -> T) -> NamespaceLogs<T> {
+        NamespaceLogs {
+            logs: self
+                .logs
+                .into_iter()
+                .map(|(name, log)| (name, log.map(&mut f)))
+                .collect(),
+        }
+    }
+
+    pub fn insert(&mut self, name: &[u8], log: Option<S>) {
+        self.logs.insert(Bytes::copy_from_slice(name), log);
+    }
+
+    /// Appends `records` to the logs they belong in, `wal` being the node's.
+    /// Consecutive records for the same log are appended together, so a
+    /// write that spans several records is torn no more often than before.
+    pub fn append(&mut self, wal: &mut S, records: &[u8]) -> io::Result<()> {
+        if self.logs.is_empty() {
+            return wal.append(records);
+        }
+        // Where each run of records bound for the same log starts.
+        let mut runs: Vec<(Option<Bytes>, usize)> = Vec::new();
+        for entry in LogReader::new(records) {
+            let (offset, name) = match entry {
+                Entry::Record {
+                    offset,
+                    command: Ok(Command::In(name, command)),
+                    ..
+                } => {
+                    // A memory-only namespace's request IDs go to the
+                    // node's log all the same, so the LSN outlives them.
+                    let memory = matches!(self.logs.get(&name[..]), Some(None));
+                    let marker = matches!(*command, Command::RequestId(_));
+                    let own = self.logs.contains_key(&name[..]) && !(memory && marker);
+                    (offset, own.then(|| Bytes::copy_from_slice(&name)))
+                }
+                Entry::Record { offset, .. }
+                | Entry::Torn { offset, .. }
+                | Entry::Unframeable { offset, .. } => (offset, None),
+            };
+            if runs.last().is_none_or(|(last, _)| *last != name) {
+                runs.push((name, offset));
+            }
+        }
+        let ends = runs.iter().skip(1).map(|(_, start)| *start);
+        for ((name, start), end) in runs.iter().zip(ends.chain([records.len()])) {
+            let log = match name {
+                Some(name) => self.logs.get_mut(name).and_then(Option::as_mut),
+                None => Some(&mut *wal),
+            };
+            if let Some(log) = log {
+                log.append(&records[*start..end])?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn sync(&mut self, wal: &mut S) -> io::Result<()> {
+        wal.sync()?;
+        self.logs.values_mut().flatten().try_for_each(S::sync)
+    }
+
+    pub fn reset(&mut self, wal: &mut S) -> io::Result<()> {
+        wal.reset()?;
+        self.logs.values_mut().flatten().try_for_each(S::reset)
+    }
+}
+
+impl NamespaceLogs<File> {
+    /// Replaces each separate log of a namespace, for a node whose own log
+    /// is at `path`, with the records of that namespace in `hashmap`. Each
+    /// file is replaced whole, see [`replace_file`].
+    pub fn replace(&mut self, path: &str, hashmap: &Db) -> Result<()> {
+        for (name, log) in &mut self.logs {
+            let Some(log) = log else {
+                continue;
+            };
+            let records = match hashmap.namespace(name) {
+                Some(namespace) => in_namespace(name, &records(namespace)),
+                None => BytesMut::new(),
+            };
+            let path = namespace_log_path(path, &String::from_utf8_lossy(name));
+            replace_file(&path, &records)?;
+            *log = create_log_file(&path)?;
+        }
+        Ok(())
+    }
+}
+
+/// Where namespace `name` is logged when it has a log of its own.
+pub fn namespace_log_path(path: &str, name: &str) -> String {
+    format!("{}.{}", path, name)
+}
+
+/// Opens the logs of the namespaces in `namespaces` for a node whose own log
+/// is at `path`, replaying each separately logged namespace into `hashmap`
+/// in place of whatever the node's log held for it. Memory-only namespaces
+/// start out empty. Also returns the highest LSN the namespaces' logs
+/// replayed, see [`ReplayReport::lsn`].
+pub fn open_namespace_logs(
+    path: &str,
+    namespaces: &BTreeMap<String, Durability>,
+    strictness: Strictness,
+    hashmap: &mut Db,
+) -> Result<(NamespaceLogs<File>, u64)> {
+    let mut logs = NamespaceLogs::default();
+    let mut lsn = 0;
+    for (name, durability) in namespaces {
+        let name = name.as_str();
+        let (namespace, log) = match durability {
+            Durability::Memory => (Db::new(), None),
+            Durability::Separate => {
+                let path = namespace_log_path(path, name);
+                let (replayed, file, report) = open_log_with(&path, strictness)
+                    .with_context(|| format!("opening the log of namespace {}", name))?;
+                lsn = lsn.max(report.lsn);
+                let namespace = replayed.namespace(name.as_bytes()).cloned();
+                (namespace.unwrap_or_default(), Some(file))
+            }
+        };
+        *hashmap.namespace_mut(name.as_bytes()) = namespace;
+        hashmap.drop_if_empty(name.as_bytes());
+        logs.insert(name.as_bytes(), log);
+    }
+    Ok((logs, lsn))
+}
+
+/// An entry of a log, as read by [`LogReader`].
+#[derive(Debug)]
+pub enum Entry {
+    /// A complete record and the command it holds, or why it couldn't be
+    /// parsed.
+    Record {
+        offset: usize,
+        len: usize,
+        command: Result<Command<'static>, ParseError>,
+    },
+    /// The log ends partway through a record.
+    Torn { offset: usize, len: usize },
+    /// A record whose framing is broken. Reading resumes at the next line.
+    Unframeable { offset: usize, err: ParseError },
+}
+
+/// How many records [`LogReader::parallel`] frames ahead to parse at once.
+const PARSE_BATCH: usize = 16 * 1024;
+
+/// Walks the records of a log with their offsets, for tools and recovery
+/// code that need to say where a problem is rather than just that there is
+/// one.
+///
+/// Framing a record needs the end of the one before it, so records are
+/// always framed in order, but a reader made with [`LogReader::parallel`]
+/// parses them a batch at a time across several threads. Either way the
+/// entries come out in log order.
+pub struct LogReader {
+    buf: BytesMut,
+    offset: usize,
+    threads: usize,
+    /// Entries parsed ahead of the ones asked for so far.
+    parsed: VecDeque<Entry>,
+}
+
+/// A record framed but not yet parsed, or an entry that needs no parsing.
+enum Framed {
+    Record {
+        offset: usize,
+        len: usize,
+        frame: Frame,
+    },
+    Done(Entry),
+}
+
+impl Framed {
+    fn parse(self) -> Entry {
+        match self {
+            Framed::Record { offset, len, frame } => Entry::Record {
+                offset,
+                len,
+                command: frame
+                    .command(&Limits::NONE)
+                    .map(|command| command.into_owned()),
+            },
+            Framed::Done(entry) => entry,
+        }
+    }
+}
+
+impl LogReader {
+    pub fn new(log: &[u8]) -> Self {
+        Self::parallel(log, 1)
+    }
+
+    /// A reader that parses records on up to `threads` threads.
+    pub fn parallel(log: &[u8], threads: usize) -> Self {
+        LogReader {
+            buf: BytesMut::from(log),
+            offset: 0,
+            threads: threads.max(1),
+            parsed: VecDeque::new(),
+        }
+    }
+
+    fn frame(&mut self) -> Option<Framed> {
+        let offset = self.offset;
+        let before = self.buf.len();
+        match split_frame(&mut self.buf, &Limits::NONE) {
+            Ok(Some(frame)) => {
+                let len = before - self.buf.len();
+                self.offset += len;
+                Some(Framed::Record { offset, len, frame })
+            }
+            Ok(None) if self.buf.is_empty() => None,
+            Ok(None) => {
+                let len = self.buf.len();
+                self.buf.clear();
+                self.offset += len;
+                Some(Framed::Done(Entry::Torn { offset, len }))
+            }
+            Err(err) => {
+                // split_frame only fails once it has seen a whole line.
+                let line = self.buf.iter().position(|b| *b == b'\n').unwrap() + 1;
+                self.buf.advance(line);
+                self.offset += line;
+                Some(Framed::Done(Entry::Unframeable { offset, err }))
+            }
+        }
+    }
+
+    /// Frames the next batch of records and parses them, split evenly
+    /// between the threads.
+    fn parse_batch(&mut self) {
+        let mut batch: Vec<Framed> = std::iter::from_fn(|| self.frame())
+            .take(PARSE_BATCH)
+            .collect();
+        let per_thread = batch.len().div_ceil(self.threads);
+        if per_thread == 0 {
+            return;
+        }
+        let mut chunks = Vec::new();
+        while !batch.is_empty() {
+            let rest = batch.split_off(per_thread.min(batch.len()));
+            chunks.push(mem::replace(&mut batch, rest));
+        }
+        thread::scope(|scope| {
+            let parsing: Vec<_> = chunks
+                .into_iter()
+                .map(|chunk| {
+                    scope.spawn(|| chunk.into_iter().map(Framed::parse).collect::<Vec<_>>())
+                })
+                .collect();
+            for parsed in parsing {
+                self.parsed.extend(parsed.join().unwrap());
+            }
+        });
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = Entry;
+
+    fn next(&mut self) -> Option<Entry> {
+        if self.threads == 1 {
+            return self.frame().map(Framed::parse);
+        }
+        if self.parsed.is_empty() {
+            self.parse_batch();
+        }
+        self.parsed.pop_front()
+    }
+}
+
+/// How many threads replay parses a log on: one per core.
+pub fn replay_threads() -> usize {
+    thread::available_parallelism().map_or(1, |n| n.get())
+}
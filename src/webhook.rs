@@ -0,0 +1,180 @@
+//! Webhooks: triggers that POST a JSON payload to an HTTP endpoint whenever
+//! a write changes a key matching a glob pattern.
+//!
+//! The payload is an object with the `event` (the lower-cased name of the
+//! command that made the change, such as `set` or `del`), the `namespace`,
+//! the `key` and `time`, the Unix time in milliseconds the write was made.
+//! Each trigger has a dispatcher task of its own that posts its events in
+//! order, retrying an endpoint that fails or answers with anything but a
+//! 2xx status with exponential backoff, so a slow endpoint never holds up a
+//! write or another trigger. Only plain `http://` URLs are supported.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::Bytes;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+
+use crate::error::{bail, DistKvError, Result};
+use crate::protocol::{Command, ErrorCode, ErrorReply};
+use crate::pubsub::{glob_match, key_events};
+
+/// How many times an event is posted before it is given up on.
+pub const MAX_ATTEMPTS: u32 = 8;
+
+/// The wait before the first retry, doubled for each one after it.
+pub const INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+
+const MAX_BACKOFF: Duration = Duration::from_secs(10);
+
+/// How long an endpoint gets to accept a connection and answer.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Keys matching `pattern` are posted to `url`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trigger {
+    pub pattern: Bytes,
+    pub url: String,
+}
+
+/// The running triggers, each with the queue of its dispatcher task.
+#[derive(Default)]
+pub struct Webhooks {
+    triggers: Vec<(Trigger, UnboundedSender<String>)>,
+}
+
+impl Webhooks {
+    /// Starts a dispatcher for each trigger. Must be called from within a
+    /// tokio runtime.
+    pub fn start(triggers: Vec<Trigger>) -> Self {
+        let triggers = triggers
+            .into_iter()
+            .map(|trigger| {
+                let (tx, rx) = mpsc::unbounded_channel();
+                tokio::spawn(dispatch(trigger.url.clone(), rx));
+                (trigger, tx)
+            })
+            .collect();
+        Webhooks { triggers }
+    }
+
+    /// Queues a payload for every trigger matching a key the write changed.
+    pub fn notify(&self, command: &Command<'_>) {
+        if self.triggers.is_empty() {
+            return;
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        for event in key_events(command) {
+            let payload = format!(
+                "{{\"event\":{},\"namespace\":{},\"key\":{},\"time\":{}}}",
+                json_string(event.event.as_bytes()),
+                json_string(event.namespace),
+                json_string(event.key),
+                time
+            );
+            for (trigger, tx) in &self.triggers {
+                if glob_match(&trigger.pattern, event.key) {
+                    let _ = tx.send(payload.clone());
+                }
+            }
+        }
+    }
+}
+
+/// Posts each payload to `url` in turn, retrying failures with backoff.
+async fn dispatch(url: String, mut payloads: UnboundedReceiver<String>) {
+    while let Some(payload) = payloads.recv().await {
+        let mut backoff = INITIAL_BACKOFF;
+        for attempt in 1..=MAX_ATTEMPTS {
+            match post(&url, &payload).await {
+                Ok(()) => break,
+                Err(e) if attempt == MAX_ATTEMPTS => {
+                    eprintln!(
+                        "Giving up on webhook {} after {} attempts: {:?}",
+                        url, attempt, e
+                    );
+                }
+                Err(_) => {
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+/// Splits an `http://host[:port][/path]` URL into the address to connect to,
+/// the `Host` header and the path.
+pub fn parse_url(url: &str) -> Result<(String, &str, &str)> {
+    let Some(rest) = url.strip_prefix("http://") else {
+        bail!(Protocol, "only http:// URLs are supported, not {}", url);
+    };
+    let (host, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    if host.is_empty() {
+        bail!(Protocol, "URL {} has no host", url);
+    }
+    let addr = match host.contains(':') {
+        true => host.to_string(),
+        false => format!("{}:80", host),
+    };
+    Ok((addr, host, path))
+}
+
+/// Checks that `url` is one webhooks can post to.
+pub fn check_url(url: &str) -> Result<()> {
+    parse_url(url).map(|_| ())
+}
+
+/// Posts a JSON `payload` to `url`, failing unless it is answered with a
+/// 2xx status.
+pub async fn post(url: &str, payload: &str) -> Result<()> {
+    let (addr, host, path) = parse_url(url)?;
+    let request = format!(
+        "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        host,
+        payload.len(),
+        payload
+    );
+    let exchange = async {
+        let mut stream = TcpStream::connect(&addr).await?;
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).await?;
+        Ok::<_, DistKvError>(response)
+    };
+    let response = tokio::time::timeout(REQUEST_TIMEOUT, exchange)
+        .await
+        .map_err(|_| ErrorReply::new(ErrorCode::Timeout, "webhook timed out"))??;
+    let status_line = response.split(|b| *b == b'\n').next().unwrap_or_default();
+    let status = String::from_utf8_lossy(status_line);
+    match status.split_whitespace().nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => bail!(Protocol, "webhook answered {:?}", status.trim_end()),
+    }
+}
+
+/// `bytes` as a JSON string, with invalid UTF-8 replaced.
+pub fn json_string(bytes: &[u8]) -> String {
+    let mut json = String::from("\"");
+    for c in String::from_utf8_lossy(bytes).chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
@@ -0,0 +1,47 @@
+use std::alloc::System;
+use std::borrow::Cow;
+use std::collections::HashMap;
+
+use dist_kv::allocator::{self, Counting};
+use dist_kv::client::Reply;
+use dist_kv::cluster::TestCluster;
+use dist_kv::protocol::Command;
+
+#[global_allocator]
+static ALLOCATOR: Counting<System> = Counting::new(System);
+
+#[test]
+fn allocations_are_counted_until_freed() {
+    let before = allocator::stats().unwrap();
+    let block = vec![0u8; 64 << 20];
+    let during = allocator::stats().unwrap();
+    assert!(during.allocated >= before.allocated + block.len());
+    assert!(during.peak >= during.allocated);
+    assert!(during.allocations > before.allocations);
+    drop(block);
+    // Other tests allocate at the same time, so only the peak is certain.
+    let after = allocator::stats().unwrap();
+    assert!(after.peak >= before.allocated + (64 << 20));
+}
+
+#[tokio::test]
+async fn info_reports_what_is_allocated() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"key", &vec![b'v'; 1 << 20]).await.unwrap();
+
+    let section = Some(Cow::Borrowed(&b"memory"[..]));
+    let Reply::Bulk(Some(info)) = client.call(&Command::Info(section)).await.unwrap() else {
+        panic!("INFO did not return a bulk reply");
+    };
+    let info = String::from_utf8(info.to_vec()).unwrap();
+    let fields: HashMap<_, _> = info
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .collect();
+    let allocated: usize = fields["allocated_bytes"].parse().unwrap();
+    assert!(allocated >= 1 << 20, "{}", info);
+    assert!(fields["allocated_peak_bytes"].parse::<usize>().unwrap() >= allocated);
+    let ratio: f64 = fields["fragmentation_ratio"].parse().unwrap();
+    assert!(ratio > 0.0, "{}", info);
+}
@@ -0,0 +1,54 @@
+use dist_kv::archive::{restore, take_snapshot, Archiver, DirStore};
+use dist_kv::cluster::TestCluster;
+use dist_kv::store::replay;
+
+#[tokio::test]
+async fn archive_restores_the_map() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let archive = cluster.dir().join("archive");
+    let log = cluster.dir().join("leader.log");
+    let leader = cluster.leader().unwrap();
+
+    client.set(b"a", b"1").await.unwrap();
+    client.set(b"b", b"2").await.unwrap();
+    let store = Box::new(DirStore::new(&archive));
+    let mut archiver = Archiver::open(store, log.to_str().unwrap()).unwrap();
+    assert!(archiver.snapshot_due());
+    // Nothing can be archived before the first snapshot.
+    assert!(archiver.archive(None).is_err());
+    archiver
+        .archive(Some(take_snapshot(leader).await.unwrap()))
+        .unwrap();
+    assert!(!archiver.snapshot_due());
+
+    client.set(b"c", b"3").await.unwrap();
+    client.del(b"a").await.unwrap();
+    archiver.archive(None).unwrap();
+    // With nothing new written there is no segment to upload.
+    archiver.archive(None).unwrap();
+    let manifest = std::fs::read_to_string(archive.join("manifest")).unwrap();
+    assert_eq!(
+        manifest.lines().collect::<Vec<_>>(),
+        ["snapshot/00000000000000000000", "wal/00000000000000000001"]
+    );
+
+    // Writes after the last segment aren't in the archive.
+    let archived = cluster.leader_hashmap().await.unwrap();
+    client.set(b"d", b"4").await.unwrap();
+    let restored = restore(&mut DirStore::new(&archive)).unwrap();
+    assert_eq!(replay(&restored).unwrap(), archived);
+
+    // A new archiver carries on after what the archive holds.
+    let store = Box::new(DirStore::new(&archive));
+    let mut archiver = Archiver::open(store, log.to_str().unwrap()).unwrap();
+    archiver
+        .archive(Some(take_snapshot(leader).await.unwrap()))
+        .unwrap();
+    let restored = restore(&mut DirStore::new(&archive)).unwrap();
+    assert_eq!(
+        replay(&restored).unwrap(),
+        cluster.leader_hashmap().await.unwrap()
+    );
+    assert!(archive.join("snapshot/00000000000000000002").exists());
+}
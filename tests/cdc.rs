@@ -0,0 +1,99 @@
+use bytes::Bytes;
+use dist_kv::client::Reply;
+use dist_kv::cluster::TestCluster;
+use dist_kv::protocol::Command;
+
+/// The op, namespace, key and value of a change push, checking the LSN.
+fn change(push: Vec<Bytes>, lsn: u64) -> Vec<String> {
+    assert_eq!(push.len(), 7, "{:?}", push);
+    assert_eq!(push[0], "change");
+    assert_eq!(push[1], lsn.to_string());
+    assert!(push[2].iter().all(u8::is_ascii_digit), "{:?}", push);
+    push[3..]
+        .iter()
+        .map(|item| String::from_utf8(item.to_vec()).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn tail_streams_committed_changes_in_order() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"a", b"1").await.unwrap();
+    client.get(b"a").await.unwrap();
+    let batch = Command::Batch(vec![
+        Command::Set(b"b"[..].into(), b"2"[..].into()),
+        Command::Delete(b"a"[..].into()),
+    ]);
+    client.call(&batch).await.unwrap();
+
+    // Catch up from the second write, then follow along.
+    let mut tail = cluster.client().await.unwrap();
+    tail.tail(2).await.unwrap();
+    let expect = |op: &'static str, key: &'static str, val: &'static str| vec![op, "0", key, val];
+    assert_eq!(
+        change(tail.next_push().await.unwrap(), 2),
+        expect("set", "b", "2")
+    );
+    assert_eq!(
+        change(tail.next_push().await.unwrap(), 2),
+        expect("del", "a", "")
+    );
+
+    let write = Command::In(
+        b"logs"[..].into(),
+        Box::new(Command::Set(b"c"[..].into(), b"3"[..].into())),
+    );
+    client.call(&write).await.unwrap();
+    assert_eq!(
+        change(tail.next_push().await.unwrap(), 3),
+        vec!["set", "logs", "c", "3"]
+    );
+
+    // A tail from the very next LSN waits for it; one from before the
+    // first write this leader made can't be served.
+    let mut next = cluster.client().await.unwrap();
+    next.tail(4).await.unwrap();
+    let mut old = cluster.client().await.unwrap();
+    let Reply::Error(err) = old.call(&Command::Tail(0)).await.unwrap() else {
+        panic!("TAIL 0 was accepted");
+    };
+    assert!(err.contains("no longer available"), "{}", err);
+    client.set(b"d", b"4").await.unwrap();
+    assert_eq!(
+        change(next.next_push().await.unwrap(), 4),
+        expect("set", "d", "4")
+    );
+}
+
+#[tokio::test]
+async fn tail_resumes_across_a_leader_restart() {
+    let mut cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    for (key, val) in [("a", "1"), ("b", "2"), ("c", "3")] {
+        client.set(key.as_bytes(), val.as_bytes()).await.unwrap();
+    }
+    let mut tail = cluster.client().await.unwrap();
+    tail.tail(1).await.unwrap();
+    for lsn in 1..=3 {
+        change(tail.next_push().await.unwrap(), lsn);
+    }
+
+    // The consumer picks up after the last change it saw, which the
+    // restarted leader's writes carry on from rather than repeat.
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut tail = cluster.client().await.unwrap();
+    tail.tail(4).await.unwrap();
+    let mut old = cluster.client().await.unwrap();
+    let Reply::Error(err) = old.call(&Command::Tail(3)).await.unwrap() else {
+        panic!("TAIL 3 was accepted");
+    };
+    assert!(err.contains("the oldest is 4"), "{}", err);
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"d", b"4").await.unwrap();
+    assert_eq!(
+        change(tail.next_push().await.unwrap(), 4),
+        vec!["set", "0", "d", "4"]
+    );
+}
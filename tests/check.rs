@@ -0,0 +1,160 @@
+use std::path::PathBuf;
+use std::process::{self, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use dist_kv::manifest::Manifest;
+use dist_kv::node::LeaderCore;
+use dist_kv::protocol::Command;
+use dist_kv::sim::{NullStorage, SimClock};
+use dist_kv::store::Db;
+
+fn temp_dir() -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    let dir = std::env::temp_dir().join(format!("dist-kv-check-{}-{}", process::id(), n));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// The records a leader logs for `n` writes, each led by its request ID.
+fn records(n: usize) -> Vec<Vec<u8>> {
+    let mut core = LeaderCore::new(Db::new(), NullStorage, SimClock::default());
+    (0..n)
+        .map(|i| {
+            let key = format!("key:{}", i % 3).into_bytes();
+            let command = match i % 4 {
+                3 => Command::Delete(key.into()),
+                _ => Command::Set(key.into(), b"value"[..].into()),
+            };
+            core.execute(&command).unwrap().1.unwrap().to_vec()
+        })
+        .collect()
+}
+
+fn check(log: &std::path::Path) -> (Output, String, String) {
+    let output = process::Command::new(env!("CARGO_BIN_EXE_dist-kv-check"))
+        .arg(log)
+        .output()
+        .unwrap();
+    let stdout = String::from_utf8(output.stdout.clone()).unwrap();
+    let stderr = String::from_utf8(output.stderr.clone()).unwrap();
+    (output, stdout, stderr)
+}
+
+#[test]
+fn a_good_log_passes() {
+    let dir = temp_dir();
+    let log = dir.join("leader.log");
+    std::fs::write(&log, records(6).concat()).unwrap();
+
+    let (output, stdout, stderr) = check(&log);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        stdout.contains("12 records (5 SET, 1 DEL, 6 other), 0 corrupt, 0 out of order"),
+        "{}",
+        stdout
+    );
+    assert!(stdout.contains("2 keys live"), "{}", stdout);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn a_corrupt_record_fails_the_check() {
+    let dir = temp_dir();
+    let log = dir.join("leader.log");
+    let records = records(4);
+    let mut contents = records[..2].concat();
+    let offset = contents.len();
+    contents.extend_from_slice(b"SET key:0\n");
+    contents.extend_from_slice(&records[2..].concat());
+    std::fs::write(&log, contents).unwrap();
+
+    let (output, stdout, stderr) = check(&log);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr.contains(&format!("offset {}: corrupt record", offset)),
+        "{}",
+        stderr
+    );
+    assert!(stdout.contains("1 corrupt"), "{}", stdout);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn a_torn_tail_is_only_a_warning() {
+    let dir = temp_dir();
+    let log = dir.join("leader.log");
+    let records = records(4);
+    let mut contents = records[..3].concat();
+    let offset = contents.len();
+    contents.extend_from_slice(&records[3][..5]);
+    std::fs::write(&log, contents).unwrap();
+
+    let (output, stdout, stderr) = check(&log);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(
+        stderr.contains(&format!(
+            "offset {}: warning: log ends in a torn record",
+            offset
+        )),
+        "{}",
+        stderr
+    );
+    assert!(stdout.contains("0 corrupt, 0 out of order"), "{}", stdout);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn a_repeated_request_fails_the_check() {
+    let dir = temp_dir();
+    let log = dir.join("leader.log");
+    let records = records(3);
+    let mut contents = records.concat();
+    let offset = contents.len();
+    contents.extend_from_slice(&records[1]);
+    std::fs::write(&log, contents).unwrap();
+
+    let (output, stdout, stderr) = check(&log);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(
+        stderr.contains(&format!("offset {}: request ", offset)),
+        "{}",
+        stderr
+    );
+    assert!(stderr.contains("out of order, after LSN 3"), "{}", stderr);
+    assert!(stdout.contains("1 out of order"), "{}", stdout);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn the_segments_the_manifest_names_are_read_in_order() {
+    let dir = temp_dir();
+    let log = dir.join("leader.log");
+    let records = records(4);
+    std::fs::write(dir.join("leader.log.segment.1"), records[..2].concat()).unwrap();
+    std::fs::write(dir.join("leader.log.segment.2"), records[2..].concat()).unwrap();
+    let manifest = Manifest {
+        generation: 2,
+        checkpoint: None,
+        segments: vec![
+            "leader.log.segment.1".to_string(),
+            "leader.log.segment.2".to_string(),
+        ],
+    };
+    manifest.save(log.to_str().unwrap()).unwrap();
+
+    let (output, stdout, stderr) = check(&log);
+    assert!(output.status.success(), "{}", stderr);
+    assert!(stdout.contains("8 records"), "{}", stdout);
+
+    // Read the other way round, the LSNs go backwards.
+    let manifest = Manifest {
+        segments: manifest.segments.into_iter().rev().collect(),
+        ..manifest
+    };
+    manifest.save(log.to_str().unwrap()).unwrap();
+    let (output, _, stderr) = check(&log);
+    assert_eq!(output.status.code(), Some(1));
+    assert!(stderr.contains("out of order"), "{}", stderr);
+    std::fs::remove_dir_all(dir).unwrap();
+}
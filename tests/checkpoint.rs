@@ -0,0 +1,389 @@
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+
+use bytes::BytesMut;
+use dist_kv::checkpoint;
+use dist_kv::cluster::TestCluster;
+use dist_kv::config::Config;
+use dist_kv::manifest::{manifest_path, Manifest};
+use dist_kv::protocol::Command;
+use dist_kv::replication::Overflow;
+use dist_kv::snapshot;
+use dist_kv::store::{replay, Db, Strictness};
+use dist_kv::wal::{open_log_with, read_segments};
+use nix::errno::Errno;
+use nix::fcntl::{fallocate, FallocateFlags};
+
+#[tokio::test]
+async fn a_restart_replays_only_the_log_after_the_checkpoint() {
+    let mut cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let log = cluster.dir().join("leader.log");
+    let log = log.to_str().unwrap();
+    for i in 0..50 {
+        let key = format!("key:{}", i);
+        client.set(key.as_bytes(), b"value").await.unwrap();
+    }
+    checkpoint::take(cluster.leader().unwrap()).await.unwrap();
+    client.set(b"key:50", b"value").await.unwrap();
+    client.del(b"key:0").await.unwrap();
+    let expected = cluster.leader_hashmap().await.unwrap();
+
+    let (hashmap, _, report) = open_log_with(log, Strictness::Strict).unwrap();
+    assert_eq!(hashmap, expected);
+    assert_eq!(report.records, 2);
+    assert_eq!(
+        report.complete,
+        std::fs::metadata(log).unwrap().len() as usize
+    );
+
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    assert_eq!(cluster.leader_hashmap().await.unwrap(), expected);
+}
+
+#[tokio::test]
+async fn a_checkpoint_of_another_log_is_ignored() {
+    let mut cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let log = cluster.dir().join("leader.log");
+    client.set(b"a", b"1").await.unwrap();
+    client.set(b"b", b"2").await.unwrap();
+    checkpoint::take(cluster.leader().unwrap()).await.unwrap();
+    let manifest = Manifest::load(log.to_str().unwrap()).unwrap();
+    assert_eq!(
+        manifest.checkpoint.as_deref(),
+        Some("leader.log.checkpoint.1")
+    );
+    cluster.kill_leader().await;
+
+    // A log shorter than the checkpoint says, and one just as long that
+    // differs where it ends, are both replayed whole.
+    let mut replaced = BytesMut::new();
+    Command::Set(b"c"[..].into(), b"3"[..].into()).encode(&mut replaced);
+    std::fs::write(&log, &replaced).unwrap();
+    cluster.restart_leader().await.unwrap();
+    assert_eq!(
+        cluster.leader_hashmap().await.unwrap(),
+        replay(&replaced).unwrap()
+    );
+
+    cluster.kill_leader().await;
+    let mut checkpointed = Db::new();
+    checkpointed.insert("x".into(), "9".into());
+    let len = replaced.len() as u64;
+    checkpoint::write(
+        log.to_str().unwrap(),
+        &snapshot::encode(&checkpointed, 1),
+        len,
+    )
+    .unwrap();
+    let mut same_length = BytesMut::new();
+    Command::Set(b"d"[..].into(), b"4"[..].into()).encode(&mut same_length);
+    assert_eq!(same_length.len(), replaced.len());
+    std::fs::write(&log, &same_length).unwrap();
+    cluster.restart_leader().await.unwrap();
+    assert_eq!(
+        cluster.leader_hashmap().await.unwrap(),
+        replay(&same_length).unwrap()
+    );
+}
+
+#[tokio::test]
+async fn recovery_uses_only_the_files_the_manifest_names() {
+    let mut cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let log = cluster.dir().join("leader.log");
+    let log = log.to_str().unwrap();
+    client.set(b"a", b"1").await.unwrap();
+    checkpoint::take(cluster.leader().unwrap()).await.unwrap();
+    client.set(b"b", b"2").await.unwrap();
+    checkpoint::take(cluster.leader().unwrap()).await.unwrap();
+    client.set(b"c", b"3").await.unwrap();
+    let expected = cluster.leader_hashmap().await.unwrap();
+
+    // The first checkpoint was removed once the manifest moved past it.
+    let manifest = Manifest::load(log).unwrap();
+    assert_eq!(manifest.generation, 2);
+    assert_eq!(
+        manifest.checkpoint.as_deref(),
+        Some("leader.log.checkpoint.2")
+    );
+    assert_eq!(manifest.segments, ["leader.log"]);
+    assert_eq!(Manifest::parse(&manifest.render()).unwrap(), manifest);
+    assert!(!cluster.dir().join("leader.log.checkpoint.1").exists());
+
+    // A checkpoint left behind by a crash before the manifest named it is
+    // ignored.
+    let mut other = Db::new();
+    other.insert("x".into(), "9".into());
+    let stray = checkpoint::encode(&snapshot::encode(&other, 1), 0, b"");
+    std::fs::write(cluster.dir().join("leader.log.checkpoint.3"), stray).unwrap();
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    assert_eq!(cluster.leader_hashmap().await.unwrap(), expected);
+
+    // A checkpoint the manifest names has to be there.
+    cluster.kill_leader().await;
+    std::fs::remove_file(cluster.dir().join("leader.log.checkpoint.2")).unwrap();
+    let err = cluster.restart_leader().await.unwrap_err();
+    assert!(
+        format!("{:#}", err).contains("leader.log.checkpoint.2"),
+        "{:#}",
+        err
+    );
+    std::fs::remove_file(manifest_path(log)).unwrap();
+    cluster.restart_leader().await.unwrap();
+    assert_eq!(cluster.leader_hashmap().await.unwrap(), expected);
+}
+
+fn files_in(dir: &std::path::Path) -> Vec<String> {
+    let mut files: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect();
+    files.sort();
+    files
+}
+
+#[tokio::test]
+async fn checkpoints_remove_the_log_segments_they_cover() {
+    let config = Config {
+        log_segment_size: 1,
+        ..Config::default()
+    };
+    let mut cluster = TestCluster::start_with_config(0, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let log = cluster.dir().join("leader.log");
+    let log = log.to_str().unwrap();
+    for i in 0..20 {
+        let key = format!("key:{}", i);
+        client.set(key.as_bytes(), b"value").await.unwrap();
+    }
+    checkpoint::take(cluster.leader().unwrap()).await.unwrap();
+    assert_eq!(
+        files_in(cluster.dir()),
+        [
+            "leader.log.checkpoint.2",
+            "leader.log.manifest",
+            "leader.log.segment.1"
+        ]
+    );
+    assert_eq!(
+        std::fs::metadata(cluster.dir().join("leader.log.segment.1"))
+            .unwrap()
+            .len(),
+        0
+    );
+
+    client.set(b"key:20", b"value").await.unwrap();
+    client.del(b"key:0").await.unwrap();
+    checkpoint::take(cluster.leader().unwrap()).await.unwrap();
+    client.set(b"key:21", b"value").await.unwrap();
+    assert_eq!(
+        files_in(cluster.dir()),
+        [
+            "leader.log.checkpoint.4",
+            "leader.log.manifest",
+            "leader.log.segment.3"
+        ]
+    );
+    let expected = cluster.leader_hashmap().await.unwrap();
+    let (_, _, report) = open_log_with(log, Strictness::Strict).unwrap();
+    assert_eq!(report.records, 1);
+
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    assert_eq!(cluster.leader_hashmap().await.unwrap(), expected);
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"key:22", b"value").await.unwrap();
+    let expected = cluster.leader_hashmap().await.unwrap();
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    assert_eq!(cluster.leader_hashmap().await.unwrap(), expected);
+}
+
+#[tokio::test]
+async fn a_lagging_follower_catches_up_after_the_segments_are_removed() {
+    let config = Config {
+        log_segment_size: 1,
+        replication_queue_size: 1,
+        replication_overflow: Overflow::Drop,
+        ..Config::default()
+    };
+    let mut cluster = TestCluster::start_with_config(0, config).await.unwrap();
+    let i = cluster.add_follower().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"synced", b"1").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+
+    // More writes than the follower's queue holds, so it is dropped and
+    // behind when the segments holding them are removed. It syncs from a
+    // snapshot, not the log, so it doesn't need them.
+    let mut requests = BytesMut::new();
+    for i in 0..200 {
+        let key = format!("key:{}", i);
+        Command::Set(key.as_bytes().into(), b"value"[..].into()).encode(&mut requests);
+    }
+    client.send_raw(&requests).await.unwrap();
+    for _ in 0..200 {
+        client.read_reply().await.unwrap();
+    }
+    checkpoint::take(cluster.leader().unwrap()).await.unwrap();
+    assert!(!files_in(cluster.dir()).contains(&"leader.log".to_string()));
+
+    cluster.wait_for_replication().await.unwrap();
+    let expected = cluster.leader_hashmap().await.unwrap();
+    assert_eq!(expected.len(), 201);
+    assert_eq!(cluster.follower_hashmap(i).unwrap(), expected);
+}
+
+#[tokio::test]
+async fn a_checkpoint_covers_segments_a_failed_one_left_behind() {
+    let config = Config {
+        log_segment_size: 1,
+        ..Config::default()
+    };
+    let mut cluster = TestCluster::start_with_config(0, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let log = cluster.dir().join("leader.log");
+    let log = log.to_str().unwrap();
+    client.set(b"a", b"1").await.unwrap();
+    // As if a checkpoint had started a segment and then failed to be written.
+    let mut manifest = Manifest::load(log).unwrap();
+    manifest.generation = 1;
+    manifest.segments.push("leader.log.segment.1".to_string());
+    std::fs::File::create(cluster.dir().join("leader.log.segment.1")).unwrap();
+    manifest.save(log).unwrap();
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"b", b"2").await.unwrap();
+    let expected = cluster.leader_hashmap().await.unwrap();
+    assert_eq!(read_segments(log).unwrap().unwrap().len(), {
+        let first = std::fs::metadata(cluster.dir().join("leader.log")).unwrap();
+        let second = std::fs::metadata(cluster.dir().join("leader.log.segment.1")).unwrap();
+        (first.len() + second.len()) as usize
+    });
+
+    checkpoint::take(cluster.leader().unwrap()).await.unwrap();
+    assert_eq!(
+        files_in(cluster.dir()),
+        [
+            "leader.log.checkpoint.3",
+            "leader.log.manifest",
+            "leader.log.segment.2"
+        ]
+    );
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    assert_eq!(cluster.leader_hashmap().await.unwrap(), expected);
+}
+
+#[tokio::test]
+async fn log_segments_are_preallocated_without_growing() {
+    let config = Config {
+        log_segment_size: 256 * 1024,
+        ..Config::default()
+    };
+    let cluster = TestCluster::start_with_config(0, config).await.unwrap();
+    let probe = cluster.dir().join("probe");
+    let file = std::fs::File::create(&probe).unwrap();
+    let probed = fallocate(
+        file.as_raw_fd(),
+        FallocateFlags::FALLOC_FL_KEEP_SIZE,
+        0,
+        4096,
+    );
+    std::fs::remove_file(probe).unwrap();
+    if probed == Err(Errno::EOPNOTSUPP) {
+        eprintln!("skipping: the filesystem can't preallocate");
+        return;
+    }
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"key", b"value").await.unwrap();
+    let allocated = |name: &str| {
+        let metadata = std::fs::metadata(cluster.dir().join(name)).unwrap();
+        (metadata.len(), metadata.blocks() * 512)
+    };
+    let (len, blocks) = allocated("leader.log");
+    assert!(len < 1024, "{} bytes", len);
+    assert!(blocks >= 256 * 1024, "{} bytes allocated", blocks);
+
+    client.set(b"big", &vec![b'x'; 300 * 1024]).await.unwrap();
+    checkpoint::take(cluster.leader().unwrap()).await.unwrap();
+    client.set(b"key", b"value").await.unwrap();
+    let (len, blocks) = allocated("leader.log.segment.1");
+    assert!(len < 1024, "{} bytes", len);
+    assert!(blocks >= 256 * 1024, "{} bytes allocated", blocks);
+}
+
+#[tokio::test]
+async fn a_follower_starts_its_log_over_from_the_snapshot_it_syncs() {
+    let mut config = Config::default();
+    config.set("namespace", "audit separate").unwrap();
+    let mut cluster = TestCluster::start_with_config(0, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"a", b"1").await.unwrap();
+    client.select(b"audit").await.unwrap();
+    client.set(b"login", b"alice").await.unwrap();
+
+    let i = cluster.add_follower().await.unwrap();
+    client.set(b"logout", b"alice").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    let follower: Vec<_> = files_in(cluster.dir())
+        .into_iter()
+        .filter(|name| name.starts_with("follower-"))
+        .collect();
+    assert_eq!(
+        follower,
+        [
+            "follower-0.log.audit",
+            "follower-0.log.checkpoint.1",
+            "follower-0.log.manifest",
+            "follower-0.log.segment.1"
+        ]
+    );
+    let audit = std::fs::read(cluster.dir().join("follower-0.log.audit")).unwrap();
+    let audit = replay(&audit).unwrap();
+    assert_eq!(audit.namespace(b"audit").unwrap().len(), 2);
+
+    let expected = cluster.leader_hashmap().await.unwrap();
+    assert_eq!(cluster.follower_hashmap(i).unwrap(), expected);
+    cluster.restart_follower(i).await.unwrap();
+    assert_eq!(cluster.follower_hashmap(i).unwrap(), expected);
+}
+
+#[tokio::test]
+async fn skipped_records_after_a_checkpoint_are_reported_at_their_log_offset() {
+    let mut cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let log = cluster.dir().join("leader.log");
+    let log = log.to_str().unwrap();
+    client.set(b"a", b"1").await.unwrap();
+    checkpoint::take(cluster.leader().unwrap()).await.unwrap();
+    client.set(b"b", b"2").await.unwrap();
+    cluster.kill_leader().await;
+
+    let mut contents = std::fs::read(log).unwrap();
+    let corrupt = contents.len();
+    contents.extend_from_slice(b"SET c\n");
+    let mut after = BytesMut::new();
+    Command::Set(b"d"[..].into(), b"4"[..].into()).encode(&mut after);
+    contents.extend_from_slice(&after);
+    std::fs::write(log, &contents).unwrap();
+
+    let err = open_log_with(log, Strictness::Strict).unwrap_err();
+    assert!(
+        format!("{:#}", err).contains(&format!("offset {}", corrupt)),
+        "{:#}",
+        err
+    );
+    let (hashmap, _, report) = open_log_with(log, Strictness::Skip).unwrap();
+    assert_eq!(report.skipped, [corrupt]);
+    assert_eq!(report.records, 2);
+    assert_eq!(report.complete, contents.len());
+    let mut keys: Vec<_> = hashmap.keys().map(|key| key.to_vec()).collect();
+    keys.sort();
+    assert_eq!(keys, [b"a", b"b", b"d"]);
+}
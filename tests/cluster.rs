@@ -0,0 +1,2906 @@
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use dist_kv::checkpoint;
+use dist_kv::client::{DistKvClient, Reply, RetryPolicy};
+use dist_kv::cluster::TestCluster;
+use dist_kv::config::Config;
+use dist_kv::discovery::discover;
+use dist_kv::error::DistKvError;
+use dist_kv::node::LeaderCore;
+use dist_kv::protocol::{
+    split_line, ClientCommand, Command, Condition, ConfigCommand, Consistency, ErrorCode,
+    ErrorReply, Expiry, ExportFormat, ObjectField, SetOptions, PROTOCOL_VERSION,
+};
+use dist_kv::replication::Overflow;
+use dist_kv::server::{self, Reload};
+use dist_kv::sim::{NullStorage, SimClock};
+use dist_kv::snapshot::{self, CHUNK_SIZE};
+use dist_kv::store::{Db, LoadProgress, Response};
+use dist_kv::wal::open_log;
+use nix::sys::signal::{raise, Signal};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+fn get(map: Option<dist_kv::store::Db>, key: &str) -> Option<Bytes> {
+    map.expect("node is not running")
+        .get(key.as_bytes())
+        .map(|val| val.to_bytes())
+}
+
+#[tokio::test]
+async fn writes_replicate_to_every_follower() {
+    let cluster = TestCluster::start(3).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"a", b"1").await.unwrap();
+    client.set(b"b", b"2").await.unwrap();
+    client.set(b"a", b"3").await.unwrap();
+    assert!(client.del(b"b").await.unwrap());
+    cluster.wait_for_replication().await.unwrap();
+
+    for i in 0..3 {
+        let map = cluster.follower_hashmap(i).unwrap();
+        assert_eq!(map.len(), 1);
+        assert_eq!(get(Some(map), "a"), Some(Bytes::from("3")));
+    }
+}
+
+#[tokio::test]
+async fn large_and_streamed_values_replicate() {
+    let cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let large = vec![b'x'; 100_000];
+    client.set(b"large", &large).await.unwrap();
+
+    let mut request = BytesMut::new();
+    Command::SetStream("streamed".as_bytes().into()).encode(&mut request);
+    request.extend_from_slice(b"$3\nabc\n$3\ndef\n$0\n\n");
+    client.send_raw(&request).await.unwrap();
+    assert_eq!(
+        client.read_reply().await.unwrap(),
+        Reply::Status("OK".into())
+    );
+    assert_eq!(
+        client.read_reply().await.unwrap(),
+        Reply::Status("OK".into())
+    );
+    cluster.wait_for_replication().await.unwrap();
+
+    let map = cluster.follower_hashmap(0);
+    assert_eq!(get(map.clone(), "large"), Some(Bytes::from(large)));
+    assert_eq!(get(map, "streamed"), Some(Bytes::from("abcdef")));
+}
+
+#[tokio::test]
+async fn a_partitioned_follower_catches_up_once_healed() {
+    let cluster = TestCluster::start_in_memory(2).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"a", b"1").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+
+    cluster.partition_follower(0).unwrap();
+    client.set(b"b", b"2").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert_eq!(get(cluster.follower_hashmap(0), "b"), None);
+    assert_eq!(
+        get(cluster.follower_hashmap(1), "b"),
+        Some(Bytes::from("2"))
+    );
+    // Clients can still reach the follower.
+    let mut reader = cluster.connect(cluster.follower_addr(0)).await.unwrap();
+    assert_eq!(reader.get(b"a").await.unwrap(), Some(Bytes::from("1")));
+
+    cluster.heal();
+    client.set(b"c", b"3").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    assert_eq!(
+        get(cluster.follower_hashmap(0), "b"),
+        Some(Bytes::from("2"))
+    );
+}
+
+#[tokio::test]
+async fn leader_restart_recovers_from_its_log() {
+    let mut cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"a", b"1").await.unwrap();
+    client.set(b"b", b"2").await.unwrap();
+    client.del(b"a").await.unwrap();
+    // Replication is batched, so a leader killed straight after a write may
+    // not have sent it yet.
+    cluster.wait_for_replication().await.unwrap();
+
+    cluster.kill_leader().await;
+    assert!(cluster.client().await.is_err());
+    cluster.restart_leader().await.unwrap();
+
+    let mut client = cluster.client().await.unwrap();
+    assert_eq!(client.get(b"a").await.unwrap(), None);
+    assert_eq!(client.get(b"b").await.unwrap(), Some(Bytes::from("2")));
+    client.set(b"c", b"3").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    assert_eq!(
+        get(cluster.follower_hashmap(0), "c"),
+        Some(Bytes::from("3"))
+    );
+}
+
+#[tokio::test]
+async fn follower_restart_recovers_from_its_log() {
+    let mut cluster = TestCluster::start(2).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"a", b"1").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+
+    cluster.kill_follower(1).await;
+    assert_eq!(cluster.follower_hashmap(1), None);
+    cluster.restart_follower(1).await.unwrap();
+    assert_eq!(
+        get(cluster.follower_hashmap(1), "a"),
+        Some(Bytes::from("1"))
+    );
+
+    client.set(b"b", b"2").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    assert_eq!(
+        get(cluster.follower_hashmap(1), "b"),
+        Some(Bytes::from("2"))
+    );
+}
+
+#[tokio::test]
+async fn leader_keeps_serving_while_a_follower_is_down() {
+    let mut cluster = TestCluster::start(2).await.unwrap();
+    cluster.kill_follower(0).await;
+
+    let mut client = cluster.client().await.unwrap();
+    for i in 0..10 {
+        client
+            .set(format!("key:{}", i).as_bytes(), b"v")
+            .await
+            .unwrap();
+    }
+    assert_eq!(client.get(b"key:9").await.unwrap(), Some(Bytes::from("v")));
+    cluster.wait_for_replication().await.unwrap();
+    assert_eq!(cluster.follower_hashmap(1).unwrap().len(), 10);
+}
+
+#[tokio::test]
+async fn range_writes_replicate_as_whole_values() {
+    let mut cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"record", b"Hello World").await.unwrap();
+    assert_eq!(client.setrange(b"record", 6, b"Redis").await.unwrap(), 11);
+    assert_eq!(client.setrange(b"padded", 3, b"x").await.unwrap(), 4);
+    assert_eq!(client.setrange(b"missing", 3, b"").await.unwrap(), 0);
+
+    assert_eq!(client.getrange(b"record", 0, 4).await.unwrap(), "Hello");
+    assert_eq!(client.getrange(b"record", -5, -1).await.unwrap(), "Redis");
+    assert_eq!(client.getrange(b"record", 6, 100).await.unwrap(), "Redis");
+    assert_eq!(client.getrange(b"record", 5, 2).await.unwrap(), "");
+    assert_eq!(client.getrange(b"missing", 0, -1).await.unwrap(), "");
+    assert_eq!(client.get(b"padded").await.unwrap().unwrap(), "\0\0\0x");
+    assert_eq!(client.get(b"missing").await.unwrap(), None);
+
+    cluster.wait_for_replication().await.unwrap();
+    let follower = cluster.follower_hashmap(0);
+    assert_eq!(get(follower, "record"), Some(Bytes::from("Hello Redis")));
+
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    assert_eq!(client.get(b"record").await.unwrap().unwrap(), "Hello Redis");
+}
+
+#[tokio::test]
+async fn bit_operations_replicate_as_whole_values() {
+    let mut cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    assert!(!client.setbit(b"flags", 1, true).await.unwrap());
+    assert!(!client.setbit(b"flags", 7, true).await.unwrap());
+    assert!(!client.setbit(b"flags", 17, true).await.unwrap());
+    assert!(client.setbit(b"flags", 7, false).await.unwrap());
+    assert!(client.getbit(b"flags", 1).await.unwrap());
+    assert!(!client.getbit(b"flags", 7).await.unwrap());
+    assert!(!client.getbit(b"flags", 1000).await.unwrap());
+    assert!(!client.getbit(b"missing", 0).await.unwrap());
+    assert_eq!(client.bitcount(b"flags").await.unwrap(), 2);
+    assert_eq!(client.bitcount(b"missing").await.unwrap(), 0);
+    let range = Command::BitCount("flags".as_bytes().into(), Some((1, -1)));
+    assert_eq!(client.call(&range).await.unwrap(), Reply::Integer(1));
+    let expected = Bytes::from_static(&[0b0100_0000, 0, 0b0100_0000]);
+    assert_eq!(client.get(b"flags").await.unwrap(), Some(expected.clone()));
+
+    cluster.wait_for_replication().await.unwrap();
+    assert_eq!(
+        get(cluster.follower_hashmap(0), "flags"),
+        Some(expected.clone())
+    );
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    assert_eq!(client.get(b"flags").await.unwrap(), Some(expected));
+}
+
+#[tokio::test]
+async fn hello_negotiates_version_and_features() {
+    let cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let hello = client.hello(&["compression", "BULK"]).await.unwrap();
+    assert_eq!(hello.version, PROTOCOL_VERSION);
+    assert_eq!(hello.role, "leader");
+    assert_eq!(hello.features, ["bulk"]);
+
+    // A client from the future is answered in the version the server speaks.
+    let reply = client.call(&Command::Hello(99, None)).await.unwrap();
+    let Reply::Bulk(Some(reply)) = reply else {
+        panic!("unexpected reply {:?}", reply);
+    };
+    assert!(reply.starts_with(format!("version:{}\n", PROTOCOL_VERSION).as_bytes()));
+    let reply = client.call(&Command::Hello(0, None)).await.unwrap();
+    assert!(matches!(reply, Reply::Error(err) if err.starts_with("ERR NOPROTO")));
+
+    let mut follower = DistKvClient::connect(cluster.follower_addr(0))
+        .await
+        .unwrap();
+    let follower = follower.hello(&[]).await.unwrap();
+    assert_eq!(follower.role, "follower");
+    assert!(follower.features.is_empty());
+    assert_ne!(follower.node_id, hello.node_id);
+}
+
+#[tokio::test]
+async fn clients_can_be_listed_named_and_killed() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut admin = cluster.client().await.unwrap();
+    let mut victim = cluster.client().await.unwrap();
+    let setname = Command::Client(ClientCommand::SetName("victim".as_bytes().into()));
+    assert_eq!(
+        victim.call(&setname).await.unwrap(),
+        Reply::Status("OK".into())
+    );
+    victim.set(b"a", b"1").await.unwrap();
+
+    let Reply::Bulk(Some(list)) = admin
+        .call(&Command::Client(ClientCommand::List))
+        .await
+        .unwrap()
+    else {
+        panic!("CLIENT LIST did not return a bulk reply");
+    };
+    let list = String::from_utf8(list.to_vec()).unwrap();
+    assert_eq!(list.lines().count(), 2, "{}", list);
+    let line = list
+        .lines()
+        .find(|line| line.contains(" name=victim "))
+        .unwrap();
+    assert!(line.ends_with(" cmd=set"), "{}", line);
+    let id: u64 = line
+        .strip_prefix("id=")
+        .and_then(|line| line.split(' ').next())
+        .unwrap()
+        .parse()
+        .unwrap();
+
+    let kill = Command::Client(ClientCommand::Kill(id));
+    assert_eq!(admin.call(&kill).await.unwrap(), Reply::Status("OK".into()));
+    assert!(victim.get(b"a").await.is_err());
+    let reply = admin.call(&kill).await.unwrap();
+    assert!(matches!(reply, Reply::Error(err) if err.starts_with("ERR NOSUCHCLIENT")));
+}
+
+#[tokio::test]
+async fn analyze_sees_the_map_as_it_started_while_writes_go_on() {
+    let config = Config {
+        history_size: 0,
+        ..Config::default()
+    };
+    let cluster = TestCluster::start_with_config(0, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    for i in 0..20_000 {
+        let key = format!("key:{}", i);
+        client.set(key.as_bytes(), b"0123456789").await.unwrap();
+    }
+    let mut scanner = cluster.client().await.unwrap();
+    let scan = tokio::spawn(async move { scanner.call(&Command::Analyze(1)).await.unwrap() });
+    let mut i = 0;
+    while !scan.is_finished() {
+        let key = format!("key:{}", 19_999 - i);
+        client.del(key.as_bytes()).await.unwrap();
+        client
+            .set(format!("new:{}", i).as_bytes(), b"x")
+            .await
+            .unwrap();
+        i += 1;
+    }
+    let Reply::Bulk(Some(report)) = scan.await.unwrap() else {
+        panic!("ANALYZE did not return a bulk reply");
+    };
+    let report = String::from_utf8(report.to_vec()).unwrap();
+    // The map as it was after some number of the writes, however many were
+    // made while the scan went on.
+    let keyspace = |writes: usize| {
+        let mut map = std::collections::HashMap::new();
+        for i in 0..20_000 {
+            map.insert(format!("key:{}", i), 10);
+        }
+        for i in 0..writes {
+            match i % 2 {
+                0 => map.remove(&format!("key:{}", 19_999 - i / 2)),
+                _ => map.insert(format!("new:{}", i / 2), 1),
+            };
+        }
+        let bytes: usize = map.iter().map(|(key, len)| key.len() + len).sum();
+        format!("keys:{}\nbytes:{}\n", map.len(), bytes)
+    };
+    assert!(
+        (0..=2 * i).any(|writes| report.contains(&keyspace(writes))),
+        "{}",
+        report
+    );
+
+    // Once the scan is done, the history it held on to is let go.
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let lsn = cluster.leader().unwrap().lock().await.core.lsn;
+    match client
+        .call(&Command::GetAt(b"key:0"[..].into(), lsn - 1))
+        .await
+        .unwrap()
+    {
+        Reply::Error(err) => assert!(err.starts_with("ERR NOTSUPPORTED"), "{}", err),
+        reply => panic!("expected a NOTSUPPORTED error, got {:?}", reply),
+    }
+}
+
+#[tokio::test]
+async fn exports_stream_the_keys_with_a_prefix() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    for i in 0..3000 {
+        let key = format!("user:{:04}", i);
+        client.set(key.as_bytes(), b"u").await.unwrap();
+    }
+    client.set(b"order:1", b"say \"hi\", bye").await.unwrap();
+    client.set(b"order:2", &[0xff, 0xfe]).await.unwrap();
+    client.expireat(b"order:1", 4_102_444_800).await.unwrap();
+
+    let mut csv = Vec::new();
+    let written = client
+        .export(ExportFormat::Csv, Some(b"order:"), &mut csv)
+        .await
+        .unwrap();
+    assert_eq!(written as usize, csv.len());
+    assert_eq!(
+        csv,
+        b"key,value,expires_at\r\n\
+          order:1,\"say \"\"hi\"\", bye\",4102444800000\r\n\
+          order:2,\xff\xfe,\r\n"
+    );
+
+    let mut ndjson = Vec::new();
+    client
+        .export(ExportFormat::Ndjson, Some(b"order:"), &mut ndjson)
+        .await
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(ndjson).unwrap(),
+        "{\"key\":\"order:1\",\"value\":\"say \\\"hi\\\", bye\",\"expires_at\":4102444800000}\n\
+         {\"key\":\"order:2\",\"value_base64\":\"//4=\",\"expires_at\":null}\n"
+    );
+
+    let mut all = Vec::new();
+    client
+        .export(ExportFormat::Ndjson, None, &mut all)
+        .await
+        .unwrap();
+    let lines: Vec<_> = all
+        .split(|b| *b == b'\n')
+        .filter(|l| !l.is_empty())
+        .collect();
+    assert_eq!(lines.len(), 3002);
+    assert!(lines[2].starts_with(b"{\"key\":\"user:0000\""));
+    client.set(b"after", b"export").await.unwrap();
+}
+
+#[tokio::test]
+async fn bulk_loads_are_applied_a_batch_at_a_time() {
+    let mut config = Config::default();
+    config.set("bulkload_batch", "1000").unwrap();
+    let cluster = TestCluster::start_with_config(1, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let keys: Vec<_> = (0..2500).map(|i| format!("key:{:04}", i)).collect();
+    let mut writes: Vec<_> = keys
+        .iter()
+        .map(|key| Command::Set(key.as_bytes().into(), b"v"[..].into()))
+        .collect();
+    writes.push(Command::Delete(b"key:0000"[..].into()));
+    assert_eq!(client.bulk_load(&writes).await.unwrap(), 2501);
+
+    assert_eq!(client.get(b"key:0000").await.unwrap(), None);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while get(cluster.follower_hashmap(0), "key:2499").is_none() {
+        assert!(Instant::now() < deadline, "key:2499 never replicated");
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    // A log record, and an fsync, for each batch rather than each write.
+    let mut follower = DistKvClient::connect(cluster.follower_addr(0))
+        .await
+        .unwrap();
+    match follower
+        .call(&Command::GetVersion(b"key:2499"[..].into(), None))
+        .await
+        .unwrap()
+    {
+        Reply::Push(items) => assert_eq!(items, [Bytes::from("3"), Bytes::from("v")]),
+        reply => panic!("expected a push, got {:?}", reply),
+    }
+
+    // Anything but SET and DEL ends the load; what came before it stays.
+    let writes = [
+        Command::Set(b"before"[..].into(), b"1"[..].into()),
+        Command::Get(b"before"[..].into()),
+        Command::Set(b"after"[..].into(), b"1"[..].into()),
+    ];
+    let err = client.bulk_load(&writes).await.unwrap_err().to_string();
+    assert!(
+        err.contains("GET can't be part of a bulk load (0 writes loaded)"),
+        "{}",
+        err
+    );
+    assert_eq!(client.get(b"after").await.unwrap(), None);
+    let err = client.call(&Command::BulkLoadEnd).await.unwrap();
+    assert!(matches!(err, Reply::Error(e) if e.contains("no bulk load")));
+}
+
+#[tokio::test]
+async fn objects_report_their_encoding_and_use() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    assert_eq!(client.object_encoding(b"missing").await.unwrap(), None);
+    assert_eq!(client.object_freq(b"missing").await.unwrap(), None);
+
+    client.set(b"key", b"value").await.unwrap();
+    assert_eq!(
+        client.object_encoding(b"key").await.unwrap().as_deref(),
+        Some("inline")
+    );
+    client.set(b"long", &[b'x'; 100]).await.unwrap();
+    assert_eq!(
+        client.object_encoding(b"long").await.unwrap().as_deref(),
+        Some("bytes")
+    );
+    assert_eq!(client.object_idle_time(b"key").await.unwrap(), Some(0));
+    assert_eq!(client.object_freq(b"key").await.unwrap(), Some(5));
+    // Looking doesn't count as using, but the first read always does.
+    assert_eq!(client.object_freq(b"key").await.unwrap(), Some(5));
+    client.get(b"key").await.unwrap();
+    assert_eq!(client.object_freq(b"key").await.unwrap(), Some(6));
+    for _ in 0..200 {
+        client.get(b"key").await.unwrap();
+    }
+    let freq = client.object_freq(b"key").await.unwrap().unwrap();
+    assert!((7..20).contains(&freq), "freq {}", freq);
+
+    client.select(b"other").await.unwrap();
+    assert_eq!(client.object_freq(b"key").await.unwrap(), None);
+}
+
+#[test]
+fn idle_keys_age_and_lose_frequency() {
+    let clock = SimClock::default();
+    clock.advance(Duration::from_secs(1000));
+    let mut core = LeaderCore::new(Db::new(), NullStorage, clock.clone());
+    let object = |core: &mut LeaderCore<_, _>, field| {
+        let command = Command::Object(field, Cow::Borrowed(&b"key"[..]));
+        core.execute(&command).unwrap().0
+    };
+    core.execute(&Command::Set(b"key"[..].into(), b"v"[..].into()))
+        .unwrap();
+    for _ in 0..3 {
+        core.execute(&Command::Get(b"key"[..].into())).unwrap();
+    }
+    let freq = match object(&mut core, ObjectField::Freq) {
+        Response::Integer(freq) => freq,
+        response => panic!("{:?}", response),
+    };
+    assert!(freq >= 6, "freq {}", freq);
+
+    clock.advance(Duration::from_secs(150));
+    assert_eq!(
+        object(&mut core, ObjectField::IdleTime),
+        Response::Integer(150)
+    );
+    assert_eq!(
+        object(&mut core, ObjectField::Freq),
+        Response::Integer(freq - 2)
+    );
+    core.execute(&Command::Get(b"key"[..].into())).unwrap();
+    assert_eq!(
+        object(&mut core, ObjectField::IdleTime),
+        Response::Integer(0)
+    );
+}
+
+#[tokio::test]
+async fn keys_are_sampled_uniformly() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    assert_eq!(client.random_key().await.unwrap(), None);
+    assert!(client.sample(5).await.unwrap().is_empty());
+    for i in 0..8 {
+        client
+            .set(format!("key:{}", i).as_bytes(), b"v")
+            .await
+            .unwrap();
+    }
+    for i in 4..8 {
+        client.del(format!("key:{}", i).as_bytes()).await.unwrap();
+    }
+
+    let mut counts = std::collections::HashMap::new();
+    for _ in 0..2000 {
+        let key = client.random_key().await.unwrap().unwrap();
+        *counts.entry(key).or_insert(0) += 1;
+    }
+    assert_eq!(counts.len(), 4);
+    for (key, count) in &counts {
+        assert!(*count > 350, "{:?} picked {} times of 2000", key, count);
+    }
+
+    let sample = client.sample(3).await.unwrap();
+    assert_eq!(sample.len(), 3);
+    assert!(sample.iter().all(|key| counts.contains_key(key)));
+    let mut all = client.sample(100).await.unwrap();
+    all.sort();
+    assert_eq!(all, ["key:0", "key:1", "key:2", "key:3"]);
+
+    client.select(b"other").await.unwrap();
+    assert_eq!(client.random_key().await.unwrap(), None);
+    client.set(b"elsewhere", b"v").await.unwrap();
+    assert_eq!(client.sample(10).await.unwrap(), ["elsewhere"]);
+}
+
+#[tokio::test]
+async fn dumped_keys_restore_into_another_cluster() {
+    let source = TestCluster::start(1).await.unwrap();
+    let mut client = source.client().await.unwrap();
+    let val: Vec<u8> = (0..=255).cycle().take(5000).collect();
+    client.set(b"blob", &val).await.unwrap();
+    client.set(b"plain", b"p").await.unwrap();
+    let in_a_day = unix_time().as_secs() + 24 * 3600;
+    client.expireat(b"blob", in_a_day).await.unwrap();
+    let dump = client.dump(b"blob").await.unwrap().unwrap();
+    assert_eq!(client.dump(b"missing").await.unwrap(), None);
+
+    let target = TestCluster::start(0).await.unwrap();
+    let mut other = target.client().await.unwrap();
+    other.restore(b"copy", &dump, false).await.unwrap();
+    assert_eq!(other.get(b"copy").await.unwrap(), Some(Bytes::from(val)));
+    let ttl = other.ttl(b"copy").await.unwrap();
+    assert!(ttl > 0 && ttl <= 24 * 3600, "{}", ttl);
+
+    let plain = client.dump(b"plain").await.unwrap().unwrap();
+    let err = other.restore(b"copy", &plain, false).await.unwrap_err();
+    assert!(err.to_string().contains("BUSYKEY"), "{}", err);
+    other.restore(b"copy", &plain, true).await.unwrap();
+    assert_eq!(other.get(b"copy").await.unwrap(), Some(Bytes::from("p")));
+    assert_eq!(other.ttl(b"copy").await.unwrap(), -1);
+
+    let mut corrupt = dump.to_vec();
+    corrupt[20] ^= 1;
+    let err = other.restore(b"bad", &corrupt, false).await.unwrap_err();
+    assert!(err.to_string().contains("checksum"), "{}", err);
+}
+
+#[tokio::test]
+async fn analyze_reports_largest_values_and_prefixes() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    for i in 0..3000 {
+        let key = format!("user:{}", i);
+        client
+            .set(key.as_bytes(), &vec![b'u'; i % 100])
+            .await
+            .unwrap();
+    }
+    client.set(b"session/1", &[b's'; 5000]).await.unwrap();
+    client.set(b"plain", b"p").await.unwrap();
+    let in_a_day = unix_time().as_secs() + 2 * 24 * 3600;
+    client.expireat(b"session/1", in_a_day).await.unwrap();
+
+    let Reply::Bulk(Some(report)) = client.call(&Command::Analyze(2)).await.unwrap() else {
+        panic!("ANALYZE did not return a bulk reply");
+    };
+    let report = String::from_utf8(report.to_vec()).unwrap();
+    let section = |name: &str| -> Vec<&str> {
+        let start = report.find(&format!("# {}\n", name)).unwrap() + name.len() + 3;
+        report[start..]
+            .lines()
+            .take_while(|line| !line.starts_with('#'))
+            .collect()
+    };
+    assert_eq!(section("Keyspace")[0], "keys:3002");
+    assert_eq!(
+        section("Largest values"),
+        ["\"session/1\" 5000", "\"user:999\" 99"]
+    );
+    assert_eq!(section("Prefixes"), ["\"user:\" 3000", "\"\" 1"]);
+    assert_eq!(
+        section("TTL"),
+        [
+            "no_ttl:3001",
+            "ttl_under_1m:0",
+            "ttl_under_1h:0",
+            "ttl_under_1d:0",
+            "ttl_over_1d:1"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn new_follower_bootstraps_from_a_snapshot() {
+    let mut cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    for i in 0..200 {
+        let val = vec![b'v'; 1000];
+        client
+            .set(format!("key:{}", i).as_bytes(), &val)
+            .await
+            .unwrap();
+    }
+
+    let i = cluster.add_follower().await.unwrap();
+    // Writes made while the snapshot is in flight must not be lost.
+    for i in 0..50 {
+        client
+            .set(format!("key:{}", i).as_bytes(), b"new")
+            .await
+            .unwrap();
+    }
+    client.del(b"key:199").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+
+    client.set(b"after", b"sync").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    let map = cluster.follower_hashmap(i).unwrap();
+    assert_eq!(map.len(), 200);
+    assert_eq!(get(Some(map.clone()), "key:0"), Some(Bytes::from("new")));
+    assert_eq!(get(Some(map.clone()), "after"), Some(Bytes::from("sync")));
+
+    // The snapshot replaced the follower's log, so it restarts where it was.
+    let log = cluster.dir().join(format!("follower-{}.log", i));
+    let (recovered, _) = open_log(log.to_str().unwrap()).unwrap();
+    assert_eq!(recovered, map);
+}
+
+async fn sync_header(cluster: &TestCluster, resume: Option<(u64, usize)>) -> (u64, usize, usize) {
+    let mut socket = TcpStream::connect(cluster.leader_addr()).await.unwrap();
+    let mut request = BytesMut::new();
+    Command::Sync(resume, None).encode(&mut request);
+    socket.write_all(&request).await.unwrap();
+    let mut buf = BytesMut::new();
+    loop {
+        if let Some(line) = split_line(&mut buf) {
+            return snapshot::parse_header(&line).unwrap();
+        }
+        assert_ne!(socket.read_buf(&mut buf).await.unwrap(), 0);
+    }
+}
+
+#[tokio::test]
+async fn interrupted_snapshot_transfer_resumes() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    // Larger than the socket buffers, so the transfer can't finish while
+    // nothing reads it.
+    for i in 0..200 {
+        let val = vec![b'v'; 100_000];
+        client
+            .set(format!("key:{}", i).as_bytes(), &val)
+            .await
+            .unwrap();
+    }
+
+    let (id, len, offset) = sync_header(&cluster, None).await;
+    assert!(len > CHUNK_SIZE);
+    assert_eq!(offset, 0);
+    assert_eq!(
+        sync_header(&cluster, Some((id, CHUNK_SIZE))).await,
+        (id, len, CHUNK_SIZE)
+    );
+    // An unknown transfer starts over from a fresh snapshot.
+    let (other, _, offset) = sync_header(&cluster, Some((id + 1, CHUNK_SIZE))).await;
+    assert_ne!(other, id + 1);
+    assert_eq!(offset, 0);
+}
+
+fn unix_time() -> std::time::Duration {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+}
+
+#[tokio::test]
+async fn ttls_replicate_and_survive_a_restart() {
+    let mut cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let hour_from_now = unix_time().as_secs() + 3600;
+    for key in [&b"session"[..], b"pinned", b"stale"] {
+        client.set(key, b"v").await.unwrap();
+    }
+
+    assert!(client.expireat(b"session", hour_from_now).await.unwrap());
+    assert!(!client.expireat(b"missing", hour_from_now).await.unwrap());
+    assert!((3599..=3600).contains(&client.ttl(b"session").await.unwrap()));
+    // Partial writes keep the TTL, while a SET drops it.
+    client.setrange(b"session", 0, b"V").await.unwrap();
+    assert!(client.ttl(b"session").await.unwrap() > 0);
+
+    assert!(!client.persist(b"pinned").await.unwrap());
+    assert!(client.expireat(b"pinned", hour_from_now).await.unwrap());
+    assert!(client.persist(b"pinned").await.unwrap());
+    assert_eq!(client.ttl(b"pinned").await.unwrap(), -1);
+
+    // A time in the past deletes the key straight away.
+    assert!(client.expireat(b"stale", 1).await.unwrap());
+    assert_eq!(client.get(b"stale").await.unwrap(), None);
+    assert_eq!(client.ttl(b"stale").await.unwrap(), -2);
+
+    cluster.wait_for_replication().await.unwrap();
+    let follower = cluster.follower_hashmap(0).unwrap();
+    assert_eq!(follower.expiry(b"session"), Some(hour_from_now * 1000));
+    assert_eq!(follower.expiry(b"pinned"), None);
+    assert!(!follower.contains_key(&b"stale"[..]));
+
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    assert!(client.ttl(b"session").await.unwrap() > 0);
+    assert_eq!(client.ttl(b"pinned").await.unwrap(), -1);
+    assert_eq!(client.get(b"session").await.unwrap().unwrap(), "V");
+}
+
+#[tokio::test]
+async fn expired_keys_are_deleted_without_being_touched() {
+    let cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"short", b"v").await.unwrap();
+    let soon = unix_time().as_millis() as u64 + 200;
+    let reply = client
+        .call(&Command::PExpireAt(b"short"[..].into(), soon))
+        .await
+        .unwrap();
+    assert_eq!(reply, Reply::Integer(1));
+
+    tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+    cluster.wait_for_replication().await.unwrap();
+    assert_eq!(get(cluster.follower_hashmap(0), "short"), None);
+}
+
+#[tokio::test]
+async fn set_options_take_a_lock_in_one_command() {
+    let mut cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let lock = SetOptions {
+        expiry: Some(Expiry::In(30_000)),
+        condition: Some(Condition::Absent),
+        keep_ttl: false,
+    };
+    assert!(client.set_with(b"lock", b"owner-1", lock).await.unwrap());
+    assert!(!client.set_with(b"lock", b"owner-2", lock).await.unwrap());
+    assert_eq!(client.get(b"lock").await.unwrap().unwrap(), "owner-1");
+    assert!((29..=30).contains(&client.ttl(b"lock").await.unwrap()));
+
+    let keep = SetOptions {
+        keep_ttl: true,
+        condition: Some(Condition::Exists),
+        ..SetOptions::default()
+    };
+    assert!(client.set_with(b"lock", b"renamed", keep).await.unwrap());
+    assert!(client.ttl(b"lock").await.unwrap() > 0);
+    assert!(!client.set_with(b"missing", b"v", keep).await.unwrap());
+    client.set(b"lock", b"plain").await.unwrap();
+    assert_eq!(client.ttl(b"lock").await.unwrap(), -1);
+
+    for bad in [
+        "SET k v NX XX",
+        "SET k v EX 10 PX 10",
+        "SET k v EX 10 KEEPTTL",
+        "SET k v EX",
+        "SET k v EX 0",
+        "SET k v SOON",
+    ] {
+        client
+            .send_raw(format!("{}\n", bad).as_bytes())
+            .await
+            .unwrap();
+        let reply = client.read_reply().await.unwrap();
+        assert!(matches!(reply, Reply::Error(_)), "{}: {:?}", bad, reply);
+    }
+
+    client
+        .set_with(
+            b"session",
+            b"s",
+            SetOptions {
+                expiry: Some(Expiry::In(60_000)),
+                ..lock
+            },
+        )
+        .await
+        .unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    let follower = cluster.follower_hashmap(0).unwrap();
+    let leader = cluster.leader_hashmap().await.unwrap();
+    assert!(follower.expiry(b"session").is_some());
+    assert_eq!(follower.expiry(b"session"), leader.expiry(b"session"));
+
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    assert!(client.ttl(b"session").await.unwrap() > 0);
+}
+
+#[tokio::test]
+async fn delifeq_releases_only_a_lock_still_held() {
+    let cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"lock", b"owner-1").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    // The lock lapsed and someone else took it.
+    client.set(b"lock", b"owner-2").await.unwrap();
+
+    // Releasing a lock someone else now holds leaves it alone.
+    assert!(!client.delifeq(b"lock", b"owner-1").await.unwrap());
+    assert!(!client.delifeq(b"missing", b"owner-1").await.unwrap());
+    assert_eq!(client.get(b"lock").await.unwrap().unwrap(), "owner-2");
+    assert!(client.delifeq(b"lock", b"owner-2").await.unwrap());
+    assert_eq!(client.get(b"lock").await.unwrap(), None);
+
+    cluster.wait_for_replication().await.unwrap();
+    assert_eq!(get(cluster.follower_hashmap(0), "lock"), None);
+}
+
+#[tokio::test]
+async fn batches_apply_and_replicate_as_one_write() {
+    let mut cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"from", b"100").await.unwrap();
+    client
+        .batch(vec![
+            Command::Set(b"from"[..].into(), b"60"[..].into()),
+            Command::Set(b"to"[..].into(), b"40"[..].into()),
+            Command::Delete(b"pending"[..].into()),
+        ])
+        .await
+        .unwrap();
+    assert_eq!(client.get(b"to").await.unwrap().unwrap(), "40");
+
+    client.send_raw(b"BATCH \"GET from\\n\"\n").await.unwrap();
+    assert!(matches!(
+        client.read_reply().await.unwrap(),
+        Reply::Error(_)
+    ));
+
+    cluster.wait_for_replication().await.unwrap();
+    assert_eq!(
+        get(cluster.follower_hashmap(0), "from"),
+        Some(Bytes::from("60"))
+    );
+
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    assert_eq!(client.get(b"from").await.unwrap().unwrap(), "60");
+    assert_eq!(client.get(b"to").await.unwrap().unwrap(), "40");
+}
+
+#[tokio::test]
+async fn namespaces_keep_their_keys_apart() {
+    let mut cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"flag", b"default").await.unwrap();
+    client.select(b"staging").await.unwrap();
+    assert_eq!(client.get(b"flag").await.unwrap(), None);
+    client.set(b"flag", b"staging").await.unwrap();
+    client
+        .batch(vec![Command::Set(b"batched"[..].into(), b"1"[..].into())])
+        .await
+        .unwrap();
+    assert_eq!(client.get(b"flag").await.unwrap().unwrap(), "staging");
+
+    let mut other = cluster.client().await.unwrap();
+    assert_eq!(other.get(b"flag").await.unwrap().unwrap(), "default");
+    assert_eq!(other.get(b"batched").await.unwrap(), None);
+    other.select(b"0").await.unwrap();
+    assert_eq!(other.get(b"flag").await.unwrap().unwrap(), "default");
+
+    cluster.wait_for_replication().await.unwrap();
+    let follower = cluster.follower_hashmap(0).unwrap();
+    let staging = follower.namespace(b"staging").unwrap();
+    assert_eq!(staging.get(&b"flag"[..]).unwrap(), "staging");
+    assert_eq!(follower.get(&b"flag"[..]).unwrap(), "default");
+
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.select(b"staging").await.unwrap();
+    assert_eq!(client.get(b"flag").await.unwrap().unwrap(), "staging");
+    assert_eq!(client.get(b"batched").await.unwrap().unwrap(), "1");
+    client.del(b"flag").await.unwrap();
+    client.del(b"batched").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    let follower = cluster.follower_hashmap(0).unwrap();
+    assert!(follower.namespace(b"staging").is_none());
+}
+
+#[tokio::test]
+async fn namespaces_choose_where_they_are_logged() {
+    let mut config = Config::default();
+    config.set("namespace", "cache memory").unwrap();
+    config.set("namespace", "audit separate").unwrap();
+    let mut cluster = TestCluster::start_with_config(1, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"config", b"durable").await.unwrap();
+    client.select(b"cache").await.unwrap();
+    client.set(b"page", b"rendered").await.unwrap();
+    client.select(b"audit").await.unwrap();
+    client.set(b"login", b"alice").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    let follower = cluster.follower_hashmap(0).unwrap();
+    let cache = follower.namespace(b"cache").unwrap();
+    assert_eq!(cache.get(&b"page"[..]).unwrap(), "rendered");
+
+    let node_id = cluster.leader().unwrap().lock().await.core.node_id.clone();
+    let log = std::fs::read_to_string(cluster.dir().join("leader.log")).unwrap();
+    // The cache's writes are not logged, but their request IDs are.
+    assert_eq!(
+        log,
+        format!(
+            "REQID {0}-1\nSET config durable\nIN cache \"REQID {0}-2\\n\"\n",
+            node_id
+        )
+    );
+    let audit = std::fs::read_to_string(cluster.dir().join("leader.log.audit")).unwrap();
+    assert_eq!(
+        audit,
+        format!(
+            "IN audit \"REQID {}-3\\n\"\nIN audit \"SET login alice\\n\"\n",
+            node_id
+        )
+    );
+
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    assert_eq!(client.get(b"config").await.unwrap().unwrap(), "durable");
+    client.select(b"audit").await.unwrap();
+    assert_eq!(client.get(b"login").await.unwrap().unwrap(), "alice");
+    client.select(b"cache").await.unwrap();
+    assert_eq!(client.get(b"page").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn tenants_are_kept_to_their_prefix_and_quotas() {
+    let mut config = Config::default();
+    config
+        .set("tenant", "billing s3cret billing: max_keys 2 max_bytes 40")
+        .unwrap();
+    config.set("tenant", "search hunter2 search:").unwrap();
+    let cluster = TestCluster::start_with_config(0, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let error = |reply: Reply, code: &str| match reply {
+        Reply::Error(err) => assert!(err.starts_with(&format!("ERR {}", code)), "{}", err),
+        reply => panic!("expected a {} error, got {:?}", code, reply),
+    };
+    let get = |key: &'static str| Command::Get(key.as_bytes().into());
+    let set = |key: &'static str, val: &'static str| {
+        Command::Set(key.as_bytes().into(), val.as_bytes().into())
+    };
+    error(client.call(&get("billing:1")).await.unwrap(), "NOAUTH");
+    assert!(client.auth(b"billing", b"wrong").await.is_err());
+    client.auth(b"billing", b"s3cret").await.unwrap();
+
+    client.set(b"billing:1", b"ten bytes!").await.unwrap();
+    error(client.call(&set("search:1", "x")).await.unwrap(), "NOPERM");
+    error(client.call(&get("search:1")).await.unwrap(), "NOPERM");
+    error(client.call(&Command::Info(None)).await.unwrap(), "NOPERM");
+    // billing:1 takes 19 bytes, so 21 are left.
+    error(
+        client
+            .call(&set("billing:2", "twelve bytes!"))
+            .await
+            .unwrap(),
+        "OVERQUOTA",
+    );
+    client.set(b"billing:2", b"eleven byte").await.unwrap();
+    error(
+        client.call(&set("billing:3", "")).await.unwrap(),
+        "OVERQUOTA",
+    );
+    // Overwriting with a value no larger stays within quota.
+    client.set(b"billing:1", b"ten bytes?").await.unwrap();
+    assert!(client.del(b"billing:2").await.unwrap());
+    client.select(b"archive").await.unwrap();
+    client.set(b"billing:3", b"x").await.unwrap();
+    error(
+        client.call(&set("billing:4", "x")).await.unwrap(),
+        "OVERQUOTA",
+    );
+
+    let mut search = cluster.client().await.unwrap();
+    search.auth(b"search", b"hunter2").await.unwrap();
+    search.set(b"search:index", &[0; 1000]).await.unwrap();
+    error(search.call(&get("billing:1")).await.unwrap(), "NOPERM");
+}
+
+#[tokio::test]
+async fn tenants_are_held_to_their_rates() {
+    let mut config = Config::default();
+    config
+        .set(
+            "tenant",
+            "billing s3cret billing: max_ops 5 max_write_bytes 100",
+        )
+        .unwrap();
+    config.set("tenant", "search hunter2 search:").unwrap();
+    let cluster = TestCluster::start_with_config(0, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let mut other = cluster.client().await.unwrap();
+    assert!(client.quota().await.is_err());
+    client.auth(b"billing", b"s3cret").await.unwrap();
+    other.auth(b"billing", b"s3cret").await.unwrap();
+
+    // A write larger than the byte rate never fits in a second.
+    let reply = client
+        .call(&Command::Set(b"billing:a"[..].into(), [0; 200][..].into()))
+        .await
+        .unwrap();
+    match reply {
+        Reply::Error(err) => assert!(err.starts_with("ERR OVERQUOTA"), "{}", err),
+        reply => panic!("expected an OVERQUOTA error, got {:?}", reply),
+    }
+
+    // Both connections share the tenant's rate. The requests may straddle a
+    // second, but no more than two.
+    let mut allowed = 0;
+    for i in 0..20 {
+        let client = if i % 2 == 0 { &mut client } else { &mut other };
+        match client
+            .call(&Command::Get(b"billing:a"[..].into()))
+            .await
+            .unwrap()
+        {
+            Reply::Error(err) => assert!(err.starts_with("ERR OVERQUOTA"), "{}", err),
+            _ => allowed += 1,
+        }
+    }
+    assert!((5..=10).contains(&allowed), "{} allowed", allowed);
+
+    let quota = client.quota().await.unwrap();
+    let fields: Vec<(&str, &str)> = quota
+        .lines()
+        .map(|line| line.split_once(':').unwrap())
+        .collect();
+    assert_eq!(fields[0], ("max_ops", "5"));
+    assert_eq!(fields[2], ("max_write_bytes", "100"));
+    assert!(fields[1].1.parse::<u64>().unwrap() <= 5, "{}", quota);
+
+    let mut search = cluster.client().await.unwrap();
+    search.auth(b"search", b"hunter2").await.unwrap();
+    for _ in 0..20 {
+        search.set(b"search:a", b"x").await.unwrap();
+    }
+    assert!(search.quota().await.unwrap().starts_with("max_ops:none\n"));
+}
+
+#[tokio::test]
+async fn each_port_runs_only_the_commands_it_accepts() {
+    // A free port for the admin listener, given up again for the leader.
+    let admin = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut config = Config::default();
+    config.set("commands", "get,set,del").unwrap();
+    config.set("listen", &format!("{} all", admin)).unwrap();
+    assert!(config.set("commands", "GET,FLUSHALL").is_err());
+    let cluster = TestCluster::start_with_config(0, config).await.unwrap();
+
+    let mut public = cluster.client().await.unwrap();
+    public.set(b"a", b"1").await.unwrap();
+    assert_eq!(public.get(b"a").await.unwrap(), Some(Bytes::from("1")));
+    let in_staging = Command::In(
+        b"staging"[..].into(),
+        Box::new(Command::Get(b"a"[..].into())),
+    );
+    for command in [
+        Command::Info(None),
+        Command::Config(ConfigCommand::Get(b"commands"[..].into())),
+        in_staging,
+    ] {
+        match public.call(&command).await.unwrap() {
+            Reply::Error(err) => assert!(err.starts_with("ERR NOPERM"), "{}", err),
+            reply => panic!("expected a NOPERM error, got {:?}", reply),
+        }
+    }
+
+    let mut client = DistKvClient::connect(admin).await.unwrap();
+    assert_eq!(client.config_get("commands").await.unwrap(), "DEL,GET,SET");
+    assert_eq!(client.get(b"a").await.unwrap(), Some(Bytes::from("1")));
+    assert!(matches!(
+        client.call(&Command::Info(None)).await.unwrap(),
+        Reply::Bulk(Some(_))
+    ));
+}
+
+#[tokio::test]
+async fn dumpstate_writes_a_report_of_the_leader() {
+    let cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let mut other = cluster.client().await.unwrap();
+    client.set(b"key", b"value").await.unwrap();
+    other.get(b"key").await.unwrap();
+
+    let path = client.dump_state().await.unwrap();
+    assert!(
+        path.starts_with(cluster.dir().to_str().unwrap()),
+        "{}",
+        path
+    );
+    let report = std::fs::read_to_string(&path).unwrap();
+    let fields: std::collections::HashMap<&str, &str> = report
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .collect();
+    assert_eq!(fields["lsn"], "1");
+    assert_eq!(fields["connected"], "2");
+    assert_eq!(fields["followers"], "1");
+    assert!(
+        fields["follower_0"].contains("state=streaming"),
+        "{}",
+        report
+    );
+    assert_eq!(fields["keyspace_bytes"], "8");
+    assert_eq!(fields["keys"], "1");
+    for section in ["# Clients", "# Replication", "# Memory", "# Keyspace"] {
+        assert!(report.contains(section), "{}", report);
+    }
+    assert_eq!(report.matches("cmd=").count(), 2);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[tokio::test]
+async fn reads_are_as_consistent_as_they_ask() {
+    let mut cluster = TestCluster::start(2).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"key", b"one").await.unwrap();
+    client.set(b"key", b"two").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    for consistency in [Consistency::Local, Consistency::Leader, Consistency::Quorum] {
+        assert_eq!(
+            client.get_with(b"key", consistency).await.unwrap(),
+            Some(Bytes::from("two"))
+        );
+    }
+    assert_eq!(
+        client
+            .get_with(b"missing", Consistency::Quorum)
+            .await
+            .unwrap(),
+        None
+    );
+
+    let mut follower = DistKvClient::connect(cluster.follower_addr(0))
+        .await
+        .unwrap();
+    assert_eq!(
+        follower.get_with(b"key", Consistency::Local).await.unwrap(),
+        Some(Bytes::from("two"))
+    );
+    let err = follower.get_with(b"key", Consistency::Quorum).await;
+    assert!(err.unwrap_err().to_string().contains("MOVED"));
+    match follower
+        .call(&Command::GetVersion(b"key"[..].into(), None))
+        .await
+        .unwrap()
+    {
+        Reply::Push(items) => assert_eq!(items, [Bytes::from("2"), Bytes::from("two")]),
+        reply => panic!("expected a push, got {:?}", reply),
+    }
+
+    // One follower down still leaves a majority; both down doesn't.
+    cluster.kill_follower(0).await;
+    assert!(client.get_with(b"key", Consistency::Quorum).await.is_ok());
+    cluster.kill_follower(1).await;
+    let err = client.get_with(b"key", Consistency::Quorum).await;
+    assert!(err.unwrap_err().to_string().contains("NOQUORUM"));
+    assert_eq!(
+        client.get_with(b"key", Consistency::Leader).await.unwrap(),
+        Some(Bytes::from("two"))
+    );
+}
+
+#[tokio::test]
+async fn quorum_reads_see_writes_made_after_a_leader_restart() {
+    let mut cluster = TestCluster::start(2).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    for val in ["one", "two", "three"] {
+        client.set(b"key", val.as_bytes()).await.unwrap();
+    }
+    cluster.wait_for_replication().await.unwrap();
+
+    // Follower 1 misses the write made after the restart, and comes back
+    // with the LSN its log ends at. The leader's write must still be newer.
+    cluster.kill_follower(1).await;
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"key", b"four").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    cluster.revive_follower(1).await.unwrap();
+    cluster.kill_follower(0).await;
+    assert_eq!(
+        client.get_with(b"key", Consistency::Quorum).await.unwrap(),
+        Some(Bytes::from("four"))
+    );
+}
+
+async fn info_section(client: &mut DistKvClient, section: &str) -> String {
+    let section = Some(Cow::Borrowed(section.as_bytes()));
+    let Reply::Bulk(Some(info)) = client.call(&Command::Info(section)).await.unwrap() else {
+        panic!("INFO did not return a bulk reply");
+    };
+    String::from_utf8(info.to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn synced_followers_learn_before_they_vote() {
+    let mut cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    // Larger than the socket buffers, so a transfer nobody reads stalls.
+    for i in 0..200 {
+        let val = vec![b'v'; 100_000];
+        client
+            .set(format!("key:{}", i).as_bytes(), &val)
+            .await
+            .unwrap();
+    }
+    sync_header(&cluster, None).await;
+    let info = info_section(&mut client, "replication").await;
+    assert!(info.contains("state=syncing,voter=0"), "{}", info);
+    // The stalled learner doesn't count, so the leader alone is a majority.
+    assert!(client.get_with(b"key:0", Consistency::Quorum).await.is_ok());
+
+    let i = cluster.add_follower().await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    client.set(b"after", b"sync").await.unwrap();
+    assert_eq!(
+        client
+            .get_with(b"after", Consistency::Quorum)
+            .await
+            .unwrap(),
+        Some(Bytes::from("sync"))
+    );
+    let info = info_section(&mut client, "replication").await;
+    let addr = cluster.follower_addr(i);
+    assert!(
+        info.contains(&format!("addr={},state=streaming,voter=1", addr)),
+        "{}",
+        info
+    );
+
+    // The promoted follower is needed for a majority now; the learner isn't.
+    cluster.kill_follower(i).await;
+    let err = client.get_with(b"after", Consistency::Quorum).await;
+    assert!(err.unwrap_err().to_string().contains("NOQUORUM"));
+}
+
+#[tokio::test]
+async fn nodes_join_and_leave_the_cluster_one_at_a_time() {
+    let mut cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"before", b"joining").await.unwrap();
+
+    // A node added starts from a snapshot, so it has the earlier writes.
+    let n = cluster.start_node().await.unwrap();
+    let addr = cluster.follower_addr(n).to_string();
+    client.cluster_add_node(&addr).await.unwrap();
+    client.set(b"after", b"joining").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    assert_eq!(
+        get(cluster.follower_hashmap(n), "before"),
+        Some(Bytes::from("joining"))
+    );
+    let err = client.cluster_add_node(&addr).await.unwrap_err();
+    assert!(err.to_string().contains("already a member"), "{}", err);
+    let info = info_section(&mut client, "replication").await;
+    assert!(
+        info.contains(&format!("addr={},state=streaming,voter=1", addr)),
+        "{}",
+        info
+    );
+
+    let removed = cluster.follower_addr(0).to_string();
+    client.cluster_remove_node(&removed).await.unwrap();
+    let info = info_section(&mut client, "replication").await;
+    assert!(info.contains("followers:1"), "{}", info);
+    assert!(!info.contains(&removed), "{}", info);
+    let err = client.cluster_remove_node(&removed).await.unwrap_err();
+    assert!(err.to_string().contains("not a member"), "{}", err);
+
+    // Nothing else changes while a learner is catching up.
+    for i in 0..200 {
+        let val = vec![b'v'; 100_000];
+        client
+            .set(format!("key:{}", i).as_bytes(), &val)
+            .await
+            .unwrap();
+    }
+    sync_header(&cluster, None).await;
+    let err = client.cluster_remove_node(&addr).await.unwrap_err();
+    assert!(err.to_string().contains("BUSY"), "{}", err);
+}
+
+#[tokio::test]
+async fn membership_changes_outlast_a_leader_restart() {
+    let mut cluster = TestCluster::start(2).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let n = cluster.start_node().await.unwrap();
+    let added = cluster.follower_addr(n).to_string();
+    client.cluster_add_node(&added).await.unwrap();
+    client.set(b"before", b"restart").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    let removed = cluster.follower_addr(0).to_string();
+    client.cluster_remove_node(&removed).await.unwrap();
+
+    // The followers still replicated to log both changes too.
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while cluster
+        .follower_hashmap(1)
+        .unwrap()
+        .member(&removed)
+        .is_none()
+    {
+        assert!(Instant::now() < deadline, "the removal never arrived");
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    let hashmap = cluster.follower_hashmap(1).unwrap();
+    assert_eq!(hashmap.member(&added), Some(true));
+    assert_eq!(hashmap.member(&removed), Some(false));
+
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let info = info_section(&mut client, "replication").await;
+    assert!(info.contains("followers:2"), "{}", info);
+    assert!(info.contains(&format!("addr={},", added)), "{}", info);
+    assert!(!info.contains(&removed), "{}", info);
+
+    client.set(b"after", b"restart").await.unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while get(cluster.follower_hashmap(n), "after").is_none() {
+        assert!(Instant::now() < deadline, "the added node missed a write");
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    assert_eq!(get(cluster.follower_hashmap(0), "after"), None);
+}
+
+#[tokio::test]
+async fn a_follower_that_missed_writes_is_sent_a_snapshot() {
+    let mut cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"a", b"1").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+
+    // Write until the leader notices the follower is gone.
+    cluster.kill_follower(0).await;
+    let deadline = Instant::now() + Duration::from_secs(5);
+    for i in 0.. {
+        client
+            .set(format!("missed:{}", i).as_bytes(), b"x")
+            .await
+            .unwrap();
+        let info = info_section(&mut client, "replication").await;
+        if info.contains("state=disconnected") {
+            break;
+        }
+        assert!(Instant::now() < deadline, "{}", info);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    cluster.revive_follower(0).await.unwrap();
+    client.set(b"b", b"2").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    assert_eq!(
+        get(cluster.follower_hashmap(0), "missed:0"),
+        Some(Bytes::from("x"))
+    );
+    assert_eq!(
+        get(cluster.follower_hashmap(0), "b"),
+        Some(Bytes::from("2"))
+    );
+
+    // Streaming picks up again after the snapshot.
+    client.set(b"c", b"3").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+}
+
+#[tokio::test]
+async fn leadership_is_handed_over_once_the_follower_has_every_write() {
+    let cluster = TestCluster::start(2).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    for i in 0..100 {
+        client
+            .set(format!("key:{}", i).as_bytes(), b"value")
+            .await
+            .unwrap();
+    }
+    let err = client.transfer_leadership("localhost:1").await.unwrap_err();
+    assert!(err.to_string().contains("not a member"), "{}", err);
+
+    let addr = cluster.follower_addr(0).to_string();
+    client.transfer_leadership(&addr).await.unwrap();
+    let expected = cluster.leader_hashmap().await.unwrap();
+    assert_eq!(cluster.follower_hashmap(0).unwrap(), expected);
+    let err = client.set(b"key:0", b"refused").await.unwrap_err();
+    assert!(err.to_string().contains("handed to"), "{}", err);
+    assert_eq!(
+        client.get(b"key:0").await.unwrap(),
+        Some(Bytes::from("value"))
+    );
+
+    // Called off, the leader takes writes again.
+    client.read_only(false).await.unwrap();
+    client.set(b"key:0", b"again").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+}
+
+#[tokio::test]
+async fn leadership_is_handed_over_after_a_restart_only_once_caught_up() {
+    let mut cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    for i in 0..10 {
+        client
+            .set(format!("key:{}", i).as_bytes(), b"value")
+            .await
+            .unwrap();
+    }
+    cluster.wait_for_replication().await.unwrap();
+
+    // The follower's LSN from before the restart is no sign that it has
+    // the writes after it: it has to reach the fence, one past the last.
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"after", b"restart").await.unwrap();
+    assert_eq!(cluster.leader().unwrap().lock().await.core.lsn, 11);
+    let addr = cluster.follower_addr(0).to_string();
+    client.transfer_leadership(&addr).await.unwrap();
+    let mut follower = DistKvClient::connect(cluster.follower_addr(0))
+        .await
+        .unwrap();
+    match follower
+        .call(&Command::GetVersion(b"after"[..].into(), None))
+        .await
+        .unwrap()
+    {
+        Reply::Push(items) => assert_eq!(items, [Bytes::from("12"), Bytes::from("restart")]),
+        reply => panic!("expected a push, got {:?}", reply),
+    }
+}
+
+#[tokio::test]
+async fn wan_replicas_are_left_out_of_quorums() {
+    let mut cluster = TestCluster::start(2).await.unwrap();
+    let wan = cluster.follower_addr(0).to_string();
+    let leader = cluster.leader().unwrap().clone();
+    leader
+        .lock()
+        .await
+        .config
+        .set("wan_replicas", &wan)
+        .unwrap();
+    cluster.restart_follower(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"key", b"value").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    let info = info_section(&mut client, "replication").await;
+    assert!(
+        info.contains(&format!("addr={},state=streaming,voter=0", wan)),
+        "{}",
+        info
+    );
+    assert!(info.contains(",wan=1,lag_alarm=0"), "{}", info);
+
+    // The leader and the other follower are the majority.
+    cluster.kill_follower(0).await;
+    let value = Some(Bytes::from("value"));
+    assert_eq!(
+        client.get_with(b"key", Consistency::Quorum).await.unwrap(),
+        value
+    );
+    cluster.kill_follower(1).await;
+    let err = client.get_with(b"key", Consistency::Quorum).await;
+    assert!(err.unwrap_err().to_string().contains("NOQUORUM"));
+}
+
+#[tokio::test]
+async fn filtered_followers_are_sent_only_their_prefixes() {
+    let mut cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"user:1", b"ada").await.unwrap();
+    client.set(b"order:1", b"book").await.unwrap();
+
+    let n = cluster.start_node().await.unwrap();
+    let addr = cluster.follower_addr(n).to_string();
+    let leader = cluster.leader().unwrap().clone();
+    leader
+        .lock()
+        .await
+        .config
+        .set("replication_filter", &format!("{} user:,session:", addr))
+        .unwrap();
+    client.cluster_add_node(&addr).await.unwrap();
+    client.set(b"session:1", b"token").await.unwrap();
+    client.set(b"order:2", b"pen").await.unwrap();
+    client.del(b"user:1").await.unwrap();
+    client.set(b"user:2", b"grace").await.unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while get(cluster.follower_hashmap(n), "user:2").is_none() {
+        assert!(Instant::now() < deadline, "user:2 never arrived");
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    let mut keys: Vec<_> = cluster
+        .follower_hashmap(n)
+        .unwrap()
+        .keys()
+        .map(|key| String::from_utf8_lossy(key).into_owned())
+        .collect();
+    keys.sort();
+    assert_eq!(keys, ["session:1", "user:2"]);
+}
+
+#[tokio::test]
+async fn transformed_followers_are_sent_rewritten_records() {
+    let mut cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"user:1", b"ada").await.unwrap();
+    client.set(b"card:1", b"4111").await.unwrap();
+
+    let n = cluster.start_node().await.unwrap();
+    let addr = cluster.follower_addr(n).to_string();
+    let leader = cluster.leader().unwrap().clone();
+    {
+        let mut leader = leader.lock().await;
+        let config = &mut leader.config;
+        config
+            .set("replication_transform", &format!("{} redact card:", addr))
+            .unwrap();
+        config
+            .set(
+                "replication_transform",
+                &format!("{} rename user: u:", addr),
+            )
+            .unwrap();
+        config
+            .set("replication_transform", &format!("{} drop tmp:", addr))
+            .unwrap();
+    }
+    client.cluster_add_node(&addr).await.unwrap();
+    client.set(b"tmp:1", b"scratch").await.unwrap();
+    client.set(b"card:2", b"5500").await.unwrap();
+    client.set(b"user:2", b"grace").await.unwrap();
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while get(cluster.follower_hashmap(n), "u:2").is_none() {
+        assert!(Instant::now() < deadline, "u:2 never arrived");
+        tokio::time::sleep(Duration::from_millis(5)).await;
+    }
+    let hashmap = cluster.follower_hashmap(n).unwrap();
+    let mut entries: Vec<_> = hashmap
+        .iter()
+        .map(|(key, value)| (String::from_utf8_lossy(key).into_owned(), value.to_bytes()))
+        .collect();
+    entries.sort();
+    assert_eq!(
+        entries,
+        [
+            ("card:1".to_string(), Bytes::new()),
+            ("card:2".to_string(), Bytes::new()),
+            ("u:1".to_string(), Bytes::from("ada")),
+            ("u:2".to_string(), Bytes::from("grace")),
+        ]
+    );
+}
+
+#[tokio::test]
+async fn a_lease_lets_quorum_reads_skip_the_followers() {
+    let mut config = Config::default();
+    config.set("read_lease_ms", "500").unwrap();
+    config.set("max_clock_drift_ms", "100").unwrap();
+    let mut cluster = TestCluster::start_with_config(2, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"key", b"value").await.unwrap();
+    let value = Some(Bytes::from("value"));
+    assert_eq!(
+        client.get_with(b"key", Consistency::Quorum).await.unwrap(),
+        value
+    );
+
+    // A follower that granted the lease turns away any other leader.
+    let mut follower = DistKvClient::connect(cluster.follower_addr(0))
+        .await
+        .unwrap();
+    let replicate = Command::Replicate(Some(b"elsewhere:1"[..].into()));
+    match follower.call(&replicate).await.unwrap() {
+        Reply::Error(err) => assert!(err.contains("LEASED"), "{}", err),
+        reply => panic!("expected a LEASED error, got {:?}", reply),
+    }
+
+    cluster.kill_follower(0).await;
+    cluster.kill_follower(1).await;
+    assert_eq!(
+        client.get_with(b"key", Consistency::Quorum).await.unwrap(),
+        value
+    );
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let err = client.get_with(b"key", Consistency::Quorum).await;
+    assert!(err.unwrap_err().to_string().contains("NOQUORUM"));
+}
+
+#[tokio::test]
+async fn debug_commands_are_refused_unless_turned_on() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let err = client.debug_sleep(0).await.unwrap_err().to_string();
+    assert!(err.contains("NOTSUPPORTED"), "{}", err);
+    assert!(client.debug_set_lsn(5).await.is_err());
+    client.dump_state().await.unwrap();
+
+    let mut config = Config::default();
+    config.set("debug_commands", "on").unwrap();
+    let cluster = TestCluster::start_with_config(0, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.debug_set_lsn(100).await.unwrap();
+    client.set(b"key", b"value").await.unwrap();
+    let object = client.debug_object(b"key").await.unwrap().unwrap();
+    for field in ["len:5\n", "history_versions:1\n", "last_write_lsn:101\n"] {
+        assert!(object.contains(field), "{} in {}", field, object);
+    }
+    assert_eq!(client.debug_object(b"missing").await.unwrap(), None);
+
+    // A sleep holds the lock, so a read from another client waits it out.
+    let mut other = cluster.client().await.unwrap();
+    let sleeping = tokio::spawn(async move { client.debug_sleep(300).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let start = std::time::Instant::now();
+    other.get(b"key").await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(200));
+    sleeping.await.unwrap().unwrap();
+}
+
+#[tokio::test]
+async fn the_audit_log_names_who_sent_each_write() {
+    let dir = std::env::temp_dir().join(format!("dist-kv-audit-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("audit.log");
+    let mut config = Config::default();
+    config.set("tenant", "billing s3cret billing:").unwrap();
+    config.set("audit_log", path.to_str().unwrap()).unwrap();
+    config.set("audit_log_size", "1kb").unwrap();
+    let cluster = TestCluster::start_with_config(0, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let local = client.local_addr().unwrap().to_string();
+
+    assert!(client.set(b"billing:a", b"1").await.is_err());
+    assert!(client.auth(b"billing", b"wrong").await.is_err());
+    client.auth(b"billing", b"s3cret").await.unwrap();
+    client.set(b"billing:a", b"secret value").await.unwrap();
+    client.get(b"billing:a").await.unwrap();
+    assert!(client.set(b"other", b"x").await.is_err());
+    client.select(b"staging").await.unwrap();
+    client.del(b"billing:a").await.unwrap();
+
+    let log = std::fs::read_to_string(&path).unwrap();
+    let lines: Vec<&str> = log.lines().collect();
+    assert_eq!(lines.len(), 6, "{}", log);
+    let fields = |line: &str| -> Vec<String> {
+        line.split(' ')
+            .filter(|field| !field.starts_with("time="))
+            .map(str::to_string)
+            .collect()
+    };
+    let addr = format!("addr={}", local);
+    let expected = [
+        [
+            "user=-",
+            &addr,
+            "ns=0",
+            "cmd=SET",
+            r#"keys="billing:a""#,
+            "result=denied",
+        ],
+        [
+            "user=billing",
+            &addr,
+            "ns=0",
+            "cmd=AUTH",
+            "keys=",
+            "result=denied",
+        ],
+        [
+            "user=billing",
+            &addr,
+            "ns=0",
+            "cmd=AUTH",
+            "keys=",
+            "result=allowed",
+        ],
+        [
+            "user=billing",
+            &addr,
+            "ns=0",
+            "cmd=SET",
+            r#"keys="billing:a""#,
+            "result=allowed",
+        ],
+        [
+            "user=billing",
+            &addr,
+            "ns=0",
+            "cmd=SET",
+            r#"keys="other""#,
+            "result=denied",
+        ],
+        [
+            "user=billing",
+            &addr,
+            "ns=staging",
+            "cmd=DEL",
+            r#"keys="billing:a""#,
+            "result=allowed",
+        ],
+    ];
+    for (line, expected) in lines.iter().zip(expected) {
+        assert_eq!(fields(line), expected, "{}", line);
+    }
+    assert!(!log.contains("secret value"));
+
+    // Once the log would grow past its size it is moved aside, and no lines
+    // are lost doing so.
+    for i in 0..20 {
+        client
+            .set(format!("billing:{}", i).as_bytes(), b"x")
+            .await
+            .unwrap();
+    }
+    let logs: Vec<String> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| std::fs::read_to_string(entry.unwrap().path()).unwrap())
+        .collect();
+    assert!(logs.len() > 1);
+    assert!(logs.iter().all(|log| log.len() <= 1024));
+    let total: usize = logs.iter().map(|log| log.lines().count()).sum();
+    assert_eq!(total, 26);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn scripts_apply_their_writes_together() {
+    let cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let incr = "(let n (+ (or (get (key 1)) 0) (arg 1))) (set (key 1) n) n";
+    for expected in [5, 10] {
+        let reply = client.eval(incr, &[b"hits"], &[b"5"]).await.unwrap();
+        assert_eq!(reply, Reply::Integer(expected));
+    }
+    let swap = r#"
+        (if (= (get (key 1)) (arg 1))
+            (do (set (key 1) (arg 2)) (del (key 2)))
+            (error "lock is held by someone else"))
+    "#;
+    client.set(b"lock", b"owner-a").await.unwrap();
+    client.set(b"pending", b"job").await.unwrap();
+    let err = client
+        .eval(swap, &[b"lock", b"pending"], &[b"owner-b", b"owner-c"])
+        .await
+        .unwrap_err();
+    assert!(
+        err.to_string().starts_with("ERR SCRIPT lock is held"),
+        "{}",
+        err
+    );
+    let reply = client
+        .eval(swap, &[b"lock", b"pending"], &[b"owner-a", b"owner-b"])
+        .await
+        .unwrap();
+    assert_eq!(reply, Reply::Integer(1));
+    assert_eq!(client.get(b"lock").await.unwrap().unwrap(), "owner-b");
+    assert_eq!(client.get(b"pending").await.unwrap(), None);
+
+    // A script that fails partway changes nothing.
+    let failing = "(set (key 1) \"changed\") (get \"undeclared\")";
+    assert!(client.eval(failing, &[b"lock"], &[]).await.is_err());
+    let endless = "(let i 0) (while 1 (let i (+ i 1)))";
+    assert!(client.eval(endless, &[], &[]).await.is_err());
+    assert!(client.eval("(get", &[], &[]).await.is_err());
+    assert_eq!(client.get(b"lock").await.unwrap().unwrap(), "owner-b");
+
+    cluster.wait_for_replication().await.unwrap();
+    assert_eq!(get(cluster.follower_hashmap(0), "hits").unwrap(), "10");
+    assert_eq!(get(cluster.follower_hashmap(0), "lock").unwrap(), "owner-b");
+}
+
+#[tokio::test]
+async fn functions_are_called_by_name() {
+    let path = std::env::temp_dir().join(format!("dist-kv-incrby-{}", std::process::id()));
+    std::fs::write(&path, "(set (key 1) (+ (or (get (key 1)) 0) (arg 1)))").unwrap();
+    let mut config = Config::default();
+    config
+        .set("function", &format!("incrby {}", path.display()))
+        .unwrap();
+    std::fs::remove_file(&path).unwrap();
+    let cluster = TestCluster::start_with_config(1, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.select(b"stats").await.unwrap();
+    for (n, expected) in [(b"3", 3), (b"4", 7)] {
+        let reply = client.fcall("incrby", &[b"visits"], &[n]).await.unwrap();
+        assert_eq!(reply, Reply::Integer(expected));
+    }
+    let err = client.fcall("missing", &[], &[]).await.unwrap_err();
+    assert!(
+        err.to_string().starts_with("ERR SCRIPT no function"),
+        "{}",
+        err
+    );
+
+    cluster.wait_for_replication().await.unwrap();
+    let follower = cluster.follower_hashmap(0).unwrap();
+    let stats = follower.namespace(b"stats").unwrap();
+    assert_eq!(stats.get(&b"visits"[..]).unwrap(), "7");
+}
+
+#[tokio::test]
+async fn followers_send_writes_to_the_leader() {
+    let cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"a", b"1").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+
+    let mut follower = DistKvClient::connect(cluster.follower_addr(0))
+        .await
+        .unwrap();
+    assert_eq!(follower.get(b"a").await.unwrap(), Some(Bytes::from("1")));
+    let moved = format!("ERR MOVED {}", cluster.leader_addr());
+    for write in [
+        Command::Set(b"a"[..].into(), b"2"[..].into()),
+        Command::Delete(b"a"[..].into()),
+        Command::In(
+            b"logs"[..].into(),
+            Box::new(Command::Persist(b"a"[..].into())),
+        ),
+    ] {
+        let reply = follower.call(&write).await.unwrap();
+        assert_eq!(reply, Reply::Error(moved.clone()), "{:?}", write);
+    }
+    assert_eq!(
+        get(cluster.follower_hashmap(0), "a"),
+        Some(Bytes::from("1"))
+    );
+
+    // The leader's own connection still replicates.
+    client.set(b"a", b"3").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    assert_eq!(follower.get(b"a").await.unwrap(), Some(Bytes::from("3")));
+}
+
+#[tokio::test]
+async fn every_node_names_the_leader() {
+    let mut cluster = TestCluster::start(1).await.unwrap();
+    let leader_addr = cluster.leader_addr().to_string();
+    let mut client = cluster.client().await.unwrap();
+    let leader = client.whoami().await.unwrap();
+    assert_eq!(leader.role, "leader");
+    assert_eq!(leader.leader.as_deref(), Some(&*leader_addr));
+    assert_eq!(client.leader().await.unwrap(), Some(leader_addr.clone()));
+
+    // A follower learns the address once the leader first replicates to it.
+    let mut follower = DistKvClient::connect(cluster.follower_addr(0))
+        .await
+        .unwrap();
+    let whoami = follower.whoami().await.unwrap();
+    assert_eq!((whoami.role.as_str(), whoami.leader), ("follower", None));
+    assert_eq!(follower.leader().await.unwrap(), None);
+    client.set(b"a", b"1").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    let whoami = follower.whoami().await.unwrap();
+    assert_eq!(whoami.leader.as_deref(), Some(&*leader_addr));
+    assert_ne!(whoami.node_id, leader.node_id);
+
+    let added = cluster.add_follower().await.unwrap();
+    let mut added = DistKvClient::connect(cluster.follower_addr(added))
+        .await
+        .unwrap();
+    assert_eq!(added.leader().await.unwrap(), Some(leader_addr));
+}
+
+#[tokio::test]
+async fn membership_is_learned_from_seeds() {
+    let cluster = TestCluster::start(2).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"a", b"1").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+
+    // A seed that is down is skipped, and one given by name is resolved.
+    let seeds = [
+        "127.0.0.1:1".to_string(),
+        format!("localhost:{}", cluster.follower_addr(1).port()),
+    ];
+    let peers = discover(&seeds).await.unwrap();
+    assert_eq!(peers.leader, Some(cluster.leader_addr().to_string()));
+    assert_eq!(
+        peers.followers,
+        [0, 1].map(|i| cluster.follower_addr(i).to_string())
+    );
+    assert!(discover(&seeds[..1]).await.is_err());
+}
+
+#[tokio::test]
+async fn config_set_changes_limits_and_saves_them() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let path = cluster.dir().join("dist-kv.conf");
+    std::fs::write(&path, "# limits\nmax_value_size 1mb\nlog_recovery strict\n").unwrap();
+    cluster.leader().unwrap().lock().await.config_path = Some(path.display().to_string());
+
+    let mut client = cluster.client().await.unwrap();
+    assert_eq!(
+        client.config_get("max_value_size").await.unwrap(),
+        "536870912"
+    );
+    client.config_set("max_value_size", "8").await.unwrap();
+    assert_eq!(client.config_get("max_value_size").await.unwrap(), "8");
+    let err = client.set(b"k", b"123456789").await.unwrap_err();
+    assert!(err.to_string().contains("TOOLARGE"), "{}", err);
+    let mut other = cluster.client().await.unwrap();
+    other.set(b"k", b"12345678").await.unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&path).unwrap(),
+        "# limits\nmax_value_size 8\nlog_recovery strict\n"
+    );
+
+    for (name, value, expected) in [
+        (
+            "log_recovery",
+            "skip",
+            "ERR NOTSUPPORTED log_recovery can only be changed",
+        ),
+        ("max_value_size", "lots", "ERR WRONGARGS invalid size lots"),
+        ("max_key_size", "", "ERR"),
+    ] {
+        let err = client.config_set(name, value).await.unwrap_err();
+        assert!(err.to_string().starts_with(expected), "{}", err);
+    }
+    assert!(client.config_get("nonsense").await.is_err());
+}
+
+#[tokio::test]
+async fn sighup_reloads_live_settings_from_the_config_file() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let leader = cluster.leader().unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let path = cluster.dir().join("dist-kv.conf");
+    std::fs::write(&path, "max_value_size 8\nlog_recovery skip\n").unwrap();
+    let config = Config::load(path.to_str().unwrap()).unwrap();
+    assert_eq!(
+        leader.lock().await.reload(&config),
+        Reload {
+            applied: vec!["max_value_size"],
+            needs_restart: vec!["log_recovery"],
+        }
+    );
+    let err = client.set(b"k", b"123456789").await.unwrap_err();
+    assert!(err.to_string().contains("TOOLARGE"), "{}", err);
+
+    std::fs::write(&path, "max_value_size 16\n").unwrap();
+    server::reload_on_hangup(leader.clone(), path.display().to_string()).unwrap();
+    raise(Signal::SIGHUP).unwrap();
+    let mut attempts = 0;
+    while client.set(b"k", b"123456789").await.is_err() {
+        assert!(attempts < 100, "the reload never took effect");
+        attempts += 1;
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(leader.lock().await.config.max_value_size, 16);
+}
+
+#[tokio::test]
+async fn commandstats_counts_calls_errors_and_latency() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    for i in 0..3 {
+        client
+            .set(format!("k{}", i).as_bytes(), b"v")
+            .await
+            .unwrap();
+    }
+    client.get(b"k0").await.unwrap();
+    assert!(client.config_get("nonsense").await.is_err());
+
+    let section = Some(Cow::Borrowed(&b"commandstats"[..]));
+    let Reply::Bulk(Some(info)) = client.call(&Command::Info(section)).await.unwrap() else {
+        panic!("INFO did not return a bulk reply");
+    };
+    let info = String::from_utf8(info.to_vec()).unwrap();
+    assert!(info.starts_with("# Commandstats\n"), "{}", info);
+    let stat = |name: &str| {
+        let prefix = format!("cmdstat_{}:", name);
+        let line = info.lines().find(|line| line.starts_with(&prefix)).unwrap();
+        line[prefix.len()..]
+            .split(',')
+            .map(|field| field.split_once('=').unwrap())
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect::<std::collections::BTreeMap<_, _>>()
+    };
+    assert_eq!(stat("set")["calls"], "3");
+    assert_eq!(stat("set")["errors"], "0");
+    assert_eq!(stat("get")["calls"], "1");
+    assert_eq!(stat("config")["errors"], "1");
+    assert!(!info.contains("# Latency"));
+
+    let leader = cluster.leader().unwrap().lock().await;
+    let set = leader.core.metrics.command_stats("SET").unwrap();
+    assert!(set.max_usec <= set.total_usec);
+}
+
+#[tokio::test]
+async fn info_reports_the_leaders_followers_and_memory() {
+    let cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"key", b"value").await.unwrap();
+
+    let Reply::Bulk(Some(info)) = client.call(&Command::Info(None)).await.unwrap() else {
+        panic!("INFO did not return a bulk reply");
+    };
+    let info = String::from_utf8(info.to_vec()).unwrap();
+    assert!(info.contains("# Keyspace\n") && info.contains("# Replication\n"));
+    let fields: std::collections::HashMap<_, _> = info
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .collect();
+    assert_eq!(fields["lsn"], "1");
+    assert_eq!(fields["followers"], "1");
+    assert!(fields["follower_0"].contains("state=streaming"));
+    assert_eq!(fields["keyspace_bytes"], "8");
+
+    let section = Some(Cow::Borrowed(&b"memory"[..]));
+    let Reply::Bulk(Some(memory)) = client.call(&Command::Info(section)).await.unwrap() else {
+        panic!("INFO did not return a bulk reply");
+    };
+    let memory = String::from_utf8(memory.to_vec()).unwrap();
+    assert!(memory.starts_with("# Memory\n"), "{}", memory);
+    assert!(!memory.contains("followers:"));
+}
+
+#[tokio::test]
+async fn interned_values_are_kept_once() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let status = |i: usize| format!("{{\"status\":\"{}\",\"region\":\"eu-west-1\"}}", i % 3);
+    client.set(b"before", status(0).as_bytes()).await.unwrap();
+    client.config_set("intern_values", "on").await.unwrap();
+    for i in 0..100 {
+        let key = format!("flag:{}", i);
+        client
+            .set(key.as_bytes(), status(i).as_bytes())
+            .await
+            .unwrap();
+    }
+    client.select(b"other").await.unwrap();
+    client.set(b"flag", status(0).as_bytes()).await.unwrap();
+    client.select(b"0").await.unwrap();
+
+    let memory = info_section(&mut client, "memory").await;
+    let fields: std::collections::HashMap<_, _> = memory
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .collect();
+    // One per status in the default namespace, and one more in the other.
+    assert_eq!(fields["interned_values"], "4");
+    assert_eq!(fields["interned_bytes"], (4 * status(0).len()).to_string());
+    assert_eq!(
+        client.get(b"flag:7").await.unwrap(),
+        Some(Bytes::from(status(1)))
+    );
+    {
+        let leader = cluster.leader().unwrap().lock().await;
+        let hashmap = &leader.core.hashmap;
+        assert!(hashmap[&b"flag:1"[..]].shares(&hashmap[&b"flag:4"[..]]));
+        assert!(hashmap[&b"before"[..]].shares(&hashmap[&b"flag:3"[..]]));
+    }
+
+    // Values no key holds any more are let go.
+    for i in 0..100 {
+        let key = format!("flag:{}", i);
+        client.set(key.as_bytes(), b"off").await.unwrap();
+    }
+    client.del(b"before").await.unwrap();
+    let memory = info_section(&mut client, "memory").await;
+    assert!(memory.contains("interned_values:1\n"), "{}", memory);
+}
+
+#[tokio::test]
+async fn request_ids_trace_a_write_to_the_followers() {
+    let cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let hello = client.hello(&["reqid"]).await.unwrap();
+    assert_eq!(hello.features, ["reqid"]);
+    client.set(b"k", b"v").await.unwrap();
+    let id = client.request_id().unwrap().to_string();
+    assert!(id.starts_with(&format!("{}-", hello.node_id)), "{}", id);
+    client.get(b"k").await.unwrap();
+    client.set(b"k", b"w").await.unwrap();
+    assert_ne!(client.request_id().unwrap(), id);
+
+    // Clients that didn't ask aren't sent the ID.
+    let mut other = cluster.client().await.unwrap();
+    other.set(b"other", b"v").await.unwrap();
+    assert_eq!(other.request_id(), None);
+
+    cluster.wait_for_replication().await.unwrap();
+    let marker = format!("REQID {}\nSET k v\n", id);
+    for log in ["leader.log", "follower-0.log"] {
+        let log = std::fs::read_to_string(cluster.dir().join(log)).unwrap();
+        assert!(log.contains(&marker), "{}", log);
+    }
+    assert_eq!(cluster.follower_hashmap(0).unwrap().len(), 2);
+}
+
+#[tokio::test]
+async fn replication_is_batched_until_a_size_or_time_threshold() {
+    let config = Config {
+        replication_batch_size: 1024,
+        replication_flush_ms: 300,
+        ..Config::default()
+    };
+    let cluster = TestCluster::start_with_config(1, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"small", b"1").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert_eq!(get(cluster.follower_hashmap(0), "small"), None);
+    cluster.wait_for_replication().await.unwrap();
+    assert_eq!(
+        get(cluster.follower_hashmap(0), "small"),
+        Some(Bytes::from("1"))
+    );
+
+    // A batch as large as the threshold is written without waiting.
+    client.set(b"large", &[b'x'; 2048]).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(get(cluster.follower_hashmap(0), "large").is_some());
+}
+
+#[tokio::test]
+async fn replication_batching_can_be_turned_off() {
+    let config = Config {
+        replication_flush_ms: 0,
+        ..Config::default()
+    };
+    let cluster = TestCluster::start_with_config(1, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"a", b"1").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    assert_eq!(
+        get(cluster.follower_hashmap(0), "a"),
+        Some(Bytes::from("1"))
+    );
+}
+
+#[tokio::test]
+async fn replication_is_compressed_when_the_leader_asks() {
+    let uncompressed = TestCluster::start(0).await.unwrap();
+    let mut client = uncompressed.client().await.unwrap();
+    let hello = client.hello(&["lz4"]).await.unwrap();
+    assert!(hello.features.is_empty());
+
+    let config = Config {
+        replication_compression: true,
+        ..Config::default()
+    };
+    let mut cluster = TestCluster::start_with_config(1, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let hello = client.hello(&["lz4"]).await.unwrap();
+    assert_eq!(hello.features, ["lz4"]);
+    let val = b"compressible ".repeat(100);
+    for i in 0..100 {
+        client
+            .set(format!("key:{}", i).as_bytes(), &val)
+            .await
+            .unwrap();
+    }
+    // One follower is introduced with REPLICATE, the other syncs.
+    let added = cluster.add_follower().await.unwrap();
+    client.set(b"after", b"sync").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+    for i in [0, added] {
+        let map = cluster.follower_hashmap(i);
+        assert_eq!(get(map.clone(), "key:99"), Some(Bytes::from(val.clone())));
+        assert_eq!(get(map, "after"), Some(Bytes::from("sync")));
+    }
+}
+
+/// Sends `n` writes in one go, so that the leader queues them for its
+/// followers faster than they are written out.
+async fn pipeline_sets(cluster: &TestCluster, n: usize) {
+    let mut client = cluster.client().await.unwrap();
+    let mut requests = BytesMut::new();
+    for i in 0..n {
+        let key = format!("key:{}", i);
+        Command::Set(key.as_bytes().into(), b"value"[..].into()).encode(&mut requests);
+    }
+    client.send_raw(&requests).await.unwrap();
+    for _ in 0..n {
+        assert_eq!(
+            client.read_reply().await.unwrap(),
+            Reply::Status("OK".into())
+        );
+    }
+}
+
+#[tokio::test]
+async fn followers_behind_a_full_queue_still_get_every_write() {
+    let spill_dir = std::env::temp_dir().join(format!("dist-kv-spill-{}", std::process::id()));
+    std::fs::create_dir_all(&spill_dir).unwrap();
+    let config = Config {
+        replication_queue_size: 1,
+        replication_overflow: Overflow::Spill,
+        replication_spill_dir: spill_dir.to_str().unwrap().to_string(),
+        ..Config::default()
+    };
+    let cluster = TestCluster::start_with_config(1, config).await.unwrap();
+    pipeline_sets(&cluster, 200).await;
+    cluster.wait_for_replication().await.unwrap();
+    assert_eq!(cluster.follower_hashmap(0).unwrap().len(), 200);
+    drop(cluster);
+    std::fs::remove_dir_all(spill_dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_dropped_follower_syncs_again() {
+    let config = Config {
+        replication_queue_size: 1,
+        replication_overflow: Overflow::Drop,
+        ..Config::default()
+    };
+    let mut cluster = TestCluster::start_with_config(0, config).await.unwrap();
+    let i = cluster.add_follower().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"synced", b"1").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+
+    pipeline_sets(&cluster, 200).await;
+    cluster.wait_for_replication().await.unwrap();
+    assert_eq!(cluster.follower_hashmap(i).unwrap().len(), 201);
+}
+
+#[tokio::test]
+async fn the_leader_refuses_writes_while_its_disk_is_nearly_full() {
+    let config = Config {
+        min_free_space: usize::MAX,
+        ..Config::default()
+    };
+    let cluster = TestCluster::start_with_config(0, config).await.unwrap();
+    let leader = cluster.leader().unwrap();
+    let mut client = cluster.client().await.unwrap();
+    leader.lock().await.check_disk().unwrap();
+
+    let set = Command::Set(b"key"[..].into(), b"value"[..].into());
+    match client.call(&set).await.unwrap() {
+        Reply::Error(err) => assert!(err.starts_with("ERR READONLY"), "{}", err),
+        reply => panic!("expected a READONLY error, got {:?}", reply),
+    }
+    assert_eq!(client.get(b"key").await.unwrap(), None);
+    let section = Some(Cow::Borrowed(&b"persistence"[..]));
+    let Reply::Bulk(Some(info)) = client.call(&Command::Info(section)).await.unwrap() else {
+        panic!("INFO did not return a bulk reply");
+    };
+    assert!(String::from_utf8_lossy(&info).contains("read_only:1\n"));
+
+    client.config_set("min_free_space", "0").await.unwrap();
+    leader.lock().await.check_disk().unwrap();
+    client.set(b"key", b"value").await.unwrap();
+    assert_eq!(
+        client.get(b"key").await.unwrap(),
+        Some(Bytes::from("value"))
+    );
+}
+
+#[tokio::test]
+async fn read_only_mode_refuses_writes_but_serves_reads() {
+    let config = Config {
+        read_only: true,
+        ..Config::default()
+    };
+    let cluster = TestCluster::start_with_config(0, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let set = Command::Set(b"key"[..].into(), b"value"[..].into());
+    match client.call(&set).await.unwrap() {
+        Reply::Error(err) => assert!(err.starts_with("ERR READONLY"), "{}", err),
+        reply => panic!("expected a READONLY error, got {:?}", reply),
+    }
+
+    client.read_only(false).await.unwrap();
+    client.set(b"key", b"value").await.unwrap();
+    client.read_only(true).await.unwrap();
+    match client.call(&set).await.unwrap() {
+        Reply::Error(err) => assert!(err.starts_with("ERR READONLY"), "{}", err),
+        reply => panic!("expected a READONLY error, got {:?}", reply),
+    }
+    assert_eq!(
+        client.get(b"key").await.unwrap(),
+        Some(Bytes::from("value"))
+    );
+
+    // The disk check taking writes again doesn't lift read-only mode.
+    cluster.leader().unwrap().lock().await.check_disk().unwrap();
+    assert!(matches!(client.call(&set).await.unwrap(), Reply::Error(_)));
+    client.read_only(false).await.unwrap();
+    client.del(b"key").await.unwrap();
+    assert_eq!(client.get(b"key").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn commands_are_refused_while_the_log_is_loading() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"key", b"value").await.unwrap();
+    cluster.leader().unwrap().lock().await.core.loading = Some(LoadProgress {
+        records: 3,
+        bytes_read: 100,
+        total_bytes: 400,
+    });
+
+    let set = Command::Set(b"other"[..].into(), b"value"[..].into());
+    let get = Command::Get(b"key"[..].into());
+    for command in [&set, &get] {
+        match client.call(command).await.unwrap() {
+            Reply::Error(err) => {
+                let err = DistKvError::from_line(&err);
+                assert!(matches!(err, DistKvError::Storage(_)), "{:?}", err);
+                assert_eq!(err.code(), Some(ErrorCode::Loading));
+            }
+            reply => panic!("expected a LOADING error, got {:?}", reply),
+        }
+    }
+    let section = Some(Cow::Borrowed(&b"persistence"[..]));
+    let Reply::Bulk(Some(info)) = client.call(&Command::Info(section)).await.unwrap() else {
+        panic!("INFO did not return a bulk reply");
+    };
+    let info = String::from_utf8_lossy(&info);
+    for line in [
+        "loading:1\n",
+        "loading_loaded_records:3\n",
+        "loading_loaded_bytes:100\n",
+        "loading_total_bytes:400\n",
+    ] {
+        assert!(info.contains(line), "{}", info);
+    }
+
+    cluster.leader().unwrap().lock().await.core.loading = None;
+    client.set(b"other", b"value").await.unwrap();
+    assert_eq!(
+        client.get(b"key").await.unwrap(),
+        Some(Bytes::from("value"))
+    );
+    let section = Some(Cow::Borrowed(&b"persistence"[..]));
+    let Reply::Bulk(Some(info)) = client.call(&Command::Info(section)).await.unwrap() else {
+        panic!("INFO did not return a bulk reply");
+    };
+    assert!(String::from_utf8_lossy(&info).contains("loading:0\n"));
+}
+
+#[tokio::test]
+async fn get_at_reads_a_key_as_of_a_past_write() {
+    let config = Config {
+        history_size: 4,
+        ..Config::default()
+    };
+    let cluster = TestCluster::start_with_config(1, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"a", b"1").await.unwrap();
+    client.set(b"a", b"2").await.unwrap();
+    client.del(b"a").await.unwrap();
+    client.set(b"b", b"x").await.unwrap();
+    client.select(b"staging").await.unwrap();
+    client.set(b"a", b"staged").await.unwrap();
+    assert_eq!(cluster.leader().unwrap().lock().await.core.lsn, 5);
+
+    assert_eq!(client.get_at(b"a", 4).await.unwrap(), None);
+    assert_eq!(
+        client.get_at(b"a", 5).await.unwrap(),
+        Some(Bytes::from("staged"))
+    );
+    client.select(b"0").await.unwrap();
+    assert_eq!(
+        client.get_at(b"a", 1).await.unwrap(),
+        Some(Bytes::from("1"))
+    );
+    assert_eq!(
+        client.get_at(b"a", 2).await.unwrap(),
+        Some(Bytes::from("2"))
+    );
+    assert_eq!(client.get_at(b"a", 3).await.unwrap(), None);
+    assert_eq!(client.get_at(b"b", 3).await.unwrap(), None);
+    assert_eq!(
+        client.get_at(b"b", 5).await.unwrap(),
+        Some(Bytes::from("x"))
+    );
+
+    // Only the last four writes are kept, and later LSNs aren't written yet.
+    for lsn in [0, 6] {
+        match client
+            .call(&Command::GetAt(b"a"[..].into(), lsn))
+            .await
+            .unwrap()
+        {
+            Reply::Error(err) => assert!(err.starts_with("ERR "), "{}", err),
+            reply => panic!("expected an error, got {:?}", reply),
+        }
+    }
+
+    // A key that expires is read as it was before.
+    let set = Command::SetWith(
+        b"c"[..].into(),
+        b"short"[..].into(),
+        SetOptions {
+            expiry: Some(Expiry::In(1)),
+            ..SetOptions::default()
+        },
+    );
+    client.call(&set).await.unwrap();
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    client.set(b"d", b"later").await.unwrap();
+    assert_eq!(client.get(b"c").await.unwrap(), None);
+    assert_eq!(
+        client.get_at(b"c", 6).await.unwrap(),
+        Some(Bytes::from("short"))
+    );
+
+    let mut follower = DistKvClient::connect(cluster.follower_addr(0))
+        .await
+        .unwrap();
+    match follower
+        .call(&Command::GetAt(b"a"[..].into(), 1))
+        .await
+        .unwrap()
+    {
+        Reply::Error(err) => assert!(err.starts_with("ERR NOTSUPPORTED"), "{}", err),
+        reply => panic!("expected a NOTSUPPORTED error, got {:?}", reply),
+    }
+}
+
+#[tokio::test]
+async fn history_lists_the_last_values_a_key_was_written_with() {
+    let config = Config {
+        history_size: 5,
+        ..Config::default()
+    };
+    let cluster = TestCluster::start_with_config(1, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"a", b"1").await.unwrap();
+    client.set(b"a", b"2").await.unwrap();
+    client.set(b"b", b"x").await.unwrap();
+    client.del(b"a").await.unwrap();
+    client.set(b"a", b"3 \"quoted\"").await.unwrap();
+
+    let history = client.history(b"a", None).await.unwrap();
+    let versions: Vec<Vec<&str>> = history
+        .lines()
+        .map(|line| line.splitn(3, ' ').collect())
+        .collect();
+    let lsns: Vec<&str> = versions.iter().map(|version| version[0]).collect();
+    assert_eq!(lsns, ["5", "4", "2", "1"]);
+    let values: Vec<&str> = versions.iter().map(|version| version[2]).collect();
+    assert_eq!(values, [r#""3 \"quoted\"""#, "nil", r#""2""#, r#""1""#]);
+    let times: Vec<u64> = versions
+        .iter()
+        .map(|version| version[1].parse().unwrap())
+        .collect();
+    assert!(
+        times.windows(2).all(|pair| pair[0] >= pair[1]),
+        "{}",
+        history
+    );
+
+    let newest = client.history(b"a", Some(2)).await.unwrap();
+    assert_eq!(newest.lines().count(), 2);
+    assert!(newest.starts_with("5 "), "{}", newest);
+    assert_eq!(client.history(b"missing", None).await.unwrap(), "");
+
+    // Only the writes kept are listed.
+    client.set(b"b", b"y").await.unwrap();
+    assert_eq!(client.history(b"a", None).await.unwrap().lines().count(), 3);
+}
+
+#[tokio::test]
+async fn client_errors_are_sorted_by_kind() {
+    let mut config = Config::default();
+    config.set("tenant", "billing s3cret billing:").unwrap();
+    let cluster = TestCluster::start_with_config(1, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let err = client.set(b"billing:a", b"1").await.unwrap_err();
+    assert!(matches!(err, DistKvError::Auth(_)), "{:?}", err);
+    assert_eq!(err.code(), Some(ErrorCode::NoAuth));
+    let err = client.auth(b"billing", b"wrong").await.unwrap_err();
+    assert!(matches!(err, DistKvError::Auth(_)), "{:?}", err);
+    client.auth(b"billing", b"s3cret").await.unwrap();
+    client.set(b"billing:a", b"1").await.unwrap();
+    cluster.wait_for_replication().await.unwrap();
+
+    let mut follower = DistKvClient::connect(cluster.follower_addr(0))
+        .await
+        .unwrap();
+    match follower.get_with(b"billing:a", Consistency::Quorum).await {
+        Err(DistKvError::NotLeader { leader }) => {
+            assert_eq!(leader, cluster.leader_addr().to_string())
+        }
+        other => panic!("expected the leader's address, got {:?}", other),
+    }
+
+    let unknown = DistKvError::from_line("ERR NEWCODE from a newer server");
+    assert!(matches!(unknown, DistKvError::Protocol(_)));
+    assert_eq!(unknown.to_string(), "ERR NEWCODE from a newer server");
+    let reply = ErrorReply::new(ErrorCode::NoQuorum, "heard from 1 of 3");
+    let err = DistKvError::from(reply.clone());
+    assert!(matches!(err, DistKvError::Replication(_)));
+    assert_eq!(err.reply(), Some(reply));
+    assert_eq!(err.to_string(), "ERR NOQUORUM heard from 1 of 3");
+}
+
+#[tokio::test]
+async fn locks_hand_out_increasing_fencing_tokens() {
+    let cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let mut other = cluster.client().await.unwrap();
+    let ttl = Duration::from_secs(30);
+
+    let first = client.lock(b"lock", ttl).await.unwrap().unwrap();
+    assert_eq!(other.lock(b"lock", ttl).await.unwrap(), None);
+    assert!((29..=30).contains(&client.ttl(b"lock").await.unwrap()));
+    assert!(!other.unlock(b"lock", first + 1).await.unwrap());
+    assert!(client.unlock(b"lock", first).await.unwrap());
+    assert!(!client.unlock(b"lock", first).await.unwrap());
+
+    let second = other.lock(b"lock", ttl).await.unwrap().unwrap();
+    assert!(second > first);
+    client.set(b"unrelated", b"1").await.unwrap();
+    assert!(other.unlock(b"lock", second).await.unwrap());
+
+    // A lease that runs out frees the lock for the next holder, whose token
+    // is larger than the one the lapsed holder still has.
+    let lapsed = client
+        .lock(b"lock", Duration::from_millis(20))
+        .await
+        .unwrap()
+        .unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    let next = other.lock(b"lock", ttl).await.unwrap().unwrap();
+    assert!(next > lapsed);
+    assert!(!client.unlock(b"lock", lapsed).await.unwrap());
+
+    // The lock is replicated as its token.
+    cluster.wait_for_replication().await.unwrap();
+    let mut follower = DistKvClient::connect(cluster.follower_addr(0))
+        .await
+        .unwrap();
+    let held = follower
+        .get_with(b"lock", Consistency::Local)
+        .await
+        .unwrap();
+    assert_eq!(held.unwrap(), next.to_string());
+
+    for bad in ["LOCK k 0", "LOCK k soon", "UNLOCK k", "UNLOCK k token"] {
+        client
+            .send_raw(format!("{}\n", bad).as_bytes())
+            .await
+            .unwrap();
+        let reply = client.read_reply().await.unwrap();
+        assert!(matches!(reply, Reply::Error(_)), "{}: {:?}", bad, reply);
+    }
+}
+
+#[tokio::test]
+async fn fencing_tokens_keep_increasing_across_restarts() {
+    let mut config = Config::default();
+    config.set("namespace", "cache memory").unwrap();
+    let mut cluster = TestCluster::start_with_config(1, config).await.unwrap();
+    let ttl = Duration::from_secs(30);
+    let mut client = cluster.client().await.unwrap();
+    let first = client.lock(b"lock", ttl).await.unwrap().unwrap();
+
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    assert_eq!(client.lock(b"lock", ttl).await.unwrap(), None);
+    let second = client.lock(b"other", ttl).await.unwrap().unwrap();
+    assert!(second > first, "{} after {}", second, first);
+
+    // A namespace kept only in memory loses its locks, but not the LSNs
+    // they were taken at.
+    client.select(b"cache").await.unwrap();
+    let third = client.lock(b"lock", ttl).await.unwrap().unwrap();
+    assert!(third > second, "{} after {}", third, second);
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.select(b"cache").await.unwrap();
+    let fourth = client.lock(b"lock", ttl).await.unwrap().unwrap();
+    assert!(fourth > third, "{} after {}", fourth, third);
+
+    // Nor does replaying from a checkpoint rather than the whole log.
+    checkpoint::take(cluster.leader().unwrap()).await.unwrap();
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let fifth = client.lock(b"last", ttl).await.unwrap().unwrap();
+    assert!(fifth > fourth, "{} after {}", fifth, fourth);
+}
+
+#[tokio::test]
+async fn ephemeral_keys_go_with_their_session() {
+    let mut config = Config::default();
+    config.set("session_timeout_ms", "300").unwrap();
+    let cluster = TestCluster::start_with_config(1, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let mut owner = cluster.client().await.unwrap();
+    owner.ephemeral(b"service:a", b"10.0.0.1").await.unwrap();
+    owner.ephemeral(b"service:b", b"10.0.0.2").await.unwrap();
+    client.set(b"service:b", b"taken over").await.unwrap();
+    assert_eq!(client.get(b"service:a").await.unwrap().unwrap(), "10.0.0.1");
+
+    // Closing the connection deletes its keys, except one written since.
+    drop(owner);
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while client.get(b"service:a").await.unwrap().is_some() {
+        assert!(
+            Instant::now() < deadline,
+            "the ephemeral key outlived its session"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(
+        client.get(b"service:b").await.unwrap().unwrap(),
+        "taken over"
+    );
+    cluster.wait_for_replication().await.unwrap();
+    let mut follower = DistKvClient::connect(cluster.follower_addr(0))
+        .await
+        .unwrap();
+    let local = Consistency::Local;
+    assert_eq!(follower.get_with(b"service:a", local).await.unwrap(), None);
+
+    // A session that keeps sending commands keeps its keys; one that goes
+    // quiet is closed once its lease runs out.
+    let mut owner = cluster.client().await.unwrap();
+    owner.ephemeral(b"service:c", b"10.0.0.3").await.unwrap();
+    for _ in 0..5 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        owner.whoami().await.unwrap();
+    }
+    assert!(client.get(b"service:c").await.unwrap().is_some());
+    tokio::time::sleep(Duration::from_millis(600)).await;
+    assert_eq!(client.get(b"service:c").await.unwrap(), None);
+    assert!(owner.whoami().await.is_err());
+}
+
+#[tokio::test]
+async fn cached_reads_are_invalidated_by_writes() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let mut writer = cluster.client().await.unwrap();
+    writer.set(b"config", b"v1").await.unwrap();
+    client.enable_caching(100).await.unwrap();
+
+    assert_eq!(client.get(b"config").await.unwrap().unwrap(), "v1");
+    assert!(client.is_cached(b"config"));
+    assert_eq!(client.get(b"missing").await.unwrap(), None);
+    assert!(client.is_cached(b"missing"));
+    let info = Command::Info(Some(b"clients"[..].into()));
+    match client.call(&info).await.unwrap() {
+        Reply::Bulk(Some(info)) => {
+            assert!(String::from_utf8_lossy(&info).contains("tracked_keys:2"))
+        }
+        reply => panic!("expected INFO, got {:?}", reply),
+    }
+
+    // Another client's write invalidates the cached value.
+    writer.set(b"config", b"v2").await.unwrap();
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while client.get(b"config").await.unwrap().unwrap() != "v2" {
+        assert!(
+            Instant::now() < deadline,
+            "the cached value was never invalidated"
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+
+    // The client's own writes never leave a stale value behind.
+    client.set(b"missing", b"now here").await.unwrap();
+    assert_eq!(client.get(b"missing").await.unwrap().unwrap(), "now here");
+
+    // Keys are cached per namespace.
+    client.select(b"other").await.unwrap();
+    assert!(!client.is_cached(b"config"));
+    assert_eq!(client.get(b"config").await.unwrap(), None);
+}
+
+#[tokio::test]
+async fn waitchange_blocks_until_the_key_is_written() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut watcher = cluster.client().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"config", b"v1").await.unwrap();
+
+    let short = Some(Duration::from_millis(50));
+    assert_eq!(watcher.wait_change(b"config", short).await.unwrap(), None);
+
+    let watching = tokio::spawn(async move {
+        let changed = watcher.wait_change(b"config", None).await.unwrap();
+        (watcher, changed)
+    });
+    // Writes to other keys don't wake it.
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    client.set(b"other", b"x").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(!watching.is_finished());
+    client.set(b"config", b"v2").await.unwrap();
+    let (mut watcher, changed) = watching.await.unwrap();
+    let (lsn, val) = changed.unwrap();
+    assert_eq!(val.unwrap(), "v2");
+    assert_eq!(lsn, 3);
+
+    let watching = tokio::spawn(async move { watcher.wait_change(b"config", None).await });
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(client.del(b"config").await.unwrap());
+    assert_eq!(watching.await.unwrap().unwrap(), Some((4, None)));
+}
+
+#[tokio::test]
+async fn retried_writes_with_a_request_id_are_applied_once() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let push =
+        |element: &'static str| Command::RPush("jobs".as_bytes().into(), element.as_bytes().into());
+    let name = |name: &'static str| Command::Client(ClientCommand::SetName(name.as_bytes().into()));
+
+    let mut client = cluster.client().await.unwrap();
+    client.call(&name("worker")).await.unwrap();
+    let first = client.call_once(&push("a"), "r1").await.unwrap();
+    assert_eq!(first, Reply::Integer(1));
+    // The retry is answered as the write was, not applied again.
+    assert_eq!(client.call_once(&push("a"), "r1").await.unwrap(), first);
+    assert_eq!(
+        client.call_once(&push("b"), "r2").await.unwrap(),
+        Reply::Integer(2)
+    );
+    // Reads aren't remembered, and a REQID only names the next command.
+    client
+        .call_once(&Command::LLen("jobs".as_bytes().into()), "r3")
+        .await
+        .unwrap();
+    client.call(&push("c")).await.unwrap();
+    assert_eq!(client.llen(b"jobs").await.unwrap(), 3);
+
+    // A client that reconnects under the same name is recognized.
+    drop(client);
+    let mut client = cluster.client().await.unwrap();
+    client.call(&name("worker")).await.unwrap();
+    assert_eq!(
+        client.call_once(&push("b"), "r2").await.unwrap(),
+        Reply::Integer(2)
+    );
+    // Others' IDs are their own.
+    let mut other = cluster.client().await.unwrap();
+    assert_eq!(
+        other.call_once(&push("d"), "r2").await.unwrap(),
+        Reply::Integer(4)
+    );
+    assert_eq!(
+        other.call_once(&push("d"), "r2").await.unwrap(),
+        Reply::Integer(4)
+    );
+    assert_eq!(client.llen(b"jobs").await.unwrap(), 4);
+
+    // Only the most recent IDs are remembered.
+    client
+        .config_set("request_dedup_window", "1")
+        .await
+        .unwrap();
+    client.call_once(&push("e"), "r4").await.unwrap();
+    client.call_once(&push("f"), "r5").await.unwrap();
+    assert_eq!(
+        client.call_once(&push("e"), "r4").await.unwrap(),
+        Reply::Integer(7)
+    );
+
+    // And only for a while after the client last sent one, even if it has
+    // a name.
+    client
+        .config_set("request_dedup_timeout_ms", "200")
+        .await
+        .unwrap();
+    client.call_once(&push("g"), "r6").await.unwrap();
+    assert_eq!(
+        client.call_once(&push("g"), "r6").await.unwrap(),
+        Reply::Integer(8)
+    );
+    tokio::time::sleep(Duration::from_millis(400)).await;
+    assert_eq!(
+        client.call_once(&push("g"), "r6").await.unwrap(),
+        Reply::Integer(9)
+    );
+}
+
+#[tokio::test]
+async fn clients_resume_their_session_after_the_connection_fails() {
+    let mut cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.enable_retries(RetryPolicy::default());
+    client.set_name(b"resumer").await.unwrap();
+    client.select(b"app").await.unwrap();
+    client.set(b"k", b"v").await.unwrap();
+    let mut subscriber = cluster.client().await.unwrap();
+    subscriber.enable_retries(RetryPolicy::default());
+    subscriber.subscribe(b"news").await.unwrap();
+
+    cluster.restart_leader().await.unwrap();
+    // Reads are retried on a new connection, in the namespace the old one
+    // had selected and under its name.
+    assert_eq!(client.get(b"k").await.unwrap().unwrap(), "v");
+    let mut admin = cluster.client().await.unwrap();
+    let Reply::Bulk(Some(list)) = admin
+        .call(&Command::Client(ClientCommand::List))
+        .await
+        .unwrap()
+    else {
+        panic!("CLIENT LIST did not return a bulk value");
+    };
+    assert!(String::from_utf8_lossy(&list).contains(" name=resumer "));
+
+    // The subscriber subscribes again, missing what was published before.
+    let listening = tokio::spawn(async move { subscriber.next_push().await.unwrap() });
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while admin.publish(b"news", b"hello").await.unwrap() == 0 {
+        assert!(Instant::now() < deadline, "the subscriber never came back");
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(listening.await.unwrap(), ["message", "news", "hello"]);
+
+    // Writes that aren't safe to send twice aren't retried, but the client
+    // connects again the next time it is used.
+    cluster.restart_leader().await.unwrap();
+    let push = Command::RPush("jobs".as_bytes().into(), "a".as_bytes().into());
+    assert!(matches!(client.call(&push).await, Err(DistKvError::Io(_))));
+    assert_eq!(client.llen(b"jobs").await.unwrap(), 0);
+    // Unless they are sent under a request ID.
+    cluster.restart_leader().await.unwrap();
+    let pushed = client.call_once(&push, "job-a").await.unwrap();
+    assert_eq!(pushed, Reply::Integer(1));
+}
+
+#[tokio::test]
+async fn unnamed_clients_do_not_retry_writes_under_a_request_id() {
+    let mut cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.enable_retries(RetryPolicy::default());
+    let push = Command::RPush("jobs".as_bytes().into(), "a".as_bytes().into());
+    client.call_once(&push, "job-a").await.unwrap();
+
+    // Without a name, the leader can't tell a retry on a new connection
+    // from a new write, so the client doesn't retry.
+    cluster.restart_leader().await.unwrap();
+    let push = Command::RPush("jobs".as_bytes().into(), "b".as_bytes().into());
+    let err = client.call_once(&push, "job-b").await;
+    assert!(matches!(err, Err(DistKvError::Io(_))), "{:?}", err);
+    assert_eq!(client.llen(b"jobs").await.unwrap(), 1);
+}
@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+use std::mem::size_of;
+
+use bytes::Bytes;
+use dist_kv::compact::{CompactBytes, INLINE};
+use dist_kv::store::Db;
+
+#[test]
+fn short_strings_are_inline_and_long_ones_are_not() {
+    assert_eq!(size_of::<CompactBytes>(), 24);
+    for len in [0, 1, INLINE - 1, INLINE, INLINE + 1, 1000] {
+        let bytes: Vec<u8> = (0..len).map(|i| i as u8).collect();
+        let compact = CompactBytes::copy_from_slice(&bytes);
+        assert_eq!(compact.is_inline(), len <= INLINE, "{} bytes", len);
+        assert_eq!(&compact[..], &bytes[..]);
+        assert_eq!(CompactBytes::from(bytes.clone()), compact);
+        assert_eq!(compact.to_bytes(), Bytes::from(bytes));
+    }
+}
+
+#[test]
+fn strings_compare_and_hash_as_their_bytes() {
+    let long = vec![b'x'; INLINE * 2];
+    let mut map = HashMap::new();
+    map.insert(CompactBytes::from("short"), 1);
+    map.insert(CompactBytes::from(&long[..]), 2);
+    assert_eq!(map.get(&b"short"[..]), Some(&1));
+    assert_eq!(map.get(&long[..]), Some(&2));
+    let (inline, heap) = (CompactBytes::from("a"), CompactBytes::from(long));
+    assert!(inline < heap);
+    assert_eq!(
+        format!("{:?}", CompactBytes::from("a\"b\n\x00")),
+        r#"b"a\"b\n\x00""#
+    );
+}
+
+#[test]
+fn values_of_any_length_round_trip_through_the_map() {
+    let mut hashmap = Db::new();
+    for len in [0, INLINE, INLINE + 1, 4096] {
+        let key = format!("key:{}", len);
+        hashmap.insert(key.as_str().into(), vec![7; len].into());
+    }
+    for len in [0, INLINE, INLINE + 1, 4096] {
+        let val = &hashmap[format!("key:{}", len).as_bytes()];
+        assert_eq!(&val[..], &vec![7; len][..]);
+    }
+}
@@ -0,0 +1,234 @@
+//! Kills a leader at a crash point and checks what a restart recovers.
+//!
+//! Each test re-runs this test binary as a child process that performs a
+//! fixed sequence of writes with a failpoint armed to crash it partway
+//! through. The parent then recovers the log the child left behind. Every
+//! acknowledged write must survive, and the recovered map must be that of
+//! some prefix of the writes.
+
+use std::io::Read;
+use std::net::TcpListener;
+use std::path::{Path, PathBuf};
+use std::process::{self, Stdio};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use bytes::BytesMut;
+use dist_kv::clock::SystemClock;
+use dist_kv::failpoint::{self, Action};
+use dist_kv::node::{FollowerCore, LeaderCore};
+use dist_kv::protocol::Command;
+use dist_kv::replication::ReplicaStream;
+use dist_kv::store::{apply, replay, replay_with, Db, Strictness};
+use dist_kv::wal::{open_log, open_log_with};
+
+const CHILD_ENV: &str = "DIST_KV_CRASH_CHILD";
+const WRITES: usize = 20;
+const CRASH_AT: usize = 12;
+
+fn write(i: usize) -> Command<'static> {
+    let key = format!("key:{}", i % 5).into_bytes();
+    match i % 4 {
+        3 => Command::Delete(key.into()),
+        _ => Command::Set(key.into(), format!("val:{}", i).into_bytes().into()),
+    }
+}
+
+/// The map after the first `n` writes.
+fn state_after(n: usize) -> Db {
+    let mut hashmap = Db::new();
+    for i in 0..n {
+        apply(&mut hashmap, &write(i));
+    }
+    hashmap
+}
+
+/// Runs in the child process, printing `acked <i>` for each write that
+/// returned. Does nothing when run as an ordinary test.
+#[test]
+fn crash_child() {
+    let Ok(spec) = std::env::var(CHILD_ENV) else {
+        return;
+    };
+    let mut spec = spec.split(' ');
+    let (name, log, replica) = (spec.next().unwrap(), spec.next().unwrap(), spec.next());
+    let (hashmap, file) = open_log(log).unwrap();
+    let mut core = LeaderCore::new(hashmap, file, SystemClock::default());
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut stream = replica.map(|addr| {
+        let stream = runtime.block_on(tokio::net::TcpStream::connect(addr));
+        ReplicaStream::new(stream.unwrap())
+    });
+    for i in 0..WRITES {
+        if i == CRASH_AT {
+            failpoint::set(name, Action::Crash);
+        }
+        let (_, record) = core.execute(&write(i)).unwrap();
+        if let (Some(stream), Some(record)) = (stream.as_mut(), record) {
+            runtime.block_on(stream.send(&record)).unwrap();
+        }
+        println!("acked {}", i);
+    }
+}
+
+fn temp_dir() -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    let dir = std::env::temp_dir().join(format!("dist-kv-crash-{}-{}", process::id(), n));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// Runs the child until it crashes at `failpoint`, returning how many writes
+/// it acknowledged.
+fn run_child(failpoint: &str, log: &Path, replica: Option<String>) -> usize {
+    let mut spec = format!("{} {}", failpoint, log.to_str().unwrap());
+    if let Some(addr) = replica {
+        spec = format!("{} {}", spec, addr);
+    }
+    let output = process::Command::new(std::env::current_exe().unwrap())
+        .args(["crash_child", "--exact", "--nocapture", "--test-threads=1"])
+        .env(CHILD_ENV, spec)
+        .stderr(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!output.status.success(), "child did not crash");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    // The harness's own output may share a line with the first write.
+    stdout.matches("acked ").count()
+}
+
+/// Crashes a leader at `failpoint` and returns how many writes it
+/// acknowledged and how many the restarted leader recovered.
+fn crash_and_recover(failpoint: &str) -> (usize, usize) {
+    let dir = temp_dir();
+    let log = dir.join("leader.log");
+    let acked = run_child(failpoint, &log, None);
+    assert_eq!(acked, CRASH_AT);
+
+    let (recovered, file) = open_log(log.to_str().unwrap()).unwrap();
+    let prefix = (acked..=WRITES)
+        .find(|&n| state_after(n) == recovered)
+        .expect("recovered map is not a prefix of the writes");
+
+    // The recovered log must keep working: later appends replay cleanly.
+    let mut core = LeaderCore::new(recovered, file, SystemClock::default());
+    for i in prefix..WRITES {
+        core.execute(&write(i)).unwrap();
+    }
+    let log = std::fs::read(&log).unwrap();
+    assert_eq!(replay(&log).unwrap(), state_after(WRITES));
+    std::fs::remove_dir_all(dir).unwrap();
+    (acked, prefix)
+}
+
+#[test]
+fn crash_mid_append_drops_the_torn_record() {
+    assert_eq!(crash_and_recover("wal::append"), (CRASH_AT, CRASH_AT));
+}
+
+#[test]
+fn crash_before_fsync_keeps_the_written_record() {
+    // The process died, not the machine, so the unsynced write is still in
+    // the page cache.
+    assert_eq!(crash_and_recover("wal::sync"), (CRASH_AT, CRASH_AT + 1));
+}
+
+#[test]
+fn crash_after_fsync_keeps_the_unacknowledged_record() {
+    assert_eq!(
+        crash_and_recover("leader::after_sync"),
+        (CRASH_AT, CRASH_AT + 1)
+    );
+}
+
+#[test]
+fn crash_mid_replication_leaves_the_follower_on_a_prefix() {
+    let dir = temp_dir();
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap().to_string();
+    let receiver = std::thread::spawn(move || {
+        let (mut socket, _) = listener.accept().unwrap();
+        let mut received = Vec::new();
+        socket.read_to_end(&mut received).unwrap();
+        received
+    });
+    let leader_log = dir.join("leader.log");
+    let acked = run_child("replication::send", &leader_log, Some(addr));
+    assert_eq!(acked, CRASH_AT);
+
+    // The follower sees the stream end partway through a record, which it
+    // must leave unapplied.
+    let follower_log = dir.join("follower.log");
+    let (hashmap, file) = open_log(follower_log.to_str().unwrap()).unwrap();
+    let mut follower = FollowerCore::new(hashmap, file, SystemClock::default());
+    let mut received = BytesMut::from(&receiver.join().unwrap()[..]);
+    follower.receive(&mut received).unwrap();
+    assert!(!received.is_empty());
+    assert_eq!(follower.hashmap, state_after(acked));
+    let (recovered, _) = open_log(follower_log.to_str().unwrap()).unwrap();
+    assert_eq!(recovered, state_after(acked));
+
+    // The leader made the write durable before replicating it.
+    let (recovered, _) = open_log(leader_log.to_str().unwrap()).unwrap();
+    assert_eq!(recovered, state_after(acked + 1));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+/// The records of `writes`, encoded back to back.
+fn records(writes: std::ops::Range<usize>) -> BytesMut {
+    let mut log = BytesMut::new();
+    for i in writes {
+        write(i).encode(&mut log);
+    }
+    log
+}
+
+#[test]
+fn skip_recovery_replays_around_a_corrupt_record() {
+    let mut log = records(0..3);
+    let corrupt = log.len();
+    log.extend_from_slice(b"SET key:0\n");
+    log.extend_from_slice(&records(3..6));
+
+    let err = replay_with(&log, Strictness::Strict).unwrap_err();
+    assert!(
+        err.to_string().contains(&format!("offset {}", corrupt)),
+        "{}",
+        err
+    );
+
+    let (hashmap, report) = replay_with(&log, Strictness::Skip).unwrap();
+    assert_eq!(hashmap, state_after(6));
+    assert_eq!(report.records, 6);
+    assert_eq!(report.skipped, [corrupt]);
+    assert_eq!(report.complete, log.len());
+}
+
+#[test]
+fn recovery_cuts_off_a_torn_tail() {
+    let dir = temp_dir();
+    let path = dir.join("leader.log");
+    let mut log = records(0..4);
+    let complete = log.len();
+    let mut torn = BytesMut::new();
+    write(4).encode(&mut torn);
+    log.extend_from_slice(&torn[..torn.len() - 3]);
+    std::fs::write(&path, &log).unwrap();
+
+    for strictness in [Strictness::Strict, Strictness::Skip] {
+        let (hashmap, report) = replay_with(&log, strictness).unwrap();
+        assert_eq!(hashmap, state_after(4));
+        assert_eq!(report.records, 4);
+        assert!(report.skipped.is_empty());
+        assert_eq!(report.complete, complete);
+    }
+
+    let (hashmap, _, report) = open_log_with(path.to_str().unwrap(), Strictness::Skip).unwrap();
+    assert_eq!(hashmap, state_after(4));
+    assert_eq!(report.complete, complete);
+    assert_eq!(std::fs::read(&path).unwrap(), &log[..complete]);
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,50 @@
+use dist_kv::cluster::TestCluster;
+use dist_kv::config::Config;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+async fn fetch(addr: std::net::SocketAddr, path: &str) -> String {
+    let mut stream = TcpStream::connect(addr).await.unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn the_dashboard_shows_the_leaders_figures() {
+    // A free port for the dashboard, given up again for the leader.
+    let addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut config = Config::default();
+    config.set("dashboard", &addr.to_string()).unwrap();
+    config.set("slow_query_ms", "0").unwrap();
+    let cluster = TestCluster::start_with_config(1, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"a", b"1").await.unwrap();
+    client.set(b"<b>", b"22").await.unwrap();
+    client.get(b"a").await.unwrap();
+
+    let stats = fetch(addr, "/stats").await;
+    assert!(stats.starts_with("HTTP/1.1 200 OK\r\n"), "{}", stats);
+    let body = stats.split("\r\n\r\n").nth(1).unwrap();
+    for field in [
+        "\"lsn\":2",
+        "\"keys\":2",
+        "\"keyspace_bytes\":7",
+        "\"name\":\"SET\",\"calls\":2,\"errors\":0",
+        "\"state\":\"streaming\"",
+        "\"command\":\"GET\",\"key\":\"a\"",
+    ] {
+        assert!(body.contains(field), "{} in {}", field, body);
+    }
+
+    let page = fetch(addr, "/").await;
+    assert!(page.starts_with("HTTP/1.1 200 OK\r\n"), "{}", page);
+    assert!(page.contains("text/html"));
+    assert!(page.contains("&lt;b&gt;") && !page.contains("<td><b>"));
+    assert!(fetch(addr, "/nowhere").await.starts_with("HTTP/1.1 404"));
+}
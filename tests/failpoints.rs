@@ -0,0 +1,170 @@
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use dist_kv::clock::SystemClock;
+use dist_kv::failpoint::{self, Action};
+use dist_kv::node::LeaderCore;
+use dist_kv::protocol::Command;
+use dist_kv::replication::ReplicaStream;
+use dist_kv::store::{self, Db};
+use tokio::io::AsyncReadExt;
+
+/// Failpoints are process-wide, so tests that arm them run one at a time.
+fn serial() -> MutexGuard<'static, ()> {
+    static LOCK: Mutex<()> = Mutex::new(());
+    let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    failpoint::clear();
+    guard
+}
+
+fn temp_log() -> PathBuf {
+    static NEXT: AtomicUsize = AtomicUsize::new(0);
+    let n = NEXT.fetch_add(1, Ordering::Relaxed);
+    std::env::temp_dir().join(format!(
+        "dist-kv-failpoints-{}-{}.log",
+        std::process::id(),
+        n
+    ))
+}
+
+fn set(key: &'static str, val: &'static str) -> Command<'static> {
+    Command::Set(key.as_bytes().into(), val.as_bytes().into())
+}
+
+fn encoded(commands: &[Command]) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    for command in commands {
+        command.encode(&mut buf);
+    }
+    buf.to_vec()
+}
+
+#[test]
+fn wal_append_error_fails_the_write() {
+    let _guard = serial();
+    let path = temp_log();
+    let file = File::create(&path).unwrap();
+    let mut core = LeaderCore::new(Db::new(), file, SystemClock::default());
+
+    core.execute(&set("a", "1")).unwrap();
+    failpoint::set_times("wal::append", Action::Error, 1);
+    assert!(core.execute(&set("b", "2")).is_err());
+    assert!(!core.hashmap.contains_key(&b"b"[..]));
+    assert_eq!(core.lsn, 1);
+
+    let db = store::replay(&std::fs::read(&path).unwrap()).unwrap();
+    assert_eq!(db.len(), 1);
+    assert!(db.contains_key(&b"a"[..]));
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn wal_short_write_leaves_a_torn_tail() {
+    let _guard = serial();
+    let path = temp_log();
+    let file = File::create(&path).unwrap();
+    let mut core = LeaderCore::new(Db::new(), file, SystemClock::default());
+
+    let (_, record) = core.execute(&set("a", "1")).unwrap();
+    failpoint::set_times("wal::append", Action::ShortWrite(4), 1);
+    assert!(core.execute(&set("b", "2")).is_err());
+
+    let log = std::fs::read(&path).unwrap();
+    assert_eq!(log.len(), record.unwrap().len() + 4);
+    let db = store::replay(&log).unwrap();
+    assert_eq!(db.len(), 1);
+    std::fs::remove_file(path).unwrap();
+}
+
+#[test]
+fn wal_sync_error_fails_the_write() {
+    let _guard = serial();
+    let path = temp_log();
+    let file = File::create(&path).unwrap();
+    let mut core = LeaderCore::new(Db::new(), file, SystemClock::default());
+
+    core.execute(&set("a", "1")).unwrap();
+    failpoint::set("wal::sync", Action::Error);
+    assert!(core.execute(&set("a", "2")).is_err());
+    assert!(core.execute(&set("b", "2")).is_err());
+    assert_eq!(core.hashmap.get(&b"a"[..]).unwrap()[..], b"1"[..]);
+    assert!(!core.hashmap.contains_key(&b"b"[..]));
+    assert_eq!(core.lsn, 1);
+    failpoint::remove("wal::sync");
+    core.execute(&set("b", "2")).unwrap();
+    assert!(core.hashmap.contains_key(&b"b"[..]));
+    std::fs::remove_file(path).unwrap();
+}
+
+fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap()
+        .block_on(future)
+}
+
+/// Sends `commands` through a replica stream and returns what the
+/// follower end received.
+async fn replicate(commands: &[Command<'_>]) -> Vec<u8> {
+    let (leader, mut follower) = tokio::io::duplex(4096);
+    let mut stream = ReplicaStream::new(leader);
+    for command in commands {
+        stream
+            .send(&encoded(std::slice::from_ref(command)))
+            .await
+            .unwrap();
+    }
+    stream.shutdown().await.unwrap();
+    let mut received = Vec::new();
+    follower.read_to_end(&mut received).await.unwrap();
+    received
+}
+
+#[test]
+fn replication_drop_loses_a_record() {
+    let _guard = serial();
+    failpoint::set_times("replication::send", Action::Drop, 1);
+    let received = block_on(replicate(&[set("a", "1"), set("b", "2")]));
+    assert_eq!(received, encoded(&[set("b", "2")]));
+}
+
+#[test]
+fn replication_duplicate_sends_twice() {
+    let _guard = serial();
+    failpoint::set_times("replication::send", Action::Duplicate, 1);
+    let received = block_on(replicate(&[set("a", "1"), set("b", "2")]));
+    assert_eq!(
+        received,
+        encoded(&[set("a", "1"), set("a", "1"), set("b", "2")])
+    );
+}
+
+#[test]
+fn replication_reorder_swaps_records() {
+    let _guard = serial();
+    failpoint::set_times("replication::send", Action::Reorder, 1);
+    let received = block_on(replicate(&[set("a", "1"), set("b", "2"), set("c", "3")]));
+    assert_eq!(
+        received,
+        encoded(&[set("b", "2"), set("a", "1"), set("c", "3")])
+    );
+}
+
+#[test]
+fn replication_delay_still_delivers() {
+    let _guard = serial();
+    failpoint::set_times(
+        "replication::send",
+        Action::Delay(Duration::from_millis(20)),
+        1,
+    );
+    let start = std::time::Instant::now();
+    let received = block_on(replicate(&[set("a", "1")]));
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    assert_eq!(received, encoded(&[set("a", "1")]));
+}
@@ -0,0 +1,112 @@
+use dist_kv::client::Reply;
+use dist_kv::cluster::TestCluster;
+use dist_kv::hyperloglog::HyperLogLog;
+use dist_kv::protocol::Command;
+
+fn assert_close(count: u64, expected: u64) {
+    let error = (count as f64 - expected as f64).abs() / expected as f64;
+    assert!(
+        error < 0.05,
+        "counted {}, expected about {}",
+        count,
+        expected
+    );
+}
+
+#[test]
+fn counts_are_close_to_the_number_of_distinct_elements() {
+    for n in [1u64, 10, 100, 1_000, 10_000, 100_000] {
+        let mut sketch = HyperLogLog::default();
+        for i in 0..n {
+            sketch.add(format!("element:{}", i).as_bytes());
+            // Duplicates don't count.
+            sketch.add(format!("element:{}", i / 2).as_bytes());
+        }
+        assert_close(sketch.count(), n);
+    }
+}
+
+#[test]
+fn merged_sketches_count_the_union() {
+    let (mut a, mut b) = (HyperLogLog::default(), HyperLogLog::default());
+    for i in 0..30_000 {
+        a.add(format!("{}", i).as_bytes());
+        b.add(format!("{}", i + 20_000).as_bytes());
+    }
+    a.merge(&b);
+    assert_close(a.count(), 50_000);
+
+    let restored = HyperLogLog::from_bytes(&a.to_bytes()).unwrap();
+    assert_eq!(restored.count(), a.count());
+    assert!(HyperLogLog::from_bytes(b"not a sketch").is_none());
+}
+
+async fn call(client: &mut dist_kv::client::DistKvClient, command: Command<'_>) -> Reply {
+    client.call(&command).await.unwrap()
+}
+
+#[tokio::test]
+async fn sketches_are_stored_as_replicated_values() {
+    let mut cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let pfadd = |key: &'static str, element: String| {
+        Command::PfAdd(key.as_bytes().into(), element.into_bytes().into())
+    };
+    for i in 0..1000 {
+        call(&mut client, pfadd("visitors:mon", format!("user:{}", i))).await;
+        call(
+            &mut client,
+            pfadd("visitors:tue", format!("user:{}", i + 500)),
+        )
+        .await;
+    }
+    let again = call(&mut client, pfadd("visitors:mon", "user:1".into())).await;
+    assert_eq!(again, Reply::Integer(0));
+
+    let merge = Command::PfMerge(
+        "visitors:week".as_bytes().into(),
+        "visitors:mon".as_bytes().into(),
+    );
+    assert_eq!(call(&mut client, merge).await, Reply::Status("OK".into()));
+    let merge = Command::PfMerge(
+        "visitors:week".as_bytes().into(),
+        "visitors:tue".as_bytes().into(),
+    );
+    assert_eq!(call(&mut client, merge).await, Reply::Status("OK".into()));
+    let Reply::Integer(week) = call(
+        &mut client,
+        Command::PfCount("visitors:week".as_bytes().into()),
+    )
+    .await
+    else {
+        panic!("PFCOUNT did not return an integer");
+    };
+    assert_close(week as u64, 1500);
+    let missing = call(
+        &mut client,
+        Command::PfCount("visitors:none".as_bytes().into()),
+    )
+    .await;
+    assert_eq!(missing, Reply::Integer(0));
+
+    client.set(b"plain", b"value").await.unwrap();
+    let wrong = call(&mut client, Command::PfCount("plain".as_bytes().into())).await;
+    assert!(matches!(wrong, Reply::Error(err) if err.starts_with("ERR WRONGTYPE")));
+
+    cluster.wait_for_replication().await.unwrap();
+    let leader = cluster.leader_hashmap().await.unwrap();
+    let follower = cluster.follower_hashmap(0).unwrap();
+    assert_eq!(
+        leader.get(&b"visitors:week"[..]),
+        follower.get(&b"visitors:week"[..])
+    );
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let restarted = call(
+        &mut client,
+        Command::PfCount("visitors:week".as_bytes().into()),
+    )
+    .await;
+    assert_eq!(restarted, Reply::Integer(week));
+}
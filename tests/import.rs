@@ -0,0 +1,105 @@
+use bytes::Bytes;
+use dist_kv::cluster::TestCluster;
+use dist_kv::import;
+use dist_kv::protocol::{Command, ErrorCode};
+
+/// A string in RDB's length-prefixed encoding.
+fn string(s: &[u8]) -> Vec<u8> {
+    let mut encoded = vec![s.len() as u8];
+    encoded.extend_from_slice(s);
+    encoded
+}
+
+/// An RDB dump with a key in each of two databases, one of them expiring,
+/// one int-encoded and one LZF-compressed.
+fn dump() -> Vec<u8> {
+    let mut rdb = b"REDIS0009".to_vec();
+    rdb.push(0xfa);
+    rdb.extend(string(b"redis-ver"));
+    rdb.extend(string(b"7.0.0"));
+    rdb.extend([0xfe, 0, 0xfb, 2, 1]);
+    rdb.push(0);
+    rdb.extend(string(b"count"));
+    rdb.extend([0xc0, 42]);
+    rdb.push(0xfc);
+    rdb.extend(4_102_444_800_000u64.to_le_bytes());
+    rdb.push(0);
+    rdb.extend(string(b"session"));
+    rdb.extend(string(b"token"));
+    rdb.extend([0xfe, 1]);
+    rdb.push(0);
+    rdb.extend(string(b"padding"));
+    // "a" then a match copying it nine more times.
+    rdb.extend([0xc3, 5, 10, 0x00, b'a', 0xe0, 0x00, 0x00]);
+    rdb.push(0xff);
+    rdb.extend([0; 8]);
+    rdb
+}
+
+#[test]
+fn rdb_dumps_are_read_as_their_string_keys() {
+    let commands = import::read(&dump()).unwrap();
+    let expected = [
+        Command::Select(b"0"[..].into()),
+        Command::Set(b"count"[..].into(), b"42"[..].into()),
+        Command::Set(b"session"[..].into(), b"token"[..].into()),
+        Command::PExpireAt(b"session"[..].into(), 4_102_444_800_000),
+        Command::Select(b"1"[..].into()),
+        Command::Set(b"padding"[..].into(), b"aaaaaaaaaa"[..].into()),
+    ];
+    assert_eq!(commands, expected);
+}
+
+#[test]
+fn rdb_keys_of_other_types_are_refused() {
+    let mut rdb = b"REDIS0009".to_vec();
+    rdb.extend([0xfe, 0, 4]);
+    rdb.extend(string(b"profile"));
+    rdb.extend([1]);
+    let err = import::read(&rdb).unwrap_err().to_string();
+    assert!(err.contains("profile is of RDB type 4"), "{}", err);
+}
+
+#[test]
+fn resp_streams_are_read_as_their_commands() {
+    let stream = b"*3\r\n$3\r\nset\r\n$1\r\nk\r\n$2\r\nv1\r\n\
+                   *1\r\n$5\r\nMULTI\r\n\
+                   *2\r\n$3\r\nDEL\r\n$1\r\nk\r\n\
+                   *1\r\n$4\r\nEXEC\r\n";
+    let commands = import::read(stream).unwrap();
+    let expected = [
+        Command::Set(b"k"[..].into(), b"v1"[..].into()),
+        Command::Delete(b"k"[..].into()),
+    ];
+    assert_eq!(commands, expected);
+
+    let unknown = b"*3\r\n$4\r\nSADD\r\n$1\r\ns\r\n$1\r\nx\r\n";
+    let err = import::read(unknown).unwrap_err().to_string();
+    assert!(err.contains("SADD at byte 0"), "{}", err);
+    assert!(import::read(b"*2\r\n$3\r\nDEL\r\n$1\r\n").is_err());
+}
+
+#[tokio::test]
+async fn imported_dumps_are_loaded_into_the_leader() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let commands = import::read(&dump()).unwrap();
+    let sent = import::load(&mut client, &commands, 2).await.unwrap();
+    assert_eq!(sent, commands.len());
+
+    let mut client = cluster.client().await.unwrap();
+    assert_eq!(client.get(b"count").await.unwrap(), Some(Bytes::from("42")));
+    assert!(client.ttl(b"session").await.unwrap() > 0);
+    client.select(b"1").await.unwrap();
+    let padding = client.get(b"padding").await.unwrap();
+    assert_eq!(padding, Some(Bytes::from("aaaaaaaaaa")));
+
+    client.read_only(true).await.unwrap();
+    let err = import::load(&mut client, &commands, 2).await.unwrap_err();
+    assert_eq!(err.code(), Some(ErrorCode::ReadOnly), "{}", err);
+    assert!(
+        err.reply().unwrap().message.starts_with("SET #2"),
+        "{}",
+        err
+    );
+}
@@ -0,0 +1,193 @@
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use bytes::BytesMut;
+use dist_kv::clock::SystemClock;
+use dist_kv::linearizability::{History, Input, Operation, Output, Recorder};
+use dist_kv::node::{FollowerCore, LeaderCore};
+use dist_kv::protocol::Command;
+use dist_kv::sim::NullStorage;
+use dist_kv::store::{run_command, Db, Key, Response, Val};
+
+fn op(client: usize, input: Input, call: u64, output: Option<(Output, u64)>) -> Operation {
+    Operation {
+        client,
+        key: Key::from("x"),
+        input,
+        call: Duration::from_millis(call),
+        output: output.map(|(output, at)| (output, Duration::from_millis(at))),
+    }
+}
+
+fn write(val: &'static str) -> Input {
+    Input::Write(Val::from(val))
+}
+
+fn read(val: Option<&'static str>) -> Output {
+    Output::Read(val.map(Val::from))
+}
+
+#[test]
+fn sequential_history_is_linearizable() {
+    let history = History(vec![
+        op(0, Input::Read, 0, Some((read(None), 1))),
+        op(0, write("a"), 2, Some((Output::Write, 3))),
+        op(1, Input::Read, 4, Some((read(Some("a")), 5))),
+        op(1, Input::Delete, 6, Some((Output::Delete(true), 7))),
+        op(0, Input::Delete, 8, Some((Output::Delete(false), 9))),
+    ]);
+    history.check().unwrap();
+}
+
+#[test]
+fn concurrent_read_may_see_either_value() {
+    for seen in [None, Some("a")] {
+        let history = History(vec![
+            op(0, write("a"), 0, Some((Output::Write, 10))),
+            op(1, Input::Read, 2, Some((read(seen), 5))),
+        ]);
+        history.check().unwrap();
+    }
+}
+
+#[test]
+fn stale_read_is_a_violation() {
+    let history = History(vec![
+        op(0, write("a"), 0, Some((Output::Write, 1))),
+        op(0, write("b"), 2, Some((Output::Write, 3))),
+        op(1, Input::Read, 4, Some((read(Some("a")), 5))),
+    ]);
+    let violation = history.check().unwrap_err();
+    assert_eq!(violation.operations, 3);
+    assert_eq!(violation.linearized, 2);
+}
+
+#[test]
+fn reads_must_agree_on_order() {
+    // Two readers see the concurrent writes take effect in opposite orders,
+    // then both read again after the writes have finished.
+    let history = History(vec![
+        op(0, write("a"), 0, Some((Output::Write, 10))),
+        op(1, write("b"), 0, Some((Output::Write, 10))),
+        op(2, Input::Read, 11, Some((read(Some("a")), 12))),
+        op(3, Input::Read, 13, Some((read(Some("b")), 14))),
+    ]);
+    assert!(history.check().is_err());
+}
+
+#[test]
+fn unfinished_write_may_or_may_not_take_effect() {
+    for seen in [None, Some("a")] {
+        let history = History(vec![
+            op(0, write("a"), 0, None),
+            op(1, Input::Read, 5, Some((read(seen), 6))),
+        ]);
+        history.check().unwrap();
+    }
+}
+
+#[test]
+fn history_is_written_as_edn() {
+    let history = History(vec![
+        op(0, write("a"), 0, Some((Output::Write, 2))),
+        op(1, Input::Read, 1, None),
+    ]);
+    let mut out = Vec::new();
+    history.write_edn(&mut out).unwrap();
+    assert_eq!(
+        String::from_utf8(out).unwrap(),
+        "{:process 0, :type :invoke, :f :write, :value [\"x\" \"a\"], :time 0}\n\
+         {:process 1, :type :invoke, :f :read, :value [\"x\" nil], :time 1000000}\n\
+         {:process 0, :type :ok, :f :write, :value [\"x\" \"a\"], :time 2000000}\n\
+         {:process 1, :type :info, :f :read, :value [\"x\" nil], :time 1000000}\n"
+    );
+}
+
+/// Runs `clients` threads doing random reads, writes and deletes on a few
+/// keys through `execute`, recording everything they see.
+fn record_concurrent_clients(
+    clients: usize,
+    execute: impl Fn(&Command) -> Response + Send + Sync + 'static,
+) -> History {
+    let recorder = Arc::new(Recorder::new(SystemClock::default()));
+    let execute = Arc::new(execute);
+    let threads: Vec<_> = (0..clients)
+        .map(|client| {
+            let recorder = recorder.clone();
+            let execute = execute.clone();
+            thread::spawn(move || {
+                let mut seed = client as u64 + 1;
+                for i in 0..200 {
+                    seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    let key = format!("key:{}", (seed >> 33) % 3);
+                    let val = format!("{}-{}", client, i);
+                    let (input, command) = match (seed >> 40) % 3 {
+                        0 => (Input::Read, Command::Get(key.as_bytes().into())),
+                        1 => (
+                            Input::Write(Val::from(val.as_str())),
+                            Command::Set(key.as_bytes().into(), val.as_bytes().into()),
+                        ),
+                        _ => (Input::Delete, Command::Delete(key.as_bytes().into())),
+                    };
+                    let id = recorder.invoke(client, key.as_bytes(), input);
+                    let output = match execute(&command) {
+                        Response::Get(_, val) => Output::Read(Some(val)),
+                        Response::KeyNotFound(_) if command.name() == "GET" => Output::Read(None),
+                        Response::KeyNotFound(_) => Output::Delete(false),
+                        Response::Delete(..) => Output::Delete(true),
+                        Response::Set(..) | Response::Replace(..) => Output::Write,
+                        response => panic!("unexpected response {:?}", response),
+                    };
+                    recorder.complete(id, output);
+                }
+            })
+        })
+        .collect();
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    recorder.history()
+}
+
+#[test]
+fn leader_is_linearizable_under_concurrent_clients() {
+    let core = Mutex::new(LeaderCore::new(
+        Db::new(),
+        NullStorage,
+        SystemClock::default(),
+    ));
+    let history = record_concurrent_clients(4, move |command| {
+        core.lock().unwrap().execute(command).unwrap().0
+    });
+    history.check().unwrap();
+}
+
+#[test]
+fn reads_from_a_lagging_follower_are_caught() {
+    // Writes go to the leader, reads to a follower that only receives the
+    // replication stream every few writes.
+    struct Nodes {
+        leader: LeaderCore<NullStorage, SystemClock>,
+        follower: FollowerCore<NullStorage, SystemClock>,
+        unsent: BytesMut,
+    }
+    let nodes = Mutex::new(Nodes {
+        leader: LeaderCore::new(Db::new(), NullStorage, SystemClock::default()),
+        follower: FollowerCore::new(Db::new(), NullStorage, SystemClock::default()),
+        unsent: BytesMut::new(),
+    });
+    let history = record_concurrent_clients(1, move |command| {
+        let nodes = &mut *nodes.lock().unwrap();
+        if let Command::Get(_) = command {
+            return run_command(&mut nodes.follower.hashmap, command);
+        }
+        let (response, record) = nodes.leader.execute(command).unwrap();
+        nodes.unsent.extend_from_slice(&record.unwrap_or_default());
+        if nodes.unsent.len() > 64 {
+            nodes.follower.receive(&mut nodes.unsent).unwrap();
+        }
+        response
+    });
+    assert!(history.check().is_err());
+}
@@ -0,0 +1,175 @@
+use std::time::Duration;
+
+use bytes::BytesMut;
+use dist_kv::client::Reply;
+use dist_kv::cluster::TestCluster;
+use dist_kv::error::DistKvError;
+use dist_kv::list::List;
+use dist_kv::protocol::{ClientCommand, Command, ErrorCode};
+
+#[test]
+fn lists_round_trip_through_their_values() {
+    let mut list = List::default();
+    list.push_back(b"b");
+    list.push_front(b"a");
+    list.push_back(b"");
+    let mut restored = List::from_bytes(&list.to_bytes()).unwrap();
+    assert_eq!(restored.len(), 3);
+    assert_eq!(restored.pop_front().unwrap(), "a");
+    assert_eq!(restored.pop_front().unwrap(), "b");
+    assert_eq!(restored.pop_front().unwrap(), "");
+    assert!(restored.is_empty());
+
+    assert!(List::from_bytes(b"not a list").is_none());
+    assert!(List::from_bytes(b"LIST\x00\x00\x00\x05abc").is_none());
+}
+
+#[tokio::test]
+async fn lists_are_stored_as_replicated_values() {
+    let mut cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    assert_eq!(client.rpush(b"jobs", b"2").await.unwrap(), 1);
+    assert_eq!(client.rpush(b"jobs", b"3").await.unwrap(), 2);
+    assert_eq!(client.lpush(b"jobs", b"1").await.unwrap(), 3);
+    assert_eq!(client.llen(b"jobs").await.unwrap(), 3);
+    assert_eq!(client.lpop(b"jobs").await.unwrap().unwrap(), "1");
+
+    client.set(b"plain", b"value").await.unwrap();
+    let wrong = client.lpop(b"plain").await.unwrap_err();
+    assert_eq!(wrong.code(), Some(ErrorCode::WrongType));
+    assert!(matches!(wrong, DistKvError::Server(_)));
+
+    cluster.wait_for_replication().await.unwrap();
+    let leader = cluster.leader_hashmap().await.unwrap();
+    let follower = cluster.follower_hashmap(0).unwrap();
+    assert_eq!(leader.get(&b"jobs"[..]), follower.get(&b"jobs"[..]));
+
+    // The pop that empties a list deletes the key, on the followers too.
+    assert_eq!(client.lpop(b"jobs").await.unwrap().unwrap(), "2");
+    assert_eq!(client.lpop(b"jobs").await.unwrap().unwrap(), "3");
+    assert_eq!(client.lpop(b"jobs").await.unwrap(), None);
+    cluster.wait_for_replication().await.unwrap();
+    let follower = cluster.follower_hashmap(0).unwrap();
+    assert!(follower.get(&b"jobs"[..]).is_none());
+
+    client.rpush(b"jobs", b"4").await.unwrap();
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    assert_eq!(client.lpop(b"jobs").await.unwrap().unwrap(), "4");
+}
+
+#[tokio::test]
+async fn pushes_past_the_value_size_limit_are_refused() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.config_set("max_value_size", "16").await.unwrap();
+    let too_large = client.set(b"plain", &[b'v'; 17]).await.unwrap_err();
+    assert_eq!(too_large.code(), Some(ErrorCode::TooLarge));
+
+    // `LIST`, then a 4-byte length and the bytes of each element: one
+    // element of 4 bytes is 12 bytes, two are 20.
+    assert_eq!(client.rpush(b"jobs", b"1234").await.unwrap(), 1);
+    for err in [
+        client.rpush(b"jobs", b"5678").await.unwrap_err(),
+        client.lpush(b"jobs", b"5678").await.unwrap_err(),
+    ] {
+        assert_eq!(err.code(), too_large.code());
+        let message = err.reply().unwrap().message;
+        assert_eq!(message, "value is 20 bytes, the limit is 16");
+    }
+    assert_eq!(client.llen(b"jobs").await.unwrap(), 1);
+    assert_eq!(client.lpop(b"jobs").await.unwrap().unwrap(), "1234");
+}
+
+#[tokio::test]
+async fn blocked_pops_are_served_in_the_order_they_waited() {
+    let cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+
+    // An element already there is popped without waiting.
+    client.rpush(b"queue", b"ready").await.unwrap();
+    let mut worker = cluster.client().await.unwrap();
+    assert_eq!(
+        worker.blpop(b"queue", None).await.unwrap().unwrap(),
+        "ready"
+    );
+    let short = Some(Duration::from_millis(50));
+    assert_eq!(worker.blpop(b"queue", short).await.unwrap(), None);
+
+    let mut workers = Vec::new();
+    for _ in 0..3 {
+        let mut worker = cluster.client().await.unwrap();
+        workers.push(tokio::spawn(async move {
+            worker.blpop(b"queue", None).await.unwrap().unwrap()
+        }));
+        // So that they queue up in order.
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+    client.set(b"other", b"x").await.unwrap();
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(workers.iter().all(|worker| !worker.is_finished()));
+
+    for job in ["job:1", "job:2", "job:3"] {
+        client.rpush(b"queue", job.as_bytes()).await.unwrap();
+    }
+    let mut popped = Vec::new();
+    for worker in workers {
+        popped.push(worker.await.unwrap());
+    }
+    assert_eq!(popped, ["job:1", "job:2", "job:3"]);
+    assert_eq!(client.llen(b"queue").await.unwrap(), 0);
+
+    // The pops made for the blocked connections reach the followers.
+    cluster.wait_for_replication().await.unwrap();
+    let follower = cluster.follower_hashmap(0).unwrap();
+    assert!(follower.get(&b"queue"[..]).is_none());
+}
+
+#[tokio::test]
+async fn elements_popped_for_a_killed_connection_go_back_on_the_list() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut admin = cluster.client().await.unwrap();
+    for i in 0..20 {
+        let name = format!("worker:{}", i);
+        let mut worker = cluster.client().await.unwrap();
+        worker.set_name(name.as_bytes()).await.unwrap();
+        let blocked = tokio::spawn(async move { worker.blpop(b"queue", None).await });
+        let id = loop {
+            let Reply::Bulk(Some(list)) = admin
+                .call(&Command::Client(ClientCommand::List))
+                .await
+                .unwrap()
+            else {
+                panic!("CLIENT LIST did not return a bulk reply");
+            };
+            let list = String::from_utf8(list.to_vec()).unwrap();
+            let line = list
+                .lines()
+                .find(|line| line.contains(&format!(" name={} ", name)));
+            if let Some(line) = line.filter(|line| line.ends_with(" cmd=blpop")) {
+                break line["id=".len()..]
+                    .split(' ')
+                    .next()
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        };
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // The kill and the push land back to back, so the element may be
+        // handed over just as the connection goes. It is either delivered
+        // or left on the list, never lost.
+        let mut pipeline = BytesMut::new();
+        Command::Client(ClientCommand::Kill(id)).encode(&mut pipeline);
+        Command::RPush(b"queue"[..].into(), b"job"[..].into()).encode(&mut pipeline);
+        admin.send_raw(&pipeline).await.unwrap();
+        admin.read_reply().await.unwrap();
+        admin.read_reply().await.unwrap();
+        let delivered = matches!(blocked.await.unwrap(), Ok(Some(_)));
+        let left = admin.lpop(b"queue").await.unwrap().is_some();
+        assert!(delivered != left, "delivered {}, left {}", delivered, left);
+    }
+}
@@ -0,0 +1,124 @@
+use bytes::BytesMut;
+use dist_kv::lz4::{compress_block, decompress_block, xxh32, FrameDecoder, FrameEncoder};
+use dist_kv::replication::ReplicaStream;
+use tokio::io::AsyncReadExt;
+
+fn decode_all(frame: &[u8], piece: usize) -> BytesMut {
+    let mut decoder = FrameDecoder::new();
+    let mut input = BytesMut::new();
+    let mut out = BytesMut::new();
+    for piece in frame.chunks(piece) {
+        input.extend_from_slice(piece);
+        decoder.decode(&mut input, &mut out).unwrap();
+    }
+    assert!(input.is_empty(), "{} bytes left undecoded", input.len());
+    out
+}
+
+#[test]
+fn xxh32_matches_the_reference() {
+    assert_eq!(xxh32(b"", 0), 0x02cc_5d05);
+    // The header checksum of the reference encoder's default frame.
+    assert_eq!((xxh32(&[0x64, 0x40], 0) >> 8) as u8, 0xa7);
+}
+
+#[test]
+fn blocks_round_trip() {
+    let mut inputs: Vec<Vec<u8>> = vec![
+        Vec::new(),
+        b"a".to_vec(),
+        b"abcdefghijklmnopqrstuvwxyz".to_vec(),
+        vec![b'x'; 100_000],
+        b"SET key:1 value\n".repeat(500),
+    ];
+    let mut seed = 1u64;
+    inputs.push(
+        (0..50_000)
+            .map(|_| {
+                seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1);
+                (seed >> 56) as u8
+            })
+            .collect(),
+    );
+    for input in inputs {
+        let mut block = BytesMut::new();
+        compress_block(&input, &mut block);
+        let mut out = BytesMut::new();
+        decompress_block(&block, &mut out, input.len()).unwrap();
+        assert_eq!(&out[..], &input[..]);
+    }
+
+    let mut block = BytesMut::new();
+    compress_block(&vec![b'x'; 1000], &mut block);
+    assert!(block.len() < 20, "{} bytes", block.len());
+    assert!(decompress_block(&block, &mut BytesMut::new(), 999).is_err());
+}
+
+#[test]
+fn frames_decode_from_any_split() {
+    let mut encoder = FrameEncoder::new();
+    let mut frame = BytesMut::new();
+    let records = b"SET key value\n".repeat(100);
+    encoder.encode(&records, &mut frame);
+    encoder.encode(b"DEL key\n", &mut frame);
+    encoder.finish(&mut frame);
+    assert_eq!(&frame[..7], &[0x04, 0x22, 0x4d, 0x18, 0x60, 0x70, 0x73]);
+    assert!(frame.len() < records.len() / 4);
+
+    let mut expected = records.clone();
+    expected.extend_from_slice(b"DEL key\n");
+    for piece in [1, 3, 7, 64, frame.len()] {
+        assert_eq!(&decode_all(&frame, piece)[..], &expected[..]);
+    }
+    // Frames may follow one another.
+    let twice = [&frame[..], &frame[..]].concat();
+    assert_eq!(decode_all(&twice, 5).len(), expected.len() * 2);
+}
+
+#[test]
+fn frames_from_the_reference_encoder_decode() {
+    // `lz4 -BX` of three SET lines: block checksums and a content checksum.
+    let frame = [
+        0x04, 0x22, 0x4d, 0x18, 0x74, 0x40, 0xbd, 0x18, 0x00, 0x00, 0x00, 0xef, 0x53, 0x45, 0x54,
+        0x20, 0x6b, 0x65, 0x79, 0x20, 0x76, 0x61, 0x6c, 0x75, 0x65, 0x0a, 0x0e, 0x00, 0x04, 0x50,
+        0x61, 0x6c, 0x75, 0x65, 0x0a, 0x47, 0x92, 0x76, 0x0c, 0x00, 0x00, 0x00, 0x00, 0x4b, 0x25,
+        0xfb, 0x37,
+    ];
+    assert_eq!(
+        &decode_all(&frame, 2)[..],
+        b"SET key value\nSET key value\nSET key value\n"
+    );
+
+    let mut corrupt = frame;
+    corrupt[14] ^= 1;
+    let mut input = BytesMut::from(&corrupt[..]);
+    let err = FrameDecoder::new()
+        .decode(&mut input, &mut BytesMut::new())
+        .unwrap_err();
+    assert!(err.to_string().contains("checksum"), "{}", err);
+}
+
+#[tokio::test]
+async fn a_compressed_replica_stream_writes_a_block_per_flush() {
+    let (leader, mut follower) = tokio::io::duplex(1 << 20);
+    let mut stream = ReplicaStream::new(leader);
+    stream.introduce(b"REPLICATE\n").await.unwrap();
+    stream.compress();
+    for i in 0..100 {
+        stream
+            .send(format!("SET key:{} value\n", i).as_bytes())
+            .await
+            .unwrap();
+    }
+    stream.shutdown().await.unwrap();
+
+    let mut received = Vec::new();
+    follower.read_to_end(&mut received).await.unwrap();
+    let frame = received.strip_prefix(&b"REPLICATE\n"[..]).unwrap();
+    let records = decode_all(frame, frame.len());
+    let expected: String = (0..100).map(|i| format!("SET key:{} value\n", i)).collect();
+    assert_eq!(&records[..], expected.as_bytes());
+    // Unbatched, each record is a block of its own, ending the frame after
+    // the last of them.
+    assert!(frame.ends_with(&[0, 0, 0, 0]));
+}
@@ -0,0 +1,238 @@
+//! Round-trip properties of the parse → apply → serialize pipeline, checked
+//! over randomly generated command sequences without any I/O.
+
+use std::borrow::Cow;
+
+use bytes::BytesMut;
+use dist_kv::equivalence;
+use dist_kv::protocol::{parse_all, split_frame, Command, Condition, Expiry, Limits, SetOptions};
+use dist_kv::sim::SimRng;
+use dist_kv::store::{apply, replay, Db};
+use dist_kv::wal::LogReader;
+
+const CASES: u64 = 200;
+
+/// Bytes that stress the encoder: whitespace, quotes, escapes, line breaks,
+/// `$` markers and non-ASCII.
+const ALPHABET: &[u8] = b"ab \t\"\\\r\n$0x\xff\x00";
+
+fn arbitrary_bytes(rng: &mut SimRng) -> Cow<'static, [u8]> {
+    let len = match rng.below(10) {
+        0 => 0,
+        1 => 1024 + rng.below(2048) as usize,
+        _ => rng.below(12) as usize,
+    };
+    let bytes = (0..len)
+        .map(|_| match rng.below(3) {
+            0 => rng.next_u64() as u8,
+            _ => ALPHABET[rng.below(ALPHABET.len() as u64) as usize],
+        })
+        .collect::<Vec<_>>();
+    Cow::Owned(bytes)
+}
+
+/// Keys come from a small pool so sequences overwrite and delete each other.
+fn arbitrary_key(rng: &mut SimRng) -> Cow<'static, [u8]> {
+    match rng.below(4) {
+        0 => arbitrary_bytes(rng),
+        _ => Cow::Owned(format!("key {}", rng.below(8)).into_bytes()),
+    }
+}
+
+/// Options for `SET`, always at least one so that they aren't parsed back as
+/// a plain `SET`.
+fn arbitrary_set_options(rng: &mut SimRng) -> SetOptions {
+    let mut options = SetOptions::default();
+    match rng.below(4) {
+        0 => options.expiry = Some(Expiry::In(1 + rng.below(1 << 40))),
+        1 => options.expiry = Some(Expiry::At(1 + rng.below(1 << 40))),
+        2 => options.keep_ttl = true,
+        _ => {}
+    }
+    if rng.chance(0.5) || options == SetOptions::default() {
+        options.condition = Some(if rng.chance(0.5) {
+            Condition::Absent
+        } else {
+            Condition::Exists
+        });
+    }
+    options
+}
+
+fn arbitrary_write(rng: &mut SimRng) -> Command<'static> {
+    match rng.below(3) {
+        0 => Command::Delete(arbitrary_key(rng)),
+        _ => Command::Set(arbitrary_key(rng), arbitrary_bytes(rng)),
+    }
+}
+
+fn arbitrary_command(rng: &mut SimRng) -> Command<'static> {
+    match rng.below(12) {
+        0..=2 => Command::Set(arbitrary_key(rng), arbitrary_bytes(rng)),
+        3 => Command::SetWith(
+            arbitrary_key(rng),
+            arbitrary_bytes(rng),
+            arbitrary_set_options(rng),
+        ),
+        4..=5 => Command::Delete(arbitrary_key(rng)),
+        6..=7 => Command::Get(arbitrary_key(rng)),
+        8 => Command::GetStream(arbitrary_key(rng), 1 + rng.below(1 << 20) as usize),
+        9 => Command::Batch((0..rng.below(5)).map(|_| arbitrary_write(rng)).collect()),
+        10 => {
+            let namespace = format!("ns{}", rng.below(3)).into_bytes();
+            Command::In(namespace.into(), Box::new(arbitrary_write(rng)))
+        }
+        _ => Command::Info(rng.chance(0.5).then(|| arbitrary_bytes(rng))),
+    }
+}
+
+fn arbitrary_commands(rng: &mut SimRng) -> Vec<Command<'static>> {
+    let len = rng.below(50) as usize;
+    (0..len).map(|_| arbitrary_command(rng)).collect()
+}
+
+fn encode_all(commands: &[Command]) -> BytesMut {
+    let mut buf = BytesMut::new();
+    for command in commands {
+        command.encode(&mut buf);
+    }
+    buf
+}
+
+#[test]
+fn parse_inverts_encode() {
+    for seed in 0..CASES {
+        let commands = arbitrary_commands(&mut SimRng::new(seed));
+        let encoded = encode_all(&commands);
+        let parsed = parse_all(&encoded, &Limits::NONE).unwrap();
+        assert_eq!(parsed, commands, "seed {}", seed);
+    }
+}
+
+#[test]
+fn parse_is_independent_of_read_boundaries() {
+    for seed in 0..CASES {
+        let mut rng = SimRng::new(seed);
+        let commands = arbitrary_commands(&mut rng);
+        let encoded = encode_all(&commands);
+
+        let mut buf = BytesMut::new();
+        let mut parsed = Vec::new();
+        let mut rest = &encoded[..];
+        while !rest.is_empty() {
+            let n = 1 + rng.below(rest.len().min(64) as u64) as usize;
+            buf.extend_from_slice(&rest[..n]);
+            rest = &rest[n..];
+            while let Some(frame) = split_frame(&mut buf, &Limits::NONE).unwrap() {
+                parsed.push(frame.command(&Limits::NONE).unwrap().into_owned());
+            }
+        }
+        assert!(buf.is_empty(), "seed {}", seed);
+        assert_eq!(parsed, commands, "seed {}", seed);
+    }
+}
+
+#[test]
+fn replaying_the_log_rebuilds_the_map() {
+    for seed in 0..CASES {
+        let commands = arbitrary_commands(&mut SimRng::new(seed));
+        let mut hashmap = Db::new();
+        let mut log = BytesMut::new();
+        for command in &commands {
+            if let (_, Some(record)) = apply(&mut hashmap, command) {
+                log.extend_from_slice(&record);
+            }
+        }
+        assert_eq!(replay(&log).unwrap(), hashmap, "seed {}", seed);
+    }
+}
+
+#[test]
+fn the_leader_log_replays_to_the_leader_map() {
+    for seed in 0..CASES {
+        let commands = arbitrary_commands(&mut SimRng::new(seed));
+        if let Err(mismatch) = equivalence::check(&commands) {
+            panic!("seed {}: {}", seed, mismatch);
+        }
+    }
+}
+
+#[test]
+fn replaying_a_prefix_matches_applying_a_prefix() {
+    for seed in 0..CASES {
+        let commands = arbitrary_commands(&mut SimRng::new(seed));
+        let mut hashmap = Db::new();
+        let mut log = BytesMut::new();
+        for command in &commands {
+            let (_, record) = apply(&mut hashmap, command);
+            log.extend_from_slice(&record.unwrap_or_default());
+            assert_eq!(replay(&log).unwrap(), hashmap, "seed {}", seed);
+        }
+    }
+}
+
+#[test]
+fn reads_do_not_change_the_map() {
+    for seed in 0..CASES {
+        let mut rng = SimRng::new(seed);
+        let mut hashmap = Db::new();
+        for command in arbitrary_commands(&mut rng) {
+            apply(&mut hashmap, &command);
+        }
+        let before = hashmap.clone();
+        let key = arbitrary_key(&mut rng);
+        let (_, record) = apply(&mut hashmap, &Command::Get(key));
+        assert!(record.is_none(), "seed {}", seed);
+        assert_eq!(hashmap, before, "seed {}", seed);
+    }
+}
+
+#[test]
+fn torn_batches_are_not_applied() {
+    for seed in 0..CASES {
+        let mut rng = SimRng::new(seed);
+        let mut hashmap = Db::new();
+        let mut log = BytesMut::new();
+        for command in arbitrary_commands(&mut rng) {
+            let (_, record) = apply(&mut hashmap, &command);
+            log.extend_from_slice(&record.unwrap_or_default());
+        }
+        let batch = Command::Batch(
+            (0..2 + rng.below(5))
+                .map(|_| arbitrary_write(&mut rng))
+                .collect(),
+        );
+        let (_, record) = apply(&mut hashmap.clone(), &batch);
+        let record = record.unwrap();
+        let torn = 1 + rng.below(record.len() as u64 - 1) as usize;
+        log.extend_from_slice(&record[..torn]);
+        assert_eq!(replay(&log).unwrap(), hashmap, "seed {}", seed);
+    }
+}
+
+#[test]
+fn parallel_log_reading_matches_sequential() {
+    for seed in 0..CASES {
+        let mut rng = SimRng::new(seed);
+        let mut log = BytesMut::new();
+        for command in arbitrary_commands(&mut rng) {
+            command.encode(&mut log);
+            match rng.below(10) {
+                0 => log.extend_from_slice(b"NOSUCHCOMMAND\n"),
+                1 => log.extend_from_slice(b"SET $x\n"),
+                _ => {}
+            }
+        }
+        let mut torn = BytesMut::new();
+        arbitrary_write(&mut rng).encode(&mut torn);
+        log.extend_from_slice(&torn[..torn.len() - 1]);
+
+        let sequential: Vec<_> = LogReader::new(&log).map(|e| format!("{:?}", e)).collect();
+        for threads in [2, 3, 8] {
+            let parallel: Vec<_> = LogReader::parallel(&log, threads)
+                .map(|e| format!("{:?}", e))
+                .collect();
+            assert_eq!(parallel, sequential, "seed {}, {} threads", seed, threads);
+        }
+    }
+}
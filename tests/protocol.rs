@@ -0,0 +1,31 @@
+use bytes::BytesMut;
+use dist_kv::client::split_reply;
+use dist_kv::protocol::{parse_all, split_frame, Command, Limits, ParseError};
+
+#[test]
+fn bulk_lengths_that_overflow_are_rejected() {
+    let mut buf = BytesMut::from(&b"GET $18446744073709551615\n\n"[..]);
+    let err = split_frame(&mut buf, &Limits::NONE).unwrap_err();
+    assert_eq!(err, ParseError::InvalidBulk);
+}
+
+#[test]
+fn tokens_that_look_like_bulk_lengths_round_trip() {
+    let commands = [
+        Command::Set(b"$5"[..].into(), b"$abc"[..].into()),
+        Command::Set(b"$"[..].into(), b"$18446744073709551615"[..].into()),
+        Command::Get(b"$3"[..].into()),
+    ];
+    let mut buf = BytesMut::new();
+    for command in &commands {
+        command.encode(&mut buf);
+    }
+    assert!(buf.starts_with(b"SET \"$5\" "), "{:?}", buf);
+    assert_eq!(parse_all(&buf, &Limits::NONE).unwrap(), commands);
+}
+
+#[test]
+fn push_lengths_larger_than_the_reply_wait_for_more() {
+    let mut buf = BytesMut::from(&b">18446744073709551615\n$1\na\n"[..]);
+    assert_eq!(split_reply(&mut buf).unwrap(), None);
+}
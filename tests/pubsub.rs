@@ -0,0 +1,114 @@
+use bytes::Bytes;
+use dist_kv::client::Reply;
+use dist_kv::cluster::TestCluster;
+use dist_kv::protocol::{ClientCommand, Command};
+use dist_kv::pubsub::glob_match;
+
+fn items(items: &[&str]) -> Vec<Bytes> {
+    items
+        .iter()
+        .map(|item| Bytes::from(item.to_string()))
+        .collect()
+}
+
+#[test]
+fn glob_patterns() {
+    let cases: &[(&str, &str, bool)] = &[
+        ("user:*", "user:1", true),
+        ("user:*", "user:", true),
+        ("user:*", "users:1", false),
+        ("*:name", "user:1:name", true),
+        ("*:name", "user:1:names", false),
+        ("a*b*c", "aXbYbZc", true),
+        ("a*b*c", "aXbYc", true),
+        ("a*b*c", "aXcYb", false),
+        ("h?llo", "hello", true),
+        ("h?llo", "hllo", false),
+        ("h[ae]llo", "hallo", true),
+        ("h[ae]llo", "hillo", false),
+        ("h[^e]llo", "hallo", true),
+        ("h[^e]llo", "hello", false),
+        ("key:[0-9]", "key:7", true),
+        ("key:[0-9]", "key:x", false),
+        ("\\*", "*", true),
+        ("\\*", "x", false),
+        ("[", "[", true),
+        ("", "", true),
+        ("", "a", false),
+        ("**", "anything", true),
+    ];
+    for &(pattern, subject, expected) in cases {
+        assert_eq!(
+            glob_match(pattern.as_bytes(), subject.as_bytes()),
+            expected,
+            "{:?} against {:?}",
+            pattern,
+            subject
+        );
+    }
+}
+
+#[tokio::test]
+async fn messages_reach_channel_and_pattern_subscribers() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut subscriber = cluster.client().await.unwrap();
+    let mut watcher = cluster.client().await.unwrap();
+    let mut publisher = cluster.client().await.unwrap();
+    assert_eq!(subscriber.subscribe(b"news").await.unwrap(), 1);
+    assert_eq!(watcher.psubscribe(b"n*s").await.unwrap(), 1);
+    assert_eq!(watcher.psubscribe(b"sports").await.unwrap(), 2);
+
+    assert_eq!(publisher.publish(b"news", b"hello").await.unwrap(), 2);
+    assert_eq!(
+        subscriber.next_push().await.unwrap(),
+        items(&["message", "news", "hello"])
+    );
+    assert_eq!(
+        watcher.next_push().await.unwrap(),
+        items(&["pmessage", "n*s", "news", "hello"])
+    );
+    assert_eq!(publisher.publish(b"weather", b"rain").await.unwrap(), 0);
+
+    let list = Command::Client(ClientCommand::List);
+    let Reply::Bulk(Some(list)) = publisher.call(&list).await.unwrap() else {
+        panic!("CLIENT LIST did not return a bulk reply");
+    };
+    let list = String::from_utf8(list.to_vec()).unwrap();
+    assert!(list.contains(" sub=1 psub=0 "), "{}", list);
+    assert!(list.contains(" sub=0 psub=2 "), "{}", list);
+
+    let unsubscribe = Command::PUnsubscribe("n*s".as_bytes().into());
+    assert_eq!(watcher.call(&unsubscribe).await.unwrap(), Reply::Integer(1));
+    drop(subscriber);
+    // The subscriber's connection closing drops its subscription.
+    for _ in 0..100 {
+        if publisher.publish(b"news", b"bye").await.unwrap() == 0 {
+            return;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+    panic!("subscriptions outlived their connection");
+}
+
+#[tokio::test]
+async fn writes_publish_keyspace_notifications() {
+    let cluster = TestCluster::start(0).await.unwrap();
+    let mut watcher = cluster.client().await.unwrap();
+    watcher.psubscribe(b"__keyspace__:user:*").await.unwrap();
+
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"user:1", b"ada").await.unwrap();
+    client.set(b"session:1", b"x").await.unwrap();
+    assert!(client.del(b"user:1").await.unwrap());
+    // Deleting a missing key changes nothing, so nothing is published.
+    assert!(!client.del(b"user:2").await.unwrap());
+    client.set(b"user:3", b"bob").await.unwrap();
+
+    for (key, event) in [("user:1", "set"), ("user:1", "del"), ("user:3", "set")] {
+        let channel = format!("__keyspace__:{}", key);
+        assert_eq!(
+            watcher.next_push().await.unwrap(),
+            items(&["pmessage", "__keyspace__:user:*", &channel, event])
+        );
+    }
+}
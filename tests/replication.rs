@@ -0,0 +1,114 @@
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
+
+use dist_kv::replication::{Overflow, QueueLimit, ReplicaQueue, ReplicaStream};
+use tokio::io::{AsyncReadExt, DuplexStream};
+
+fn temp_dir() -> PathBuf {
+    static DIRS: AtomicUsize = AtomicUsize::new(0);
+    let n = DIRS.fetch_add(1, Ordering::Relaxed);
+    let dir = std::env::temp_dir().join(format!("dist-kv-queue-{}-{}", std::process::id(), n));
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+/// A queue of `max_bytes` to a follower that reads nothing until told to,
+/// with room for only a few bytes in flight between them.
+fn queue(max_bytes: usize, overflow: Overflow, spill_dir: PathBuf) -> (ReplicaQueue, DuplexStream) {
+    let (leader, follower) = tokio::io::duplex(16);
+    let limit = QueueLimit {
+        max_bytes,
+        overflow,
+        spill_dir,
+    };
+    let queue = ReplicaQueue::start(ReplicaStream::new(leader), limit, "test".to_string());
+    (queue, follower)
+}
+
+fn record(i: usize) -> Vec<u8> {
+    format!("SET key:{} value\n", i).into_bytes()
+}
+
+async fn read_records(follower: &mut DuplexStream, n: usize) -> Vec<u8> {
+    let expected: usize = (0..n).map(|i| record(i).len()).sum();
+    let mut received = vec![0; expected];
+    follower.read_exact(&mut received).await.unwrap();
+    received
+}
+
+fn records(n: usize) -> Vec<u8> {
+    (0..n).flat_map(record).collect()
+}
+
+#[tokio::test]
+async fn a_full_queue_blocks_until_the_follower_catches_up() {
+    let (mut queue, mut follower) = queue(64, Overflow::Block, temp_dir());
+    let mut pushed = 0;
+    while tokio::time::timeout(Duration::from_millis(50), queue.push(&record(pushed)))
+        .await
+        .is_ok()
+    {
+        pushed += 1;
+        assert!(pushed < 20, "the queue never filled");
+    }
+    assert!(queue.queued() <= 64);
+
+    // The push that timed out never queued its record, so it is sent again.
+    let reading = tokio::spawn(async move { read_records(&mut follower, 20).await });
+    for i in pushed..20 {
+        queue.push(&record(i)).await.unwrap();
+    }
+    assert_eq!(reading.await.unwrap(), records(20));
+}
+
+#[tokio::test]
+async fn a_full_queue_spills_to_disk_in_order() {
+    let dir = temp_dir();
+    let (mut queue, mut follower) = queue(64, Overflow::Spill, dir.clone());
+    for i in 0..100 {
+        queue.push(&record(i)).await.unwrap();
+    }
+    assert!(queue.queued() <= 64);
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+
+    assert_eq!(read_records(&mut follower, 100).await, records(100));
+    drop(queue);
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 0);
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_full_queue_drops_the_follower() {
+    let (mut queue, mut follower) = queue(64, Overflow::Drop, temp_dir());
+    let mut pushed = 0;
+    let err = loop {
+        match queue.push(&record(pushed)).await {
+            Ok(()) => pushed += 1,
+            Err(err) => break err,
+        }
+        assert!(pushed < 20, "the queue never filled");
+    };
+    assert!(err.to_string().contains("behind"), "{}", err);
+
+    // Dropping the queue closes the stream, partway through or not.
+    drop(queue);
+    let mut received = Vec::new();
+    follower.read_to_end(&mut received).await.unwrap();
+    assert!(records(pushed).starts_with(&received));
+}
+
+#[tokio::test]
+async fn shutdown_writes_out_the_queue() {
+    let (mut queue, mut follower) = queue(1024, Overflow::Block, temp_dir());
+    for i in 0..10 {
+        queue.push(&record(i)).await.unwrap();
+    }
+    let reading = tokio::spawn(async move {
+        let mut received = Vec::new();
+        follower.read_to_end(&mut received).await.unwrap();
+        received
+    });
+    queue.shutdown().await.unwrap();
+    assert_eq!(reading.await.unwrap(), records(10));
+}
@@ -0,0 +1,79 @@
+use std::time::Duration;
+
+use bytes::BytesMut;
+use dist_kv::clock::Clock;
+use dist_kv::node::FollowerCore;
+use dist_kv::protocol::Command;
+use dist_kv::sim::{NullStorage, SimClock, SimConfig, Simulation};
+use dist_kv::store::Db;
+
+fn run(config: SimConfig) {
+    let mut sim = Simulation::new(config);
+    if let Err(e) = sim.run() {
+        panic!("{}", e);
+    }
+}
+
+#[test]
+fn many_seeds_with_faults() {
+    for seed in 0..50 {
+        run(SimConfig {
+            seed,
+            ..SimConfig::default()
+        });
+    }
+}
+
+#[test]
+fn heavy_crash_and_partition_rates() {
+    for seed in 0..20 {
+        run(SimConfig {
+            seed,
+            steps: 2_000,
+            sync_failure_rate: 0.05,
+            crash_rate: 0.05,
+            partition_rate: 0.05,
+            ..SimConfig::default()
+        });
+    }
+}
+
+#[test]
+fn same_seed_is_deterministic() {
+    let config = SimConfig {
+        seed: 42,
+        max_delay: Duration::from_millis(5),
+        ..SimConfig::default()
+    };
+    let mut a = Simulation::new(config.clone());
+    let mut b = Simulation::new(config);
+    a.run().unwrap();
+    b.run().unwrap();
+    assert_eq!(a.clock().now(), b.clock().now());
+    assert_eq!(a.trace(), b.trace());
+}
+
+#[test]
+fn a_follower_lease_runs_out_with_the_clock() {
+    let clock = SimClock::default();
+    let mut follower = FollowerCore::new(Db::new(), NullStorage, clock.clone());
+    let replicate = |follower: &mut FollowerCore<_, _>, leader: &[u8]| {
+        let mut buf = BytesMut::new();
+        Command::Replicate(Some(leader.into())).encode(&mut buf);
+        let (replies, replicating) = follower.answer(&mut buf, &mut false).unwrap();
+        (String::from_utf8_lossy(&replies).into_owned(), replicating)
+    };
+    assert!(replicate(&mut follower, b"a:1").1);
+
+    let mut buf = BytesMut::new();
+    Command::GetVersion(b"key"[..].into(), Some(500)).encode(&mut buf);
+    follower.answer(&mut buf, &mut false).unwrap();
+    clock.advance(Duration::from_millis(499));
+    let (replies, replicating) = replicate(&mut follower, b"b:1");
+    assert!(!replicating);
+    assert!(replies.contains("LEASED"), "{}", replies);
+    assert!(replies.contains("for another 1ms"), "{}", replies);
+
+    clock.advance(Duration::from_millis(1));
+    assert!(replicate(&mut follower, b"b:1").1);
+}
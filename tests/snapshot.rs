@@ -0,0 +1,111 @@
+use bytes::BytesMut;
+use dist_kv::error::DistKvError;
+use dist_kv::protocol::Command;
+use dist_kv::snapshot::{self, HEADER_LEN, MAGIC, VERSION};
+use dist_kv::store::{Db, Key, Val};
+
+fn sample() -> Db {
+    let mut hashmap = Db::new();
+    for i in 0..50 {
+        let key = Key::from(format!("key:{}", i).as_str());
+        hashmap.insert(key, Val::from(vec![i as u8; i * 10]));
+    }
+    hashmap.insert("$0 \n".into(), Val::new());
+    hashmap
+}
+
+#[test]
+fn snapshots_round_trip_with_their_header() {
+    let encoded = snapshot::encode(&sample(), 42);
+    assert!(encoded.starts_with(MAGIC));
+    let decoded = snapshot::decode(&encoded).unwrap();
+    assert_eq!(decoded.header.version, VERSION);
+    assert_eq!(decoded.header.lsn, 42);
+    assert_eq!(decoded.header.len as usize, encoded.len() - HEADER_LEN);
+    assert_eq!(decoded.hashmap, sample());
+    assert_eq!(snapshot::encode(&decoded.hashmap, 42), encoded);
+}
+
+#[test]
+fn corrupt_snapshots_are_refused() {
+    let encoded = snapshot::encode(&sample(), 1);
+
+    let mut flipped = encoded.to_vec();
+    let last = flipped.len() - 1;
+    flipped[last] ^= 1;
+    let err = snapshot::decode(&flipped).unwrap_err();
+    assert!(err.to_string().contains("checksum"), "{}", err);
+
+    let truncated = &encoded[..encoded.len() - 5];
+    assert!(snapshot::decode(truncated).is_err());
+    assert!(snapshot::decode(&encoded[..HEADER_LEN - 1]).is_err());
+}
+
+#[test]
+fn newer_versions_are_refused() {
+    let mut encoded = snapshot::encode(&sample(), 1).to_vec();
+    let version = (VERSION + 1).to_be_bytes();
+    encoded[MAGIC.len()..MAGIC.len() + 2].copy_from_slice(&version);
+    let err = snapshot::decode(&encoded).unwrap_err();
+    assert!(err.to_string().contains("version"), "{}", err);
+}
+
+#[test]
+fn headerless_snapshots_are_read_as_version_0() {
+    let mut legacy = BytesMut::new();
+    for (key, val) in &sample() {
+        Command::Set(key[..].into(), val[..].into()).encode(&mut legacy);
+    }
+    let decoded = snapshot::decode(&legacy).unwrap();
+    assert_eq!(decoded.header.version, 0);
+    assert_eq!(decoded.hashmap, sample());
+}
+
+#[test]
+fn expiry_times_are_kept() {
+    let mut hashmap = sample();
+    hashmap.expire_at(b"key:3", 1_700_000_000_000);
+    let decoded = snapshot::decode(&snapshot::encode(&hashmap, 7)).unwrap();
+    assert_eq!(decoded.hashmap.expiry(b"key:3"), Some(1_700_000_000_000));
+    assert_eq!(decoded.hashmap.expiry(b"key:4"), None);
+    assert_eq!(decoded.hashmap, hashmap);
+}
+
+#[test]
+fn namespaces_are_kept() {
+    let mut hashmap = sample();
+    let staging = hashmap.namespace_mut(b"staging");
+    staging.insert("key:1".into(), "staged".into());
+    staging.expire_at(b"key:1", 1_700_000_000_000);
+    let decoded = snapshot::decode(&snapshot::encode(&hashmap, 7)).unwrap();
+    let staging = decoded.hashmap.namespace(b"staging").unwrap();
+    assert_eq!(staging.get(&b"key:1"[..]).unwrap(), "staged");
+    assert_eq!(staging.expiry(b"key:1"), Some(1_700_000_000_000));
+    assert_eq!(decoded.hashmap, hashmap);
+}
+
+#[test]
+fn membership_is_kept() {
+    let mut hashmap = sample();
+    hashmap.set_member("10.0.0.5:7000", Some(true));
+    hashmap.set_member("10.0.0.2:7000", Some(false));
+    let decoded = snapshot::decode(&snapshot::encode(&hashmap, 7)).unwrap();
+    assert_eq!(decoded.hashmap.member("10.0.0.5:7000"), Some(true));
+    assert_eq!(decoded.hashmap.member("10.0.0.2:7000"), Some(false));
+    assert_eq!(decoded.hashmap, hashmap);
+}
+
+#[test]
+fn malformed_sync_headers_are_protocol_errors() {
+    let (id, len, offset) = snapshot::parse_header(b"+SNAPSHOT 7 100 20").unwrap();
+    assert_eq!((id, len, offset), (7, 100, 20));
+    for (line, field) in [
+        (&b"+SNAPSHOT x 100 20"[..], "snapshot transfer id"),
+        (b"+SNAPSHOT 7 -1 20", "snapshot length"),
+        (b"+SNAPSHOT 7 100 ", "snapshot offset"),
+    ] {
+        let err = snapshot::parse_header(line).unwrap_err();
+        assert!(matches!(err, DistKvError::Protocol(_)), "{:?}", err);
+        assert!(err.to_string().contains(field), "{}", err);
+    }
+}
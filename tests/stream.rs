@@ -0,0 +1,84 @@
+use dist_kv::cluster::TestCluster;
+use dist_kv::protocol::ErrorCode;
+use dist_kv::stream::{Stream, StreamId};
+
+fn id(ms: u64, seq: u64) -> StreamId {
+    StreamId { ms, seq }
+}
+
+#[test]
+fn streams_round_trip_through_their_values() {
+    let mut stream = Stream::default();
+    assert_eq!(stream.next_id(5), id(5, 0));
+    assert!(stream.add(id(5, 0), b"a"));
+    assert_eq!(stream.next_id(5), id(5, 1));
+    // A clock that went back doesn't take IDs back with it.
+    assert_eq!(stream.next_id(3), id(5, 1));
+    assert!(!stream.add(id(4, 9), b"late"));
+    assert!(stream.add(id(7, 0), b""));
+
+    let restored = Stream::from_bytes(&stream.to_bytes()).unwrap();
+    assert_eq!(restored.len(), 2);
+    assert_eq!(restored.last_id(), id(7, 0));
+    let entries = restored.read(id(5, 0), 10);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].0, id(7, 0));
+    assert!(Stream::from_bytes(b"not a stream").is_none());
+
+    assert_eq!(StreamId::parse(b"12-3"), Some(id(12, 3)));
+    assert_eq!(StreamId::parse(b"12"), Some(id(12, 0)));
+    assert_eq!(id(12, 3).to_string(), "12-3");
+    assert_eq!(StreamId::parse(b"12-x"), None);
+}
+
+#[tokio::test]
+async fn streams_are_read_on_from_an_offset() {
+    let mut cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let mut ids = Vec::new();
+    for event in ["created", "paid", "shipped"] {
+        ids.push(
+            client
+                .xadd(b"orders", None, event.as_bytes())
+                .await
+                .unwrap(),
+        );
+    }
+    assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    let last = ids[2];
+    let explicit = id(last.ms, last.seq + 10);
+    let added = client.xadd(b"orders", Some(explicit), b"delivered");
+    assert_eq!(added.await.unwrap(), explicit);
+    let stale = client.xadd(b"orders", Some(ids[0]), b"again").await;
+    assert_eq!(stale.unwrap_err().code(), Some(ErrorCode::WrongArgs));
+
+    // A consumer reads a page at a time, starting after the last ID it saw.
+    let page = client.xread(b"orders", id(0, 0), Some(2)).await.unwrap();
+    assert_eq!(page.len(), 2);
+    assert_eq!(page[0], (ids[0], "created".into()));
+    assert_eq!(page[1], (ids[1], "paid".into()));
+    let page = client.xread(b"orders", page[1].0, None).await.unwrap();
+    assert_eq!(page.len(), 2);
+    assert_eq!(page[1], (explicit, "delivered".into()));
+    let page = client.xread(b"orders", explicit, None).await.unwrap();
+    assert!(page.is_empty());
+    let missing = client.xread(b"none", id(0, 0), None).await.unwrap();
+    assert!(missing.is_empty());
+
+    client.set(b"plain", b"value").await.unwrap();
+    let wrong = client.xread(b"plain", id(0, 0), None).await.unwrap_err();
+    assert_eq!(wrong.code(), Some(ErrorCode::WrongType));
+
+    cluster.wait_for_replication().await.unwrap();
+    let leader = cluster.leader_hashmap().await.unwrap();
+    let follower = cluster.follower_hashmap(0).unwrap();
+    assert_eq!(leader.get(&b"orders"[..]), follower.get(&b"orders"[..]));
+
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    let all = client.xread(b"orders", id(0, 0), None).await.unwrap();
+    assert_eq!(all.len(), 4);
+    let next = client.xadd(b"orders", None, b"returned").await.unwrap();
+    assert!(next > explicit);
+}
@@ -0,0 +1,119 @@
+use dist_kv::cluster::TestCluster;
+use dist_kv::trace;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+use tokio::sync::mpsc;
+
+/// Answers every request with `200 OK`, sending on each body.
+async fn collect(listener: TcpListener, bodies: mpsc::UnboundedSender<String>) {
+    loop {
+        let (mut socket, _) = listener.accept().await.unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        let body_start = loop {
+            let n = socket.read(&mut buf).await.unwrap();
+            request.extend_from_slice(&buf[..n]);
+            if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                let head = String::from_utf8_lossy(&request[..end]).to_string();
+                let len: usize = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .unwrap()
+                    .parse()
+                    .unwrap();
+                if request.len() >= end + 4 + len {
+                    assert!(head.starts_with("POST /v1/traces HTTP/1.1"), "{}", head);
+                    break end + 4;
+                }
+            }
+        };
+        let response = "HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n";
+        socket.write_all(response.as_bytes()).await.unwrap();
+        let _ = bodies.send(String::from_utf8(request[body_start..].to_vec()).unwrap());
+    }
+}
+
+#[derive(Debug)]
+struct ExportedSpan {
+    trace_id: String,
+    span_id: String,
+    parent: String,
+    name: String,
+    /// The rest of the span's JSON, holding its attributes.
+    rest: String,
+}
+
+/// Picks the spans out of an export request, relying on the order the
+/// exporter writes their fields in.
+fn spans(body: &str) -> Vec<ExportedSpan> {
+    let field = |span: &str, name: &str| -> String {
+        let start = span.find(&format!("\"{}\":\"", name)).unwrap() + name.len() + 4;
+        span[start..].split('"').next().unwrap().to_string()
+    };
+    body.split("{\"traceId\"")
+        .skip(1)
+        .map(|span| {
+            let span = format!("\"traceId\"{}", span);
+            ExportedSpan {
+                trace_id: field(&span, "traceId"),
+                span_id: field(&span, "spanId"),
+                parent: field(&span, "parentSpanId"),
+                name: field(&span, "name"),
+                rest: span,
+            }
+        })
+        .collect()
+}
+
+#[tokio::test]
+async fn a_write_is_traced_through_the_wal_and_replication() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/v1/traces", listener.local_addr().unwrap());
+    let (tx, mut bodies) = mpsc::unbounded_channel();
+    tokio::spawn(collect(listener, tx));
+    trace::start_exporter(&url, "test").unwrap();
+    assert!(trace::start_exporter(&url, "again").is_err());
+
+    let cluster = TestCluster::start(1).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.hello(&["reqid"]).await.unwrap();
+    client.set(b"traced", b"1").await.unwrap();
+    let request_id = client.request_id().unwrap().to_string();
+    cluster.wait_for_replication().await.unwrap();
+
+    // Every span has ended by now, so they are all exported by the time
+    // the collector goes quiet.
+    let mut exported = Vec::new();
+    while let Ok(body) = tokio::time::timeout(trace::EXPORT_INTERVAL * 4, bodies.recv()).await {
+        let body = body.unwrap();
+        assert!(body.contains("\"service.name\""), "{}", body);
+        exported.extend(spans(&body));
+    }
+    for name in ["wal.sync", "replication.apply"] {
+        assert!(exported.iter().any(|span| span.name == name), "no {}", name);
+    }
+
+    let find = |name: &str, attr: &str| {
+        exported
+            .iter()
+            .find(|span| span.name == name && span.rest.contains(attr))
+            .unwrap_or_else(|| panic!("no {} span with {} in {:?}", name, attr, exported))
+    };
+    let tagged = format!("\"stringValue\":\"{}\"", request_id);
+    let request = find("request", &tagged);
+    assert_eq!(request.parent, "");
+    let append = find("wal.append", &tagged);
+    assert_eq!(append.trace_id, request.trace_id);
+    let execute = exported
+        .iter()
+        .find(|span| span.span_id == append.parent)
+        .unwrap();
+    assert_eq!(execute.name, "execute");
+    assert_eq!(execute.parent, request.span_id);
+    let send = exported
+        .iter()
+        .find(|span| span.name == "replication.send" && span.trace_id == request.trace_id)
+        .unwrap();
+    assert_eq!(send.parent, request.span_id);
+    find("replication.apply", &tagged);
+}
@@ -0,0 +1,61 @@
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr};
+
+use dist_kv::transport::{MemoryNetwork, Transport};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+fn ip(n: u8) -> IpAddr {
+    IpAddr::V4(Ipv4Addr::new(10, 0, 0, n))
+}
+
+#[tokio::test]
+async fn hosts_connect_through_memory() {
+    let network = MemoryNetwork::new();
+    let (a, b) = (network.host(ip(1)), network.host(ip(2)));
+    let mut listener = b.bind("0.0.0.0:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    assert_eq!(addr.ip(), ip(2));
+    let err = b.bind(&addr.to_string()).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::AddrInUse);
+
+    let mut client = a.connect(&addr.to_string()).await.unwrap();
+    let (mut server, from) = listener.accept().await.unwrap();
+    assert_eq!(from, client.local_addr().unwrap());
+    assert_eq!(server.peer_addr().unwrap(), client.local_addr().unwrap());
+    client.write_all(b"ping").await.unwrap();
+    let mut buf = [0; 4];
+    server.read_exact(&mut buf).await.unwrap();
+    assert_eq!(&buf, b"ping");
+
+    drop(server);
+    assert_eq!(client.read(&mut buf).await.unwrap(), 0);
+    drop(listener);
+    let err = a.connect(&addr.to_string()).await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionRefused);
+}
+
+#[tokio::test]
+async fn partitions_cut_connections_until_healed() {
+    let network = MemoryNetwork::new();
+    let (a, b, c) = (
+        network.host(ip(1)),
+        network.host(ip(2)),
+        network.host(ip(3)),
+    );
+    let mut listener = b.bind("10.0.0.2:7000").await.unwrap();
+    let mut client = a.connect("10.0.0.2:7000").await.unwrap();
+    let (_server, _) = listener.accept().await.unwrap();
+    let mut buf = [0; 4];
+    let reading = tokio::spawn(async move { client.read(&mut buf).await });
+
+    network.partition(ip(1), ip(2));
+    let err = reading.await.unwrap().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::ConnectionReset);
+    let err = a.connect("10.0.0.2:7000").await.unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::HostUnreachable);
+    // Other hosts are unaffected.
+    c.connect("10.0.0.2:7000").await.unwrap();
+
+    network.heal();
+    a.connect("10.0.0.2:7000").await.unwrap();
+}
@@ -0,0 +1,58 @@
+use bytes::BytesMut;
+use dist_kv::cluster::TestCluster;
+use dist_kv::config::Config;
+use dist_kv::protocol::Command;
+use dist_kv::store::replay;
+use dist_kv::uring::UringFile;
+use dist_kv::wal::{create_log_file, Storage, WalBackend};
+
+#[test]
+fn records_appended_through_the_ring_are_replayed_in_order() {
+    let dir = std::env::temp_dir().join(format!("dist-kv-uring-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("leader.log");
+    let mut file = UringFile::new(create_log_file(&path).unwrap()).unwrap();
+    let mut expected = BytesMut::new();
+    // More than the ring holds, so some are submitted before the sync.
+    for i in 0..1000 {
+        let mut record = BytesMut::new();
+        let key = format!("key:{}", i % 300);
+        Command::Set(key.as_bytes().into(), format!("{}", i).as_bytes().into()).encode(&mut record);
+        file.append(&record).unwrap();
+        expected.extend_from_slice(&record);
+        if i % 400 == 399 {
+            file.sync().unwrap();
+        }
+    }
+    file.sync().unwrap();
+    assert_eq!(file.metadata().unwrap().len(), expected.len() as u64);
+    let log = std::fs::read(&path).unwrap();
+    assert_eq!(log, expected);
+
+    file.reset().unwrap();
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    drop(file);
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_leader_logging_through_io_uring_recovers_its_writes() {
+    let config = Config {
+        wal_backend: WalBackend::IoUring,
+        ..Config::default()
+    };
+    let mut cluster = TestCluster::start_with_config(1, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    for i in 0..50 {
+        let key = format!("key:{}", i);
+        client.set(key.as_bytes(), b"value").await.unwrap();
+    }
+    client.del(b"key:0").await.unwrap();
+    let expected = cluster.leader_hashmap().await.unwrap();
+    let log = std::fs::read(cluster.dir().join("leader.log")).unwrap();
+    assert_eq!(replay(&log).unwrap(), expected);
+
+    cluster.kill_leader().await;
+    cluster.restart_leader().await.unwrap();
+    assert_eq!(cluster.leader_hashmap().await.unwrap(), expected);
+}
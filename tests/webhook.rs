@@ -0,0 +1,63 @@
+use dist_kv::cluster::TestCluster;
+use dist_kv::config::Config;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+/// Accepts one request and answers it with `status`, returning the body.
+async fn answer(listener: &TcpListener, status: &str) -> String {
+    let (mut socket, _) = listener.accept().await.unwrap();
+    let mut request = Vec::new();
+    let mut buf = [0; 4096];
+    // The client sends the whole request and waits for the answer.
+    let body_start = loop {
+        let n = socket.read(&mut buf).await.unwrap();
+        request.extend_from_slice(&buf[..n]);
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            let head = String::from_utf8_lossy(&request[..end]).to_string();
+            let len: usize = head
+                .lines()
+                .find_map(|line| line.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            if request.len() >= end + 4 + len {
+                assert!(head.starts_with("POST /hooks/keys HTTP/1.1"), "{}", head);
+                break end + 4;
+            }
+        }
+    };
+    let response = format!("HTTP/1.1 {}\r\nContent-Length: 0\r\n\r\n", status);
+    socket.write_all(response.as_bytes()).await.unwrap();
+    String::from_utf8(request[body_start..].to_vec()).unwrap()
+}
+
+#[tokio::test]
+async fn matching_writes_are_posted_with_retries() {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/hooks/keys", listener.local_addr().unwrap());
+    let mut config = Config::default();
+    config.set("trigger", &format!("user:* {}", url)).unwrap();
+    let cluster = TestCluster::start_with_config(0, config).await.unwrap();
+    let mut client = cluster.client().await.unwrap();
+    client.set(b"session:1", b"ignored").await.unwrap();
+    client.set(b"user:1", b"ada").await.unwrap();
+    client.select(b"staging").await.unwrap();
+    client.del(b"user:2").await.unwrap();
+    client.set(b"user:\"2\"", b"grace").await.unwrap();
+
+    // The first attempt fails and is retried with the same payload.
+    let first = answer(&listener, "503 Service Unavailable").await;
+    let retried = answer(&listener, "200 OK").await;
+    assert_eq!(first, retried);
+    assert!(
+        first.starts_with(r#"{"event":"set","namespace":"0","key":"user:1","time":"#),
+        "{}",
+        first
+    );
+    let second = answer(&listener, "204 No Content").await;
+    assert!(
+        second.starts_with(r#"{"event":"set","namespace":"staging","key":"user:\"2\"","time":"#),
+        "{}",
+        second
+    );
+}